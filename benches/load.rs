@@ -0,0 +1,55 @@
+//! Compares loading a large transaction log with and without pooled record buffers
+
+use std::{fmt::Write, io::Cursor};
+
+use cashflow::{
+    engine::{Engine, EngineSettings},
+    types::{MemoryAccountBook, MemoryTransactionLog},
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// Number of rows in the generated input
+const ROWS: u32 = 100_000;
+
+/// Builds a CSV transaction log with a mix of every transaction type
+fn generate_input() -> Vec<u8> {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        let client = tx % 1000;
+        let line = match tx % 10 {
+            0..=5 => format!("deposit,{client},{tx},{}.{:04}", tx % 500, tx % 10_000),
+            6 | 7 => format!("withdrawal,{client},{tx},{}.5", tx % 50),
+            8 => format!("dispute,{client},{},", tx - 8),
+            _ => format!("resolve,{client},{},", tx - 9),
+        };
+        writeln!(input, "{line}").unwrap();
+    }
+    input.into_bytes()
+}
+
+fn bench_load(c: &mut Criterion) {
+    let input = generate_input();
+    let mut group = c.benchmark_group("load_csv");
+    group.throughput(Throughput::Elements(u64::from(ROWS)));
+    for pooled_records in [false, true] {
+        let label = if pooled_records { "pooled" } else { "unpooled" };
+        group.bench_with_input(BenchmarkId::from_parameter(label), &input, |b, input| {
+            b.iter(|| {
+                let mut engine = Engine::with_settings(
+                    MemoryAccountBook::new(),
+                    MemoryTransactionLog::new(),
+                    EngineSettings {
+                        pooled_records,
+                        ..EngineSettings::default()
+                    },
+                );
+                engine.load_csv(&mut Cursor::new(input)).unwrap();
+                engine
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_load);
+criterion_main!(benches);
@@ -0,0 +1,6 @@
+//! Transactions in CSV, through parsing, the engine and back out as a report
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| cashflow::fuzz::fuzz_one_input(data));
@@ -0,0 +1,10 @@
+//! Account reports in CSV, as read back for diffs and changed-account output
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = cashflow::io::read_accounts_from_csv(&mut Cursor::new(data));
+});
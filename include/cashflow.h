@@ -0,0 +1,49 @@
+/* C interface to the cashflow engine, from the cdylib built with
+ * `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+ *
+ * Strings passed in are borrowed and must be NUL-terminated UTF-8. Strings returned by
+ * cashflow_report_csv belong to the caller, who frees them with cashflow_string_free. An engine
+ * must not be used from more than one thread at a time.
+ */
+#ifndef CASHFLOW_H
+#define CASHFLOW_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Returned when a row was applied */
+#define CASHFLOW_OK 0
+/* Returned when a row couldn't be parsed or applied; see cashflow_last_error */
+#define CASHFLOW_REJECTED 1
+/* Returned when a pointer was null, or a string wasn't UTF-8 */
+#define CASHFLOW_INVALID_ARGUMENT (-1)
+
+typedef struct CashflowEngine CashflowEngine;
+
+/* Creates an engine with no accounts. Free it with cashflow_engine_free. */
+CashflowEngine *cashflow_engine_new(void);
+
+/* Frees an engine. Passing NULL does nothing. */
+void cashflow_engine_free(CashflowEngine *engine);
+
+/* Applies one CSV row without a header, like "deposit,1,1,5.0". */
+int cashflow_apply_csv_line(CashflowEngine *engine, const char *line);
+
+/* Returns why the last row was rejected, or the last report failed, or NULL if neither did.
+ * Owned by the engine, and valid until the next call with it. */
+const char *cashflow_last_error(const CashflowEngine *engine);
+
+/* Returns every account as CSV with a header, in client order (empty with no accounts), or NULL
+ * if engine is NULL or writing the accounts failed; see cashflow_last_error. Free it with
+ * cashflow_string_free. */
+char *cashflow_report_csv(CashflowEngine *engine);
+
+/* Frees a string returned by cashflow_report_csv. Passing NULL does nothing. */
+void cashflow_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CASHFLOW_H */
@@ -0,0 +1,272 @@
+//! Flags deposits and withdrawals that are unusually large for their account, for someone to
+//! review by hand.
+//!
+//! An [`AnomalyDetector`](crate::anomaly::AnomalyDetector) keeps a running average of each
+//! account's deposit and withdrawal amounts. Once an account has enough history, any amount more
+//! than a set multiple of that average is recorded as an [`Anomaly`](crate::anomaly::Anomaly).
+//! Nothing is rejected: flagged transactions are applied like any other.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    errors::Error,
+    events::EventListener,
+    types::{Account, ClientId, TransactionId, TransactionRecord, TransactionType},
+};
+
+/// When an amount counts as unusually large
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnomalyThresholds {
+    /// Flag amounts more than this many times the account's average. Defaults to 10.
+    pub multiple: Decimal,
+    /// Only judge accounts with at least this many deposits and withdrawals already applied, so
+    /// a new account's first few amounts aren't all flagged. Defaults to 3.
+    pub min_history: u64,
+    /// Never flag amounts below this, however small the account's average. Defaults to zero.
+    pub min_amount: Decimal,
+}
+
+impl Default for AnomalyThresholds {
+    fn default() -> Self {
+        Self {
+            multiple: Decimal::TEN,
+            min_history: 3,
+            min_amount: Decimal::ZERO,
+        }
+    }
+}
+
+/// A deposit or withdrawal flagged as unusually large
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Anomaly {
+    /// The flagged transaction's type
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    /// The account it was applied to
+    pub client: ClientId,
+    /// The flagged transaction
+    #[serde(rename = "tx")]
+    pub transaction: TransactionId,
+    /// The flagged amount
+    pub amount: Decimal,
+    /// The account's average amount before this one
+    pub average: Decimal,
+}
+
+/// An account's deposits and withdrawals so far
+#[derive(Debug, Default, Clone, Copy)]
+struct History {
+    /// Number of deposits and withdrawals
+    count: u64,
+    /// Sum of their amounts, saturating rather than overflowing
+    sum: Decimal,
+}
+
+/// The history and findings behind an [`AnomalyDetector`] and its clones
+#[derive(Debug)]
+struct DetectorState {
+    /// When to flag an amount
+    thresholds: AnomalyThresholds,
+    /// Every account's history
+    history: HashMap<ClientId, History>,
+    /// Everything flagged so far, in order
+    anomalies: Vec<Anomaly>,
+    /// Also sent each anomaly as it's found, if set
+    sender: Option<mpsc::Sender<Anomaly>>,
+}
+
+/// Watches deposits and withdrawals as an engine applies them, flagging unusually large ones.
+///
+/// As with an [`AuditLog`](crate::audit::AuditLog), clones share the same findings, so keep one
+/// to call [`report`](Self::report) on after registering another with the engine.
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    /// Shared with clones
+    inner: Arc<Mutex<DetectorState>>,
+}
+
+impl Clone for AnomalyDetector {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl AnomalyDetector {
+    /// Creates a detector flagging amounts according to the supplied thresholds
+    #[must_use]
+    pub fn new(thresholds: AnomalyThresholds) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(DetectorState {
+                thresholds,
+                history: HashMap::new(),
+                anomalies: Vec::new(),
+                sender: None,
+            })),
+        }
+    }
+
+    /// Also sends each anomaly over a channel as soon as it's found. Anomalies are still sent
+    /// once the receiver is gone, to no effect.
+    #[must_use]
+    pub fn with_sender(self, sender: mpsc::Sender<Anomaly>) -> Self {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .sender = Some(sender);
+        self
+    }
+
+    /// Returns everything flagged so far, in the order it was applied
+    #[must_use]
+    pub fn report(&self) -> Vec<Anomaly> {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .anomalies
+            .clone()
+    }
+
+    /// Writes everything flagged so far as CSV, with a header of
+    /// `type,client,tx,amount,average`
+    /// # Errors
+    /// If writing fails
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        let anomalies = self.report();
+        if anomalies.is_empty() {
+            csv_writer.write_record(["type", "client", "tx", "amount", "average"])?;
+        }
+        for anomaly in anomalies {
+            csv_writer.serialize(anomaly)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+impl EventListener for AnomalyDetector {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        let (TransactionType::Deposit | TransactionType::Withdrawal, Some(amount)) =
+            (transaction.transaction_type(), transaction.amount())
+        else {
+            return;
+        };
+        let amount = amount.abs();
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let thresholds = inner.thresholds;
+        let history = inner.history.entry(transaction.client_id()).or_default();
+        let prior = *history;
+        history.count += 1;
+        history.sum = history.sum.saturating_add(amount);
+        if prior.count == 0 || prior.count < thresholds.min_history {
+            return;
+        }
+        let average = prior.sum / Decimal::from(prior.count);
+        // Nothing a Decimal holds can exceed a limit too large for one
+        let Some(limit) = average.checked_mul(thresholds.multiple) else {
+            return;
+        };
+        if amount < thresholds.min_amount || amount <= limit {
+            return;
+        }
+        let anomaly = Anomaly {
+            transaction_type: transaction.transaction_type(),
+            client: transaction.client_id(),
+            transaction: transaction.transaction_id(),
+            amount,
+            average: average.round_dp(4),
+        };
+        inner.anomalies.push(anomaly);
+        if let Some(sender) = &inner.sender {
+            let _ = sender.send(anomaly);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_anomalies() {
+        let (sender, receiver) = mpsc::channel();
+        let detector = AnomalyDetector::new(AnomalyThresholds {
+            min_history: 2,
+            ..AnomalyThresholds::default()
+        })
+        .with_sender(sender);
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(detector.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,12.0
+deposit,1,3,500.0
+withdrawal,1,4,20.0
+deposit,2,5,1.0
+deposit,2,6,1.0
+deposit,2,7,10.0
+deposit,3,8,1000.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let report = detector.report();
+        // tx 7 is exactly 10x the average, and client 3 has no history to judge by
+        assert_eq!(
+            report,
+            vec![Anomaly {
+                transaction_type: TransactionType::Deposit,
+                client: ClientId(1),
+                transaction: TransactionId(3),
+                amount: dec!(500.0),
+                average: dec!(11.0),
+            }]
+        );
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), report);
+        // The flagged deposit still went through
+        let account = &engine.account_book().accounts[&ClientId(1)];
+        assert_eq!(account.funds_available(), dec!(502.0));
+
+        let mut output = Vec::new();
+        detector.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount,average\ndeposit,1,3,500.0,11.0\n"
+        );
+    }
+
+    #[test]
+    fn test_anomalies_near_max() {
+        let detector = AnomalyDetector::new(AnomalyThresholds {
+            min_history: 2,
+            ..AnomalyThresholds::default()
+        });
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(detector.clone());
+        // The account never leaves range, but its history adds up to more than a Decimal holds
+        let input = "type,client,tx,amount
+deposit,1,1,40000000000000000000000000000
+withdrawal,1,2,40000000000000000000000000000
+deposit,1,3,40000000000000000000000000000
+deposit,1,4,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert!(detector.report().is_empty());
+    }
+}
@@ -0,0 +1,1210 @@
+//! A structured trail of every decision an [`Engine`](crate::engine::Engine) makes, to answer
+//! "why did the engine do that?" after the fact.
+//!
+//! An [`AuditLog`](crate::audit::AuditLog) writes one JSON object per line (NDJSON) for each
+//! transaction the engine is handed: the incoming record, where it was read from, what was
+//! decided, and the account's balances afterwards. For example:
+//! ```json
+//! {"sequence":1,"prev":"0000…0000","type":"deposit","client":1,"tx":1,"amount":"5.0","source":"monday.csv","line":2,"decision":"applied","available":"5.0000","held":"0.0000","total":"5.0000","locked":false}
+//! {"sequence":2,"prev":"9f3c…41d2","type":"dispute","client":1,"tx":9,"amount":null,"source":"monday.csv","line":3,"decision":"ignored_missing_reference","available":"5.0000","held":"0.0000","total":"5.0000","locked":false}
+//! ```
+//! The `source` is left out for inputs that weren't given a name.
+//! Rows that can't be parsed never reach the engine, so aren't audited.
+//! [Custom transactions](crate::handler) are audited once they're applied, under their own type.
+//!
+//! Entries form a hash chain, so the trail can't be quietly edited afterwards. Each entry's `prev`
+//! is the hex SHA-256 digest of the line before it (64 zeros for the first), or an HMAC-SHA256 of
+//! it if the log was given a [secret](crate::audit::AuditLog::with_secret). Changing, removing or
+//! reordering any line breaks the chain at the line after it, which
+//! [`verify_chain`](crate::audit::verify_chain) finds. Editing the last line, or dropping lines
+//! from the end, leaves a valid but shorter chain, so keep the digest of the last entry, from
+//! [`AuditLog::head`](crate::audit::AuditLog::head), somewhere else to compare with. Without a
+//! secret, anyone able to edit the file can recompute the whole chain, so the head needs keeping
+//! either way.
+//!
+//! Entries for a client whose transactions have been
+//! [erased](crate::engine::Engine::erase_client) can be replaced afterwards with
+//! [`redact_client`](crate::audit::redact_client), which rewrites the file with a tombstone in
+//! place of each one written before the erasure, carrying just its position, client and digest,
+//! and a seal over those:
+//! ```json
+//! {"sequence":1,"prev":"0000…0000","decision":"redacted","client":1,"digest":"9f3c…41d2","seal":"5be0…07a9"}
+//! ```
+//! [`verify_chain`](crate::audit::verify_chain) takes a tombstone's digest as that of the entry
+//! it replaced, so the chain, and its head, are unchanged. The seal is an HMAC keyed with the
+//! log's secret, so only logs written with one can be redacted, and a tombstone is only accepted
+//! if its seal checks out and the client's erasure is recorded further on in the chain. A
+//! tombstone hides what an entry said but not that there was one.
+//!
+//! For long-running processes, write the trail to a [`RotatingFile`](crate::audit::RotatingFile),
+//! which moves the file aside once it gets too big or too old, optionally compresses it, and only
+//! keeps so many old segments.
+//!
+//! A [`DeadLetterLog`](crate::audit::DeadLetterLog) collects just the transactions that were
+//! skipped or rejected, in a form that can be fixed up and loaded again.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use csv::ByteRecord;
+use hmac::{Hmac, Mac};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    clock::{Clock, SharedClock},
+    erasure::Erasure,
+    errors::{DomainError, Error, ParseError},
+    events::EventListener,
+    handler::CustomTransaction,
+    types::{
+        Account, ClientId, Provenance, RawClientId, TransactionId, TransactionRecord,
+        TransactionType,
+    },
+};
+
+/// What the engine decided to do with a transaction
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditDecision {
+    /// The transaction was applied
+    Applied,
+    /// A dispute, resolve or chargeback was accepted but changed nothing, because the transaction
+    /// it refers to is missing
+    IgnoredMissingReference,
+    /// The transaction was rejected because its account is locked
+    RejectedLocked,
+    /// The transaction was rejected because it had already been applied
+    RejectedDuplicate,
+    /// The transaction was rejected for some other reason, given alongside
+    Rejected,
+    /// Not a transaction: the client's transactions were
+    /// [erased](crate::engine::Engine::erase_client). The entry has the
+    /// [`Erasure`] in place of a transaction's fields.
+    Erased,
+    /// Not a transaction: a tombstone in place of an entry, written by [`redact_client`]
+    Redacted,
+}
+
+/// A single line of the audit trail
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    /// Position of this entry in the trail, counting from 1
+    sequence: u64,
+    /// Hex digest of the entry before, chaining the entries together
+    prev: &'a str,
+    /// The incoming transaction's type, which may be a [custom](crate::handler) one
+    #[serde(rename = "type")]
+    transaction_type: &'a str,
+    /// The incoming transaction's client
+    client: ClientId,
+    /// The incoming transaction's ID
+    tx: TransactionId,
+    /// The incoming transaction's amount, as given
+    amount: Option<Decimal>,
+    /// The input the transaction was read from, if it was named
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    /// The transaction's line within its input
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u64>,
+    /// What was done with the transaction
+    decision: AuditDecision,
+    /// The [code](Error::code) of the error the transaction was rejected with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    /// Why the transaction was rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Available funds afterwards. Left out for rejections, which don't change the account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available: Option<Decimal>,
+    /// Held funds afterwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    held: Option<Decimal>,
+    /// Total funds afterwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<Decimal>,
+    /// Whether the account is locked afterwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked: Option<bool>,
+}
+
+/// The transaction an [`AuditEntry`] is for
+struct Incoming<'a> {
+    /// Its type, as it appears in the `type` column
+    kind: &'a str,
+    /// Its client
+    client: ClientId,
+    /// Its ID
+    tx: TransactionId,
+    /// Its amount, as given
+    amount: Option<Decimal>,
+}
+
+impl From<&TransactionRecord> for Incoming<'static> {
+    fn from(transaction: &TransactionRecord) -> Self {
+        Self {
+            kind: transaction.transaction_type.name(),
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            amount: transaction.amount,
+        }
+    }
+}
+
+impl<'a> From<&'a CustomTransaction> for Incoming<'a> {
+    fn from(transaction: &'a CustomTransaction) -> Self {
+        Self {
+            kind: &transaction.kind,
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            amount: transaction.amount,
+        }
+    }
+}
+
+/// A line of the audit trail recording an erasure
+#[derive(Debug, Serialize)]
+struct ErasureEntry<'a> {
+    /// Position of this entry in the trail, counting from 1
+    sequence: u64,
+    /// Hex digest of the entry before, chaining the entries together
+    prev: &'a str,
+    /// Always [`AuditDecision::Erased`]
+    decision: AuditDecision,
+    /// What was erased
+    #[serde(flatten)]
+    erasure: &'a Erasure,
+}
+
+/// A line of the audit trail in place of a redacted entry
+#[derive(Debug, Serialize, Deserialize)]
+struct RedactedEntry {
+    /// Position of the redacted entry in the trail
+    sequence: u64,
+    /// Hex digest of the entry before, as the redacted entry had it
+    prev: String,
+    /// Always [`AuditDecision::Redacted`]
+    decision: AuditDecision,
+    /// The client whose entry was redacted
+    client: ClientId,
+    /// Hex digest of the redacted entry, which the next entry's `prev` is
+    digest: String,
+    /// Hex HMAC of the fields above, keyed with the log's secret, so a tombstone can't be made
+    /// without it
+    seal: String,
+}
+
+impl RedactedEntry {
+    /// Returns what the seal of a tombstone with these fields should be
+    fn seal(&self, secret: &[u8]) -> String {
+        let sealed = format!(
+            "redacted\n{}\n{}\n{}\n{}",
+            self.sequence,
+            RawClientId::from(self.client),
+            self.prev,
+            self.digest
+        );
+        digest(Some(secret), sealed.as_bytes())
+    }
+}
+
+/// The writer behind an [`AuditLog`] and its clones
+#[derive(Debug)]
+struct AuditWriter<W> {
+    /// Where entries are written
+    writer: W,
+    /// Sequence number of the last entry written
+    sequence: u64,
+    /// Hex digest of the last entry written
+    head: String,
+    /// Key the digests are HMACs with, if any
+    secret: Option<Vec<u8>>,
+    /// Where the transaction about to be recorded was read from
+    provenance: Option<Provenance>,
+    /// The first error hit while writing, after which nothing more is written
+    error: Option<std::io::Error>,
+}
+
+/// Records every decision made by the engine it's registered with, as NDJSON.
+///
+/// Clones share the same output, so keep one to call [`finish`](Self::finish) with after
+/// registering another with [`Engine::add_listener`](crate::engine::Engine::add_listener):
+/// ```
+/// # use cashflow::{
+/// #     audit::AuditLog,
+/// #     engine::Engine,
+/// #     types::{MemoryAccountBook, MemoryTransactionLog},
+/// # };
+/// let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+/// let audit_log = AuditLog::new(vec![]);
+/// engine.add_listener(audit_log.clone());
+/// engine.load_csv(&mut "type,client,tx,amount\ndeposit,1,1,5.0\n".as_bytes()).unwrap();
+/// audit_log.finish().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct AuditLog<W> {
+    /// Shared with every clone
+    inner: Arc<Mutex<AuditWriter<W>>>,
+}
+
+impl<W> Clone for AuditLog<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> AuditLog<W> {
+    /// Creates an audit log writing to the supplied writer. Entries aren't flushed individually,
+    /// so a [`BufWriter`] (or a [`RotatingFile`]) is a good idea for files.
+    #[must_use]
+    pub fn new(writer: W) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(AuditWriter {
+                writer,
+                sequence: 0,
+                head: GENESIS.to_string(),
+                secret: None,
+                provenance: None,
+                error: None,
+            })),
+        }
+    }
+
+    /// Chains entries with HMAC-SHA256 keyed with the supplied secret, rather than plain SHA-256,
+    /// so the chain can't be rebuilt after editing without it
+    #[must_use]
+    pub fn with_secret(self, secret: impl Into<Vec<u8>>) -> Self {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .secret = Some(secret.into());
+        self
+    }
+
+    /// Returns the hex digest of the last entry written, or 64 zeros if there are none yet, to
+    /// keep somewhere safe and compare with what [`verify_chain`] returns later
+    #[must_use]
+    pub fn head(&self) -> String {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .head
+            .clone()
+    }
+
+    /// Flushes everything written so far.
+    /// # Errors
+    /// If any entry failed to be written (in which case nothing after it was written either), or
+    /// flushing fails
+    pub fn finish(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(err) = inner.error.take() {
+            return Err(err.into());
+        }
+        Ok(inner.writer.flush()?)
+    }
+
+    /// Writes an entry for a transaction, with the account's state if it was accepted, or the
+    /// error if it was rejected
+    fn record(
+        &self,
+        transaction: &Incoming<'_>,
+        decision: AuditDecision,
+        error: Option<&Error>,
+        account: Option<&Account>,
+    ) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.error.is_some() {
+            return;
+        }
+        inner.sequence += 1;
+        let provenance = inner.provenance.take();
+        let entry = AuditEntry {
+            sequence: inner.sequence,
+            prev: &inner.head,
+            transaction_type: transaction.kind,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            source: provenance
+                .as_ref()
+                .map(|provenance| &*provenance.source)
+                .filter(|source| !source.is_empty()),
+            line: provenance.as_ref().map(|provenance| provenance.line),
+            decision,
+            code: error.map(Error::code),
+            reason: error.map(Error::to_string),
+            available: account.map(Account::funds_available),
+            held: account.map(Account::funds_held),
+            total: account.map(Account::total),
+            locked: account.map(Account::is_locked),
+        };
+        let line = serde_json::to_vec(&entry);
+        inner.append(line);
+    }
+}
+
+impl<W: Write> AuditWriter<W> {
+    /// Writes a serialized entry, chaining the next entry to it
+    fn append(&mut self, line: serde_json::Result<Vec<u8>>) {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                self.error = Some(err.into());
+                return;
+            }
+        };
+        self.head = digest(self.secret.as_deref(), &line);
+        let result = self
+            .writer
+            .write_all(&line)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        self.error = result.err();
+    }
+}
+
+/// The `prev` of the first entry in a chain
+pub(crate) const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Returns the hex digest of an audit log line, keyed with the secret if there is one
+pub(crate) fn digest(secret: Option<&[u8]>, line: &[u8]) -> String {
+    let bytes: [u8; 32] = match secret {
+        Some(secret) => {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+            mac.update(line);
+            mac.finalize().into_bytes().into()
+        }
+        None => Sha256::digest(line).into(),
+    };
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Checks that every line of an audit log follows on from the one before, returning the digest
+/// of the last, to compare with the [`head`](AuditLog::head) kept when it was written.
+///
+/// Pass the same secret the log was written with, if any. The chain runs across rotated
+/// segments, so check those by reading them oldest first, eg with [`Read::chain`](io::Read::chain),
+/// ending with the current file. This starts from the first entry ever written; to check segments
+/// whose older ones have been archived or deleted, use [`verify_chain_from`].
+///
+/// A [redacted](redact_client) entry's tombstone is taken in its place only if its seal checks
+/// out against the secret, and the erasure of its client follows it in what's read.
+/// # Errors
+/// [`ParseError::BrokenChain`] at the first line that isn't an entry, or whose `prev` isn't the
+/// digest of the line before, or that's a tombstone that can't be accepted, or if reading fails
+pub fn verify_chain<R: BufRead>(reader: R, secret: Option<&[u8]>) -> Result<String, Error> {
+    verify_chain_from(reader, secret, GENESIS)
+}
+
+/// Checks that every line of part of an audit log follows on from the one before, as
+/// [`verify_chain`] does, starting from `head`, the digest of the entry before the first one
+/// read, and returns the digest of the last.
+///
+/// This checks a rotated segment, or several, on its own: pass the head returned by checking the
+/// segments before it, or one kept when they were rotated, so a chain can be checked a segment
+/// at a time, and after older segments are gone. Tombstones are still only accepted if their
+/// client's erasure is in what's read.
+/// # Errors
+/// As for [`verify_chain`], including at the first line if its `prev` isn't `head`
+pub fn verify_chain_from<R: BufRead>(
+    reader: R,
+    secret: Option<&[u8]>,
+    head: &str,
+) -> Result<String, Error> {
+    let mut head = head.to_string();
+    // The first tombstone of each client whose erasure hasn't been read yet
+    let mut unerased: BTreeMap<ClientId, u64> = BTreeMap::new();
+    for entry in entries(reader) {
+        let (index, line, entry) = entry?;
+        let number = index as u64 + 1;
+        let broken = || ParseError::BrokenChain { line: number };
+        if entry.get("prev").and_then(serde_json::Value::as_str) != Some(&*head) {
+            return Err(broken().into());
+        }
+        let client = || serde_json::from_value::<ClientId>(entry.get("client")?.clone()).ok();
+        head = match decision(&entry) {
+            Some("redacted") => {
+                let tombstone: RedactedEntry =
+                    serde_json::from_value(entry).map_err(|_| broken())?;
+                let secret = secret.ok_or_else(broken)?;
+                if tombstone.seal != tombstone.seal(secret) {
+                    return Err(broken().into());
+                }
+                unerased.entry(tombstone.client).or_insert(number);
+                tombstone.digest
+            }
+            Some("erased") => {
+                if let Some(client) = client() {
+                    unerased.remove(&client);
+                }
+                digest(secret, &line)
+            }
+            _ => digest(secret, &line),
+        };
+    }
+    match unerased.into_values().min() {
+        Some(line) => Err(ParseError::BrokenChain { line }.into()),
+        None => Ok(head),
+    }
+}
+
+/// Copies an audit log, replacing every entry for the client written before their last
+/// [erasure](crate::engine::Engine::erase_client) with a tombstone, and returns how many were
+/// replaced. The entries recording erasures are kept, as are those written after the last one,
+/// which are for transactions applied since. Nothing is replaced if the client was never erased.
+///
+/// Pass the secret the log was written with: tombstones are sealed with it, so a log written
+/// without one can't be redacted. The reader is read twice, first to find the erasure, so it has
+/// to be seekable. Rotated segments can be redacted one at a time, but only those read along
+/// with the segment recording the erasure, and compressed ones need decompressing first. Write to
+/// a new file and move it over the old one once done, rather than rewriting the file in place,
+/// and not while an [`AuditLog`] is still appending to it.
+/// # Errors
+/// [`ParseError::BrokenChain`] at the first line that isn't an entry, or if reading or writing
+/// fails
+pub fn redact_client<R: BufRead + Seek, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    client: ClientId,
+    secret: &[u8],
+) -> Result<usize, Error> {
+    let start = reader.stream_position()?;
+    let client_value = serde_json::to_value(client).map_err(io::Error::from)?;
+    let is_client = |entry: &serde_json::Value| entry.get("client") == Some(&client_value);
+    let mut erased_at = None;
+    for entry in entries(&mut reader) {
+        let (index, _, entry) = entry?;
+        if is_client(&entry) && decision(&entry) == Some("erased") {
+            erased_at = Some(index);
+        }
+    }
+    reader.seek(SeekFrom::Start(start))?;
+    let mut redacted = 0;
+    for entry in entries(&mut reader) {
+        let (index, line, entry) = entry?;
+        let kept = matches!(decision(&entry), Some("erased" | "redacted"));
+        if is_client(&entry) && !kept && erased_at.is_some_and(|erased_at| index < erased_at) {
+            let broken = || ParseError::BrokenChain {
+                line: index as u64 + 1,
+            };
+            let mut tombstone = RedactedEntry {
+                sequence: entry
+                    .get("sequence")
+                    .and_then(serde_json::Value::as_u64)
+                    .ok_or_else(broken)?,
+                prev: entry
+                    .get("prev")
+                    .and_then(serde_json::Value::as_str)
+                    .ok_or_else(broken)?
+                    .to_string(),
+                decision: AuditDecision::Redacted,
+                client,
+                digest: digest(Some(secret), &line),
+                seal: String::new(),
+            };
+            tombstone.seal = tombstone.seal(secret);
+            serde_json::to_writer(&mut writer, &tombstone).map_err(io::Error::from)?;
+            redacted += 1;
+        } else {
+            writer.write_all(&line)?;
+        }
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(redacted)
+}
+
+/// Reads the lines of an audit log, with their 0-based index and as parsed
+fn entries<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<(usize, Vec<u8>, serde_json::Value), Error>> {
+    reader.split(b'\n').enumerate().map(|(index, line)| {
+        let line = line?;
+        let entry = serde_json::from_slice(&line).map_err(|_| ParseError::BrokenChain {
+            line: index as u64 + 1,
+        })?;
+        Ok((index, line, entry))
+    })
+}
+
+/// Returns an audit log entry's decision
+fn decision(entry: &serde_json::Value) -> Option<&str> {
+    entry.get("decision")?.as_str()
+}
+
+impl<W: Write> EventListener for AuditLog<W> {
+    fn on_read(&mut self, provenance: &Provenance) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .provenance = Some(provenance.clone());
+    }
+
+    fn on_applied(&mut self, transaction: &TransactionRecord, account: &Account) {
+        self.record(
+            &transaction.into(),
+            AuditDecision::Applied,
+            None,
+            Some(account),
+        );
+    }
+
+    fn on_custom_applied(&mut self, transaction: &CustomTransaction, account: &Account) {
+        self.record(
+            &transaction.into(),
+            AuditDecision::Applied,
+            None,
+            Some(account),
+        );
+    }
+
+    fn on_missing_reference(&mut self, transaction: &TransactionRecord, account: &Account) {
+        self.record(
+            &transaction.into(),
+            AuditDecision::IgnoredMissingReference,
+            None,
+            Some(account),
+        );
+    }
+
+    fn on_erased(&mut self, erasure: &Erasure) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.error.is_some() {
+            return;
+        }
+        inner.sequence += 1;
+        let entry = ErasureEntry {
+            sequence: inner.sequence,
+            prev: &inner.head,
+            decision: AuditDecision::Erased,
+            erasure,
+        };
+        let line = serde_json::to_vec(&entry);
+        inner.append(line);
+    }
+
+    fn on_rejected(&mut self, transaction: &TransactionRecord, error: &Error) {
+        let decision = match error {
+            Error::Domain(DomainError::Locked(_)) => AuditDecision::RejectedLocked,
+            Error::Domain(DomainError::Duplicate(_)) => AuditDecision::RejectedDuplicate,
+            _ => AuditDecision::Rejected,
+        };
+        self.record(&transaction.into(), decision, Some(error), None);
+    }
+}
+
+/// When a [`RotatingFile`] is rotated, and what happens to old segments
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate once the file holds at least this many bytes. Defaults to no limit.
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file has been written to for this long. Defaults to no limit.
+    pub max_age: Option<Duration>,
+    /// How many rotated segments to keep; older ones are deleted. Defaults to none, so rotating
+    /// just starts the file again.
+    pub keep: usize,
+    /// Compress rotated segments with zstd
+    #[cfg(feature = "zstd")]
+    pub compress: bool,
+}
+
+/// A file of newline-delimited entries, such as an [`AuditLog`], that's rotated so it can't fill
+/// the disk.
+///
+/// Once the file reaches the [`Rotation`]'s size or age limit, it's renamed with a `.1` suffix
+/// (`audit.ndjson.1`), older segments move up one (to `.2`, `.3` and so on), and a new file is
+/// started. Segments beyond the retention count are deleted, and with the `zstd` feature, rotated
+/// segments can be compressed (to `audit.ndjson.1.zst`). Rotation only happens between lines,
+/// and only when something is written, so an idle file isn't rotated until its next entry.
+///
+/// Writes are buffered, so there's no need to wrap this in a [`BufWriter`].
+#[derive(Debug)]
+pub struct RotatingFile {
+    /// Path of the file being written
+    path: PathBuf,
+    /// When to rotate, and what to keep
+    rotation: Rotation,
+    /// The file being written
+    file: BufWriter<File>,
+    /// Bytes written to the file so far
+    written: u64,
+    /// When the file was started
+    started: Instant,
+    /// Where the file's age is measured by
+    clock: SharedClock,
+    /// Whether everything written so far ends with a newline
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    /// Creates a file at the supplied path, rotating away anything already there rather than
+    /// overwriting it
+    /// # Errors
+    /// If an existing file can't be rotated, or the file can't be created
+    pub fn create(path: impl Into<PathBuf>, rotation: Rotation) -> Result<Self, Error> {
+        let path = path.into();
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > 0) {
+            rotate(&path, &rotation)?;
+        }
+        Ok(Self {
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            rotation,
+            written: 0,
+            started: Instant::now(),
+            clock: SharedClock::default(),
+            at_line_start: true,
+        })
+    }
+
+    /// Measures the file's age for [`Rotation::max_age`] by the supplied clock, rather than the
+    /// system's
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = SharedClock::new(clock);
+        self.started = self.clock.instant();
+        self
+    }
+
+    /// Returns the path the supplied rotated segment is kept at, counting from 1 for the newest
+    #[must_use]
+    pub fn segment_path(&self, index: usize) -> PathBuf {
+        segment_path(&self.path, index, self.compressed())
+    }
+
+    /// Whether rotated segments are compressed
+    fn compressed(&self) -> bool {
+        #[cfg(feature = "zstd")]
+        return self.rotation.compress;
+        #[cfg(not(feature = "zstd"))]
+        false
+    }
+
+    /// Whether the file has reached its size or age limit
+    fn due(&self) -> bool {
+        self.written > 0
+            && (self
+                .rotation
+                .max_bytes
+                .is_some_and(|max| self.written >= max)
+                || self.rotation.max_age.is_some_and(|max| {
+                    self.clock.instant().saturating_duration_since(self.started) >= max
+                }))
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.due() {
+            self.file.flush()?;
+            rotate(&self.path, &self.rotation)?;
+            self.file = BufWriter::new(File::create(&self.path)?);
+            self.written = 0;
+            self.started = self.clock.instant();
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        if let Some(last) = buf[..written].last() {
+            self.at_line_start = *last == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Path of a rotated segment of the file at `path`
+pub(crate) fn segment_path(path: &Path, index: usize, compressed: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    if compressed {
+        name.push(".zst");
+    }
+    name.into()
+}
+
+/// Moves the file at `path` to the newest segment, making room by moving older segments up, and
+/// deleting any beyond the retention count
+fn rotate(path: &Path, rotation: &Rotation) -> io::Result<()> {
+    if rotation.keep == 0 {
+        return ignore_missing(fs::remove_file(path));
+    }
+    // Compression may have been switched on or off since older segments were written
+    for compressed in [false, true] {
+        ignore_missing(fs::remove_file(segment_path(
+            path,
+            rotation.keep,
+            compressed,
+        )))?;
+        for index in (1..rotation.keep).rev() {
+            ignore_missing(fs::rename(
+                segment_path(path, index, compressed),
+                segment_path(path, index + 1, compressed),
+            ))?;
+        }
+    }
+    let newest = segment_path(path, 1, false);
+    fs::rename(path, &newest)?;
+    #[cfg(feature = "zstd")]
+    if rotation.compress {
+        zstd::stream::copy_encode(
+            File::open(&newest)?,
+            File::create(segment_path(path, 1, true))?,
+            0,
+        )?;
+        fs::remove_file(&newest)?;
+    }
+    Ok(())
+}
+
+/// Treats a file that doesn't exist as success, for cleaning up files that may not be there
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// A single line of a dead-letter file
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    /// The transaction's type
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    /// The transaction's client
+    client: ClientId,
+    /// The transaction's ID
+    tx: TransactionId,
+    /// The transaction's amount, as given
+    amount: Option<Decimal>,
+    /// The [code](Error::code) of the error the transaction was rejected with
+    code: &'static str,
+    /// Why the transaction was rejected
+    reason: &'a str,
+}
+
+/// The writer behind a [`DeadLetterLog`] and its clones
+#[derive(Debug)]
+struct DeadLetterWriter<W: Write> {
+    /// Where dead letters are written
+    writer: csv::Writer<W>,
+    /// The first error hit while writing, after which nothing more is written
+    error: Option<csv::Error>,
+}
+
+/// Writes every row an engine skips or rejects to CSV, so they can be fixed and replayed.
+///
+/// Set a skipping [`ErrorPolicy`](crate::errors::ErrorPolicy), like
+/// [`SkipAndCollect`](crate::errors::SkipAndCollect), to carry on past failures.
+///
+/// Output has the same columns as transaction input, plus the error's stable
+/// [code](Error::code) and a human-readable `reason`, which are ignored when the file is loaded
+/// again:
+/// ```csv
+/// type,client,tx,amount,code,reason
+/// deposit,1,2,1.0,locked,Account id[1] is locked
+/// deposit,x,3,1.0,parse,"Error parsing input at line 3 (byte 38): field 1: invalid digit found in string. Record: deposit,x,3,1.0"
+/// ```
+/// Rows that couldn't be parsed are written as they were read, assuming the usual column order.
+///
+/// As with an [`AuditLog`], clones share the same output, so keep one to call
+/// [`finish`](Self::finish) with.
+#[derive(Debug)]
+pub struct DeadLetterLog<W: Write> {
+    /// Shared with every clone
+    inner: Arc<Mutex<DeadLetterWriter<W>>>,
+}
+
+impl<W: Write> Clone for DeadLetterLog<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> DeadLetterLog<W> {
+    /// Creates a dead-letter log writing to the supplied writer
+    #[must_use]
+    pub fn new(writer: W) -> Self {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        let error = writer
+            .write_record(["type", "client", "tx", "amount", "code", "reason"])
+            .err();
+        Self {
+            inner: Arc::new(Mutex::new(DeadLetterWriter { writer, error })),
+        }
+    }
+
+    /// Flushes everything written so far.
+    /// # Errors
+    /// If any row failed to be written (in which case nothing after it was written either), or
+    /// flushing fails
+    pub fn finish(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(err) = inner.error.take() {
+            return Err(err.into());
+        }
+        Ok(inner.writer.flush()?)
+    }
+
+    /// Writes a row with the writer, unless an earlier row failed
+    fn write<F>(&self, write_row: F)
+    where
+        F: FnOnce(&mut csv::Writer<W>) -> Result<(), csv::Error>,
+    {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.error.is_none() {
+            inner.error = write_row(&mut inner.writer).err();
+        }
+    }
+}
+
+impl<W: Write> EventListener for DeadLetterLog<W> {
+    fn on_rejected(&mut self, transaction: &TransactionRecord, error: &Error) {
+        let reason = error.to_string();
+        self.write(|writer| {
+            writer.serialize(DeadLetter {
+                transaction_type: transaction.transaction_type,
+                client: transaction.client_id,
+                tx: transaction.transaction_id,
+                amount: transaction.amount,
+                code: error.code(),
+                reason: &reason,
+            })
+        });
+    }
+
+    fn on_unparsed(&mut self, record: &ByteRecord, error: &Error) {
+        let reason = error.to_string();
+        // Padded or truncated to the four transaction columns, so the file stays loadable
+        let fields = (0..4).map(|index| record.get(index).unwrap_or_default());
+        self.write(|writer| {
+            writer.write_record(fields.chain([error.code().as_bytes(), reason.as_bytes()]))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        engine::Engine,
+        errors::SkipAndCollect,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_audit_decisions() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let audit_log = AuditLog::new(vec![]);
+        engine.add_listener(audit_log.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,9,
+dispute,1,1,
+chargeback,1,1,
+deposit,1,2,1.0
+";
+        assert!(engine
+            .load_named_csv("input.csv", &mut Cursor::new(input))
+            .is_err());
+        audit_log.finish().unwrap();
+        drop(engine);
+        let output = Arc::try_unwrap(audit_log.inner)
+            .unwrap()
+            .into_inner()
+            .unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(output.writer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let decisions: Vec<_> = lines.iter().map(|line| &line["decision"]).collect();
+        assert_eq!(
+            decisions,
+            [
+                "applied",
+                "ignored_missing_reference",
+                "applied",
+                "applied",
+                "rejected_locked"
+            ]
+        );
+        assert_eq!(lines[2]["held"], "5.0000");
+        assert_eq!(lines[3]["locked"], true);
+        assert_eq!(lines[4]["code"], "locked");
+        assert_eq!(lines[4]["reason"], "Account id[1] is locked");
+        assert_eq!(lines[4]["sequence"], 5);
+        assert!(lines[4].get("available").is_none());
+        assert_eq!(lines[4]["source"], "input.csv");
+        assert_eq!(lines[4]["line"], 6);
+    }
+
+    #[test]
+    fn test_audit_erasure() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let audit_log = AuditLog::new(vec![]).with_secret("secret");
+        engine.add_listener(audit_log.clone());
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\n";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let erasure = engine.erase_client(ClientId(1), b"seal").unwrap();
+        // Applied since, so not erased
+        let input = "type,client,tx,amount\ndeposit,1,3,2.0\n";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        audit_log.finish().unwrap();
+        let head = audit_log.head();
+        drop(engine);
+        let output = Arc::try_unwrap(audit_log.inner)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .writer;
+        let parse = |output: &[u8]| -> Vec<serde_json::Value> {
+            String::from_utf8(output.to_vec())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let lines = parse(&output);
+        assert_eq!(lines[2]["decision"], "erased");
+        assert_eq!(lines[2]["sequence"], 3);
+        assert_eq!(lines[2]["client"], 1);
+        assert_eq!(lines[2]["transactions"], 1);
+        assert_eq!(lines[2]["total"], "5.0000");
+        assert_eq!(lines[2]["seal"], erasure.seal);
+        // The erasure is part of the chain
+        assert_eq!(verify_chain(&output[..], Some(b"secret")).unwrap(), head);
+
+        let mut redacted = vec![];
+        assert_eq!(
+            redact_client(Cursor::new(&output), &mut redacted, ClientId(1), b"secret").unwrap(),
+            1
+        );
+        let redacted_lines = parse(&redacted);
+        let mut tombstone = RedactedEntry {
+            sequence: 1,
+            prev: GENESIS.to_string(),
+            decision: AuditDecision::Redacted,
+            client: ClientId(1),
+            digest: lines[1]["prev"].as_str().unwrap().to_string(),
+            seal: String::new(),
+        };
+        tombstone.seal = tombstone.seal(b"secret");
+        assert_eq!(redacted_lines[0], serde_json::to_value(&tombstone).unwrap());
+        assert_eq!(redacted_lines[1..], lines[1..]);
+        // The tombstone stands in for the entry, so the chain and its head are unchanged
+        assert_eq!(verify_chain(&redacted[..], Some(b"secret")).unwrap(), head);
+        // But can't be checked without the secret
+        let err = verify_chain(&redacted[..], None).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse(ParseError::BrokenChain { line: 1 })
+        ));
+        // Redacting again changes nothing
+        let mut again = vec![];
+        assert_eq!(
+            redact_client(Cursor::new(&redacted), &mut again, ClientId(1), b"secret").unwrap(),
+            0
+        );
+        assert_eq!(again, redacted);
+
+        // Tombstones can't be forged, nor made for a client who wasn't erased
+        let forge = |key: Option<&[u8]>| {
+            let mut tombstone = RedactedEntry {
+                sequence: 2,
+                prev: lines[1]["prev"].as_str().unwrap().to_string(),
+                decision: AuditDecision::Redacted,
+                client: ClientId(2),
+                digest: lines[2]["prev"].as_str().unwrap().to_string(),
+                seal: String::new(),
+            };
+            tombstone.seal = key.map_or_else(|| "0".repeat(64), |key| tombstone.seal(key));
+            let mut forged: Vec<String> = String::from_utf8(redacted.clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            forged[1] = serde_json::to_string(&tombstone).unwrap();
+            verify_chain(forged.join("\n").as_bytes(), Some(b"secret")).unwrap_err()
+        };
+        for key in [None, Some(&b"guess"[..]), Some(&b"secret"[..])] {
+            let err = forge(key);
+            assert!(matches!(
+                err,
+                Error::Parse(ParseError::BrokenChain { line: 2 })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let write = |secret: Option<&str>| {
+            let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+            let mut audit_log = AuditLog::new(vec![]);
+            if let Some(secret) = secret {
+                audit_log = audit_log.with_secret(secret);
+            }
+            engine.add_listener(audit_log.clone());
+            let input =
+                "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.0\ndispute,1,1,\n";
+            engine.load_csv(&mut Cursor::new(input)).unwrap();
+            audit_log.finish().unwrap();
+            let head = audit_log.head();
+            drop(engine);
+            let output = Arc::try_unwrap(audit_log.inner)
+                .unwrap()
+                .into_inner()
+                .unwrap();
+            (String::from_utf8(output.writer).unwrap(), head)
+        };
+        let broken_at = |result: Result<String, Error>| match result {
+            Err(Error::Parse(ParseError::BrokenChain { line })) => Some(line),
+            _ => None,
+        };
+
+        let (output, head) = write(None);
+        assert!(output.starts_with(&format!("{{\"sequence\":1,\"prev\":\"{GENESIS}\"")));
+        assert_eq!(verify_chain(output.as_bytes(), None).unwrap(), head);
+        let tampered = output.replacen("\"5.0\"", "\"50.0\"", 1);
+        assert_eq!(broken_at(verify_chain(tampered.as_bytes(), None)), Some(2));
+        let lines: Vec<_> = output.lines().collect();
+        let reordered = [lines[1], lines[0], lines[2]].join("\n");
+        assert_eq!(broken_at(verify_chain(reordered.as_bytes(), None)), Some(1));
+        let removed = [lines[0], lines[2]].join("\n");
+        assert_eq!(broken_at(verify_chain(removed.as_bytes(), None)), Some(2));
+        // Truncation leaves a valid chain, but not the one written
+        let truncated = lines[..2].join("\n");
+        assert_ne!(verify_chain(truncated.as_bytes(), None).unwrap(), head);
+        // The later lines check out on their own, from the head of those before them
+        let earlier = verify_chain(lines[0].as_bytes(), None).unwrap();
+        let later = lines[1..].join("\n");
+        assert_eq!(
+            verify_chain_from(later.as_bytes(), None, &earlier).unwrap(),
+            head
+        );
+        assert_eq!(broken_at(verify_chain(later.as_bytes(), None)), Some(1));
+        assert_eq!(
+            broken_at(verify_chain_from(later.as_bytes(), None, &head)),
+            Some(1)
+        );
+
+        let (output, head) = write(Some("secret"));
+        assert_eq!(
+            verify_chain(output.as_bytes(), Some(b"secret")).unwrap(),
+            head
+        );
+        assert_eq!(broken_at(verify_chain(output.as_bytes(), None)), Some(2));
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let directory =
+            std::env::temp_dir().join(format!("cashflow-rotate-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("audit.ndjson");
+        fs::write(&path, "from an earlier run\n").unwrap();
+        let rotation = Rotation {
+            max_bytes: Some(10),
+            keep: 2,
+            ..Rotation::default()
+        };
+        let mut file = RotatingFile::create(&path, rotation).unwrap();
+        assert_eq!(
+            fs::read_to_string(file.segment_path(1)).unwrap(),
+            "from an earlier run\n"
+        );
+        // Only rotated between lines, however the lines are written
+        for line in ["first entry\n", "second entry\n", "third entry\n"] {
+            let (start, end) = line.split_at(5);
+            file.write_all(start.as_bytes()).unwrap();
+            file.write_all(end.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        let read = |path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "third entry\n");
+        assert_eq!(read(file.segment_path(1)), "second entry\n");
+        assert_eq!(read(file.segment_path(2)), "first entry\n");
+        // The earlier run's file was pushed out, and nothing's kept beyond the retention count
+        assert!(!segment_path(&path, 3, false).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_rotating_file_compressed() {
+        let directory =
+            std::env::temp_dir().join(format!("cashflow-rotate-zstd-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("audit.ndjson");
+        let rotation = Rotation {
+            max_bytes: Some(1),
+            keep: 1,
+            compress: true,
+            ..Rotation::default()
+        };
+        let mut file = RotatingFile::create(&path, rotation).unwrap();
+        file.write_all(b"first entry\nsecond entry\n").unwrap();
+        file.write_all(b"third entry\n").unwrap();
+        file.flush().unwrap();
+        let segment = file.segment_path(1);
+        assert!(segment.to_string_lossy().ends_with(".1.zst"));
+        let decompressed = zstd::stream::decode_all(File::open(segment).unwrap()).unwrap();
+        assert_eq!(decompressed, b"first entry\nsecond entry\n");
+        assert!(!segment_path(&path, 1, false).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_dead_letters_replay() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.set_error_policy(SkipAndCollect::new());
+        let dead_letters = DeadLetterLog::new(vec![]);
+        engine.add_listener(dead_letters.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,x,2,1.0
+chargeback,2,3,
+deposit,2,3,2.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(engine.metrics().errors, 1);
+        dead_letters.finish().unwrap();
+        drop(engine);
+        let output = Arc::try_unwrap(dead_letters.inner)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .writer
+            .into_inner()
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("type,client,tx,amount,code,reason\ndeposit,x,2,1.0,parse,"));
+
+        // Once fixed, dead letters load like any other input
+        let fixed = output.replace("deposit,x,", "deposit,1,");
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.load_csv(&mut Cursor::new(fixed)).unwrap();
+        assert_eq!(engine.metrics().applied.deposits, 1);
+    }
+}
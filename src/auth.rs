@@ -0,0 +1,204 @@
+//! Who may do what through the [`Server`](crate::server::Server), so one endpoint surface can
+//! serve both the feeds producing transactions and the back-office staff looking after accounts.
+//!
+//! Every request other than the health probes and metrics is an
+//! [`Operation`](crate::auth::Operation), and each operation needs a
+//! [`Role`](crate::auth::Role). Before handling a request, the server
+//! asks its [`Authorizer`](crate::auth::Authorizer) whether the API key the request came with,
+//! sent as `Authorization: Bearer <key>`, may perform it. Requests without a key the authorizer
+//! knows get `401`, and those with a key lacking the role get `403`.
+//!
+//! Unlocking an account is the only [`Role::Admin`](crate::auth::Role::Admin) operation so far,
+//! since the engine has no balance adjustments, account merges or account closures to serve.
+//! [`Operation`](crate::auth::Operation) is `#[non_exhaustive]` so those can join it as
+//! admin operations once they exist, without breaking authorizers matching on it.
+//!
+//! [`ApiKeys`](crate::auth::ApiKeys) gives each key a set of roles:
+//! ```
+//! # use cashflow::auth::{ApiKeys, Authorizer, Operation, Role};
+//! let keys = ApiKeys::new()
+//!     .with_key("feed-secret", [Role::Producer])
+//!     .with_key("ops-secret", [Role::Reader, Role::Admin]);
+//! assert!(keys.authorize(Some("feed-secret"), Operation::SubmitTransactions).is_ok());
+//! assert!(keys.authorize(Some("feed-secret"), Operation::UnlockAccount).is_err());
+//! assert!(keys.authorize(Some("ops-secret"), Operation::UnlockAccount).is_ok());
+//! ```
+//! Set one with [`Server::set_authorizer`](crate::server::Server::set_authorizer). Until then,
+//! the server [allows everything](crate::auth::AllowAll), as it did before authorization existed,
+//! so only bind it to trusted networks.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    io::Read,
+};
+
+use serde::Deserialize;
+
+use crate::errors::Error;
+
+/// Something a request asks the server to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Apply transactions, with `POST /transactions`
+    SubmitTransactions,
+    /// Read accounts, with `GET /accounts`
+    ReadAccounts,
+    /// Unlock an account, with `POST /accounts/<client>/unlock`
+    UnlockAccount,
+}
+
+impl Operation {
+    /// Returns the role needed to perform the operation
+    #[must_use]
+    pub fn role(self) -> Role {
+        match self {
+            Self::SubmitTransactions => Role::Producer,
+            Self::ReadAccounts => Role::Reader,
+            Self::UnlockAccount => Role::Admin,
+        }
+    }
+}
+
+/// What a caller is trusted with. Roles don't imply each other, so a key that needs to both
+/// read and unlock accounts needs both roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Feeds submitting transactions
+    Producer,
+    /// Reading account balances
+    Reader,
+    /// Privileged changes to accounts outside the usual transactions, like unlocking them
+    Admin,
+}
+
+/// Why a request wasn't allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Denial {
+    /// There was no API key, or it isn't one the authorizer knows. Sent as `401`.
+    #[error("Unknown or missing API key")]
+    Unauthenticated,
+    /// The API key doesn't have the role the operation needs. Sent as `403`.
+    #[error("API key lacks the {0:?} role")]
+    Forbidden(Role),
+}
+
+/// Decides whether a request may perform an operation.
+///
+/// Set with [`Server::set_authorizer`](crate::server::Server::set_authorizer).
+pub trait Authorizer: Debug + Send {
+    /// Checks whether whoever holds the API key, or anyone if there isn't one, may perform the
+    /// operation
+    /// # Errors
+    /// Why the request isn't allowed, if it isn't
+    fn authorize(&self, api_key: Option<&str>, operation: Operation) -> Result<(), Denial>;
+}
+
+/// Allows every request, with or without a key. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _api_key: Option<&str>, _operation: Operation) -> Result<(), Denial> {
+        Ok(())
+    }
+}
+
+/// A fixed set of API keys, each with its own roles. Requests without one of the keys aren't
+/// allowed anything.
+#[derive(Default, Clone)]
+pub struct ApiKeys {
+    /// Roles by key
+    keys: HashMap<String, HashSet<Role>>,
+}
+
+impl ApiKeys {
+    /// Creates an authorizer with no keys, which denies every request until keys are added
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives a key the supplied roles, on top of any it already has
+    #[must_use]
+    pub fn with_key(
+        mut self,
+        key: impl Into<String>,
+        roles: impl IntoIterator<Item = Role>,
+    ) -> Self {
+        self.keys.entry(key.into()).or_default().extend(roles);
+        self
+    }
+
+    /// Reads keys from CSV with a `key` and a `role` column, one of `producer`, `reader` or
+    /// `admin`. Keys with several roles have a row for each.
+    /// # Errors
+    /// If the CSV can't be read, or a role isn't one of those
+    pub fn read_keys<R: Read>(reader: R) -> Result<Self, Error> {
+        let mut keys = Self::new();
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in csv_reader.deserialize() {
+            let row: KeyRow = row?;
+            keys = keys.with_key(row.key, [row.role]);
+        }
+        Ok(keys)
+    }
+}
+
+/// A row of a keys file
+#[derive(Deserialize)]
+struct KeyRow {
+    /// The API key
+    key: String,
+    /// One of the key's roles
+    role: Role,
+}
+
+impl Debug for ApiKeys {
+    // The keys are secrets, so only their number is shown
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeys")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+impl Authorizer for ApiKeys {
+    fn authorize(&self, api_key: Option<&str>, operation: Operation) -> Result<(), Denial> {
+        let roles = api_key
+            .and_then(|api_key| self.keys.get(api_key))
+            .ok_or(Denial::Unauthenticated)?;
+        let role = operation.role();
+        if roles.contains(&role) {
+            Ok(())
+        } else {
+            Err(Denial::Forbidden(role))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_keys() {
+        let input = "key,role\nfeed,producer\nops,reader\nops,admin\n";
+        let keys = ApiKeys::read_keys(input.as_bytes()).unwrap();
+        assert_eq!(keys.authorize(Some("ops"), Operation::ReadAccounts), Ok(()));
+        assert_eq!(
+            keys.authorize(Some("ops"), Operation::SubmitTransactions),
+            Err(Denial::Forbidden(Role::Producer))
+        );
+        assert_eq!(
+            keys.authorize(None, Operation::ReadAccounts),
+            Err(Denial::Unauthenticated)
+        );
+        assert_eq!(format!("{keys:?}"), "ApiKeys { keys: 2 }");
+        assert!(ApiKeys::read_keys("key,role\nops,root\n".as_bytes()).is_err());
+    }
+}
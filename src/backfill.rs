@@ -0,0 +1,337 @@
+//! Rebuilding state from archived transactions up to a cutoff, then handing over to the live feed
+//! without applying anything twice, eg after a snapshot turns out to be corrupt.
+//!
+//! [`Engine::backfill`](crate::engine::Engine::backfill) applies archived transactions, such as
+//! those written by a [`RetentionPolicy`](crate::retention::RetentionPolicy), up to a
+//! [`Cutoff`](crate::backfill::Cutoff), and ignores the rest. The live feed usually overlaps the
+//! archives, so the engine then catches up: transactions loaded afterwards are skipped until the
+//! first one past the cutoff, and everything from there on is applied as usual.
+//!
+//! The hand-over happens at the first transaction past the cutoff, in both the archives and the
+//! live feed, so both need to be in the same order. A dispute, resolve or chargeback refers to an
+//! earlier transaction rather than carrying an ID of its own, so one in the live feed before the
+//! hand-over is only skipped if the backfill applied the same one. Archives written by a
+//! retention policy only hold deposits and withdrawals, so disputes of them in the live feed are
+//! still applied, even those from before the cutoff.
+//!
+//! If the archives end before the cutoff, the live feed takes over earlier instead: at its first
+//! deposit or withdrawal that the backfill didn't apply, so nothing missing from the archives is
+//! skipped.
+//! ```
+//! # use cashflow::{backfill::Cutoff, engine::Engine, types::TransactionId};
+//! let archive = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,3.0\ndeposit,1,3,1.0\n";
+//! let live = "type,client,tx,amount\ndeposit,1,2,3.0\ndeposit,1,3,1.0\ndeposit,1,4,2.0\n";
+//! let mut engine = Engine::in_memory();
+//! let cutoff = Cutoff::Transaction(TransactionId::from(2));
+//! let backfill = engine.backfill([archive.as_bytes()], cutoff)?;
+//! assert_eq!(backfill.applied, 2);
+//! engine.load_csv(&mut live.as_bytes())?;
+//! // Transaction 2 was skipped, having been backfilled, and 3 was applied from the live feed
+//! assert_eq!(engine.metrics().applied.total(), 4);
+//! # Ok::<(), cashflow::errors::Error>(())
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use crate::types::{TransactionId, TransactionRecord, TransactionType};
+
+/// The last transaction a backfill applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cutoff {
+    /// Up to the deposit or withdrawal with this ID. Assumes IDs increase through the feed.
+    Transaction(TransactionId),
+    /// Up to the last transaction at or before this timestamp, in seconds since the Unix epoch.
+    /// Transactions without a timestamp go with those around them.
+    Timestamp(u64),
+}
+
+impl Cutoff {
+    /// Returns whether a transaction is the first past the cutoff
+    fn is_past(self, position: Position) -> bool {
+        match self {
+            Self::Transaction(cutoff) => position.own_id.is_some_and(|id| id > cutoff),
+            Self::Timestamp(cutoff) => position
+                .timestamp
+                .is_some_and(|timestamp| timestamp > cutoff),
+        }
+    }
+}
+
+/// Where a transaction falls in a feed, as far as a hand-over is concerned
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Position {
+    /// The transaction's ID, if it carries one of its own rather than referring to another
+    pub(crate) own_id: Option<TransactionId>,
+    /// When the transaction happened, if known
+    pub(crate) timestamp: Option<u64>,
+    /// The transaction's type and the ID it refers to, if it refers to another rather than
+    /// carrying an ID of its own
+    pub(crate) referral: Option<(TransactionType, TransactionId)>,
+}
+
+impl From<&TransactionRecord> for Position {
+    fn from(transaction: &TransactionRecord) -> Self {
+        let transaction_type = transaction.transaction_type();
+        let id = transaction.transaction_id();
+        let (own_id, referral) = match transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => (Some(id), None),
+            _ => (None, Some((transaction_type, id))),
+        };
+        Self {
+            own_id,
+            timestamp: transaction.timestamp(),
+            referral,
+        }
+    }
+}
+
+/// What came of a call to [`Engine::backfill`](crate::engine::Engine::backfill)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backfill {
+    /// How many archived transactions were applied, or rejected by the engine's rules
+    pub applied: u64,
+    /// How many archived transactions were past the cutoff, so were left for the live feed
+    pub skipped: u64,
+    /// Whether the archives went past the cutoff. If not, they may be missing transactions
+    /// from before it, so the live feed takes over at the first deposit or withdrawal the
+    /// backfill didn't apply.
+    pub reached_cutoff: bool,
+}
+
+/// Where an engine is in a backfill: applying archives up to the cutoff, or skipping the live
+/// feed until it's past it
+#[derive(Debug, Clone)]
+pub(crate) struct Handover {
+    /// Where archives end and the live feed takes over
+    cutoff: Cutoff,
+    /// Whether archives are being applied, rather than the live feed
+    backfilling: bool,
+    /// Whether a transaction past the cutoff has been seen
+    passed: bool,
+    /// Whether the archives went past the cutoff, once they've all been applied
+    reached_cutoff: bool,
+    /// The IDs of the deposits and withdrawals applied from the archives, for when they didn't
+    /// reach the cutoff
+    backfilled: HashSet<TransactionId>,
+    /// How many of each dispute, resolve and chargeback were applied from the archives, by type
+    /// and the transaction referred to, so only those the live feed repeats are skipped
+    referrals: HashMap<(TransactionType, TransactionId), u32>,
+    /// Transactions applied so far
+    applied: u64,
+    /// Transactions skipped so far
+    skipped: u64,
+}
+
+impl Handover {
+    /// Starts applying archives up to `cutoff`
+    pub(crate) fn new(cutoff: Cutoff) -> Self {
+        Self {
+            cutoff,
+            backfilling: true,
+            passed: false,
+            reached_cutoff: false,
+            backfilled: HashSet::new(),
+            referrals: HashMap::new(),
+            applied: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Returns whether a transaction should be applied, counting it either way
+    pub(crate) fn admit(&mut self, position: Position) -> bool {
+        let past = if self.backfilling || self.reached_cutoff {
+            self.cutoff.is_past(position)
+        } else {
+            // The archives stopped short, so take over at the first transaction they didn't have
+            position
+                .own_id
+                .is_some_and(|id| !self.backfilled.contains(&id))
+        };
+        self.passed = self.passed || past;
+        // Archives are applied until the cutoff, and the live feed from then on
+        let mut admit = self.passed != self.backfilling;
+        if let Some(referral) = position.referral {
+            if self.backfilling && admit {
+                *self.referrals.entry(referral).or_default() += 1;
+            } else if !self.backfilling && !admit {
+                // Skipped only as a repeat of one the backfill applied
+                admit = !self.take_referral(referral);
+            }
+        }
+        if admit {
+            self.applied += 1;
+            if let (true, Some(id)) = (self.backfilling, position.own_id) {
+                self.backfilled.insert(id);
+            }
+        } else {
+            self.skipped += 1;
+        }
+        admit
+    }
+
+    /// Counts off one of the disputes, resolves or chargebacks the backfill applied, returning
+    /// whether there was one left
+    fn take_referral(&mut self, referral: (TransactionType, TransactionId)) -> bool {
+        let Some(count) = self.referrals.get_mut(&referral) else {
+            return false;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.referrals.remove(&referral);
+        }
+        true
+    }
+
+    /// Returns whether the live feed has caught up, so the hand-over is done
+    pub(crate) fn is_live(&self) -> bool {
+        !self.backfilling && self.passed
+    }
+
+    /// Finishes applying archives, returning what was done, and starts catching up with the live
+    /// feed
+    pub(crate) fn finish_backfill(&mut self) -> Backfill {
+        let backfill = Backfill {
+            applied: self.applied,
+            skipped: self.skipped,
+            reached_cutoff: self.passed,
+        };
+        let backfilled = std::mem::take(&mut self.backfilled);
+        let referrals = std::mem::take(&mut self.referrals);
+        *self = Self {
+            backfilling: false,
+            reached_cutoff: backfill.reached_cutoff,
+            // Only needed if the archives didn't reach the cutoff
+            backfilled: if backfill.reached_cutoff {
+                HashSet::new()
+            } else {
+                backfilled
+            },
+            referrals,
+            ..Self::new(self.cutoff)
+        };
+        backfill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{ClientId, MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_backfill_timestamp() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        // The archive is split in two, and the dispute without a timestamp goes with the
+        // deposit before it
+        let archives = [
+            "type,client,tx,amount,timestamp\ndeposit,1,1,5.0,100\n",
+            "type,client,tx,amount,timestamp
+deposit,1,2,3.0,200
+dispute,1,2,,
+deposit,1,3,1.0,300
+",
+        ];
+        let backfill = engine
+            .backfill(archives.map(Cursor::new), Cutoff::Timestamp(200))
+            .unwrap();
+        assert_eq!(
+            backfill,
+            Backfill {
+                applied: 3,
+                skipped: 1,
+                reached_cutoff: true,
+            }
+        );
+        assert!(engine.is_catching_up());
+
+        // The live feed starts before the cutoff
+        let live = "type,client,tx,amount,timestamp
+deposit,1,2,3.0,200
+dispute,1,2,,
+deposit,1,3,1.0,300
+resolve,1,2,,
+";
+        engine.load_csv(&mut Cursor::new(live)).unwrap();
+        assert!(!engine.is_catching_up());
+        let account = &engine.account_book().accounts[&ClientId(1)];
+        assert_eq!(account.funds_available(), dec!(9));
+        assert_eq!(account.funds_held(), dec!(0));
+        assert_eq!(engine.metrics().applied.total(), 5);
+
+        // Caught up, so nothing more is skipped
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndeposit,1,4,1.0\n"))
+            .unwrap();
+        assert_eq!(engine.metrics().applied.total(), 6);
+    }
+
+    #[test]
+    fn test_backfill_dispute_across_cutoff() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        // As archived by a retention policy, with only deposits and withdrawals
+        let archive = "type,client,tx,amount,timestamp
+deposit,1,1,5.0,100
+deposit,1,2,3.0,200
+deposit,1,3,1.0,300
+";
+        engine
+            .backfill([Cursor::new(archive)], Cutoff::Timestamp(200))
+            .unwrap();
+        // Transaction 1 was disputed before the cutoff, which the archive doesn't have, so the
+        // live feed's dispute is applied, though the deposits around it are skipped
+        let live = "type,client,tx,amount,timestamp
+deposit,1,1,5.0,100
+deposit,1,2,3.0,200
+dispute,1,1,,200
+deposit,1,3,1.0,300
+";
+        engine.load_csv(&mut Cursor::new(live)).unwrap();
+        assert!(!engine.is_catching_up());
+        let account = &engine.account_book().accounts[&ClientId(1)];
+        assert_eq!(account.funds_available(), dec!(4));
+        assert_eq!(account.funds_held(), dec!(5));
+        // So it can be resolved
+        engine
+            .load_csv(&mut Cursor::new(
+                "type,client,tx,amount
+resolve,1,1,
+",
+            ))
+            .unwrap();
+        assert_eq!(
+            engine.account_book().accounts[&ClientId(1)].funds_held(),
+            dec!(0)
+        );
+    }
+
+    #[test]
+    fn test_backfill_short() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let archive = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        let backfill = engine
+            .backfill(
+                [Cursor::new(archive)],
+                Cutoff::Transaction(TransactionId(2)),
+            )
+            .unwrap();
+        // The archive stops short of the cutoff, so the live feed takes over at the first
+        // transaction it didn't have, rather than losing those up to the cutoff
+        assert!(!backfill.reached_cutoff);
+        let live = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,3.0\ndeposit,1,3,1.0\n";
+        engine.load_csv(&mut Cursor::new(live)).unwrap();
+        assert!(!engine.is_catching_up());
+        assert_eq!(
+            engine.account_book().accounts[&ClientId(1)].funds_available(),
+            dec!(9)
+        );
+        assert_eq!(engine.metrics().applied.total(), 3);
+    }
+}
@@ -0,0 +1,140 @@
+//! Where the engine and its listeners get the time from, so anything that depends on it can be
+//! tested deterministically.
+//!
+//! A [`Clock`](crate::clock::Clock) tells the wall-clock time, for timestamps and ages, and a
+//! monotonic [`Instant`](std::time::Instant), for measuring durations.
+//! [`SystemClock`](crate::clock::SystemClock) asks the operating system, and is what everything
+//! uses unless told otherwise. [`MockClock`](crate::clock::MockClock) only moves when it's
+//! [advanced](crate::clock::MockClock::advance), so tests can step through rate limits, dispute
+//! ages and sampling intervals without waiting:
+//! ```
+//! # use std::time::{Duration, UNIX_EPOCH};
+//! # use cashflow::clock::{Clock, MockClock};
+//! let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+//! let started = clock.instant();
+//! clock.advance(Duration::from_secs(90));
+//! assert_eq!(clock.instant() - started, Duration::from_secs(90));
+//! ```
+//! Hand a clock to an [`Engine`](crate::engine::Engine) with `set_clock`, and to listeners that
+//! keep time with their `with_clock` methods. Clones of a `MockClock` share the same time, so keep
+//! one to advance.
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A source of the current time
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current wall-clock time
+    fn now(&self) -> SystemTime;
+
+    /// Returns the current monotonic time, for measuring how long something took
+    fn instant(&self) -> Instant;
+
+    /// Waits until `duration` has passed on this clock, eg for a rate limit to allow another
+    /// transaction.
+    ///
+    /// The default implementation puts the thread to sleep.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// The operating system's clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it's advanced, for tests.
+///
+/// Clones share the same time. Sleeping on it advances it, rather than waiting.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    /// The wall-clock time it was created at
+    start: SystemTime,
+    /// A real instant taken when it was created, to offset monotonic times from
+    base: Instant,
+    /// How far it's been advanced since it was created
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    /// Creates a clock showing the supplied wall-clock time
+    #[must_use]
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            base: Instant::now(),
+            elapsed: Arc::default(),
+        }
+    }
+
+    /// Moves the clock, and every clone of it, forward
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+
+    /// Returns how far the clock has been advanced since it was created
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+
+    fn instant(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// A [`Clock`] that can be cloned and shared between the things keeping time. The
+/// [`SystemClock`] by default.
+#[derive(Debug, Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    /// Wraps a clock so it can be shared
+    #[must_use]
+    pub fn new<C: Clock + 'static>(clock: C) -> Self {
+        Self(Arc::new(clock))
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    fn instant(&self) -> Instant {
+        self.0.instant()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration);
+    }
+}
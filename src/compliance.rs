@@ -0,0 +1,328 @@
+//! Flags clients moving enough money in a short time that a regulatory filing may be due, like a
+//! currency transaction report, for compliance to review.
+//!
+//! A [`ThresholdMonitor`](crate::compliance::ThresholdMonitor) adds up each client's deposits, and
+//! separately their withdrawals, over a rolling window. When either total reaches its
+//! [threshold](crate::compliance::FilingThresholds), the client becomes a
+//! [`FilingCandidate`](crate::compliance::FilingCandidate), with the transactions that got it
+//! there, so several smaller amounts adding up count as much as one large one. A client is only
+//! flagged again once the total has dropped back below the threshold, as transactions leave the
+//! window, and then reached it again.
+//!
+//! Transactions are placed in time by their timestamp, if they have one, or otherwise by when
+//! they're applied. Nothing is rejected: flagged transactions are applied like any other.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    clock::{Clock, SharedClock},
+    errors::Error,
+    events::EventListener,
+    types::{Account, ClientId, TransactionId, TransactionRecord, TransactionType},
+};
+
+/// How much money a client can move within the window before a filing may be due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilingThresholds {
+    /// Flag clients whose deposits within the window add up to at least this, if set. Defaults
+    /// to 10,000.
+    pub deposits: Option<Decimal>,
+    /// Flag clients whose withdrawals within the window add up to at least this, if set.
+    /// Defaults to 10,000.
+    pub withdrawals: Option<Decimal>,
+    /// How far back deposits and withdrawals are added up. Defaults to a day.
+    pub window: Duration,
+}
+
+impl Default for FilingThresholds {
+    fn default() -> Self {
+        Self {
+            deposits: Some(Decimal::from(10_000)),
+            withdrawals: Some(Decimal::from(10_000)),
+            window: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Which way the money went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Into the account, by deposits
+    Deposits,
+    /// Out of the account, by withdrawals
+    Withdrawals,
+}
+
+/// A client whose deposits or withdrawals within the window reached the threshold
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FilingCandidate {
+    /// The client flagged
+    pub client: ClientId,
+    /// Whether deposits or withdrawals reached the threshold
+    pub direction: Direction,
+    /// What they added up to, once the threshold was reached
+    pub total: Decimal,
+    /// The threshold reached
+    pub threshold: Decimal,
+    /// When the earliest transaction in the window happened, in seconds since the Unix epoch
+    pub from: u64,
+    /// When the transaction that reached the threshold happened, in seconds since the Unix epoch
+    pub to: u64,
+    /// The transactions adding up to the total, in the order they were applied
+    pub transactions: Vec<TransactionId>,
+}
+
+/// The candidates in a JSON report
+#[derive(Debug, Serialize)]
+struct Candidates<'a> {
+    /// Every candidate, in the order they were flagged
+    candidates: &'a [FilingCandidate],
+}
+
+/// A deposit or withdrawal still within the window
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// When it happened, in seconds since the Unix epoch
+    time: u64,
+    /// The transaction
+    transaction: TransactionId,
+    /// Its amount
+    amount: Decimal,
+}
+
+/// The totals and findings behind a [`ThresholdMonitor`] and its clones
+#[derive(Debug)]
+struct MonitorState {
+    /// When to flag a client
+    thresholds: FilingThresholds,
+    /// Each client's deposits or withdrawals within the window, by client and direction
+    windows: HashMap<(ClientId, Direction), Vec<Entry>>,
+    /// Everything flagged so far, in order
+    candidates: Vec<FilingCandidate>,
+}
+
+/// Watches deposits and withdrawals as an engine applies them, flagging clients whose totals
+/// within a rolling window reach the [`FilingThresholds`].
+///
+/// As with an [`AnomalyDetector`](crate::anomaly::AnomalyDetector), clones share the same
+/// findings, so keep one to call [`report`](Self::report) on after registering another with the
+/// engine.
+#[derive(Debug, Clone)]
+pub struct ThresholdMonitor {
+    /// Shared with clones
+    inner: Arc<Mutex<MonitorState>>,
+    /// Places transactions without a timestamp in time
+    clock: SharedClock,
+}
+
+impl ThresholdMonitor {
+    /// Creates a monitor flagging clients according to the supplied thresholds
+    #[must_use]
+    pub fn new(thresholds: FilingThresholds) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(MonitorState {
+                thresholds,
+                windows: HashMap::new(),
+                candidates: Vec::new(),
+            })),
+            clock: SharedClock::default(),
+        }
+    }
+
+    /// Places transactions without a timestamp in time by the supplied clock, rather than the
+    /// system's
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Returns every client flagged so far, in the order they were flagged
+    #[must_use]
+    pub fn report(&self) -> Vec<FilingCandidate> {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .candidates
+            .clone()
+    }
+
+    /// Writes every client flagged so far as a single JSON document, like
+    /// `{"candidates":[{"client":1,"direction":"deposits","total":"12000","threshold":"10000",...}]}`
+    /// # Errors
+    /// If writing fails
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let candidates = self.report();
+        serde_json::to_writer(
+            &mut *writer,
+            &Candidates {
+                candidates: &candidates,
+            },
+        )
+        .map_err(std::io::Error::from)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl EventListener for ThresholdMonitor {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        let direction = match transaction.transaction_type() {
+            TransactionType::Deposit => Direction::Deposits,
+            TransactionType::Withdrawal => Direction::Withdrawals,
+            _ => return,
+        };
+        let Some(amount) = transaction.amount() else {
+            return;
+        };
+        let time = transaction.timestamp().unwrap_or_else(|| {
+            self.clock
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *inner;
+        let threshold = match direction {
+            Direction::Deposits => inner.thresholds.deposits,
+            Direction::Withdrawals => inner.thresholds.withdrawals,
+        };
+        let Some(threshold) = threshold else {
+            return;
+        };
+        let window = inner.thresholds.window.as_secs();
+        let client = transaction.client_id();
+        let entries = inner.windows.entry((client, direction)).or_default();
+        // Timestamps can go back in time, so entries aren't necessarily in time order
+        entries.retain(|entry| entry.time.saturating_add(window) > time);
+        // Amounts can add up to more than a Decimal holds even though no balance does, eg
+        // money deposited, withdrawn and deposited again
+        let before = entries
+            .iter()
+            .fold(Decimal::ZERO, |sum, entry| sum.saturating_add(entry.amount));
+        entries.push(Entry {
+            time,
+            transaction: transaction.transaction_id(),
+            amount: amount.abs(),
+        });
+        let total = before.saturating_add(amount.abs());
+        if before >= threshold || total < threshold {
+            return;
+        }
+        inner.candidates.push(FilingCandidate {
+            client,
+            direction,
+            total,
+            threshold,
+            from: entries.iter().map(|entry| entry.time).min().unwrap_or(time),
+            to: time,
+            transactions: entries.iter().map(|entry| entry.transaction).collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::SystemTime};
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        clock::MockClock,
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_threshold_monitor() {
+        let thresholds = FilingThresholds {
+            deposits: Some(dec!(100)),
+            withdrawals: None,
+            window: Duration::from_secs(60),
+        };
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let monitor = ThresholdMonitor::new(thresholds).with_clock(clock);
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(monitor.clone());
+        // Deposits adding up to the threshold, a withdrawal that isn't watched, and another
+        // client below the threshold
+        let input = "type,client,tx,amount
+deposit,1,1,60
+deposit,2,2,99
+withdrawal,1,3,500
+deposit,1,4,40
+deposit,1,5,10
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let candidates = monitor.report();
+        assert_eq!(
+            candidates,
+            [FilingCandidate {
+                client: ClientId(1),
+                direction: Direction::Deposits,
+                total: dec!(100),
+                threshold: dec!(100),
+                from: 1_000,
+                to: 1_000,
+                transactions: vec![TransactionId(1), TransactionId(4)],
+            }]
+        );
+
+        // Once the earlier deposits have left the window, the client can be flagged again.
+        // Timestamps take precedence over the clock.
+        let input = "type,client,tx,amount,timestamp
+deposit,1,6,50,1030
+deposit,1,7,30,1065
+deposit,1,8,20,1070
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let candidates = monitor.report();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[1].total, dec!(100));
+        assert_eq!(
+            candidates[1].transactions,
+            [TransactionId(6), TransactionId(7), TransactionId(8)]
+        );
+        assert_eq!((candidates[1].from, candidates[1].to), (1_030, 1_070));
+
+        let mut json = vec![];
+        monitor.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["candidates"][0]["direction"], "deposits");
+        assert_eq!(json["candidates"][0]["transactions"][1], 4);
+    }
+
+    #[test]
+    fn test_threshold_monitor_near_max() {
+        let thresholds = FilingThresholds {
+            deposits: Some(Decimal::MAX),
+            withdrawals: None,
+            window: Duration::from_secs(60),
+        };
+        let monitor = ThresholdMonitor::new(thresholds);
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(monitor.clone());
+        // The account never leaves range, but the deposits add up to more than a Decimal holds
+        let input = "type,client,tx,amount
+deposit,1,1,40000000000000000000000000000
+withdrawal,1,2,40000000000000000000000000000
+deposit,1,3,40000000000000000000000000000
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let candidates = monitor.report();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].total, Decimal::MAX);
+    }
+}
@@ -0,0 +1,387 @@
+//! Checking an account book against the transaction log it was built from, to catch storage
+//! backends that have drifted apart, eg after a partial write or a restore of only one of them.
+//!
+//! A [`ConsistencyCheck`](crate::consistency::ConsistencyCheck) works out each client's expected
+//! total from the deposits and withdrawals in a [`TransactionLog`](crate::types::TransactionLog),
+//! and compares it with the total stored in their account.
+//! [`Engine::check_consistency`](crate::engine::Engine::check_consistency) and `cashflow check`
+//! are built on it.
+//!
+//! Disputes and resolves only move funds between available and held, so don't change the total,
+//! and aren't registered in the log. Chargebacks aren't either, but take funds out, so the funds
+//! charged back from each client need passing to
+//! [`with_chargebacks`](crate::consistency::ConsistencyCheck::with_chargebacks); an engine keeps
+//! track of [those it applied](crate::engine::Engine::charged_back). Held funds can't be checked
+//! at all.
+//!
+//! Totals are added up with checked arithmetic, in whatever order the log yields transactions, so
+//! a client whose transactions add up to more than a [`Decimal`](rust_decimal::Decimal) holds
+//! part way through is [overflowed](crate::consistency::ConsistencyReport::overflowed), and left
+//! unchecked, rather than reported as drift.
+//!
+//! The log needs to hold every transaction since the accounts' opening balances, if any. Accounts
+//! restored from a baseline need those balances passed to
+//! [`with_opening`](crate::consistency::ConsistencyCheck::with_opening), which `cashflow check`
+//! does with `--baseline`. An engine carries forward what the transactions it purged, by
+//! retention or erasure, came to, and starts those clients from it.
+//! ```
+//! # use cashflow::{engine::Engine, types::ClientId};
+//! let mut engine = Engine::in_memory();
+//! let input = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5\n";
+//! engine.load_csv(&mut input.as_bytes())?;
+//! assert!(engine.check_consistency().is_consistent());
+//! # Ok::<(), cashflow::errors::Error>(())
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    locale::to_scale,
+    types::{Account, ClientId, TransactionRecord, TransactionType, DECIMAL_SCALE},
+};
+
+/// How many drifted and overflowed accounts are listed when a [`ConsistencyReport`] is displayed
+const LISTED: usize = 10;
+
+/// A client whose stored total isn't what their transactions add up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Drift {
+    /// The client
+    pub client: ClientId,
+    /// What the opening balance, registered transactions and chargebacks add up to
+    pub expected: Decimal,
+    /// The account's total, or `None` if there's no account
+    pub stored: Option<Decimal>,
+    /// Whether the account is locked
+    pub locked: bool,
+}
+
+impl Drift {
+    /// Returns how much more is stored than expected, or less if negative
+    #[must_use]
+    pub fn difference(&self) -> Decimal {
+        self.stored.unwrap_or_default() - self.expected
+    }
+}
+
+/// What a [`ConsistencyCheck`] found
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConsistencyReport {
+    /// How many clients were checked, with an account, transactions or both
+    pub clients: usize,
+    /// Clients whose stored total isn't what their transactions add up to, in client order
+    pub drifts: Vec<Drift>,
+    /// Clients whose transactions add up to more than a [`Decimal`] can hold, so couldn't be
+    /// checked, in client order
+    pub overflowed: Vec<ClientId>,
+}
+
+impl ConsistencyReport {
+    /// Returns whether every stored total matched the log, apart from any that overflowed
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        self.drifts.is_empty()
+    }
+
+    /// Counts a client, and files them as drifted if their totals differ
+    fn push(&mut self, drift: Drift) {
+        self.clients += 1;
+        if drift.stored != Some(drift.expected) {
+            self.drifts.push(drift);
+        }
+    }
+}
+
+impl Display for ConsistencyReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} clients checked", self.clients)?;
+        writeln!(f, "drifted: {}", self.drifts.len())?;
+        for drift in self.drifts.iter().take(LISTED) {
+            let stored = drift.stored.map_or_else(
+                || "no account".to_string(),
+                |stored| to_scale(stored, DECIMAL_SCALE),
+            );
+            writeln!(
+                f,
+                "  client {}: expected {}, stored {stored}",
+                drift.client.0,
+                to_scale(drift.expected, DECIMAL_SCALE)
+            )?;
+        }
+        if self.drifts.len() > LISTED {
+            writeln!(f, "  and {} more", self.drifts.len() - LISTED)?;
+        }
+        writeln!(f, "overflowed: {}", self.overflowed.len())?;
+        for client in self.overflowed.iter().take(LISTED) {
+            writeln!(f, "  client {}", client.0)?;
+        }
+        if self.overflowed.len() > LISTED {
+            writeln!(f, "  and {} more", self.overflowed.len() - LISTED)?;
+        }
+        Ok(())
+    }
+}
+
+/// Works out what each client's total should be from a transaction log, to compare with the
+/// account book
+#[derive(Debug, Clone)]
+pub struct ConsistencyCheck {
+    /// The account book's scale, which amounts are rounded to as they're applied
+    scale: u32,
+    /// Each client's total before the first transaction in the log
+    opening: BTreeMap<ClientId, Decimal>,
+    /// Funds charged back from each client since then
+    charged_back: BTreeMap<ClientId, Decimal>,
+}
+
+impl Default for ConsistencyCheck {
+    fn default() -> Self {
+        Self::new(DECIMAL_SCALE)
+    }
+}
+
+impl ConsistencyCheck {
+    /// Creates a check of an account book with the supplied
+    /// [scale](crate::types::AccountBook::scale), whose accounts all started empty
+    #[must_use]
+    pub fn new(scale: u32) -> Self {
+        Self {
+            scale,
+            opening: BTreeMap::new(),
+            charged_back: BTreeMap::new(),
+        }
+    }
+
+    /// Starts the supplied accounts from their totals, rather than from zero, eg those of a
+    /// baseline the log's transactions were applied on top of
+    #[must_use]
+    pub fn with_opening<'a, A>(mut self, accounts: A) -> Self
+    where
+        A: IntoIterator<Item = &'a Account>,
+    {
+        self.opening.extend(
+            accounts
+                .into_iter()
+                .map(|account| (account.client_id(), account.total())),
+        );
+        self
+    }
+
+    /// Starts the supplied clients from the supplied totals, rather than from zero, eg those an
+    /// [engine](crate::engine::Engine::consistency_check) carried forward from transactions it
+    /// purged
+    #[must_use]
+    pub fn with_opening_totals<O>(mut self, totals: O) -> Self
+    where
+        O: IntoIterator<Item = (ClientId, Decimal)>,
+    {
+        self.opening.extend(totals);
+        self
+    }
+
+    /// Takes the funds charged back from each client out of what their transactions add up to,
+    /// eg from [`Engine::charged_back`](crate::engine::Engine::charged_back), since chargebacks
+    /// aren't registered in the log
+    #[must_use]
+    pub fn with_chargebacks<C>(mut self, charged_back: C) -> Self
+    where
+        C: IntoIterator<Item = (ClientId, Decimal)>,
+    {
+        self.charged_back.extend(charged_back);
+        self
+    }
+
+    /// Compares the total of each account with what the transactions add up to
+    #[must_use]
+    pub fn check<'a, A, T>(&self, accounts: A, transactions: T) -> ConsistencyReport
+    where
+        A: IntoIterator<Item = &'a Account>,
+        T: IntoIterator<Item = TransactionRecord>,
+    {
+        // `None` once a client's total has overflowed
+        let mut expected: BTreeMap<ClientId, Option<Decimal>> = self
+            .opening
+            .iter()
+            .map(|(&client, &total)| (client, Some(total)))
+            .collect();
+        for transaction in transactions {
+            let Some(mut amount) = transaction.amount() else {
+                continue;
+            };
+            // Rounded the same way as when it was applied
+            amount.rescale(self.scale);
+            let total = expected
+                .entry(transaction.client_id())
+                .or_insert(Some(Decimal::ZERO));
+            *total = match transaction.transaction_type() {
+                TransactionType::Deposit => total.and_then(|total| total.checked_add(amount)),
+                TransactionType::Withdrawal => total.and_then(|total| total.checked_sub(amount)),
+                _ => *total,
+            };
+        }
+        for (&client, &charged_back) in &self.charged_back {
+            let total = expected.entry(client).or_insert(Some(Decimal::ZERO));
+            *total = total.and_then(|total| total.checked_sub(charged_back));
+        }
+        let mut report = ConsistencyReport::default();
+        let mut stored: BTreeMap<ClientId, &Account> = accounts
+            .into_iter()
+            .map(|account| (account.client_id(), account))
+            .collect();
+        for (client, expected) in expected {
+            let account = stored.remove(&client);
+            let Some(expected) = expected else {
+                report.clients += 1;
+                report.overflowed.push(client);
+                continue;
+            };
+            report.push(Drift {
+                client,
+                expected,
+                stored: account.map(Account::total),
+                locked: account.is_some_and(Account::is_locked),
+            });
+        }
+        // Accounts without transactions should be empty
+        for (client, account) in stored {
+            report.push(Drift {
+                client,
+                expected: Decimal::ZERO,
+                stored: Some(account.total()),
+                locked: account.is_locked(),
+            });
+        }
+        report.drifts.sort_unstable_by_key(|drift| drift.client);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::types::{AccountBuilder, TransactionId};
+
+    use super::*;
+
+    #[test]
+    fn test_consistency_check() {
+        let record = |transaction_type, client, transaction, amount| {
+            TransactionRecord::new(
+                transaction_type,
+                ClientId(client),
+                TransactionId(transaction),
+                Some(amount),
+            )
+        };
+        let transactions = [
+            record(TransactionType::Deposit, 1, 1, dec!(5)),
+            record(TransactionType::Withdrawal, 1, 2, dec!(1.5)),
+            record(TransactionType::Deposit, 2, 3, dec!(2)),
+            record(TransactionType::Deposit, 3, 4, dec!(4)),
+            record(TransactionType::Deposit, 4, 5, dec!(1)),
+        ];
+        let accounts = [
+            // Matches, with funds held for a dispute
+            AccountBuilder::new(ClientId(1))
+                .available(dec!(1.5))
+                .held(dec!(2))
+                .build()
+                .unwrap(),
+            // Drifted
+            AccountBuilder::new(ClientId(2))
+                .available(dec!(3))
+                .build()
+                .unwrap(),
+            // Charged back, then unlocked
+            AccountBuilder::new(ClientId(3)).build().unwrap(),
+            // Started from a baseline
+            AccountBuilder::new(ClientId(5))
+                .available(dec!(7))
+                .build()
+                .unwrap(),
+            // No transactions at all
+            AccountBuilder::new(ClientId(6))
+                .available(dec!(1))
+                .build()
+                .unwrap(),
+        ];
+        let report = ConsistencyCheck::default()
+            .with_opening(&accounts[3..4])
+            .with_chargebacks([(ClientId(3), dec!(4))])
+            .check(&accounts, transactions);
+        assert_eq!(report.clients, 6);
+        assert!(!report.is_consistent());
+        assert_eq!(
+            report.drifts,
+            [
+                Drift {
+                    client: ClientId(2),
+                    expected: dec!(2),
+                    stored: Some(dec!(3)),
+                    locked: false,
+                },
+                Drift {
+                    client: ClientId(4),
+                    expected: dec!(1),
+                    stored: None,
+                    locked: false,
+                },
+                Drift {
+                    client: ClientId(6),
+                    expected: dec!(0),
+                    stored: Some(dec!(1)),
+                    locked: false,
+                },
+            ]
+        );
+        assert_eq!(report.drifts[0].difference(), dec!(1));
+        assert!(report.overflowed.is_empty());
+        let display = report.to_string();
+        assert!(display.contains("client 4: expected 1.0000, stored no account"));
+    }
+
+    #[test]
+    fn test_consistency_check_overflow() {
+        // Each deposit fits, but not both, whichever order they're added up in
+        let transactions = [1, 2].map(|transaction| {
+            TransactionRecord::new(
+                TransactionType::Deposit,
+                ClientId(1),
+                TransactionId(transaction),
+                Some(Decimal::MAX - Decimal::ONE),
+            )
+        });
+        let accounts = [AccountBuilder::new(ClientId(1)).build().unwrap()];
+        let report = ConsistencyCheck::new(0).check(&accounts, transactions);
+        assert_eq!(report.clients, 1);
+        assert!(report.is_consistent());
+        assert_eq!(report.overflowed, [ClientId(1)]);
+    }
+
+    #[test]
+    fn test_engine_chargebacks() {
+        let mut engine = crate::engine::Engine::in_memory();
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,2.0
+dispute,1,2,
+chargeback,1,2,
+";
+        engine.load_csv(&mut input.as_bytes()).unwrap();
+        // Still consistent once the charged back account is unlocked
+        assert_eq!(engine.unlock(ClientId(1)).unwrap(), Some(true));
+        let report = engine.check_consistency();
+        assert!(report.is_consistent(), "{report}");
+        assert_eq!(
+            engine.charged_back().collect::<Vec<_>>(),
+            [(ClientId(1), dec!(2))]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! Comparing two account books, eg the same input run through two engine versions, or a book
+//! against a snapshot from an earlier run.
+//!
+//! An [`AccountBookDiff`](crate::diff::AccountBookDiff) takes the accounts of each side, from
+//! anything that iterates over them: a [`MemoryAccountBook`](crate::types::MemoryAccountBook), or
+//! the values of a report read back with
+//! [`read_accounts_from_csv`](crate::io::read_accounts_from_csv). It lists the accounts created
+//! and removed, and for the rest, how their balances and lock state changed.
+//! [`io::diff_reports`](crate::io::diff_reports) and `cashflow diff` are built on it.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    io::{Discrepancy, DiscrepancyKind},
+    types::{Account, ClientId},
+};
+
+/// The state of an account on one side of an [`AccountBookDiff`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Balances {
+    /// Funds available
+    pub available: Decimal,
+    /// Funds held for disputes
+    pub held: Decimal,
+    /// Whether the account is locked
+    pub locked: bool,
+}
+
+impl Balances {
+    /// Returns the account's available and held funds combined
+    #[must_use]
+    pub fn total(&self) -> Decimal {
+        self.available.saturating_add(self.held)
+    }
+}
+
+impl From<&Account> for Balances {
+    fn from(account: &Account) -> Self {
+        Self {
+            available: account.funds_available(),
+            held: account.funds_held(),
+            locked: account.is_locked(),
+        }
+    }
+}
+
+/// An account that's on both sides of an [`AccountBookDiff`], but differs between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AccountChange {
+    /// The client whose account changed
+    pub client: ClientId,
+    /// The account in the first book
+    pub before: Balances,
+    /// The account in the second book
+    pub after: Balances,
+}
+
+impl AccountChange {
+    /// Returns how much available funds went up by, or down by if negative
+    #[must_use]
+    pub fn available_delta(&self) -> Decimal {
+        self.after.available.saturating_sub(self.before.available)
+    }
+
+    /// Returns how much held funds went up by, or down by if negative
+    #[must_use]
+    pub fn held_delta(&self) -> Decimal {
+        self.after.held.saturating_sub(self.before.held)
+    }
+
+    /// Returns how much the total balance went up by, or down by if negative
+    #[must_use]
+    pub fn total_delta(&self) -> Decimal {
+        self.after.total().saturating_sub(self.before.total())
+    }
+
+    /// Returns whether the account is locked in the second book, if that's changed
+    #[must_use]
+    pub fn lock_change(&self) -> Option<bool> {
+        (self.before.locked != self.after.locked).then_some(self.after.locked)
+    }
+}
+
+/// Everything that differs between two account books. Amounts are compared by value, so `1.5`
+/// and `1.5000` are the same.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AccountBookDiff {
+    /// Accounts only in the second book, with their balances there, in client order
+    pub created: Vec<(ClientId, Balances)>,
+    /// Accounts only in the first book, with their balances there, in client order
+    pub removed: Vec<(ClientId, Balances)>,
+    /// Accounts in both books whose balances or lock state differ, in client order
+    pub changed: Vec<AccountChange>,
+}
+
+impl AccountBookDiff {
+    /// Compares the accounts in book `a` with those in book `b`
+    #[must_use]
+    pub fn new<'a, A, B>(a: A, b: B) -> Self
+    where
+        A: IntoIterator<Item = &'a Account>,
+        B: IntoIterator<Item = &'a Account>,
+    {
+        let mut before: BTreeMap<ClientId, Balances> = a
+            .into_iter()
+            .map(|account| (account.client_id(), account.into()))
+            .collect();
+        let mut after: Vec<(ClientId, Balances)> = b
+            .into_iter()
+            .map(|account| (account.client_id(), account.into()))
+            .collect();
+        after.sort_unstable_by_key(|(client, _)| *client);
+        let mut diff = Self::default();
+        for (client, after) in after {
+            match before.remove(&client) {
+                None => diff.created.push((client, after)),
+                Some(before) if before != after => diff.changed.push(AccountChange {
+                    client,
+                    before,
+                    after,
+                }),
+                Some(_) => {}
+            }
+        }
+        diff.removed = before.into_iter().collect();
+        diff
+    }
+
+    /// Returns whether the books are the same
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Returns how much the total funds across all accounts went up by, or down by if negative.
+    /// Sums across accounts can outgrow any one account, so this saturates rather than overflows.
+    #[must_use]
+    pub fn total_delta(&self) -> Decimal {
+        let created = self.created.iter().map(|(_, after)| after.total());
+        let removed = self.removed.iter().map(|(_, before)| -before.total());
+        let changed = self.changed.iter().map(AccountChange::total_delta);
+        created
+            .chain(removed)
+            .chain(changed)
+            .fold(Decimal::ZERO, Decimal::saturating_add)
+    }
+
+    /// Lists the differences one by one, ordered by client, as
+    /// [`diff_reports`](crate::io::diff_reports) does. A changed account has a [`Discrepancy`]
+    /// for each of its available funds, held funds and lock state that differ.
+    #[must_use]
+    pub fn discrepancies(&self) -> Vec<Discrepancy> {
+        let mut discrepancies = vec![];
+        for (client, _) in &self.created {
+            discrepancies.push(Discrepancy {
+                client: *client,
+                kind: DiscrepancyKind::OnlyInB,
+            });
+        }
+        for (client, _) in &self.removed {
+            discrepancies.push(Discrepancy {
+                client: *client,
+                kind: DiscrepancyKind::OnlyInA,
+            });
+        }
+        for change in &self.changed {
+            let (a, b) = (change.before, change.after);
+            let mut push = |kind| {
+                discrepancies.push(Discrepancy {
+                    client: change.client,
+                    kind,
+                });
+            };
+            if a.available != b.available {
+                push(DiscrepancyKind::Available {
+                    a: a.available,
+                    b: b.available,
+                });
+            }
+            if a.held != b.held {
+                push(DiscrepancyKind::Held {
+                    a: a.held,
+                    b: b.held,
+                });
+            }
+            if a.locked != b.locked {
+                push(DiscrepancyKind::Locked {
+                    a: a.locked,
+                    b: b.locked,
+                });
+            }
+        }
+        // Stable, so each client's discrepancies stay in the order above
+        discrepancies.sort_by_key(|discrepancy| discrepancy.client);
+        discrepancies
+    }
+}
+
+impl Display for AccountBookDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let locks = self
+            .changed
+            .iter()
+            .filter(|change| change.lock_change().is_some())
+            .count();
+        let total_delta = self.total_delta();
+        write!(
+            f,
+            "{} accounts created, {} removed, {} changed ({locks} lock changes), total funds {}{}",
+            self.created.len(),
+            self.removed.len(),
+            self.changed.len(),
+            if total_delta.is_sign_negative() {
+                ""
+            } else {
+                "+"
+            },
+            total_delta
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        io::read_accounts_from_csv,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_account_book_diff() {
+        let snapshot = "client,available,held,total,locked
+1,5.0,0,5.0,false
+2,3.0000,0.0000,3.0000,false
+3,1,0,1,false
+";
+        let snapshot = read_accounts_from_csv(&mut Cursor::new(snapshot)).unwrap();
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0000
+deposit,2,2,3.0
+deposit,2,3,1.0
+dispute,2,3,
+chargeback,2,3,
+deposit,4,4,2.5
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let diff = AccountBookDiff::new(snapshot.values(), engine.account_book());
+        assert_eq!(diff.created.len(), 1);
+        assert_eq!(diff.created[0].0, ClientId(4));
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].0, ClientId(3));
+        assert_eq!(diff.changed.len(), 1);
+        let change = diff.changed[0];
+        assert_eq!(change.client, ClientId(2));
+        assert_eq!(change.total_delta(), dec!(0));
+        assert_eq!(change.lock_change(), Some(true));
+        assert_eq!(diff.total_delta(), dec!(1.5));
+        assert_eq!(
+            diff.to_string(),
+            "1 accounts created, 1 removed, 1 changed (1 lock changes), total funds +1.5000"
+        );
+        let clients: Vec<_> = diff
+            .discrepancies()
+            .iter()
+            .map(|discrepancy| discrepancy.client.0)
+            .collect();
+        assert_eq!(clients, [2, 3, 4]);
+
+        assert!(AccountBookDiff::new(engine.account_book(), engine.account_book()).is_empty());
+    }
+
+    #[test]
+    fn test_total_delta_near_max() {
+        let balances = |available| Balances {
+            available,
+            held: Decimal::ZERO,
+            locked: false,
+        };
+        let diff = AccountBookDiff {
+            created: vec![(ClientId(1), balances(Decimal::MAX))],
+            removed: vec![(ClientId(2), balances(Decimal::MIN))],
+            changed: vec![AccountChange {
+                client: ClientId(3),
+                before: balances(Decimal::MIN),
+                after: balances(Decimal::MAX),
+            }],
+        };
+        assert_eq!(diff.changed[0].total_delta(), Decimal::MAX);
+        assert_eq!(diff.total_delta(), Decimal::MAX);
+    }
+}
@@ -0,0 +1,114 @@
+//! Entry points that take and return strings rather than files, for embedding the engine where
+//! there's no filesystem, like a browser tool built for `wasm32-unknown-unknown`.
+//!
+//! [`process_csv`](crate::embed::process_csv) runs a whole input in one go, and a
+//! [`CsvSession`](crate::embed::CsvSession) applies input as it arrives, eg one uploaded file at a
+//! time, writing the accounts out whenever they're wanted. Both write accounts in client order, so
+//! the output is the same from run to run. Everything here is plain data in and out, so it can be
+//! exported to JavaScript as is by a thin `wasm-bindgen` wrapper.
+//!
+//! Listeners that read the clock, like the rate limiter or the dashboard reports, aren't meant for
+//! `wasm32-unknown-unknown`, which has no clock to read.
+
+use std::io::Cursor;
+
+use crate::{
+    engine::Engine,
+    errors::Error,
+    metrics::RunSummary,
+    types::{MemoryAccountBook, MemoryTransactionLog},
+};
+
+/// Applies transactions in CSV, returning the resulting accounts as CSV, in client order. An empty
+/// input gives an empty output.
+/// # Errors
+/// If a row can't be parsed or applied
+pub fn process_csv(input: &str) -> Result<String, Error> {
+    let mut session = CsvSession::new();
+    session.apply_csv(input)?;
+    session.accounts_csv()
+}
+
+/// An engine kept between calls, applying transactions in CSV as they arrive
+#[derive(Debug)]
+pub struct CsvSession {
+    /// Holds the accounts and transactions so far
+    engine: Engine<MemoryAccountBook, MemoryTransactionLog>,
+}
+
+impl Default for CsvSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CsvSession {
+    /// Starts a session with no accounts
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            engine: Engine::in_memory(),
+        }
+    }
+
+    /// Applies transactions in CSV, on top of everything applied before. Each call's input needs
+    /// its own header row.
+    /// # Errors
+    /// If a row can't be parsed or applied, in which case the rows before it are still applied
+    pub fn apply_csv(&mut self, input: &str) -> Result<(), Error> {
+        self.engine.load_csv(&mut Cursor::new(input))
+    }
+
+    /// Writes every account as CSV, in client order
+    /// # Errors
+    /// If writing fails
+    pub fn accounts_csv(&self) -> Result<String, Error> {
+        let mut output = vec![];
+        self.engine.report(&mut output)?;
+        let output = String::from_utf8(output)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        Ok(output)
+    }
+
+    /// Summarizes everything applied so far
+    #[must_use]
+    pub fn summary(&self) -> RunSummary {
+        self.engine.summary()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_csv() {
+        let input = "type,client,tx,amount
+deposit,2,1,5.0
+deposit,1,2,1.5
+withdrawal,2,3,2.0
+";
+        assert_eq!(
+            process_csv(input).unwrap(),
+            "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,3.0000,0.0000,3.0000,false
+"
+        );
+        assert_eq!(process_csv("type,client,tx,amount\n").unwrap(), "");
+
+        let mut session = CsvSession::new();
+        session.apply_csv(input).unwrap();
+        session
+            .apply_csv("type,client,tx,amount\ndispute,1,2,\n")
+            .unwrap();
+        assert!(session
+            .accounts_csv()
+            .unwrap()
+            .contains("1,0.0000,1.5000,1.5000,false"));
+        assert_eq!(session.summary().rows_read, 4);
+        assert!(session
+            .apply_csv("type,client,tx,amount\nbogus,1,9,\n")
+            .is_err());
+    }
+}
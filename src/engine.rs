@@ -0,0 +1,2517 @@
+//! A single handle bundling an [`AccountBook`](crate::types::AccountBook) and a
+//! [`TransactionLog`](crate::types::TransactionLog), along with the
+//! settings that control how transactions are processed
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+#[cfg(feature = "stream")]
+use std::{future::poll_fn, pin::pin};
+
+use csv::ByteRecord;
+#[cfg(feature = "stream")]
+use futures_core::Stream;
+use rust_decimal::Decimal;
+
+use crate::{
+    backfill::{Backfill, Cutoff, Handover, Position},
+    clock::{Clock, SharedClock},
+    consistency::{ConsistencyCheck, ConsistencyReport},
+    erasure::Erasure,
+    errors::{
+        DomainError, Error, ErrorAction, ErrorPolicy, FailedRow, ParseError, Strict, Warning,
+    },
+    events::{EventHistory, EventListener, EventListeners, PriorState},
+    handler::{CustomTransaction, TransactionHandler, TransactionHandlers},
+    idempotency::{IdempotencyWindow, Remembered, Submission},
+    invariants::Invariants,
+    io::{self, CsvSource, MergeOrder, ReportOptions},
+    metrics::{self, EngineStats, Metrics, MetricsRegistry, RunSummary, TransactionCounts},
+    ops,
+    plugin::{ReportInput, ReportPlugin, ReportPlugins},
+    preview::{ProjectedAccount, ReadThrough},
+    ratelimit::{RateLimit, RateLimiter},
+    retention::{RetentionPolicy, RetentionReport},
+    saga::{Saga, SagaOutcome},
+    source::TransactionSource,
+    state::{Snapshot, WalEntry},
+    types::{
+        Account, AccountBook, CapacityHint, ClientId, MemoryAccountBook, MemoryTransactionLog,
+        Provenance, Transaction, TransactionId, TransactionLog, TransactionRecord, TransactionType,
+    },
+};
+
+/// Tuning knobs for an [`Engine`]. All settings are opt-in; the defaults match the behaviour of
+/// the free functions in [`io`].
+#[derive(Debug, Default, Clone)]
+pub struct EngineSettings {
+    /// Read every incoming row into a single reusable record buffer, deserializing each
+    /// [`Transaction`] straight out of it as raw bytes.
+    ///
+    /// This skips the UTF-8 validation of each row done by the default path. Transactions and
+    /// their states hold nothing on the heap, so the row buffer is the only thing pooled. The
+    /// `load` benchmark compares the two paths.
+    pub pooled_records: bool,
+    /// Time how long each transaction takes to apply, for [`Metrics::apply_latency`].
+    ///
+    /// This costs a couple of clock reads per transaction, so is off by default.
+    pub record_latency: bool,
+    /// Reject transactions that the default rules let through: withdrawals exceeding available
+    /// funds, deposits and withdrawals that aren't for a positive amount, and disputes, resolves
+    /// and chargebacks that refer to unknown transactions or to another client's transactions.
+    ///
+    /// See [`DomainError::InsufficientFunds`], [`DomainError::InvalidAmount`],
+    /// [`DomainError::UnknownReference`] and [`DomainError::ClientMismatch`].
+    pub validate_transactions: bool,
+    /// Check invariants after every transaction applied, panicking with a detailed dump of the
+    /// account involved if any don't hold: that held funds aren't negative (unless a resolve or
+    /// chargeback of an undisputed transaction explains it), that total and held funds match the
+    /// transactions applied, and that a locked account was locked by a chargeback.
+    ///
+    /// This is for catching logic bugs during development. It keeps its own copy of every
+    /// account's history, so is slow and memory hungry, and assumes accounts start out empty.
+    pub paranoid: bool,
+    /// Limit how fast each client's transactions are accepted, rejecting (or holding up) any
+    /// beyond the limit. Unlimited by default.
+    pub rate_limit: Option<RateLimit>,
+    /// What to do with a transaction whose [timestamp](Transaction::timestamp) is earlier than
+    /// one already applied. Transactions without timestamps are never out of order.
+    pub out_of_order: OutOfOrder,
+    /// Keep every [`DomainEvent`](crate::events::DomainEvent), for rebuilding past account states
+    /// with [`Engine::state_at`].
+    ///
+    /// The history grows with every transaction applied, and assumes accounts start out empty.
+    pub keep_history: bool,
+    /// How long [`Engine::submit_csv`] remembers each idempotency key, answering a resubmission
+    /// with the same key with the first outcome rather than applying it again. Keys are ignored
+    /// by default.
+    pub idempotency_window: Option<Duration>,
+}
+
+/// What an [`Engine`] does with transactions that arrive out of order, per
+/// [`EngineSettings::out_of_order`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfOrder {
+    /// Apply them as usual
+    #[default]
+    Allow,
+    /// Apply them, but raise a [`Warning::OutOfOrder`]
+    Warn,
+    /// Reject them with [`DomainError::OutOfOrder`]
+    Reject,
+}
+
+/// A flag that can be used to stop an [`Engine`] part way through loading.
+///
+/// Clones share the same flag, so one can be handed to another thread (or a signal handler, or a
+/// request handler) and cancelled from there. The engine checks it between transactions; once
+/// cancelled, loading stops with [`Error::Cancelled`] and whatever was applied stays applied.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken {
+    /// Whether cancellation has been requested
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Creates a token that hasn't been cancelled
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests that any load checking this token stops before its next transaction
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether cancellation has been requested
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// The [`ErrorPolicy`] an engine consults, boxed so the engine doesn't need another type parameter
+struct BoxedErrorPolicy(Box<dyn ErrorPolicy + Send>);
+
+impl Default for BoxedErrorPolicy {
+    fn default() -> Self {
+        Self(Box::new(Strict))
+    }
+}
+
+impl Debug for BoxedErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorPolicy")
+    }
+}
+
+/// The source an engine applies in chunks, boxed so the engine doesn't need another type
+/// parameter
+struct BoxedSource(Box<dyn TransactionSource + Send>);
+
+impl Debug for BoxedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TransactionSource")
+    }
+}
+
+/// What happened during a call to [`Engine::apply_chunk`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkProgress {
+    /// Number of transactions applied during this chunk
+    pub applied: usize,
+    /// Whether the source has been used up (or there was none). Once this is `true`, there's
+    /// nothing more to apply until another source is attached.
+    pub finished: bool,
+}
+
+/// Owns an account book and a transaction log, and applies incoming transactions to them
+/// according to its [`EngineSettings`].
+#[derive(Debug, Default)]
+pub struct Engine<A, T> {
+    /// Storage for all accounts
+    account_book: A,
+    /// Storage for all registered transactions
+    transaction_log: T,
+    /// Settings controlling how transactions are processed
+    settings: EngineSettings,
+    /// Counters and timings of everything processed so far
+    metrics: MetricsRegistry,
+    /// Checked between transactions, to stop loading early
+    cancellation: CancellationToken,
+    /// Where the time comes from, for rate limits, latencies and ingest times
+    clock: SharedClock,
+    /// Input being worked through by [`Engine::apply_chunk`]
+    source: Option<BoxedSource>,
+    /// Name of the input being loaded, for each transaction's [`Provenance`]. Empty if the input
+    /// wasn't named.
+    input: Arc<str>,
+    /// The line of the input last read
+    line: u64,
+    /// Called as each transaction is applied or rejected
+    listeners: EventListeners,
+    /// Decides what to do about rows that fail to parse or apply
+    error_policy: BoxedErrorPolicy,
+    /// Checked after every transaction applied, in [`EngineSettings::paranoid`] mode
+    invariants: Invariants,
+    /// Enforces [`EngineSettings::rate_limit`], if set
+    rate_limiter: Option<RateLimiter>,
+    /// The latest timestamp of the transactions applied, for [`EngineSettings::out_of_order`]
+    latest_timestamp: Option<u64>,
+    /// Every event so far, if [`EngineSettings::keep_history`] is set
+    history: Option<EventHistory>,
+    /// Idempotency keys seen recently, if they're being remembered
+    idempotency: Option<IdempotencyWindow>,
+    /// Checks transactions are signed by their client, if set
+    #[cfg(feature = "signatures")]
+    verifier: Option<crate::signature::SignatureVerifier>,
+    /// Reports that can be written by name
+    reports: ReportPlugins,
+    /// Handlers of custom transaction types, by name
+    handlers: TransactionHandlers,
+    /// What's archived and purged by [`Engine::enforce_retention`], if anything
+    retention: Option<RetentionPolicy>,
+    /// Where a [backfill](Engine::backfill) hands over to the live feed, until it has
+    handover: Option<Handover>,
+    /// Funds charged back from each client, which the transaction log doesn't keep, for
+    /// [consistency checks](Engine::check_consistency)
+    charged_back: BTreeMap<ClientId, Decimal>,
+    /// What each client's total came to from transactions no longer in the log, purged by
+    /// retention or erasure, for [consistency checks](Engine::check_consistency)
+    opening: BTreeMap<ClientId, Decimal>,
+    /// How many times each transaction is disputed and not yet resolved or charged back, which
+    /// the transaction log doesn't keep, so erasure and retention can leave those transactions be
+    disputed: BTreeMap<TransactionId, u32>,
+    /// Sagas a [replayed](Engine::replay) WAL recorded starting but not finishing, by the ID of
+    /// their first step, for [`Engine::recover_sagas`]
+    interrupted: BTreeMap<TransactionId, Saga>,
+    /// Reports spans and metrics to OpenTelemetry
+    #[cfg(feature = "otel")]
+    telemetry: crate::telemetry::Telemetry,
+}
+
+impl<A, T> Engine<A, T>
+where
+    A: AccountBook,
+    T: TransactionLog,
+{
+    /// Creates an engine with default settings around the supplied account book and transaction log
+    #[must_use]
+    pub fn new(account_book: A, transaction_log: T) -> Self {
+        Self::with_settings(account_book, transaction_log, EngineSettings::default())
+    }
+
+    /// Creates an engine with the supplied settings
+    #[must_use]
+    pub fn with_settings(account_book: A, transaction_log: T, settings: EngineSettings) -> Self {
+        let rate_limiter = settings.rate_limit.map(RateLimiter::new);
+        let history = settings.keep_history.then(EventHistory::new);
+        let idempotency = settings.idempotency_window.map(IdempotencyWindow::new);
+        let mut engine = Self {
+            account_book,
+            transaction_log,
+            settings,
+            metrics: MetricsRegistry::default(),
+            cancellation: CancellationToken::new(),
+            clock: SharedClock::default(),
+            source: None,
+            input: Arc::default(),
+            line: 0,
+            listeners: EventListeners::default(),
+            error_policy: BoxedErrorPolicy::default(),
+            invariants: Invariants::default(),
+            rate_limiter,
+            latest_timestamp: None,
+            history: history.clone(),
+            idempotency,
+            #[cfg(feature = "signatures")]
+            verifier: None,
+            reports: ReportPlugins::default(),
+            handlers: TransactionHandlers::default(),
+            retention: None,
+            handover: None,
+            charged_back: BTreeMap::new(),
+            opening: BTreeMap::new(),
+            disputed: BTreeMap::new(),
+            interrupted: BTreeMap::new(),
+            #[cfg(feature = "otel")]
+            telemetry: crate::telemetry::Telemetry::default(),
+        };
+        if let Some(history) = history {
+            engine.add_listener(history);
+        }
+        engine
+    }
+
+    /// Returns the settings this engine was created with
+    #[must_use]
+    pub fn settings(&self) -> &EngineSettings {
+        &self.settings
+    }
+
+    /// Returns a handle that can be used to stop this engine's loads part way through.
+    ///
+    /// All handles refer to the same token until it's replaced with
+    /// [`set_cancellation_token`](Self::set_cancellation_token).
+    #[must_use]
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Replaces the token checked during loads, eg with a fresh one after a cancelled load, or
+    /// with one shared by several engines
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = token;
+    }
+
+    /// Returns the clock this engine gets the time from
+    #[must_use]
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Replaces the clock this engine gets the time from, eg with a
+    /// [`MockClock`](crate::clock::MockClock) to test rate limits without waiting. Listeners
+    /// that keep time have clocks of their own.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = SharedClock::new(clock);
+    }
+
+    /// Registers a listener to be called as transactions are applied or rejected. Listeners are
+    /// called in the order they were added.
+    pub fn add_listener<L>(&mut self, listener: L)
+    where
+        L: EventListener + Send + 'static,
+    {
+        self.listeners.push(Box::new(listener));
+    }
+
+    /// Registers a report, to be written by name with [`write_report`](Self::write_report). It
+    /// replaces any report already registered with the same name.
+    pub fn add_report<P>(&mut self, plugin: P)
+    where
+        P: ReportPlugin + Send + 'static,
+    {
+        self.reports.insert(Box::new(plugin));
+    }
+
+    /// Registers a handler of a custom transaction type, applied to rows of that type loaded by
+    /// [`load_csv`](Self::load_csv). It replaces any handler already registered with the same
+    /// name. Handlers named after built-in types are never called. See [`crate::handler`].
+    pub fn add_handler<H>(&mut self, handler: H)
+    where
+        H: TransactionHandler + Send + 'static,
+    {
+        self.handlers.insert(Box::new(handler));
+    }
+
+    /// Returns the names of the registered reports, in the order they were added
+    #[must_use]
+    pub fn report_names(&self) -> Vec<&str> {
+        self.reports.names()
+    }
+
+    /// Writes the named report, handing it every account and logged transaction along with the
+    /// engine's [stats](Self::stats) and [summary](Self::summary)
+    /// # Errors
+    /// [`Error::UnknownReport`] if no report with that name is registered, or any error writing
+    /// the report
+    pub fn write_report<W: Write>(&mut self, name: &str, writer: &mut W) -> Result<(), Error> {
+        let input = ReportInput {
+            accounts: Box::new(self.account_book.accounts()),
+            transactions: self.transaction_log.transactions(),
+            stats: self.metrics.stats(),
+            summary: self.metrics.summary(),
+        };
+        self.reports.get_mut(name)?.write_report(input, writer)
+    }
+
+    /// Writes every account as CSV, in client order, in the same format as
+    /// [`io::write_accounts_to_csv`]
+    /// # Errors
+    /// If writing fails
+    pub fn report<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut accounts: Vec<&Account> = self.account_book.accounts().collect();
+        accounts.sort_unstable_by_key(|account| account.client_id());
+        io::write_accounts_to_csv_with(writer, accounts, &ReportOptions::default())
+    }
+
+    /// Takes a [`Snapshot`] of every account and logged transaction, along with what the engine
+    /// keeps that the log doesn't: the IDs of the [custom transactions](crate::handler) applied,
+    /// the funds charged back, the transactions under dispute, and the opening balances of
+    /// clients whose transactions were purged. Write it out and [restore](Self::restore) it
+    /// later.
+    #[must_use]
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::capture(&self.account_book, &self.transaction_log);
+        snapshot.custom = self.handlers.applied().collect();
+        snapshot.charged_back.clone_from(&self.charged_back);
+        snapshot.disputed.clone_from(&self.disputed);
+        snapshot.opening.clone_from(&self.opening);
+        snapshot
+    }
+
+    /// Puts everything in a [`Snapshot`] back, as [`Snapshot::restore`] does, along with what
+    /// the engine keeps itself, so custom transactions aren't applied again, and consistency
+    /// checks, erasure and retention carry on where they left off
+    /// # Errors
+    /// If the account book or transaction log fails
+    pub fn restore(&mut self, mut snapshot: Snapshot) -> Result<(), Error> {
+        self.handlers.remember(std::mem::take(&mut snapshot.custom));
+        self.charged_back.append(&mut snapshot.charged_back);
+        self.disputed.append(&mut snapshot.disputed);
+        self.opening.append(&mut snapshot.opening);
+        snapshot.restore(&mut self.account_book, &mut self.transaction_log)
+    }
+
+    /// Applies what a [`Wal`](crate::state::Wal) recorded, as read by
+    /// [`read_wal_entries`](crate::state::read_wal_entries), eg on top of a
+    /// [restored](Self::restore) snapshot. Transactions are applied as by [`apply`](Self::apply),
+    /// and custom transactions are handed to their handlers, which need registering first.
+    ///
+    /// Sagas the WAL recorded starting but not finishing are kept to be finished with
+    /// [`recover_sagas`](Self::recover_sagas).
+    /// # Errors
+    /// The error a transaction or custom transaction was rejected with, unless the engine's
+    /// [`ErrorPolicy`] skips it, or [`Error::Cancelled`] if the engine's [`CancellationToken`] is
+    /// cancelled
+    pub fn replay<I>(&mut self, entries: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = WalEntry>,
+    {
+        self.batch("replay", |engine| {
+            engine.input = Arc::default();
+            engine.line = 0;
+            entries.into_iter().try_for_each(|entry| match entry {
+                WalEntry::Transaction(transaction) => engine.apply_recorded(transaction),
+                WalEntry::Custom(transaction) => {
+                    engine.apply_custom(&transaction, &transaction.to_record())
+                }
+                WalEntry::SagaStarted(saga) => {
+                    if let Some(id) = saga.id() {
+                        engine.interrupted.insert(id, saga);
+                    }
+                    Ok(())
+                }
+                WalEntry::SagaFinished(id) => {
+                    engine.interrupted.remove(&id);
+                    Ok(())
+                }
+            })
+        })
+    }
+
+    /// Sets what happens when a row fails to parse or a transaction is rejected. Until this is
+    /// called, the engine is [`Strict`], stopping at the first failure.
+    ///
+    /// Skipped rows are still counted in [`Metrics::errors`], and passed to listeners, so they
+    /// can be written out with a [`DeadLetterLog`](crate::audit::DeadLetterLog).
+    pub fn set_error_policy<P>(&mut self, policy: P)
+    where
+        P: ErrorPolicy + Send + 'static,
+    {
+        self.error_policy = BoxedErrorPolicy(Box::new(policy));
+    }
+
+    /// Rejects every transaction not signed by its client's key with
+    /// [`DomainError::BadSignature`], before it's applied. Until this is called, signatures are
+    /// ignored.
+    #[cfg(feature = "signatures")]
+    pub fn set_verifier(&mut self, verifier: crate::signature::SignatureVerifier) {
+        self.verifier = Some(verifier);
+    }
+
+    /// Sets how long history is kept before [`enforce_retention`](Self::enforce_retention)
+    /// archives and purges it, replacing any policy already set
+    pub fn set_retention(&mut self, policy: RetentionPolicy) {
+        self.retention = Some(policy);
+    }
+
+    /// Archives, then purges, the transactions and audit segments older than the
+    /// [retention policy](Self::set_retention) allows as of `now`, returning how many were
+    /// purged. Does nothing until a policy is set. Transactions under dispute are kept until the
+    /// dispute is resolved or charged back.
+    ///
+    /// Only call this between loads, since transactions purged part way through a load can't be
+    /// disputed by the rest of it.
+    /// # Errors
+    /// If archiving fails, in which case whatever it was for is kept, or if purging fails
+    pub fn enforce_retention(&mut self, now: SystemTime) -> Result<RetentionReport, Error> {
+        let Some(policy) = &mut self.retention else {
+            return Ok(RetentionReport::default());
+        };
+        let (report, purged) = policy.enforce(&mut self.transaction_log, &self.disputed, now)?;
+        self.carry_forward(&purged);
+        Ok(report)
+    }
+
+    /// Adds what transactions just purged from the log moved to their clients' opening
+    /// balances, so consistency checks still add up without them
+    fn carry_forward(&mut self, purged: &[TransactionRecord]) {
+        let scale = self.account_book.scale();
+        for record in purged {
+            let Some(mut amount) = record.amount else {
+                continue;
+            };
+            // Rounded the same way as when it was applied
+            amount.rescale(scale);
+            let opening = self.opening.entry(record.client_id).or_default();
+            *opening = match record.transaction_type {
+                TransactionType::Deposit => opening.saturating_add(amount),
+                TransactionType::Withdrawal => opening.saturating_sub(amount),
+                _ => *opening,
+            };
+        }
+    }
+
+    /// Rebuilds state from archived transactions, applying each archive in turn up to `cutoff`
+    /// and ignoring anything after it, then catches up with the live feed: transactions loaded
+    /// afterwards are skipped until the first past the cutoff, or the first the archives didn't
+    /// have if they end before it. See [`crate::backfill`].
+    ///
+    /// Skipped transactions are counted as parsed, but neither applied nor rejected, and
+    /// listeners aren't told about them.
+    /// # Errors
+    /// As for [`load_csv`](Self::load_csv), in which case the engine doesn't catch up, and
+    /// loads everything afterwards as usual
+    pub fn backfill<R, I>(&mut self, archives: I, cutoff: Cutoff) -> Result<Backfill, Error>
+    where
+        R: Read,
+        I: IntoIterator<Item = R>,
+    {
+        self.handover = Some(Handover::new(cutoff));
+        let result = archives
+            .into_iter()
+            .try_for_each(|mut archive| self.load_csv(&mut archive));
+        let mut handover = self.handover.take().unwrap_or(Handover::new(cutoff));
+        result?;
+        let backfill = handover.finish_backfill();
+        self.handover = Some(handover);
+        Ok(backfill)
+    }
+
+    /// Returns whether the engine is still skipping transactions the last
+    /// [backfill](Self::backfill) applied, waiting for the first past its cutoff
+    #[must_use]
+    pub fn is_catching_up(&self) -> bool {
+        self.handover.is_some()
+    }
+
+    /// Pre-sizes the account book and transaction log for the amount of data about to be loaded
+    pub fn reserve(&mut self, hint: CapacityHint) {
+        self.account_book.reserve(hint.accounts());
+        self.transaction_log.reserve(hint.transactions());
+    }
+
+    /// Loads and applies transactions from a CSV-formatted stream.
+    ///
+    /// See [`io::load_transactions_from_csv`] for the expected format. The input is left unnamed
+    /// in each transaction's [`Provenance`]; use [`load_named_csv`](Self::load_named_csv) to name
+    /// it. Rows of a custom type are applied by the [handler](Self::add_handler) registered for
+    /// it.
+    /// # Errors
+    /// Stops at, and returns, the first error the engine's [`ErrorPolicy`] doesn't skip, or
+    /// [`Error::Cancelled`] if the engine's [`CancellationToken`] is cancelled
+    pub fn load_csv<R>(&mut self, reader: &mut R) -> Result<(), Error>
+    where
+        R: Read,
+    {
+        self.load_named_csv("", reader)
+    }
+
+    /// Loads and applies a batch of CSV-formatted transactions submitted through an API, like
+    /// [`load_csv`](Self::load_csv), returning how many were applied and why any others weren't.
+    ///
+    /// With an [`EngineSettings::idempotency_window`], a batch submitted with the same
+    /// idempotency key as one within the window isn't applied; the first batch's outcome is
+    /// returned instead, marked as [replayed](Submission::replayed). If the first batch was
+    /// stopped by a [retryable](Error::is_retryable) error, it's carried on with from where it
+    /// stopped instead, and the [applied](Submission::applied) count includes what the earlier
+    /// attempts applied. See [`crate::idempotency`].
+    /// # Errors
+    /// [`Error::IdempotencyKeyReused`] if the key was used within the window for a different
+    /// batch. Errors loading the batch are returned in the [`Submission`], so they can be
+    /// replayed too.
+    pub fn submit_csv(
+        &mut self,
+        idempotency_key: Option<&str>,
+        body: &[u8],
+    ) -> Result<Submission, Error> {
+        let now = self.clock.instant();
+        let key = idempotency_key.filter(|_| self.idempotency.is_some());
+        // How many transactions earlier attempts applied, and the line to carry on from
+        let (mut applied, mut from_line) = (0, 0);
+        if let (Some(key), Some(idempotency)) = (key, &mut self.idempotency) {
+            match idempotency.lookup(key, body, now)? {
+                Some(Remembered::Done(submission)) => return Ok(submission),
+                Some(Remembered::Stopped {
+                    applied: before,
+                    line,
+                }) => {
+                    (applied, from_line) = (before, line);
+                }
+                None => {}
+            }
+        }
+        let applied_before = self.metrics().applied.total();
+        let result = self.read_csv_from("", &mut &*body, from_line);
+        applied += self.metrics().applied.total() - applied_before;
+        // The line of the transaction it stopped at, which wasn't applied
+        let resume = result
+            .as_ref()
+            .err()
+            .filter(|err| err.is_retryable())
+            .map(|_| self.line);
+        let submission = Submission {
+            applied,
+            error: result.err().map(Arc::new),
+            replayed: false,
+        };
+        if let (Some(key), Some(idempotency)) = (key, &mut self.idempotency) {
+            idempotency.record(key, body, submission.clone(), resume, now);
+        }
+        Ok(submission)
+    }
+
+    /// Returns the number of idempotency keys remembered, including any that have expired but
+    /// haven't been forgotten yet
+    #[must_use]
+    pub fn idempotency_keys(&self) -> usize {
+        self.idempotency.as_ref().map_or(0, IdempotencyWindow::len)
+    }
+
+    /// Loads and applies transactions from a CSV-formatted stream, like
+    /// [`load_csv`](Self::load_csv), giving `name` (eg the filename) as the source in each
+    /// transaction's [`Provenance`]
+    /// # Errors
+    /// As for [`load_csv`](Self::load_csv)
+    pub fn load_named_csv<R>(&mut self, name: &str, reader: &mut R) -> Result<(), Error>
+    where
+        R: Read,
+    {
+        self.read_csv_from(name, reader, 0)
+    }
+
+    /// Loads and applies transactions from a CSV-formatted stream, as for
+    /// [`load_named_csv`](Self::load_named_csv), skipping the rows before `from_line`
+    fn read_csv_from<R>(&mut self, name: &str, reader: &mut R, from_line: u64) -> Result<(), Error>
+    where
+        R: Read,
+    {
+        self.batch("load_csv", |engine| {
+            engine.input = Arc::from(name);
+            let result = io::read_csv_with(
+                reader,
+                engine.settings.pooled_records,
+                |transaction, record, headers| {
+                    engine.line = io::line_of(record);
+                    if engine.line < from_line {
+                        return Ok(());
+                    }
+                    match transaction {
+                        Ok(transaction) => {
+                            engine.metrics.rows_parsed += 1;
+                            engine.apply_recorded(transaction)
+                        }
+                        // Rows of a custom type don't parse as a built-in one
+                        Err(err) => match engine.handlers.parse(record, headers) {
+                            Some(transaction) => {
+                                engine.metrics.rows_parsed += 1;
+                                engine.apply_custom(&transaction, record)
+                            }
+                            None => engine.handle_unparsed(record, err),
+                        },
+                    }
+                },
+            );
+            // Errors while applying were already counted
+            if let Err(Error::Io(_) | Error::Parse(_)) = result {
+                engine.metrics.errors += 1;
+            }
+            engine.metrics.last_ingest = metrics::wall_clock(&engine.clock);
+            result
+        })
+    }
+
+    /// Loads and applies transactions from several CSV-formatted streams, parsing them in parallel.
+    ///
+    /// See [`io::load_transactions_from_csv_files`] for how the inputs are merged.
+    /// The [`ErrorPolicy`] is consulted about rows that can't be parsed before anything is applied.
+    /// The inputs are left unnamed in each transaction's [`Provenance`]; use
+    /// [`load_named_csv_files`](Self::load_named_csv_files) to name them.
+    /// # Errors
+    /// Returns the first parsing error (by input order) the [`ErrorPolicy`] doesn't skip without
+    /// applying anything, or the first error encountered while applying that it doesn't skip, or
+    /// [`Error::Cancelled`] if the engine's [`CancellationToken`] is cancelled
+    pub fn load_csv_files<R>(&mut self, readers: Vec<R>, order: MergeOrder) -> Result<(), Error>
+    where
+        R: Read + Send,
+    {
+        let inputs = readers
+            .into_iter()
+            .map(|reader| (String::new(), reader))
+            .collect();
+        self.load_named_csv_files(inputs, order)
+    }
+
+    /// Loads and applies transactions from several CSV-formatted streams, like
+    /// [`load_csv_files`](Self::load_csv_files), giving each input's name (eg its filename) as the
+    /// source in its transactions' [`Provenance`]
+    /// # Errors
+    /// As for [`load_csv_files`](Self::load_csv_files)
+    pub fn load_named_csv_files<R>(
+        &mut self,
+        inputs: Vec<(String, R)>,
+        order: MergeOrder,
+    ) -> Result<(), Error>
+    where
+        R: Read + Send,
+    {
+        self.batch("load_csv_files", |engine| {
+            let (names, readers): (Vec<Arc<str>>, Vec<R>) = inputs
+                .into_iter()
+                .map(|(name, reader)| (Arc::from(name), reader))
+                .unzip();
+            // Unparsed rows are set aside, so the policy can be consulted about them in input order
+            let (rows, unparsed) =
+                io::parse_csv_files(readers, order, engine.settings.pooled_records, true)
+                    .inspect_err(|_| engine.metrics.errors += 1)?;
+            for row in unparsed {
+                engine.input = Arc::clone(&names[row.input]);
+                engine.line = io::line_of(&row.record);
+                engine
+                    .handle_unparsed(&row.record, row.error)
+                    .inspect_err(|_| engine.metrics.errors += 1)?;
+            }
+            engine.metrics.rows_parsed += rows.len() as u64;
+            let result = rows.into_iter().try_for_each(|row| {
+                engine.input = Arc::clone(&names[row.input]);
+                engine.line = row.line;
+                engine.apply_recorded(row.transaction)
+            });
+            engine.metrics.last_ingest = metrics::wall_clock(&engine.clock);
+            result
+        })
+    }
+
+    /// Holds on to a CSV-formatted stream, to be applied a piece at a time with
+    /// [`apply_chunk`](Self::apply_chunk). Replaces any source that was attached before.
+    ///
+    /// The stream is left unnamed in each transaction's [`Provenance`].
+    pub fn attach_csv<R>(&mut self, reader: R)
+    where
+        R: Read + Send + 'static,
+    {
+        self.attach_source(CsvSource::new(reader));
+    }
+
+    /// Holds on to a source of transactions in any format, to be applied a piece at a time with
+    /// [`apply_chunk`](Self::apply_chunk). Replaces any source that was attached before.
+    ///
+    /// The source is left unnamed in each transaction's [`Provenance`].
+    pub fn attach_source<S>(&mut self, source: S)
+    where
+        S: TransactionSource + Send + 'static,
+    {
+        self.source = Some(BoxedSource(Box::new(source)));
+    }
+
+    /// Applies every transaction from a source in any format, like [`load_csv`](Self::load_csv)
+    /// does for CSV.
+    ///
+    /// Items the source can't parse are handed to the engine's [`ErrorPolicy`], along with the
+    /// source's [raw fields](TransactionSource::raw_fields). The source is left unnamed in each
+    /// transaction's [`Provenance`].
+    /// # Errors
+    /// Stops at, and returns, the first error the engine's [`ErrorPolicy`] doesn't skip, any error
+    /// reading from the source, or [`Error::Cancelled`] if the engine's [`CancellationToken`] is
+    /// cancelled
+    pub fn load_source<S>(&mut self, source: &mut S) -> Result<(), Error>
+    where
+        S: TransactionSource + ?Sized,
+    {
+        self.batch("load_source", |engine| {
+            engine.input = Arc::default();
+            let result = engine.apply_from(source, usize::MAX, &mut 0);
+            engine.metrics.last_ingest = metrics::wall_clock(&engine.clock);
+            result.map(|_| ())
+        })
+    }
+
+    /// Applies up to `max_rows` transactions from the attached source, then returns.
+    ///
+    /// This lets ingestion be interleaved with other work on the same thread, eg from a GUI event
+    /// loop or an async task, without handing the engine to another thread. The source is
+    /// dropped once it's used up.
+    /// # Errors
+    /// Stops at, and returns, the first error the engine's [`ErrorPolicy`] doesn't skip. The failed
+    /// row is skipped, so calling again carries on from the row after it.
+    pub fn apply_chunk(&mut self, max_rows: usize) -> Result<ChunkProgress, Error> {
+        self.batch("apply_chunk", |engine| engine.apply_chunk_inner(max_rows))
+    }
+
+    /// Applies a single transaction created in code, eg with [`Transaction::deposit`], just as if
+    /// it had been loaded: the engine's settings, listeners and metrics all apply.
+    ///
+    /// The transaction is left unnamed in its [`Provenance`], with no line.
+    /// # Errors
+    /// The error the transaction was rejected with, unless the engine's [`ErrorPolicy`] skips it,
+    /// or [`Error::Cancelled`] if the engine's [`CancellationToken`] is cancelled
+    pub fn apply(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.batch("apply", |engine| {
+            engine.input = Arc::default();
+            engine.line = 0;
+            engine.apply_recorded(transaction)
+        })
+    }
+
+    /// Applies each step of a [`Saga`] in turn, like [`apply`](Self::apply), and if one is
+    /// rejected, applies the compensations of the steps before it, latest first, to undo them.
+    ///
+    /// A rejected step fails the saga even if the engine's [`ErrorPolicy`] skips it, though the
+    /// policy can still have it retried.
+    /// # Errors
+    /// [`Error::Cancelled`] if the engine's [`CancellationToken`] is cancelled before the saga
+    /// starts. Once it's started, it runs to the end, compensations included, and anything
+    /// rejected is reported in the [`SagaOutcome`].
+    pub fn run_saga(&mut self, saga: Saga) -> Result<SagaOutcome, Error> {
+        self.batch("run_saga", |engine| {
+            if engine.cancellation.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            engine.input = Arc::default();
+            engine.line = 0;
+            engine.listeners.saga_started(&saga);
+            let outcome = saga.run(|transaction| engine.apply_with_policy(transaction).0);
+            engine.listeners.saga_finished(&outcome);
+            Ok(outcome)
+        })
+    }
+
+    /// Finishes the sagas a [replayed](Self::replay) WAL recorded starting but not finishing, eg
+    /// because of a crash, so no money is left half moved. A saga whose every step was applied
+    /// is left as it is. Otherwise, the steps it applied are undone by their compensations,
+    /// latest first, as if the next step had failed with [`DomainError::SagaInterrupted`].
+    ///
+    /// Call it after replaying, before applying anything else. Listeners are told of each saga
+    /// as it's recovered, so a new WAL records it finishing.
+    /// # Errors
+    /// If the transaction log fails to say whether a step was applied, in which case the sagas
+    /// not yet recovered are kept to try again
+    pub fn recover_sagas(&mut self) -> Result<Vec<SagaOutcome>, Error> {
+        self.batch("recover_sagas", |engine| {
+            engine.input = Arc::default();
+            engine.line = 0;
+            let mut outcomes = vec![];
+            while let Some((id, saga)) = engine.interrupted.pop_first() {
+                let mut applied = BTreeSet::new();
+                let ids = saga.steps.iter().flat_map(|step| {
+                    [
+                        Some(step.transaction.transaction_id()),
+                        step.compensation_id,
+                    ]
+                });
+                for transaction_id in ids.flatten() {
+                    match engine.transaction_log.transaction(transaction_id) {
+                        Ok(Some(_)) => {
+                            applied.insert(transaction_id);
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            engine.interrupted.insert(id, saga);
+                            return Err(err);
+                        }
+                    }
+                }
+                engine.listeners.saga_started(&saga);
+                let outcome = saga.recover(
+                    |transaction_id| applied.contains(&transaction_id),
+                    |transaction| engine.apply_with_policy(transaction).0,
+                );
+                engine.listeners.saga_finished(&outcome);
+                outcomes.push(outcome);
+            }
+            Ok(outcomes)
+        })
+    }
+
+    /// Applies every transaction from an async [`Stream`], eg one fed from a network connection,
+    /// as each arrives. The stream can be built with the usual combinators first, to buffer,
+    /// time out or rate limit what arrives.
+    ///
+    /// Items that are an [`Error::Parse`] are handed to the engine's [`ErrorPolicy`], like rows
+    /// that can't be parsed from a file. Transactions are applied synchronously, so the engine
+    /// only waits on the stream, never on its storage. Transactions are left unnamed in their
+    /// [`Provenance`], with no line.
+    /// # Errors
+    /// Stops at, and returns, the first error the engine's [`ErrorPolicy`] doesn't skip, any other
+    /// error from the stream, or [`Error::Cancelled`] if the engine's [`CancellationToken`] is
+    /// cancelled
+    #[cfg(feature = "stream")]
+    pub async fn apply_stream<S>(&mut self, stream: S) -> Result<(), Error>
+    where
+        S: Stream<Item = Result<Transaction, Error>>,
+    {
+        let mut stream = pin!(stream);
+        self.input = Arc::default();
+        self.line = 0;
+        while let Some(item) = poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+            self.apply_item(item, Vec::new)?;
+            self.metrics.last_ingest = metrics::wall_clock(&self.clock);
+        }
+        Ok(())
+    }
+
+    /// Does the work for [`apply_chunk`](Self::apply_chunk)
+    fn apply_chunk_inner(&mut self, max_rows: usize) -> Result<ChunkProgress, Error> {
+        let Some(mut source) = self.source.take() else {
+            return Ok(ChunkProgress {
+                applied: 0,
+                finished: true,
+            });
+        };
+        self.metrics.last_ingest = metrics::wall_clock(&self.clock);
+        self.input = Arc::default();
+        let mut applied = 0;
+        let result = self.apply_from(&mut source.0, max_rows, &mut applied);
+        let finished = matches!(result, Ok(true));
+        if !finished {
+            self.source = Some(source);
+        }
+        result.map(|_| ChunkProgress { applied, finished })
+    }
+
+    /// Applies up to `max_rows` transactions from a source, counting those applied in `applied`.
+    /// Returns whether the source was used up.
+    ///
+    /// Items the source can't parse are handed to the error policy; any other error from the
+    /// source stops loading.
+    fn apply_from<S>(
+        &mut self,
+        source: &mut S,
+        max_rows: usize,
+        applied: &mut usize,
+    ) -> Result<bool, Error>
+    where
+        S: TransactionSource + ?Sized,
+    {
+        while *applied < max_rows {
+            let next = source.next_transaction();
+            self.line = source.line();
+            let Some(next) = next.transpose() else {
+                return Ok(true);
+            };
+            if self.apply_item(next, || source.raw_fields())? {
+                *applied += 1;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Applies one item from a source or stream. Returns whether it was a transaction, rather than
+    /// an item that couldn't be parsed and was skipped.
+    ///
+    /// Items that couldn't be parsed are handed to the error policy, along with their
+    /// `raw_fields`; any other error is returned.
+    fn apply_item<'r>(
+        &mut self,
+        item: Result<Transaction, Error>,
+        raw_fields: impl FnOnce() -> Vec<&'r [u8]>,
+    ) -> Result<bool, Error> {
+        match item {
+            Ok(transaction) => {
+                self.metrics.rows_parsed += 1;
+                self.apply_recorded(transaction)?;
+                Ok(true)
+            }
+            Err(err @ Error::Parse(ParseError::Row { line, .. })) => {
+                let mut record = ByteRecord::from(raw_fields());
+                // Sources without lines, and streams, may not know where the item was
+                if line > 0 {
+                    let mut position = csv::Position::new();
+                    position.set_line(line);
+                    record.set_position(Some(position));
+                }
+                if let Err(err) = self.handle_unparsed(&record, err) {
+                    self.metrics.errors += 1;
+                    return Err(err);
+                }
+                Ok(false)
+            }
+            Err(err) => {
+                self.metrics.errors += 1;
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs one batch of loading, traced as a span when the `otel` feature is enabled
+    fn batch<R>(
+        &mut self,
+        name: &'static str,
+        load: impl FnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        #[cfg(feature = "otel")]
+        self.telemetry.start_batch(name);
+        #[cfg(not(feature = "otel"))]
+        let _ = name;
+        let result = load(self);
+        #[cfg(feature = "otel")]
+        self.telemetry.end_batch(&result);
+        result
+    }
+
+    /// Consults the error policy about a row that couldn't be parsed, returning the error unless
+    /// the row is to be skipped
+    fn handle_unparsed(&mut self, record: &ByteRecord, error: Error) -> Result<(), Error> {
+        self.metrics.rows_unparsed += 1;
+        match self
+            .error_policy
+            .0
+            .on_error(FailedRow::Unparsed(record), &error, 1)
+        {
+            ErrorAction::Continue => {
+                self.metrics.errors += 1;
+                if !self.listeners.is_empty() {
+                    self.listeners.read(&self.provenance());
+                }
+                self.listeners.unparsed(record, &error);
+                Ok(())
+            }
+            ErrorAction::Abort | ErrorAction::Retry => Err(error),
+        }
+    }
+
+    /// Hands a custom transaction to its handler, asking the error policy what to do if it's
+    /// rejected, as for a row that couldn't be parsed but retrying if the policy says to
+    fn apply_custom(
+        &mut self,
+        transaction: &CustomTransaction,
+        record: &ByteRecord,
+    ) -> Result<(), Error> {
+        if self.cancellation.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        // Custom transactions carry an ID of their own, as deposits and withdrawals do
+        let position = Position {
+            own_id: Some(transaction.transaction_id),
+            timestamp: transaction.timestamp,
+            referral: None,
+        };
+        if !self.hand_over(position) {
+            return Ok(());
+        }
+        let mut attempt = 0;
+        loop {
+            let Err(err) = self.handlers.apply(
+                transaction,
+                &mut self.account_book,
+                &mut self.transaction_log,
+            ) else {
+                self.metrics.touched.insert(transaction.client_id);
+                if !self.listeners.is_empty() {
+                    self.listeners.read(&self.provenance());
+                    if let Ok(Some(account)) = self.account_book.get(transaction.client_id) {
+                        self.listeners.custom_applied(transaction, account);
+                    }
+                }
+                return Ok(());
+            };
+            attempt += 1;
+            let action = self
+                .error_policy
+                .0
+                .on_error(FailedRow::Unparsed(record), &err, attempt);
+            if action != ErrorAction::Retry || self.cancellation.is_cancelled() {
+                self.metrics.errors += 1;
+                return match action {
+                    ErrorAction::Continue => Ok(()),
+                    ErrorAction::Abort | ErrorAction::Retry => Err(err),
+                };
+            }
+        }
+    }
+
+    /// Returns whether a transaction should be applied, rather than skipped as one the last
+    /// [backfill](Self::backfill) already applied
+    fn hand_over(&mut self, position: Position) -> bool {
+        let Some(handover) = &mut self.handover else {
+            return true;
+        };
+        if !handover.admit(position) {
+            return false;
+        }
+        if handover.is_live() {
+            self.handover = None;
+        }
+        true
+    }
+
+    /// Applies a single transaction, recording its outcome in the engine's metrics.
+    ///
+    /// If it's rejected, the error policy decides whether to retry it, skip it, or return the
+    /// error. Only storing it is retried: one turned away by the rate limit or the engine's own
+    /// checks won't fare any better a second time, so a retry returns the error for those.
+    fn apply_recorded(&mut self, transaction: Transaction) -> Result<(), Error> {
+        if self.cancellation.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if !self.hand_over(Position::from(&TransactionRecord::from(&transaction))) {
+            return Ok(());
+        }
+        match self.apply_with_policy(transaction) {
+            (_, ErrorAction::Continue) => Ok(()),
+            (result, ErrorAction::Abort | ErrorAction::Retry) => result,
+        }
+    }
+
+    /// Applies a single transaction, recording its outcome in the engine's metrics, and returns
+    /// the outcome along with what the error policy made of it, if it was rejected
+    fn apply_with_policy(&mut self, transaction: Transaction) -> (Result<(), Error>, ErrorAction) {
+        // Counted as applied or rejected, depending on the outcome
+        let mut counts = TransactionCounts::default();
+        counts.increment(&transaction.transaction_type);
+        let record = TransactionRecord::from(&transaction);
+        if !self.listeners.is_empty() {
+            self.listeners.read(&self.provenance());
+        }
+        let prior = (!self.listeners.is_empty()).then(|| self.prior_state(&record));
+        // Only a chargeback can lock an account
+        let was_locked =
+            record.transaction_type == TransactionType::Chargeback && self.is_locked(&record);
+        let started = self.settings.record_latency.then(|| self.clock.instant());
+        #[cfg(feature = "otel")]
+        let started_at = self.clock.now();
+        let signed = self.is_signed(&transaction);
+        let mut state = transaction.into();
+        // Checked once, so retries don't take more of the rate limit than the transaction does
+        let checked = self
+            .admit(&record)
+            .and_then(|()| check_signed(signed, &record))
+            .and_then(|()| self.check_order(&record))
+            .and_then(|()| self.validate(&record));
+        let (result, action) = match checked {
+            Err(err) => {
+                let action = self
+                    .error_policy
+                    .0
+                    .on_error(FailedRow::Rejected(&record), &err, 1);
+                (Err(err), action)
+            }
+            Ok(()) => {
+                let mut attempt = 0;
+                loop {
+                    let Err(err) = self
+                        .account_book
+                        .apply(&mut self.transaction_log, &mut state)
+                    else {
+                        break (Ok(()), ErrorAction::Continue);
+                    };
+                    attempt += 1;
+                    let action =
+                        self.error_policy
+                            .0
+                            .on_error(FailedRow::Rejected(&record), &err, attempt);
+                    if action != ErrorAction::Retry || self.cancellation.is_cancelled() {
+                        break (Err(err), action);
+                    }
+                }
+            }
+        };
+        if let Some(started) = started {
+            let latency = self.clock.instant().saturating_duration_since(started);
+            self.metrics.apply_latency.record(latency);
+        }
+        match result {
+            Ok(()) => {
+                self.metrics.applied += counts;
+                self.metrics.last_applied = Some(record.transaction_id);
+                self.metrics.touched.insert(record.client_id);
+                if let Some(amount) = self.funds_moved(&record) {
+                    let scale = self.account_book.scale();
+                    self.metrics
+                        .funds
+                        .record_funds(record.transaction_type, amount, scale);
+                    if record.transaction_type == TransactionType::Chargeback {
+                        let mut amount = amount;
+                        amount.rescale(scale);
+                        let total = self.charged_back.entry(record.client_id).or_default();
+                        *total = total.saturating_add(amount);
+                    }
+                    self.track_dispute(&record);
+                }
+                match record.transaction_type {
+                    // Only these are registered in the log
+                    TransactionType::Deposit | TransactionType::Withdrawal => {
+                        self.metrics.funds_moved = self
+                            .metrics
+                            .funds_moved
+                            .saturating_add(record.amount.unwrap_or_default().abs());
+                        let provenance = self.provenance();
+                        self.transaction_log
+                            .record_provenance(record.transaction_id, provenance);
+                    }
+                    TransactionType::Chargeback if !was_locked => {
+                        let locked = self
+                            .account_book
+                            .account(record.client_id)
+                            .is_ok_and(Account::is_locked);
+                        self.metrics.accounts_locked += u64::from(locked);
+                    }
+                    _ => {}
+                }
+                if self.settings.paranoid {
+                    self.check_invariants(&record);
+                }
+            }
+            Err(_) => {
+                self.metrics.rejected += counts;
+                self.metrics.errors += 1;
+            }
+        }
+        #[cfg(feature = "otel")]
+        self.telemetry.record(&record, started_at, &result);
+        if let Some(prior) = prior {
+            self.notify_listeners(&record, &prior, &result);
+        }
+        if result.is_ok() {
+            for warning in [self.order_warning(&record), self.warning(&record)]
+                .into_iter()
+                .flatten()
+            {
+                self.metrics.warnings += 1;
+                self.listeners.warning(&warning);
+            }
+            self.latest_timestamp = self.latest_timestamp.max(record.timestamp);
+        }
+        (result, action)
+    }
+
+    /// Checks the account a transaction has just been applied to against
+    /// [`EngineSettings::paranoid`] invariants
+    fn check_invariants(&mut self, transaction: &TransactionRecord) {
+        let referred_amount = self.referred_amount(transaction);
+        if let Ok(account) = self.account_book.account(transaction.client_id) {
+            self.invariants.check(transaction, referred_amount, account);
+        }
+    }
+
+    /// Returns the amount of the transaction a dispute, resolve or chargeback refers to, if it
+    /// can be found and has one
+    fn referred_amount(&self, transaction: &TransactionRecord) -> Option<Decimal> {
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => None,
+            _ => self
+                .transaction_log
+                .transaction(transaction.transaction_id)
+                .ok()
+                .flatten()
+                .and_then(|referred| referred.amount),
+        }
+    }
+
+    /// Returns the funds moved by a transaction that's just been applied, if it moved any
+    fn funds_moved(&self, transaction: &TransactionRecord) -> Option<Decimal> {
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => transaction.amount,
+            _ => self.referred_amount(transaction),
+        }
+    }
+
+    /// Takes a transaction out of its client's [`EngineSettings::rate_limit`], if there is one
+    fn admit(&mut self, transaction: &TransactionRecord) -> Result<(), Error> {
+        match &mut self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.admit(transaction.client_id, &self.clock),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a transaction against the stricter rules enabled by
+    /// [`EngineSettings::validate_transactions`], if they are
+    fn validate(&mut self, transaction: &TransactionRecord) -> Result<(), Error> {
+        if !self.settings.validate_transactions {
+            return Ok(());
+        }
+        validate(&self.account_book, &self.transaction_log, transaction)
+    }
+
+    /// Returns whether a transaction is signed by its client, or doesn't need to be because the
+    /// engine has no verifier
+    #[cfg_attr(not(feature = "signatures"), allow(clippy::unused_self))]
+    fn is_signed(&self, transaction: &Transaction) -> bool {
+        #[cfg(feature = "signatures")]
+        if let Some(verifier) = &self.verifier {
+            return verifier.verify(transaction).is_ok();
+        }
+        let _ = transaction;
+        true
+    }
+
+    /// Returns the transaction's timestamp and the latest already applied, if it's earlier
+    fn out_of_order(&self, transaction: &TransactionRecord) -> Option<(u64, u64)> {
+        let (timestamp, latest) = (transaction.timestamp?, self.latest_timestamp?);
+        (timestamp < latest).then_some((timestamp, latest))
+    }
+
+    /// Rejects a transaction that's out of order, if [`EngineSettings::out_of_order`] says to
+    fn check_order(&self, transaction: &TransactionRecord) -> Result<(), Error> {
+        if self.settings.out_of_order != OutOfOrder::Reject {
+            return Ok(());
+        }
+        match self.out_of_order(transaction) {
+            Some((timestamp, latest)) => Err(DomainError::OutOfOrder {
+                transaction: transaction.transaction_id,
+                timestamp,
+                latest,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Warns about an applied transaction that was out of order, if
+    /// [`EngineSettings::out_of_order`] says to
+    fn order_warning(&self, transaction: &TransactionRecord) -> Option<Warning> {
+        if self.settings.out_of_order != OutOfOrder::Warn {
+            return None;
+        }
+        let (timestamp, latest) = self.out_of_order(transaction)?;
+        Some(Warning::OutOfOrder {
+            client: transaction.client_id,
+            transaction: transaction.transaction_id,
+            timestamp,
+            latest,
+        })
+    }
+
+    /// Counts a dispute of a transaction that's just been applied as opened, or a resolve or
+    /// chargeback as closing one
+    fn track_dispute(&mut self, transaction: &TransactionRecord) {
+        let id = transaction.transaction_id;
+        match transaction.transaction_type {
+            TransactionType::Dispute => *self.disputed.entry(id).or_default() += 1,
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                if let Some(open) = self.disputed.get_mut(&id) {
+                    *open -= 1;
+                    if *open == 0 {
+                        self.disputed.remove(&id);
+                    }
+                }
+            }
+            TransactionType::Deposit | TransactionType::Withdrawal => {}
+        }
+    }
+
+    /// Returns whether a transaction's client has a locked account, looked up without creating
+    /// one, since the transaction may yet be rejected
+    fn is_locked(&self, transaction: &TransactionRecord) -> bool {
+        self.account_book
+            .get(transaction.client_id)
+            .is_ok_and(|account| account.is_some_and(Account::is_locked))
+    }
+
+    /// Checks an applied transaction for anything questionable
+    fn warning(&self, transaction: &TransactionRecord) -> Option<Warning> {
+        if let TransactionType::Deposit | TransactionType::Withdrawal = transaction.transaction_type
+        {
+            return None;
+        }
+        // If the log can't be read, there's no telling whether the reference is missing
+        let referred = self
+            .transaction_log
+            .transaction(transaction.transaction_id)
+            .ok()?;
+        referred
+            .and_then(|referred| referred.amount)
+            .is_none()
+            .then_some(Warning::UnknownReference {
+                transaction_type: transaction.transaction_type,
+                client: transaction.client_id,
+                transaction: transaction.transaction_id,
+            })
+    }
+
+    /// Returns where the row being applied was read from
+    fn provenance(&self) -> Provenance {
+        Provenance {
+            source: Arc::clone(&self.input),
+            line: self.line,
+        }
+    }
+
+    /// Captures what listeners need to know from before a transaction is applied
+    fn prior_state(&self, transaction: &TransactionRecord) -> PriorState {
+        let was_locked = self.is_locked(transaction);
+        let referred = match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => None,
+            _ => self
+                .transaction_log
+                .transaction(transaction.transaction_id)
+                .ok()
+                .flatten()
+                .filter(|referred| referred.amount.is_some()),
+        };
+        PriorState {
+            was_locked,
+            referred,
+        }
+    }
+
+    /// Tells listeners about a transaction that's just been applied or rejected
+    fn notify_listeners(
+        &mut self,
+        transaction: &TransactionRecord,
+        prior: &PriorState,
+        result: &Result<(), Error>,
+    ) {
+        match result {
+            // If the account can't be looked up, there's nothing to tell listeners about it
+            Ok(()) => {
+                let scale = self.account_book.scale();
+                if let Ok(account) = self.account_book.account(transaction.client_id) {
+                    self.listeners.applied(transaction, prior, account, scale);
+                }
+            }
+            Err(err) => self.listeners.rejected(transaction, err),
+        }
+    }
+
+    /// Returns a snapshot of everything this engine has processed so far
+    #[must_use]
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+
+    /// Returns the running totals across all accounts, by transaction type and balance.
+    ///
+    /// These are kept up to date as transactions are applied, so unlike
+    /// [`AccountGauges`](crate::metrics::AccountGauges) they don't need a scan of the account book.
+    #[must_use]
+    pub fn stats(&self) -> EngineStats {
+        self.metrics.stats()
+    }
+
+    /// Summarizes everything this engine has processed so far, for reporting at the end of a run
+    #[must_use]
+    pub fn summary(&self) -> RunSummary {
+        self.metrics.summary()
+    }
+
+    /// Checks both the account book's and the transaction log's storage can be reached
+    /// # Errors
+    /// The first error either storage reports
+    pub fn check_storage(&self) -> Result<(), Error> {
+        self.account_book.check_connection()?;
+        self.transaction_log.check_connection()
+    }
+
+    /// Rebuilds the accounts as they were at `timestamp`, in the same units as each transaction's
+    /// [timestamp](Transaction::timestamp), by replaying the events of every transaction applied
+    /// up to then. See [`EventHistory::state_at`].
+    ///
+    /// Returns `None` unless [`EngineSettings::keep_history`] is set.
+    #[must_use]
+    pub fn state_at(&self, timestamp: u64) -> Option<MemoryAccountBook> {
+        let scale = self.account_book.scale();
+        Some(self.history.as_ref()?.state_at(timestamp, scale))
+    }
+
+    /// Works out what applying a transaction would do to its client's account, by the same rules
+    /// as [`apply`](Self::apply), without changing anything. Rate limits and a backfill's
+    /// hand-over aren't taken into account, and the built-in rules are used whatever the account
+    /// book's [`AccountBook::apply`]. See [`crate::preview`].
+    /// # Errors
+    /// The error applying the transaction would be rejected with, or any error reading the
+    /// account book or transaction log
+    pub fn simulate(&self, transaction: &Transaction) -> Result<ProjectedAccount, Error> {
+        let record = TransactionRecord::from(transaction);
+        let client = record.client_id;
+        check_signed(self.is_signed(transaction), &record)?;
+        self.check_order(&record)?;
+        // Looked up without creating the account, in case the client has none
+        let scale = self.account_book.scale();
+        let before = self
+            .account_book
+            .get(client)?
+            .cloned()
+            .unwrap_or_else(|| Account::with_scale(client, scale));
+        let mut book = MemoryAccountBook::new().with_scale(scale);
+        book.accounts.insert(client, before.clone());
+        if self.settings.validate_transactions {
+            validate(&book, &self.transaction_log, &record)?;
+        }
+        ops::apply_transaction(
+            &mut book,
+            &mut ReadThrough(&self.transaction_log),
+            &mut ops::copy_of(transaction).into(),
+        )?;
+        let after = book
+            .accounts
+            .remove(&client)
+            .unwrap_or_else(|| before.clone());
+        let warnings = [self.order_warning(&record), self.warning(&record)]
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(ProjectedAccount {
+            before,
+            after,
+            warnings,
+        })
+    }
+
+    /// Checks each account's total against what its transactions in the log, and the
+    /// chargebacks this engine applied, add up to, to catch the account book and transaction log
+    /// drifting apart. Clients whose transactions were purged by retention or erasure start from
+    /// what those came to. See [`crate::consistency`].
+    #[must_use]
+    pub fn check_consistency(&self) -> ConsistencyReport {
+        self.consistency_check().check(
+            self.account_book.accounts(),
+            self.transaction_log.transactions(),
+        )
+    }
+
+    /// Returns the [`ConsistencyCheck`] that [`check_consistency`](Self::check_consistency)
+    /// makes, with the chargebacks this engine applied and the opening balances of clients whose
+    /// transactions were purged, eg to check other accounts against this engine's log
+    #[must_use]
+    pub fn consistency_check(&self) -> ConsistencyCheck {
+        ConsistencyCheck::new(self.account_book.scale())
+            .with_opening_totals(self.opening.iter().map(|(&client, &total)| (client, total)))
+            .with_chargebacks(self.charged_back())
+    }
+
+    /// Returns the funds charged back from each client's account by the chargebacks this engine
+    /// applied, by client, rounded to the account book's [scale](AccountBook::scale). Chargebacks
+    /// aren't registered in the transaction log, so this is the only record of them.
+    pub fn charged_back(&self) -> impl Iterator<Item = (ClientId, Decimal)> + '_ {
+        self.charged_back
+            .iter()
+            .map(|(&client, &amount)| (client, amount))
+    }
+
+    /// Returns the transactions under dispute, with how many disputes of each are still open,
+    /// by transaction. A resolve or chargeback closes one dispute of its transaction.
+    pub fn disputed(&self) -> impl Iterator<Item = (TransactionId, u32)> + '_ {
+        self.disputed.iter().map(|(&id, &open)| (id, open))
+    }
+
+    /// Unlocks a client's account, eg once the chargeback that locked it has been looked into,
+    /// returning whether it was locked, or `None` if the client has no account. No transaction
+    /// is involved, so listeners aren't told.
+    /// # Errors
+    /// If the account book fails
+    pub fn unlock(&mut self, client: ClientId) -> Result<Option<bool>, Error> {
+        // Looked up without creating the account, in case the client has none
+        if self.account_book.get(client)?.is_none() {
+            return Ok(None);
+        }
+        let account = self.account_book.account_mut(client)?;
+        Ok(Some(std::mem::replace(&mut account.locked, false)))
+    }
+
+    /// Erases a client's transactions, eg at their request under data protection law, returning
+    /// a sealed summary of what was erased, which every listener is also given. The account
+    /// keeps its balances, so totals across the book are unchanged. The summary is sealed with an
+    /// HMAC keyed with the secret, which needs keeping to check it later. See [`crate::erasure`].
+    ///
+    /// A client can't be erased while any of their transactions is under dispute, since the
+    /// dispute could then never be resolved or charged back.
+    /// # Errors
+    /// [`DomainError::Disputed`] for the first of the client's transactions under dispute, or if
+    /// the transaction log can't be read or purged, or the summary can't be sealed, in which case
+    /// nothing is erased and listeners aren't told
+    pub fn erase_client(&mut self, client: ClientId, secret: &[u8]) -> Result<Erasure, Error> {
+        let mut records: Vec<TransactionRecord> = self
+            .transaction_log
+            .transactions()
+            .filter(|record| record.client_id() == client)
+            .collect();
+        records.sort_unstable_by_key(TransactionRecord::transaction_id);
+        if let Some(disputed) = records
+            .iter()
+            .find(|record| self.disputed.contains_key(&record.transaction_id))
+        {
+            return Err(DomainError::Disputed(disputed.transaction_id).into());
+        }
+        // Looked up without creating the account, in case the client has none
+        let account = self
+            .account_book
+            .get(client)?
+            .cloned()
+            .unwrap_or_else(|| Account::with_scale(client, self.account_book.scale()));
+        let ids: Vec<TransactionId> = records
+            .iter()
+            .map(TransactionRecord::transaction_id)
+            .collect();
+        // Sealed first, so nothing is purged unless it can be summed up
+        let mut erasure = Erasure::seal(&account, &records, secret)?;
+        erasure.transactions = self.transaction_log.purge(&ids)?;
+        // The account's balances stand in for its history now, chargebacks included
+        let mut purged = Vec::with_capacity(erasure.transactions);
+        for record in records {
+            if self
+                .transaction_log
+                .transaction(record.transaction_id)?
+                .is_none()
+            {
+                purged.push(record);
+            }
+        }
+        self.carry_forward(&purged);
+        if let Some(charged_back) = self.charged_back.remove(&client) {
+            let opening = self.opening.entry(client).or_default();
+            *opening = opening.saturating_sub(charged_back);
+        }
+        self.listeners.erased(&erasure);
+        Ok(erasure)
+    }
+
+    /// Returns the account book
+    #[must_use]
+    pub fn account_book(&self) -> &A {
+        &self.account_book
+    }
+
+    /// Returns the transaction log
+    #[must_use]
+    pub fn transaction_log(&self) -> &T {
+        &self.transaction_log
+    }
+
+    /// Consumes the engine, returning the account book and transaction log
+    #[must_use]
+    pub fn into_parts(self) -> (A, T) {
+        (self.account_book, self.transaction_log)
+    }
+}
+
+impl Engine<MemoryAccountBook, MemoryTransactionLog> {
+    /// Creates an engine with default settings that keeps everything in memory, the simplest way
+    /// to get started
+    #[must_use]
+    pub fn in_memory() -> Self {
+        Self::new(MemoryAccountBook::new(), MemoryTransactionLog::new())
+    }
+}
+
+/// Rejects a transaction that isn't signed by its client, as found by [`Engine::is_signed`]
+fn check_signed(signed: bool, transaction: &TransactionRecord) -> Result<(), Error> {
+    if signed {
+        return Ok(());
+    }
+    Err(DomainError::BadSignature {
+        transaction: transaction.transaction_id,
+        client: transaction.client_id,
+    }
+    .into())
+}
+
+/// Checks a transaction against the stricter rules of [`EngineSettings::validate_transactions`]
+pub(crate) fn validate<A, T>(
+    account_book: &A,
+    transaction_log: &T,
+    transaction: &TransactionRecord,
+) -> Result<(), Error>
+where
+    A: AccountBook + ?Sized,
+    T: TransactionLog + ?Sized,
+{
+    let transaction_id = transaction.transaction_id;
+    match transaction.transaction_type {
+        TransactionType::Deposit | TransactionType::Withdrawal => {
+            let amount = transaction
+                .amount
+                .ok_or(DomainError::MissingAmount(transaction_id))?;
+            if amount <= Decimal::ZERO {
+                return Err(DomainError::InvalidAmount {
+                    transaction: transaction_id,
+                    amount,
+                }
+                .into());
+            }
+            if let TransactionType::Withdrawal = transaction.transaction_type {
+                // Looked up without creating the account, since the withdrawal may be rejected
+                let (locked, available) = account_book
+                    .get(transaction.client_id)?
+                    .map_or((false, Decimal::ZERO), |account| {
+                        (account.is_locked(), account.funds_available())
+                    });
+                // Locked accounts are left for the usual lock check to reject
+                if !locked && amount > available {
+                    return Err(DomainError::InsufficientFunds {
+                        client: transaction.client_id,
+                        requested: amount,
+                        available,
+                    }
+                    .into());
+                }
+            }
+        }
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+            let referred = transaction_log
+                .transaction(transaction_id)?
+                .ok_or(DomainError::UnknownReference(transaction_id))?;
+            if referred.client_id != transaction.client_id {
+                return Err(DomainError::ClientMismatch {
+                    transaction: transaction_id,
+                    owner: referred.client_id,
+                    client: transaction.client_id,
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        clock::MockClock,
+        errors::{RetryWithBackoff, SkipAndCollect},
+        metrics::AccountGauges,
+        types::{
+            ClientId, MemoryAccountBook, MemoryTransactionLog, ProvenanceLog, RawTransactionId,
+            Retrying, TransactionId,
+        },
+    };
+
+    use crate::source::{JsonLinesSource, MemorySource};
+
+    use super::*;
+
+    /// An account book that fails a set number of times before working
+    #[derive(Default)]
+    struct FlakyAccountBook {
+        /// Where accounts are actually kept
+        inner: MemoryAccountBook,
+        /// How many more calls to fail
+        failures: u32,
+    }
+
+    impl AccountBook for FlakyAccountBook {
+        fn account(&mut self, client_id: ClientId) -> Result<&Account, Error> {
+            self.inner.account(client_id)
+        }
+
+        fn account_mut(&mut self, client_id: ClientId) -> Result<&mut Account, Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Error::Transient("blip".into()));
+            }
+            self.inner.account_mut(client_id)
+        }
+
+        fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+            self.inner.accounts()
+        }
+    }
+
+    /// A transaction log whose registrations fail a set number of times before working
+    #[derive(Default)]
+    struct FlakyTransactionLog {
+        /// Where transactions are actually kept
+        inner: MemoryTransactionLog,
+        /// How many more registrations to fail
+        failures: u32,
+    }
+
+    impl TransactionLog for FlakyTransactionLog {
+        fn transaction(
+            &self,
+            transaction_id: TransactionId,
+        ) -> Result<Option<TransactionRecord>, Error> {
+            self.inner.transaction(transaction_id)
+        }
+
+        fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Error::Transient("blip".into()));
+            }
+            self.inner.register(transaction)
+        }
+    }
+
+    const TEST_INPUT_CSV: &[u8] = b"type, client, tx, amount
+deposit,    1,  1,    7.0
+deposit,    2,  2,    2.0
+withdrawal, 1,  3,    1.5
+dispute,    2,  2,
+";
+
+    #[test]
+    fn test_pooled_and_unpooled_agree() {
+        let mut results = vec![];
+        for pooled_records in [false, true] {
+            let mut engine = Engine::with_settings(
+                MemoryAccountBook::new(),
+                MemoryTransactionLog::new(),
+                EngineSettings {
+                    pooled_records,
+                    ..EngineSettings::default()
+                },
+            );
+            engine.load_csv(&mut Cursor::new(TEST_INPUT_CSV)).unwrap();
+            let (mut book, _) = engine.into_parts();
+            let first = book.account(1.into()).unwrap().funds_available();
+            let second = book.account(2.into()).unwrap().funds_held();
+            results.push((first, second));
+        }
+        assert_eq!(results[0], (dec!(5.5), dec!(2)));
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let token = engine.cancellation_token();
+        token.cancel();
+        let result = engine.load_csv(&mut Cursor::new(TEST_INPUT_CSV));
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(engine.account_book().accounts.is_empty());
+
+        engine.set_cancellation_token(CancellationToken::new());
+        engine.load_csv(&mut Cursor::new(TEST_INPUT_CSV)).unwrap();
+        assert_eq!(engine.account_book().accounts.len(), 2);
+    }
+
+    #[test]
+    fn test_skip_and_collect() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let policy = SkipAndCollect::new();
+        engine.set_error_policy(policy.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,oops
+dispute,1,1,
+chargeback,1,1,
+deposit,1,3,1.0
+deposit,2,4,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let skipped = policy.skipped();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].transaction.is_none());
+        assert!(skipped[0].reason.contains("line 3"));
+        assert_eq!(skipped[1].reason, "Account id[1] is locked");
+        assert_eq!(engine.metrics().errors, 2);
+        assert_eq!(engine.metrics().applied.deposits, 2);
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        let book = FlakyAccountBook {
+            failures: 2,
+            ..FlakyAccountBook::default()
+        };
+        let mut engine = Engine::new(book, MemoryTransactionLog::new());
+        engine.set_error_policy(RetryWithBackoff::new(2, Duration::ZERO));
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(engine.metrics().applied.deposits, 1);
+
+        let book = FlakyAccountBook {
+            failures: 3,
+            ..FlakyAccountBook::default()
+        };
+        let mut engine = Engine::new(book, MemoryTransactionLog::new());
+        engine.set_error_policy(RetryWithBackoff::new(2, Duration::ZERO));
+        let result = engine.load_csv(&mut Cursor::new(input));
+        assert!(matches!(result, Err(Error::Transient(_))));
+    }
+
+    #[test]
+    fn test_retry_admits_once() {
+        let settings = EngineSettings {
+            rate_limit: Some(RateLimit::per_second(2)),
+            ..EngineSettings::default()
+        };
+        let book = FlakyAccountBook {
+            failures: 2,
+            ..FlakyAccountBook::default()
+        };
+        let mut engine = Engine::with_settings(book, MemoryTransactionLog::new(), settings);
+        engine.set_clock(MockClock::new(UNIX_EPOCH));
+        engine.set_error_policy(RetryWithBackoff::new(2, Duration::ZERO));
+        let deposit = |tx| Transaction::deposit(ClientId(1), TransactionId(tx), dec!(1)).unwrap();
+        // Two failed attempts to store the first deposit leave the second its token
+        engine.apply(deposit(1)).unwrap();
+        engine.apply(deposit(2)).unwrap();
+        assert!(matches!(
+            engine.apply(deposit(3)),
+            Err(Error::RateLimited { .. })
+        ));
+    }
+
+    #[test]
+    fn test_retrying_storage() {
+        let backoff = RetryWithBackoff::new(3, Duration::ZERO);
+        let book = FlakyAccountBook {
+            failures: 3,
+            ..FlakyAccountBook::default()
+        };
+        let log = FlakyTransactionLog {
+            failures: 3,
+            ..FlakyTransactionLog::default()
+        };
+        let mut engine = Engine::new(Retrying::new(book, backoff), Retrying::new(log, backoff));
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\n";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let account = engine.account_book.account(1.into()).unwrap();
+        assert_eq!(account.funds_held(), dec!(5));
+        assert_eq!(engine.account_book.accounts().count(), 1);
+
+        // Gives up once out of retries
+        let log = FlakyTransactionLog {
+            failures: 4,
+            ..FlakyTransactionLog::default()
+        };
+        let mut engine = Engine::new(MemoryAccountBook::new(), Retrying::new(log, backoff));
+        let result = engine.load_csv(&mut Cursor::new("type,client,tx,amount\ndeposit,1,1,5.0\n"));
+        assert!(matches!(result, Err(Error::Transient(_))));
+    }
+
+    #[test]
+    fn test_validate_transactions() {
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            EngineSettings {
+                validate_transactions: true,
+                ..EngineSettings::default()
+            },
+        );
+        let policy = SkipAndCollect::new();
+        engine.set_error_policy(policy.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,-1.0
+withdrawal,1,3,6.0
+withdrawal,1,4,
+dispute,1,9,
+dispute,2,1,
+withdrawal,1,5,5.0
+withdrawal,3,6,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let reasons: Vec<_> = policy
+            .skipped()
+            .into_iter()
+            .map(|skipped| skipped.reason)
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                "Amount -1.0 of transaction id id[2] is not positive",
+                "Withdrawal of 6.0 from account id[1] exceeds available funds of 5.0000",
+                "Transaction id id[4] is missing an amount",
+                "Transaction id id[9] refers to an unknown transaction",
+                "Transaction id id[1] belongs to account id[1], not account id[2]",
+                "Withdrawal of 1.0 from account id[3] exceeds available funds of 0",
+            ]
+        );
+        // Rejected transactions don't open accounts for clients that have none
+        assert_eq!(engine.account_book().accounts().count(), 1);
+        let (mut book, _) = engine.into_parts();
+        assert_eq!(book.account(1.into()).unwrap().total(), dec!(0));
+    }
+
+    #[test]
+    fn test_unlock() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        assert_eq!(engine.unlock(ClientId(1)).unwrap(), None);
+        // Looking the client up didn't open an account for them
+        assert_eq!(engine.account_book().get(ClientId(1)).unwrap(), None);
+        engine
+            .load_csv(&mut Cursor::new(
+                "type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1,\nchargeback,1,1,\n",
+            ))
+            .unwrap();
+        assert_eq!(engine.unlock(ClientId(1)).unwrap(), Some(true));
+        assert_eq!(engine.unlock(ClientId(1)).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let (sender, receiver) = std::sync::mpsc::channel::<Warning>();
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(sender);
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,
+resolve,1,1,
+chargeback,2,7,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(engine.metrics().warnings, 1);
+        drop(engine);
+        let warnings: Vec<_> = receiver.iter().map(|warning| warning.to_string()).collect();
+        assert_eq!(
+            warnings,
+            ["Chargeback for account id[2] refers to unknown transaction id id[7], so was ignored"]
+        );
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,5.0,100
+deposit,1,2,1.0,
+deposit,2,3,2.0,90
+dispute,1,1,,120
+withdrawal,2,4,1.0,110
+";
+        let load = |out_of_order| {
+            let (sender, receiver) = std::sync::mpsc::channel::<Warning>();
+            let settings = EngineSettings {
+                out_of_order,
+                ..EngineSettings::default()
+            };
+            let mut engine = Engine::with_settings(
+                MemoryAccountBook::new(),
+                MemoryTransactionLog::new(),
+                settings,
+            );
+            engine.add_listener(sender);
+            let policy = SkipAndCollect::new();
+            engine.set_error_policy(policy.clone());
+            engine.load_csv(&mut Cursor::new(input)).unwrap();
+            let (book, log) = engine.into_parts();
+            let warnings: Vec<_> = receiver.iter().map(|warning| warning.code()).collect();
+            (book, log, warnings, policy.skipped())
+        };
+
+        let (book, log, warnings, skipped) = load(OutOfOrder::Allow);
+        let timestamp = |id| {
+            log.transaction(TransactionId(id))
+                .unwrap()
+                .unwrap()
+                .timestamp()
+        };
+        assert_eq!((timestamp(1), timestamp(2)), (Some(100), None));
+        assert_eq!(book.accounts[&ClientId(2)].total(), dec!(1));
+        assert!(warnings.is_empty() && skipped.is_empty());
+
+        let (book, _, warnings, skipped) = load(OutOfOrder::Warn);
+        assert_eq!(book.accounts[&ClientId(2)].total(), dec!(1));
+        assert_eq!(warnings, ["out_of_order", "out_of_order"]);
+        assert!(skipped.is_empty());
+
+        let (book, _, warnings, skipped) = load(OutOfOrder::Reject);
+        // Every transaction of client 2 was rejected, so they have no account
+        assert!(!book.accounts.contains_key(&ClientId(2)));
+        assert!(warnings.is_empty());
+        let reasons: Vec<_> = skipped.into_iter().map(|skipped| skipped.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                "Transaction id id[3] at 90 is earlier than 100, already applied",
+                "Transaction id id[4] at 110 is earlier than 120, already applied",
+            ]
+        );
+    }
+
+    #[cfg(feature = "signatures")]
+    #[test]
+    fn test_signatures() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        use crate::{signature::SignatureVerifier, types::Signature};
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sign = |message: &str| Signature::from_bytes(key.sign(message.as_bytes()).to_bytes());
+        let input = format!(
+            "type,client,tx,amount,signature
+deposit,1,1,5.0,{}
+deposit,1,2,3.0,{}
+deposit,1,3,2.0,
+",
+            sign("deposit,1,1,5.0,,"),
+            sign("deposit,1,2,30.0,,"),
+        );
+        let mut verifier = SignatureVerifier::new();
+        verifier.add_key(ClientId(1), key.verifying_key());
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.set_verifier(verifier);
+        let policy = SkipAndCollect::new();
+        engine.set_error_policy(policy.clone());
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let (book, _) = engine.into_parts();
+        assert_eq!(book.accounts[&ClientId(1)].total(), dec!(5));
+        let reasons: Vec<_> = policy
+            .skipped()
+            .into_iter()
+            .map(|skipped| skipped.reason)
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                "Transaction id id[2] isn't signed by the key of account id[1]",
+                "Transaction id id[3] isn't signed by the key of account id[1]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_at() {
+        let settings = EngineSettings {
+            keep_history: true,
+            ..EngineSettings::default()
+        };
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        let input = "type,client,tx,amount,timestamp
+deposit,7,1,10.0,100
+withdrawal,7,2,3.0,200
+deposit,8,3,5.0,
+dispute,7,1,,300
+chargeback,7,1,,400
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert!(engine.state_at(0).unwrap().accounts.is_empty());
+        let book = engine.state_at(250).unwrap();
+        assert_eq!(book.accounts[&ClientId(7)].funds_available(), dec!(7));
+        // Client 8's deposit has no timestamp, so happened along with the withdrawal before it
+        assert_eq!(book.accounts[&ClientId(8)].total(), dec!(5));
+        let book = engine.state_at(300).unwrap();
+        assert_eq!(book.accounts[&ClientId(7)].funds_held(), dec!(10));
+        assert!(!book.accounts[&ClientId(7)].is_locked());
+        assert_eq!(
+            engine.state_at(u64::MAX).unwrap().accounts,
+            engine.account_book().accounts
+        );
+        assert!(Engine::in_memory().state_at(0).is_none());
+    }
+
+    #[test]
+    fn test_clock() {
+        let settings = EngineSettings {
+            rate_limit: Some(RateLimit::per_second(1)),
+            ..EngineSettings::default()
+        };
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        let clock = MockClock::new(UNIX_EPOCH);
+        engine.set_clock(clock.clone());
+        let deposit = |tx| Transaction::deposit(ClientId(1), TransactionId(tx), dec!(1)).unwrap();
+        engine.apply(deposit(1)).unwrap();
+        assert!(matches!(
+            engine.apply(deposit(2)),
+            Err(Error::RateLimited { retry_after, .. }) if retry_after == Duration::from_secs(1)
+        ));
+        clock.advance(Duration::from_secs(1));
+        engine.apply(deposit(3)).unwrap();
+        let input = "type,client,tx,amount\ndeposit,2,4,1.0\n";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(engine.metrics().last_ingest, Some(clock.now()));
+    }
+
+    #[test]
+    fn test_submit_idempotent() {
+        let settings = EngineSettings {
+            idempotency_window: Some(Duration::from_secs(60)),
+            validate_transactions: true,
+            ..EngineSettings::default()
+        };
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        let clock = MockClock::new(UNIX_EPOCH);
+        engine.set_clock(clock.clone());
+        let batch = b"type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\n";
+        let total = |engine: &Engine<MemoryAccountBook, MemoryTransactionLog>| {
+            engine.account_book().accounts[&ClientId(1)].total()
+        };
+
+        let first = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!((first.applied, first.replayed), (1, false));
+        assert_eq!(
+            first.error.as_deref().map(Error::code),
+            Some("insufficient_funds")
+        );
+        let retry = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!((retry.applied, retry.replayed), (1, true));
+        assert_eq!(
+            retry.error.as_deref().map(Error::code),
+            Some("insufficient_funds")
+        );
+        assert_eq!(total(&engine), dec!(5));
+        assert!(matches!(
+            engine.submit_csv(Some("a"), b"type,client,tx,amount\n"),
+            Err(Error::IdempotencyKeyReused(key)) if key == "a"
+        ));
+
+        // Once the window has passed, the key is forgotten, and the batch applied again
+        clock.advance(Duration::from_secs(60));
+        let late = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!((late.applied, late.replayed), (2, false));
+        assert!(late.error.is_none());
+        assert_eq!(total(&engine), dec!(1));
+        assert_eq!(engine.idempotency_keys(), 1);
+        // Without a key, nothing's remembered
+        let batch = b"type,client,tx,amount\ndeposit,1,3,1.0\n";
+        assert_eq!(engine.submit_csv(None, batch).unwrap().applied, 1);
+        assert_eq!(engine.idempotency_keys(), 1);
+        assert_eq!(total(&engine), dec!(2));
+    }
+
+    #[test]
+    fn test_submit_resumes() {
+        let settings = EngineSettings {
+            idempotency_window: Some(Duration::from_secs(60)),
+            rate_limit: Some(RateLimit::per_second(1)),
+            ..EngineSettings::default()
+        };
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        let clock = MockClock::new(UNIX_EPOCH);
+        engine.set_clock(clock.clone());
+        let batch = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\ndeposit,2,3,2.0\n";
+        let total = |engine: &Engine<MemoryAccountBook, MemoryTransactionLog>| {
+            engine.account_book().accounts[&ClientId(1)].total()
+        };
+
+        let first = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!(first.applied, 1);
+        assert_eq!(
+            first.error.as_deref().map(Error::code),
+            Some("rate_limited")
+        );
+        // Still over the limit, so it stops at the same transaction, without applying the first
+        // again
+        let retry = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!((retry.applied, retry.replayed), (1, false));
+        assert_eq!(
+            retry.error.as_deref().map(Error::code),
+            Some("rate_limited")
+        );
+        assert_eq!(total(&engine), dec!(5));
+        // Once it isn't, it carries on from there
+        clock.advance(Duration::from_secs(1));
+        let retry = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!((retry.applied, retry.replayed), (3, false));
+        assert!(retry.error.is_none());
+        assert_eq!(total(&engine), dec!(6));
+        // And is then done with
+        let replayed = engine.submit_csv(Some("a"), batch).unwrap();
+        assert_eq!((replayed.applied, replayed.replayed), (3, true));
+        assert_eq!(total(&engine), dec!(6));
+    }
+
+    #[test]
+    fn test_apply_in_chunks() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        assert!(engine.apply_chunk(10).unwrap().finished);
+        engine.attach_csv(Cursor::new(TEST_INPUT_CSV));
+        let progress = engine.apply_chunk(3).unwrap();
+        assert_eq!(progress.applied, 3);
+        assert!(!progress.finished);
+        assert_eq!(engine.account_book().accounts.len(), 2);
+        let progress = engine.apply_chunk(3).unwrap();
+        assert_eq!(progress.applied, 1);
+        assert!(progress.finished);
+        assert_eq!(engine.metrics().applied.total(), 4);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            EngineSettings {
+                record_latency: true,
+                ..EngineSettings::default()
+            },
+        );
+        engine.load_csv(&mut Cursor::new(TEST_INPUT_CSV)).unwrap();
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,3,9,1.0\nbogus,3,10,1.0\n";
+        assert!(engine.load_csv(&mut Cursor::new(input)).is_err());
+        let metrics = engine.metrics();
+        assert_eq!(metrics.rows_parsed, 5);
+        assert_eq!(metrics.applied.deposits, 3);
+        assert_eq!(metrics.applied.withdrawals, 1);
+        assert_eq!(metrics.applied.disputes, 1);
+        assert_eq!(metrics.errors, 1);
+        assert_eq!(metrics.apply_latency.unwrap().count, 5);
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut engine = Engine::new(
+            MemoryAccountBook::new(),
+            ProvenanceLog::new(MemoryTransactionLog::new()),
+        );
+        let monday: &[u8] = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\n";
+        let tuesday: &[u8] = b"type,client,tx,amount\ndispute,1,1,\nwithdrawal,1,3,2.0\n";
+        let inputs = vec![
+            ("monday.csv".to_string(), Cursor::new(monday)),
+            ("tuesday.csv".to_string(), Cursor::new(tuesday)),
+        ];
+        engine
+            .load_named_csv_files(inputs, MergeOrder::InputOrder)
+            .unwrap();
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndeposit,2,4,1.0\n"))
+            .unwrap();
+        let provenance = |id: RawTransactionId| {
+            let provenance = engine.transaction_log().provenance(id.into()).unwrap();
+            provenance.map(|provenance| provenance.to_string())
+        };
+        assert_eq!(provenance(1).as_deref(), Some("monday.csv:2"));
+        assert_eq!(provenance(2).as_deref(), Some("monday.csv:3"));
+        assert_eq!(provenance(3).as_deref(), Some("tuesday.csv:3"));
+        assert_eq!(provenance(4).as_deref(), Some(":2"));
+        assert_eq!(provenance(5), None);
+    }
+
+    #[test]
+    fn test_run_summary() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.set_error_policy(SkipAndCollect::new());
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.5
+withdrawal,2,3,1.5
+bogus,2,4,1.0
+dispute,1,1,
+chargeback,1,1,
+chargeback,1,1,
+dispute,2,99,
+deposit,1,5,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let summary = engine.summary();
+        assert_eq!(summary.rows_read, 9);
+        assert_eq!(summary.applied.total(), 7);
+        assert_eq!(summary.applied.chargebacks, 2);
+        assert_eq!(summary.ignored, 1);
+        assert_eq!(summary.rejected.deposits, 1);
+        assert_eq!(summary.unparsed, 1);
+        assert_eq!(summary.accounts_touched, 2);
+        assert_eq!(summary.accounts_locked, 1);
+        assert_eq!(summary.funds_moved, dec!(17.0));
+        assert!(summary.to_string().contains("accounts locked: 1\n"));
+    }
+
+    #[test]
+    fn test_facade() {
+        let mut engine = Engine::in_memory();
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndeposit,2,1,5.0\n"))
+            .unwrap();
+        let client = ClientId::from(1);
+        engine
+            .apply(Transaction::deposit(client, TransactionId::from(2), dec!(2.5)).unwrap())
+            .unwrap();
+        engine
+            .apply(Transaction::dispute(client, TransactionId::from(2)))
+            .unwrap();
+        assert!(engine
+            .apply(Transaction::withdrawal(client, TransactionId::from(3), dec!(1)).unwrap())
+            .is_ok());
+        assert_eq!(engine.metrics().applied.total(), 4);
+
+        let mut report = vec![];
+        engine.report(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client,available,held,total,locked
+1,-1.0000,2.5000,1.5000,false
+2,5.0000,0.0000,5.0000,false
+"
+        );
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.accounts.len(), 2);
+        assert_eq!(snapshot.transactions.len(), 3);
+    }
+
+    #[test]
+    fn test_load_source() {
+        let mut engine = Engine::in_memory();
+        engine.set_error_policy(SkipAndCollect::new());
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"3.0"}
+{"type":"deposit","client":1,"tx":"two","amount":"1.0"}
+{"type":"withdrawal","client":1,"tx":3,"amount":"1.0"}
+"#;
+        engine
+            .load_source(&mut JsonLinesSource::new(input.as_bytes()))
+            .unwrap();
+        let summary = engine.summary();
+        assert_eq!((summary.rows_read, summary.unparsed), (3, 1));
+        let mut source = MemorySource::new();
+        source.push(Transaction::dispute(
+            ClientId::from(1),
+            TransactionId::from(1),
+        ));
+        engine.attach_source(source);
+        assert_eq!(
+            engine.apply_chunk(5).unwrap(),
+            ChunkProgress {
+                applied: 1,
+                finished: true
+            }
+        );
+        let account = engine.account_book().accounts.get(&ClientId::from(1));
+        assert_eq!(account.unwrap().funds_held(), dec!(3));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_apply_stream() {
+        let mut engine = Engine::in_memory();
+        engine.set_error_policy(SkipAndCollect::new());
+        let client = ClientId::from(1);
+        let unparsed = ParseError::Row {
+            line: 0,
+            byte: 0,
+            record: "deposit,1,x,1.0".into(),
+            reason: "invalid digit".into(),
+        };
+        let items = vec![
+            Transaction::deposit(client, TransactionId::from(1), dec!(3)),
+            Err(unparsed.into()),
+            Ok(Transaction::dispute(client, TransactionId::from(1))),
+        ];
+        futures::executor::block_on(engine.apply_stream(futures::stream::iter(items))).unwrap();
+        let summary = engine.summary();
+        assert_eq!((summary.rows_read, summary.unparsed), (3, 1));
+        let account = engine.account_book().accounts.get(&client);
+        assert_eq!(account.unwrap().funds_held(), dec!(3));
+
+        let items = vec![Err(Error::Transient("connection reset".into()))];
+        let result = futures::executor::block_on(engine.apply_stream(futures::stream::iter(items)));
+        assert!(matches!(result, Err(Error::Transient(_))));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.set_error_policy(SkipAndCollect::new());
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.5
+withdrawal,2,3,1.5
+dispute,2,2,
+resolve,2,2,
+dispute,1,1,
+chargeback,1,1,
+chargeback,1,1,
+dispute,2,99,
+deposit,1,5,1.0
+deposit,3,6,2.0
+dispute,3,6,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let stats = engine.stats();
+        assert_eq!(stats.applied.total(), 11);
+        assert_eq!(stats.amounts.deposits, dec!(17.5));
+        assert_eq!(stats.amounts.withdrawals, dec!(1.5));
+        assert_eq!(stats.amounts.disputes, dec!(17.5));
+        assert_eq!(stats.amounts.resolves, dec!(5.5));
+        assert_eq!(stats.amounts.chargebacks, dec!(20));
+        // The same as adding up every account
+        let gauges = AccountGauges::from_accounts(engine.account_book());
+        assert_eq!(stats.available, gauges.available);
+        assert_eq!(stats.held, gauges.held);
+        assert_eq!(stats.locked, gauges.locked);
+        assert_eq!(stats.total(), dec!(-4));
+        assert!(stats.to_string().contains("locked accounts: 1\n"));
+    }
+
+    #[test]
+    fn test_paranoid() {
+        let settings = EngineSettings {
+            paranoid: true,
+            ..EngineSettings::default()
+        };
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        // Every quirk the default rules allow, none of which should trip an invariant
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,15.0
+resolve,1,1,
+dispute,1,2,
+dispute,1,2,
+dispute,2,1,
+deposit,1,2,1.0
+chargeback,1,1,
+chargeback,1,1,
+dispute,1,99,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert!(engine.account_book().accounts[&ClientId(1)].is_locked());
+    }
+}
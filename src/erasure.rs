@@ -0,0 +1,176 @@
+//! Erasing a client's history on request, as data protection law like the GDPR can require,
+//! without unbalancing the books.
+//!
+//! [`Engine::erase_client`](crate::engine::Engine::erase_client) purges every transaction of the
+//! client from the [`TransactionLog`](crate::types::TransactionLog), along with where each was
+//! read from. What's owed can't be erased, so the account itself stays, with its balances, as a
+//! tombstone: totals across the account book are unchanged, and the account can still be paid
+//! out or closed. In place of the transactions, an [`Erasure`](crate::erasure::Erasure) sums up
+//! what was erased, sealed with an HMAC of it keyed with a secret the caller keeps, and is passed
+//! to every listener, so an [`AuditLog`](crate::audit::AuditLog) records it in its chain.
+//! Listeners keeping transactions of their own should forget the client's then.
+//!
+//! A client with a transaction under dispute can't be erased until the dispute is resolved or
+//! charged back, since it couldn't be afterwards. Once erased, disputes of the client's
+//! transactions are ignored like those of any unknown transaction, and new transactions for the
+//! client are applied to the tombstone as usual.
+//!
+//! An audit log can't forget by itself, since it's only ever appended to, so its entries for the
+//! client are still in its file after the erasure. Rewrite the file with
+//! [`audit::redact_client`](crate::audit::redact_client) afterwards, which replaces those written
+//! before the erasure with tombstones the chain still verifies through.
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    audit::digest,
+    errors::{DomainError, Error},
+    retention::transactions_to_csv,
+    types::{Account, ClientId, TransactionRecord, TransactionType},
+};
+
+/// What was erased of a client, summed up in place of their transactions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Erasure {
+    /// The client erased
+    pub client: ClientId,
+    /// How many transactions were purged from the log. Logs that can't purge transactions keep
+    /// them, so this can be fewer than the client had.
+    pub transactions: usize,
+    /// What the client's deposits added up to
+    pub deposits: Decimal,
+    /// What the client's withdrawals added up to
+    pub withdrawals: Decimal,
+    /// The account's available funds, which it keeps
+    pub available: Decimal,
+    /// The account's held funds, which it keeps
+    pub held: Decimal,
+    /// The account's total funds, which it keeps
+    pub total: Decimal,
+    /// Whether the account is locked
+    pub locked: bool,
+    /// Hex HMAC-SHA256 of the erased transactions, keyed with the secret passed to
+    /// [`Engine::erase_client`](crate::engine::Engine::erase_client), and written as CSV with the
+    /// same columns as transaction input plus `timestamp`, in transaction ID order, as for an
+    /// [archive](crate::retention::Archive). Anyone with the secret and a copy of the
+    /// transactions can check it against this. Without the secret, guesses at the transactions
+    /// can't be checked against it.
+    pub seal: String,
+}
+
+impl Erasure {
+    /// Sums up and seals the transactions of an account being erased, which must be sorted by
+    /// transaction ID, counting all of them as purged.
+    /// # Errors
+    /// [`DomainError::AmountOutOfRange`] at the transaction taking a sum out of the range of a
+    /// [`Decimal`], or if writing the transactions out fails
+    pub(crate) fn seal(
+        account: &Account,
+        records: &[TransactionRecord],
+        secret: &[u8],
+    ) -> Result<Self, Error> {
+        let sum = |transaction_type| {
+            records
+                .iter()
+                .filter(|record| record.transaction_type() == transaction_type)
+                .try_fold(Decimal::ZERO, |sum, record| {
+                    sum.checked_add(record.amount().unwrap_or_default())
+                        .ok_or(DomainError::AmountOutOfRange(record.transaction_id()))
+                })
+        };
+        Ok(Self {
+            client: account.client_id(),
+            transactions: records.len(),
+            deposits: sum(TransactionType::Deposit)?,
+            withdrawals: sum(TransactionType::Withdrawal)?,
+            available: account.funds_available(),
+            held: account.funds_held(),
+            total: account.total(),
+            locked: account.is_locked(),
+            seal: digest(Some(secret), &transactions_to_csv(records)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{AccountBook, MemoryAccountBook, MemoryTransactionLog, TransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_erase_client() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.5
+deposit,1,4,2.0
+dispute,1,4,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let book_total = |engine: &Engine<MemoryAccountBook, MemoryTransactionLog>| {
+            engine
+                .account_book()
+                .accounts()
+                .map(Account::total)
+                .sum::<Decimal>()
+        };
+        let before = book_total(&engine);
+
+        // Not while a dispute is open, since it could never be closed
+        let err = engine.erase_client(ClientId(1), b"secret").unwrap_err();
+        assert_eq!(err.code(), "disputed");
+        assert_eq!(engine.transaction_log().transactions().count(), 4);
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\nresolve,1,4,\n"))
+            .unwrap();
+
+        let erasure = engine.erase_client(ClientId(1), b"secret").unwrap();
+        let erased = "type,client,tx,amount,timestamp
+deposit,1,1,5.0,
+withdrawal,1,3,1.5,
+deposit,1,4,2.0,
+";
+        assert_eq!(
+            erasure,
+            Erasure {
+                client: ClientId(1),
+                transactions: 3,
+                deposits: dec!(7),
+                withdrawals: dec!(1.5),
+                available: dec!(5.5),
+                held: dec!(0),
+                total: dec!(5.5),
+                locked: false,
+                seal: digest(Some(b"secret"), erased.as_bytes()),
+            }
+        );
+        // The account stays, so the books still balance, but its transactions are gone
+        assert_eq!(book_total(&engine), before);
+        let clients: Vec<ClientId> = engine
+            .transaction_log()
+            .transactions()
+            .map(|record| record.client_id())
+            .collect();
+        assert_eq!(clients, [ClientId(2)]);
+        // And the account starts from what they came to when it's checked
+        assert!(engine.check_consistency().is_consistent());
+        // Nothing is left to dispute
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndispute,1,4,\n"))
+            .unwrap();
+        assert_eq!(
+            engine.account_book().accounts[&ClientId(1)].funds_held(),
+            dec!(0)
+        );
+    }
+}
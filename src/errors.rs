@@ -1,11 +1,145 @@
-use crate::types::{ClientId, TransactionId};
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
 
-/// Error type that can be returned by fallible operations in this crate
+#[cfg(feature = "csv")]
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::types::{ClientId, Currency, TransactionId, TransactionRecord, TransactionType};
+
+/// Error type that can be returned by fallible operations in this crate.
+///
+/// Errors reading or writing, parsing input, and rejected transactions are grouped into
+/// [`IoError`], [`ParseError`] and [`DomainError`], so a match can handle a whole class of errors
+/// without listing every variant. More variants may be added to any of them in future, so
+/// matches need a wildcard arm.
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
-    /// Error reading or writing CSV files; could wrap IO or parsing errors
-    #[error("Error processing CSV")]
-    Load(#[from] csv::Error),
+    /// Reading input or writing output failed
+    #[error(transparent)]
+    Io(#[from] IoError),
+    /// Input, or a state file, audit log, filter or transaction type name, couldn't be parsed
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    /// A transaction was rejected by the rules of the ledger
+    #[error(transparent)]
+    Domain(#[from] DomainError),
+    /// Loading was stopped early at the caller's request. Everything applied before that point
+    /// remains applied.
+    #[error("Loading cancelled")]
+    Cancelled,
+    /// A storage backend failed in a way that may succeed if tried again, such as a timeout or a
+    /// dropped connection. [`AccountBook`](crate::types::AccountBook) and
+    /// [`TransactionLog`](crate::types::TransactionLog) implementations should return this for
+    /// blips, so they can be retried by [`Retrying`](crate::types::Retrying).
+    #[error("Temporary storage failure: {0}")]
+    Transient(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The client had more transactions handed to the engine than its
+    /// [`RateLimit`](crate::ratelimit::RateLimit) allows. Only returned if
+    /// [`EngineSettings::rate_limit`](crate::engine::EngineSettings::rate_limit) is set.
+    #[error("Account {client} is over its rate limit; retry in {retry_after:?}")]
+    RateLimited {
+        /// The client over its limit
+        client: ClientId,
+        /// How long until the client can have another transaction accepted
+        retry_after: Duration,
+    },
+    /// No [`ReportPlugin`](crate::plugin::ReportPlugin) with this name is registered with the
+    /// engine
+    #[error("No report named {0:?}")]
+    UnknownReport(String),
+    /// An [idempotency key](crate::idempotency) was reused for a different submission within
+    /// the window it's remembered for
+    #[error("Idempotency key {0:?} was already used for a different submission")]
+    IdempotencyKeyReused(String),
+    /// No tenant with this ID has been added to the
+    /// [`TenantManager`](crate::tenant::TenantManager)
+    #[cfg(feature = "csv")]
+    #[error("No tenant {0}")]
+    UnknownTenant(crate::tenant::TenantId),
+}
+
+/// Failure reading input or writing output
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum IoError {
+    /// Error reading or writing a stream, whether CSV, an
+    /// [`AuditLog`](crate::audit::AuditLog), or anything else
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Input that couldn't be parsed
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// CSV that couldn't be read, eg because it isn't UTF-8 or a row has the wrong number of
+    /// fields. CSV errors caused by IO are [`IoError::Io`] instead.
+    #[error("Error processing CSV: {0}")]
+    #[cfg(feature = "csv")]
+    Csv(#[source] csv::Error),
+    /// A row of input couldn't be parsed into a transaction
+    #[error("Error parsing input at line {line} (byte {byte}): {reason}. Record: {record}")]
+    Row {
+        /// The 1-based line number the row starts on
+        line: u64,
+        /// The offset in bytes from the start of the input to the start of the row
+        byte: u64,
+        /// The row as read (after trimming), with its fields joined by commas
+        record: String,
+        /// What was wrong with the row
+        reason: String,
+    },
+    /// A [`Filter`](crate::filter::Filter) expression couldn't be parsed
+    #[error("Invalid filter at byte {position}: {reason}")]
+    Filter {
+        /// The offset in bytes from the start of the expression to where it went wrong
+        position: usize,
+        /// What was wrong with the expression
+        reason: String,
+    },
+    /// A [`TransactionType`] was parsed from a name that isn't one
+    #[error("Unknown transaction type {0:?}")]
+    UnknownTransactionType(String),
+    /// A [`Currency`] was parsed from something other than three letters
+    #[error("Invalid currency {0:?}")]
+    InvalidCurrency(String),
+    /// A [`Signature`](crate::types::Signature) was parsed from something other than 128 hex
+    /// digits
+    #[error("Invalid signature {0:?}")]
+    InvalidSignature(String),
+    /// A client's public key was parsed from something other than 64 hex digits, or isn't a valid
+    /// Ed25519 key, or a state file [encryption key](crate::state) couldn't be read. Holds the
+    /// public key, or what was wrong with the encryption key, which is never shown.
+    #[error("Invalid key {0:?}")]
+    InvalidKey(String),
+    /// A [state file](crate::state) couldn't be read, because it isn't one, it's corrupt, or it
+    /// needs a newer version of this crate
+    #[error("Invalid state file: {0}")]
+    InvalidState(String),
+    /// An [audit log](crate::audit::AuditLog)'s hash chain is broken, so it's been changed since
+    /// it was written
+    #[error("Audit log chain broken at line {line}")]
+    BrokenChain {
+        /// The 1-based line number of the first entry that doesn't follow on from the one before
+        line: u64,
+    },
+    /// A [`TenantId`](crate::tenant::TenantId) was parsed from something other than 1 to 64
+    /// letters, digits, `-` or `_`
+    #[error("Invalid tenant {0:?}")]
+    InvalidTenant(String),
+}
+
+/// A transaction rejected by the rules of the ledger. Applying the same transaction again will
+/// fail the same way.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum DomainError {
     /// Once a [`Transaction`](crate::types::Transaction) has been successfully applied, it cannot be applied again.
     /// If that happens, this error will be returned.
     /// Note that duplicate transactions in the incoming stream will each be applied without causing a duplicate error.
@@ -14,4 +148,576 @@ pub enum Error {
     /// If an account is locked, and the operation is not allowed on locked accounts, this error will be returned
     #[error("Account {0} is locked")]
     Locked(ClientId),
+    /// The transaction's amount is too large to be stored by the
+    /// [`TransactionLog`](crate::types::TransactionLog) in use, or would take a balance out of the
+    /// range of a [`Decimal`]
+    #[error("Amount of transaction id {0} is out of range")]
+    AmountOutOfRange(TransactionId),
+    /// A withdrawal was for more than the account's available funds. Only checked if
+    /// [`EngineSettings::validate_transactions`](crate::engine::EngineSettings::validate_transactions)
+    /// is set.
+    #[error(
+        "Withdrawal of {requested} from account {client} exceeds available funds of {available}"
+    )]
+    InsufficientFunds {
+        /// The account withdrawn from
+        client: ClientId,
+        /// The amount of the withdrawal
+        requested: Decimal,
+        /// The funds available at the time
+        available: Decimal,
+    },
+    /// A deposit or withdrawal had no amount
+    #[error("Transaction id {0} is missing an amount")]
+    MissingAmount(TransactionId),
+    /// A dispute, resolve or chargeback referred to a transaction that isn't in the
+    /// [`TransactionLog`](crate::types::TransactionLog). Only checked if
+    /// [`EngineSettings::validate_transactions`](crate::engine::EngineSettings::validate_transactions)
+    /// is set; otherwise these are ignored.
+    #[error("Transaction id {0} refers to an unknown transaction")]
+    UnknownReference(TransactionId),
+    /// A dispute, resolve or chargeback came from a different client than the transaction it
+    /// refers to. Only checked if
+    /// [`EngineSettings::validate_transactions`](crate::engine::EngineSettings::validate_transactions)
+    /// is set.
+    #[error("Transaction id {transaction} belongs to account {owner}, not account {client}")]
+    ClientMismatch {
+        /// The transaction referred to
+        transaction: TransactionId,
+        /// The client the referred transaction belongs to
+        owner: ClientId,
+        /// The client the referring transaction came from
+        client: ClientId,
+    },
+    /// A deposit or withdrawal was for zero or a negative amount. Checked when one is created
+    /// with [`Transaction::deposit`](crate::types::Transaction::deposit) or
+    /// [`Transaction::withdrawal`](crate::types::Transaction::withdrawal), but when parsed, only
+    /// if [`EngineSettings::validate_transactions`](crate::engine::EngineSettings::validate_transactions)
+    /// is set.
+    #[error("Amount {amount} of transaction id {transaction} is not positive")]
+    InvalidAmount {
+        /// The transaction with the bad amount
+        transaction: TransactionId,
+        /// The amount given
+        amount: Decimal,
+    },
+    /// A deposit or withdrawal was in a different currency to its account. An account in
+    /// [`Currency::NONE`](crate::types::Currency::NONE) takes the currency of the first deposit
+    /// or withdrawal in another while it's empty.
+    #[error("Transaction id {transaction} is in {found}, but its account is in {expected}")]
+    CurrencyMismatch {
+        /// The transaction in the wrong currency
+        transaction: TransactionId,
+        /// The currency of the account
+        expected: Currency,
+        /// The currency of the transaction
+        found: Currency,
+    },
+    /// A transaction's timestamp was earlier than one already applied, and
+    /// [`EngineSettings::out_of_order`](crate::engine::EngineSettings::out_of_order) rejects those
+    #[error(
+        "Transaction id {transaction} at {timestamp} is earlier than {latest}, already applied"
+    )]
+    OutOfOrder {
+        /// The transaction out of order
+        transaction: TransactionId,
+        /// Its timestamp, in seconds since the Unix epoch
+        timestamp: u64,
+        /// The latest timestamp already applied
+        latest: u64,
+    },
+    /// A transaction's signature is missing or wasn't made with its client's key, or the client
+    /// has no key registered, so it may be forged. Only checked with the `signatures` feature,
+    /// once a `SignatureVerifier` is set on the engine.
+    #[error("Transaction id {transaction} isn't signed by the key of account {client}")]
+    BadSignature {
+        /// The transaction that isn't properly signed
+        transaction: TransactionId,
+        /// The client it claims to be from
+        client: ClientId,
+    },
+    /// A step of a [`Saga`](crate::saga::Saga) can't be undone if a later step fails, because
+    /// it's neither a deposit nor a withdrawal, or it has no compensating transaction and isn't
+    /// the last step
+    #[error("Transaction id {0} can't be compensated")]
+    Uncompensable(TransactionId),
+    /// A client was to be [erased](crate::engine::Engine::erase_client), but one of their
+    /// transactions is under dispute
+    #[error("Transaction id {0} is under dispute")]
+    Disputed(TransactionId),
+    /// A [`Saga`](crate::saga::Saga) was stopped partway through, eg by a crash, so
+    /// [`Engine::recover_sagas`](crate::engine::Engine::recover_sagas) undid the steps it had
+    /// applied. Holds the ID of its first step.
+    #[error("Saga starting with transaction id {0} was interrupted")]
+    SagaInterrupted(TransactionId),
+    /// An account's available and held funds add up to more than a [`Decimal`] can hold, so it
+    /// can't be [built](crate::types::AccountBuilder::build) or deserialized. Accounts changed by
+    /// transactions fail with [`DomainError::AmountOutOfRange`] instead.
+    #[error("Total funds of account {0} are out of range")]
+    BalanceOutOfRange(ClientId),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(IoError::Io(err))
+    }
+}
+
+/// Sorts CSV errors by cause: those reading or writing are [`IoError`]s, and the rest
+/// [`ParseError`]s
+#[cfg(feature = "csv")]
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        if err.is_io_error() {
+            let csv::ErrorKind::Io(err) = err.into_kind() else {
+                unreachable!("checked it's an IO error")
+            };
+            Self::Io(IoError::Io(err))
+        } else {
+            Self::Parse(ParseError::Csv(err))
+        }
+    }
+}
+
+impl Error {
+    /// Returns a short code identifying the kind of error, eg `"locked"`.
+    ///
+    /// Unlike the error messages, codes never change between versions, so they're safe to match
+    /// on in other systems. New variants get new codes.
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io(err) => err.code(),
+            Self::Parse(err) => err.code(),
+            Self::Domain(err) => err.code(),
+            Self::Cancelled => "cancelled",
+            Self::Transient(_) => "transient",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::UnknownReport(_) => "unknown_report",
+            Self::IdempotencyKeyReused(_) => "idempotency_key_reused",
+            #[cfg(feature = "csv")]
+            Self::UnknownTenant(_) => "unknown_tenant",
+        }
+    }
+
+    /// Returns whether the error is [transient](Error::Transient), so the failed call may succeed
+    /// if tried again
+    #[must_use]
+    #[inline]
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Transient(_))
+    }
+
+    /// Returns whether the same thing may succeed if tried again later: the error is
+    /// [transient](Error::Transient), a [rate limit](Error::RateLimited), or
+    /// [cancellation](Error::Cancelled)
+    #[must_use]
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Transient(_) | Self::RateLimited { .. } | Self::Cancelled
+        )
+    }
+}
+
+impl IoError {
+    /// Returns a short code identifying the kind of error, as for [`Error::code`]
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+        }
+    }
+}
+
+impl ParseError {
+    /// Returns a short code identifying the kind of error, as for [`Error::code`]
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "csv")]
+            Self::Csv(_) => "load",
+            Self::Row { .. } => "parse",
+            Self::Filter { .. } => "filter",
+            Self::UnknownTransactionType(_) => "unknown_transaction_type",
+            Self::InvalidCurrency(_) => "invalid_currency",
+            Self::InvalidSignature(_) => "invalid_signature",
+            Self::InvalidKey(_) => "invalid_key",
+            Self::InvalidState(_) => "invalid_state",
+            Self::BrokenChain { .. } => "broken_chain",
+            Self::InvalidTenant(_) => "invalid_tenant",
+        }
+    }
+}
+
+impl DomainError {
+    /// Returns a short code identifying the kind of error, as for [`Error::code`]
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Duplicate(_) => "duplicate",
+            Self::Locked(_) => "locked",
+            Self::AmountOutOfRange(_) => "amount_out_of_range",
+            Self::InsufficientFunds { .. } => "insufficient_funds",
+            Self::MissingAmount(_) => "missing_amount",
+            Self::UnknownReference(_) => "unknown_reference",
+            Self::ClientMismatch { .. } => "client_mismatch",
+            Self::InvalidAmount { .. } => "invalid_amount",
+            Self::CurrencyMismatch { .. } => "currency_mismatch",
+            Self::OutOfOrder { .. } => "out_of_order",
+            Self::BadSignature { .. } => "bad_signature",
+            Self::Uncompensable(_) => "uncompensable",
+            Self::Disputed(_) => "disputed",
+            Self::SagaInterrupted(_) => "saga_interrupted",
+            Self::BalanceOutOfRange(_) => "balance_out_of_range",
+        }
+    }
+}
+
+/// A machine-readable description of an [`Error`], for sending to other systems.
+///
+/// Serialized as eg `{"code":"locked","message":"Account id[1] is locked","client":1}`; fields
+/// that don't apply to the error are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ErrorReport {
+    /// The error's stable [code](Error::code)
+    pub code: String,
+    /// The human-readable error message, which may change between versions
+    pub message: String,
+    /// The transaction the error concerns, if any
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub transaction: Option<TransactionId>,
+    /// The client the error concerns, if any
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub client: Option<ClientId>,
+    /// The input line the error occurred on, if known
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub line: Option<u64>,
+}
+
+impl From<&Error> for ErrorReport {
+    fn from(error: &Error) -> Self {
+        let (transaction, client, line) = match error {
+            Error::Parse(ParseError::Row { line, .. } | ParseError::BrokenChain { line }) => {
+                (None, None, Some(*line))
+            }
+            Error::Domain(
+                DomainError::Duplicate(transaction)
+                | DomainError::AmountOutOfRange(transaction)
+                | DomainError::MissingAmount(transaction)
+                | DomainError::UnknownReference(transaction)
+                | DomainError::InvalidAmount { transaction, .. }
+                | DomainError::CurrencyMismatch { transaction, .. }
+                | DomainError::OutOfOrder { transaction, .. }
+                | DomainError::Uncompensable(transaction)
+                | DomainError::Disputed(transaction)
+                | DomainError::SagaInterrupted(transaction),
+            ) => (Some(*transaction), None, None),
+            Error::Domain(
+                DomainError::Locked(client)
+                | DomainError::InsufficientFunds { client, .. }
+                | DomainError::BalanceOutOfRange(client),
+            )
+            | Error::RateLimited { client, .. } => (None, Some(*client), None),
+            Error::Domain(
+                DomainError::ClientMismatch {
+                    transaction,
+                    client,
+                    ..
+                }
+                | DomainError::BadSignature {
+                    transaction,
+                    client,
+                },
+            ) => (Some(*transaction), Some(*client), None),
+            Error::Io(_)
+            | Error::Parse(_)
+            | Error::Cancelled
+            | Error::Transient(_)
+            | Error::UnknownReport(_)
+            | Error::IdempotencyKeyReused(_) => (None, None, None),
+            #[cfg(feature = "csv")]
+            Error::UnknownTenant(_) => (None, None, None),
+        };
+        Self {
+            code: error.code().to_string(),
+            message: error.to_string(),
+            transaction,
+            client,
+            line,
+        }
+    }
+}
+
+/// Something questionable about a transaction that was applied anyway.
+///
+/// Warnings don't stop loading. They're counted in
+/// [`Metrics::warnings`](crate::metrics::Metrics::warnings) and passed to
+/// [`EventListener::on_warning`](crate::events::EventListener::on_warning).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum Warning {
+    /// A dispute, resolve or chargeback referred to a transaction that isn't in the
+    /// [`TransactionLog`](crate::types::TransactionLog), so it was ignored
+    #[error("{transaction_type:?} for account {client} refers to unknown transaction id {transaction}, so was ignored")]
+    UnknownReference {
+        /// The type of the referring transaction
+        transaction_type: TransactionType,
+        /// The client the referring transaction came from
+        client: ClientId,
+        /// The ID of the missing transaction
+        transaction: TransactionId,
+    },
+    /// A transaction's timestamp was earlier than one already applied, and
+    /// [`EngineSettings::out_of_order`](crate::engine::EngineSettings::out_of_order) only warns
+    /// about those
+    #[error("Transaction id {transaction} for account {client} at {timestamp} is earlier than {latest}, already applied")]
+    OutOfOrder {
+        /// The client the transaction came from
+        client: ClientId,
+        /// The transaction out of order
+        transaction: TransactionId,
+        /// Its timestamp, in seconds since the Unix epoch
+        timestamp: u64,
+        /// The latest timestamp already applied
+        latest: u64,
+    },
+}
+
+impl Warning {
+    /// Returns a short code identifying the kind of warning, which, like [`Error::code`], never
+    /// changes between versions
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownReference { .. } => "unknown_reference",
+            Self::OutOfOrder { .. } => "out_of_order",
+        }
+    }
+}
+
+/// A row that failed, as handed to an [`ErrorPolicy`]
+#[derive(Debug, Clone, Copy)]
+pub enum FailedRow<'a> {
+    /// The row couldn't be parsed into a transaction
+    #[cfg(feature = "csv")]
+    Unparsed(&'a ByteRecord),
+    /// The row was parsed, but the transaction was rejected
+    Rejected(&'a TransactionRecord),
+}
+
+/// What to do about a failed row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Skip the row and carry on with the next one
+    Continue,
+    /// Stop loading, returning the error
+    Abort,
+    /// Try applying the transaction again. Rows that couldn't be parsed won't parse any better
+    /// a second time, so this is treated as [`Abort`](Self::Abort) for them, other than those
+    /// of a custom transaction type, which are handed to their handler again. The same goes for
+    /// transactions turned away before reaching storage, such as by the rate limit.
+    Retry,
+}
+
+/// Decides what an [`Engine`](crate::engine::Engine) does when a row fails to parse or apply.
+///
+/// Set with [`Engine::set_error_policy`](crate::engine::Engine::set_error_policy). The engine
+/// keeps retrying for as long as the policy says to, so policies returning
+/// [`ErrorAction::Retry`] need to give up at some point.
+pub trait ErrorPolicy {
+    /// Called for each failure. `attempt` counts the failures for this row so far, starting at 1.
+    fn on_error(&mut self, row: FailedRow<'_>, error: &Error, attempt: u32) -> ErrorAction;
+}
+
+/// Stops at the first failure. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Strict;
+
+impl ErrorPolicy for Strict {
+    fn on_error(&mut self, _row: FailedRow<'_>, _error: &Error, _attempt: u32) -> ErrorAction {
+        ErrorAction::Abort
+    }
+}
+
+/// A row skipped by [`SkipAndCollect`]
+#[derive(Debug, Clone)]
+pub struct SkippedRow {
+    /// The rejected transaction, or `None` if the row couldn't be parsed
+    pub transaction: Option<TransactionRecord>,
+    /// Why the row was skipped
+    pub reason: String,
+}
+
+/// Skips every failed row, keeping a note of each.
+///
+/// Clones share the same notes, so keep one to call [`skipped`](Self::skipped) with after
+/// handing another to the engine.
+#[derive(Debug, Default, Clone)]
+pub struct SkipAndCollect {
+    /// Every row skipped so far
+    skipped: Arc<Mutex<Vec<SkippedRow>>>,
+}
+
+impl SkipAndCollect {
+    /// Creates a policy that hasn't skipped anything yet
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of every row skipped so far, in the order they were skipped
+    #[must_use]
+    pub fn skipped(&self) -> Vec<SkippedRow> {
+        self.skipped
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl ErrorPolicy for SkipAndCollect {
+    fn on_error(&mut self, row: FailedRow<'_>, error: &Error, _attempt: u32) -> ErrorAction {
+        let transaction = match row {
+            #[cfg(feature = "csv")]
+            FailedRow::Unparsed(_) => None,
+            FailedRow::Rejected(transaction) => Some(*transaction),
+        };
+        self.skipped
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(SkippedRow {
+                transaction,
+                reason: error.to_string(),
+            });
+        ErrorAction::Continue
+    }
+}
+
+/// Retries rejected transactions, waiting longer between each attempt, then stops if they still
+/// fail.
+///
+/// Rejections that retrying can't fix, which are any [`DomainError`], and rows that couldn't be
+/// parsed, stop straight away.
+///
+/// The same settings can be given to [`Retrying`](crate::types::Retrying) to retry individual
+/// storage calls instead of whole transactions.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryWithBackoff {
+    /// How many times to retry a transaction before giving up
+    max_retries: u32,
+    /// How long to wait before the first retry. Doubles for each one after.
+    initial_delay: Duration,
+    /// The longest to wait between retries
+    max_delay: Duration,
+}
+
+impl RetryWithBackoff {
+    /// Creates a policy retrying up to `max_retries` times, waiting `initial_delay` before the
+    /// first retry and doubling the wait for each one after, up to 30 seconds
+    #[must_use]
+    pub fn new(max_retries: u32, initial_delay: Duration) -> Self {
+        Self {
+            max_retries,
+            initial_delay,
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// Caps the wait between retries
+    #[must_use]
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Returns how many times to retry before giving up
+    pub(crate) fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Returns how long to wait before the given retry, counting from 1
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+impl ErrorPolicy for RetryWithBackoff {
+    fn on_error(&mut self, row: FailedRow<'_>, error: &Error, attempt: u32) -> ErrorAction {
+        let permanent = matches!(error, Error::Domain(_));
+        if permanent || !matches!(row, FailedRow::Rejected(_)) || attempt > self.max_retries {
+            return ErrorAction::Abort;
+        }
+        std::thread::sleep(self.delay(attempt));
+        ErrorAction::Retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_error_report() {
+        let report = ErrorReport::from(&DomainError::Locked(3.into()).into());
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"code":"locked","message":"Account id[3] is locked","client":3}"#
+        );
+        let report = ErrorReport::from(
+            &ParseError::Row {
+                line: 7,
+                byte: 99,
+                record: "deposit,x".to_string(),
+                reason: "field 1: invalid digit found in string".to_string(),
+            }
+            .into(),
+        );
+        assert_eq!(report.code, "parse");
+        assert_eq!(report.line, Some(7));
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_error_categories() {
+        use std::error::Error as _;
+
+        let error = Error::from(csv::Error::from(std::io::Error::other("disk gone")));
+        assert!(matches!(error, Error::Io(IoError::Io(_))));
+        assert_eq!(error.source().unwrap().to_string(), "disk gone");
+
+        let mut reader = csv::Reader::from_reader("a,b\n1\n".as_bytes());
+        let error = Error::from(reader.records().next().unwrap().unwrap_err());
+        assert!(matches!(error, Error::Parse(ParseError::Csv(_))));
+        assert_eq!(error.code(), "load");
+        assert!(error.source().is_some());
+
+        let error = Error::from(DomainError::Locked(1.into()));
+        assert_eq!(error.to_string(), "Account id[1] is locked");
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn test_backoff_delays() {
+        let policy = RetryWithBackoff::new(10, Duration::from_millis(100))
+            .with_max_delay(Duration::from_millis(500));
+        let delays: Vec<_> = (1..=5)
+            .map(|retry| policy.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+    }
 }
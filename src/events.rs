@@ -0,0 +1,544 @@
+//! Hooks for reacting to what an [`Engine`](crate::engine::Engine) does with each transaction.
+//!
+//! Register an [`EventListener`](crate::events::EventListener) with
+//! [`Engine::add_listener`](crate::engine::Engine::add_listener) to trigger side effects, like
+//! notifications or syncing to another system, as transactions are applied.
+//!
+//! For building projections elsewhere, every change to account state is also described by a
+//! [`DomainEvent`](crate::events::DomainEvent). Registering an
+//! [`mpsc::Sender`](std::sync::mpsc::Sender) as a listener streams them over a channel, and
+//! [`write_events_to_csv`](crate::io::write_events_to_csv) writes them out. An
+//! [`EventHistory`](crate::events::EventHistory) keeps them, so accounts can be rebuilt as they
+//! were at any point in the past.
+
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    erasure::Erasure,
+    errors::{Error, Warning},
+    handler::CustomTransaction,
+    saga::{Saga, SagaOutcome},
+    types::{
+        Account, ClientId, MemoryAccountBook, Provenance, TransactionId, TransactionRecord,
+        TransactionType,
+    },
+};
+
+/// Callbacks made by an [`Engine`](crate::engine::Engine) as it processes transactions.
+///
+/// Every method has a default that does nothing, so implementors only need the ones they care
+/// about. Callbacks are made on the thread doing the loading, after the transaction has been
+/// applied (or rejected), so they should be quick.
+pub trait EventListener {
+    /// Called with where a transaction or unparsed row was read from, before any other call about
+    /// it
+    fn on_read(&mut self, _provenance: &Provenance) {}
+
+    /// Called after a transaction has been applied, with the account as it is afterwards
+    fn on_applied(&mut self, _transaction: &TransactionRecord, _account: &Account) {}
+
+    /// Called instead of [`on_applied`](Self::on_applied) for a dispute, resolve or chargeback
+    /// that changed nothing because the transaction it refers to is missing (or has no amount).
+    ///
+    /// These still count as applied, so by default this just calls `on_applied`.
+    fn on_missing_reference(&mut self, transaction: &TransactionRecord, account: &Account) {
+        self.on_applied(transaction, account);
+    }
+
+    /// Called when a transaction is rejected, with the reason it was rejected
+    fn on_rejected(&mut self, _transaction: &TransactionRecord, _error: &Error) {}
+
+    /// Called when an engine's [`ErrorPolicy`](crate::errors::ErrorPolicy) skips a row that
+    /// couldn't be parsed, with the row as read and the reason it couldn't be parsed
+    fn on_unparsed(&mut self, _record: &ByteRecord, _error: &Error) {}
+
+    /// Called after a transaction locks an account that wasn't locked before it
+    fn on_account_locked(&mut self, _transaction: &TransactionRecord, _account: &Account) {}
+
+    /// Called after a dispute moves the funds of the disputed transaction into held funds
+    fn on_dispute_opened(
+        &mut self,
+        _dispute: &TransactionRecord,
+        _disputed: &TransactionRecord,
+        _account: &Account,
+    ) {
+    }
+
+    /// Called after a transaction is applied with a [`Warning`], eg a dispute of an unknown
+    /// transaction
+    fn on_warning(&mut self, _warning: &Warning) {}
+
+    /// Called for every change a transaction makes to an account, after
+    /// [`on_applied`](Self::on_applied)
+    fn on_event(&mut self, _event: &DomainEvent) {}
+
+    /// Called after a [custom transaction](crate::handler) has been applied, with the account as
+    /// it is afterwards
+    fn on_custom_applied(&mut self, _transaction: &CustomTransaction, _account: &Account) {}
+
+    /// Called after a client's transactions are [erased](crate::engine::Engine::erase_client),
+    /// with a summary of what was erased
+    fn on_erased(&mut self, _erasure: &Erasure) {}
+
+    /// Called before the first step of a [`Saga`] is applied, including one being
+    /// [recovered](crate::engine::Engine::recover_sagas)
+    fn on_saga_started(&mut self, _saga: &Saga) {}
+
+    /// Called once every step of a saga, and any compensation, has been applied or rejected
+    fn on_saga_finished(&mut self, _outcome: &SagaOutcome) {}
+}
+
+/// Streams [`DomainEvent`]s over a channel, eg to a thread writing them out.
+///
+/// The channel is unbounded, so the receiving end should keep up. Events are dropped once the
+/// receiver is gone.
+impl EventListener for mpsc::Sender<DomainEvent> {
+    fn on_event(&mut self, event: &DomainEvent) {
+        let _ = self.send(*event);
+    }
+}
+
+/// Streams [`Warning`]s over a channel, eg to a thread reporting them.
+///
+/// As for [`DomainEvent`]s, the channel is unbounded, and warnings are dropped once the receiver
+/// is gone.
+impl EventListener for mpsc::Sender<Warning> {
+    fn on_warning(&mut self, warning: &Warning) {
+        let _ = self.send(*warning);
+    }
+}
+
+/// The kinds of change a transaction can make to an account
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainEventKind {
+    /// A deposit added to available funds
+    FundsDeposited,
+    /// A withdrawal took away from available funds
+    FundsWithdrawn,
+    /// A dispute moved funds from available to held
+    FundsHeld,
+    /// A resolve moved funds from held back to available
+    FundsReleased,
+    /// A chargeback took away held funds
+    FundsChargedBack,
+    /// A chargeback locked the account
+    AccountLocked,
+}
+
+/// A single change to an account's state.
+///
+/// Replaying every event in sequence order reproduces the final account states.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct DomainEvent {
+    /// Position of this event in the engine's history, counting from 1
+    pub sequence: u64,
+    /// What happened
+    #[serde(rename = "event")]
+    pub kind: DomainEventKind,
+    /// The account that changed
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    /// The transaction that caused the change. Disputes, resolves and chargebacks share the ID
+    /// of the transaction they refer to.
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    /// The amount of funds moved, if any, to the account book's
+    /// [scale](crate::types::AccountBook::scale)
+    pub amount: Option<Decimal>,
+}
+
+impl DomainEvent {
+    /// Makes this event's change to an account, for rebuilding accounts from their events
+    pub fn apply_to(&self, account: &mut Account) {
+        let amount = self.amount.unwrap_or_default();
+        match self.kind {
+            DomainEventKind::FundsDeposited => account.funds_available += amount,
+            DomainEventKind::FundsWithdrawn => account.funds_available -= amount,
+            DomainEventKind::FundsHeld => {
+                account.funds_available -= amount;
+                account.funds_held += amount;
+            }
+            DomainEventKind::FundsReleased => {
+                account.funds_held -= amount;
+                account.funds_available += amount;
+            }
+            DomainEventKind::FundsChargedBack => account.funds_held -= amount,
+            DomainEventKind::AccountLocked => account.locked = true,
+        }
+    }
+}
+
+/// Keeps every [`DomainEvent`], with the timestamp of the transaction that caused it, so accounts
+/// can be rebuilt as they were at a past point with [`state_at`](Self::state_at).
+///
+/// A transaction without a [timestamp](TransactionRecord::timestamp) is taken to have happened at
+/// the same time as the one applied before it, or at 0 if it's the first. Clones share the same
+/// history, so one can be kept while another is registered with
+/// [`Engine::add_listener`](crate::engine::Engine::add_listener), or the engine can keep one itself
+/// with [`EngineSettings::keep_history`](crate::engine::EngineSettings::keep_history).
+#[derive(Debug, Default, Clone)]
+pub struct EventHistory {
+    /// The events so far, and the time of the latest transaction applied
+    inner: Arc<Mutex<HistoryInner>>,
+}
+
+/// The shared state of an [`EventHistory`]
+#[derive(Debug, Default)]
+struct HistoryInner {
+    /// Every event so far, in sequence order, with the timestamp it happened at
+    events: Vec<(u64, DomainEvent)>,
+    /// The timestamp of the latest transaction applied
+    now: u64,
+}
+
+impl EventHistory {
+    /// Creates an empty history
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of events kept
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .events
+            .len()
+    }
+
+    /// Returns whether no events have been kept
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rebuilds the accounts as they were at `timestamp`, by replaying every event that happened
+    /// at or before it, in sequence order, onto empty accounts with balances to `scale` decimal
+    /// places.
+    ///
+    /// Only accounts changed by then are in the book, and it starts out empty, so any balances
+    /// the accounts had before the history was kept aren't included. Events don't carry a
+    /// currency, so neither do the accounts.
+    #[must_use]
+    pub fn state_at(&self, timestamp: u64, scale: u32) -> MemoryAccountBook {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut account_book = MemoryAccountBook::new().with_scale(scale);
+        for (_, event) in inner.events.iter().filter(|(time, _)| *time <= timestamp) {
+            let account = account_book
+                .accounts
+                .entry(event.client_id)
+                .or_insert_with(|| Account::with_scale(event.client_id, scale));
+            event.apply_to(account);
+        }
+        account_book
+    }
+}
+
+impl EventListener for EventHistory {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(timestamp) = transaction.timestamp() {
+            inner.now = timestamp;
+        }
+    }
+
+    fn on_event(&mut self, event: &DomainEvent) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = inner.now;
+        inner.events.push((now, *event));
+    }
+}
+
+/// The state of things before a transaction was applied, to work out which events it caused
+#[derive(Debug, Default)]
+pub(crate) struct PriorState {
+    /// Whether the transaction's account was already locked
+    pub(crate) was_locked: bool,
+    /// For disputes, resolves and chargebacks, the registered transaction referred to, if it
+    /// has an amount
+    pub(crate) referred: Option<TransactionRecord>,
+}
+
+/// The listeners registered on an engine
+#[derive(Default)]
+pub(crate) struct EventListeners {
+    /// Listeners, in the order they were added
+    listeners: Vec<Box<dyn EventListener + Send>>,
+    /// Sequence number of the last [`DomainEvent`] sent
+    sequence: u64,
+}
+
+impl EventListeners {
+    /// Adds a listener, to be called after those already added
+    pub(crate) fn push(&mut self, listener: Box<dyn EventListener + Send>) {
+        self.listeners.push(listener);
+    }
+
+    /// Returns whether there are no listeners, so there's no need to work out any events
+    pub(crate) fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Calls every listener for an applied transaction, along with any events it caused, with
+    /// amounts rounded to the account book's `scale`
+    pub(crate) fn applied(
+        &mut self,
+        transaction: &TransactionRecord,
+        prior: &PriorState,
+        account: &Account,
+        scale: u32,
+    ) {
+        let locked = !prior.was_locked && account.is_locked();
+        let missing_reference = prior.referred.is_none()
+            && !matches!(
+                transaction.transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            );
+        let events = self.domain_events(transaction, prior, locked, scale);
+        for listener in &mut self.listeners {
+            if missing_reference {
+                listener.on_missing_reference(transaction, account);
+            } else {
+                listener.on_applied(transaction, account);
+            }
+            if let (TransactionType::Dispute, Some(disputed)) =
+                (transaction.transaction_type, &prior.referred)
+            {
+                listener.on_dispute_opened(transaction, disputed, account);
+            }
+            if locked {
+                listener.on_account_locked(transaction, account);
+            }
+            for event in events.iter().flatten() {
+                listener.on_event(event);
+            }
+        }
+    }
+
+    /// Calls every listener for an applied custom transaction
+    pub(crate) fn custom_applied(&mut self, transaction: &CustomTransaction, account: &Account) {
+        for listener in &mut self.listeners {
+            listener.on_custom_applied(transaction, account);
+        }
+    }
+
+    /// Tells every listener where the next transaction or unparsed row was read from
+    pub(crate) fn read(&mut self, provenance: &Provenance) {
+        for listener in &mut self.listeners {
+            listener.on_read(provenance);
+        }
+    }
+
+    /// Calls every listener for a rejected transaction
+    pub(crate) fn rejected(&mut self, transaction: &TransactionRecord, error: &Error) {
+        for listener in &mut self.listeners {
+            listener.on_rejected(transaction, error);
+        }
+    }
+
+    /// Calls every listener for a warning
+    pub(crate) fn warning(&mut self, warning: &Warning) {
+        for listener in &mut self.listeners {
+            listener.on_warning(warning);
+        }
+    }
+
+    /// Calls every listener for a client's erasure
+    pub(crate) fn erased(&mut self, erasure: &Erasure) {
+        for listener in &mut self.listeners {
+            listener.on_erased(erasure);
+        }
+    }
+
+    /// Tells every listener a saga is starting
+    pub(crate) fn saga_started(&mut self, saga: &Saga) {
+        for listener in &mut self.listeners {
+            listener.on_saga_started(saga);
+        }
+    }
+
+    /// Tells every listener a saga has finished
+    pub(crate) fn saga_finished(&mut self, outcome: &SagaOutcome) {
+        for listener in &mut self.listeners {
+            listener.on_saga_finished(outcome);
+        }
+    }
+
+    /// Calls every listener for a row that couldn't be parsed
+    pub(crate) fn unparsed(&mut self, record: &ByteRecord, error: &Error) {
+        for listener in &mut self.listeners {
+            listener.on_unparsed(record, error);
+        }
+    }
+
+    /// Works out the changes an applied transaction made, numbering them in sequence
+    fn domain_events(
+        &mut self,
+        transaction: &TransactionRecord,
+        prior: &PriorState,
+        locked: bool,
+        scale: u32,
+    ) -> [Option<DomainEvent>; 2] {
+        let referred_amount = prior.referred.and_then(|referred| referred.amount);
+        let (kind, amount) = match transaction.transaction_type {
+            TransactionType::Deposit => (DomainEventKind::FundsDeposited, transaction.amount),
+            TransactionType::Withdrawal => (DomainEventKind::FundsWithdrawn, transaction.amount),
+            TransactionType::Dispute => (DomainEventKind::FundsHeld, referred_amount),
+            TransactionType::Resolve => (DomainEventKind::FundsReleased, referred_amount),
+            TransactionType::Chargeback => (DomainEventKind::FundsChargedBack, referred_amount),
+        };
+        // Referring transactions with nothing to refer to don't change anything
+        let funds_event = amount.map(|mut amount| {
+            amount.rescale(scale);
+            self.next_event(transaction, kind, Some(amount))
+        });
+        let lock_event =
+            locked.then(|| self.next_event(transaction, DomainEventKind::AccountLocked, None));
+        [funds_event, lock_event]
+    }
+
+    /// Creates the next event in sequence
+    fn next_event(
+        &mut self,
+        transaction: &TransactionRecord,
+        kind: DomainEventKind,
+        amount: Option<Decimal>,
+    ) -> DomainEvent {
+        self.sequence += 1;
+        DomainEvent {
+            sequence: self.sequence,
+            kind,
+            client_id: transaction.client_id,
+            transaction_id: transaction.transaction_id,
+            amount,
+        }
+    }
+}
+
+impl std::fmt::Debug for EventListeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventListeners")
+            .field("len", &self.listeners.len())
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        errors::DomainError,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    /// Records a line for every callback
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl EventListener for Recorder {
+        fn on_applied(&mut self, transaction: &TransactionRecord, account: &Account) {
+            let line = format!(
+                "applied {} {}",
+                transaction.transaction_id(),
+                account.total()
+            );
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn on_rejected(&mut self, transaction: &TransactionRecord, error: &Error) {
+            let line = format!("rejected {}: {error}", transaction.transaction_id());
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn on_account_locked(&mut self, _transaction: &TransactionRecord, account: &Account) {
+            let line = format!("locked {}", account.client_id());
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn on_dispute_opened(
+            &mut self,
+            _dispute: &TransactionRecord,
+            disputed: &TransactionRecord,
+            account: &Account,
+        ) {
+            let line = format!(
+                "disputed {} holding {}",
+                disputed.transaction_id(),
+                account.funds_held()
+            );
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    #[test]
+    fn test_listener_callbacks() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(Recorder(Arc::clone(&events)));
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,9,
+dispute,1,1,
+chargeback,1,1,
+deposit,1,2,1.0
+";
+        let result = engine.load_csv(&mut Cursor::new(input));
+        assert!(matches!(result, Err(Error::Domain(DomainError::Locked(_)))));
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "applied id[1] 5.0000",
+                "applied id[9] 5.0000",
+                "applied id[1] 5.0000",
+                "disputed id[1] holding 5.0000",
+                "applied id[1] 0.0000",
+                "locked id[1]",
+                "rejected id[2]: Account id[1] is locked",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_domain_events_over_channel() {
+        let (sender, receiver) = mpsc::channel::<DomainEvent>();
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(sender);
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+resolve,1,7,
+dispute,1,1,
+chargeback,1,1,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        drop(engine);
+        let events: Vec<_> = receiver
+            .iter()
+            .map(|event| (event.sequence, event.kind, event.amount))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (1, DomainEventKind::FundsDeposited, Some(dec!(5))),
+                (2, DomainEventKind::FundsHeld, Some(dec!(5))),
+                (3, DomainEventKind::FundsChargedBack, Some(dec!(5))),
+                (4, DomainEventKind::AccountLocked, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! A C interface to the engine, for embedding in C and C++ services, behind the `ffi` feature.
+//!
+//! Build the library as a `cdylib` with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`, so builds without the
+//! feature don't produce one, and `include/cashflow.h` declares everything here. An engine is
+//! created with `cashflow_engine_new`, fed one CSV row at a time with `cashflow_apply_csv_line`,
+//! and its accounts read back with `cashflow_report_csv`, built on an
+//! [`embed::CsvSession`](crate::embed::CsvSession). Strings passed in are borrowed, and must be
+//! NUL-terminated UTF-8; strings handed back are owned by the caller, who frees them with
+//! `cashflow_string_free`. These signatures won't change within a major version.
+#![allow(unsafe_code)]
+
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+};
+
+use crate::embed::CsvSession;
+
+/// Returned when a row was applied
+pub const CASHFLOW_OK: c_int = 0;
+/// Returned when a row couldn't be parsed or applied. The reason is available from
+/// [`cashflow_last_error`].
+pub const CASHFLOW_REJECTED: c_int = 1;
+/// Returned when a pointer was null, or a string wasn't UTF-8
+pub const CASHFLOW_INVALID_ARGUMENT: c_int = -1;
+
+/// The header prepended to each row, since rows are passed in one at a time
+const HEADER: &str = "type,client,tx,amount\n";
+
+/// An engine, as seen from C. Opaque to callers.
+#[derive(Debug, Default)]
+pub struct CashflowEngine {
+    /// Holds the accounts and transactions so far
+    session: CsvSession,
+    /// Why the last row was rejected, if it was
+    last_error: Option<CString>,
+}
+
+/// Creates an engine with no accounts. Free it with [`cashflow_engine_free`].
+#[no_mangle]
+pub extern "C" fn cashflow_engine_new() -> *mut CashflowEngine {
+    Box::into_raw(Box::default())
+}
+
+/// Frees an engine. Passing null does nothing.
+///
+/// # Safety
+/// `engine` must be null or have come from [`cashflow_engine_new`], and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn cashflow_engine_free(engine: *mut CashflowEngine) {
+    if !engine.is_null() {
+        drop(unsafe { Box::from_raw(engine) });
+    }
+}
+
+/// Applies one CSV row without a header, like `deposit,1,1,5.0`. Returns [`CASHFLOW_OK`], or
+/// [`CASHFLOW_REJECTED`] if the row couldn't be parsed or applied, or
+/// [`CASHFLOW_INVALID_ARGUMENT`].
+///
+/// # Safety
+/// `engine` must be null or have come from [`cashflow_engine_new`], and `line` must be null or
+/// point to a NUL-terminated string. Neither may be in use on another thread.
+#[no_mangle]
+pub unsafe extern "C" fn cashflow_apply_csv_line(
+    engine: *mut CashflowEngine,
+    line: *const c_char,
+) -> c_int {
+    let Some(engine) = (unsafe { engine.as_mut() }) else {
+        return CASHFLOW_INVALID_ARGUMENT;
+    };
+    if line.is_null() {
+        return CASHFLOW_INVALID_ARGUMENT;
+    }
+    let Ok(line) = unsafe { CStr::from_ptr(line) }.to_str() else {
+        return CASHFLOW_INVALID_ARGUMENT;
+    };
+    match engine.session.apply_csv(&format!("{HEADER}{line}\n")) {
+        Ok(()) => {
+            engine.last_error = None;
+            CASHFLOW_OK
+        }
+        Err(err) => {
+            // Messages never contain NUL, but dropping the message beats panicking across FFI
+            engine.last_error = CString::new(err.to_string()).ok();
+            CASHFLOW_REJECTED
+        }
+    }
+}
+
+/// Returns why the last row passed to [`cashflow_apply_csv_line`] was rejected, or why the last
+/// [`cashflow_report_csv`] failed, or null if neither did. The string belongs to the engine, and
+/// lasts until the next call with it.
+///
+/// # Safety
+/// `engine` must be null or have come from [`cashflow_engine_new`].
+#[no_mangle]
+pub unsafe extern "C" fn cashflow_last_error(engine: *const CashflowEngine) -> *const c_char {
+    unsafe { engine.as_ref() }
+        .and_then(|engine| engine.last_error.as_ref())
+        .map_or(ptr::null(), |err| err.as_ptr())
+}
+
+/// Returns every account as CSV with a header, in client order, or null if `engine` is null or
+/// writing the accounts failed, in which case [`cashflow_last_error`] says why. With no accounts,
+/// the string is empty. Free it with [`cashflow_string_free`].
+///
+/// # Safety
+/// `engine` must be null or have come from [`cashflow_engine_new`], and not be in use on another
+/// thread.
+#[no_mangle]
+pub unsafe extern "C" fn cashflow_report_csv(engine: *mut CashflowEngine) -> *mut c_char {
+    let Some(engine) = (unsafe { engine.as_mut() }) else {
+        return ptr::null_mut();
+    };
+    let report = engine
+        .session
+        .accounts_csv()
+        .and_then(|report| CString::new(report).map_err(|err| std::io::Error::from(err).into()));
+    match report {
+        Ok(report) => {
+            engine.last_error = None;
+            report.into_raw()
+        }
+        Err(err) => {
+            engine.last_error = CString::new(err.to_string()).ok();
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Frees a string returned by [`cashflow_report_csv`]. Passing null does nothing.
+///
+/// # Safety
+/// `string` must be null or have come from [`cashflow_report_csv`], and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn cashflow_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffi() {
+        let engine = cashflow_engine_new();
+        let apply = |line: &str| {
+            let line = CString::new(line).unwrap();
+            unsafe { cashflow_apply_csv_line(engine, line.as_ptr()) }
+        };
+        assert_eq!(apply("deposit,1,1,5.0"), CASHFLOW_OK);
+        assert_eq!(apply("withdrawal, 1, 2, 1.5"), CASHFLOW_OK);
+        assert!(unsafe { cashflow_last_error(engine) }.is_null());
+        assert_eq!(apply("refund,1,3,1.0"), CASHFLOW_REJECTED);
+        let err = unsafe { CStr::from_ptr(cashflow_last_error(engine)) };
+        assert!(err.to_str().unwrap().contains("refund"));
+        assert_eq!(
+            unsafe { cashflow_apply_csv_line(engine, ptr::null()) },
+            CASHFLOW_INVALID_ARGUMENT
+        );
+
+        let report = unsafe { cashflow_report_csv(engine) };
+        assert_eq!(
+            unsafe { CStr::from_ptr(report) }.to_str().unwrap(),
+            "client,available,held,total,locked\n1,3.5000,0.0000,3.5000,false\n"
+        );
+        assert!(unsafe { cashflow_last_error(engine) }.is_null());
+        assert!(unsafe { cashflow_report_csv(ptr::null_mut()) }.is_null());
+        unsafe {
+            cashflow_string_free(report);
+            cashflow_engine_free(engine);
+        }
+    }
+}
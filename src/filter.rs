@@ -0,0 +1,396 @@
+//! Filter expressions over accounts, for ad-hoc investigations without exporting to a database.
+//!
+//! A [`Filter`](crate::filter::Filter) can be built in code, or parsed from a string such as
+//! `locked && total < 0`. Expressions are made of:
+//! - comparisons of `client`, `available`, `held`, `total` or `locked` with a number, using `<`,
+//!   `<=`, `>`, `>=`, `==` or `!=`. `locked` counts as 1 for locked accounts and 0 otherwise.
+//! - `locked` on its own, which matches locked accounts
+//! - `!`, `&&` and `||`, where `!` binds tightest and `&&` binds tighter than `||`
+//! - parentheses
+//!
+//! Filters can be applied to account output with
+//! [`ReportOptions::filter`](crate::io::ReportOptions::filter).
+
+use std::{fmt::Display, str::FromStr};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    errors::{Error, ParseError},
+    io::AccountColumn,
+    types::Account,
+};
+
+/// How a column's value is compared with a number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// `<`
+    Less,
+    /// `<=`
+    LessOrEqual,
+    /// `>`
+    Greater,
+    /// `>=`
+    GreaterOrEqual,
+    /// `==`
+    Equal,
+    /// `!=`
+    NotEqual,
+}
+
+impl Comparison {
+    /// Returns the comparison's operator, eg `<=`
+    #[must_use]
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Less => "<",
+            Self::LessOrEqual => "<=",
+            Self::Greater => ">",
+            Self::GreaterOrEqual => ">=",
+            Self::Equal => "==",
+            Self::NotEqual => "!=",
+        }
+    }
+
+    /// Compares `value` with `operand`
+    fn holds(self, value: Decimal, operand: Decimal) -> bool {
+        match self {
+            Self::Less => value < operand,
+            Self::LessOrEqual => value <= operand,
+            Self::Greater => value > operand,
+            Self::GreaterOrEqual => value >= operand,
+            Self::Equal => value == operand,
+            Self::NotEqual => value != operand,
+        }
+    }
+}
+
+/// An expression picking out accounts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Matches locked accounts
+    Locked,
+    /// Matches accounts whose value in the column compares with the number as given
+    Compare(AccountColumn, Comparison, Decimal),
+    /// Matches accounts the inner filter doesn't
+    Not(Box<Filter>),
+    /// Matches accounts both filters match
+    And(Box<Filter>, Box<Filter>),
+    /// Matches accounts either filter matches
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    /// Returns whether the account matches the filter
+    #[must_use]
+    pub fn matches(&self, account: &Account) -> bool {
+        match self {
+            Self::Locked => account.is_locked(),
+            Self::Compare(column, comparison, operand) => {
+                comparison.holds(column_value(*column, account), *operand)
+            }
+            Self::Not(inner) => !inner.matches(account),
+            Self::And(left, right) => left.matches(account) && right.matches(account),
+            Self::Or(left, right) => left.matches(account) || right.matches(account),
+        }
+    }
+}
+
+/// Returns the account's value in the column as a number
+fn column_value(column: AccountColumn, account: &Account) -> Decimal {
+    match column {
+        AccountColumn::Client => account.client_id().0.into(),
+        AccountColumn::Available => account.funds_available(),
+        AccountColumn::Held => account.funds_held(),
+        AccountColumn::Total => account.total(),
+        AccountColumn::Locked => u8::from(account.is_locked()).into(),
+    }
+}
+
+/// Formats the filter so it parses back to the same thing, with parentheses around every `&&`
+/// and `||`
+impl Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Locked => write!(f, "locked"),
+            Self::Compare(column, comparison, operand) => {
+                write!(f, "{} {} {operand}", column.name(), comparison.symbol())
+            }
+            Self::Not(inner) => write!(f, "!{inner}"),
+            Self::And(left, right) => write!(f, "({left} && {right})"),
+            Self::Or(left, right) => write!(f, "({left} || {right})"),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    /// Parses a filter expression, as described in the [module docs](crate::filter)
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            next: 0,
+            end: expression.len(),
+        };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(filter),
+            Some((position, token)) => Err(invalid(*position, format!("unexpected {token}"))),
+        }
+    }
+}
+
+/// A piece of a filter expression
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    /// A column name
+    Name(&'a str),
+    /// A number to compare with
+    Number(Decimal),
+    /// A comparison operator
+    Compare(Comparison),
+    /// `!`
+    Not,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    /// `(`
+    Open,
+    /// `)`
+    Close,
+}
+
+impl Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "'{name}'"),
+            Self::Number(number) => write!(f, "{number}"),
+            Self::Compare(comparison) => write!(f, "'{}'", comparison.symbol()),
+            Self::Not => write!(f, "'!'"),
+            Self::And => write!(f, "'&&'"),
+            Self::Or => write!(f, "'||'"),
+            Self::Open => write!(f, "'('"),
+            Self::Close => write!(f, "')'"),
+        }
+    }
+}
+
+/// Builds the error for an invalid expression
+fn invalid(position: usize, reason: impl Into<String>) -> Error {
+    ParseError::Filter {
+        position,
+        reason: reason.into(),
+    }
+    .into()
+}
+
+/// Splits an expression into tokens, each with its byte offset
+fn tokenize(expression: &str) -> Result<Vec<(usize, Token<'_>)>, Error> {
+    /// Operators, longest first so `<=` isn't read as `<`
+    const OPERATORS: [(&str, Token<'static>); 11] = [
+        ("<=", Token::Compare(Comparison::LessOrEqual)),
+        (">=", Token::Compare(Comparison::GreaterOrEqual)),
+        ("==", Token::Compare(Comparison::Equal)),
+        ("!=", Token::Compare(Comparison::NotEqual)),
+        ("&&", Token::And),
+        ("||", Token::Or),
+        ("<", Token::Compare(Comparison::Less)),
+        (">", Token::Compare(Comparison::Greater)),
+        ("!", Token::Not),
+        ("(", Token::Open),
+        (")", Token::Close),
+    ];
+    let mut tokens = vec![];
+    let mut position = 0;
+    while let Some(c) = expression[position..].chars().next() {
+        let rest = &expression[position..];
+        let length = if c.is_whitespace() {
+            c.len_utf8()
+        } else if let Some((symbol, token)) = OPERATORS
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+        {
+            tokens.push((position, *token));
+            symbol.len()
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let length = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push((position, Token::Name(&rest[..length])));
+            length
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let length = rest[1..]
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .map_or(rest.len(), |length| length + 1);
+            let number = Decimal::from_str(&rest[..length]).map_err(|err| {
+                invalid(
+                    position,
+                    format!("invalid number {}: {err}", &rest[..length]),
+                )
+            })?;
+            tokens.push((position, Token::Number(number)));
+            length
+        } else {
+            return Err(invalid(position, format!("unexpected character '{c}'")));
+        };
+        position += length;
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens of an expression
+struct Parser<'a> {
+    /// Tokens of the expression, with their byte offsets
+    tokens: Vec<(usize, Token<'a>)>,
+    /// Index of the next token to parse
+    next: usize,
+    /// Length of the expression, where running out of tokens is reported
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Takes the next token, or fails if there isn't one
+    fn take(&mut self, expected: &str) -> Result<(usize, Token<'a>), Error> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .copied()
+            .ok_or_else(|| invalid(self.end, format!("expected {expected}")))?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    /// Takes the next token if it's the one given
+    fn take_if(&mut self, token: Token<'_>) -> bool {
+        let matched = self
+            .tokens
+            .get(self.next)
+            .is_some_and(|(_, next)| *next == token);
+        self.next += usize::from(matched);
+        matched
+    }
+
+    /// Parses `&&` expressions joined by `||`
+    fn or(&mut self) -> Result<Filter, Error> {
+        let mut filter = self.and()?;
+        while self.take_if(Token::Or) {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    /// Parses unary expressions joined by `&&`
+    fn and(&mut self) -> Result<Filter, Error> {
+        let mut filter = self.unary()?;
+        while self.take_if(Token::And) {
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    /// Parses a negation, a parenthesised expression, `locked`, or a comparison
+    fn unary(&mut self) -> Result<Filter, Error> {
+        let (position, token) = self.take("a column, '!' or '('")?;
+        match token {
+            Token::Not => Ok(Filter::Not(Box::new(self.unary()?))),
+            Token::Open => {
+                let filter = self.or()?;
+                match self.take("')'")? {
+                    (_, Token::Close) => Ok(filter),
+                    (position, token) => {
+                        Err(invalid(position, format!("expected ')', not {token}")))
+                    }
+                }
+            }
+            Token::Name(name) => {
+                let column = AccountColumn::from_name(name)
+                    .ok_or_else(|| invalid(position, format!("unknown column '{name}'")))?;
+                let comparison = match self.tokens.get(self.next) {
+                    Some((_, Token::Compare(comparison))) => *comparison,
+                    _ if column == AccountColumn::Locked => return Ok(Filter::Locked),
+                    _ => return Err(invalid(position, format!("'{name}' needs a comparison"))),
+                };
+                self.next += 1;
+                match self.take("a number")? {
+                    (_, Token::Number(number)) => Ok(Filter::Compare(column, comparison, number)),
+                    (position, token) => {
+                        Err(invalid(position, format!("expected a number, not {token}")))
+                    }
+                }
+            }
+            token => Err(invalid(
+                position,
+                format!("expected a column, '!' or '(', not {token}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::types::ClientId;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let filter: Filter = "locked && total < 0 || !(held <= 1.5) && client != 3"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter,
+            Filter::Or(
+                Box::new(Filter::And(
+                    Box::new(Filter::Locked),
+                    Box::new(Filter::Compare(
+                        AccountColumn::Total,
+                        Comparison::Less,
+                        dec!(0)
+                    )),
+                )),
+                Box::new(Filter::And(
+                    Box::new(Filter::Not(Box::new(Filter::Compare(
+                        AccountColumn::Held,
+                        Comparison::LessOrEqual,
+                        dec!(1.5)
+                    )))),
+                    Box::new(Filter::Compare(
+                        AccountColumn::Client,
+                        Comparison::NotEqual,
+                        dec!(3)
+                    )),
+                )),
+            )
+        );
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+
+        let error = |expression: &str| match expression.parse::<Filter>() {
+            Err(Error::Parse(ParseError::Filter { position, reason })) => (position, reason),
+            result => panic!("{expression} parsed as {result:?}"),
+        };
+        assert_eq!(error("total"), (0, "'total' needs a comparison".into()));
+        assert_eq!(error("held > "), (7, "expected a number".into()));
+        assert_eq!(error("owner == 1"), (0, "unknown column 'owner'".into()));
+        assert_eq!(error("(locked"), (7, "expected ')'".into()));
+        assert_eq!(error("locked locked").0, 7);
+        assert_eq!(error("total < 1 # 2").0, 10);
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let mut account = Account::new(ClientId(4));
+        account.funds_available = dec!(-2);
+        account.funds_held = dec!(1);
+        account.locked = true;
+        let matches = |expression: &str| expression.parse::<Filter>().unwrap().matches(&account);
+        assert!(matches("locked && total < 0"));
+        assert!(matches("total == -1 && available < -1.5"));
+        assert!(matches("locked == 1"));
+        assert!(!matches("!locked || client > 4"));
+        assert!(matches("client >= 4 && (held > 5 || available != 0)"));
+    }
+}
@@ -0,0 +1,88 @@
+//! An entry point for fuzzing the CSV path end to end, as run by the `cargo-fuzz` targets in
+//! `fuzz/`.
+//!
+//! [`fuzz_one_input`](crate::fuzz::fuzz_one_input) takes arbitrary bytes as transactions in CSV,
+//! so anything that makes it panic is a bug: hostile input should only ever be rejected.
+
+use std::io::Cursor;
+
+use crate::{
+    engine::{Engine, EngineSettings},
+    errors::SkipAndCollect,
+    io,
+    types::{AccountBook, MemoryAccountBook, MemoryTransactionLog, MinorUnitsTransactionLog},
+};
+
+/// Loads `data` as transactions in CSV, skipping bad rows, then writes the accounts out and reads
+/// them back, panicking if they don't match.
+///
+/// The input goes through two engines: one with the default settings, and one with pooled
+/// records, transaction validation and a [`MinorUnitsTransactionLog`], so both parsers and both
+/// sets of rules are exercised.
+///
+/// # Panics
+/// If the accounts read back from the report don't match those written, or on any bug the input
+/// reaches
+pub fn fuzz_one_input(data: &[u8]) {
+    let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+    engine.set_error_policy(SkipAndCollect::new());
+    // Errors are expected; only panics matter
+    let _ = engine.load_csv(&mut Cursor::new(data));
+    check_report(engine.into_parts().0);
+
+    let settings = EngineSettings {
+        pooled_records: true,
+        validate_transactions: true,
+        ..EngineSettings::default()
+    };
+    let mut engine = Engine::with_settings(
+        MemoryAccountBook::new(),
+        MinorUnitsTransactionLog::new(),
+        settings,
+    );
+    engine.set_error_policy(SkipAndCollect::new());
+    let _ = engine.load_csv(&mut Cursor::new(data));
+    let _ = engine.summary().to_string();
+    check_report(engine.into_parts().0);
+}
+
+/// Writes the accounts as a report, and checks reading it back gives the same accounts
+fn check_report(mut account_book: MemoryAccountBook) {
+    let mut report = vec![];
+    io::write_accounts_to_csv(&mut report, &account_book).expect("writing to memory can't fail");
+    let read_back = io::read_accounts_from_csv(&mut Cursor::new(report))
+        .expect("a report should always read back");
+    assert_eq!(read_back.len(), account_book.accounts.len());
+    for (client_id, account) in read_back {
+        let original = account_book.account(client_id).expect("in memory");
+        assert_eq!(
+            &account, original,
+            "account {client_id} changed in the report"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzz_one_input() {
+        let max = "79228162514264337593543950335";
+        let inputs = [
+            String::new(),
+            "\u{feff}type,client,tx,amount\n\"deposit\n".to_string(),
+            format!("type,client,tx,amount\ndeposit,1,1,{max}\ndeposit,1,2,{max}\n"),
+            format!("type,client,tx,amount\ndeposit,1,1,{max}\nwithdrawal,1,2,-{max}\n"),
+            format!(
+                "type,client,tx,amount\ndeposit,1,1,{max}\ndeposit,2,2,{max}\ndispute,1,1,\n\
+                 dispute,1,1,\nchargeback,1,1,\nchargeback,1,1,\n"
+            ),
+            "type,client,tx,amount\ndeposit,65536,1,1\ndeposit,1,4294967296,1\n".to_string(),
+            "type,client,tx,amount\ndeposit,1,1,0.00000000000000000000000000001\n".to_string(),
+        ];
+        for input in inputs {
+            fuzz_one_input(input.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,580 @@
+//! Transaction types supplied from outside the crate, for domain-specific operations such as fees
+//! or interest.
+//!
+//! A [`TransactionHandler`](crate::handler::TransactionHandler) is registered under the name that
+//! appears in the `type` column, with
+//! [`Engine::add_handler`](crate::engine::Engine::add_handler). Rows of that type that don't parse
+//! as a built-in [`Transaction`](crate::types::Transaction) are parsed as a
+//! [`CustomTransaction`](crate::handler::CustomTransaction) instead, and handed to the handler
+//! along with the client's account and the transaction log. The built-in types can't be
+//! overridden: rows of those types never reach a handler.
+//!
+//! The handler works on a [`StagedAccount`](crate::handler::StagedAccount), a copy of the
+//! account that's only written back if it succeeds, as built-in transactions are. Anything it
+//! registers in the log is [purged](crate::types::TransactionLog::purge) again if it fails, so a
+//! failure leaves the log as it was too, as long as the log can purge. For one that can't,
+//! register anything last. Failures go to the engine's [`ErrorPolicy`](crate::errors::ErrorPolicy)
+//! as the raw row, and are retried if it says so.
+//!
+//! Each transaction is applied once: one whose ID is already in the transaction log, or was
+//! already used by a custom transaction, is rejected as a
+//! [duplicate](crate::errors::DomainError::Duplicate) without reaching its handler. The IDs of
+//! custom transactions are kept in the engine's [snapshots](crate::engine::Engine::snapshot), and
+//! brought back by [`Engine::restore`](crate::engine::Engine::restore), so they're remembered
+//! across restarts. During a [backfill](crate::backfill), custom transactions are handed over to
+//! the live feed with the deposits and withdrawals around them.
+//!
+//! Only rows loaded by [`Engine::load_csv`](crate::engine::Engine::load_csv),
+//! [`Engine::load_named_csv`](crate::engine::Engine::load_named_csv) and
+//! [`Engine::submit_csv`](crate::engine::Engine::submit_csv), and those recorded in a
+//! [`Wal`](crate::state::Wal), are handled. Custom transactions aren't counted as applied in the
+//! metrics, and aren't part of an account's history.
+//!
+//! Listeners are told about each custom transaction applied with
+//! [`on_custom_applied`](crate::events::EventListener::on_custom_applied), so they're journaled in
+//! a [`Wal`](crate::state::Wal) and the [audit log](crate::audit). Recovering from a WAL with
+//! [`Engine::replay`](crate::engine::Engine::replay) hands them to their handlers again, so those
+//! need registering first.
+//! ```
+//! # use cashflow::{engine::Engine, errors::Error, handler::*, types::*};
+//! # use rust_decimal_macros::dec;
+//! /// Takes a fee out of the client's available funds
+//! struct Fee;
+//!
+//! impl TransactionHandler for Fee {
+//!     fn name(&self) -> &str {
+//!         "fee"
+//!     }
+//!
+//!     fn apply(
+//!         &mut self,
+//!         transaction: &CustomTransaction,
+//!         account: &mut StagedAccount<'_>,
+//!         _transaction_log: &mut dyn TransactionLog,
+//!     ) -> Result<(), Error> {
+//!         account.withdraw(transaction.amount.unwrap_or(dec!(1)))
+//!     }
+//! }
+//!
+//! let mut engine = Engine::in_memory();
+//! engine.add_handler(Fee);
+//! engine.load_csv(&mut "type,client,tx,amount\ndeposit,1,1,5.0\nfee,1,2,\n".as_bytes())?;
+//! let account = engine.account_book().accounts().next().unwrap();
+//! assert_eq!(account.funds_available(), dec!(4));
+//! # Ok::<(), Error>(())
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Debug, Formatter},
+};
+
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    errors::{DomainError, Error, ParseError},
+    types::{
+        deserialize_option_decimal, Account, AccountBook, ClientId, Money, Provenance, Transaction,
+        TransactionId, TransactionLog, TransactionRecord,
+    },
+};
+
+/// A row of a type handled by a [`TransactionHandler`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CustomTransaction {
+    /// The type, as it appears in the `type` column
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The client whose account it applies to
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    /// The transaction's ID
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    /// The amount, if the row has one, exactly as it was given
+    #[serde(default, deserialize_with = "deserialize_option_decimal")]
+    pub amount: Option<Decimal>,
+    /// When the transaction happened, in seconds since the Unix epoch, if the input has a
+    /// `timestamp` column
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+impl CustomTransaction {
+    /// Returns the transaction as a row, eg for an [`ErrorPolicy`](crate::errors::ErrorPolicy)
+    /// when it wasn't read from one
+    pub(crate) fn to_record(&self) -> ByteRecord {
+        let amount = self
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        ByteRecord::from(vec![
+            self.kind.clone(),
+            self.client_id.to_string(),
+            self.transaction_id.to_string(),
+            amount,
+        ])
+    }
+}
+
+/// A copy of a client's account for a [`TransactionHandler`] to change, written back only if the
+/// handler succeeds.
+///
+/// Amounts are in the account's currency, and rounded to the account book's
+/// [scale](AccountBook::scale), as those of built-in transactions are.
+#[derive(Debug)]
+pub struct StagedAccount<'a> {
+    /// The copy being changed
+    account: &'a mut Account,
+    /// The transaction being applied, for errors
+    transaction_id: TransactionId,
+    /// The account book's scale
+    scale: u32,
+}
+
+impl StagedAccount<'_> {
+    /// Returns the account as it is so far
+    #[must_use]
+    pub fn account(&self) -> &Account {
+        self.account
+    }
+
+    /// Adds funds to the available funds, as a deposit does
+    /// # Errors
+    /// [`DomainError::Locked`] if the account is locked, or [`DomainError::AmountOutOfRange`] if
+    /// a balance would overflow
+    pub fn deposit(&mut self, amount: Decimal) -> Result<(), Error> {
+        let amount = Money::new(amount, self.account.currency);
+        let applied = self
+            .account
+            .deposit(amount, self.transaction_id, self.scale)?;
+        self.check(applied)
+    }
+
+    /// Takes funds out of the available funds, as a withdrawal does. They're allowed to go
+    /// negative.
+    /// # Errors
+    /// [`DomainError::Locked`] if the account is locked, or [`DomainError::AmountOutOfRange`] if
+    /// a balance would overflow
+    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), Error> {
+        let amount = Money::new(amount, self.account.currency);
+        let applied = self
+            .account
+            .withdraw(amount, self.transaction_id, self.scale)?;
+        self.check(applied)
+    }
+
+    /// Moves funds from available to held, as a dispute does, even if the account is locked
+    /// # Errors
+    /// [`DomainError::AmountOutOfRange`] if a balance would overflow
+    pub fn hold(&mut self, amount: Decimal) -> Result<(), Error> {
+        let applied = self.account.dispute(amount, self.scale);
+        self.check(applied)
+    }
+
+    /// Moves funds from held back to available, as a resolve does
+    /// # Errors
+    /// [`DomainError::AmountOutOfRange`] if a balance would overflow
+    pub fn release(&mut self, amount: Decimal) -> Result<(), Error> {
+        let applied = self.account.resolve(amount, self.scale);
+        self.check(applied)
+    }
+
+    /// Takes funds out of the held funds and locks the account, as a chargeback does
+    /// # Errors
+    /// [`DomainError::AmountOutOfRange`] if a balance would overflow
+    pub fn charge_back(&mut self, amount: Decimal) -> Result<(), Error> {
+        let applied = self.account.chargeback(amount, self.scale);
+        self.check(applied)
+    }
+
+    /// Turns an overflowed balance into an error
+    fn check(&self, applied: Option<()>) -> Result<(), Error> {
+        applied.ok_or_else(|| DomainError::AmountOutOfRange(self.transaction_id).into())
+    }
+}
+
+/// Applies a custom transaction type, registered with
+/// [`Engine::add_handler`](crate::engine::Engine::add_handler)
+pub trait TransactionHandler {
+    /// The type this handles, as it appears in the `type` column, eg `fee`
+    fn name(&self) -> &str;
+
+    /// Applies a transaction to the client's account, and registers whatever it needs to in the
+    /// transaction log, eg so it can be disputed
+    /// # Errors
+    /// If the transaction is rejected. The account is then left as it was.
+    fn apply(
+        &mut self,
+        transaction: &CustomTransaction,
+        account: &mut StagedAccount<'_>,
+        transaction_log: &mut dyn TransactionLog,
+    ) -> Result<(), Error>;
+}
+
+/// The transaction handlers registered on an engine
+#[derive(Default)]
+pub(crate) struct TransactionHandlers {
+    /// Handlers, in the order they were added
+    handlers: Vec<Box<dyn TransactionHandler + Send>>,
+    /// The IDs of the custom transactions applied so far, so none is applied twice
+    applied: BTreeSet<TransactionId>,
+}
+
+impl TransactionHandlers {
+    /// Adds a handler, replacing any with the same name
+    pub(crate) fn insert(&mut self, handler: Box<dyn TransactionHandler + Send>) {
+        match self
+            .handlers
+            .iter_mut()
+            .find(|existing| existing.name() == handler.name())
+        {
+            Some(existing) => *existing = handler,
+            None => self.handlers.push(handler),
+        }
+    }
+
+    /// Returns the names of the handlers, in the order they were added
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.handlers.iter().map(|handler| handler.name()).collect()
+    }
+
+    /// Parses a row as a custom transaction, if it's of a type with a handler
+    pub(crate) fn parse(
+        &self,
+        record: &ByteRecord,
+        headers: &ByteRecord,
+    ) -> Option<CustomTransaction> {
+        if self.handlers.is_empty() {
+            return None;
+        }
+        let transaction: CustomTransaction = record.deserialize(Some(headers)).ok()?;
+        self.handlers
+            .iter()
+            .any(|handler| handler.name() == transaction.kind)
+            .then_some(transaction)
+    }
+
+    /// Hands a custom transaction to its handler with a staged copy of the client's account,
+    /// which replaces the account if the handler succeeds
+    /// # Errors
+    /// [`DomainError::Duplicate`] if the transaction's ID was already used, or whatever the
+    /// handler returns
+    pub(crate) fn apply<A>(
+        &mut self,
+        transaction: &CustomTransaction,
+        account_book: &mut A,
+        transaction_log: &mut dyn TransactionLog,
+    ) -> Result<(), Error>
+    where
+        A: AccountBook + ?Sized,
+    {
+        let handler = self
+            .handlers
+            .iter_mut()
+            .find(|handler| handler.name() == transaction.kind)
+            .ok_or_else(|| ParseError::UnknownTransactionType(transaction.kind.clone()))?;
+        let transaction_id = transaction.transaction_id;
+        if self.applied.contains(&transaction_id)
+            || transaction_log.transaction(transaction_id)?.is_some()
+        {
+            return Err(DomainError::Duplicate(transaction_id).into());
+        }
+        let scale = account_book.scale();
+        let account = account_book.account_mut(transaction.client_id)?;
+        let mut staged = account.clone();
+        let mut staged_log = StagedLog {
+            inner: transaction_log,
+            registered: vec![],
+        };
+        let result = handler.apply(
+            transaction,
+            &mut StagedAccount {
+                account: &mut staged,
+                transaction_id,
+                scale,
+            },
+            &mut staged_log,
+        );
+        if let Err(err) = result {
+            // Whatever it registered before failing goes too
+            if !staged_log.registered.is_empty() {
+                staged_log.inner.purge(&staged_log.registered)?;
+            }
+            return Err(err);
+        }
+        *account = staged;
+        self.applied.insert(transaction_id);
+        Ok(())
+    }
+
+    /// Returns the IDs of the custom transactions applied so far, in order
+    pub(crate) fn applied(&self) -> impl Iterator<Item = TransactionId> + '_ {
+        self.applied.iter().copied()
+    }
+
+    /// Remembers custom transactions applied before, eg by the engine a snapshot was taken of
+    pub(crate) fn remember(&mut self, applied: impl IntoIterator<Item = TransactionId>) {
+        self.applied.extend(applied);
+    }
+}
+
+/// The transaction log as a handler sees it, noting what it registers so it can be purged if the
+/// handler fails
+struct StagedLog<'a> {
+    /// The engine's transaction log
+    inner: &'a mut dyn TransactionLog,
+    /// The IDs registered so far
+    registered: Vec<TransactionId>,
+}
+
+impl TransactionLog for StagedLog<'_> {
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        self.inner.transaction(transaction_id)
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let transaction_id = transaction.transaction_id();
+        self.inner.register(transaction)?;
+        self.registered.push(transaction_id);
+        Ok(())
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.inner.check_connection()
+    }
+
+    fn record_provenance(&mut self, transaction_id: TransactionId, provenance: Provenance) {
+        self.inner.record_provenance(transaction_id, provenance);
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        self.inner.transactions()
+    }
+
+    fn provenance(&self, transaction_id: TransactionId) -> Result<Option<Provenance>, Error> {
+        self.inner.provenance(transaction_id)
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        self.registered.retain(|id| !transaction_ids.contains(id));
+        self.inner.purge(transaction_ids)
+    }
+}
+
+impl Debug for TransactionHandlers {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        backfill::Cutoff,
+        engine::Engine,
+        errors::SkipAndCollect,
+        state::{read_wal, read_wal_entries, Wal, WalEntry},
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    /// Credits a bonus, registered as a deposit so it can be disputed
+    struct Bonus;
+
+    impl TransactionHandler for Bonus {
+        fn name(&self) -> &str {
+            "bonus"
+        }
+
+        fn apply(
+            &mut self,
+            transaction: &CustomTransaction,
+            account: &mut StagedAccount<'_>,
+            transaction_log: &mut dyn TransactionLog,
+        ) -> Result<(), Error> {
+            let amount = transaction
+                .amount
+                .ok_or(DomainError::MissingAmount(transaction.transaction_id))?;
+            account.deposit(amount)?;
+            transaction_log.register(Transaction::deposit(
+                transaction.client_id,
+                transaction.transaction_id,
+                amount,
+            )?)
+        }
+    }
+
+    /// Changes the account and registers a deposit, then fails
+    struct Broken;
+
+    impl TransactionHandler for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        fn apply(
+            &mut self,
+            transaction: &CustomTransaction,
+            account: &mut StagedAccount<'_>,
+            transaction_log: &mut dyn TransactionLog,
+        ) -> Result<(), Error> {
+            account.withdraw(dec!(100))?;
+            transaction_log.register(Transaction::deposit(
+                transaction.client_id,
+                transaction.transaction_id,
+                dec!(100),
+            )?)?;
+            Err(DomainError::Locked(transaction.client_id).into())
+        }
+    }
+
+    /// Takes a fee out, without registering anything
+    struct Fee;
+
+    impl TransactionHandler for Fee {
+        fn name(&self) -> &str {
+            "fee"
+        }
+
+        fn apply(
+            &mut self,
+            transaction: &CustomTransaction,
+            account: &mut StagedAccount<'_>,
+            _transaction_log: &mut dyn TransactionLog,
+        ) -> Result<(), Error> {
+            account.withdraw(transaction.amount.unwrap_or_default())
+        }
+    }
+
+    #[test]
+    fn test_custom_transactions() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_handler(Bonus);
+        engine.add_handler(Broken);
+        let policy = SkipAndCollect::new();
+        engine.set_error_policy(policy.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+bonus,1,2,2.5
+broken,1,3,
+dispute,1,2,
+unknown,1,4,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let account = &engine.account_book().accounts[&ClientId(1)];
+        // The bonus was credited and then disputed, and the broken handler's changes undone, its
+        // deposit included
+        assert_eq!(account.funds_available(), dec!(5));
+        assert_eq!(account.funds_held(), dec!(2.5));
+        assert_eq!(engine.transaction_log().transactions().count(), 2);
+        let skipped = policy.skipped();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].reason.contains("locked"));
+        assert!(skipped[1].reason.contains("unknown variant `unknown`"));
+    }
+
+    #[test]
+    fn test_custom_transactions_applied_once() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_handler(Fee);
+        let policy = SkipAndCollect::new();
+        engine.set_error_policy(policy.clone());
+        // The second fee repeats the first's ID, and the third a deposit's
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+fee,1,2,1.0
+fee,1,2,1.0
+fee,1,1,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let account = &engine.account_book().accounts[&ClientId(1)];
+        assert_eq!(account.funds_available(), dec!(4));
+        let skipped = policy.skipped();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped
+            .iter()
+            .all(|row| row.reason.contains("already applied")));
+
+        // Fees the backfill applied are skipped while the live feed catches up
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_handler(Fee);
+        let archive = "type,client,tx,amount\ndeposit,1,1,5.0\nfee,1,2,1.0\n";
+        engine
+            .backfill(
+                [Cursor::new(archive)],
+                Cutoff::Transaction(TransactionId(2)),
+            )
+            .unwrap();
+        let live = "type,client,tx,amount\nfee,1,2,1.0\nfee,1,3,1.0\n";
+        engine.load_csv(&mut Cursor::new(live)).unwrap();
+        assert!(!engine.is_catching_up());
+        assert_eq!(
+            engine.account_book().accounts[&ClientId(1)].funds_available(),
+            dec!(3)
+        );
+
+        // Or ones applied before a snapshot was restored
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.custom.len(), 2);
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_handler(Fee);
+        engine.set_error_policy(policy.clone());
+        engine.restore(snapshot).unwrap();
+        engine.load_csv(&mut Cursor::new(live)).unwrap();
+        assert_eq!(
+            engine.account_book().accounts[&ClientId(1)].funds_available(),
+            dec!(3)
+        );
+        assert_eq!(policy.skipped().len(), 4);
+    }
+
+    #[test]
+    fn test_custom_transactions_journaled() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_handler(Bonus);
+        engine.add_handler(Fee);
+        let path = std::env::temp_dir().join(format!("cashflow-custom-{}.wal", std::process::id()));
+        let wal = Wal::new(std::fs::File::create(&path).unwrap());
+        engine.add_listener(wal.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+bonus,1,2,2.5
+fee,1,3,1.0
+dispute,1,2,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        wal.finish().unwrap();
+        let wal = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_wal(&mut Cursor::new(&wal)).is_err());
+        let entries = read_wal_entries(&mut Cursor::new(&wal)).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(&entries[2], WalEntry::Custom(fee) if fee.kind == "fee"));
+
+        // Recovering from the WAL hands the custom transactions to their handlers again
+        let mut recovered = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        recovered.add_handler(Bonus);
+        recovered.add_handler(Fee);
+        recovered.replay(entries).unwrap();
+        assert_eq!(
+            recovered.account_book().accounts,
+            engine.account_book().accounts
+        );
+        assert_eq!(recovered.snapshot(), engine.snapshot());
+    }
+}
@@ -0,0 +1,250 @@
+//! Remembering submissions by a key their sender chooses, so a retried API call is answered with
+//! the outcome of the first rather than applied again.
+//!
+//! An API client that times out waiting for a response can't tell whether its batch was applied,
+//! and resubmitting it would apply its deposits and withdrawals a second time. Instead, it sends
+//! the same idempotency key with the retry, and
+//! [`Engine::submit_csv`](crate::engine::Engine::submit_csv) returns the first attempt's
+//! [`Submission`](crate::idempotency::Submission) without applying anything.
+//!
+//! A submission stopped by an error that may go away if tried again, like a rate limit or a
+//! storage blip (see [`Error::is_retryable`](crate::errors::Error::is_retryable)), isn't
+//! replayed. A retry with the same key carries on from the transaction it stopped at instead,
+//! so the transactions already applied aren't applied again, and the rest are.
+//!
+//! Keys are remembered for the
+//! [`EngineSettings::idempotency_window`](crate::engine::EngineSettings::idempotency_window),
+//! measured by the engine's [clock](crate::clock), and at most
+//! [`MAX_KEYS`](crate::idempotency::MAX_KEYS) of them, after which the oldest are forgotten
+//! early. Reusing a key within the window for a different body is an
+//! error, [`Error::IdempotencyKeyReused`](crate::errors::Error::IdempotencyKeyReused), since it's
+//! most likely a bug in the client.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::errors::Error;
+
+/// What came of submitting a batch of transactions
+#[derive(Debug, Clone)]
+pub struct Submission {
+    /// How many transactions were applied before any error
+    pub applied: u64,
+    /// Why the rest of the transactions weren't applied, if loading stopped early
+    pub error: Option<Arc<Error>>,
+    /// Whether this is the remembered outcome of an earlier submission with the same key, in
+    /// which case nothing was applied this time
+    pub replayed: bool,
+}
+
+/// The most keys remembered at once
+pub const MAX_KEYS: usize = 100_000;
+
+/// A remembered submission
+#[derive(Debug)]
+struct Entry {
+    /// SHA-256 of the submitted body, to spot a key reused for something else
+    fingerprint: [u8; 32],
+    /// What came of it
+    submission: Submission,
+    /// The line of the body's first transaction not yet applied, if it was stopped by a
+    /// retryable error
+    resume: Option<u64>,
+}
+
+/// What's remembered of an earlier submission with the same key
+#[derive(Debug)]
+pub(crate) enum Remembered {
+    /// It's done with, so this is what came of it
+    Done(Submission),
+    /// It was stopped by a retryable error, having applied this many transactions, so carry on
+    /// from the transaction at this line
+    Stopped {
+        /// How many transactions were applied
+        applied: u64,
+        /// The line of the first transaction not yet applied
+        line: u64,
+    },
+}
+
+/// Submissions remembered by idempotency key, for as long as the window lasts
+#[derive(Debug)]
+pub(crate) struct IdempotencyWindow {
+    /// How long each key is remembered
+    window: Duration,
+    /// Remembered submissions by key
+    entries: HashMap<String, Entry>,
+    /// Keys in the order they were first seen, with when, so expired ones can be forgotten
+    seen: VecDeque<(Instant, String)>,
+}
+
+impl IdempotencyWindow {
+    /// Creates a window remembering keys for the supplied duration
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: HashMap::new(),
+            seen: VecDeque::new(),
+        }
+    }
+
+    /// Returns what's remembered of an earlier submission with the key, if it's still within the
+    /// window
+    /// # Errors
+    /// [`Error::IdempotencyKeyReused`] if the key was used for a different body
+    pub(crate) fn lookup(
+        &mut self,
+        key: &str,
+        body: &[u8],
+        now: Instant,
+    ) -> Result<Option<Remembered>, Error> {
+        self.expire(now);
+        let Some(entry) = self.entries.get(key) else {
+            return Ok(None);
+        };
+        if entry.fingerprint != fingerprint(body) {
+            return Err(Error::IdempotencyKeyReused(key.to_string()));
+        }
+        Ok(Some(match entry.resume {
+            Some(line) => Remembered::Stopped {
+                applied: entry.submission.applied,
+                line,
+            },
+            None => Remembered::Done(Submission {
+                replayed: true,
+                ..entry.submission.clone()
+            }),
+        }))
+    }
+
+    /// Remembers the outcome of a submission with the key, and if it was stopped by a retryable
+    /// error, the line of the transaction to carry on from
+    pub(crate) fn record(
+        &mut self,
+        key: &str,
+        body: &[u8],
+        submission: Submission,
+        resume: Option<u64>,
+        now: Instant,
+    ) {
+        let entry = Entry {
+            fingerprint: fingerprint(body),
+            submission,
+            resume,
+        };
+        if self.entries.insert(key.to_string(), entry).is_none() {
+            self.seen.push_back((now, key.to_string()));
+        }
+        while self.entries.len() > MAX_KEYS {
+            let Some((_, oldest)) = self.seen.pop_front() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Returns the number of keys remembered
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Forgets keys first seen longer ago than the window
+    fn expire(&mut self, now: Instant) {
+        while let Some((seen, key)) = self.seen.front() {
+            if now.saturating_duration_since(*seen) < self.window {
+                break;
+            }
+            self.entries.remove(key);
+            self.seen.pop_front();
+        }
+    }
+}
+
+/// Returns the SHA-256 of a submission's body
+fn fingerprint(body: &[u8]) -> [u8; 32] {
+    Sha256::digest(body).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A submission that applied `applied` transactions without error
+    fn submission(applied: u64) -> Submission {
+        Submission {
+            applied,
+            error: None,
+            replayed: false,
+        }
+    }
+
+    #[test]
+    fn test_expiry() {
+        let window = Duration::from_secs(60);
+        let mut keys = IdempotencyWindow::new(window);
+        let start = Instant::now();
+        keys.record("a", b"body", submission(1), None, start);
+        let remembered = keys
+            .lookup("a", b"body", start + window - Duration::from_nanos(1))
+            .unwrap();
+        assert!(matches!(
+            remembered,
+            Some(Remembered::Done(Submission {
+                applied: 1,
+                replayed: true,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            keys.lookup("a", b"other", start),
+            Err(Error::IdempotencyKeyReused(key)) if key == "a"
+        ));
+        // Forgotten once exactly the window has passed
+        assert!(keys.lookup("a", b"body", start + window).unwrap().is_none());
+        assert_eq!(keys.len(), 0);
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let mut keys = IdempotencyWindow::new(Duration::from_secs(60));
+        let now = Instant::now();
+        for key in 0..=MAX_KEYS {
+            keys.record(&key.to_string(), b"body", submission(1), None, now);
+        }
+        assert_eq!(keys.len(), MAX_KEYS);
+        // The oldest key goes first
+        assert!(keys.lookup("0", b"body", now).unwrap().is_none());
+        assert!(keys.lookup("1", b"body", now).unwrap().is_some());
+        assert!(keys
+            .lookup(&MAX_KEYS.to_string(), b"body", now)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_rerecording_stopped_key() {
+        let window = Duration::from_secs(60);
+        let mut keys = IdempotencyWindow::new(window);
+        let start = Instant::now();
+        keys.record("a", b"body", submission(2), Some(4), start);
+        assert!(matches!(
+            keys.lookup("a", b"body", start).unwrap(),
+            Some(Remembered::Stopped {
+                applied: 2,
+                line: 4
+            })
+        ));
+        // Finishing the submission later doesn't restart its window
+        keys.record("a", b"body", submission(5), None, start + window / 2);
+        assert!(matches!(
+            keys.lookup("a", b"body", start + window / 2).unwrap(),
+            Some(Remembered::Done(Submission { applied: 5, .. }))
+        ));
+        assert!(keys.lookup("a", b"body", start + window).unwrap().is_none());
+    }
+}
@@ -0,0 +1,247 @@
+//! Checks of transaction inputs for upstream data problems, before they reach any balances.
+//!
+//! An [`IdAnalyzer`](crate::integrity::IdAnalyzer) reads inputs without applying anything, and
+//! [finishes](crate::integrity::IdAnalyzer::finish) with an
+//! [`IdAnalysis`](crate::integrity::IdAnalysis) of the deposit and withdrawal IDs it saw:
+//!
+//! - **Duplicates**: IDs used by more than one deposit or withdrawal. The engine would apply each
+//!   of them, so the later ones silently replace the earlier in the transaction log, and disputes
+//!   can only ever reach the last.
+//! - **Gaps**: runs of IDs missing between the smallest and largest seen, which usually mean rows
+//!   were dropped upstream. Nothing is assumed about IDs below the smallest.
+//!
+//! Disputes, resolves and chargebacks refer to the ID of the transaction they're about, so they
+//! aren't counted.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    io::Read,
+    sync::Arc,
+};
+
+use crate::{
+    errors::Error,
+    io::{line_of, read_csv},
+    types::{Provenance, RawTransactionId, TransactionId, TransactionType},
+};
+
+/// How many duplicates and gaps are listed when an [`IdAnalysis`] is displayed
+const LISTED: usize = 10;
+
+/// Collects the transaction IDs in one or more inputs, for an [`IdAnalysis`]
+#[derive(Debug, Default)]
+pub struct IdAnalyzer {
+    /// Where each deposit and withdrawal ID was seen, in the order read
+    seen: Vec<(TransactionId, Provenance)>,
+    /// Rows that couldn't be parsed, and so were skipped
+    unparsed: u64,
+}
+
+impl IdAnalyzer {
+    /// Creates an analyzer that hasn't seen anything yet
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads transactions from CSV, noting the ID of each deposit and withdrawal and where it was.
+    /// Rows that can't be parsed are counted and skipped.
+    /// # Errors
+    /// If the input can't be read as CSV at all
+    pub fn read_csv<R: Read>(&mut self, name: &str, reader: R) -> Result<(), Error> {
+        let source: Arc<str> = Arc::from(name);
+        read_csv(reader, false, |transaction, record| {
+            match transaction {
+                Ok(transaction) => {
+                    if matches!(
+                        transaction.transaction_type,
+                        TransactionType::Deposit | TransactionType::Withdrawal
+                    ) {
+                        let provenance = Provenance {
+                            source: Arc::clone(&source),
+                            line: line_of(record),
+                        };
+                        self.seen.push((transaction.transaction_id, provenance));
+                    }
+                }
+                Err(_) => self.unparsed += 1,
+            }
+            Ok(())
+        })
+    }
+
+    /// Finds the duplicates and gaps among everything read
+    #[must_use]
+    pub fn finish(self) -> IdAnalysis {
+        let transactions = self.seen.len() as u64;
+        let mut occurrences: BTreeMap<TransactionId, Vec<Provenance>> = BTreeMap::new();
+        for (id, provenance) in self.seen {
+            occurrences.entry(id).or_default().push(provenance);
+        }
+        let gaps = occurrences
+            .keys()
+            .zip(occurrences.keys().skip(1))
+            .filter(|(a, b)| b.0 - a.0 > 1)
+            .map(|(a, b)| IdGap {
+                first: TransactionId(a.0 + 1),
+                last: TransactionId(b.0 - 1),
+            })
+            .collect();
+        let duplicates = occurrences
+            .into_iter()
+            .filter(|(_, occurrences)| occurrences.len() > 1)
+            .map(|(tx, occurrences)| DuplicateId { tx, occurrences })
+            .collect();
+        IdAnalysis {
+            transactions,
+            unparsed: self.unparsed,
+            duplicates,
+            gaps,
+        }
+    }
+}
+
+/// A transaction ID used by more than one deposit or withdrawal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateId {
+    /// The ID
+    pub tx: TransactionId,
+    /// Everywhere it was used, in the order read
+    pub occurrences: Vec<Provenance>,
+}
+
+/// A run of transaction IDs that were never seen, between two that were
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdGap {
+    /// The first missing ID
+    pub first: TransactionId,
+    /// The last missing ID, which is `first` if only one is missing
+    pub last: TransactionId,
+}
+
+impl IdGap {
+    /// Returns how many IDs are missing
+    #[must_use]
+    pub fn missing(&self) -> RawTransactionId {
+        self.last.0 - self.first.0 + 1
+    }
+}
+
+/// The duplicate and missing transaction IDs found by an [`IdAnalyzer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdAnalysis {
+    /// Number of deposits and withdrawals read
+    pub transactions: u64,
+    /// Number of rows that couldn't be parsed
+    pub unparsed: u64,
+    /// IDs used more than once, in ID order
+    pub duplicates: Vec<DuplicateId>,
+    /// Runs of missing IDs, in ID order
+    pub gaps: Vec<IdGap>,
+}
+
+impl IdAnalysis {
+    /// Returns whether no duplicates or gaps were found
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.duplicates.is_empty() && self.gaps.is_empty()
+    }
+}
+
+impl Display for IdAnalysis {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} deposits and withdrawals, {} unparsed rows",
+            self.transactions, self.unparsed
+        )?;
+        writeln!(f, "duplicate IDs: {}", self.duplicates.len())?;
+        for duplicate in self.duplicates.iter().take(LISTED) {
+            let places: Vec<String> = duplicate
+                .occurrences
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            writeln!(f, "  {} at {}", duplicate.tx, places.join(", "))?;
+        }
+        if self.duplicates.len() > LISTED {
+            writeln!(f, "  and {} more", self.duplicates.len() - LISTED)?;
+        }
+        let missing: RawTransactionId = self.gaps.iter().map(IdGap::missing).sum();
+        writeln!(f, "gaps: {} ({missing} IDs missing)", self.gaps.len())?;
+        for gap in self.gaps.iter().take(LISTED) {
+            if gap.first == gap.last {
+                writeln!(f, "  {}", gap.first)?;
+            } else {
+                writeln!(f, "  {} to {}", gap.first, gap.last)?;
+            }
+        }
+        if self.gaps.len() > LISTED {
+            writeln!(f, "  and {} more", self.gaps.len() - LISTED)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_id_analysis() {
+        let mut analyzer = IdAnalyzer::new();
+        let first = "type,client,tx,amount
+deposit,1,3,5.0
+deposit,1,4,1.0
+dispute,1,3,
+deposit,2,bad,1.0
+";
+        let second = "type,client,tx,amount
+withdrawal,2,4,1.0
+deposit,2,8,1.0
+deposit,1,10,1.0
+";
+        analyzer.read_csv("a.csv", Cursor::new(first)).unwrap();
+        analyzer.read_csv("b.csv", Cursor::new(second)).unwrap();
+        let analysis = analyzer.finish();
+        assert!(!analysis.is_clean());
+        assert_eq!(analysis.transactions, 5);
+        assert_eq!(analysis.unparsed, 1);
+        assert_eq!(analysis.duplicates.len(), 1);
+        assert_eq!(analysis.duplicates[0].tx, TransactionId(4));
+        let places: Vec<String> = analysis.duplicates[0]
+            .occurrences
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(places, ["a.csv:3", "b.csv:2"]);
+        let gaps: Vec<(RawTransactionId, RawTransactionId)> = analysis
+            .gaps
+            .iter()
+            .map(|gap| (gap.first.0, gap.last.0))
+            .collect();
+        assert_eq!(gaps, [(5, 7), (9, 9)]);
+        assert_eq!(
+            analysis.to_string(),
+            "5 deposits and withdrawals, 1 unparsed rows
+duplicate IDs: 1
+  id[4] at a.csv:3, b.csv:2
+gaps: 2 (4 IDs missing)
+  id[5] to id[7]
+  id[9]
+"
+        );
+
+        let mut analyzer = IdAnalyzer::new();
+        analyzer
+            .read_csv(
+                "c.csv",
+                Cursor::new("type,client,tx,amount\ndeposit,1,1,1.0\n"),
+            )
+            .unwrap();
+        assert!(analyzer.finish().is_clean());
+    }
+}
@@ -0,0 +1,212 @@
+//! Invariants checked after every transaction in
+//! [paranoid](crate::engine::EngineSettings::paranoid) mode.
+//!
+//! Alongside the account book, a shadow copy of each account's total and held funds is kept,
+//! worked out from the transactions applied rather than by the code under test. After each
+//! transaction, the account must agree with its shadow, must not hold negative funds unless
+//! something odd (like a resolve of an undisputed transaction) was allowed through, and must only
+//! be locked if a chargeback locked it. Any disagreement panics, with a dump of the account, its
+//! shadow and its recent transactions.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write,
+};
+
+use rust_decimal::Decimal;
+
+use crate::types::{Account, ClientId, TransactionId, TransactionRecord, TransactionType};
+
+/// Number of each account's most recent transactions kept for the dump
+const RECENT_TRANSACTIONS: usize = 16;
+
+/// What an account should look like, according to the transactions applied to it
+#[derive(Debug, Default)]
+struct Expected {
+    /// Total funds
+    total: Decimal,
+    /// Held funds
+    held: Decimal,
+    /// Transactions currently under dispute
+    disputed: HashSet<TransactionId>,
+    /// The chargeback that locked the account, if one has
+    locked_by: Option<TransactionId>,
+    /// Whether a resolve or chargeback of an undisputed transaction was applied, which can
+    /// legitimately leave held funds negative
+    flagged: bool,
+    /// The most recent transactions applied, oldest first
+    recent: VecDeque<TransactionRecord>,
+}
+
+/// Shadow copies of every account seen
+#[derive(Debug, Default)]
+pub(crate) struct Invariants {
+    /// Keyed by client
+    expected: HashMap<ClientId, Expected>,
+}
+
+impl Invariants {
+    /// Updates the account's shadow for a transaction that's just been applied, then checks the
+    /// account against it. `referred_amount` is the amount of the transaction a dispute, resolve
+    /// or chargeback refers to, if there is one.
+    ///
+    /// Accounts are assumed to start out empty.
+    /// # Panics
+    /// If any invariant doesn't hold, describing what went wrong
+    pub(crate) fn check(
+        &mut self,
+        transaction: &TransactionRecord,
+        referred_amount: Option<Decimal>,
+        account: &Account,
+    ) {
+        let expected = self.expected.entry(transaction.client_id).or_default();
+        let referred = transaction.transaction_id;
+        match (transaction.transaction_type, referred_amount) {
+            (TransactionType::Deposit, _) => {
+                expected.total += transaction.amount.unwrap_or_default()
+            }
+            (TransactionType::Withdrawal, _) => {
+                expected.total -= transaction.amount.unwrap_or_default();
+            }
+            (TransactionType::Dispute, Some(amount)) => {
+                expected.held += amount;
+                expected.disputed.insert(referred);
+            }
+            (TransactionType::Resolve, Some(amount)) => {
+                expected.held -= amount;
+                expected.flagged |= !expected.disputed.remove(&referred);
+            }
+            (TransactionType::Chargeback, Some(amount)) => {
+                expected.held -= amount;
+                expected.total -= amount;
+                expected.flagged |= !expected.disputed.remove(&referred);
+                expected.locked_by.get_or_insert(referred);
+            }
+            // Nothing to refer to, so nothing changes
+            (_, None) => {}
+        }
+        if expected.recent.len() == RECENT_TRANSACTIONS {
+            expected.recent.pop_front();
+        }
+        expected.recent.push_back(*transaction);
+
+        let mut violations = vec![];
+        if account.total() != expected.total {
+            violations.push(format!(
+                "total is {}, but the transactions applied add up to {}",
+                account.total(),
+                expected.total
+            ));
+        }
+        if account.funds_held() != expected.held {
+            violations.push(format!(
+                "held is {}, but the transactions applied add up to {}",
+                account.funds_held(),
+                expected.held
+            ));
+        }
+        if account.funds_held() < Decimal::ZERO && !expected.flagged {
+            violations.push(format!(
+                "held is negative ({}) without an undisputed resolve or chargeback to explain it",
+                account.funds_held()
+            ));
+        }
+        if account.is_locked() && expected.locked_by.is_none() {
+            violations.push("locked, but no chargeback locked it".to_string());
+        }
+        if !violations.is_empty() {
+            panic!("{}", dump(transaction, account, expected, &violations));
+        }
+    }
+}
+
+/// Describes invariant violations in enough detail to debug them from
+fn dump(
+    transaction: &TransactionRecord,
+    account: &Account,
+    expected: &Expected,
+    violations: &[String],
+) -> String {
+    let mut dump = format!(
+        "Invariant violated after applying {:?} {} to account {}:\n",
+        transaction.transaction_type, transaction.transaction_id, transaction.client_id
+    );
+    for violation in violations {
+        let _ = writeln!(dump, "  - {violation}");
+    }
+    let _ = writeln!(dump, "Account: {account:?}");
+    let _ = writeln!(
+        dump,
+        "Expected: total {}, held {}, disputed {:?}, locked by {:?}, flagged {}",
+        expected.total, expected.held, expected.disputed, expected.locked_by, expected.flagged
+    );
+    let _ = writeln!(dump, "Recent transactions, oldest first:");
+    for recent in &expected.recent {
+        let _ = writeln!(dump, "  {recent:?}");
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::types::RawTransactionId;
+
+    use super::*;
+
+    /// Builds a transaction record
+    fn record(
+        transaction_type: TransactionType,
+        tx: RawTransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client_id: ClientId(1),
+            transaction_id: TransactionId(tx),
+            amount,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_invariants_hold() {
+        let mut invariants = Invariants::default();
+        let mut account = Account::new(ClientId(1));
+        account.funds_available = dec!(5);
+        invariants.check(
+            &record(TransactionType::Deposit, 1, Some(dec!(5))),
+            None,
+            &account,
+        );
+        account.funds_available = dec!(0);
+        account.funds_held = dec!(5);
+        invariants.check(
+            &record(TransactionType::Dispute, 1, None),
+            Some(dec!(5)),
+            &account,
+        );
+        account.funds_held = dec!(0);
+        account.locked = true;
+        invariants.check(
+            &record(TransactionType::Chargeback, 1, None),
+            Some(dec!(5)),
+            &account,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "locked, but no chargeback locked it")]
+    fn test_unexplained_lock() {
+        let mut invariants = Invariants::default();
+        let mut account = Account::new(ClientId(1));
+        account.funds_available = dec!(5);
+        account.locked = true;
+        invariants.check(
+            &record(TransactionType::Deposit, 1, Some(dec!(5))),
+            None,
+            &account,
+        );
+    }
+}
@@ -1,14 +1,37 @@
 //! Helpers for reading from transaction logs and outputting reports
 
-use std::io::{Read, Write};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    hash::BuildHasher,
+    io::{Read, Write},
+    num::NonZeroUsize,
+};
+
+/// Reading transaction logs from memory-mapped files, with the `mmap` feature enabled
+#[cfg(feature = "mmap")]
+pub mod mmap;
+/// Reading transaction logs through `io_uring`, on Linux with the `io-uring` feature enabled
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
 
-use csv::Trim;
-use rust_decimal::Decimal;
-use serde::Serialize;
+use csv::{ByteRecord, Trim};
+use hmac::{Hmac, Mac};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
 use crate::{
-    errors::Error,
-    types::{Account, AccountBook, ClientId, Transaction, TransactionLog},
+    diff::AccountBookDiff,
+    errors::{Error, ParseError},
+    events::DomainEvent,
+    filter::Filter,
+    locale::to_scale,
+    source::TransactionSource,
+    types::{
+        Account, AccountBook, ClientId, MemoryAccountBook, MemoryTransactionLog, Transaction,
+        TransactionId, TransactionLog, TransactionRecord, TransactionType, DECIMAL_SCALE,
+    },
 };
 
 /// Loads transactions from a CSV-formatted file stream.
@@ -33,30 +56,318 @@ pub fn load_transactions_from_csv<R, A, T>(
 where
     R: Read,
     A: AccountBook,
-    for<'a> &'a A: IntoIterator<Item = &'a Account>,
     T: TransactionLog,
 {
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .trim(Trim::All)
-        .flexible(true)
-        .from_reader(reader);
-    for record in csv_reader.deserialize() {
-        let transaction: Transaction = record?;
-        account_book.apply(transaction_log, &mut transaction.into())?;
+    read_csv(reader, false, |transaction, _| {
+        account_book.apply(transaction_log, &mut transaction?.into())
+    })
+}
+
+/// Loads transactions from a CSV-formatted file stream, as [`load_transactions_from_csv`] does,
+/// but calls `should_stop` before applying each one and stops once it returns `true`.
+///
+/// This lets a long-running load be abandoned from elsewhere (eg by checking a
+/// [`CancellationToken`](crate::engine::CancellationToken)). Transactions applied before stopping
+/// stay applied, so the account book holds the partial state.
+/// # Errors
+/// [`Error::Cancelled`] if `should_stop` returned `true`, otherwise as for
+/// [`load_transactions_from_csv`]
+pub fn load_transactions_from_csv_until<R, A, T, F>(
+    reader: &mut R,
+    account_book: &mut A,
+    transaction_log: &mut T,
+    mut should_stop: F,
+) -> Result<(), Error>
+where
+    R: Read,
+    A: AccountBook,
+    T: TransactionLog,
+    F: FnMut() -> bool,
+{
+    read_csv(reader, false, |transaction, _| {
+        let transaction = transaction?;
+        if should_stop() {
+            return Err(Error::Cancelled);
+        }
+        account_book.apply(transaction_log, &mut transaction.into())
+    })
+}
+
+/// Returns a CSV reader configuration suitable for reading transactions, which tolerates
+/// whitespace and missing trailing commas
+fn transaction_reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.trim(Trim::All).flexible(true);
+    builder
+}
+
+/// A CSV transaction stream that's read one transaction at a time, on demand, as a
+/// [`TransactionSource`].
+///
+/// Used by [`Engine::apply_chunk`](crate::engine::Engine::apply_chunk) to work through an input
+/// in steps. See [`load_transactions_from_csv`] for the expected format.
+pub struct CsvSource {
+    /// The underlying CSV reader
+    reader: csv::Reader<Box<dyn Read + Send>>,
+    /// The header row, once it's been read
+    headers: Option<ByteRecord>,
+    /// Buffer reused for every row
+    record: ByteRecord,
+}
+
+impl CsvSource {
+    /// Creates a source reading from the supplied stream
+    #[must_use]
+    pub fn new<R>(reader: R) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        Self {
+            reader: transaction_reader_builder().from_reader(Box::new(reader)),
+            headers: None,
+            record: ByteRecord::new(),
+        }
+    }
+}
+
+/// After an error, the source moves on to the following row, so reading can continue
+impl TransactionSource for CsvSource {
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, Error> {
+        if self.headers.is_none() {
+            self.headers = Some(self.reader.byte_headers()?.clone());
+        }
+        if !self.reader.read_byte_record(&mut self.record)? {
+            return Ok(None);
+        }
+        self.record
+            .deserialize(self.headers.as_ref())
+            .map(Some)
+            .map_err(|err| parse_error(&self.record, err))
+    }
+
+    fn line(&self) -> u64 {
+        line_of(&self.record)
+    }
+
+    fn raw_fields(&self) -> Vec<&[u8]> {
+        self.record.iter().collect()
+    }
+}
+
+impl std::fmt::Debug for CsvSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CsvSource")
+            .field("position", self.reader.position())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parses each CSV row into a [`Transaction`] and hands it to `handle`, along with the raw row,
+/// stopping at the first error `handle` returns.
+///
+/// Rows that fail to parse are handed over as errors, so `handle` can decide whether to carry on.
+/// Errors reading the input itself always stop.
+///
+/// If `pooled` is set, every row is read into one reusable [`ByteRecord`] and deserialized
+/// straight from its bytes, rather than going through csv's per-row [`csv::StringRecord`] path.
+pub(crate) fn read_csv<R, F>(reader: R, pooled: bool, mut handle: F) -> Result<(), Error>
+where
+    R: Read,
+    F: FnMut(Result<Transaction, Error>, &ByteRecord) -> Result<(), Error>,
+{
+    read_csv_with(reader, pooled, |transaction, record, _| {
+        handle(transaction, record)
+    })
+}
+
+/// Like [`read_csv`], but also hands over the input's headers, eg to deserialize a row that
+/// didn't parse as a [`Transaction`] into something else
+pub(crate) fn read_csv_with<R, F>(reader: R, pooled: bool, mut handle: F) -> Result<(), Error>
+where
+    R: Read,
+    F: FnMut(Result<Transaction, Error>, &ByteRecord, &ByteRecord) -> Result<(), Error>,
+{
+    let mut csv_reader = transaction_reader_builder().from_reader(reader);
+    if pooled {
+        let headers = csv_reader.byte_headers()?.clone();
+        let mut record = ByteRecord::new();
+        while csv_reader.read_byte_record(&mut record)? {
+            let transaction = record
+                .deserialize(Some(&headers))
+                .map_err(|err| parse_error(&record, err));
+            handle(transaction, &record, &headers)?;
+        }
+    } else {
+        let headers = csv_reader.headers()?.clone();
+        let mut record = csv::StringRecord::new();
+        while csv_reader.read_record(&mut record)? {
+            let transaction = record
+                .deserialize(Some(&headers))
+                .map_err(|err| parse_error(record.as_byte_record(), err));
+            handle(
+                transaction,
+                record.as_byte_record(),
+                headers.as_byte_record(),
+            )?;
+        }
     }
     Ok(())
 }
 
+/// Describes a row that couldn't be deserialized, with where it was in the input
+fn parse_error(record: &ByteRecord, err: csv::Error) -> Error {
+    let (line, byte) = record
+        .position()
+        .map_or((0, 0), |position| (position.line(), position.byte()));
+    let fields: Vec<_> = record.iter().map(String::from_utf8_lossy).collect();
+    // The position is already given separately, so only keep the description of what went wrong
+    let reason = match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        _ => err.to_string(),
+    };
+    ParseError::Row {
+        line,
+        byte,
+        record: fields.join(","),
+        reason,
+    }
+    .into()
+}
+
+/// A row that couldn't be parsed into a [`Transaction`]
+#[derive(Debug)]
+pub(crate) struct UnparsedRow {
+    /// Index of the input it came from
+    pub(crate) input: usize,
+    /// The row as read
+    pub(crate) record: ByteRecord,
+    /// Why it couldn't be parsed
+    pub(crate) error: Error,
+}
+
+/// A transaction parsed from one of several inputs, with where it was read from
+#[derive(Debug)]
+pub(crate) struct ParsedRow {
+    /// Index of the input it came from
+    pub(crate) input: usize,
+    /// Its line within that input
+    pub(crate) line: u64,
+    /// The transaction itself
+    pub(crate) transaction: Transaction,
+}
+
+/// Returns the line a row was read from, or 0 if the reader didn't say
+pub(crate) fn line_of(record: &ByteRecord) -> u64 {
+    record.position().map_or(0, csv::Position::line)
+}
+
+/// The order in which transactions parsed from several inputs are applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeOrder {
+    /// Every transaction from the first input, then every transaction from the second, and so on.
+    /// This gives the same result as loading each input in turn.
+    #[default]
+    InputOrder,
+    /// All transactions sorted by [`TransactionId`]. Transactions
+    /// sharing an ID (eg a deposit and its dispute) keep their relative input order.
+    TransactionId,
+}
+
+/// Loads transactions from several CSV-formatted streams at once.
+///
+/// Each input is parsed on its own thread, then the parsed transactions are merged according to
+/// `order` and applied one at a time, so the result doesn't depend on which thread finishes first.
+///
+/// Nothing is applied unless every input parses successfully. If several inputs fail, the error
+/// from the earliest input is returned.
+pub fn load_transactions_from_csv_files<R, A, T>(
+    readers: Vec<R>,
+    order: MergeOrder,
+    account_book: &mut A,
+    transaction_log: &mut T,
+) -> Result<(), Error>
+where
+    R: Read + Send,
+    A: AccountBook,
+    T: TransactionLog,
+{
+    let (rows, _) = parse_csv_files(readers, order, false, false)?;
+    for row in rows {
+        account_book.apply(transaction_log, &mut row.transaction.into())?;
+    }
+    Ok(())
+}
+
+/// Parses several CSV inputs in parallel, returning their transactions merged according to `order`,
+/// each with where it was read from. See [`read_csv`] for `pooled`.
+///
+/// If `skip_unparsed` is set, rows that can't be parsed are returned separately (in input order)
+/// rather than failing the whole parse.
+pub(crate) fn parse_csv_files<R>(
+    readers: Vec<R>,
+    order: MergeOrder,
+    pooled: bool,
+    skip_unparsed: bool,
+) -> Result<(Vec<ParsedRow>, Vec<UnparsedRow>), Error>
+where
+    R: Read + Send,
+{
+    let parsed = std::thread::scope(|scope| {
+        let handles: Vec<_> = readers
+            .into_iter()
+            .enumerate()
+            .map(|(input, reader)| {
+                scope.spawn(move || {
+                    let mut transactions = vec![];
+                    let mut unparsed = vec![];
+                    read_csv(reader, pooled, |transaction, record| {
+                        match transaction {
+                            Ok(transaction) => transactions.push(ParsedRow {
+                                input,
+                                line: line_of(record),
+                                transaction,
+                            }),
+                            Err(error) if skip_unparsed => unparsed.push(UnparsedRow {
+                                input,
+                                record: record.clone(),
+                                error,
+                            }),
+                            Err(error) => return Err(error),
+                        }
+                        Ok(())
+                    })
+                    .map(|()| (transactions, unparsed))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("CSV parsing thread panicked"))
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+    let (transactions, unparsed): (Vec<_>, Vec<_>) = parsed.into_iter().unzip();
+    let mut transactions: Vec<ParsedRow> = transactions.into_iter().flatten().collect();
+    if order == MergeOrder::TransactionId {
+        transactions.sort_by_key(|row| row.transaction.transaction_id);
+    }
+    Ok((transactions, unparsed.into_iter().flatten().collect()))
+}
+
 /// Type used for serializing an [`Account`], but also including a `total`.
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct AccountWithTotal {
     /// The client's unique identifier
     client: ClientId,
     /// The amount of available funds
+    // Read as strings, since CSV would otherwise guess at a type, losing precision in a float
+    // or failing on integers too large for one
+    #[serde(with = "rust_decimal::serde::str")]
     available: Decimal,
     /// The amount of held funds
+    #[serde(with = "rust_decimal::serde::str")]
     held: Decimal,
     /// The total amount of funds
+    #[serde(with = "rust_decimal::serde::str")]
     total: Decimal,
     /// Whether the account is locked
     locked: bool,
@@ -86,16 +397,662 @@ impl From<&Account> for AccountWithTotal {
 /// ```
 pub fn write_accounts_to_csv<W, A>(writer: &mut W, account_book: &A) -> Result<(), Error>
 where
+    A: AccountBook + ?Sized,
     W: Write,
-    for<'a> &'a A: IntoIterator<Item = &'a Account>,
 {
     let mut csv_writer = csv::Writer::from_writer(writer);
-    for account in account_book {
+    for account in account_book.accounts() {
         csv_writer.serialize(AccountWithTotal::from(account))?;
     }
     Ok(())
 }
 
+/// A column of account output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccountColumn {
+    /// The client's unique identifier
+    Client,
+    /// Available funds
+    Available,
+    /// Held funds
+    Held,
+    /// Total funds
+    Total,
+    /// Whether the account is locked
+    Locked,
+}
+
+impl AccountColumn {
+    /// Every column, in the usual order
+    pub const ALL: [Self; 5] = [
+        Self::Client,
+        Self::Available,
+        Self::Held,
+        Self::Total,
+        Self::Locked,
+    ];
+
+    /// Returns the column's name, as used in headers
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Client => "client",
+            Self::Available => "available",
+            Self::Held => "held",
+            Self::Total => "total",
+            Self::Locked => "locked",
+        }
+    }
+
+    /// Returns the column with the given name, if there is one
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|column| column.name() == name)
+    }
+
+    /// Formats the account's value for this column, rounding amounts as `options` says to
+    fn value(self, account: &Account, options: &ReportOptions) -> String {
+        match self {
+            Self::Client => match &options.pseudonymizer {
+                Some(pseudonymizer) => pseudonymizer.pseudonym(account.client_id()),
+                None => account.client_id().0.to_string(),
+            },
+            Self::Available => options.amount(account.funds_available()).to_string(),
+            Self::Held => options.amount(account.funds_held()).to_string(),
+            Self::Total => options.amount(account.total()).to_string(),
+            Self::Locked => account.is_locked().to_string(),
+        }
+    }
+}
+
+/// How amounts are rounded to a [`ReportOptions::scale`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rounding {
+    /// To the nearest, with halves going to the even neighbour, eg 1.005 to 1.00 and 1.015 to
+    /// 1.02. Also known as banker's rounding.
+    #[default]
+    HalfEven,
+    /// To the nearest, with halves going away from zero, eg 1.005 to 1.01 and -1.005 to -1.01
+    HalfUp,
+    /// Toward zero, eg 1.009 to 1.00 and -1.009 to -1.00
+    TowardZero,
+    /// Away from zero, eg 1.001 to 1.01 and -1.001 to -1.01
+    AwayFromZero,
+    /// Toward negative infinity, eg 1.009 to 1.00 and -1.001 to -1.01
+    Floor,
+    /// Toward positive infinity, eg 1.001 to 1.01 and -1.009 to -1.00
+    Ceiling,
+}
+
+impl Rounding {
+    /// Every rounding mode
+    pub const ALL: [Self; 6] = [
+        Self::HalfEven,
+        Self::HalfUp,
+        Self::TowardZero,
+        Self::AwayFromZero,
+        Self::Floor,
+        Self::Ceiling,
+    ];
+
+    /// Returns the mode's name, eg `half-even`
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::HalfEven => "half-even",
+            Self::HalfUp => "half-up",
+            Self::TowardZero => "toward-zero",
+            Self::AwayFromZero => "away-from-zero",
+            Self::Floor => "floor",
+            Self::Ceiling => "ceiling",
+        }
+    }
+
+    /// Returns the mode with the given name, if there is one
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|rounding| rounding.name() == name)
+    }
+
+    /// Returns the equivalent [`RoundingStrategy`]
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Self::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Self::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Self::TowardZero => RoundingStrategy::ToZero,
+            Self::AwayFromZero => RoundingStrategy::AwayFromZero,
+            Self::Floor => RoundingStrategy::ToNegativeInfinity,
+            Self::Ceiling => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
+}
+
+/// Which accounts, and which of their columns, to output, for consumers that only want part of
+/// a large report.
+///
+/// Filters combine, so an account is only output if it passes all of them. The default outputs
+/// everything.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReportOptions {
+    /// Only output locked accounts
+    pub only_locked: bool,
+    /// Only output accounts with negative available or total funds
+    pub only_negative: bool,
+    /// Only output accounts with at least this much total funds
+    pub min_total: Option<Decimal>,
+    /// Only output these clients' accounts
+    pub clients: Option<HashSet<ClientId>>,
+    /// Only output accounts matching this expression
+    pub filter: Option<Filter>,
+    /// Output only these columns, in this order. Empty for all of them.
+    pub columns: Vec<AccountColumn>,
+    /// Output amounts to exactly this many decimal places, eg 2 for systems that only take
+    /// cents. `None` outputs them as held, to the account book's
+    /// [scale](crate::types::AccountBook::scale).
+    ///
+    /// Each amount is rounded on its own, so a rounded total may differ from the sum of the
+    /// rounded available and held funds.
+    pub scale: Option<u32>,
+    /// How amounts are rounded to [`scale`](Self::scale)
+    pub rounding: Rounding,
+    /// Output a pseudonym in place of each client ID, so the report can be shared without
+    /// exposing real accounts. Filters still select by the real ID.
+    pub pseudonymizer: Option<Pseudonymizer>,
+    /// Round amounts down to a multiple of this, eg 100 to output 0, 100, 200 and so on, so
+    /// exact balances can't be matched up with accounts elsewhere. Applied before
+    /// [`scale`](Self::scale).
+    pub amount_bucket: Option<Decimal>,
+}
+
+/// Replaces client IDs with pseudonyms that are stable for a secret, for sharing reports for
+/// analysis without exposing which account is which.
+///
+/// A pseudonym is the first 24 hex digits of the HMAC-SHA256 of the client ID, keyed with the
+/// secret, so the same client always gets the same pseudonym under the same secret, and reports
+/// made with it can be joined, but without the secret, pseudonyms can't be traced back to IDs,
+/// even by trying every possible one. The secret is never shown, including by `Debug`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Pseudonymizer {
+    /// What pseudonyms are keyed with
+    secret: Vec<u8>,
+}
+
+impl Pseudonymizer {
+    /// Creates a pseudonymizer keyed with the supplied secret
+    #[must_use]
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// Returns the client's pseudonym
+    #[must_use]
+    pub fn pseudonym(&self, client: ClientId) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(client.0.to_string().as_bytes());
+        mac.finalize().into_bytes()[..12]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Pseudonymizer(..)")
+    }
+}
+
+impl ReportOptions {
+    /// Returns whether the account passes every filter
+    #[must_use]
+    pub fn matches(&self, account: &Account) -> bool {
+        (!self.only_locked || account.is_locked())
+            && (!self.only_negative
+                || account.funds_available() < Decimal::ZERO
+                || account.total() < Decimal::ZERO)
+            && self.min_total.is_none_or(|min| account.total() >= min)
+            && self
+                .clients
+                .as_ref()
+                .is_none_or(|clients| clients.contains(&account.client_id()))
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(account))
+    }
+
+    /// Rounds an amount down to its [bucket](Self::amount_bucket), and then to the
+    /// [`scale`](Self::scale) to output, if they're set. An amount too large to bucket, eg with
+    /// a tiny bucket, is left as it is.
+    #[must_use]
+    pub fn amount(&self, amount: Decimal) -> Decimal {
+        let amount = match self.amount_bucket {
+            Some(bucket) if bucket > Decimal::ZERO => amount
+                .checked_div(bucket)
+                .and_then(|buckets| buckets.floor().checked_mul(bucket))
+                .unwrap_or(amount),
+            _ => amount,
+        };
+        match self.scale {
+            Some(scale) => {
+                let mut rounded = amount.round_dp_with_strategy(scale, self.rounding.strategy());
+                rounded.rescale(scale);
+                rounded
+            }
+            None => amount,
+        }
+    }
+
+    /// Returns the columns to output
+    #[must_use]
+    pub fn columns(&self) -> &[AccountColumn] {
+        if self.columns.is_empty() {
+            &AccountColumn::ALL
+        } else {
+            &self.columns
+        }
+    }
+}
+
+/// Outputs the supplied accounts to CSV, as [`write_accounts_to_csv`] does, but only those
+/// passing the filters in `options`, and only the columns it selects.
+///
+/// Any iterator of accounts will do, so this can be combined with other selections, eg:
+/// ```
+/// # use cashflow::{io::{self, AccountColumn, ReportOptions}, types::MemoryAccountBook};
+/// # let account_book = MemoryAccountBook::new();
+/// let options = ReportOptions {
+///     only_locked: true,
+///     columns: vec![AccountColumn::Client, AccountColumn::Total],
+///     ..ReportOptions::default()
+/// };
+/// io::write_accounts_to_csv_with(&mut std::io::stdout(), &account_book, &options).unwrap();
+/// ```
+/// # Errors
+/// If writing fails
+pub fn write_accounts_to_csv_with<'a, W, I>(
+    writer: &mut W,
+    accounts: I,
+    options: &ReportOptions,
+) -> Result<(), Error>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Account>,
+{
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    let columns = options.columns();
+    let mut fields = Vec::with_capacity(columns.len());
+    let mut header_written = false;
+    for account in accounts {
+        if !options.matches(account) {
+            continue;
+        }
+        // Like serialization, only written along with the first row
+        if !header_written {
+            csv_writer.write_record(columns.iter().map(|column| column.name()))?;
+            header_written = true;
+        }
+        fields.clear();
+        fields.extend(columns.iter().map(|column| column.value(account, options)));
+        csv_writer.write_record(&fields)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// Number of accounts each thread formats at a time in [`write_accounts_to_csv_parallel`]
+const ROWS_PER_THREAD: usize = 64 * 1024;
+
+/// Outputs the state of the supplied accounts to CSV, formatting rows on several threads.
+///
+/// The output is identical to [`write_accounts_to_csv`]. Accounts are handed out in batches of a
+/// fixed size per thread, each thread formats its batch into memory, and the results are written
+/// in order, so memory use stays bounded however large the account book is.
+///
+/// This only pays off for very large account books, where formatting dominates; for small ones,
+/// [`write_accounts_to_csv`] is faster.
+pub fn write_accounts_to_csv_parallel<W, A>(
+    writer: &mut W,
+    account_book: &A,
+    threads: NonZeroUsize,
+) -> Result<(), Error>
+where
+    A: AccountBook + ?Sized,
+    W: Write,
+{
+    write_accounts_in_batches(writer, account_book, threads, ROWS_PER_THREAD)
+}
+
+/// Does the work for [`write_accounts_to_csv_parallel`], with a configurable batch size
+fn write_accounts_in_batches<W, A>(
+    writer: &mut W,
+    account_book: &A,
+    threads: NonZeroUsize,
+    rows_per_thread: usize,
+) -> Result<(), Error>
+where
+    A: AccountBook + ?Sized,
+    W: Write,
+{
+    let mut accounts = account_book.accounts();
+    let mut batch: Vec<&Account> = Vec::with_capacity(threads.get() * rows_per_thread);
+    let mut needs_headers = true;
+    loop {
+        batch.clear();
+        batch.extend(accounts.by_ref().take(batch.capacity()));
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let outputs = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .chunks(rows_per_thread)
+                .enumerate()
+                .map(|(index, chunk)| {
+                    let has_headers = needs_headers && index == 0;
+                    scope.spawn(move || format_accounts(chunk, has_headers))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("CSV formatting thread panicked"))
+                .collect::<Result<Vec<_>, Error>>()
+        })?;
+        for output in outputs {
+            writer.write_all(&output).map_err(csv::Error::from)?;
+        }
+        needs_headers = false;
+    }
+}
+
+/// Formats accounts as CSV rows in memory, optionally preceded by the header row
+fn format_accounts(accounts: &[&Account], has_headers: bool) -> Result<Vec<u8>, Error> {
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(has_headers)
+        .from_writer(vec![]);
+    for account in accounts {
+        csv_writer.serialize(AccountWithTotal::from(*account))?;
+    }
+    csv_writer
+        .into_inner()
+        .map_err(|err| err.into_error().into())
+}
+
+impl TryFrom<AccountWithTotal> for Account {
+    type Error = Error;
+
+    fn try_from(account: AccountWithTotal) -> Result<Self, Error> {
+        Account::builder(account.client)
+            .available(account.available)
+            .held(account.held)
+            .locked(account.locked)
+            .build()
+    }
+}
+
+/// Reads account states previously written by [`write_accounts_to_csv`], keyed by client.
+///
+/// The `total` column is ignored, since it's derived from the other two balances.
+/// # Errors
+/// If reading fails, a row can't be parsed, or
+/// [`DomainError::BalanceOutOfRange`](crate::errors::DomainError::BalanceOutOfRange) if an
+/// account's balances add up to more than a [`Decimal`] can hold
+pub fn read_accounts_from_csv<R>(reader: &mut R) -> Result<HashMap<ClientId, Account>, Error>
+where
+    R: Read,
+{
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(reader);
+    let mut accounts = HashMap::new();
+    for record in csv_reader.deserialize() {
+        let account: AccountWithTotal = record?;
+        accounts.insert(account.client, account.try_into()?);
+    }
+    Ok(accounts)
+}
+
+/// Outputs, in the same format as [`write_accounts_to_csv`], only those accounts which differ from
+/// the supplied baseline (usually read back with [`read_accounts_from_csv`]).
+///
+/// An account is written if it's not in the baseline at all, or if its available funds, held
+/// funds or lock state have changed. Its currency isn't compared, since the report doesn't have
+/// it.
+pub fn write_changed_accounts_to_csv<W, A, S>(
+    writer: &mut W,
+    account_book: &A,
+    baseline: &HashMap<ClientId, Account, S>,
+) -> Result<(), Error>
+where
+    A: AccountBook + ?Sized,
+    W: Write,
+    S: BuildHasher,
+{
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for account in account_book.accounts() {
+        let unchanged = baseline.get(&account.client_id()).is_some_and(|before| {
+            before.funds_available() == account.funds_available()
+                && before.funds_held() == account.funds_held()
+                && before.is_locked() == account.is_locked()
+        });
+        if !unchanged {
+            csv_writer.serialize(AccountWithTotal::from(account))?;
+        }
+    }
+    Ok(())
+}
+
+/// How a client's account differs between two reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscrepancyKind {
+    /// The account is only in the first report
+    OnlyInA,
+    /// The account is only in the second report
+    OnlyInB,
+    /// The account's available funds differ
+    Available {
+        /// Available funds in the first report
+        a: Decimal,
+        /// Available funds in the second report
+        b: Decimal,
+    },
+    /// The account's held funds differ
+    Held {
+        /// Held funds in the first report
+        a: Decimal,
+        /// Held funds in the second report
+        b: Decimal,
+    },
+    /// The account is locked in one report but not the other
+    Locked {
+        /// Whether the account is locked in the first report
+        a: bool,
+        /// Whether the account is locked in the second report
+        b: bool,
+    },
+}
+
+/// A difference in one client's account between two reports, from [`diff_reports`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Discrepancy {
+    /// The client whose account differs
+    pub client: ClientId,
+    /// How it differs
+    pub kind: DiscrepancyKind,
+}
+
+impl Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let client = self.client.0;
+        match self.kind {
+            DiscrepancyKind::OnlyInA => write!(f, "client {client}: only in a"),
+            DiscrepancyKind::OnlyInB => write!(f, "client {client}: only in b"),
+            DiscrepancyKind::Available { a, b } => {
+                let (a, b) = (to_scale(a, DECIMAL_SCALE), to_scale(b, DECIMAL_SCALE));
+                write!(f, "client {client}: available {a} -> {b}")
+            }
+            DiscrepancyKind::Held { a, b } => {
+                let (a, b) = (to_scale(a, DECIMAL_SCALE), to_scale(b, DECIMAL_SCALE));
+                write!(f, "client {client}: held {a} -> {b}")
+            }
+            DiscrepancyKind::Locked { a, b } => write!(f, "client {client}: locked {a} -> {b}"),
+        }
+    }
+}
+
+/// Compares two account reports (usually read back with [`read_accounts_from_csv`]), eg to check
+/// an engine upgrade gives the same results as the previous version did.
+///
+/// Returns every difference in available funds, held funds and lock state, ordered by client.
+/// Amounts are compared by value, so `1.5` and `1.5000` are the same. A client with several
+/// differences has a [`Discrepancy`] for each. See [`AccountBookDiff`] for the differences
+/// grouped by account.
+#[must_use]
+pub fn diff_reports<S1, S2>(
+    a: &HashMap<ClientId, Account, S1>,
+    b: &HashMap<ClientId, Account, S2>,
+) -> Vec<Discrepancy>
+where
+    S1: BuildHasher,
+    S2: BuildHasher,
+{
+    AccountBookDiff::new(a.values(), b.values()).discrepancies()
+}
+
+/// Outputs a stream of [`DomainEvent`]s to CSV, flushing after each one, until the stream ends.
+///
+/// This suits the receiving end of an [`mpsc::Sender`](std::sync::mpsc::Sender) registered with
+/// [`Engine::add_listener`](crate::engine::Engine::add_listener), so downstream systems see
+/// events as they happen.
+///
+/// Output data will be in the form:
+/// ```csv
+/// sequence,event,client,tx,amount
+/// 1,funds_deposited,1,1,5.0000
+/// 2,funds_held,1,1,5.0000
+/// ```
+/// # Errors
+/// If writing fails
+pub fn write_events_to_csv<W, I>(writer: &mut W, events: I) -> Result<(), Error>
+where
+    W: Write,
+    I: IntoIterator<Item = DomainEvent>,
+{
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for event in events {
+        csv_writer.serialize(event)?;
+        csv_writer.flush().map_err(csv::Error::from)?;
+    }
+    Ok(())
+}
+
+/// A single line of a client's statement, from [`read_history`]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryRow {
+    /// The transaction's type
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    /// The client whose statement this is
+    pub client: ClientId,
+    /// The transaction's ID, or for disputes, resolves and chargebacks, the ID referred to
+    pub tx: TransactionId,
+    /// The amount moved, if any
+    pub amount: Option<Decimal>,
+    /// Available funds afterwards
+    pub available: Decimal,
+    /// Held funds afterwards
+    pub held: Decimal,
+    /// Total funds afterwards
+    pub total: Decimal,
+    /// Whether the account is locked afterwards
+    pub locked: bool,
+}
+
+/// Reads a statement of one client's transactions, in the order they appear in a CSV-formatted
+/// transaction log, with the account's balances after each one.
+///
+/// The whole log is replayed into scratch in-memory storage, since a client's disputes can refer
+/// to other clients' transactions. The `amount` of a dispute, resolve or chargeback is the amount
+/// of the transaction it refers to, and is `None` if that transaction is missing (in which case
+/// nothing changed). Transactions that were rejected, such as deposits into a locked account, are
+/// left out.
+/// # Errors
+/// If the log can't be read or parsed
+pub fn read_history<R: Read>(log: &mut R, client: ClientId) -> Result<Vec<HistoryRow>, Error> {
+    let mut account_book = MemoryAccountBook::new();
+    let mut transaction_log = MemoryTransactionLog::new();
+    let mut history = vec![];
+    read_csv(log, false, |transaction, _| {
+        let transaction = transaction?;
+        let record = TransactionRecord::from(&transaction);
+        let amount = match record.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => record.amount,
+            _ => transaction_log
+                .transaction(record.transaction_id)?
+                .and_then(|referred| referred.amount),
+        };
+        let applied = account_book
+            .apply(&mut transaction_log, &mut transaction.into())
+            .is_ok();
+        if !applied || record.client_id != client {
+            return Ok(());
+        }
+        let account = account_book.account(client)?;
+        history.push(HistoryRow {
+            transaction_type: record.transaction_type,
+            client,
+            tx: record.transaction_id,
+            amount,
+            available: account.funds_available(),
+            held: account.funds_held(),
+            total: account.total(),
+            locked: account.is_locked(),
+        });
+        Ok(())
+    })?;
+    Ok(history)
+}
+
+/// Outputs a statement of one client's transactions to CSV, as read by [`read_history`].
+///
+/// Output data will be in the form:
+/// ```csv
+/// type,client,tx,amount,available,held,total,locked
+/// deposit,1,1,5.0,5.0000,0.0000,5.0000,false
+/// dispute,1,1,5.0,0.0000,5.0000,5.0000,false
+/// ```
+/// # Errors
+/// If the log can't be read or parsed, or writing fails
+pub fn write_history_csv<W, R>(writer: &mut W, log: &mut R, client: ClientId) -> Result<(), Error>
+where
+    W: Write,
+    R: Read,
+{
+    let history = read_history(log, client)?;
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for row in &history {
+        csv_writer.serialize(row)?;
+    }
+    if history.is_empty() {
+        csv_writer.write_record([
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "available",
+            "held",
+            "total",
+            "locked",
+        ])?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -103,7 +1060,11 @@ mod tests {
     use csv::StringRecord;
     use rust_decimal_macros::dec;
 
-    use crate::types::{MemoryAccountBook, MemoryTransactionLog};
+    use crate::{
+        engine::Engine,
+        errors::DomainError,
+        types::{MemoryAccountBook, MemoryTransactionLog, RawClientId},
+    };
 
     use super::*;
 
@@ -128,6 +1089,505 @@ resolve,        2,    2
         assert_eq!(book.account(2.into()).unwrap().funds_available(), dec!(1));
     }
 
+    #[test]
+    #[cfg(feature = "wide-ids")]
+    fn test_wide_ids() {
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        let input = "type,client,tx,amount
+deposit,70000,5000000000,2.5
+dispute,70000,5000000000,
+";
+        load_transactions_from_csv(&mut Cursor::new(input), &mut book, &mut txnlog).unwrap();
+        let account = book.account(70_000.into()).unwrap();
+        assert_eq!(account.funds_held(), dec!(2.5));
+        let mut output = vec![];
+        write_accounts_to_csv(&mut output, &book).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("70000,0.0000,2.5000,2.5000,false"));
+    }
+
+    #[test]
+    fn test_missing_and_extra_fields() {
+        let cases = [
+            // Deposits and withdrawals need amounts, whether blank or left off entirely
+            ("deposit,1,1,", "MissingAmount"),
+            ("deposit,1,1", "MissingAmount"),
+            ("withdrawal,1,1,", "MissingAmount"),
+            ("withdrawal,1,1", "MissingAmount"),
+            // Referring transactions don't, and any amount they have is ignored
+            ("dispute,1,1", "Ok"),
+            ("resolve,1,1,", "Ok"),
+            ("chargeback,1,1,9.99", "Ok"),
+            // Extra fields are ignored
+            ("deposit,1,1,1.0,extra", "Ok"),
+            ("dispute,1,1,,extra,more", "Ok"),
+            // Everything else is required
+            (",1,1,1.0", "Parse"),
+            ("deposit,,1,1.0", "Parse"),
+            ("deposit,1,,1.0", "Parse"),
+            ("deposit", "Parse"),
+            ("deposit,1", "Parse"),
+            ("refund,1,1,1.0", "Parse"),
+            ("deposit,1,1,lots", "Parse"),
+        ];
+        for (row, expected) in cases {
+            let input = format!("type,client,tx,amount\n{row}\n");
+            let mut book = MemoryAccountBook::new();
+            let mut txnlog = MemoryTransactionLog::new();
+            let result =
+                load_transactions_from_csv(&mut Cursor::new(input), &mut book, &mut txnlog);
+            let outcome = match result {
+                Ok(()) => "Ok",
+                Err(Error::Domain(DomainError::MissingAmount(_))) => "MissingAmount",
+                Err(Error::Parse(ParseError::Row { .. })) => "Parse",
+                Err(err) => panic!("Unexpected error for {row}: {err}"),
+            };
+            assert_eq!(outcome, expected, "{row}");
+        }
+    }
+
+    #[test]
+    fn test_currency_column() {
+        let input = "type,client,tx,amount,currency
+deposit,1,1,5.0,usd
+deposit,2,2,1.0,
+withdrawal,1,3,1.0,EUR
+";
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        let result = load_transactions_from_csv(&mut Cursor::new(input), &mut book, &mut txnlog);
+        assert!(matches!(
+            result,
+            Err(Error::Domain(DomainError::CurrencyMismatch { .. }))
+        ));
+        assert_eq!(book.account(1.into()).unwrap().currency().code(), "USD");
+        assert!(book.account(2.into()).unwrap().currency().is_none());
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit, x ,2,1.0\n";
+        for pooled in [false, true] {
+            let result = read_csv(Cursor::new(input), pooled, |transaction, _| {
+                transaction.map(drop)
+            });
+            match result {
+                Err(Error::Parse(ParseError::Row {
+                    line,
+                    byte,
+                    record,
+                    reason,
+                })) => {
+                    assert_eq!((line, byte), (3, 38));
+                    assert_eq!(record, "deposit,x,2,1.0");
+                    assert!(reason.starts_with("field 1:"), "{reason}");
+                }
+                other => panic!("Unexpected result {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_until_stopped() {
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        let mut cursor = Cursor::new(TEST_INPUT_CSV);
+        let mut rows = 0;
+        let result = load_transactions_from_csv_until(&mut cursor, &mut book, &mut txnlog, || {
+            rows += 1;
+            rows > 3
+        });
+        assert!(matches!(result, Err(Error::Cancelled)));
+        // The first three deposits were applied, but not the withdrawal after them
+        assert_eq!(book.account(1.into()).unwrap().funds_available(), dec!(9));
+        assert_eq!(book.account(2.into()).unwrap().funds_available(), dec!(2));
+    }
+
+    #[test]
+    fn test_read_multiple_files() {
+        let first: &[u8] = b"type,client,tx,amount
+deposit,1,5,10.0
+dispute,1,5,
+chargeback,1,5,
+";
+        let second: &[u8] = b"type,client,tx,amount
+deposit,1,3,1.0
+";
+        // In input order, the chargeback locks the account before the second deposit arrives
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        let readers = vec![Cursor::new(first), Cursor::new(second)];
+        assert!(matches!(
+            load_transactions_from_csv_files(
+                readers,
+                MergeOrder::InputOrder,
+                &mut book,
+                &mut txnlog
+            ),
+            Err(Error::Domain(DomainError::Locked(_)))
+        ));
+
+        // Sorted by ID, the second deposit is applied first
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        let readers = vec![Cursor::new(first), Cursor::new(second)];
+        load_transactions_from_csv_files(
+            readers,
+            MergeOrder::TransactionId,
+            &mut book,
+            &mut txnlog,
+        )
+        .unwrap();
+        let account = book.account(1.into()).unwrap();
+        assert_eq!(account.funds_available(), dec!(1));
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn test_read_multiple_files_applies_nothing_on_error() {
+        let good: &[u8] = b"type,client,tx,amount\ndeposit,1,1,5.0\n";
+        let bad: &[u8] = b"type,client,tx,amount\nteleport,1,2,5.0\n";
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        let readers = vec![Cursor::new(good), Cursor::new(bad)];
+        assert!(load_transactions_from_csv_files(
+            readers,
+            MergeOrder::InputOrder,
+            &mut book,
+            &mut txnlog
+        )
+        .is_err());
+        assert!(book.accounts.is_empty());
+    }
+
+    #[test]
+    fn test_parallel_write_matches_sequential() {
+        let mut book = MemoryAccountBook::new();
+        for client in 0..100u8 {
+            book.account_mut(RawClientId::from(client).into())
+                .unwrap()
+                .funds_available = Decimal::new(i64::from(client) * 3, 1);
+        }
+        let mut sequential = vec![];
+        write_accounts_to_csv(&mut sequential, &book).unwrap();
+        let mut parallel = vec![];
+        let threads = NonZeroUsize::new(3).unwrap();
+        write_accounts_in_batches(&mut parallel, &book, threads, 7).unwrap();
+        assert_eq!(
+            String::from_utf8(parallel).unwrap(),
+            String::from_utf8(sequential).unwrap()
+        );
+
+        let mut empty = vec![];
+        write_accounts_to_csv_parallel(&mut empty, &MemoryAccountBook::new(), threads).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_write_changed_accounts_only() {
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        load_transactions_from_csv(&mut Cursor::new(TEST_INPUT_CSV), &mut book, &mut txnlog)
+            .unwrap();
+        let mut output = vec![];
+        write_accounts_to_csv(&mut output, &book).unwrap();
+        let baseline = read_accounts_from_csv(&mut Cursor::new(&output)).unwrap();
+        assert_eq!(baseline.len(), 2);
+
+        let more: &[u8] = b"type,client,tx,amount\ndeposit,2,7,1.0\ndeposit,3,8,1.0\n";
+        load_transactions_from_csv(&mut Cursor::new(more), &mut book, &mut txnlog).unwrap();
+        let mut output = vec![];
+        write_changed_accounts_to_csv(&mut output, &book, &baseline).unwrap();
+        let changed = read_accounts_from_csv(&mut Cursor::new(&output)).unwrap();
+        let mut changed_clients: Vec<_> = changed.keys().copied().collect();
+        changed_clients.sort();
+        assert_eq!(changed_clients, vec![ClientId::from(2), ClientId::from(3)]);
+        assert_eq!(changed[&ClientId::from(2)].funds_available(), dec!(2));
+
+        // Accounts in a currency are unchanged from a baseline read back without it
+        let mut book = MemoryAccountBook::new();
+        let input: &[u8] = b"type,client,tx,amount,currency\ndeposit,1,1,5.0,USD\n";
+        load_transactions_from_csv(&mut Cursor::new(input), &mut book, &mut txnlog).unwrap();
+        let mut output = vec![];
+        write_accounts_to_csv(&mut output, &book).unwrap();
+        let baseline = read_accounts_from_csv(&mut Cursor::new(&output)).unwrap();
+        let mut output = vec![];
+        write_changed_accounts_to_csv(&mut output, &book, &baseline).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_write_events() {
+        let (sender, receiver) = std::sync::mpsc::channel::<DomainEvent>();
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(sender);
+        engine
+            .load_csv(&mut Cursor::new(
+                "type,client,tx,amount\ndeposit,1,1,5\ndispute,1,1,\n",
+            ))
+            .unwrap();
+        drop(engine);
+        let mut output = vec![];
+        write_events_to_csv(&mut output, receiver).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "sequence,event,client,tx,amount
+1,funds_deposited,1,1,5.0000
+2,funds_held,1,1,5.0000
+"
+        );
+    }
+
+    #[test]
+    fn test_write_filtered_accounts() {
+        let mut account_book = MemoryAccountBook::new();
+        let mut transaction_log = MemoryTransactionLog::new();
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,1.0
+withdrawal,2,3,3.0
+deposit,3,4,2.0
+dispute,3,4,
+chargeback,3,4,
+deposit,4,5,9.0
+";
+        load_transactions_from_csv(
+            &mut Cursor::new(input),
+            &mut account_book,
+            &mut transaction_log,
+        )
+        .unwrap();
+        let mut accounts: Vec<_> = account_book.accounts().cloned().collect();
+        accounts.sort_by_key(Account::client_id);
+        let write = |options: &ReportOptions| {
+            let mut output = vec![];
+            write_accounts_to_csv_with(&mut output, &accounts, options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        // By default, the same as the usual output
+        let mut expected = vec![];
+        write_accounts_to_csv(&mut expected, &account_book).unwrap();
+        let mut output = vec![];
+        write_accounts_to_csv_with(
+            &mut output,
+            account_book.accounts(),
+            &ReportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+
+        let only_locked = ReportOptions {
+            only_locked: true,
+            columns: vec![AccountColumn::Locked, AccountColumn::Client],
+            ..ReportOptions::default()
+        };
+        assert_eq!(write(&only_locked), "locked,client\ntrue,3\n");
+        let only_negative = ReportOptions {
+            only_negative: true,
+            columns: vec![AccountColumn::Client],
+            ..ReportOptions::default()
+        };
+        assert_eq!(write(&only_negative), "client\n2\n");
+        let rich_clients = ReportOptions {
+            min_total: Some(dec!(5)),
+            clients: Some([ClientId(1), ClientId(2)].into()),
+            columns: vec![AccountColumn::Client, AccountColumn::Total],
+            ..ReportOptions::default()
+        };
+        assert_eq!(write(&rich_clients), "client,total\n1,5.0000\n");
+        // Nothing matches, so not even a header
+        let nobody = ReportOptions {
+            clients: Some(HashSet::new()),
+            ..ReportOptions::default()
+        };
+        assert_eq!(write(&nobody), "");
+        let cents = ReportOptions {
+            clients: Some([ClientId(1), ClientId(2)].into()),
+            columns: vec![AccountColumn::Available, AccountColumn::Total],
+            scale: Some(2),
+            ..ReportOptions::default()
+        };
+        assert_eq!(write(&cents), "available,total\n5.00,5.00\n-2.00,-2.00\n");
+    }
+
+    #[test]
+    fn test_rounding() {
+        let round = |amount, rounding| {
+            let options = ReportOptions {
+                scale: Some(2),
+                rounding,
+                ..ReportOptions::default()
+            };
+            options.amount(amount).to_string()
+        };
+        let expected = [
+            (Rounding::HalfEven, ["1.00", "1.02", "-1.00", "1.00"]),
+            (Rounding::HalfUp, ["1.01", "1.02", "-1.01", "1.00"]),
+            (Rounding::TowardZero, ["1.00", "1.01", "-1.00", "1.00"]),
+            (Rounding::AwayFromZero, ["1.01", "1.02", "-1.01", "1.01"]),
+            (Rounding::Floor, ["1.00", "1.01", "-1.01", "1.00"]),
+            (Rounding::Ceiling, ["1.01", "1.02", "-1.00", "1.01"]),
+        ];
+        for (rounding, expected) in expected {
+            let rounded = [dec!(1.005), dec!(1.015), dec!(-1.005), dec!(1.0001)]
+                .map(|amount| round(amount, rounding));
+            assert_eq!(
+                rounded.each_ref().map(String::as_str),
+                expected,
+                "{rounding:?}"
+            );
+            assert_eq!(Rounding::from_name(rounding.name()), Some(rounding));
+        }
+        // Padded out to the scale
+        assert_eq!(round(dec!(3), Rounding::HalfEven), "3.00");
+    }
+
+    #[test]
+    fn test_pseudonymize() {
+        let accounts = [
+            Account::builder(ClientId(1))
+                .available(dec!(1234.5678))
+                .build()
+                .unwrap(),
+            Account::builder(ClientId(2))
+                .available(dec!(-50))
+                .held(dec!(99.99))
+                .build()
+                .unwrap(),
+        ];
+        let pseudonymizer = Pseudonymizer::new("secret");
+        let options = ReportOptions {
+            pseudonymizer: Some(pseudonymizer.clone()),
+            amount_bucket: Some(dec!(100)),
+            scale: Some(2),
+            ..ReportOptions::default()
+        };
+        let mut output = vec![];
+        write_accounts_to_csv_with(&mut output, &accounts, &options).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let (one, two) = (
+            pseudonymizer.pseudonym(ClientId(1)),
+            pseudonymizer.pseudonym(ClientId(2)),
+        );
+        assert_eq!(
+            output,
+            format!(
+                "client,available,held,total,locked
+{one},1200.00,0.00,1200.00,false
+{two},-100.00,0.00,0.00,false
+"
+            )
+        );
+        assert_eq!(one.len(), 24);
+        assert_ne!(one, two);
+        // Stable for the same secret, and different for another
+        assert_eq!(Pseudonymizer::new("secret").pseudonym(ClientId(1)), one);
+        assert_ne!(Pseudonymizer::new("other").pseudonym(ClientId(1)), one);
+        assert_eq!(format!("{pseudonymizer:?}"), "Pseudonymizer(..)");
+
+        // Amounts too large to bucket are left as they are, rather than panicking
+        let options = ReportOptions {
+            amount_bucket: Some(dec!(0.5)),
+            ..ReportOptions::default()
+        };
+        assert_eq!(options.amount(dec!(7.9)), dec!(7.5));
+        assert_eq!(options.amount(Decimal::MAX), Decimal::MAX);
+        assert_eq!(options.amount(Decimal::MIN), Decimal::MIN);
+    }
+
+    #[test]
+    fn test_diff_reports() {
+        let a = "client,available,held,total,locked
+1,5.0,0,5.0,false
+2,1.0000,2.0000,3.0000,false
+3,1,0,1,false
+";
+        let b = "client,available,held,total,locked
+1,5.0000,0.0000,5.0000,false
+2,3.0000,0.0000,3.0000,true
+4,1,0,1,false
+";
+        let a = read_accounts_from_csv(&mut Cursor::new(a)).unwrap();
+        let b = read_accounts_from_csv(&mut Cursor::new(b)).unwrap();
+        let discrepancies = diff_reports(&a, &b);
+        assert_eq!(
+            discrepancies,
+            vec![
+                Discrepancy {
+                    client: ClientId(2),
+                    kind: DiscrepancyKind::Available {
+                        a: dec!(1),
+                        b: dec!(3),
+                    },
+                },
+                Discrepancy {
+                    client: ClientId(2),
+                    kind: DiscrepancyKind::Held {
+                        a: dec!(2),
+                        b: dec!(0),
+                    },
+                },
+                Discrepancy {
+                    client: ClientId(2),
+                    kind: DiscrepancyKind::Locked { a: false, b: true },
+                },
+                Discrepancy {
+                    client: ClientId(3),
+                    kind: DiscrepancyKind::OnlyInA,
+                },
+                Discrepancy {
+                    client: ClientId(4),
+                    kind: DiscrepancyKind::OnlyInB,
+                },
+            ]
+        );
+        assert_eq!(
+            discrepancies[0].to_string(),
+            "client 2: available 1.0000 -> 3.0000"
+        );
+        assert!(diff_reports(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn test_write_history() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.0
+dispute,1,1,
+dispute,1,9,
+resolve,1,1,
+dispute,1,1,
+chargeback,1,1,
+deposit,1,4,2.0
+";
+        let mut output = vec![];
+        write_history_csv(&mut output, &mut Cursor::new(input), ClientId(1)).unwrap();
+        // The deposit into the locked account was rejected, so isn't listed
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount,available,held,total,locked
+deposit,1,1,5.0,5.0000,0.0000,5.0000,false
+withdrawal,1,3,1.0,4.0000,0.0000,4.0000,false
+dispute,1,1,5.0,-1.0000,5.0000,4.0000,false
+dispute,1,9,,-1.0000,5.0000,4.0000,false
+resolve,1,1,5.0,4.0000,0.0000,4.0000,false
+dispute,1,1,5.0,-1.0000,5.0000,4.0000,false
+chargeback,1,1,5.0,-1.0000,0.0000,-1.0000,true
+"
+        );
+
+        let mut output = vec![];
+        write_history_csv(&mut output, &mut Cursor::new(input), ClientId(3)).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount,available,held,total,locked\n"
+        );
+    }
+
     #[test]
     fn test_write_with_whitespace_and_missing_commas() {
         let mut book = MemoryAccountBook::new();
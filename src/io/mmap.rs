@@ -0,0 +1,95 @@
+//! Memory-mapped input files, for parsing large local files without copying them through a
+//! [`BufReader`](std::io::BufReader) first.
+#![allow(unsafe_code)]
+
+use std::{
+    fs::File,
+    io::{self, Cursor, Read},
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+/// A file mapped into memory, readable as a byte slice or through [`Read`].
+///
+/// Parsing reads straight out of the mapping, so there are no read syscalls and no intermediate
+/// buffer between the page cache and the CSV parser.
+///
+/// # Limitations
+/// The file must not be modified (least of all truncated) by anything else while it's mapped.
+/// Doing so is undefined behaviour, and on most platforms truncation crashes the process.
+/// Only use this for local files that are known to be complete.
+#[derive(Debug)]
+pub struct MappedFile {
+    /// The mapping, along with the current read position
+    map: Cursor<Mmap>,
+}
+
+impl MappedFile {
+    /// Opens and maps the file at `path`
+    /// # Errors
+    /// If the file can't be opened or mapped
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(&File::open(path)?)
+    }
+
+    /// Maps an already opened file. The file can be closed afterwards; the mapping stays valid.
+    /// # Errors
+    /// If the file can't be mapped
+    pub fn new(file: &File) -> io::Result<Self> {
+        // SAFETY: see the limitations above; callers are told not to modify mapped files
+        let map = unsafe { Mmap::map(file)? };
+        Ok(Self {
+            map: Cursor::new(map),
+        })
+    }
+
+    /// Returns the whole file's contents
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.map.get_ref()
+    }
+}
+
+impl Read for MappedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.map.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::{Engine, EngineSettings},
+        types::{AccountBook, MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_load_mapped_file() {
+        let path = std::env::temp_dir().join(format!("cashflow-mmap-{}", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,3.5\nwithdrawal,1,2,1.0\n")
+            .unwrap();
+        let mut mapped = MappedFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(mapped.as_bytes().starts_with(b"type,client"));
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            EngineSettings {
+                pooled_records: true,
+                ..EngineSettings::default()
+            },
+        );
+        engine.load_csv(&mut mapped).unwrap();
+        let (mut book, _) = engine.into_parts();
+        assert_eq!(book.account(1.into()).unwrap().funds_available(), dec!(2.5));
+    }
+}
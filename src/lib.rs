@@ -1,11 +1,165 @@
-#![doc = include_str!("../README.md")]
-#![forbid(unsafe_code)]
+#![cfg_attr(feature = "csv", doc = include_str!("../README.md"))]
+#![cfg_attr(
+    not(feature = "csv"),
+    doc = "The core of Cashflow, without the `csv` feature: the [`types`] of accounts and \
+           transactions, and [`AccountBook::apply`](types::AccountBook::apply) to apply them."
+)]
+#![cfg_attr(
+    not(any(
+        feature = "io-uring",
+        feature = "mmap",
+        feature = "ffi",
+        feature = "wasm"
+    )),
+    forbid(unsafe_code)
+)]
+// The io_uring and mmap readers and the C and JavaScript interfaces can't avoid unsafe code, so
+// it's allowed only within those modules
+#![cfg_attr(
+    any(
+        feature = "io-uring",
+        feature = "mmap",
+        feature = "ffi",
+        feature = "wasm"
+    ),
+    deny(unsafe_code)
+)]
 #![warn(missing_docs)]
+/// Flags unusually large deposits and withdrawals for review
+#[cfg(feature = "csv")]
+pub mod anomaly;
+/// NDJSON audit trail of every decision the engine makes
+#[cfg(feature = "csv")]
+pub mod audit;
+/// Roles and API keys deciding what requests to the server may do
+#[cfg(feature = "csv")]
+pub mod auth;
+/// Rebuilding state from archives up to a cutoff, then handing over to the live feed
+#[cfg(feature = "csv")]
+pub mod backfill;
+/// Injectable sources of the current time, so time-dependent behavior can be tested
+pub mod clock;
+/// Flags clients whose deposits or withdrawals over a rolling window may need a regulatory filing
+#[cfg(feature = "csv")]
+pub mod compliance;
+/// Checking an account book against the transaction log it was built from
+#[cfg(feature = "csv")]
+pub mod consistency;
+/// Comparing account books, eg across runs or engine versions
+#[cfg(feature = "csv")]
+pub mod diff;
+/// Entry points taking and returning strings, for embedding without a filesystem, eg in WebAssembly
+#[cfg(feature = "csv")]
+pub mod embed;
+/// High-level engine bundling account and transaction storage with processing settings
+#[cfg(feature = "csv")]
+pub mod engine;
+/// Erasing a client's transactions on request while keeping the books balanced
+#[cfg(feature = "csv")]
+pub mod erasure;
 /// Error handling and custom [`Error`](std::error::Error) types
 pub mod errors;
+/// Callbacks for reacting to transactions as they're applied
+#[cfg(feature = "csv")]
+pub mod events;
+/// A C interface to the engine, for embedding in C and C++ services
+#[cfg(feature = "ffi")]
+pub mod ffi;
+/// Filter expressions picking out accounts, like `locked && total < 0`
+#[cfg(feature = "csv")]
+pub mod filter;
+/// An entry point for fuzzing the CSV path end to end
+#[cfg(feature = "csv")]
+pub mod fuzz;
+/// Custom transaction types, applied by handlers supplied from outside the crate
+#[cfg(feature = "csv")]
+pub mod handler;
+/// Remembering API submissions by idempotency key, so retries aren't applied twice
+#[cfg(feature = "csv")]
+pub mod idempotency;
+/// Checks of transaction inputs for upstream data problems, like duplicate IDs
+#[cfg(feature = "csv")]
+pub mod integrity;
+/// Invariants checked in paranoid mode
+#[cfg(feature = "csv")]
+mod invariants;
 /// Functions for reading and writing transaction logs and account states
+#[cfg(feature = "csv")]
 pub mod io;
+/// Locale-aware formatting of amounts in reports meant for people
+pub mod locale;
+/// Counters and latency histograms for monitoring processing
+#[cfg(feature = "csv")]
+pub mod metrics;
+/// Upgrading state files written by older versions of this crate
+#[cfg(feature = "csv")]
+pub mod migration;
+/// Mock storage backends that record calls and fail on demand, for testing pipelines
+#[cfg(feature = "test-util")]
+pub mod mock;
+/// Alerts about significant events, sent to pluggable notifiers
+#[cfg(feature = "csv")]
+pub mod notify;
 /// Business logic for processing transactions
 mod ops;
+/// Client statements rendered to PDF
+#[cfg(feature = "pdf")]
+pub mod pdf;
+/// Report formats supplied from outside the crate, written by name
+#[cfg(feature = "csv")]
+pub mod plugin;
+/// Previewing what a transaction would do to an account, without applying it
+#[cfg(feature = "csv")]
+pub mod preview;
+/// Per-client limits on how fast transactions are accepted
+#[cfg(feature = "csv")]
+pub mod ratelimit;
+/// Recording runs to a file, and replaying them to reproduce bugs
+#[cfg(feature = "csv")]
+pub mod replay;
+/// Reports on accounts with their disputes and recent activity, for dashboards
+#[cfg(feature = "csv")]
+pub mod report;
+/// Archiving and purging old transactions and audit entries, to keep live storage bounded
+#[cfg(feature = "csv")]
+pub mod retention;
+/// Transfers and conversions spanning several accounts, undone if any step fails
+#[cfg(feature = "csv")]
+pub mod saga;
+/// A small HTTP server exposing an engine's accounts and metrics
+#[cfg(feature = "csv")]
+pub mod server;
+/// An engine handle shared between threads, with clients split between locked shards
+#[cfg(feature = "csv")]
+pub mod shared;
+/// Checking transactions are signed by their client's Ed25519 key
+#[cfg(feature = "signatures")]
+pub mod signature;
+/// Seeded simulations checking storage backends against the built-in ones
+#[cfg(feature = "csv")]
+pub mod simulation;
+/// Sources of transactions in any format, decoupling parsing from applying
+pub mod source;
+/// Versioned files for persisting engine state between runs
+#[cfg(feature = "csv")]
+pub mod state;
+/// Strategies for property testing storage backends against the built-in ones
+#[cfg(feature = "proptest")]
+pub mod strategy;
+/// A consolidated report of suspicious activity, for compliance review
+#[cfg(feature = "csv")]
+pub mod suspicious;
+/// OpenTelemetry traces and metrics, exported over OTLP
+#[cfg(feature = "otel")]
+pub mod telemetry;
+/// Separate accounts and transactions for each of several tenants
+#[cfg(feature = "csv")]
+pub mod tenant;
+/// Moving older transactions to compressed storage, with lookups falling back to it
+#[cfg(feature = "csv")]
+pub mod tiering;
 /// Data types used throughout Cashflow
 pub mod types;
+/// JavaScript bindings for the string entry points, for WebAssembly in a browser
+#[cfg(feature = "wasm")]
+pub mod wasm;
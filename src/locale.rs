@@ -0,0 +1,189 @@
+//! Locale-aware formatting of amounts in reports meant for people.
+//!
+//! A [`NumberFormat`](crate::locale::NumberFormat) picks the decimal separator and digit grouping
+//! for a locale, eg `1.234,5000` for `de-DE`. Reports written as text, like
+//! [`TotalsReport`](crate::report::TotalsReport), implement
+//! [`LocalizedDisplay`](crate::locale::LocalizedDisplay), so
+//! [`NumberFormat::display`](crate::locale::NumberFormat::display) writes them with amounts in
+//! that format; their [`Display`](std::fmt::Display) impls use the canonical format. Machine
+//! formats, like CSV and JSON, are always canonical.
+
+use std::fmt::{self, Display, Formatter};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::types::DECIMAL_SCALE;
+
+/// How amounts are written: which character separates the decimal places, which (if any)
+/// separates groups of three digits, and how many decimal places there are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    /// Separates the whole part from the decimal places
+    pub decimal_separator: char,
+    /// Separates each group of three digits in the whole part, if they're grouped
+    pub grouping_separator: Option<char>,
+    /// Decimal places amounts are written to, which should be the account book's
+    /// [scale](crate::types::AccountBook::scale)
+    pub scale: u32,
+}
+
+impl NumberFormat {
+    /// The format used by machine formats: a `.` and no grouping, eg `1234.5000`
+    pub const CANONICAL: Self = Self {
+        decimal_separator: '.',
+        grouping_separator: None,
+        scale: DECIMAL_SCALE,
+    };
+
+    /// Returns the format for a locale, given as eg `de-DE`, `fr_FR.UTF-8` or just `en`, or
+    /// `None` if the language isn't known.
+    ///
+    /// `C` and `POSIX` give the [canonical](Self::CANONICAL) format. Where a locale groups digits
+    /// with a (non-breaking) space, a plain space is used, so fonts without one still render it.
+    #[must_use]
+    pub fn for_locale(locale: &str) -> Option<Self> {
+        // Drop any encoding or modifier, as in `de_DE.UTF-8@euro`
+        let locale = locale.split(['.', '@']).next().unwrap_or_default();
+        let mut parts = locale.split(['-', '_']);
+        let language = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let region = parts.next().unwrap_or_default().to_ascii_uppercase();
+        let (decimal_separator, grouping_separator) = match (language.as_str(), region.as_str()) {
+            ("c" | "posix", _) => return Some(Self::CANONICAL),
+            ("de" | "fr" | "it", "CH" | "LI") => ('.', '\''),
+            ("en" | "ja" | "zh" | "ko" | "he" | "th" | "ms" | "hi", _) => ('.', ','),
+            ("de" | "nl" | "it" | "es" | "pt" | "da" | "id" | "tr" | "el" | "ro" | "hr", _) => {
+                (',', '.')
+            }
+            (
+                "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "nn" | "no" | "uk" | "hu"
+                | "bg" | "et" | "lv" | "lt",
+                _,
+            ) => (',', ' '),
+            _ => return None,
+        };
+        Some(Self {
+            decimal_separator,
+            grouping_separator: Some(grouping_separator),
+            scale: DECIMAL_SCALE,
+        })
+    }
+
+    /// Writes amounts to `scale` decimal places, rather than [`DECIMAL_SCALE`], to match an
+    /// account book with that [scale](crate::types::AccountBook::scale)
+    #[must_use]
+    pub fn with_scale(self, scale: u32) -> Self {
+        Self { scale, ..self }
+    }
+
+    /// Formats an amount to [`scale`](Self::scale) decimal places
+    #[must_use]
+    pub fn amount(&self, amount: Decimal) -> String {
+        let canonical = to_scale(amount, self.scale);
+        if self.with_scale(DECIMAL_SCALE) == Self::CANONICAL {
+            return canonical;
+        }
+        let (sign, digits) = match canonical.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", canonical.as_str()),
+        };
+        let (whole, decimals) = digits.split_once('.').unwrap_or((digits, ""));
+        let mut formatted = String::with_capacity(canonical.len() + whole.len() / 3);
+        formatted.push_str(sign);
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                formatted.extend(self.grouping_separator);
+            }
+            formatted.push(digit);
+        }
+        if !decimals.is_empty() {
+            formatted.push(self.decimal_separator);
+            formatted.push_str(decimals);
+        }
+        formatted
+    }
+
+    /// Wraps a report so it displays with amounts in this format
+    #[must_use]
+    pub fn display<'a, T>(&'a self, report: &'a T) -> Localized<'a, T>
+    where
+        T: LocalizedDisplay + ?Sized,
+    {
+        Localized {
+            report,
+            format: self,
+        }
+    }
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self::CANONICAL
+    }
+}
+
+/// Text reports that can be written with their amounts formatted for a locale
+pub trait LocalizedDisplay {
+    /// Writes the report as [`Display`] would, but with amounts in the given format
+    /// # Errors
+    /// If the formatter fails
+    fn fmt_localized(&self, f: &mut Formatter<'_>, format: &NumberFormat) -> fmt::Result;
+}
+
+/// A report displayed with amounts formatted for a locale, from [`NumberFormat::display`]
+#[derive(Debug)]
+pub struct Localized<'a, T: ?Sized> {
+    /// The report
+    report: &'a T,
+    /// How to write its amounts
+    format: &'a NumberFormat,
+}
+
+impl<T> Display for Localized<'_, T>
+where
+    T: LocalizedDisplay + ?Sized,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.report.fmt_localized(f, self.format)
+    }
+}
+
+/// Writes an amount rounded to `scale` decimal places, with trailing zeros to make it up to them.
+///
+/// Amounts too large to have that many decimal places are written with as many as they can have,
+/// where formatting with a precision, as in `{:.4}`, would panic.
+pub(crate) fn to_scale(amount: Decimal, scale: u32) -> String {
+    let mut rounded = amount.round_dp_with_strategy(scale, RoundingStrategy::MidpointNearestEven);
+    rounded.rescale(scale);
+    rounded.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_locale_formats() {
+        let amount = dec!(-1234567.5);
+        assert_eq!(NumberFormat::CANONICAL.amount(amount), "-1234567.5000");
+        let format = |locale| NumberFormat::for_locale(locale).unwrap().amount(amount);
+        assert_eq!(format("en-US"), "-1,234,567.5000");
+        assert_eq!(format("de_DE.UTF-8"), "-1.234.567,5000");
+        assert_eq!(format("fr"), "-1 234 567,5000");
+        assert_eq!(format("de-CH"), "-1'234'567.5000");
+        assert_eq!(format("C"), "-1234567.5000");
+        assert_eq!(
+            NumberFormat::for_locale("de").unwrap().amount(dec!(999)),
+            "999,0000"
+        );
+        assert_eq!(NumberFormat::for_locale("xx-YY"), None);
+        let cents = NumberFormat::for_locale("en").unwrap().with_scale(2);
+        assert_eq!(cents.amount(amount), "-1,234,567.50");
+        // Too large for four decimal places, so written with as many as fit
+        assert_eq!(
+            NumberFormat::CANONICAL.amount(dec!(7922816251426433759354395033)),
+            "7922816251426433759354395033.0"
+        );
+    }
+}
@@ -1,19 +1,1243 @@
-use cashflow::io;
-use cashflow::types::{MemoryAccountBook, MemoryTransactionLog};
-use std::{fs::File, io::BufReader};
+use cashflow::anomaly::{AnomalyDetector, AnomalyThresholds};
+use cashflow::audit::{self, AuditLog, DeadLetterLog, RotatingFile, Rotation};
+use cashflow::auth::ApiKeys;
+use cashflow::backfill::Cutoff;
+use cashflow::compliance::{FilingThresholds, ThresholdMonitor};
+use cashflow::diff::AccountBookDiff;
+use cashflow::engine::{Engine, EngineSettings, OutOfOrder};
+use cashflow::errors::SkipAndCollect;
+use cashflow::events::DomainEvent;
+use cashflow::filter::Filter;
+use cashflow::integrity::{IdAnalysis, IdAnalyzer};
+use cashflow::io::{self, AccountColumn, MergeOrder, Pseudonymizer, ReportOptions, Rounding};
+use cashflow::locale::NumberFormat;
+use cashflow::notify::{Alerting, WebhookNotifier};
+use cashflow::plugin::StatsReport;
+use cashflow::ratelimit::{Excess, RateLimit};
+use cashflow::replay::{self, ReplayRecorder};
+use cashflow::report::{
+    self, AccountActivity, AccountLocks, FlowInterval, Flows, FundsSeries, Sampling,
+    SegmentThresholds, Totals,
+};
+use cashflow::server::Server;
+#[cfg(feature = "signatures")]
+use cashflow::signature::SignatureVerifier;
+use cashflow::suspicious::SuspiciousActivity;
+use cashflow::types::{
+    CapacityHint, MemoryAccountBook, MemoryTransactionLog, MinorUnitsTransactionLog, RawClientId,
+    RawTransactionId, TransactionLog, DECIMAL_SCALE,
+};
+use rust_decimal::Decimal;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read},
+    num::NonZeroUsize,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+const USAGE: &str = "Usage: cashflow [OPTIONS] [--baseline=accounts.csv] {transactions.csv} \
+                     [more_transactions.csv ...]
+       cashflow serve {address:port} [--api-keys=api_keys.csv] [--idempotency-window=seconds] [OPTIONS] [transactions.csv ...]
+       cashflow replay {replay.ndjson}
+       cashflow history {client} {transactions.csv}
+       cashflow statement {client} {transactions.csv} {statement.pdf} [locale]
+       cashflow diff {accounts.csv} {other_accounts.csv}
+       cashflow query {expression} {accounts.csv}
+       cashflow check-ids {transactions.csv} [more_transactions.csv ...]
+       cashflow check [--baseline=opening_accounts.csv] {accounts.csv} {transactions.csv} [more_transactions.csv ...]
+       cashflow verify-audit [--from=head] {oldest_audit.ndjson} [newer_audit.ndjson ...]
+Options: [--metrics] [--stats] [--totals] [--top=count] [--dispute-aging] [--segments] [--high-value=amount] [--active-transactions=count] [--locale=en-US] [--quiet] [--check-ids] [--minor-units] [--precision=places] [--validate] [--paranoid] [--lenient] [--dead-letters=rejected.csv] \
+         [--events=events.csv] [--audit=audit.ndjson] [--otel]
+         [--audit-max-bytes=bytes] [--audit-max-age=seconds] [--audit-keep=count] [--audit-compress]
+         [--alert-webhook=http://host/path] [--alert-chargeback-over=amount]
+         [--rate-limit=per_second] [--rate-limit-burst=count] [--rate-limit-wait]
+         [--out-of-order=allow|warn|reject] [--keys=keys.csv]
+         [--anomalies=anomalies.csv] [--anomaly-multiple=10] [--suspicious=suspicious.csv|suspicious.json]
+         [--filings=filings.json] [--filing-threshold=10000] [--filing-window=seconds]
+         [--record=replay.ndjson] [--backfill=archive.csv,... --backfill-until=tx:id|time:seconds]
+         [--json-report=accounts.json] [--locked-report=locked.csv]
+         [--balance-histogram=balances.csv|balances.json] [--balance-bounds=0,100,1000]
+         [--flows=flows.csv] [--flow-interval=hour|day]
+         [--series=series.csv] [--series-every=transactions] [--series-interval=seconds]
+         [--report=name=file ...]
+         [--only-locked] [--only-negative] [--min-total=amount] [--clients=1,2,3] [--columns=client,total]
+         [--filter=expression] [--scale=places] [--rounding=half-even|half-up|toward-zero|away-from-zero|floor|ceiling]
+         [--pseudonymize] [--amount-bucket=amount]";
+
+/// Number of each account's most recent transactions included in the JSON report
+const RECENT_TRANSACTIONS: usize = 10;
+
+/// Number of accounts listed as the largest by `--totals`
+const LARGEST_ACCOUNTS: usize = 10;
+
+/// Options parsed from the command line
+struct Options {
+    /// Print metrics to stderr once done
+    print_metrics: bool,
+    /// Print the engine's running totals across all accounts to stderr once done
+    print_stats: bool,
+    /// Print aggregate totals and the largest accounts to stderr once done
+    print_totals: bool,
+    /// Print this many of the accounts with the most total, held and negative funds to stderr
+    /// once done
+    print_top: Option<usize>,
+    /// Print open disputes bucketed by age to stderr once done
+    print_dispute_aging: bool,
+    /// Print accounts classified into segments to stderr once done
+    print_segments: bool,
+    /// Check the inputs for duplicate and missing transaction IDs before loading them
+    check_ids: bool,
+    /// Where the lines between segments fall
+    segment_thresholds: SegmentThresholds,
+    /// How amounts are formatted in the reports printed to stderr
+    number_format: NumberFormat,
+    /// Don't print a summary of the run to stderr
+    quiet: bool,
+    /// Store the transaction log as integer minor units
+    minor_units: bool,
+    /// Decimal places amounts are kept to
+    precision: u32,
+    /// Reject overdrafts, non-positive amounts and bad references
+    validate: bool,
+    /// Check invariants after every transaction, aborting if any don't hold
+    paranoid: bool,
+    /// Limit each client to this many transactions a second
+    rate_limit: Option<u32>,
+    /// Let each client have this many transactions in a burst
+    rate_limit_burst: Option<u32>,
+    /// Hold up transactions over the rate limit, rather than rejecting them
+    rate_limit_wait: bool,
+    /// What to do with transactions timestamped earlier than one already applied
+    out_of_order: OutOfOrder,
+    /// Reject transactions not signed with their client's key from this file
+    keys_filename: Option<String>,
+    /// Skip bad rows rather than stopping
+    lenient: bool,
+    /// Write skipped and rejected transactions to this file
+    dead_letters_filename: Option<String>,
+    /// Only output accounts that differ from those in this file
+    baseline_filename: Option<String>,
+    /// Write a domain event for every account change to this file
+    events_filename: Option<String>,
+    /// Record every decision made to this file
+    audit_filename: Option<String>,
+    /// When to rotate the audit log
+    audit_rotation: Rotation,
+    /// Compress rotated audit log segments
+    audit_compress: bool,
+    /// Export traces and metrics over OTLP
+    otel: bool,
+    /// Post alerts to this webhook
+    alert_webhook: Option<String>,
+    /// Alert about chargebacks of at least this much
+    alert_chargeback_over: Option<Decimal>,
+    /// Write deposits and withdrawals flagged as unusually large to this file
+    anomalies_filename: Option<String>,
+    /// Where to write the suspicious activity report, if anywhere
+    suspicious_filename: Option<String>,
+    /// Flag amounts more than this many times the account's average
+    anomaly_multiple: Option<Decimal>,
+    /// Where to write clients that may need a regulatory filing, if anywhere
+    filings_filename: Option<String>,
+    /// When deposits or withdrawals within the window may need a filing
+    filing_thresholds: FilingThresholds,
+    /// Record every row handled, and its outcome, to this file
+    record_filename: Option<String>,
+    /// Write accounts, with their open disputes and recent transactions, to this file as JSON
+    json_report_filename: Option<String>,
+    /// Write locked accounts, with the chargebacks that locked them, to this file as CSV
+    locked_report_filename: Option<String>,
+    /// Write accounts bucketed by total funds to this file, as JSON if it ends in `.json`, or CSV
+    balance_histogram_filename: Option<String>,
+    /// Bounds between the buckets of the balance histogram
+    balance_bounds: Vec<Decimal>,
+    /// Write money flow per interval to this file as CSV
+    flows_filename: Option<String>,
+    /// How long each interval of money flow covers
+    flow_interval: FlowInterval,
+    /// Write samples of the funds across all accounts to this file as CSV during the run
+    series_filename: Option<String>,
+    /// When to take samples of the funds across all accounts
+    series_sampling: Sampling,
+    /// Write each named report plugin to its file
+    reports: Vec<(String, String)>,
+    /// Which accounts and columns to output
+    report_options: ReportOptions,
+    /// Serve over HTTP on this address, rather than writing accounts to stdout
+    serve_address: Option<String>,
+    /// Only allow requests to the server with API keys from this file, as their roles allow
+    api_keys_filename: Option<String>,
+    /// Remember the server's idempotency keys for this long
+    idempotency_window: Option<Duration>,
+    /// Archives to rebuild state from before loading the transaction logs
+    backfill_filenames: Vec<String>,
+    /// Where the archives hand over to the transaction logs
+    backfill_cutoff: Option<Cutoff>,
+    /// Transaction logs to load
+    log_filenames: Vec<String>,
+}
+
+impl Options {
+    /// Parses options from the process arguments, panicking with usage information if they're
+    /// invalid
+    fn from_args() -> Self {
+        let mut args: Vec<String> = std::env::args().skip(1).collect();
+        let serve_address = if args.first().is_some_and(|arg| arg == "serve") {
+            assert!(args.len() > 1, "{USAGE}");
+            Some(args.drain(..2).nth(1).unwrap_or_default())
+        } else {
+            None
+        };
+        let (flags, log_filenames): (Vec<String>, Vec<String>) =
+            args.into_iter().partition(|arg| arg.starts_with("--"));
+        let mut options = Self {
+            print_metrics: false,
+            print_stats: false,
+            print_totals: false,
+            print_top: None,
+            print_dispute_aging: false,
+            print_segments: false,
+            check_ids: false,
+            segment_thresholds: SegmentThresholds::default(),
+            number_format: NumberFormat::CANONICAL,
+            quiet: false,
+            minor_units: false,
+            precision: DECIMAL_SCALE,
+            validate: false,
+            paranoid: false,
+            rate_limit: None,
+            rate_limit_burst: None,
+            rate_limit_wait: false,
+            out_of_order: OutOfOrder::Allow,
+            keys_filename: None,
+            lenient: false,
+            dead_letters_filename: None,
+            baseline_filename: None,
+            events_filename: None,
+            audit_filename: None,
+            audit_rotation: Rotation::default(),
+            audit_compress: false,
+            otel: false,
+            alert_webhook: None,
+            alert_chargeback_over: None,
+            anomalies_filename: None,
+            suspicious_filename: None,
+            anomaly_multiple: None,
+            filings_filename: None,
+            filing_thresholds: FilingThresholds::default(),
+            record_filename: None,
+            json_report_filename: None,
+            locked_report_filename: None,
+            balance_histogram_filename: None,
+            balance_bounds: report::DEFAULT_BALANCE_BOUNDS.to_vec(),
+            flows_filename: None,
+            flow_interval: FlowInterval::default(),
+            series_filename: None,
+            series_sampling: Sampling::default(),
+            reports: vec![],
+            report_options: ReportOptions::default(),
+            serve_address,
+            api_keys_filename: None,
+            idempotency_window: None,
+            backfill_filenames: Vec::new(),
+            backfill_cutoff: None,
+            log_filenames,
+        };
+        for flag in flags {
+            match flag.as_str() {
+                "--metrics" => options.print_metrics = true,
+                "--stats" => options.print_stats = true,
+                "--totals" => options.print_totals = true,
+                "--dispute-aging" => options.print_dispute_aging = true,
+                "--segments" => options.print_segments = true,
+                "--check-ids" => options.check_ids = true,
+                "--only-locked" => options.report_options.only_locked = true,
+                "--only-negative" => options.report_options.only_negative = true,
+                "--pseudonymize" => {
+                    // Taken from the environment, so it doesn't show up in process listings
+                    let secret = std::env::var("CASHFLOW_PSEUDONYM_SECRET").unwrap_or_else(|_| {
+                        panic!("--pseudonymize needs a secret in CASHFLOW_PSEUDONYM_SECRET")
+                    });
+                    options.report_options.pseudonymizer = Some(Pseudonymizer::new(secret));
+                }
+                "--quiet" => options.quiet = true,
+                "--minor-units" => options.minor_units = true,
+                "--validate" => options.validate = true,
+                "--paranoid" => options.paranoid = true,
+                "--rate-limit-wait" => options.rate_limit_wait = true,
+                "--lenient" => options.lenient = true,
+                "--otel" => options.otel = true,
+                "--audit-compress" => options.audit_compress = true,
+                _ => {
+                    if let Some(filename) = flag.strip_prefix("--baseline=") {
+                        options.baseline_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--events=") {
+                        options.events_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--audit=") {
+                        options.audit_filename = Some(filename.to_string());
+                    } else if let Some(bytes) = flag.strip_prefix("--audit-max-bytes=") {
+                        let bytes = bytes
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid size {bytes}: {err}"));
+                        options.audit_rotation.max_bytes = Some(bytes);
+                    } else if let Some(seconds) = flag.strip_prefix("--audit-max-age=") {
+                        let seconds = seconds
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid age {seconds}: {err}"));
+                        options.audit_rotation.max_age = Some(Duration::from_secs(seconds));
+                    } else if let Some(count) = flag.strip_prefix("--audit-keep=") {
+                        options.audit_rotation.keep = count
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid count {count}: {err}"));
+                    } else if let Some(seconds) = flag.strip_prefix("--idempotency-window=") {
+                        let seconds = seconds
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid window {seconds}: {err}"));
+                        options.idempotency_window = Some(Duration::from_secs(seconds));
+                    } else if let Some(filename) = flag.strip_prefix("--api-keys=") {
+                        options.api_keys_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--keys=") {
+                        options.keys_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--dead-letters=") {
+                        options.dead_letters_filename = Some(filename.to_string());
+                    } else if let Some(url) = flag.strip_prefix("--alert-webhook=") {
+                        options.alert_webhook = Some(url.to_string());
+                    } else if let Some(amount) = flag.strip_prefix("--alert-chargeback-over=") {
+                        let amount = amount
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid amount {amount}: {err}"));
+                        options.alert_chargeback_over = Some(amount);
+                    } else if let Some(rate) = flag.strip_prefix("--rate-limit=") {
+                        let rate = rate
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid rate {rate}: {err}"));
+                        options.rate_limit = Some(rate);
+                    } else if let Some(burst) = flag.strip_prefix("--rate-limit-burst=") {
+                        let burst = burst
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid burst {burst}: {err}"));
+                        options.rate_limit_burst = Some(burst);
+                    } else if let Some(policy) = flag.strip_prefix("--out-of-order=") {
+                        options.out_of_order = match policy {
+                            "allow" => OutOfOrder::Allow,
+                            "warn" => OutOfOrder::Warn,
+                            "reject" => OutOfOrder::Reject,
+                            _ => panic!("Unknown out of order policy {policy}"),
+                        };
+                    } else if let Some(count) = flag.strip_prefix("--top=") {
+                        let count = count
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid count {count}: {err}"));
+                        options.print_top = Some(count);
+                    } else if let Some(filename) = flag.strip_prefix("--record=") {
+                        options.record_filename = Some(filename.to_string());
+                    } else if let Some(amount) = flag.strip_prefix("--min-total=") {
+                        let amount = amount
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid amount {amount}: {err}"));
+                        options.report_options.min_total = Some(amount);
+                    } else if let Some(filenames) = flag.strip_prefix("--backfill=") {
+                        options.backfill_filenames =
+                            filenames.split(',').map(str::to_string).collect();
+                    } else if let Some(cutoff) = flag.strip_prefix("--backfill-until=") {
+                        options.backfill_cutoff = Some(parse_cutoff(cutoff));
+                    } else if let Some(clients) = flag.strip_prefix("--clients=") {
+                        let clients = clients
+                            .split(',')
+                            .map(|client| {
+                                client
+                                    .parse::<RawClientId>()
+                                    .map(Into::into)
+                                    .unwrap_or_else(|err| panic!("Invalid client {client}: {err}"))
+                            })
+                            .collect();
+                        options.report_options.clients = Some(clients);
+                    } else if let Some(columns) = flag.strip_prefix("--columns=") {
+                        options.report_options.columns = columns
+                            .split(',')
+                            .map(|column| {
+                                AccountColumn::from_name(column)
+                                    .unwrap_or_else(|| panic!("Unknown column {column}"))
+                            })
+                            .collect();
+                    } else if let Some(precision) = flag.strip_prefix("--precision=") {
+                        options.precision = precision
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid precision {precision}: {err}"));
+                    } else if let Some(scale) = flag.strip_prefix("--scale=") {
+                        let scale = scale
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid scale {scale}: {err}"));
+                        options.report_options.scale = Some(scale);
+                    } else if let Some(bucket) = flag.strip_prefix("--amount-bucket=") {
+                        let bucket = bucket
+                            .parse()
+                            .ok()
+                            .filter(|bucket| *bucket > Decimal::ZERO)
+                            .unwrap_or_else(|| panic!("Invalid bucket {bucket}"));
+                        options.report_options.amount_bucket = Some(bucket);
+                    } else if let Some(rounding) = flag.strip_prefix("--rounding=") {
+                        options.report_options.rounding = Rounding::from_name(rounding)
+                            .unwrap_or_else(|| panic!("Unknown rounding {rounding}"));
+                    } else if let Some(expression) = flag.strip_prefix("--filter=") {
+                        options.report_options.filter = Some(parse_filter(expression));
+                    } else if let Some(filename) = flag.strip_prefix("--json-report=") {
+                        options.json_report_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--locked-report=") {
+                        options.locked_report_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--balance-histogram=") {
+                        options.balance_histogram_filename = Some(filename.to_string());
+                    } else if let Some(bounds) = flag.strip_prefix("--balance-bounds=") {
+                        options.balance_bounds = bounds
+                            .split(',')
+                            .map(|bound| {
+                                bound
+                                    .parse()
+                                    .unwrap_or_else(|err| panic!("Invalid bound {bound}: {err}"))
+                            })
+                            .collect();
+                    } else if let Some(filename) = flag.strip_prefix("--flows=") {
+                        options.flows_filename = Some(filename.to_string());
+                    } else if let Some(interval) = flag.strip_prefix("--flow-interval=") {
+                        options.flow_interval = match interval {
+                            "hour" => FlowInterval::Hour,
+                            "day" => FlowInterval::Day,
+                            _ => panic!("Unknown flow interval {interval}"),
+                        };
+                    } else if let Some(filename) = flag.strip_prefix("--series=") {
+                        options.series_filename = Some(filename.to_string());
+                    } else if let Some(count) = flag.strip_prefix("--series-every=") {
+                        options.series_sampling.every_transactions = count
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid count {count}: {err}"));
+                    } else if let Some(seconds) = flag.strip_prefix("--series-interval=") {
+                        let seconds = seconds
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid interval {seconds}: {err}"));
+                        options.series_sampling.every = Some(Duration::from_secs(seconds));
+                    } else if let Some(amount) = flag.strip_prefix("--high-value=") {
+                        options.segment_thresholds.high_value = amount
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid amount {amount}: {err}"));
+                    } else if let Some(count) = flag.strip_prefix("--active-transactions=") {
+                        options.segment_thresholds.active_transactions = count
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid count {count}: {err}"));
+                    } else if let Some(locale) = flag.strip_prefix("--locale=") {
+                        options.number_format = parse_locale(locale);
+                    } else if let Some(report) = flag.strip_prefix("--report=") {
+                        let (name, filename) = report
+                            .split_once('=')
+                            .unwrap_or_else(|| panic!("Expected --report=name=file, got {flag}"));
+                        options
+                            .reports
+                            .push((name.to_string(), filename.to_string()));
+                    } else if let Some(filename) = flag.strip_prefix("--anomalies=") {
+                        options.anomalies_filename = Some(filename.to_string());
+                    } else if let Some(filename) = flag.strip_prefix("--suspicious=") {
+                        options.suspicious_filename = Some(filename.to_string());
+                    } else if let Some(multiple) = flag.strip_prefix("--anomaly-multiple=") {
+                        let multiple = multiple
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid multiple {multiple}: {err}"));
+                        options.anomaly_multiple = Some(multiple);
+                    } else if let Some(filename) = flag.strip_prefix("--filings=") {
+                        options.filings_filename = Some(filename.to_string());
+                    } else if let Some(amount) = flag.strip_prefix("--filing-threshold=") {
+                        let amount: Decimal = amount
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid amount {amount}: {err}"));
+                        options.filing_thresholds.deposits = Some(amount);
+                        options.filing_thresholds.withdrawals = Some(amount);
+                    } else if let Some(seconds) = flag.strip_prefix("--filing-window=") {
+                        let seconds = seconds
+                            .parse()
+                            .unwrap_or_else(|err| panic!("Invalid window {seconds}: {err}"));
+                        options.filing_thresholds.window = Duration::from_secs(seconds);
+                    } else {
+                        panic!("Unknown option {flag}\n{USAGE}");
+                    }
+                }
+            }
+        }
+        assert!(
+            !options.log_filenames.is_empty() || options.serve_address.is_some(),
+            "{USAGE}"
+        );
+        // The JSON report nests transactions, and would give away the clients' IDs
+        assert!(
+            options.report_options.pseudonymizer.is_none()
+                || options.json_report_filename.is_none(),
+            "--pseudonymize can't be combined with --json-report"
+        );
+        options.number_format = options.number_format.with_scale(options.precision);
+        options
+    }
+}
 
 fn main() {
-    let log_filename = std::env::args()
-        .nth(1)
-        .expect("Usage: cashflow {transactions.csv}");
-    let log_file = File::open(&log_filename)
-        .unwrap_or_else(|err| panic!("Couldn't open transaction log at {log_filename}: {err}"));
-    let mut log_reader = BufReader::new(log_file);
-    let mut account_book = MemoryAccountBook::new();
-    let mut transaction_log = MemoryTransactionLog::new();
-    io::load_transactions_from_csv(&mut log_reader, &mut account_book, &mut transaction_log)
-        .unwrap_or_else(|err| panic!("Failed to load transactions from CSV file: {err}"));
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [command, replay_filename] if command == "replay" => {
+            replay_file(replay_filename);
+            return;
+        }
+        [command, client, log_filename] if command == "history" => {
+            write_history(client, log_filename);
+            return;
+        }
+        [command, client, log_filename, pdf_filename, locale @ ..]
+            if command == "statement" && locale.len() <= 1 =>
+        {
+            let format = locale
+                .first()
+                .map_or(NumberFormat::CANONICAL, |locale| parse_locale(locale));
+            write_statement(client, log_filename, pdf_filename, &format);
+            return;
+        }
+        [command, a_filename, b_filename] if command == "diff" => {
+            diff_reports(a_filename, b_filename);
+            return;
+        }
+        [command, expression, accounts_filename] if command == "query" => {
+            query_accounts(expression, accounts_filename);
+            return;
+        }
+        [command, from, audit_filenames @ ..]
+            if command == "verify-audit"
+                && from.starts_with("--from=")
+                && !audit_filenames.is_empty() =>
+        {
+            verify_audit(from.strip_prefix("--from="), audit_filenames);
+            return;
+        }
+        [command, audit_filenames @ ..]
+            if command == "verify-audit" && !audit_filenames.is_empty() =>
+        {
+            verify_audit(None, audit_filenames);
+            return;
+        }
+        [command, baseline, accounts_filename, log_filenames @ ..]
+            if command == "check"
+                && baseline.starts_with("--baseline=")
+                && !log_filenames.is_empty() =>
+        {
+            let baseline_filename = baseline.strip_prefix("--baseline=");
+            check_consistency(baseline_filename, accounts_filename, log_filenames);
+            return;
+        }
+        [command, accounts_filename, log_filenames @ ..]
+            if command == "check" && !log_filenames.is_empty() =>
+        {
+            check_consistency(None, accounts_filename, log_filenames);
+            return;
+        }
+        [command, log_filenames @ ..] if command == "check-ids" && !log_filenames.is_empty() => {
+            let analysis = analyze_ids(log_filenames);
+            print!("{analysis}");
+            if !analysis.is_clean() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+    let options = Options::from_args();
+    // Installed before the engine is created, so the engine reports to it
+    #[cfg(feature = "otel")]
+    let exporter = options.otel.then(|| {
+        cashflow::telemetry::OtlpExporter::install()
+            .unwrap_or_else(|err| panic!("Couldn't set up OTLP export: {err}"))
+    });
+    #[cfg(not(feature = "otel"))]
+    assert!(
+        !options.otel,
+        "--otel needs cashflow built with the otel feature"
+    );
+    if options.minor_units {
+        run(
+            &options,
+            MinorUnitsTransactionLog::new().with_scale(options.precision),
+        );
+    } else {
+        run(&options, MemoryTransactionLog::new());
+    }
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = exporter {
+        exporter
+            .shutdown()
+            .unwrap_or_else(|err| panic!("Failed to export telemetry: {err}"));
+    }
+}
+
+/// Loads all transaction logs using the supplied storage, then either serves the engine over HTTP
+/// or writes the resulting accounts to stdout
+fn run<T: TransactionLog>(options: &Options, transaction_log: T) {
+    if options.check_ids {
+        let analysis = analyze_ids(&options.log_filenames);
+        assert!(
+            analysis.duplicates.is_empty(),
+            "Refusing to load transactions with duplicate IDs:\n{analysis}"
+        );
+        if !analysis.gaps.is_empty() {
+            eprint!("Transaction IDs have gaps, so rows may be missing:\n{analysis}");
+        }
+    }
+    let log_files: Vec<_> = options
+        .log_filenames
+        .iter()
+        .map(|log_filename| {
+            File::open(log_filename).unwrap_or_else(|err| {
+                panic!("Couldn't open transaction log at {log_filename}: {err}")
+            })
+        })
+        .collect();
+    // Pre-sizing storage is only an optimization, so files without metadata just don't count
+    let input_size = log_files
+        .iter()
+        .filter_map(|log_file| log_file.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let mut log_readers: Vec<_> = log_files.into_iter().map(reader_for).collect();
+    let settings = EngineSettings {
+        pooled_records: true,
+        record_latency: options.print_metrics,
+        validate_transactions: options.validate,
+        paranoid: options.paranoid,
+        rate_limit: options.rate_limit.map(|rate| RateLimit {
+            burst: options.rate_limit_burst.unwrap_or(rate),
+            on_excess: if options.rate_limit_wait {
+                Excess::Wait
+            } else {
+                Excess::Reject
+            },
+            ..RateLimit::per_second(rate)
+        }),
+        out_of_order: options.out_of_order,
+        keep_history: false,
+        idempotency_window: options.idempotency_window,
+    };
+    let account_book = MemoryAccountBook::new().with_scale(options.precision);
+    let mut engine = Engine::with_settings(account_book, transaction_log, settings);
+    engine.reserve(CapacityHint::from_input_size(input_size));
+    #[cfg(feature = "signatures")]
+    if let Some(keys_filename) = options.keys_filename.as_deref() {
+        let keys_file = File::open(keys_filename)
+            .unwrap_or_else(|err| panic!("Couldn't open keys at {keys_filename}: {err}"));
+        let verifier = SignatureVerifier::read_keys(BufReader::new(keys_file))
+            .unwrap_or_else(|err| panic!("Couldn't read keys from {keys_filename}: {err}"));
+        engine.set_verifier(verifier);
+    }
+    #[cfg(not(feature = "signatures"))]
+    assert!(
+        options.keys_filename.is_none(),
+        "--keys needs cashflow built with the signatures feature"
+    );
+    let skipped = options.lenient.then(|| {
+        let policy = SkipAndCollect::new();
+        engine.set_error_policy(policy.clone());
+        policy
+    });
+    let event_writer = options.events_filename.as_deref().map(|events_filename| {
+        let (sender, receiver) = mpsc::channel();
+        engine.add_listener(sender);
+        spawn_event_writer(events_filename, receiver)
+    });
+    #[cfg(feature = "zstd")]
+    let audit_rotation = Rotation {
+        compress: options.audit_compress,
+        ..options.audit_rotation
+    };
+    #[cfg(not(feature = "zstd"))]
+    let audit_rotation = {
+        assert!(
+            !options.audit_compress,
+            "--audit-compress needs cashflow built with the zstd feature"
+        );
+        options.audit_rotation
+    };
+    let audit_log = options.audit_filename.as_deref().map(|audit_filename| {
+        let audit_file = RotatingFile::create(audit_filename, audit_rotation)
+            .unwrap_or_else(|err| panic!("Couldn't create audit log at {audit_filename}: {err}"));
+        let mut audit_log = AuditLog::new(audit_file);
+        if let Ok(secret) = std::env::var("CASHFLOW_AUDIT_SECRET") {
+            audit_log = audit_log.with_secret(secret);
+        }
+        engine.add_listener(audit_log.clone());
+        audit_log
+    });
+    let dead_letters = options
+        .dead_letters_filename
+        .as_deref()
+        .map(|dead_letters_filename| {
+            let dead_letters_file = File::create(dead_letters_filename).unwrap_or_else(|err| {
+                panic!("Couldn't create dead-letter file at {dead_letters_filename}: {err}")
+            });
+            let dead_letters = DeadLetterLog::new(BufWriter::new(dead_letters_file));
+            engine.add_listener(dead_letters.clone());
+            dead_letters
+        });
+    let alerting = options.alert_webhook.as_deref().map(|url| {
+        let mut notifier = WebhookNotifier::new(url)
+            .unwrap_or_else(|err| panic!("Couldn't use alert webhook {url}: {err}"));
+        // Taken from the environment, so it doesn't show up in process listings
+        if let Ok(secret) = std::env::var("CASHFLOW_WEBHOOK_SECRET") {
+            notifier = notifier.with_secret(secret);
+        }
+        let mut alerting = Alerting::new(notifier);
+        if let Some(amount) = options.alert_chargeback_over {
+            alerting = alerting.with_large_chargeback(amount);
+        }
+        engine.add_listener(alerting.clone());
+        alerting
+    });
+    let recorder = options.record_filename.as_deref().map(|record_filename| {
+        let record_file = File::create(record_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create replay file at {record_filename}: {err}")
+        });
+        let recorder = ReplayRecorder::new(BufWriter::new(record_file), engine.settings());
+        engine.add_listener(recorder.clone());
+        recorder
+    });
+    let mut anomaly_thresholds = AnomalyThresholds::default();
+    if let Some(multiple) = options.anomaly_multiple {
+        anomaly_thresholds.multiple = multiple;
+    }
+    let anomalies = options.anomalies_filename.as_ref().map(|_| {
+        let detector = AnomalyDetector::new(anomaly_thresholds);
+        engine.add_listener(detector.clone());
+        detector
+    });
+    let suspicious = options.suspicious_filename.as_ref().map(|_| {
+        let mut suspicious = SuspiciousActivity::new(anomaly_thresholds);
+        if let Some(amount) = options.alert_chargeback_over {
+            suspicious = suspicious.with_large_chargeback(amount);
+        }
+        engine.add_listener(suspicious.clone());
+        suspicious
+    });
+    let filings = options.filings_filename.as_ref().map(|_| {
+        let monitor = ThresholdMonitor::new(options.filing_thresholds);
+        engine.add_listener(monitor.clone());
+        monitor
+    });
+    let tracks_activity = options.json_report_filename.is_some()
+        || options.print_dispute_aging
+        || options.print_segments;
+    let activity = tracks_activity.then(|| {
+        let activity = AccountActivity::new(RECENT_TRANSACTIONS);
+        engine.add_listener(activity.clone());
+        activity
+    });
+    let locks = options.locked_report_filename.as_ref().map(|_| {
+        let locks = AccountLocks::new();
+        engine.add_listener(locks.clone());
+        locks
+    });
+    let flows = options.flows_filename.as_ref().map(|_| {
+        let flows = Flows::new(options.flow_interval);
+        engine.add_listener(flows.clone());
+        flows
+    });
+    let series = options.series_filename.as_deref().map(|series_filename| {
+        let series_file = File::create(series_filename)
+            .unwrap_or_else(|err| panic!("Couldn't create series at {series_filename}: {err}"));
+        let series = FundsSeries::new(BufWriter::new(series_file), options.series_sampling);
+        engine.add_listener(series.clone());
+        series
+    });
+    add_reports(&mut engine);
+    for (name, _) in &options.reports {
+        let names = engine.report_names();
+        assert!(
+            names.contains(&name.as_str()),
+            "Unknown report {name}; expected one of {}",
+            names.join(", ")
+        );
+    }
+    let totals = options.print_totals.then(|| {
+        let totals = Totals::new();
+        engine.add_listener(totals.clone());
+        totals
+    });
+    if !options.backfill_filenames.is_empty() {
+        let cutoff = options
+            .backfill_cutoff
+            .unwrap_or_else(|| panic!("--backfill needs --backfill-until"));
+        let archives = options.backfill_filenames.iter().map(|filename| {
+            File::open(filename)
+                .map(BufReader::new)
+                .unwrap_or_else(|err| panic!("Couldn't open archive {filename}: {err}"))
+        });
+        let backfill = engine
+            .backfill(archives, cutoff)
+            .unwrap_or_else(|err| panic!("Failed to backfill from archives: {err}"));
+        if !options.quiet {
+            eprintln!(
+                "Backfilled {} transactions, leaving {} for the live feed",
+                backfill.applied, backfill.skipped
+            );
+        }
+        if !backfill.reached_cutoff {
+            eprintln!("Warning: the archives stop short of the cutoff");
+        }
+    }
+    match log_readers.len() {
+        0 => Ok(()),
+        1 => engine.load_named_csv(&options.log_filenames[0], &mut log_readers[0]),
+        _ => {
+            let inputs = options
+                .log_filenames
+                .iter()
+                .cloned()
+                .zip(log_readers)
+                .collect();
+            engine.load_named_csv_files(inputs, MergeOrder::InputOrder)
+        }
+    }
+    .unwrap_or_else(|err| panic!("Failed to load transactions from CSV file: {err}"));
+    if let Some(audit_log) = &audit_log {
+        audit_log
+            .finish()
+            .unwrap_or_else(|err| panic!("Failed to write audit log: {err}"));
+        eprintln!("Audit log head: {}", audit_log.head());
+    }
+    if let Some(series) = &series {
+        series
+            .finish()
+            .unwrap_or_else(|err| panic!("Failed to write series: {err}"));
+    }
+    if let Some(recorder) = &recorder {
+        recorder
+            .finish()
+            .unwrap_or_else(|err| panic!("Failed to write replay file: {err}"));
+    }
+    if let Some(skipped) = skipped.map(|policy| policy.skipped()) {
+        if !skipped.is_empty() {
+            eprintln!("Skipped {} rows that couldn't be loaded", skipped.len());
+        }
+    }
+    if let Some(dead_letters) = &dead_letters {
+        dead_letters
+            .finish()
+            .unwrap_or_else(|err| panic!("Failed to write dead letters: {err}"));
+    }
+    if let (Some(detector), Some(anomalies_filename)) = (&anomalies, &options.anomalies_filename) {
+        let anomalies_file = File::create(anomalies_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create anomalies report at {anomalies_filename}: {err}")
+        });
+        detector
+            .write_report(&mut BufWriter::new(anomalies_file))
+            .unwrap_or_else(|err| panic!("Failed to write anomalies report: {err}"));
+    }
+    if let (Some(suspicious), Some(suspicious_filename)) =
+        (&suspicious, &options.suspicious_filename)
+    {
+        let suspicious_file = File::create(suspicious_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create suspicious activity report at {suspicious_filename}: {err}")
+        });
+        let mut writer = BufWriter::new(suspicious_file);
+        if suspicious_filename.ends_with(".json") {
+            suspicious.write_json(&mut writer)
+        } else {
+            suspicious.write_csv(&mut writer)
+        }
+        .unwrap_or_else(|err| panic!("Failed to write suspicious activity report: {err}"));
+    }
+    if let (Some(monitor), Some(filings_filename)) = (&filings, &options.filings_filename) {
+        let filings_file = File::create(filings_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create filings report at {filings_filename}: {err}")
+        });
+        monitor
+            .write_json(&mut BufWriter::new(filings_file))
+            .unwrap_or_else(|err| panic!("Failed to write filings report: {err}"));
+    }
+    if let (Some(activity), Some(json_report_filename)) = (&activity, &options.json_report_filename)
+    {
+        let json_report_file = File::create(json_report_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create JSON report at {json_report_filename}: {err}")
+        });
+        report::write_accounts_json(
+            &mut BufWriter::new(json_report_file),
+            engine.account_book(),
+            activity,
+            &options.report_options,
+        )
+        .unwrap_or_else(|err| panic!("Failed to write JSON report: {err}"));
+    }
+    if let (Some(locks), Some(locked_report_filename)) = (&locks, &options.locked_report_filename) {
+        let locked_report_file = File::create(locked_report_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create locked accounts report at {locked_report_filename}: {err}")
+        });
+        let locked = report::locked_accounts(
+            engine.account_book(),
+            engine.transaction_log(),
+            locks,
+            SystemTime::now(),
+        )
+        .unwrap_or_else(|err| panic!("Failed to look up locked accounts: {err}"));
+        report::write_locked_accounts_csv(&mut BufWriter::new(locked_report_file), &locked)
+            .unwrap_or_else(|err| panic!("Failed to write locked accounts report: {err}"));
+    }
+    if let Some(balance_histogram_filename) = &options.balance_histogram_filename {
+        let balance_histogram_file =
+            File::create(balance_histogram_filename).unwrap_or_else(|err| {
+                panic!("Couldn't create balance histogram at {balance_histogram_filename}: {err}")
+            });
+        let mut writer = BufWriter::new(balance_histogram_file);
+        let distribution =
+            report::balance_distribution(engine.account_book(), &options.balance_bounds);
+        if balance_histogram_filename.ends_with(".json") {
+            report::write_balance_distribution_json(&mut writer, &distribution)
+        } else {
+            report::write_balance_distribution_csv(&mut writer, &distribution)
+        }
+        .unwrap_or_else(|err| panic!("Failed to write balance histogram: {err}"));
+    }
+    for (name, filename) in &options.reports {
+        let file = File::create(filename)
+            .unwrap_or_else(|err| panic!("Couldn't create {name} report at {filename}: {err}"));
+        engine
+            .write_report(name, &mut BufWriter::new(file))
+            .unwrap_or_else(|err| panic!("Failed to write {name} report: {err}"));
+    }
+    if let (Some(flows), Some(flows_filename)) = (&flows, &options.flows_filename) {
+        let flows_file = File::create(flows_filename).unwrap_or_else(|err| {
+            panic!("Couldn't create flows report at {flows_filename}: {err}")
+        });
+        report::write_flows_csv(&mut BufWriter::new(flows_file), &flows.buckets())
+            .unwrap_or_else(|err| panic!("Failed to write flows report: {err}"));
+    }
+    // Undelivered alerts shouldn't stop the accounts being written
+    if let Some(Err(err)) = alerting.as_ref().map(Alerting::finish) {
+        eprintln!("Failed to send some alerts: {err}");
+    }
+    if !options.quiet && !options.log_filenames.is_empty() {
+        eprint!("{}", options.number_format.display(&engine.summary()));
+    }
+    if options.print_stats {
+        eprint!("{}", options.number_format.display(&engine.stats()));
+    }
+    if let Some(totals) = &totals {
+        let report = totals.report(engine.account_book(), LARGEST_ACCOUNTS);
+        eprint!("{}", options.number_format.display(&report));
+    }
+    if let Some(activity) = activity.as_ref().filter(|_| options.print_dispute_aging) {
+        let aging = activity.dispute_aging(SystemTime::now());
+        eprint!("{}", options.number_format.display(&aging));
+    }
+    if let Some(activity) = activity.as_ref().filter(|_| options.print_segments) {
+        let segmentation =
+            report::segment_accounts(engine.account_book(), activity, &options.segment_thresholds);
+        eprint!("{}", options.number_format.display(&segmentation));
+    }
+    if let Some(count) = options.print_top {
+        let top = report::top_accounts(engine.account_book(), count);
+        eprint!("{}", options.number_format.display(&top));
+    }
+    if let Some(address) = &options.serve_address {
+        let mut server = Server::bind(address, engine)
+            .unwrap_or_else(|err| panic!("Couldn't listen on {address}: {err}"));
+        if let Some(api_keys_filename) = &options.api_keys_filename {
+            let api_keys_file = File::open(api_keys_filename).unwrap_or_else(|err| {
+                panic!("Couldn't open API keys at {api_keys_filename}: {err}")
+            });
+            let api_keys =
+                ApiKeys::read_keys(BufReader::new(api_keys_file)).unwrap_or_else(|err| {
+                    panic!("Couldn't read API keys from {api_keys_filename}: {err}")
+                });
+            server.set_authorizer(api_keys);
+        }
+        eprintln!("Serving on {address}");
+        server
+            .run()
+            .unwrap_or_else(|err| panic!("Server stopped: {err}"));
+        return;
+    }
+    if options.print_metrics {
+        eprint!("{}", engine.metrics());
+    }
     let mut stdout = std::io::stdout().lock();
-    io::write_accounts_to_csv(&mut stdout, &account_book)
+    match &options.baseline_filename {
+        Some(baseline_filename) => {
+            let baseline_file = File::open(baseline_filename).unwrap_or_else(|err| {
+                panic!("Couldn't open baseline accounts at {baseline_filename}: {err}")
+            });
+            let baseline = io::read_accounts_from_csv(&mut BufReader::new(baseline_file))
+                .unwrap_or_else(|err| panic!("Failed to read baseline accounts: {err}"));
+            let changed = engine
+                .account_book()
+                .into_iter()
+                .filter(|account| baseline.get(&account.client_id()) != Some(*account));
+            io::write_accounts_to_csv_with(&mut stdout, changed, &options.report_options)
+        }
+        None if options.report_options != ReportOptions::default() => {
+            io::write_accounts_to_csv_with(
+                &mut stdout,
+                engine.account_book(),
+                &options.report_options,
+            )
+        }
+        None => {
+            let threads = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+            io::write_accounts_to_csv_parallel(&mut stdout, engine.account_book(), threads)
+        }
+    }
+    .unwrap_or_else(|err| panic!("Failed to write accounts to CSV: {err}"));
+    // Dropping the engine drops its end of the event channel, letting the writer finish
+    drop(engine);
+    if let Some(event_writer) = event_writer {
+        event_writer
+            .join()
+            .expect("Event writer panicked")
+            .unwrap_or_else(|err| panic!("Failed to write events to CSV: {err}"));
+    }
+}
+
+/// Registers the report plugins `--report` can write. Plugins from other crates are added here.
+fn add_reports<T: TransactionLog>(engine: &mut Engine<MemoryAccountBook, T>) {
+    engine.add_report(StatsReport);
+}
+
+/// Replays a recording, reporting whether it went the same way, then writes the resulting accounts
+/// to stdout. Exits with an error if the replay differed from the recording.
+fn replay_file(replay_filename: &str) {
+    let replay_file = File::open(replay_filename)
+        .unwrap_or_else(|err| panic!("Couldn't open replay file at {replay_filename}: {err}"));
+    let result = replay::replay(
+        BufReader::new(replay_file),
+        MemoryAccountBook::new(),
+        MemoryTransactionLog::new(),
+    )
+    .unwrap_or_else(|err| panic!("Failed to replay {replay_filename}: {err}"));
+    let mut stdout = std::io::stdout().lock();
+    io::write_accounts_to_csv(&mut stdout, result.engine.account_book())
         .unwrap_or_else(|err| panic!("Failed to write accounts to CSV: {err}"));
+    match result.divergence {
+        None => eprintln!("Replayed {} rows, all as recorded", result.rows),
+        Some(divergence) => {
+            eprintln!(
+                "Replay differed from the recording at row {}:\n  recorded: {:?}\n  replayed: {:?}",
+                divergence.row, divergence.recorded, divergence.replayed
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Compares two account reports, writing every difference to stdout. Exits with an error if there
+/// are any.
+fn diff_reports(a_filename: &str, b_filename: &str) {
+    let read = |filename: &str| {
+        let file = File::open(filename)
+            .unwrap_or_else(|err| panic!("Couldn't open accounts at {filename}: {err}"));
+        io::read_accounts_from_csv(&mut BufReader::new(file))
+            .unwrap_or_else(|err| panic!("Failed to read accounts from {filename}: {err}"))
+    };
+    let (a, b) = (read(a_filename), read(b_filename));
+    let diff = AccountBookDiff::new(a.values(), b.values());
+    for discrepancy in diff.discrepancies() {
+        println!("{discrepancy}");
+    }
+    if !diff.is_empty() {
+        eprintln!("{diff}");
+        std::process::exit(1);
+    }
+}
+
+/// Parses a `--backfill-until` cutoff, like `tx:1000` or `time:1700000000`
+fn parse_cutoff(cutoff: &str) -> Cutoff {
+    if let Some(transaction) = cutoff.strip_prefix("tx:") {
+        let transaction = transaction
+            .parse::<RawTransactionId>()
+            .unwrap_or_else(|err| panic!("Invalid transaction {transaction}: {err}"));
+        Cutoff::Transaction(transaction.into())
+    } else if let Some(timestamp) = cutoff.strip_prefix("time:") {
+        let timestamp = timestamp
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid timestamp {timestamp}: {err}"));
+        Cutoff::Timestamp(timestamp)
+    } else {
+        panic!("Unknown cutoff {cutoff}; expected tx:<id> or time:<seconds>")
+    }
+}
+
+/// Checks the hash chain running through the named audit log segments, oldest first, from the
+/// head of the segments before them if they don't start the chain, printing the digest of the
+/// last entry, and exiting with an error if it's broken
+fn verify_audit(head: Option<&str>, audit_filenames: &[String]) {
+    let reader = audit_filenames
+        .iter()
+        .map(|filename| -> Box<dyn Read> {
+            Box::new(
+                File::open(filename)
+                    .unwrap_or_else(|err| panic!("Couldn't open audit log at {filename}: {err}")),
+            )
+        })
+        .reduce(|chained, next| Box::new(chained.chain(next)))
+        .unwrap_or_else(|| Box::new(std::io::empty()));
+    let secret = std::env::var("CASHFLOW_AUDIT_SECRET").ok();
+    let reader = BufReader::new(reader);
+    let secret = secret.as_deref().map(str::as_bytes);
+    let result = match head {
+        Some(head) => audit::verify_chain_from(reader, secret, head),
+        None => audit::verify_chain(reader, secret),
+    };
+    match result {
+        Ok(head) => println!("{head}"),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Checks the accounts in the named file against the transactions the named logs register, when
+/// applied in order on top of the baseline accounts, if any, printing what doesn't match, and
+/// exiting with an error if anything drifted
+fn check_consistency(
+    baseline_filename: Option<&str>,
+    accounts_filename: &str,
+    log_filenames: &[String],
+) {
+    let accounts_file = File::open(accounts_filename)
+        .unwrap_or_else(|err| panic!("Couldn't open accounts at {accounts_filename}: {err}"));
+    let accounts = io::read_accounts_from_csv(&mut BufReader::new(accounts_file))
+        .unwrap_or_else(|err| panic!("Failed to read accounts from {accounts_filename}: {err}"));
+    // Applied by the engine's rules, so the log only registers what would have been applied
+    let mut engine = Engine::in_memory();
+    engine.set_error_policy(SkipAndCollect::new());
+    for log_filename in log_filenames {
+        let log_file = File::open(log_filename)
+            .unwrap_or_else(|err| panic!("Couldn't open transaction log at {log_filename}: {err}"));
+        engine
+            .load_named_csv(log_filename, &mut reader_for(log_file))
+            .unwrap_or_else(|err| panic!("Failed to read {log_filename}: {err}"));
+    }
+    let mut check = engine.consistency_check();
+    if let Some(baseline_filename) = baseline_filename {
+        let baseline_file = File::open(baseline_filename).unwrap_or_else(|err| {
+            panic!("Couldn't open baseline accounts at {baseline_filename}: {err}")
+        });
+        let baseline = io::read_accounts_from_csv(&mut BufReader::new(baseline_file))
+            .unwrap_or_else(|err| panic!("Failed to read baseline accounts: {err}"));
+        check = check.with_opening(baseline.values());
+    }
+    let report = check.check(accounts.values(), engine.transaction_log().transactions());
+    print!("{report}");
+    if !report.is_consistent() {
+        std::process::exit(1);
+    }
+}
+
+/// Writes the accounts in the named file matching a filter expression to stdout, in client order
+fn query_accounts(expression: &str, accounts_filename: &str) {
+    let filter = parse_filter(expression);
+    let accounts_file = File::open(accounts_filename)
+        .unwrap_or_else(|err| panic!("Couldn't open accounts at {accounts_filename}: {err}"));
+    let accounts = io::read_accounts_from_csv(&mut BufReader::new(accounts_file))
+        .unwrap_or_else(|err| panic!("Failed to read accounts from {accounts_filename}: {err}"));
+    let mut accounts: Vec<_> = accounts.values().collect();
+    accounts.sort_unstable_by_key(|account| account.client_id());
+    let options = ReportOptions {
+        filter: Some(filter),
+        ..ReportOptions::default()
+    };
+    let mut stdout = std::io::stdout().lock();
+    io::write_accounts_to_csv_with(&mut stdout, accounts, &options)
+        .unwrap_or_else(|err| panic!("Failed to write accounts: {err}"));
+}
+
+/// Parses a filter expression, panicking with the reason if it's invalid
+fn parse_filter(expression: &str) -> Filter {
+    expression
+        .parse()
+        .unwrap_or_else(|err| panic!("Invalid filter {expression:?}: {err}"))
+}
+
+/// Reads the named transaction logs for an analysis of their transaction IDs, without applying them
+fn analyze_ids<S: AsRef<str>>(log_filenames: &[S]) -> IdAnalysis {
+    let mut analyzer = IdAnalyzer::new();
+    for log_filename in log_filenames {
+        let log_filename = log_filename.as_ref();
+        let log_file = File::open(log_filename)
+            .unwrap_or_else(|err| panic!("Couldn't open transaction log at {log_filename}: {err}"));
+        analyzer
+            .read_csv(log_filename, reader_for(log_file))
+            .unwrap_or_else(|err| panic!("Failed to read {log_filename}: {err}"));
+    }
+    analyzer.finish()
+}
+
+/// Writes a statement of one client's transactions in the named file to stdout
+fn write_history(client: &str, log_filename: &str) {
+    let client: RawClientId = client
+        .parse()
+        .unwrap_or_else(|err| panic!("Invalid client {client}: {err}"));
+    let log_file = File::open(log_filename)
+        .unwrap_or_else(|err| panic!("Couldn't open transaction log at {log_filename}: {err}"));
+    let mut stdout = std::io::stdout().lock();
+    io::write_history_csv(&mut stdout, &mut BufReader::new(log_file), client.into())
+        .unwrap_or_else(|err| panic!("Failed to write history: {err}"));
+}
+
+/// Parses a locale for formatting amounts, panicking if it isn't known
+fn parse_locale(locale: &str) -> NumberFormat {
+    NumberFormat::for_locale(locale).unwrap_or_else(|| panic!("Unknown locale {locale}"))
+}
+
+/// Writes a PDF statement of one client's transactions in the named file
+#[cfg(feature = "pdf")]
+fn write_statement(client: &str, log_filename: &str, pdf_filename: &str, format: &NumberFormat) {
+    let client: RawClientId = client
+        .parse()
+        .unwrap_or_else(|err| panic!("Invalid client {client}: {err}"));
+    let log_file = File::open(log_filename)
+        .unwrap_or_else(|err| panic!("Couldn't open transaction log at {log_filename}: {err}"));
+    let history = io::read_history(&mut BufReader::new(log_file), client.into())
+        .unwrap_or_else(|err| panic!("Failed to read history: {err}"));
+    let pdf_file = File::create(pdf_filename)
+        .unwrap_or_else(|err| panic!("Couldn't create statement at {pdf_filename}: {err}"));
+    let mut writer = BufWriter::new(pdf_file);
+    cashflow::pdf::write_statement_pdf(&mut writer, client.into(), &history, format)
+        .unwrap_or_else(|err| panic!("Failed to write statement: {err}"));
+}
+
+/// Stands in for writing PDF statements, which need the `pdf` feature
+#[cfg(not(feature = "pdf"))]
+fn write_statement(
+    _client: &str,
+    _log_filename: &str,
+    _pdf_filename: &str,
+    _format: &NumberFormat,
+) {
+    panic!("statement needs cashflow built with the pdf feature");
+}
+
+/// Starts a thread writing every event received to the named file, until the channel closes
+fn spawn_event_writer(
+    events_filename: &str,
+    receiver: mpsc::Receiver<DomainEvent>,
+) -> JoinHandle<Result<(), cashflow::errors::Error>> {
+    let events_file = File::create(events_filename)
+        .unwrap_or_else(|err| panic!("Couldn't create event log at {events_filename}: {err}"));
+    thread::spawn(move || io::write_events_to_csv(&mut BufWriter::new(events_file), receiver))
+}
+
+/// Wraps an input file for reading, preferring a memory map, then `io_uring`, where they're
+/// enabled and available
+fn reader_for(log_file: File) -> Box<dyn Read + Send> {
+    #[cfg(feature = "mmap")]
+    match io::mmap::MappedFile::new(&log_file) {
+        Ok(mapped) => return Box::new(mapped),
+        Err(err) => eprintln!("Couldn't map input file, falling back to reading it: {err}"),
+    }
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    match log_file.try_clone().and_then(io::uring::UringReader::new) {
+        Ok(reader) => return Box::new(reader),
+        Err(err) => eprintln!("io_uring unavailable, falling back to buffered reads: {err}"),
+    }
+    Box::new(BufReader::new(log_file))
 }
@@ -0,0 +1,721 @@
+//! Counters and latency histograms describing what an [`Engine`](crate::engine::Engine) has
+//! processed
+
+use std::{
+    fmt::Display,
+    io::Write,
+    ops::AddAssign,
+    time::{Duration, SystemTime},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    clock::Clock,
+    locale::{LocalizedDisplay, NumberFormat},
+    types::{Account, ClientId, TransactionId, TransactionType},
+};
+
+/// Counts of transactions, broken down by [`TransactionType`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TransactionCounts {
+    /// Number of [`TransactionType::Deposit`]s
+    pub deposits: u64,
+    /// Number of [`TransactionType::Withdrawal`]s
+    pub withdrawals: u64,
+    /// Number of [`TransactionType::Dispute`]s
+    pub disputes: u64,
+    /// Number of [`TransactionType::Resolve`]s
+    pub resolves: u64,
+    /// Number of [`TransactionType::Chargeback`]s
+    pub chargebacks: u64,
+}
+
+impl TransactionCounts {
+    /// Adds one to the count for the given type
+    pub(crate) fn increment(&mut self, transaction_type: &TransactionType) {
+        match transaction_type {
+            TransactionType::Deposit => self.deposits += 1,
+            TransactionType::Withdrawal => self.withdrawals += 1,
+            TransactionType::Dispute => self.disputes += 1,
+            TransactionType::Resolve => self.resolves += 1,
+            TransactionType::Chargeback => self.chargebacks += 1,
+        }
+    }
+
+    /// Returns the count across all types
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.deposits + self.withdrawals + self.disputes + self.resolves + self.chargebacks
+    }
+}
+
+impl AddAssign for TransactionCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+    }
+}
+
+impl Display for TransactionCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (deposit {}, withdrawal {}, dispute {}, resolve {}, chargeback {})",
+            self.total(),
+            self.deposits,
+            self.withdrawals,
+            self.disputes,
+            self.resolves,
+            self.chargebacks
+        )
+    }
+}
+
+/// Funds moved by transactions, broken down by [`TransactionType`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TransactionAmounts {
+    /// Funds deposited
+    pub deposits: Decimal,
+    /// Funds withdrawn
+    pub withdrawals: Decimal,
+    /// Funds moved into held by disputes
+    pub disputes: Decimal,
+    /// Funds released from held by resolves
+    pub resolves: Decimal,
+    /// Funds taken from held by chargebacks
+    pub chargebacks: Decimal,
+}
+
+/// Writes the amounts as a single `amounts:` line
+impl LocalizedDisplay for TransactionAmounts {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        writeln!(
+            f,
+            "amounts: deposit {}, withdrawal {}, dispute {}, resolve {}, chargeback {}",
+            format.amount(self.deposits),
+            format.amount(self.withdrawals),
+            format.amount(self.disputes),
+            format.amount(self.resolves),
+            format.amount(self.chargebacks)
+        )
+    }
+}
+
+/// Running totals across every account, kept up to date by an [`Engine`](crate::engine::Engine)
+/// as it applies transactions, from [`Engine::stats`](crate::engine::Engine::stats).
+///
+/// Only transactions applied by the engine are counted, so accounts are assumed to start out
+/// empty.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EngineStats {
+    /// Transactions applied, by type. Includes those that changed nothing.
+    pub applied: TransactionCounts,
+    /// Funds moved, by type
+    pub amounts: TransactionAmounts,
+    /// Available funds across all accounts
+    pub available: Decimal,
+    /// Held funds across all accounts
+    pub held: Decimal,
+    /// Number of locked accounts
+    pub locked: u64,
+}
+
+impl EngineStats {
+    /// Returns the total funds across all accounts
+    #[must_use]
+    pub fn total(&self) -> Decimal {
+        self.available.saturating_add(self.held)
+    }
+
+    /// Adds the funds an applied transaction moved, rounded to the account book's `scale`: its own
+    /// amount for a deposit or withdrawal, or the amount of the transaction it refers to otherwise.
+    ///
+    /// Sums across accounts can outgrow any one account, so they saturate rather than overflow.
+    pub(crate) fn record_funds(
+        &mut self,
+        transaction_type: TransactionType,
+        mut amount: Decimal,
+        scale: u32,
+    ) {
+        amount.rescale(scale);
+        let amounts = &mut self.amounts;
+        let (sum, available, held) = match transaction_type {
+            TransactionType::Deposit => (&mut amounts.deposits, amount, Decimal::ZERO),
+            TransactionType::Withdrawal => (&mut amounts.withdrawals, -amount, Decimal::ZERO),
+            TransactionType::Dispute => (&mut amounts.disputes, -amount, amount),
+            TransactionType::Resolve => (&mut amounts.resolves, amount, -amount),
+            TransactionType::Chargeback => (&mut amounts.chargebacks, Decimal::ZERO, -amount),
+        };
+        *sum = sum.saturating_add(amount);
+        self.available = self.available.saturating_add(available);
+        self.held = self.held.saturating_add(held);
+    }
+}
+
+impl Display for EngineStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_localized(f, &NumberFormat::CANONICAL)
+    }
+}
+
+impl LocalizedDisplay for EngineStats {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        writeln!(f, "applied: {}", self.applied)?;
+        self.amounts.fmt_localized(f, format)?;
+        writeln!(f, "available: {}", format.amount(self.available))?;
+        writeln!(f, "held: {}", format.amount(self.held))?;
+        writeln!(f, "total: {}", format.amount(self.total()))?;
+        writeln!(f, "locked accounts: {}", self.locked)
+    }
+}
+
+/// Percentiles of a recorded latency distribution.
+///
+/// Percentiles are approximate: each is the upper bound of the power-of-two bucket it falls in,
+/// so may overstate the true value by up to 2x. The maximum is exact.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// Number of durations recorded
+    pub count: u64,
+    /// Median duration
+    pub p50: Duration,
+    /// 90th percentile duration
+    pub p90: Duration,
+    /// 99th percentile duration
+    pub p99: Duration,
+    /// Longest duration
+    pub max: Duration,
+    /// Sum of all durations
+    pub sum: Duration,
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {:?}, p90 {:?}, p99 {:?}, max {:?} ({} samples)",
+            self.p50, self.p90, self.p99, self.max, self.count
+        )
+    }
+}
+
+/// A distribution of durations, bucketed by powers of two nanoseconds
+#[derive(Debug, Clone)]
+pub(crate) struct LatencyHistogram {
+    /// Bucket `i` counts durations of `2^i` up to `2^(i+1) - 1` nanoseconds
+    /// (with zero counted in bucket 0)
+    buckets: [u64; 64],
+    /// Total number of durations recorded
+    count: u64,
+    /// Longest duration recorded
+    max: Duration,
+    /// Sum of all durations recorded
+    sum: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; 64],
+            count: 0,
+            max: Duration::ZERO,
+            sum: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Adds a duration to the distribution
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = 63 - nanos.max(1).leading_zeros() as usize;
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+        self.sum += duration;
+    }
+
+    /// Returns the (approximate) duration below which `percentile` percent of durations fall
+    fn percentile(&self, percentile: u64) -> Duration {
+        // The rank of the sample we're looking for, rounding up, so p50 of 3 samples is the 2nd
+        let rank = (self.count * percentile).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper_bound = Duration::from_nanos(u64::MAX >> (63 - bucket));
+                return upper_bound.min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Summarizes the distribution, or returns `None` if nothing has been recorded
+    pub(crate) fn summary(&self) -> Option<LatencySummary> {
+        (self.count > 0).then(|| LatencySummary {
+            count: self.count,
+            p50: self.percentile(50),
+            p90: self.percentile(90),
+            p99: self.percentile(99),
+            max: self.max,
+            sum: self.sum,
+        })
+    }
+}
+
+/// A point-in-time copy of everything an [`Engine`](crate::engine::Engine) has recorded
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of rows successfully parsed into transactions
+    pub rows_parsed: u64,
+    /// Number of transactions successfully applied, by type
+    pub applied: TransactionCounts,
+    /// Number of transactions that failed to apply, by type
+    pub rejected: TransactionCounts,
+    /// Number of rows that failed to parse or apply
+    pub errors: u64,
+    /// Number of transactions applied with a [`Warning`](crate::errors::Warning), eg disputes of
+    /// unknown transactions
+    pub warnings: u64,
+    /// When transactions were last loaded, if they ever have been. Always `None` where there's no
+    /// clock, as on `wasm32-unknown-unknown`.
+    pub last_ingest: Option<SystemTime>,
+    /// The most recent transaction applied successfully, if any have been
+    pub last_applied: Option<TransactionId>,
+    /// Time taken to apply each transaction. Only recorded if
+    /// [`EngineSettings::record_latency`](crate::engine::EngineSettings::record_latency) is set.
+    pub apply_latency: Option<LatencySummary>,
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "rows parsed: {}", self.rows_parsed)?;
+        writeln!(f, "applied: {}", self.applied)?;
+        writeln!(f, "rejected: {}", self.rejected)?;
+        writeln!(f, "errors: {}", self.errors)?;
+        writeln!(f, "warnings: {}", self.warnings)?;
+        match &self.apply_latency {
+            Some(latency) => writeln!(f, "apply latency: {latency}"),
+            None => writeln!(f, "apply latency: not recorded"),
+        }
+    }
+}
+
+/// What an [`Engine`](crate::engine::Engine) has done with everything it's loaded, for reporting
+/// once a run is over
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Number of rows read, whether or not they could be parsed
+    pub rows_read: u64,
+    /// Number of transactions applied, by type. Includes those ignored.
+    pub applied: TransactionCounts,
+    /// Number of transactions applied without effect, eg disputes of unknown transactions
+    pub ignored: u64,
+    /// Number of transactions that failed to apply, by type
+    pub rejected: TransactionCounts,
+    /// Number of rows that couldn't be parsed into transactions
+    pub unparsed: u64,
+    /// Number of distinct accounts with at least one transaction applied
+    pub accounts_touched: u64,
+    /// Number of accounts locked by a chargeback
+    pub accounts_locked: u64,
+    /// Sum of the amounts of all deposits and withdrawals applied
+    pub funds_moved: Decimal,
+}
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_localized(f, &NumberFormat::CANONICAL)
+    }
+}
+
+impl LocalizedDisplay for RunSummary {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        writeln!(f, "rows read: {}", self.rows_read)?;
+        writeln!(f, "applied: {}", self.applied)?;
+        writeln!(f, "ignored: {}", self.ignored)?;
+        writeln!(f, "rejected: {}", self.rejected)?;
+        writeln!(f, "unparsed: {}", self.unparsed)?;
+        writeln!(f, "accounts touched: {}", self.accounts_touched)?;
+        writeln!(f, "accounts locked: {}", self.accounts_locked)?;
+        writeln!(f, "funds moved: {}", format.amount(self.funds_moved))
+    }
+}
+
+/// Point-in-time figures describing all the accounts in an account book
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AccountGauges {
+    /// Number of accounts
+    pub accounts: u64,
+    /// Number of locked accounts
+    pub locked: u64,
+    /// Sum of available funds across all accounts
+    pub available: Decimal,
+    /// Sum of held funds across all accounts
+    pub held: Decimal,
+}
+
+impl AccountGauges {
+    /// Adds up figures across the supplied accounts.
+    ///
+    /// Sums across accounts can outgrow any one account, so they saturate rather than overflow,
+    /// as [`EngineStats`] does.
+    pub fn from_accounts<'a, I>(accounts: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let mut gauges = Self::default();
+        for account in accounts {
+            gauges.accounts += 1;
+            gauges.locked += u64::from(account.is_locked());
+            gauges.available = gauges.available.saturating_add(account.funds_available());
+            gauges.held = gauges.held.saturating_add(account.funds_held());
+        }
+        gauges
+    }
+}
+
+/// Returns the clock's time, or `None` where there's no clock to ask, as on
+/// `wasm32-unknown-unknown`, where [`SystemTime::now`] panics
+pub(crate) fn wall_clock(clock: &dyn Clock) -> Option<SystemTime> {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        None
+    } else {
+        Some(clock.now())
+    }
+}
+
+/// Writes one metric's `HELP` and `TYPE` lines
+fn write_metadata<W: Write>(
+    writer: &mut W,
+    name: &str,
+    metric_type: &str,
+    help: &str,
+) -> std::io::Result<()> {
+    writeln!(writer, "# HELP {name} {help}")?;
+    writeln!(writer, "# TYPE {name} {metric_type}")
+}
+
+/// Writes metrics and account gauges in the
+/// [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+///
+/// Ingest lag is measured as the time between [`Metrics::last_ingest`] and `now`.
+pub fn write_prometheus<W: Write>(
+    writer: &mut W,
+    metrics: &Metrics,
+    gauges: &AccountGauges,
+    now: SystemTime,
+) -> std::io::Result<()> {
+    write_metadata(
+        writer,
+        "cashflow_rows_parsed_total",
+        "counter",
+        "Rows successfully parsed into transactions",
+    )?;
+    writeln!(writer, "cashflow_rows_parsed_total {}", metrics.rows_parsed)?;
+    write_metadata(
+        writer,
+        "cashflow_transactions_total",
+        "counter",
+        "Transactions processed, by type and outcome",
+    )?;
+    for (outcome, counts) in [
+        ("applied", &metrics.applied),
+        ("rejected", &metrics.rejected),
+    ] {
+        for (transaction_type, count) in [
+            ("deposit", counts.deposits),
+            ("withdrawal", counts.withdrawals),
+            ("dispute", counts.disputes),
+            ("resolve", counts.resolves),
+            ("chargeback", counts.chargebacks),
+        ] {
+            writeln!(
+                writer,
+                "cashflow_transactions_total{{type=\"{transaction_type}\",outcome=\"{outcome}\"}} {count}"
+            )?;
+        }
+    }
+    write_metadata(
+        writer,
+        "cashflow_errors_total",
+        "counter",
+        "Rows that failed to parse or apply",
+    )?;
+    writeln!(writer, "cashflow_errors_total {}", metrics.errors)?;
+    write_metadata(
+        writer,
+        "cashflow_warnings_total",
+        "counter",
+        "Transactions applied with a warning, eg disputes of unknown transactions",
+    )?;
+    writeln!(writer, "cashflow_warnings_total {}", metrics.warnings)?;
+    write_metadata(writer, "cashflow_accounts", "gauge", "Number of accounts")?;
+    writeln!(writer, "cashflow_accounts {}", gauges.accounts)?;
+    write_metadata(
+        writer,
+        "cashflow_accounts_locked",
+        "gauge",
+        "Number of locked accounts",
+    )?;
+    writeln!(writer, "cashflow_accounts_locked {}", gauges.locked)?;
+    write_metadata(
+        writer,
+        "cashflow_funds_available",
+        "gauge",
+        "Available funds across all accounts",
+    )?;
+    writeln!(writer, "cashflow_funds_available {}", gauges.available)?;
+    write_metadata(
+        writer,
+        "cashflow_funds_held",
+        "gauge",
+        "Funds held for dispute across all accounts",
+    )?;
+    writeln!(writer, "cashflow_funds_held {}", gauges.held)?;
+    if let Some(last_ingest) = metrics.last_ingest {
+        let lag = now.duration_since(last_ingest).unwrap_or_default();
+        write_metadata(
+            writer,
+            "cashflow_ingest_lag_seconds",
+            "gauge",
+            "Time since transactions were last loaded",
+        )?;
+        writeln!(writer, "cashflow_ingest_lag_seconds {}", lag.as_secs_f64())?;
+    }
+    if let Some(latency) = &metrics.apply_latency {
+        write_metadata(
+            writer,
+            "cashflow_apply_latency_seconds",
+            "summary",
+            "Time taken to apply each transaction",
+        )?;
+        for (quantile, value) in [
+            ("0.5", latency.p50),
+            ("0.9", latency.p90),
+            ("0.99", latency.p99),
+        ] {
+            writeln!(
+                writer,
+                "cashflow_apply_latency_seconds{{quantile=\"{quantile}\"}} {}",
+                value.as_secs_f64()
+            )?;
+        }
+        writeln!(
+            writer,
+            "cashflow_apply_latency_seconds_sum {}",
+            latency.sum.as_secs_f64()
+        )?;
+        writeln!(
+            writer,
+            "cashflow_apply_latency_seconds_count {}",
+            latency.count
+        )?;
+    }
+    Ok(())
+}
+
+/// Collects metrics as an engine processes transactions
+#[derive(Debug, Default)]
+pub(crate) struct MetricsRegistry {
+    /// See [`Metrics::rows_parsed`]
+    pub(crate) rows_parsed: u64,
+    /// See [`Metrics::applied`]
+    pub(crate) applied: TransactionCounts,
+    /// See [`Metrics::rejected`]
+    pub(crate) rejected: TransactionCounts,
+    /// See [`Metrics::errors`]
+    pub(crate) errors: u64,
+    /// See [`Metrics::warnings`]
+    pub(crate) warnings: u64,
+    /// See [`Metrics::last_ingest`]
+    pub(crate) last_ingest: Option<SystemTime>,
+    /// See [`Metrics::last_applied`]
+    pub(crate) last_applied: Option<TransactionId>,
+    /// See [`Metrics::apply_latency`]
+    pub(crate) apply_latency: LatencyHistogram,
+    /// See [`RunSummary::unparsed`]
+    pub(crate) rows_unparsed: u64,
+    /// Every client with a transaction applied
+    pub(crate) touched: ClientSet,
+    /// See [`RunSummary::accounts_locked`]
+    pub(crate) accounts_locked: u64,
+    /// See [`RunSummary::funds_moved`]
+    pub(crate) funds_moved: Decimal,
+    /// Funds moved by type, and balances across all accounts. Its applied counts and locked
+    /// accounts are left to [`applied`](Self::applied) and
+    /// [`accounts_locked`](Self::accounts_locked).
+    pub(crate) funds: EngineStats,
+}
+
+impl MetricsRegistry {
+    /// Takes a copy of the current metrics
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            rows_parsed: self.rows_parsed,
+            applied: self.applied,
+            rejected: self.rejected,
+            errors: self.errors,
+            warnings: self.warnings,
+            last_ingest: self.last_ingest,
+            last_applied: self.last_applied,
+            apply_latency: self.apply_latency.summary(),
+        }
+    }
+
+    /// Takes a copy of the running totals across all accounts
+    pub(crate) fn stats(&self) -> EngineStats {
+        EngineStats {
+            applied: self.applied,
+            locked: self.accounts_locked,
+            ..self.funds
+        }
+    }
+
+    /// Summarizes everything processed so far
+    pub(crate) fn summary(&self) -> RunSummary {
+        RunSummary {
+            rows_read: self.rows_parsed + self.rows_unparsed,
+            applied: self.applied,
+            ignored: self.warnings,
+            rejected: self.rejected,
+            unparsed: self.rows_unparsed,
+            accounts_touched: self.touched.len,
+            accounts_locked: self.accounts_locked,
+            funds_moved: self.funds_moved,
+        }
+    }
+}
+
+/// A set of client IDs, held as one bit per possible ID, or hashed with the `wide-ids` feature
+#[derive(Debug, Default)]
+pub(crate) struct ClientSet {
+    /// Bit `i % 64` of word `i / 64` is set if client `i` is in the set. Only grows as far as the
+    /// highest ID inserted.
+    #[cfg(not(feature = "wide-ids"))]
+    words: Vec<u64>,
+    /// Every client in the set, since a bit per possible wide ID would take far too much memory
+    #[cfg(feature = "wide-ids")]
+    clients: std::collections::HashSet<ClientId>,
+    /// Number of clients in the set
+    len: u64,
+}
+
+impl ClientSet {
+    /// Adds a client to the set, if it isn't already in it
+    #[cfg(feature = "wide-ids")]
+    pub(crate) fn insert(&mut self, client: ClientId) {
+        if self.clients.insert(client) {
+            self.len += 1;
+        }
+    }
+
+    /// Adds a client to the set, if it isn't already in it
+    #[cfg(not(feature = "wide-ids"))]
+    pub(crate) fn insert(&mut self, client: ClientId) {
+        let index = usize::from(client.0);
+        let (word, mask) = (index / 64, 1 << (index % 64));
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        if self.words[word] & mask == 0 {
+            self.words[word] |= mask;
+            self.len += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.summary(), None);
+        for nanos in 1..=100 {
+            histogram.record(Duration::from_nanos(nanos * 10));
+        }
+        let summary = histogram.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        // 500ns falls in the 256..=511ns bucket
+        assert_eq!(summary.p50, Duration::from_nanos(511));
+        // 900ns and 990ns both fall in the 512..=1023ns bucket, but nothing exceeded 1000ns
+        assert_eq!(summary.p90, Duration::from_nanos(1000));
+        assert_eq!(summary.p99, Duration::from_nanos(1000));
+        assert_eq!(summary.max, Duration::from_nanos(1000));
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let mut metrics = Metrics {
+            rows_parsed: 3,
+            errors: 1,
+            warnings: 4,
+            last_ingest: Some(SystemTime::UNIX_EPOCH),
+            ..Metrics::default()
+        };
+        metrics.applied.deposits = 2;
+        metrics.rejected.withdrawals = 1;
+        let mut account = Account::new(1.into());
+        account.funds_available = Decimal::new(25, 1);
+        account.locked = true;
+        let gauges = AccountGauges::from_accounts([&account]);
+        let mut output = vec![];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(90);
+        write_prometheus(&mut output, &metrics, &gauges, now).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("cashflow_rows_parsed_total 3\n"));
+        assert!(output
+            .contains("cashflow_transactions_total{type=\"deposit\",outcome=\"applied\"} 2\n"));
+        assert!(output
+            .contains("cashflow_transactions_total{type=\"withdrawal\",outcome=\"rejected\"} 1\n"));
+        assert!(output.contains("cashflow_warnings_total 4\n"));
+        assert!(output.contains("cashflow_accounts_locked 1\n"));
+        assert!(output.contains("cashflow_funds_available 2.5\n"));
+        assert!(output.contains("cashflow_ingest_lag_seconds 90\n"));
+        assert!(!output.contains("cashflow_apply_latency_seconds"));
+    }
+
+    #[test]
+    fn test_gauges_saturate() {
+        let accounts: Vec<Account> = (1..=2)
+            .map(|client| {
+                let mut account = Account::new(client.into());
+                account.funds_available = Decimal::MAX - Decimal::ONE;
+                account
+            })
+            .collect();
+        let gauges = AccountGauges::from_accounts(&accounts);
+        assert_eq!(gauges.accounts, 2);
+        assert_eq!(gauges.available, Decimal::MAX);
+    }
+
+    #[test]
+    fn test_counts_by_type() {
+        let mut counts = TransactionCounts::default();
+        counts.increment(&TransactionType::Deposit);
+        counts.increment(&TransactionType::Deposit);
+        counts.increment(&TransactionType::Chargeback);
+        assert_eq!(counts.deposits, 2);
+        assert_eq!(counts.chargebacks, 1);
+        assert_eq!(counts.total(), 3);
+    }
+}
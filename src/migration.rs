@@ -0,0 +1,507 @@
+//! Upgrading state files written by older versions of this crate, step by step, so a persistent
+//! deployment can upgrade the crate without touching its data by hand.
+//!
+//! Every [state file](crate::state) records the schema version it was written with. When the
+//! schema changes, [`SCHEMA_VERSION`](crate::state::SCHEMA_VERSION) goes up by one, and a
+//! [`Migration`](crate::migration::Migration) from the version before is added to the built-in
+//! [`Migrations`](crate::migration::Migrations). A file several versions behind goes through each
+//! step in turn, so every step only needs to know about the version before it.
+//!
+//! Snapshots and WALs are migrated in memory whenever they're read, so nothing needs doing for
+//! them to keep working. [`Migrations::upgrade`](crate::migration::Migrations::upgrade) rewrites a
+//! file in the current schema for good, eg before a rollback window closes, or so it no longer
+//! needs migrating on every read. It can check a file would upgrade without writing anything,
+//! and keep a copy of the original. Steps of your own, eg to try a schema change out, can be
+//! created with [`Migration::new`](crate::migration::Migration::new) and added with
+//! [`Migrations::with_step`](crate::migration::Migrations::with_step).
+//! ```
+//! # use cashflow::{engine::Engine, migration::{Migrations, UpgradeOptions}};
+//! # let path = std::env::temp_dir().join(format!("cashflow-doc-{}.state", std::process::id()));
+//! let engine = Engine::in_memory();
+//! engine.snapshot().write(&mut std::fs::File::create(&path)?)?;
+//! let options = UpgradeOptions {
+//!     dry_run: true,
+//!     ..UpgradeOptions::default()
+//! };
+//! let upgrade = Migrations::builtin().upgrade(&path, &options)?;
+//! // Already in the current schema, so there's nothing to do
+//! assert!(upgrade.steps.is_empty());
+//! # std::fs::remove_file(&path)?;
+//! # Ok::<(), cashflow::errors::Error>(())
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+#[cfg(feature = "encryption")]
+use crate::state::EncryptionKey;
+use crate::{
+    audit::GENESIS,
+    errors::Error,
+    state::{
+        invalid, link, read_any_header, read_raw_frame, write_frame, write_header, Chain, Key,
+        Kind, CHAINED_SINCE, SCHEMA_VERSION,
+    },
+};
+
+/// The crate's own steps, from the first schema version up to [`SCHEMA_VERSION`]
+const BUILTIN: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "journal custom transactions, and keep their IDs in snapshots",
+        snapshot: unchanged,
+        transaction: unchanged,
+    },
+    Migration {
+        from: 2,
+        description: "keep chargebacks, open disputes and opening balances in snapshots",
+        snapshot: unchanged,
+        transaction: unchanged,
+    },
+    Migration {
+        from: 3,
+        description: "chain WAL frames together",
+        snapshot: unchanged,
+        // Chained as they're rewritten, since a step only sees one frame at a time
+        transaction: unchanged,
+    },
+    Migration {
+        from: 4,
+        description: "record consumer offsets in WAL frames",
+        snapshot: unchanged,
+        transaction: unchanged,
+    },
+    Migration {
+        from: 5,
+        description: "record the start and end of sagas in WALs",
+        snapshot: unchanged,
+        transaction: unchanged,
+    },
+];
+
+/// One step upgrading state files from a schema version to the next
+/// ```
+/// # use cashflow::migration::{Migration, Migrations};
+/// // A later schema version that renamed `held` to `reserved` in snapshots' accounts
+/// let rename = |mut payload: serde_json::Value| {
+///     for account in payload["accounts"].as_array_mut().into_iter().flatten() {
+///         account["reserved"] = account["held"].take();
+///     }
+///     Ok(payload)
+/// };
+/// let step = Migration::new(cashflow::state::SCHEMA_VERSION, "rename held", rename, Ok);
+/// let migrations = Migrations::builtin().with_step(step);
+/// assert_eq!(migrations.target(), cashflow::state::SCHEMA_VERSION + 1);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    /// The version upgraded from, to the one after it
+    pub from: u16,
+    /// What changed, eg for logging an upgrade
+    pub description: &'static str,
+    /// Upgrades a snapshot's payload
+    snapshot: fn(Value) -> Result<Value, Error>,
+    /// Upgrades the payload of one of a WAL's transactions
+    transaction: fn(Value) -> Result<Value, Error>,
+}
+
+impl Migration {
+    /// Creates a step from `from` to the version after it, which upgrades a snapshot's payload
+    /// with `snapshot`, and each of a WAL's with `transaction`
+    #[must_use]
+    pub const fn new(
+        from: u16,
+        description: &'static str,
+        snapshot: fn(Value) -> Result<Value, Error>,
+        transaction: fn(Value) -> Result<Value, Error>,
+    ) -> Self {
+        Self {
+            from,
+            description,
+            snapshot,
+            transaction,
+        }
+    }
+}
+
+/// The steps from each older schema version to the next, up to the version this crate writes
+#[derive(Debug, Clone)]
+pub struct Migrations {
+    /// The steps, in any order
+    steps: Vec<Migration>,
+    /// The version they end at
+    target: u16,
+}
+
+impl Migrations {
+    /// Returns the crate's own steps, up to [`SCHEMA_VERSION`]
+    #[must_use]
+    pub fn builtin() -> Self {
+        Self {
+            steps: BUILTIN.to_vec(),
+            target: SCHEMA_VERSION,
+        }
+    }
+
+    /// Adds a step, replacing any from the same version, and moves the target on to the version
+    /// it ends at if that's later, eg to upgrade files to a schema a later version of this crate
+    /// will write
+    #[must_use]
+    pub fn with_step(mut self, step: Migration) -> Self {
+        self.steps.retain(|existing| existing.from != step.from);
+        self.target = self.target.max(step.from.saturating_add(1));
+        self.steps.push(step);
+        self
+    }
+
+    /// Returns the version the steps end at
+    #[must_use]
+    pub fn target(&self) -> u16 {
+        self.target
+    }
+
+    /// Returns the steps a file written with `version` goes through, in order, or none if it's
+    /// already at the target or newer
+    /// # Errors
+    /// [`ParseError::InvalidState`](crate::errors::ParseError::InvalidState) if a step is missing,
+    /// eg for a version that was never released
+    pub fn steps_from(&self, version: u16) -> Result<Vec<&Migration>, Error> {
+        (version..self.target)
+            .map(|from| {
+                self.steps
+                    .iter()
+                    .find(|step| step.from == from)
+                    .ok_or_else(|| invalid(format!("unknown schema version {from}")))
+            })
+            .collect()
+    }
+
+    /// Upgrades a frame's payload from the schema version it was written with. Payloads from the
+    /// target version or newer are left as they are, since their header says they can be read.
+    pub(crate) fn migrate(&self, version: u16, kind: Kind, payload: Value) -> Result<Value, Error> {
+        self.steps_from(version)?
+            .into_iter()
+            .try_fold(payload, |payload, step| match kind {
+                Kind::Snapshot => (step.snapshot)(payload),
+                Kind::Wal => (step.transaction)(payload),
+            })
+    }
+
+    /// Rewrites a snapshot or WAL in the target schema, if it was written with an older one.
+    /// Every frame is migrated before anything is written, and the upgraded file is written aside
+    /// and renamed over the original, so a failure partway leaves the original as it was. A WAL's
+    /// last frame, if it was cut short by a crash, is left out, as it would be when read, and its
+    /// frames are chained together again, since migrating them changes their digests.
+    /// # Errors
+    /// [`ParseError::InvalidState`](crate::errors::ParseError::InvalidState) if the file isn't a
+    /// state file, is corrupt, is encrypted and there's no key, or a step fails, or
+    /// any error reading or writing files
+    pub fn upgrade(&self, path: &Path, options: &UpgradeOptions) -> Result<Upgrade, Error> {
+        let original = fs::read(path)?;
+        let mut reader = original.as_slice();
+        let header = read_any_header(&mut reader)?;
+        let steps = self.steps_from(header.version)?;
+        let mut upgrade = Upgrade {
+            from: header.version,
+            to: header.version.max(self.target),
+            steps: steps.iter().map(|step| step.description).collect(),
+            frames: 0,
+            backup: None,
+            written: false,
+        };
+        if steps.is_empty() {
+            return Ok(upgrade);
+        }
+        let key = match (header.encrypted, options.key()) {
+            (true, None) => return Err(invalid("file is encrypted, so needs a key to upgrade")),
+            (true, key) => key,
+            (false, _) => None,
+        };
+        let mut upgraded = vec![];
+        write_header(&mut upgraded, header.kind, self.target, header.encrypted)?;
+        let mut chain = Chain::new(header.version);
+        let mut head = GENESIS.to_string();
+        let mut index = 0;
+        loop {
+            let payload = match header.kind {
+                Kind::Snapshot => read_raw_frame(&mut reader, key, index)?,
+                Kind::Wal => chain.next(&mut reader, key)?,
+            };
+            let Some(payload) = payload else {
+                break;
+            };
+            let mut payload = self.migrate(header.version, header.kind, payload)?;
+            if header.kind == Kind::Wal && self.target >= CHAINED_SINCE {
+                payload = link(payload, &mut head)?;
+            }
+            write_frame(&mut upgraded, &payload, key, index)?;
+            index += 1;
+        }
+        upgrade.frames = index;
+        if options.dry_run {
+            return Ok(upgrade);
+        }
+        if options.backup {
+            let backup = with_suffix(path, &format!(".v{}.bak", header.version));
+            write_synced(&backup, &original)?;
+            upgrade.backup = Some(backup);
+        }
+        let partial = with_suffix(path, ".partial");
+        write_synced(&partial, &upgraded)?;
+        fs::rename(&partial, path)?;
+        upgrade.written = true;
+        Ok(upgrade)
+    }
+}
+
+/// How [`Migrations::upgrade`] goes about it
+#[derive(Debug, Clone, Default)]
+pub struct UpgradeOptions {
+    /// Migrate every frame, to check the file would upgrade, but write nothing
+    pub dry_run: bool,
+    /// Keep a copy of the original file alongside it, named after it with `.v{version}.bak` on
+    /// the end
+    pub backup: bool,
+    /// The key an encrypted file was written with, which it's written with again
+    #[cfg(feature = "encryption")]
+    pub key: Option<EncryptionKey>,
+}
+
+impl UpgradeOptions {
+    /// Returns the key to decrypt and encrypt frames with, if there is one
+    fn key(&self) -> Option<&Key> {
+        #[cfg(feature = "encryption")]
+        return self.key.as_ref();
+        #[cfg(not(feature = "encryption"))]
+        None
+    }
+}
+
+/// What [`Migrations::upgrade`] did to a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upgrade {
+    /// The schema version the file was written with
+    pub from: u16,
+    /// The schema version it's in now, or would be after a dry run
+    pub to: u16,
+    /// What each step it went through changed, in order. Empty if it was already up to date.
+    pub steps: Vec<&'static str>,
+    /// How many frames were migrated
+    pub frames: u64,
+    /// Where the original was copied to, if it was
+    pub backup: Option<PathBuf>,
+    /// Whether the file was rewritten. Not for a dry run, or a file already up to date.
+    pub written: bool,
+}
+
+/// Leaves a payload as it is, for a step that only added something older versions never wrote
+fn unchanged(payload: Value) -> Result<Value, Error> {
+    Ok(payload)
+}
+
+/// Returns a path with a suffix added to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Writes a file and waits for it to reach the disk
+fn write_synced(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        state::{read_wal, Snapshot, Wal},
+    };
+
+    use super::*;
+
+    /// Adds a field to every payload, as a schema change might
+    fn add_origin(mut payload: Value) -> Result<Value, Error> {
+        payload["origin"] = Value::from("current");
+        Ok(payload)
+    }
+
+    /// Renames the field added by [`add_origin`]
+    fn rename_origin(mut payload: Value) -> Result<Value, Error> {
+        let origin = payload["origin"].take();
+        payload["written_by"] = origin;
+        Ok(payload)
+    }
+
+    /// Steps two versions past the current one, where the first added a field and the second
+    /// renamed it
+    fn migrations() -> Migrations {
+        let step = |from, description, step| Migration::new(from, description, step, step);
+        // Out of order, as they may be registered
+        Migrations::builtin()
+            .with_step(step(SCHEMA_VERSION + 1, "rename origin", rename_origin))
+            .with_step(step(SCHEMA_VERSION, "add origin", add_origin))
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let dir = std::env::temp_dir().join(format!("cashflow-migration-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut engine = Engine::in_memory();
+        let wal_path = dir.join("wal.state");
+        let wal = Wal::new(File::create(&wal_path).unwrap());
+        engine.add_listener(wal.clone());
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5\n";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let snapshot_path = dir.join("snapshot.state");
+        engine
+            .snapshot()
+            .write(&mut File::create(&snapshot_path).unwrap())
+            .unwrap();
+        wal.finish().unwrap();
+        let original = fs::read(&wal_path).unwrap();
+
+        // Already up to date with the built-in steps
+        let upgrade = Migrations::builtin()
+            .upgrade(&wal_path, &UpgradeOptions::default())
+            .unwrap();
+        let current = SCHEMA_VERSION;
+        assert_eq!(
+            (upgrade.from, upgrade.to, upgrade.written),
+            (current, current, false)
+        );
+
+        // A dry run checks every frame, but leaves the file alone
+        let dry_run = UpgradeOptions {
+            dry_run: true,
+            ..UpgradeOptions::default()
+        };
+        let upgrade = migrations().upgrade(&wal_path, &dry_run).unwrap();
+        assert_eq!(upgrade.steps, ["add origin", "rename origin"]);
+        assert_eq!(upgrade.frames, 2);
+        assert!(!upgrade.written);
+        assert_eq!(fs::read(&wal_path).unwrap(), original);
+
+        let backup = UpgradeOptions {
+            backup: true,
+            ..UpgradeOptions::default()
+        };
+        let upgrade = migrations().upgrade(&wal_path, &backup).unwrap();
+        assert_eq!(
+            (upgrade.from, upgrade.to, upgrade.written),
+            (current, current + 2, true)
+        );
+        let backup_path = upgrade.backup.unwrap();
+        assert_eq!(backup_path, dir.join(format!("wal.state.v{current}.bak")));
+        assert_eq!(fs::read(&backup_path).unwrap(), original);
+
+        // Each frame went through both steps, in order, and still reads back
+        let upgraded = fs::read(&wal_path).unwrap();
+        let mut reader = upgraded.as_slice();
+        let header = read_any_header(&mut reader).unwrap();
+        assert_eq!(header.version, current + 2);
+        let frame = read_raw_frame(&mut reader, None, 0).unwrap().unwrap();
+        assert_eq!(frame["written_by"], "current");
+        assert!(frame.get("origin").is_none_or(Value::is_null));
+        let transactions = read_wal(&mut Cursor::new(&upgraded)).unwrap();
+        assert_eq!(transactions[1].amount(), Some(dec!(1.5)));
+        // Now up to date, so upgrading again does nothing
+        let upgrade = migrations().upgrade(&wal_path, &backup).unwrap();
+        assert!(upgrade.steps.is_empty() && !upgrade.written);
+
+        migrations()
+            .upgrade(&snapshot_path, &UpgradeOptions::default())
+            .unwrap();
+        let snapshot = Snapshot::read(&mut File::open(&snapshot_path).unwrap()).unwrap();
+        assert_eq!(snapshot, engine.snapshot());
+
+        // A version with no step to the next can't be upgraded
+        let gap = Migrations {
+            steps: vec![],
+            target: current + 1,
+        };
+        let err = gap.upgrade(&backup_path, &dry_run).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Invalid state file: unknown schema version {current}")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_chains_wal() {
+        // A WAL from before frames were chained
+        let mut wal = vec![];
+        write_header(&mut wal, Kind::Wal, CHAINED_SINCE - 1, false).unwrap();
+        let frames = [
+            serde_json::json!({"type": "deposit", "client": 1, "tx": 1, "amount": "5.0"}),
+            serde_json::json!({"type": "withdrawal", "client": 1, "tx": 2, "amount": "1.5"}),
+        ];
+        for (index, frame) in (0..).zip(&frames) {
+            write_frame(&mut wal, frame, None, index).unwrap();
+        }
+        assert_eq!(read_wal(&mut Cursor::new(&wal)).unwrap().len(), 2);
+
+        let path =
+            std::env::temp_dir().join(format!("cashflow-chain-{}.state", std::process::id()));
+        fs::write(&path, &wal).unwrap();
+        let upgrade = Migrations::builtin()
+            .upgrade(&path, &UpgradeOptions::default())
+            .unwrap();
+        assert!(upgrade.steps.contains(&"chain WAL frames together"));
+        let upgraded = fs::read(&path).unwrap();
+        let mut reader = upgraded.as_slice();
+        read_any_header(&mut reader).unwrap();
+        let frame = read_raw_frame(&mut reader, None, 0).unwrap().unwrap();
+        assert_eq!(frame["prev"], GENESIS);
+        let transactions = read_wal(&mut Cursor::new(&upgraded)).unwrap();
+        assert_eq!(transactions[1].amount(), Some(dec!(1.5)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_version_1() {
+        // A snapshot as the first schema version wrote it, before engine state was kept
+        let payload = r#"{
+            "accounts": [{"available": "3.5000", "client": 1, "held": "0.0000", "locked": false}],
+            "transactions": [
+                {"amount": "5.0", "client": 1, "tx": 1, "type": "deposit"},
+                {"amount": "1.5", "client": 1, "tx": 2, "type": "withdrawal"}
+            ]
+        }"#;
+        let mut file = b"CFST\x01\x00\x01\x00\x01".to_vec();
+        let payload: Value = serde_json::from_str(payload).unwrap();
+        write_frame(&mut file, &payload, None, 0).unwrap();
+
+        let snapshot = Snapshot::read(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(snapshot.accounts[0].funds_available(), dec!(3.5));
+        assert_eq!(snapshot.transactions.len(), 2);
+        assert!(snapshot.custom.is_empty() && snapshot.opening.is_empty());
+        let mut engine = Engine::in_memory();
+        engine.restore(snapshot).unwrap();
+        assert!(engine.check_consistency().is_consistent());
+
+        let path = std::env::temp_dir().join(format!("cashflow-v1-{}.state", std::process::id()));
+        fs::write(&path, &file).unwrap();
+        let upgrade = Migrations::builtin()
+            .upgrade(&path, &UpgradeOptions::default())
+            .unwrap();
+        assert_eq!((upgrade.from, upgrade.to), (1, SCHEMA_VERSION));
+        assert_eq!(upgrade.steps.len(), usize::from(SCHEMA_VERSION - 1));
+        let upgraded = Snapshot::read(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(upgraded, engine.snapshot());
+        fs::remove_file(&path).unwrap();
+    }
+}
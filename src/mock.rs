@@ -0,0 +1,531 @@
+//! Mock storage backends, behind the `test-util` feature, for unit testing pipelines built on the
+//! engine against storage that misbehaves.
+//!
+//! A [`MockAccountBook`](crate::mock::MockAccountBook) and a
+//! [`MockTransactionLog`](crate::mock::MockTransactionLog) keep everything in memory, like the
+//! built-in stores, but record every call made to them, and can be told to fail some of those
+//! calls with a chosen [`Failures`](crate::mock::Failures) pattern. Failed calls return
+//! [`Error::Transient`](crate::errors::Error::Transient) unless another error is supplied, and
+//! change nothing.
+//! ```
+//! # use cashflow::{mock::*, types::*};
+//! # use rust_decimal::Decimal;
+//! let mut account_book = MockAccountBook::new();
+//! let mut transaction_log = MockTransactionLog::new().fail_register(Failures::Every(2));
+//! let client = ClientId::from(1);
+//! for id in 1..=4 {
+//!     let deposit = Transaction::deposit(client, id.into(), Decimal::ONE).unwrap();
+//!     let result = account_book.apply(&mut transaction_log, &mut deposit.into());
+//!     assert_eq!(result.is_err(), id % 2 == 0);
+//! }
+//! assert_eq!(transaction_log.calls().len(), 8);
+//! transaction_log.assert_called(&TransactionLogCall::Transaction(TransactionId::from(3)));
+//! ```
+
+use std::sync::{Mutex, PoisonError};
+
+use crate::{
+    errors::Error,
+    types::{
+        Account, AccountBook, ClientId, MemoryAccountBook, MemoryTransactionLog, Transaction,
+        TransactionId, TransactionLog, TransactionRecord,
+    },
+};
+
+/// Which calls to a mocked method fail, counting calls to that method from one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Failures {
+    /// No call fails
+    #[default]
+    Never,
+    /// Every call fails
+    Always,
+    /// Every nth call fails: the nth, the 2nth, and so on. `Every(0)` never fails.
+    Every(u64),
+    /// The first n calls fail, and the rest succeed, like a backend that comes back up
+    First(u64),
+    /// The first n calls succeed, and the rest fail, like a backend that goes down
+    After(u64),
+}
+
+impl Failures {
+    /// Returns whether the supplied call, counting from one, fails
+    fn fails(self, call: u64) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Always => true,
+            // Calls count from one, so `Every(0)` never matches
+            Self::Every(n) => call.is_multiple_of(n),
+            Self::First(n) => call <= n,
+            Self::After(n) => call > n,
+        }
+    }
+}
+
+/// A call made to a [`MockAccountBook`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountBookCall {
+    /// [`AccountBook::account`]
+    Account(ClientId),
+    /// [`AccountBook::account_mut`]
+    AccountMut(ClientId),
+    /// [`AccountBook::reserve`]
+    Reserve(usize),
+    /// [`AccountBook::check_connection`]
+    CheckConnection,
+    /// [`AccountBook::accounts`]
+    Accounts,
+    /// [`AccountBook::accounts_after`]
+    AccountsAfter(Option<ClientId>, usize),
+    /// [`AccountBook::get`]
+    Get(ClientId),
+}
+
+/// A call made to a [`MockTransactionLog`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionLogCall {
+    /// [`TransactionLog::transaction`]
+    Transaction(TransactionId),
+    /// [`TransactionLog::register`]
+    Register(TransactionRecord),
+    /// [`TransactionLog::reserve`]
+    Reserve(usize),
+    /// [`TransactionLog::check_connection`]
+    CheckConnection,
+}
+
+/// The calls made to a mock, and how many of each failable method
+#[derive(Debug)]
+struct Recorder<C> {
+    /// Every call, oldest first
+    calls: Vec<C>,
+    /// Number of calls to each failable method so far
+    counts: [u64; 3],
+}
+
+impl<C> Default for Recorder<C> {
+    fn default() -> Self {
+        Self {
+            calls: vec![],
+            counts: [0; 3],
+        }
+    }
+}
+
+/// The calls made to a mock, behind a lock so methods taking `&self` can record theirs too
+#[derive(Debug)]
+struct Calls<C> {
+    /// Guarded by a mutex rather than a `RefCell`, so mocks can be sent between threads
+    recorder: Mutex<Recorder<C>>,
+    /// Which calls to each failable method fail
+    failures: [Failures; 3],
+    /// Creates the error returned by failed calls
+    error: fn() -> Error,
+}
+
+impl<C: Clone + PartialEq + std::fmt::Debug> Calls<C> {
+    /// Starts with no calls, and nothing failing
+    fn new() -> Self {
+        Self {
+            recorder: Mutex::default(),
+            failures: [Failures::Never; 3],
+            error: injected_failure,
+        }
+    }
+
+    /// Records a call to the failable method with the supplied index, returning an error if it
+    /// should fail
+    fn record(&self, method: usize, call: C) -> Result<(), Error> {
+        let mut recorder = self.recorder.lock().unwrap_or_else(PoisonError::into_inner);
+        recorder.calls.push(call);
+        recorder.counts[method] += 1;
+        if self.failures[method].fails(recorder.counts[method]) {
+            return Err((self.error)());
+        }
+        Ok(())
+    }
+
+    /// Records a call to a method that can't fail
+    fn record_infallible(&self, call: C) {
+        let mut recorder = self.recorder.lock().unwrap_or_else(PoisonError::into_inner);
+        recorder.calls.push(call);
+    }
+
+    /// Returns every call, oldest first
+    fn calls(&self) -> Vec<C> {
+        let recorder = self.recorder.lock().unwrap_or_else(PoisonError::into_inner);
+        recorder.calls.clone()
+    }
+
+    /// Forgets every call, and starts counting calls from one again
+    fn clear(&self) {
+        *self.recorder.lock().unwrap_or_else(PoisonError::into_inner) = Recorder::default();
+    }
+
+    /// Panics unless the supplied call was made
+    fn assert_called(&self, call: &C) {
+        let calls = self.calls();
+        assert!(
+            calls.contains(call),
+            "expected a call {call:?}, but the calls were {calls:?}"
+        );
+    }
+
+    /// Panics unless exactly the supplied calls were made, in order
+    fn assert_calls(&self, expected: &[C]) {
+        assert_eq!(self.calls(), expected, "calls didn't match");
+    }
+}
+
+/// The error failed calls return unless another is supplied
+fn injected_failure() -> Error {
+    Error::Transient("injected failure".into())
+}
+
+/// An in-memory [`AccountBook`] that records calls, and fails them on demand.
+///
+/// [`AccountBook::account`], [`AccountBook::account_mut`] and [`AccountBook::check_connection`]
+/// can be made to fail. Every call is recorded, including those that fail.
+#[derive(Debug)]
+pub struct MockAccountBook {
+    /// Where accounts are actually kept
+    inner: MemoryAccountBook,
+    /// Calls made so far, and which to fail
+    calls: Calls<AccountBookCall>,
+}
+
+impl Default for MockAccountBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockAccountBook {
+    /// Index of [`AccountBook::account`] among the failable methods
+    const ACCOUNT: usize = 0;
+    /// Index of [`AccountBook::account_mut`] among the failable methods
+    const ACCOUNT_MUT: usize = 1;
+    /// Index of [`AccountBook::check_connection`] among the failable methods
+    const CHECK_CONNECTION: usize = 2;
+
+    /// Creates an empty account book that doesn't fail
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: MemoryAccountBook::new(),
+            calls: Calls::new(),
+        }
+    }
+
+    /// Sets which calls to [`AccountBook::account`] fail
+    #[must_use]
+    pub fn fail_account(mut self, failures: Failures) -> Self {
+        self.calls.failures[Self::ACCOUNT] = failures;
+        self
+    }
+
+    /// Sets which calls to [`AccountBook::account_mut`] fail
+    #[must_use]
+    pub fn fail_account_mut(mut self, failures: Failures) -> Self {
+        self.calls.failures[Self::ACCOUNT_MUT] = failures;
+        self
+    }
+
+    /// Sets which calls to [`AccountBook::check_connection`] fail
+    #[must_use]
+    pub fn fail_check_connection(mut self, failures: Failures) -> Self {
+        self.calls.failures[Self::CHECK_CONNECTION] = failures;
+        self
+    }
+
+    /// Sets the error failed calls return, rather than [`Error::Transient`]
+    #[must_use]
+    pub fn fail_with(mut self, error: fn() -> Error) -> Self {
+        self.calls.error = error;
+        self
+    }
+
+    /// Returns every call made so far, oldest first
+    #[must_use]
+    pub fn calls(&self) -> Vec<AccountBookCall> {
+        self.calls.calls()
+    }
+
+    /// Forgets every call made so far. Calls are counted from one again for [`Failures`].
+    pub fn clear_calls(&self) {
+        self.calls.clear();
+    }
+
+    /// Checks the supplied call was made
+    ///
+    /// # Panics
+    /// If it wasn't, listing the calls that were
+    pub fn assert_called(&self, call: &AccountBookCall) {
+        self.calls.assert_called(call);
+    }
+
+    /// Checks exactly the supplied calls were made, in order
+    ///
+    /// # Panics
+    /// If they weren't
+    pub fn assert_calls(&self, expected: &[AccountBookCall]) {
+        self.calls.assert_calls(expected);
+    }
+}
+
+impl AccountBook for MockAccountBook {
+    fn account(&mut self, client_id: ClientId) -> Result<&Account, Error> {
+        self.calls
+            .record(Self::ACCOUNT, AccountBookCall::Account(client_id))?;
+        self.inner.account(client_id)
+    }
+
+    fn account_mut(&mut self, client_id: ClientId) -> Result<&mut Account, Error> {
+        self.calls
+            .record(Self::ACCOUNT_MUT, AccountBookCall::AccountMut(client_id))?;
+        self.inner.account_mut(client_id)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.calls
+            .record_infallible(AccountBookCall::Reserve(additional));
+        self.inner.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.calls
+            .record(Self::CHECK_CONNECTION, AccountBookCall::CheckConnection)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+        self.calls.record_infallible(AccountBookCall::Accounts);
+        self.inner.accounts()
+    }
+
+    fn get(&self, client_id: ClientId) -> Result<Option<&Account>, Error> {
+        self.calls
+            .record_infallible(AccountBookCall::Get(client_id));
+        self.inner.get(client_id)
+    }
+
+    fn scale(&self) -> u32 {
+        self.inner.scale()
+    }
+
+    fn accounts_after(
+        &self,
+        after: Option<ClientId>,
+        limit: usize,
+    ) -> Result<Vec<&Account>, Error> {
+        self.calls
+            .record_infallible(AccountBookCall::AccountsAfter(after, limit));
+        self.inner.accounts_after(after, limit)
+    }
+}
+
+impl IntoIterator for MockAccountBook {
+    type Item = Account;
+    type IntoIter = <MemoryAccountBook as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.inner.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a MockAccountBook {
+    type Item = &'a Account;
+    type IntoIter = <&'a MemoryAccountBook as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        (&self.inner).into_iter()
+    }
+}
+
+/// An in-memory [`TransactionLog`] that records calls, and fails them on demand.
+///
+/// [`TransactionLog::transaction`], [`TransactionLog::register`] and
+/// [`TransactionLog::check_connection`] can be made to fail. Every call is recorded, including
+/// those that fail; a failed registration doesn't keep the transaction, so it can be retried.
+#[derive(Debug)]
+pub struct MockTransactionLog {
+    /// Where transactions are actually kept
+    inner: MemoryTransactionLog,
+    /// Calls made so far, and which to fail
+    calls: Calls<TransactionLogCall>,
+}
+
+impl Default for MockTransactionLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockTransactionLog {
+    /// Index of [`TransactionLog::transaction`] among the failable methods
+    const TRANSACTION: usize = 0;
+    /// Index of [`TransactionLog::register`] among the failable methods
+    const REGISTER: usize = 1;
+    /// Index of [`TransactionLog::check_connection`] among the failable methods
+    const CHECK_CONNECTION: usize = 2;
+
+    /// Creates an empty transaction log that doesn't fail
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: MemoryTransactionLog::new(),
+            calls: Calls::new(),
+        }
+    }
+
+    /// Sets which calls to [`TransactionLog::transaction`] fail
+    #[must_use]
+    pub fn fail_transaction(mut self, failures: Failures) -> Self {
+        self.calls.failures[Self::TRANSACTION] = failures;
+        self
+    }
+
+    /// Sets which calls to [`TransactionLog::register`] fail
+    #[must_use]
+    pub fn fail_register(mut self, failures: Failures) -> Self {
+        self.calls.failures[Self::REGISTER] = failures;
+        self
+    }
+
+    /// Sets which calls to [`TransactionLog::check_connection`] fail
+    #[must_use]
+    pub fn fail_check_connection(mut self, failures: Failures) -> Self {
+        self.calls.failures[Self::CHECK_CONNECTION] = failures;
+        self
+    }
+
+    /// Sets the error failed calls return, rather than [`Error::Transient`]
+    #[must_use]
+    pub fn fail_with(mut self, error: fn() -> Error) -> Self {
+        self.calls.error = error;
+        self
+    }
+
+    /// Returns every call made so far, oldest first
+    #[must_use]
+    pub fn calls(&self) -> Vec<TransactionLogCall> {
+        self.calls.calls()
+    }
+
+    /// Forgets every call made so far. Calls are counted from one again for [`Failures`].
+    pub fn clear_calls(&self) {
+        self.calls.clear();
+    }
+
+    /// Checks the supplied call was made
+    ///
+    /// # Panics
+    /// If it wasn't, listing the calls that were
+    pub fn assert_called(&self, call: &TransactionLogCall) {
+        self.calls.assert_called(call);
+    }
+
+    /// Checks exactly the supplied calls were made, in order
+    ///
+    /// # Panics
+    /// If they weren't
+    pub fn assert_calls(&self, expected: &[TransactionLogCall]) {
+        self.calls.assert_calls(expected);
+    }
+}
+
+impl TransactionLog for MockTransactionLog {
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        self.calls.record(
+            Self::TRANSACTION,
+            TransactionLogCall::Transaction(transaction_id),
+        )?;
+        self.inner.transaction(transaction_id)
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.calls.record(
+            Self::REGISTER,
+            TransactionLogCall::Register((&transaction).into()),
+        )?;
+        self.inner.register(transaction)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.calls
+            .record_infallible(TransactionLogCall::Reserve(additional));
+        self.inner.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.calls
+            .record(Self::CHECK_CONNECTION, TransactionLogCall::CheckConnection)
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        self.inner.transactions()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        errors::{DomainError, RetryWithBackoff},
+        types::{RawTransactionId, Retrying},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_mocks() {
+        let client = ClientId::from(1);
+        let deposit =
+            |id: RawTransactionId| Transaction::deposit(client, id.into(), dec!(1)).unwrap();
+        let mut account_book = MockAccountBook::new().fail_account_mut(Failures::First(1));
+        let mut transaction_log = MockTransactionLog::new()
+            .fail_register(Failures::After(1))
+            .fail_with(|| DomainError::Locked(ClientId::from(9)).into());
+        let result = account_book.apply(&mut transaction_log, &mut deposit(1).into());
+        assert!(result.unwrap_err().is_transient());
+        account_book
+            .apply(&mut transaction_log, &mut deposit(1).into())
+            .unwrap();
+        let result = account_book.apply(&mut transaction_log, &mut deposit(2).into());
+        assert_eq!(result.unwrap_err().code(), "locked");
+        account_book.assert_calls(&[
+            AccountBookCall::AccountMut(client),
+            AccountBookCall::AccountMut(client),
+            AccountBookCall::AccountMut(client),
+        ]);
+        transaction_log.assert_called(&TransactionLogCall::Register((&deposit(2)).into()));
+        assert_eq!(transaction_log.transactions().count(), 1);
+
+        // Transient failures are retried away
+        let backoff = RetryWithBackoff::new(3, Duration::ZERO);
+        let mut account_book = Retrying::new(
+            MockAccountBook::new().fail_account_mut(Failures::First(2)),
+            backoff,
+        );
+        let mut transaction_log = Retrying::new(
+            MockTransactionLog::new().fail_register(Failures::Every(2)),
+            backoff,
+        );
+        for id in 1..=4 {
+            account_book
+                .apply(&mut transaction_log, &mut deposit(id).into())
+                .unwrap();
+        }
+        let account_book = account_book.into_inner();
+        assert_eq!(account_book.calls().len(), 10);
+        account_book.clear_calls();
+        assert!(account_book.calls().is_empty());
+        assert!(account_book.check_connection().is_ok());
+        assert!(Failures::Every(3).fails(6) && !Failures::Every(0).fails(1));
+    }
+}
@@ -0,0 +1,500 @@
+//! Alerts about significant events, so ops get paged without polling reports.
+//!
+//! An [`Alerting`](crate::notify::Alerting) listener watches what an
+//! [`Engine`](crate::engine::Engine) does, and passes an [`Alert`](crate::notify::Alert) to its
+//! [`Notifier`](crate::notify::Notifier) when:
+//! - an account is locked
+//! - a chargeback takes away at least a set amount
+//! - an account's total balance goes negative
+//!
+//! [`WebhookNotifier`](crate::notify::WebhookNotifier) posts each alert as JSON to a URL,
+//! optionally signed with HMAC-SHA256 so the receiver can tell it came from us.
+
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::{
+    errors::Error,
+    events::{DomainEvent, DomainEventKind, EventListener},
+    types::{Account, ClientId, TransactionId, TransactionRecord},
+};
+
+/// Something significant that happened to an account
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "alert", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Alert {
+    /// A chargeback locked the account
+    AccountLocked {
+        /// The account locked
+        client: ClientId,
+        /// The transaction charged back
+        #[serde(rename = "tx")]
+        transaction: TransactionId,
+        /// The amount charged back
+        amount: Option<Decimal>,
+        /// Available funds afterwards
+        available: Decimal,
+        /// Held funds afterwards
+        held: Decimal,
+        /// The total balance afterwards
+        total: Decimal,
+    },
+    /// A chargeback took away at least the [`Alerting`] threshold
+    LargeChargeback {
+        /// The account charged back
+        client: ClientId,
+        /// The transaction charged back
+        #[serde(rename = "tx")]
+        transaction: TransactionId,
+        /// The amount taken away
+        amount: Decimal,
+    },
+    /// The account's total balance went below zero. Not raised again until it's back to zero
+    /// or above.
+    NegativeBalance {
+        /// The account
+        client: ClientId,
+        /// The transaction that took the balance below zero
+        #[serde(rename = "tx")]
+        transaction: TransactionId,
+        /// The total balance afterwards
+        total: Decimal,
+    },
+}
+
+/// Somewhere to send [`Alert`]s, eg a pager or a chat channel
+pub trait Notifier {
+    /// Sends a single alert
+    /// # Errors
+    /// If the alert couldn't be delivered
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error>;
+}
+
+/// Sends alerts over a channel, eg to a thread delivering them. Alerts are dropped once the
+/// receiver is gone.
+impl Notifier for mpsc::Sender<Alert> {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+        let _ = self.send(*alert);
+        Ok(())
+    }
+}
+
+/// The notifier behind an [`Alerting`] listener and its clones
+#[derive(Debug)]
+struct AlertingState<N> {
+    /// Where alerts are sent
+    notifier: N,
+    /// Chargebacks of at least this much raise an alert
+    large_chargeback: Option<Decimal>,
+    /// Accounts that have already been alerted about for a negative balance
+    negative: HashSet<ClientId>,
+    /// A lock alert waiting on the events of the chargeback that caused it, to learn the amount
+    pending_lock: Option<Alert>,
+    /// The first error hit while sending alerts. Later alerts are still sent.
+    error: Option<Error>,
+}
+
+/// Raises [`Alert`]s as an engine processes transactions, sending them to a [`Notifier`].
+///
+/// As with an [`AuditLog`](crate::audit::AuditLog), clones share the same notifier, so keep one
+/// to call [`finish`](Self::finish) on after registering another with the engine.
+#[derive(Debug)]
+pub struct Alerting<N> {
+    /// Shared with clones
+    inner: Arc<Mutex<AlertingState<N>>>,
+}
+
+impl<N> Clone for Alerting<N> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<N: Notifier> Alerting<N> {
+    /// Creates a listener sending alerts to the supplied notifier. Chargebacks only raise an alert
+    /// if they lock the account, until a threshold is set with
+    /// [`with_large_chargeback`](Self::with_large_chargeback).
+    #[must_use]
+    pub fn new(notifier: N) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(AlertingState {
+                notifier,
+                large_chargeback: None,
+                negative: HashSet::new(),
+                pending_lock: None,
+                error: None,
+            })),
+        }
+    }
+
+    /// Raises an alert for chargebacks of at least `amount`
+    #[must_use]
+    pub fn with_large_chargeback(self, amount: Decimal) -> Self {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .large_chargeback = Some(amount);
+        self
+    }
+
+    /// Checks every alert was delivered
+    /// # Errors
+    /// The first error the notifier returned, if any
+    pub fn finish(&self) -> Result<(), Error> {
+        match self
+            .inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .error
+            .take()
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends an alert, keeping the error if it's the first to fail
+    fn raise(&self, alert: &Alert) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = inner.notifier.notify(alert) {
+            inner.error.get_or_insert(err);
+        }
+    }
+}
+
+impl<N: Notifier> EventListener for Alerting<N> {
+    fn on_applied(&mut self, transaction: &TransactionRecord, account: &Account) {
+        let client = account.client_id();
+        let total = account.total();
+        let newly_negative = {
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            if total < Decimal::ZERO {
+                inner.negative.insert(client)
+            } else {
+                inner.negative.remove(&client);
+                false
+            }
+        };
+        if newly_negative {
+            self.raise(&Alert::NegativeBalance {
+                client,
+                transaction: transaction.transaction_id(),
+                total,
+            });
+        }
+    }
+
+    fn on_account_locked(&mut self, transaction: &TransactionRecord, account: &Account) {
+        // Raised on the account locked event, which comes after the funds charged back event
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pending_lock = Some(Alert::AccountLocked {
+            client: account.client_id(),
+            transaction: transaction.transaction_id(),
+            amount: None,
+            available: account.funds_available(),
+            held: account.funds_held(),
+            total: account.total(),
+        });
+    }
+
+    fn on_event(&mut self, event: &DomainEvent) {
+        let (threshold, pending_lock) = {
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            let pending_lock = match event.kind {
+                DomainEventKind::FundsChargedBack => {
+                    if let Some(Alert::AccountLocked { amount, .. }) = &mut inner.pending_lock {
+                        *amount = event.amount;
+                    }
+                    None
+                }
+                DomainEventKind::AccountLocked => inner.pending_lock.take(),
+                _ => None,
+            };
+            (inner.large_chargeback, pending_lock)
+        };
+        if let Some(alert) = pending_lock {
+            self.raise(&alert);
+        }
+        let (DomainEventKind::FundsChargedBack, Some(amount)) = (event.kind, event.amount) else {
+            return;
+        };
+        if threshold.is_some_and(|threshold| amount >= threshold) {
+            self.raise(&Alert::LargeChargeback {
+                client: event.client_id,
+                transaction: event.transaction_id,
+                amount,
+            });
+        }
+    }
+}
+
+/// Posts each alert as JSON to a webhook, eg
+/// `{"alert":"negative_balance","client":1,"tx":7,"total":"-5.0000"}`.
+///
+/// Only plain `http://` URLs are supported, so point this at something on a trusted network,
+/// like a local relay to the paging service. With a secret set by
+/// [`with_secret`](Self::with_secret), each post carries an `X-Cashflow-Signature` header of
+/// `sha256=` and the hex HMAC-SHA256 of the body, keyed with the secret, so the receiver can
+/// check it wasn't forged.
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    /// `host:port` to connect to
+    address: String,
+    /// Sent as the `Host` header
+    host: String,
+    /// The path to post to, eg `/hooks/cashflow`
+    path: String,
+    /// How long to wait to connect, and for each read and write
+    timeout: Duration,
+    /// Key for signing each body, if set
+    secret: Option<Vec<u8>>,
+}
+
+impl WebhookNotifier {
+    /// Creates a notifier posting to the supplied `http://` URL, timing out after 5 seconds
+    /// # Errors
+    /// If the URL isn't a valid `http://` URL
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid URL {url}"));
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(invalid().into());
+        }
+        let address = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'))
+        {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+            timeout: Duration::from_secs(5),
+            secret: None,
+        })
+    }
+
+    /// Changes how long to wait to connect, and for each read and write
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Signs every post with HMAC-SHA256, keyed with the supplied secret
+    #[must_use]
+    pub fn with_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+}
+
+/// Returns the `X-Cashflow-Signature` value for a body: `sha256=` and its hex HMAC-SHA256
+fn signature(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(body);
+    let mut signature = String::from("sha256=");
+    for byte in mac.finalize().into_bytes() {
+        signature.push_str(&format!("{byte:02x}"));
+    }
+    signature
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+        let body = serde_json::to_vec(alert).map_err(io::Error::from)?;
+        let address = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} didn't resolve", self.address),
+            )
+        })?;
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.host,
+            body.len()
+        )?;
+        if let Some(secret) = &self.secret {
+            write!(
+                stream,
+                "X-Cashflow-Signature: {}\r\n",
+                signature(secret, &body)
+            )?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(&body)?;
+        stream.flush()?;
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => {
+                Err(io::Error::other(format!("Webhook replied {}", status_line.trim_end())).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Cursor, Read},
+        net::TcpListener,
+        thread,
+    };
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_alerts() {
+        let (sender, receiver) = mpsc::channel();
+        let alerting = Alerting::new(sender).with_large_chargeback(dec!(100));
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(alerting.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,50.0
+deposit,2,2,150.0
+withdrawal,2,3,100.0
+dispute,2,2,
+chargeback,2,2,
+dispute,1,1,
+chargeback,1,1,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        alerting.finish().unwrap();
+        // Both ends hold the sender, so both need dropping for the channel to close
+        drop(engine);
+        drop(alerting);
+        let alerts: Vec<_> = receiver.iter().collect();
+        assert_eq!(
+            alerts,
+            [
+                Alert::NegativeBalance {
+                    client: 2.into(),
+                    transaction: 2.into(),
+                    total: dec!(-100),
+                },
+                Alert::LargeChargeback {
+                    client: 2.into(),
+                    transaction: 2.into(),
+                    amount: dec!(150),
+                },
+                Alert::AccountLocked {
+                    client: 2.into(),
+                    transaction: 2.into(),
+                    amount: Some(dec!(150)),
+                    available: dec!(-100),
+                    held: dec!(0),
+                    total: dec!(-100),
+                },
+                // Client 1's chargeback is under the threshold, and leaves a zero balance
+                Alert::AccountLocked {
+                    client: 1.into(),
+                    transaction: 1.into(),
+                    amount: Some(dec!(50)),
+                    available: dec!(0),
+                    held: dec!(0),
+                    total: dec!(0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/cashflow", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for status in ["204 No Content", "500 Internal Server Error"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                // The headers and body may arrive separately, so read up to the end of the body
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                requests.push(request);
+                write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+            }
+            requests
+        });
+        let mut notifier = WebhookNotifier::new(&url).unwrap().with_secret("key");
+        let alert = Alert::AccountLocked {
+            client: 3.into(),
+            transaction: 9.into(),
+            amount: Some(dec!(2.5)),
+            available: dec!(1),
+            held: dec!(0),
+            total: dec!(1),
+        };
+        notifier.notify(&alert).unwrap();
+        assert!(notifier.notify(&alert).is_err());
+        let requests = server.join().unwrap();
+        let body = r#"{"alert":"account_locked","client":3,"tx":9,"amount":"2.5","available":"1","held":"0","total":"1"}"#;
+        assert!(requests[0].starts_with("POST /hooks/cashflow HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with(body));
+        let expected = format!(
+            "X-Cashflow-Signature: {}\r\n",
+            signature(b"key", body.as_bytes())
+        );
+        assert!(requests[0].contains(&expected));
+        // A known HMAC-SHA256 test vector (RFC 4231, test case 2)
+        assert_eq!(
+            signature(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        assert!(WebhookNotifier::new("https://example.com").is_err());
+        assert_eq!(
+            WebhookNotifier::new("http://example.com").unwrap().address,
+            "example.com:80"
+        );
+    }
+}
@@ -1,72 +1,138 @@
+use std::hash::BuildHasher;
+
 use rust_decimal::Decimal;
 
 use crate::{
-    errors::Error,
+    errors::{DomainError, Error, RetryWithBackoff},
     types::{
-        Account, AccountBook, ClientId, MemoryAccountBook, MemoryTransactionLog, TransactionLog,
-        TransactionState, TransactionType, DECIMAL_SCALE,
+        Account, AccountBook, ClientId, Currency, MemoryAccountBook, MemoryTransactionLog,
+        MinorUnitsEntry, MinorUnitsTransactionLog, Money, Provenance, ProvenanceLog, Retrying,
+        Transaction, TransactionId, TransactionLog, TransactionRecord, TransactionState,
+        TransactionType,
     },
 };
 impl Account {
-    /// Adds funds to an account's available funds.
+    /// Adds funds to an account's available funds, rounded to `scale` decimal places.
     /// # Errors
-    /// [`Error::Locked`] if the account is locked
-    fn deposit(&mut self, mut amount: Decimal) -> Result<(), Error> {
+    /// [`DomainError::Locked`] if the account is locked, [`DomainError::CurrencyMismatch`] if the
+    /// amount is in another currency, or `None` if the balance would overflow
+    pub(crate) fn deposit(
+        &mut self,
+        amount: Money,
+        transaction_id: TransactionId,
+        scale: u32,
+    ) -> Result<Option<()>, Error> {
         self.check_lock()?;
-        amount.rescale(DECIMAL_SCALE);
-        self.funds_available += amount;
-        Ok(())
+        self.check_currency(amount.currency(), transaction_id)?;
+        let mut amount = amount.amount();
+        amount.rescale(scale);
+        Ok(self.update(
+            self.funds_available.checked_add(amount),
+            Some(self.funds_held),
+        ))
     }
 
-    /// Subtracts funds from an account's available funds.
+    /// Subtracts funds from an account's available funds, rounded to `scale` decimal places.
     ///
     /// Account balances are allowed to go negative, if the amount
     /// exceeds the available funds.
     /// # Errors
-    /// [`Error::Locked`] if the account is locked
-    fn withdraw(&mut self, mut amount: Decimal) -> Result<(), Error> {
+    /// [`DomainError::Locked`] if the account is locked, [`DomainError::CurrencyMismatch`] if the
+    /// amount is in another currency, or `None` if the balance would overflow
+    pub(crate) fn withdraw(
+        &mut self,
+        amount: Money,
+        transaction_id: TransactionId,
+        scale: u32,
+    ) -> Result<Option<()>, Error> {
         self.check_lock()?;
-        amount.rescale(DECIMAL_SCALE);
-        self.funds_available -= amount;
-        Ok(())
+        self.check_currency(amount.currency(), transaction_id)?;
+        let mut amount = amount.amount();
+        amount.rescale(scale);
+        Ok(self.update(
+            self.funds_available.checked_sub(amount),
+            Some(self.funds_held),
+        ))
     }
 
-    /// Moves funds out of available to held funds.
+    /// Moves funds out of available to held funds, rounded to `scale` decimal places, returning
+    /// `None` if a balance would overflow.
     ///
     /// Account balances are allowed to go negative, if the amount
     /// exceeds the available funds.
     ///
     /// This operation will succeed on locked accounts.
-    fn dispute(&mut self, mut amount: Decimal) {
-        amount.rescale(DECIMAL_SCALE);
-        self.funds_available -= amount;
-        self.funds_held += amount;
+    pub(crate) fn dispute(&mut self, mut amount: Decimal, scale: u32) -> Option<()> {
+        amount.rescale(scale);
+        self.update(
+            self.funds_available.checked_sub(amount),
+            self.funds_held.checked_add(amount),
+        )
     }
 
-    /// Moves funds out of held funds into available funds.
+    /// Moves funds out of held funds into available funds, rounded to `scale` decimal places,
+    /// returning `None` if a balance would overflow.
     ///
     /// Account balances are allowed to go negative, if the amount
     /// exceeds the held funds.
-    fn resolve(&mut self, mut amount: Decimal) {
-        amount.rescale(DECIMAL_SCALE);
-        self.funds_held -= amount;
-        self.funds_available += amount;
+    pub(crate) fn resolve(&mut self, mut amount: Decimal, scale: u32) -> Option<()> {
+        amount.rescale(scale);
+        self.update(
+            self.funds_available.checked_add(amount),
+            self.funds_held.checked_sub(amount),
+        )
     }
 
-    /// Subtracts funds from held funds and locks the account.
+    /// Subtracts funds from held funds, rounded to `scale` decimal places, and locks the account,
+    /// returning `None` if the balance would overflow.
     ///
     /// Account balances are allowed to go negative, if the amount
     /// exceeds the held funds.
-    fn chargeback(&mut self, mut amount: Decimal) {
-        amount.rescale(DECIMAL_SCALE);
-        self.funds_held -= amount;
+    pub(crate) fn chargeback(&mut self, mut amount: Decimal, scale: u32) -> Option<()> {
+        amount.rescale(scale);
+        self.update(
+            Some(self.funds_available),
+            self.funds_held.checked_sub(amount),
+        )?;
         self.locked = true;
+        Some(())
     }
 
-    /// Returns an [`Error::Locked`] if the account is locked.
+    /// Sets both balances, but only if neither overflowed and their total fits too, so
+    /// [`Account::total`] can't overflow. Otherwise, leaves the account as it was.
+    fn update(&mut self, available: Option<Decimal>, held: Option<Decimal>) -> Option<()> {
+        let (available, held) = (available?, held?);
+        available.checked_add(held)?;
+        self.funds_available = available;
+        self.funds_held = held;
+        Some(())
+    }
+
+    /// Returns a [`DomainError::Locked`] if the account is locked.
     fn check_lock(&self) -> Result<(), Error> {
         if self.locked {
-            return Err(Error::Locked(self.client_id));
+            return Err(DomainError::Locked(self.client_id).into());
+        }
+        Ok(())
+    }
+
+    /// Returns a [`DomainError::CurrencyMismatch`] if a transaction's currency isn't the
+    /// account's. An empty account in [`Currency::NONE`] is put in the transaction's currency.
+    fn check_currency(
+        &mut self,
+        currency: Currency,
+        transaction_id: TransactionId,
+    ) -> Result<(), Error> {
+        if self.currency.is_none() && self.funds_available.is_zero() && self.funds_held.is_zero() {
+            self.currency = currency;
+        }
+        if currency != self.currency {
+            return Err(DomainError::CurrencyMismatch {
+                transaction: transaction_id,
+                expected: self.currency,
+                found: currency,
+            }
+            .into());
         }
         Ok(())
     }
@@ -75,83 +141,125 @@ impl Account {
 /// Does the work of applying each incoming transaction to the account book, and storing it in the log.
 ///
 /// This takes a [`TransactionState`] to ensure that each transaction is only applied once.
-pub(crate) fn apply_transaction<A, T>(
+///
+/// The change to the account is staged on a copy, and only written back once the log has
+/// registered the transaction, so a failure at either step leaves both the book and the log as
+/// they were, and the transaction [not applied](TransactionState::NotApplied) to be tried again.
+pub(crate) fn apply_transaction<A>(
     account_book: &mut A,
-    transaction_log: &mut T,
+    transaction_log: &mut dyn TransactionLog,
     transaction_state: &mut TransactionState,
 ) -> Result<(), Error>
 where
-    A: AccountBook,
-    for<'a> &'a A: IntoIterator<Item = &'a Account>,
-    T: TransactionLog,
+    A: AccountBook + ?Sized,
 {
-    match transaction_state {
+    let transaction = match transaction_state {
         // Error for already-applied transactions
-        TransactionState::Applied(txn_id) => return Err(Error::Duplicate(*txn_id)),
-        TransactionState::NotApplied(transaction) => {
-            let transaction_id = transaction.transaction_id;
-            let referred_amount = transaction_log
-                .transaction(transaction_id)?
-                .and_then(|referred| referred.amount);
-            let account = account_book.account_mut(transaction.client_id)?;
-            match transaction.transaction_type {
-                TransactionType::Deposit => account.deposit(transaction.amount.unwrap())?,
-                TransactionType::Withdrawal => account.withdraw(transaction.amount.unwrap())?,
-                // Ignoring missing referred transactions (or referred transactions with no amounts)
-                // for the operations below
-                TransactionType::Dispute => {
-                    if let Some(amount) = referred_amount {
-                        account.dispute(amount)
-                    }
-                }
-                TransactionType::Resolve => {
-                    if let Some(amount) = referred_amount {
-                        account.resolve(amount)
-                    }
-                }
-                TransactionType::Chargeback => {
-                    if let Some(amount) = referred_amount {
-                        account.chargeback(amount)
-                    }
-                }
-            }
-            // Since the input was a mutable reference to an enum, we can swap it out for a new
-            // [`TransactionState::Applied`], allowing us to move the input `Transaction` to the
-            // internal storage.
-            let mut new_state = TransactionState::Applied(transaction.transaction_id);
-            std::mem::swap(transaction_state, &mut new_state);
-            match new_state {
-                TransactionState::NotApplied(txn) => match txn.transaction_type {
-                    // Deposits and withdrawals get added to the transaction register, for future reference
-                    TransactionType::Deposit | TransactionType::Withdrawal => {
-                        transaction_log.register(txn)?;
-                    }
-                    _ => (),
-                },
-                TransactionState::Applied(_) => unreachable!(),
-            }
+        TransactionState::Applied(txn_id) => return Err(DomainError::Duplicate(*txn_id).into()),
+        TransactionState::NotApplied(transaction) => transaction,
+    };
+    let scale = account_book.scale();
+    let transaction_id = transaction.transaction_id;
+    let referred_amount = transaction_log
+        .transaction(transaction_id)?
+        .and_then(|referred| referred.amount);
+    let account = account_book.account_mut(transaction.client_id)?;
+    let mut staged = account.clone();
+    let applied = match transaction.transaction_type {
+        TransactionType::Deposit => staged.deposit(
+            transaction
+                .money()
+                .ok_or(DomainError::MissingAmount(transaction_id))?,
+            transaction_id,
+            scale,
+        )?,
+        TransactionType::Withdrawal => staged.withdraw(
+            transaction
+                .money()
+                .ok_or(DomainError::MissingAmount(transaction_id))?,
+            transaction_id,
+            scale,
+        )?,
+        // Ignoring missing referred transactions (or referred transactions with no amounts)
+        // for the operations below
+        TransactionType::Dispute => {
+            referred_amount.map_or(Some(()), |amount| staged.dispute(amount, scale))
+        }
+        TransactionType::Resolve => {
+            referred_amount.map_or(Some(()), |amount| staged.resolve(amount, scale))
         }
+        TransactionType::Chargeback => {
+            referred_amount.map_or(Some(()), |amount| staged.chargeback(amount, scale))
+        }
+    };
+    applied.ok_or(DomainError::AmountOutOfRange(transaction_id))?;
+    match transaction.transaction_type {
+        // Deposits and withdrawals get added to the transaction register, for future reference.
+        // The log is given a copy, so the transaction is still here to retry if it fails.
+        TransactionType::Deposit | TransactionType::Withdrawal => {
+            transaction_log.register(copy_of(transaction))?;
+        }
+        _ => (),
     }
+    // Both steps succeeded, so commit the staged account
+    *account = staged;
+    *transaction_state = TransactionState::Applied(transaction_id);
     Ok(())
 }
 
-impl AccountBook for MemoryAccountBook {
+/// Returns a copy of a transaction, to register in the log without giving up the original.
+///
+/// This is the only way to duplicate a [`Transaction`], and it's kept to this crate. Every copy
+/// goes to a [`TransactionLog`] or to a preview against a throwaway account book, so the
+/// [`TransactionState`] holding the original still decides whether it's been applied.
+pub(crate) fn copy_of(transaction: &Transaction) -> Transaction {
+    Transaction {
+        transaction_type: transaction.transaction_type,
+        client_id: transaction.client_id,
+        transaction_id: transaction.transaction_id,
+        amount: transaction.amount,
+        currency: transaction.currency,
+        timestamp: transaction.timestamp,
+        signature: transaction.signature,
+    }
+}
+
+impl<S> AccountBook for MemoryAccountBook<S>
+where
+    S: BuildHasher,
+{
     fn account(&mut self, client_id: ClientId) -> Result<&Account, Error> {
         Ok(self
             .accounts
             .entry(client_id)
-            .or_insert_with(|| Account::new(client_id)))
+            .or_insert_with(|| Account::with_scale(client_id, self.scale)))
     }
 
     fn account_mut(&mut self, client_id: ClientId) -> Result<&mut Account, Error> {
         Ok(self
             .accounts
             .entry(client_id)
-            .or_insert_with(|| Account::new(client_id)))
+            .or_insert_with(|| Account::with_scale(client_id, self.scale)))
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+        Box::new(self.accounts.values())
+    }
+
+    fn get(&self, client_id: ClientId) -> Result<Option<&Account>, Error> {
+        Ok(self.accounts.get(&client_id))
+    }
+
+    fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.accounts.reserve(additional);
     }
 }
 
-impl<'a> IntoIterator for &'a MemoryAccountBook {
+impl<'a, S> IntoIterator for &'a MemoryAccountBook<S> {
     type Item = &'a Account;
 
     type IntoIter = std::collections::hash_map::Values<'a, ClientId, Account>;
@@ -161,7 +269,7 @@ impl<'a> IntoIterator for &'a MemoryAccountBook {
     }
 }
 
-impl IntoIterator for MemoryAccountBook {
+impl<S> IntoIterator for MemoryAccountBook<S> {
     type Item = Account;
     type IntoIter = std::collections::hash_map::IntoValues<ClientId, Account>;
 
@@ -170,35 +278,274 @@ impl IntoIterator for MemoryAccountBook {
     }
 }
 
-impl TransactionLog for MemoryTransactionLog {
+impl<S> TransactionLog for MemoryTransactionLog<S>
+where
+    S: BuildHasher,
+{
     fn transaction(
         &self,
-        transaction_id: crate::types::TransactionId,
-    ) -> Result<Option<&crate::types::Transaction>, Error> {
-        Ok(self.transactions.get(&transaction_id))
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        Ok(self.transactions.get(&transaction_id).map(Into::into))
     }
 
-    fn register(&mut self, transaction: crate::types::Transaction) -> Result<(), Error> {
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
         self.transactions
             .insert(transaction.transaction_id, transaction);
         Ok(())
     }
+
+    fn reserve(&mut self, additional: usize) {
+        self.transactions.reserve(additional);
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        Box::new(self.transactions.values().map(Into::into))
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        Ok(transaction_ids
+            .iter()
+            .filter(|transaction_id| self.transactions.remove(transaction_id).is_some())
+            .count())
+    }
+}
+
+impl<S> TransactionLog for MinorUnitsTransactionLog<S>
+where
+    S: BuildHasher,
+{
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        Ok(self
+            .transactions
+            .get(&transaction_id)
+            .map(|entry| entry.record(transaction_id, self.scale)))
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let amount = match transaction.amount {
+            Some(mut amount) => {
+                amount.rescale(self.scale);
+                i64::try_from(amount.mantissa())
+                    .ok()
+                    .filter(|&minor_units| minor_units != MinorUnitsEntry::NO_AMOUNT)
+                    .ok_or(DomainError::AmountOutOfRange(transaction.transaction_id))?
+            }
+            None => MinorUnitsEntry::NO_AMOUNT,
+        };
+        self.transactions.insert(
+            transaction.transaction_id,
+            MinorUnitsEntry {
+                amount,
+                client_id: transaction.client_id,
+                transaction_type: transaction.transaction_type,
+            },
+        );
+        Ok(())
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.transactions.reserve(additional);
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        Box::new(
+            self.transactions
+                .iter()
+                .map(|(&transaction_id, entry)| entry.record(transaction_id, self.scale)),
+        )
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        Ok(transaction_ids
+            .iter()
+            .filter(|transaction_id| self.transactions.remove(transaction_id).is_some())
+            .count())
+    }
+}
+
+/// Makes a storage call, retrying it while it fails with [`Error::Transient`]
+fn retry_transient<R>(
+    backoff: &RetryWithBackoff,
+    mut call: impl FnMut() -> Result<R, Error>,
+) -> Result<R, Error> {
+    let mut retry = 0;
+    loop {
+        match call() {
+            Err(err) if err.is_transient() && retry < backoff.max_retries() => {
+                retry += 1;
+                std::thread::sleep(backoff.delay(retry));
+            }
+            result => return result,
+        }
+    }
+}
+
+impl<A> AccountBook for Retrying<A>
+where
+    A: AccountBook,
+{
+    fn account(&mut self, client_id: ClientId) -> Result<&Account, Error> {
+        // The borrow checker won't allow returning the account from inside the retry loop
+        let inner = &mut self.inner;
+        retry_transient(&self.backoff, || inner.account(client_id).map(|_| ()))?;
+        self.inner.account(client_id)
+    }
+
+    fn account_mut(&mut self, client_id: ClientId) -> Result<&mut Account, Error> {
+        let inner = &mut self.inner;
+        retry_transient(&self.backoff, || inner.account_mut(client_id).map(|_| ()))?;
+        self.inner.account_mut(client_id)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+        self.inner.accounts()
+    }
+
+    fn get(&self, client_id: ClientId) -> Result<Option<&Account>, Error> {
+        retry_transient(&self.backoff, || self.inner.get(client_id))
+    }
+
+    fn scale(&self) -> u32 {
+        self.inner.scale()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.inner.check_connection()
+    }
+
+    fn accounts_after(
+        &self,
+        after: Option<ClientId>,
+        limit: usize,
+    ) -> Result<Vec<&Account>, Error> {
+        retry_transient(&self.backoff, || self.inner.accounts_after(after, limit))
+    }
+}
+
+impl<T> TransactionLog for Retrying<T>
+where
+    T: TransactionLog,
+{
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        retry_transient(&self.backoff, || self.inner.transaction(transaction_id))
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let inner = &mut self.inner;
+        // A transient failure means the log didn't keep the transaction, so each attempt is
+        // given a copy of it, signature and all
+        retry_transient(&self.backoff, || inner.register(copy_of(&transaction)))
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.inner.check_connection()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        self.inner.transactions()
+    }
+
+    fn record_provenance(&mut self, transaction_id: TransactionId, provenance: Provenance) {
+        self.inner.record_provenance(transaction_id, provenance);
+    }
+
+    fn provenance(&self, transaction_id: TransactionId) -> Result<Option<Provenance>, Error> {
+        retry_transient(&self.backoff, || self.inner.provenance(transaction_id))
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        let inner = &mut self.inner;
+        retry_transient(&self.backoff, || inner.purge(transaction_ids))
+    }
+}
+
+impl<T> TransactionLog for ProvenanceLog<T>
+where
+    T: TransactionLog,
+{
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        self.inner.transaction(transaction_id)
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.inner.register(transaction)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+        self.provenance.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.inner.check_connection()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        self.inner.transactions()
+    }
+
+    fn record_provenance(&mut self, transaction_id: TransactionId, provenance: Provenance) {
+        self.provenance.insert(transaction_id, provenance);
+    }
+
+    fn provenance(&self, transaction_id: TransactionId) -> Result<Option<Provenance>, Error> {
+        Ok(self.provenance.get(&transaction_id).cloned())
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        let purged = self.inner.purge(transaction_ids)?;
+        for transaction_id in transaction_ids {
+            self.provenance.remove(transaction_id);
+        }
+        Ok(purged)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::hash_map::DefaultHasher, hash::BuildHasherDefault, time::Duration};
+
     use rust_decimal_macros::dec;
 
-    use crate::types::{Transaction, TransactionId};
+    use crate::types::{CapacityHint, RawClientId, RawTransactionId, Signature, DECIMAL_SCALE};
 
     use super::*;
 
+    /// The transaction the tests' deposits and withdrawals are made in
+    const TX: TransactionId = TransactionId(1);
+
     #[test]
     fn test_deposit() {
         let mut account = Account::new(44.into());
-        account.deposit(dec!(4.35)).unwrap();
+        account
+            .deposit(Money::new(dec!(4.35), Currency::NONE), TX, DECIMAL_SCALE)
+            .unwrap();
         assert_eq!(account.funds_available(), dec!(4.35));
-        account.deposit(dec!(2.47724244)).unwrap();
+        account
+            .deposit(
+                Money::new(dec!(2.47724244), Currency::NONE),
+                TX,
+                DECIMAL_SCALE,
+            )
+            .unwrap();
         assert_eq!(account.funds_available(), dec!(6.8272));
         assert_eq!(account.funds_held(), dec!(0));
     }
@@ -206,8 +553,16 @@ mod tests {
     #[test]
     fn test_withdrawal() {
         let mut account = Account::new(35.into());
-        account.deposit(dec!(44.865)).unwrap();
-        account.withdraw(dec!(2.47724244)).unwrap();
+        account
+            .deposit(Money::new(dec!(44.865), Currency::NONE), TX, DECIMAL_SCALE)
+            .unwrap();
+        account
+            .withdraw(
+                Money::new(dec!(2.47724244), Currency::NONE),
+                TX,
+                DECIMAL_SCALE,
+            )
+            .unwrap();
         assert_eq!(account.funds_available(), dec!(42.3878));
         assert_eq!(account.funds_held(), dec!(0));
     }
@@ -215,11 +570,13 @@ mod tests {
     #[test]
     fn test_dispute_and_resolve() {
         let mut account = Account::new(26.into());
-        account.deposit(dec!(2.8422)).unwrap();
-        account.dispute(dec!(2.8422));
+        account
+            .deposit(Money::new(dec!(2.8422), Currency::NONE), TX, DECIMAL_SCALE)
+            .unwrap();
+        account.dispute(dec!(2.8422), DECIMAL_SCALE);
         assert_eq!(account.funds_available(), dec!(0));
         assert_eq!(account.funds_held(), dec!(2.8422));
-        account.resolve(dec!(2.8422));
+        account.resolve(dec!(2.8422), DECIMAL_SCALE);
         assert_eq!(account.funds_available(), dec!(2.8422));
         assert_eq!(account.funds_held(), dec!(0));
     }
@@ -227,13 +584,49 @@ mod tests {
     #[test]
     fn test_chargeback_and_lock() {
         let mut account = Account::new(24.into());
-        account.deposit(dec!(4.652)).unwrap();
-        account.dispute(dec!(4.652));
+        account
+            .deposit(Money::new(dec!(4.652), Currency::NONE), TX, DECIMAL_SCALE)
+            .unwrap();
+        account.dispute(dec!(4.652), DECIMAL_SCALE);
         assert_eq!(account.funds_held(), dec!(4.652));
-        account.chargeback(dec!(4.652));
+        account.chargeback(dec!(4.652), DECIMAL_SCALE);
         assert_eq!(account.funds_held(), dec!(0));
         assert!(account.is_locked());
-        assert!(account.deposit(dec!(2.00)).is_err());
+        assert!(account
+            .deposit(Money::new(dec!(2.00), Currency::NONE), TX, DECIMAL_SCALE)
+            .is_err());
+    }
+
+    #[test]
+    fn test_currencies() {
+        let usd: Currency = "usd".parse().unwrap();
+        let eur: Currency = "EUR".parse().unwrap();
+        assert!("US".parse::<Currency>().is_err());
+        let client = ClientId::from(1);
+        let deposit = |tx: RawTransactionId, currency| {
+            let transaction_id = TransactionId::from(tx);
+            let deposit = Transaction::deposit(client, transaction_id, dec!(5)).unwrap();
+            TransactionState::from(deposit.with_currency(currency))
+        };
+        let mut book = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        apply_transaction(&mut book, &mut txnlog, &mut deposit(1, usd)).unwrap();
+        let balance = book.account(client).unwrap().balance();
+        assert_eq!(balance, Money::new(dec!(5), usd));
+        assert_eq!(balance.to_string(), "5.0000 USD");
+        for currency in [eur, Currency::NONE] {
+            let result = apply_transaction(&mut book, &mut txnlog, &mut deposit(2, currency));
+            assert!(matches!(
+                result,
+                Err(Error::Domain(DomainError::CurrencyMismatch { expected, found, .. }))
+                    if expected == usd && found == currency
+            ));
+        }
+        assert_eq!(balance.checked_add(Money::new(dec!(1), eur)), None);
+        assert_eq!(
+            balance.checked_sub(Money::new(dec!(1), usd)),
+            Some(Money::new(dec!(4), usd))
+        );
     }
 
     #[test]
@@ -248,11 +641,148 @@ mod tests {
         let mut book = MemoryAccountBook::new();
         let account = book.account_mut(25.into()).unwrap();
         assert_eq!(account.client_id, ClientId::from(25));
-        account.deposit(dec!(4.4444)).unwrap();
+        account
+            .deposit(Money::new(dec!(4.4444), Currency::NONE), TX, DECIMAL_SCALE)
+            .unwrap();
         let account = book.account_mut(25.into()).unwrap();
         assert_eq!(account.funds_available(), dec!(4.4444));
     }
 
+    #[test]
+    fn test_accounts_after() {
+        let mut book = MemoryAccountBook::new();
+        for client in [5, 1, 9, 3, 7] {
+            book.account(client.into()).unwrap();
+        }
+        let clients = |page: Vec<&Account>| -> Vec<RawClientId> {
+            page.iter().map(|account| account.client_id.0).collect()
+        };
+        assert_eq!(clients(book.accounts_after(None, 2).unwrap()), [1, 3]);
+        assert_eq!(
+            clients(book.accounts_after(Some(3.into()), 2).unwrap()),
+            [5, 7]
+        );
+        assert_eq!(
+            clients(book.accounts_after(Some(7.into()), 2).unwrap()),
+            [9]
+        );
+        assert!(book.accounts_after(Some(9.into()), 2).unwrap().is_empty());
+        assert!(book.accounts_after(None, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let mut accounts = MemoryAccountBook::with_capacity_and_hasher(16, hasher.clone());
+        let mut txnlog = MemoryTransactionLog::with_hasher(hasher);
+        assert!(accounts.accounts.capacity() >= 16);
+        let transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: ClientId::from(7),
+            transaction_id: TransactionId::from(1),
+            amount: Some(dec!(1.5)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        };
+        apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
+        assert_eq!(
+            accounts.account(7.into()).unwrap().funds_available(),
+            dec!(1.5)
+        );
+        assert!(txnlog.transaction(1.into()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_reserve_from_hint() {
+        let hint = CapacityHint::from_input_size(24_000_000);
+        assert_eq!(hint.transactions(), 1_000_000);
+        // Capped at the number of possible clients
+        #[cfg(not(feature = "wide-ids"))]
+        assert_eq!(hint.accounts(), 65536);
+        #[cfg(feature = "wide-ids")]
+        assert_eq!(hint.accounts(), 1_000_000);
+        let mut accounts = MemoryAccountBook::new();
+        let mut txnlog = MemoryTransactionLog::new();
+        accounts.reserve(hint.accounts());
+        txnlog.reserve(hint.transactions());
+        assert!(accounts.accounts.capacity() >= hint.accounts());
+        assert!(txnlog.transactions.capacity() >= 1_000_000);
+    }
+
+    #[test]
+    fn test_minor_units_log() {
+        // Wide IDs take up too much of the entry for it to halve the size
+        #[cfg(not(feature = "wide-ids"))]
+        assert!(
+            std::mem::size_of::<(TransactionId, MinorUnitsEntry)>()
+                <= std::mem::size_of::<(TransactionId, Transaction)>() / 2
+        );
+        let mut accounts = MemoryAccountBook::new();
+        let mut txnlog = MinorUnitsTransactionLog::new();
+        let transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: ClientId::from(3),
+            transaction_id: TransactionId::from(30),
+            amount: Some(dec!(12.3456)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        };
+        apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
+        let record = txnlog.transaction(30.into()).unwrap().unwrap();
+        assert_eq!(record.amount(), Some(dec!(12.3456)));
+        assert_eq!(record.client_id(), ClientId::from(3));
+        let transaction = Transaction {
+            transaction_type: TransactionType::Dispute,
+            client_id: ClientId::from(3),
+            transaction_id: TransactionId::from(30),
+            amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        };
+        apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
+        assert_eq!(
+            accounts.account(3.into()).unwrap().funds_held(),
+            dec!(12.3456)
+        );
+        let transaction = Transaction {
+            transaction_type: TransactionType::Deposit,
+            client_id: ClientId::from(3),
+            transaction_id: TransactionId::from(31),
+            amount: Some(Decimal::MAX),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        };
+        assert!(matches!(
+            txnlog.register(transaction),
+            Err(Error::Domain(DomainError::AmountOutOfRange(_)))
+        ));
+    }
+
+    #[test]
+    fn test_scales() {
+        let client = ClientId::from(1);
+        for (scale, held, empty) in [(2, "1.23", "0.00"), (8, "1.23456789", "0.00000000")] {
+            let mut book = MemoryAccountBook::new().with_scale(scale);
+            let mut txnlog = MinorUnitsTransactionLog::new().with_scale(scale);
+            let deposit =
+                Transaction::deposit(client, TransactionId::from(1), dec!(1.2345678912)).unwrap();
+            apply_transaction(&mut book, &mut txnlog, &mut deposit.into()).unwrap();
+            let dispute = Transaction::dispute(client, TransactionId::from(1));
+            apply_transaction(&mut book, &mut txnlog, &mut dispute.into()).unwrap();
+            let account = book.account(client).unwrap();
+            assert_eq!(account.funds_held().to_string(), held);
+            assert_eq!(account.funds_available().to_string(), empty);
+            assert_eq!(
+                book.account(ClientId::from(2)).unwrap().total().to_string(),
+                empty
+            );
+        }
+    }
+
     #[test]
     fn test_apply_deposit() {
         let mut accounts = MemoryAccountBook::new();
@@ -262,6 +792,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3311),
             amount: Some(dec!(24.22)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         let mut state = transaction.into();
         apply_transaction(&mut accounts, &mut txnlog, &mut state).unwrap();
@@ -282,6 +815,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3311),
             amount: Some(dec!(24.22)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let transaction = Transaction {
@@ -289,6 +825,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3312),
             amount: Some(dec!(0.21)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let transaction = Transaction {
@@ -296,6 +835,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3313),
             amount: Some(dec!(7.8484)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let transaction = Transaction {
@@ -303,6 +845,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3313),
             amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let account = accounts.account_mut(41.into()).unwrap();
@@ -314,6 +859,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3319),
             amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let account = accounts.account_mut(41.into()).unwrap();
@@ -324,6 +872,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3313),
             amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let account = accounts.account_mut(41.into()).unwrap();
@@ -334,6 +885,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3313),
             amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let transaction = Transaction {
@@ -341,6 +895,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3313),
             amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         apply_transaction(&mut accounts, &mut txnlog, &mut transaction.into()).unwrap();
         let account = accounts.account_mut(41.into()).unwrap();
@@ -351,6 +908,9 @@ mod tests {
             client_id: ClientId::from(41),
             transaction_id: TransactionId::from(3314),
             amount: Some(dec!(17.4219)),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
         };
         let mut state = transaction.into();
         assert!(apply_transaction(&mut accounts, &mut txnlog, &mut state).is_err());
@@ -361,4 +921,82 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_apply_atomic() {
+        /// A log that fails to register the next transaction
+        #[derive(Default)]
+        struct FailingLog(MemoryTransactionLog, bool);
+        impl TransactionLog for FailingLog {
+            fn transaction(
+                &self,
+                transaction_id: TransactionId,
+            ) -> Result<Option<TransactionRecord>, Error> {
+                self.0.transaction(transaction_id)
+            }
+
+            fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+                if std::mem::take(&mut self.1) {
+                    return Err(Error::Transient("unreachable".into()));
+                }
+                self.0.register(transaction)
+            }
+        }
+
+        let mut accounts = MemoryAccountBook::new();
+        let mut txnlog = FailingLog(MemoryTransactionLog::new(), true);
+        let transaction = Transaction::deposit(ClientId::from(5), TX, dec!(3.5)).unwrap();
+        let mut state = transaction.into();
+        assert!(apply_transaction(&mut accounts, &mut txnlog, &mut state).is_err());
+        assert_eq!(
+            accounts.account(5.into()).unwrap().funds_available(),
+            dec!(0)
+        );
+        assert_eq!(txnlog.transaction(TX).unwrap(), None);
+        assert!(matches!(state, TransactionState::NotApplied(_)));
+
+        // The same transaction can be tried again once the log recovers
+        apply_transaction(&mut accounts, &mut txnlog, &mut state).unwrap();
+        assert_eq!(
+            accounts.account(5.into()).unwrap().funds_available(),
+            dec!(3.5)
+        );
+        assert_eq!(
+            txnlog.transaction(TX).unwrap().unwrap().amount,
+            Some(dec!(3.5))
+        );
+    }
+
+    #[test]
+    fn test_retrying_register() {
+        /// A log that fails its first registration, and keeps every transaction it's given
+        #[derive(Default)]
+        struct FlakyLog(Vec<Transaction>);
+        impl TransactionLog for FlakyLog {
+            fn transaction(
+                &self,
+                _transaction_id: TransactionId,
+            ) -> Result<Option<TransactionRecord>, Error> {
+                Ok(None)
+            }
+
+            fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+                self.0.push(transaction);
+                if self.0.len() == 1 {
+                    return Err(Error::Transient("unreachable".into()));
+                }
+                Ok(())
+            }
+        }
+
+        let backoff = RetryWithBackoff::new(3, Duration::ZERO);
+        let mut txnlog = Retrying::new(FlakyLog::default(), backoff);
+        let transaction = Transaction::deposit(ClientId::from(5), TX, dec!(3.5))
+            .unwrap()
+            .with_currency("USD".parse().unwrap())
+            .with_signature(Signature([7; 64]));
+        txnlog.register(copy_of(&transaction)).unwrap();
+        // The retry is given the transaction as it was, not just what a record keeps
+        assert_eq!(txnlog.into_inner().0, [copy_of(&transaction), transaction]);
+    }
 }
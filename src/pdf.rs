@@ -0,0 +1,209 @@
+//! Client-facing statements rendered to PDF, behind the `pdf` feature.
+//!
+//! [`write_statement_pdf`](crate::pdf::write_statement_pdf) lays out a client's
+//! [history](crate::io::read_history) as a table of transactions with the balances after each,
+//! under a header naming the client, and finishes with the closing balances. Amounts are written
+//! in the client's [`NumberFormat`](crate::locale::NumberFormat). Pages are A4, using the standard
+//! Helvetica and Courier fonts, so nothing needs embedding.
+
+use std::io::Write;
+
+use pdf_writer::{Content, Name, Pdf, Rect, Ref, Str};
+
+use crate::{errors::Error, io::HistoryRow, locale::NumberFormat, types::ClientId};
+
+/// Width of an A4 page, in points
+const PAGE_WIDTH: f32 = 595.0;
+/// Height of an A4 page, in points
+const PAGE_HEIGHT: f32 = 842.0;
+/// Space around the edge of each page, in points
+const MARGIN: f32 = 50.0;
+/// Size of the header text
+const HEADER_SIZE: f32 = 16.0;
+/// Size of the table text
+const TABLE_SIZE: f32 = 9.0;
+/// Distance between table rows
+const LEADING: f32 = 12.0;
+/// Transactions listed on each page, leaving room for the header and the closing balances
+const ROWS_PER_PAGE: usize = 56;
+
+/// Name of the header font in each page's resources
+const HEADER_FONT: Name<'static> = Name(b"F1");
+/// Name of the table font in each page's resources
+const TABLE_FONT: Name<'static> = Name(b"F2");
+
+/// Outputs a client's statement as a PDF document, from their history as read by
+/// [`read_history`](crate::io::read_history), with amounts in the given format.
+///
+/// Each page has the client in its header, and the transactions continue from page to page. An
+/// empty history still produces a page, saying there were no transactions.
+/// # Errors
+/// If writing fails
+pub fn write_statement_pdf<W: Write>(
+    writer: &mut W,
+    client: ClientId,
+    history: &[HistoryRow],
+    format: &NumberFormat,
+) -> Result<(), Error> {
+    let mut next_id = Ref::new(1);
+    let catalog_id = next_id.bump();
+    let page_tree_id = next_id.bump();
+    let header_font_id = next_id.bump();
+    let table_font_id = next_id.bump();
+    let pages: Vec<&[HistoryRow]> = if history.is_empty() {
+        vec![&[]]
+    } else {
+        history.chunks(ROWS_PER_PAGE).collect()
+    };
+    // Each page and its content stream
+    let ids: Vec<(Ref, Ref)> = pages
+        .iter()
+        .map(|_| (next_id.bump(), next_id.bump()))
+        .collect();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(ids.iter().map(|(page_id, _)| *page_id))
+        .count(i32::try_from(pages.len()).unwrap_or(i32::MAX));
+    pdf.type1_font(header_font_id)
+        .base_font(Name(b"Helvetica-Bold"));
+    pdf.type1_font(table_font_id).base_font(Name(b"Courier"));
+    for (number, (rows, &(page_id, content_id))) in pages.iter().zip(&ids).enumerate() {
+        let mut page = pdf.page(page_id);
+        page.media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        page.resources()
+            .fonts()
+            .pair(HEADER_FONT, header_font_id)
+            .pair(TABLE_FONT, table_font_id);
+        drop(page);
+
+        let closing = history.last().filter(|_| number + 1 == pages.len());
+        let content = page_content(client, rows, (number + 1, pages.len()), closing, format);
+        pdf.stream(content_id, &content);
+    }
+    writer.write_all(&pdf.finish())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Lays out one page: the header, a table of transactions, and the closing balances if it's the
+/// last page
+fn page_content(
+    client: ClientId,
+    rows: &[HistoryRow],
+    (number, of): (usize, usize),
+    closing: Option<&HistoryRow>,
+    format: &NumberFormat,
+) -> Vec<u8> {
+    let mut content = Content::new();
+    content.begin_text();
+    content.set_font(HEADER_FONT, HEADER_SIZE);
+    content.next_line(MARGIN, PAGE_HEIGHT - MARGIN - HEADER_SIZE);
+    content.show(Str(format!("Statement for client {}", client.0).as_bytes()));
+
+    content.set_font(TABLE_FONT, TABLE_SIZE);
+    content.set_leading(LEADING);
+    content.next_line(0.0, -HEADER_SIZE);
+    content.show(Str(format!("Page {number} of {of}").as_bytes()));
+    content.next_line(0.0, -2.0 * LEADING);
+    let header = table_row(
+        "Type",
+        "Tx",
+        "Amount",
+        ["Available", "Held", "Total"],
+        "Locked",
+    );
+    content.show(Str(header.as_bytes()));
+    if rows.is_empty() {
+        content.next_line_show(Str(b"No transactions"));
+    }
+    for row in rows {
+        let amount = row
+            .amount
+            .map(|amount| format.amount(amount))
+            .unwrap_or_default();
+        let line = table_row(
+            row.transaction_type.name(),
+            &row.tx.0.to_string(),
+            &amount,
+            [row.available, row.held, row.total].map(|balance| format.amount(balance)),
+            if row.locked { "yes" } else { "no" },
+        );
+        content.next_line_show(Str(line.as_bytes()));
+    }
+    if let Some(closing) = closing {
+        content.next_line(0.0, -LEADING);
+        let line = format!(
+            "Closing balances: available {}, held {}, total {}{}",
+            format.amount(closing.available),
+            format.amount(closing.held),
+            format.amount(closing.total),
+            if closing.locked { " (locked)" } else { "" }
+        );
+        content.next_line_show(Str(line.as_bytes()));
+    }
+    content.end_text();
+    content.finish()
+}
+
+/// Formats a table row in fixed-width columns, for the monospaced table font
+fn table_row<S: AsRef<str>>(
+    transaction_type: &str,
+    tx: &str,
+    amount: &str,
+    balances: [S; 3],
+    locked: &str,
+) -> String {
+    let [available, held, total] = balances.each_ref().map(AsRef::as_ref);
+    format!(
+        "{transaction_type:<10} {tx:>10} {amount:>14} {available:>14} {held:>14} {total:>14} \
+         {locked:>6}"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::types::{TransactionId, TransactionType};
+
+    use super::*;
+
+    #[test]
+    fn test_statement_pdf() {
+        let row = HistoryRow {
+            transaction_type: TransactionType::Deposit,
+            client: ClientId(7),
+            tx: TransactionId(1),
+            amount: Some(dec!(5.0)),
+            available: dec!(5.0),
+            held: dec!(0),
+            total: dec!(5.0),
+            locked: false,
+        };
+        let mut output = vec![];
+        let rows = vec![row; ROWS_PER_PAGE + 1];
+        write_statement_pdf(&mut output, ClientId(7), &rows, &NumberFormat::CANONICAL).unwrap();
+        let pdf = String::from_utf8_lossy(&output);
+        assert!(pdf.starts_with("%PDF-"));
+        assert!(pdf.contains("/Count 2"));
+        assert!(pdf.contains("(Statement for client 7)"));
+        assert!(pdf.contains("(Page 2 of 2)"));
+        let row = "deposit             1         5.0000         5.0000         0.0000         5.0000     no";
+        assert!(pdf.contains(&format!("({row})")));
+        assert!(pdf.contains("(Closing balances: available 5.0000, held 0.0000, total 5.0000)"));
+
+        let mut output = vec![];
+        write_statement_pdf(&mut output, ClientId(7), &[], &NumberFormat::CANONICAL).unwrap();
+        assert!(String::from_utf8_lossy(&output).contains("(No transactions)"));
+
+        let mut output = vec![];
+        let german = NumberFormat::for_locale("de-DE").unwrap();
+        write_statement_pdf(&mut output, ClientId(7), &rows[..1], &german).unwrap();
+        let pdf = String::from_utf8_lossy(&output);
+        assert!(pdf.contains("(Closing balances: available 5,0000, held 0,0000, total 5,0000)"));
+    }
+}
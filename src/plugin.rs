@@ -0,0 +1,194 @@
+//! Report formats supplied from outside the crate, written by name.
+//!
+//! A [`ReportPlugin`](crate::plugin::ReportPlugin) is handed every account, every transaction in
+//! the transaction log, and the engine's running [stats](crate::metrics::EngineStats), and writes
+//! them out in whatever format it likes. Plugins can live in crates of their own: register one
+//! with [`Engine::add_report`](crate::engine::Engine::add_report), then
+//! [`Engine::write_report`](crate::engine::Engine::write_report) writes it by name, as
+//! `--report=name=file` does for the plugins the command line is built with.
+//! [`StatsReport`](crate::plugin::StatsReport) is built in, as `stats`.
+
+use std::{fmt::Debug, io::Write};
+
+use serde::Serialize;
+
+use crate::{
+    errors::Error,
+    metrics::{EngineStats, RunSummary},
+    types::{Account, TransactionRecord},
+};
+
+/// Everything a [`ReportPlugin`] has to work with
+pub struct ReportInput<'a> {
+    /// Every account, in no particular order
+    pub accounts: Box<dyn Iterator<Item = &'a Account> + 'a>,
+    /// Every transaction in the transaction log, in no particular order. Only deposits and
+    /// withdrawals are kept there, and only logs that can be read back in full yield any; see
+    /// [`TransactionLog::transactions`](crate::types::TransactionLog::transactions).
+    pub transactions: Box<dyn Iterator<Item = TransactionRecord> + 'a>,
+    /// The engine's running totals across all accounts
+    pub stats: EngineStats,
+    /// What the engine has done with everything it's loaded
+    pub summary: RunSummary,
+}
+
+/// A report format, written by name with
+/// [`Engine::write_report`](crate::engine::Engine::write_report)
+pub trait ReportPlugin {
+    /// The name the report is asked for by, eg `stats`
+    fn name(&self) -> &str;
+
+    /// Writes the report
+    /// # Errors
+    /// If the report can't be written
+    fn write_report(&mut self, input: ReportInput<'_>, writer: &mut dyn Write)
+        -> Result<(), Error>;
+}
+
+/// The report plugins registered on an engine
+#[derive(Default)]
+pub(crate) struct ReportPlugins {
+    /// Plugins, in the order they were added
+    plugins: Vec<Box<dyn ReportPlugin + Send>>,
+}
+
+impl ReportPlugins {
+    /// Adds a plugin, replacing any with the same name
+    pub(crate) fn insert(&mut self, plugin: Box<dyn ReportPlugin + Send>) {
+        match self
+            .plugins
+            .iter_mut()
+            .find(|existing| existing.name() == plugin.name())
+        {
+            Some(existing) => *existing = plugin,
+            None => self.plugins.push(plugin),
+        }
+    }
+
+    /// Returns the names of the plugins, in the order they were added
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.plugins.iter().map(|plugin| plugin.name()).collect()
+    }
+
+    /// Finds the plugin with the given name
+    pub(crate) fn get_mut(&mut self, name: &str) -> Result<&mut (dyn ReportPlugin + Send), Error> {
+        match self.plugins.iter_mut().find(|plugin| plugin.name() == name) {
+            Some(plugin) => Ok(plugin.as_mut()),
+            None => Err(Error::UnknownReport(name.to_string())),
+        }
+    }
+}
+
+impl Debug for ReportPlugins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// The figures in a [`StatsReport`]
+#[derive(Debug, Serialize)]
+struct Stats {
+    /// Number of accounts
+    accounts: u64,
+    /// Number of transactions in the transaction log
+    transactions: u64,
+    /// The engine's running totals
+    stats: EngineStats,
+}
+
+/// Writes the number of accounts and logged transactions, and the engine's
+/// [`EngineStats`], as a JSON object. Registered on the command line as `stats`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StatsReport;
+
+impl ReportPlugin for StatsReport {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn write_report(
+        &mut self,
+        input: ReportInput<'_>,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let stats = Stats {
+            accounts: input.accounts.count() as u64,
+            transactions: input.transactions.count() as u64,
+            stats: input.stats,
+        };
+        serde_json::to_writer(&mut *writer, &stats).map_err(std::io::Error::from)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MinorUnitsTransactionLog, TransactionType},
+    };
+
+    use super::*;
+
+    /// Lists the clients of locked accounts, and how many deposits there were
+    struct LockedClients;
+
+    impl ReportPlugin for LockedClients {
+        fn name(&self) -> &str {
+            "locked"
+        }
+
+        fn write_report(
+            &mut self,
+            input: ReportInput<'_>,
+            writer: &mut dyn Write,
+        ) -> Result<(), Error> {
+            let mut clients: Vec<_> = input
+                .accounts
+                .filter(|account| account.is_locked())
+                .map(|account| account.client_id().0)
+                .collect();
+            clients.sort_unstable();
+            let deposits = input
+                .transactions
+                .filter(|transaction| transaction.transaction_type == TransactionType::Deposit)
+                .count();
+            writeln!(writer, "{clients:?} {deposits} {}", input.summary.rows_read)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_report_plugins() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MinorUnitsTransactionLog::new());
+        engine.add_report(StatsReport);
+        engine.add_report(LockedClients);
+        assert_eq!(engine.report_names(), ["stats", "locked"]);
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,2,3,1.0
+dispute,2,2,
+chargeback,2,2,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+
+        let mut output = vec![];
+        engine.write_report("locked", &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "[2] 2 5\n");
+
+        let mut output = vec![];
+        engine.write_report("stats", &mut output).unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(stats["accounts"], 2);
+        assert_eq!(stats["transactions"], 3);
+        assert_eq!(stats["stats"]["held"], "0.0000");
+        assert_eq!(stats["stats"]["locked"], 1);
+
+        let err = engine.write_report("missing", &mut vec![]).unwrap_err();
+        assert_eq!(err.code(), "unknown_report");
+    }
+}
@@ -0,0 +1,140 @@
+//! Previewing what a transaction would do to its client's account before submitting it, eg so a
+//! front-end can warn that a withdrawal will be rejected.
+//!
+//! [`Engine::simulate`](crate::engine::Engine::simulate) runs a transaction through the same
+//! rules as applying it, on a copy of the account, and returns the account as it would be, in a
+//! [`ProjectedAccount`](crate::preview::ProjectedAccount), or the error it would be rejected with.
+//! Nothing is changed: the account book, transaction log, metrics and listeners are left alone.
+//! ```
+//! # use cashflow::{engine::Engine, errors::Error, types::*};
+//! # use rust_decimal_macros::dec;
+//! let mut engine = Engine::in_memory();
+//! engine.load_csv(&mut "type,client,tx,amount\ndeposit,1,1,5.0\n".as_bytes())?;
+//! let withdrawal = Transaction::withdrawal(ClientId::from(1), TransactionId::from(2), dec!(8))?;
+//! let projected = engine.simulate(&withdrawal)?;
+//! // The engine lets balances go negative, unless it validates transactions
+//! assert_eq!(projected.after.funds_available(), dec!(-3));
+//! # Ok::<(), Error>(())
+//! ```
+
+use crate::{
+    errors::{Error, Warning},
+    types::{Account, Provenance, Transaction, TransactionId, TransactionLog, TransactionRecord},
+};
+
+/// What an account would be if a transaction were applied, as found by
+/// [`Engine::simulate`](crate::engine::Engine::simulate)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectedAccount {
+    /// The account as it is. A client without an account yet gets an empty one.
+    pub before: Account,
+    /// The account as it would be once the transaction is applied
+    pub after: Account,
+    /// What applying the transaction would warn about
+    pub warnings: Vec<Warning>,
+}
+
+/// A transaction log that reads through to another, and forgets whatever's registered in it, so
+/// a transaction can be applied without changing the log
+#[derive(Debug)]
+pub(crate) struct ReadThrough<'a, T: ?Sized>(pub(crate) &'a T);
+
+impl<T: TransactionLog + ?Sized> TransactionLog for ReadThrough<'_, T> {
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        self.0.transaction(transaction_id)
+    }
+
+    fn register(&mut self, _transaction: Transaction) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.0.check_connection()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        self.0.transactions()
+    }
+
+    fn provenance(&self, transaction_id: TransactionId) -> Result<Option<Provenance>, Error> {
+        self.0.provenance(transaction_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::{Engine, EngineSettings},
+        errors::DomainError,
+        types::{ClientId, MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_simulate() {
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            EngineSettings {
+                validate_transactions: true,
+                ..EngineSettings::default()
+            },
+        );
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+
+        let withdrawal = Transaction::withdrawal(ClientId(1), TransactionId(2), dec!(2)).unwrap();
+        let projected = engine.simulate(&withdrawal).unwrap();
+        assert_eq!(projected.before.funds_available(), dec!(5));
+        assert_eq!(projected.after.funds_available(), dec!(3));
+        assert!(projected.warnings.is_empty());
+
+        // Rejected as it would be if applied
+        let withdrawal = Transaction::withdrawal(ClientId(1), TransactionId(2), dec!(6)).unwrap();
+        let err = engine.simulate(&withdrawal).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Domain(DomainError::InsufficientFunds { .. })
+        ));
+
+        // A dispute moves the funds it refers to, and a client without an account gets an empty one
+        let projected = engine
+            .simulate(&Transaction::dispute(ClientId(1), TransactionId(1)))
+            .unwrap();
+        assert_eq!(projected.after.funds_held(), dec!(5));
+        let projected = engine
+            .simulate(&Transaction::deposit(ClientId(7), TransactionId(3), dec!(1)).unwrap())
+            .unwrap();
+        assert_eq!(projected.before.total(), dec!(0));
+        assert_eq!(projected.after.total(), dec!(1));
+
+        // Nothing was changed
+        assert_eq!(engine.account_book().accounts.len(), 1);
+        assert_eq!(engine.transaction_log().transactions().count(), 1);
+        assert_eq!(engine.metrics().applied.total(), 1);
+        let account = &engine.account_book().accounts[&ClientId(1)];
+        assert_eq!(account.funds_available(), dec!(5));
+        assert_eq!(account.funds_held(), dec!(0));
+    }
+
+    #[test]
+    fn test_simulate_warnings() {
+        let engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let projected = engine
+            .simulate(&Transaction::chargeback(ClientId(1), TransactionId(9)))
+            .unwrap();
+        assert_eq!(projected.after, projected.before);
+        assert!(matches!(
+            projected.warnings[..],
+            [Warning::UnknownReference { .. }]
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+//! Per-client limits on how fast transactions are accepted, so an upstream flooding one account
+//! can't monopolise the engine.
+//!
+//! Each client gets a token bucket holding up to [`burst`](crate::ratelimit::RateLimit::burst)
+//! tokens, refilled with one token every
+//! [`refill_every`](crate::ratelimit::RateLimit::refill_every). Every transaction handed to the
+//! engine takes a token from its client's bucket. When the bucket is empty, the transaction is
+//! either rejected with [`Error::RateLimited`](crate::errors::Error::RateLimited), or held until
+//! a token is available, depending on [`Excess`](crate::ratelimit::Excess).
+//!
+//! Set a limit with [`EngineSettings::rate_limit`](crate::engine::EngineSettings::rate_limit).
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{clock::Clock, errors::Error, types::ClientId};
+
+/// What to do with transactions beyond a client's limit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Excess {
+    /// Reject them with [`Error::RateLimited`]
+    #[default]
+    Reject,
+    /// Wait for the client's bucket to refill, then apply them. Everything behind them waits too,
+    /// so this slows the whole input down to the flooding client's pace.
+    Wait,
+}
+
+/// A per-client token bucket limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Most transactions a client can have accepted in a burst, after a quiet spell
+    pub burst: u32,
+    /// How often a client earns another transaction, up to the burst. Zero means no limit.
+    pub refill_every: Duration,
+    /// What to do with transactions beyond the limit
+    pub on_excess: Excess,
+}
+
+impl RateLimit {
+    /// Creates a limit of the supplied number of transactions per second for each client, allowing
+    /// a second's worth in a burst, and rejecting any more
+    #[must_use]
+    pub fn per_second(rate: u32) -> Self {
+        Self {
+            burst: rate,
+            refill_every: Duration::from_secs(1) / rate.max(1),
+            on_excess: Excess::Reject,
+        }
+    }
+}
+
+/// A single client's bucket
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Tokens left
+    tokens: u32,
+    /// When the last token was added, or the bucket was last found full
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// Adds any tokens earned since the last refill
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let earned = elapsed.as_nanos() / limit.refill_every.as_nanos();
+        let tokens = u128::from(self.tokens) + earned;
+        if tokens >= u128::from(limit.burst) {
+            self.tokens = limit.burst;
+            self.refilled = now;
+        } else {
+            // Less than the burst, so fits
+            self.tokens = u32::try_from(tokens).unwrap_or(limit.burst);
+            self.refilled += limit.refill_every * u32::try_from(earned).unwrap_or(u32::MAX);
+        }
+    }
+
+    /// Takes a token, or returns how long until one will be available
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens == 0 {
+            return Err((self.refilled + limit.refill_every).saturating_duration_since(now));
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+}
+
+/// Every client's bucket under a [`RateLimit`]
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// The limit applied to each client
+    limit: RateLimit,
+    /// Buckets of clients seen so far
+    buckets: HashMap<ClientId, Bucket>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter with every client's bucket full
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the client's bucket, waiting on the clock for one if the limit says to
+    /// # Errors
+    /// [`Error::RateLimited`] if the bucket's empty and excess transactions are rejected
+    pub(crate) fn admit(&mut self, client: ClientId, clock: &dyn Clock) -> Result<(), Error> {
+        if self.limit.refill_every.is_zero() {
+            return Ok(());
+        }
+        let limit = self.limit;
+        let now = clock.instant();
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: limit.burst,
+            refilled: now,
+        });
+        loop {
+            match (bucket.take(&limit, clock.instant()), limit.on_excess) {
+                (Ok(()), _) => return Ok(()),
+                (Err(retry_after), Excess::Reject) => {
+                    return Err(Error::RateLimited {
+                        client,
+                        retry_after,
+                    })
+                }
+                (Err(retry_after), Excess::Wait) => clock.sleep(retry_after),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limit = RateLimit {
+            burst: 2,
+            refill_every: Duration::from_secs(10),
+            on_excess: Excess::Reject,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2,
+            refilled: start,
+        };
+        assert_eq!(bucket.take(&limit, start), Ok(()));
+        assert_eq!(bucket.take(&limit, start), Ok(()));
+        assert_eq!(
+            bucket.take(&limit, start + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        // One token earned, with 5s towards the next
+        assert_eq!(bucket.take(&limit, start + Duration::from_secs(15)), Ok(()));
+        assert_eq!(
+            bucket.take(&limit, start + Duration::from_secs(15)),
+            Err(Duration::from_secs(5))
+        );
+        // Never more than the burst, however long it's been
+        let later = start + Duration::from_secs(1000);
+        assert_eq!(bucket.take(&limit, later), Ok(()));
+        assert_eq!(bucket.take(&limit, later), Ok(()));
+        assert!(bucket.take(&limit, later).is_err());
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            burst: 1,
+            refill_every: Duration::from_millis(20),
+            on_excess: Excess::Wait,
+        });
+        let clock = MockClock::new(UNIX_EPOCH);
+        let start = clock.instant();
+        limiter.admit(ClientId(1), &clock).unwrap();
+        limiter.admit(ClientId(2), &clock).unwrap();
+        assert_eq!(clock.instant(), start);
+        limiter.admit(ClientId(1), &clock).unwrap();
+        assert_eq!(clock.instant() - start, Duration::from_millis(20));
+    }
+}
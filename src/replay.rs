@@ -0,0 +1,437 @@
+//! Recording a run's input and decisions, and replaying them, so a bug seen on a huge feed can be
+//! reproduced from a single file.
+//!
+//! A [`ReplayRecorder`](crate::replay::ReplayRecorder) writes a header line, then one JSON
+//! object per row an [`Engine`](crate::engine::Engine) handles, in the order it handled them:
+//! the row's fields and the [`Outcome`](crate::replay::Outcome). For example:
+//! ```json
+//! {"cashflow_replay":1,"validate_transactions":false}
+//! {"row":["deposit","1","1","5.0"],"outcome":"applied"}
+//! {"row":["teleport","1","2","5.0"],"outcome":"unparsed","code":"parse"}
+//! {"row":["withdrawal","2","3","1.0"],"outcome":"rejected","code":"locked"}
+//! ```
+//! [`replay`](crate::replay::replay) feeds those rows to a fresh engine with the same settings,
+//! and reports the first row where the outcome differs.
+//!
+//! Rows are recorded as the engine sees them, so the order is the order they were applied in, even
+//! when several inputs were merged. A row that stops a [`Strict`](crate::errors::Strict) run
+//! without being parsed never reaches the recorder, so isn't recorded.
+
+use std::{
+    io::{BufRead, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use csv::ByteRecord;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::{Engine, EngineSettings},
+    errors::{Error, ErrorAction, ErrorPolicy, FailedRow},
+    events::EventListener,
+    types::{Account, AccountBook, TransactionLog, TransactionRecord},
+};
+
+/// Version of the replay format written by this crate
+const FORMAT_VERSION: u32 = 1;
+
+/// The first line of a replay file
+#[derive(Debug, Serialize, Deserialize)]
+struct ReplayHeader {
+    /// Version of the format
+    cashflow_replay: u32,
+    /// See [`EngineSettings::validate_transactions`], the only setting that changes decisions
+    validate_transactions: bool,
+}
+
+/// What an engine did with a row
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    /// The transaction was applied
+    Applied,
+    /// The transaction was applied, but changed nothing since the transaction it refers to is
+    /// missing
+    Ignored,
+    /// The transaction was rejected
+    Rejected {
+        /// The error's [code](Error::code)
+        code: String,
+    },
+    /// The row couldn't be parsed, and was skipped
+    Unparsed {
+        /// The error's [code](Error::code)
+        code: String,
+    },
+}
+
+/// A single row of a replay file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReplayEntry {
+    /// The row's fields, in `type,client,tx,amount` order for rows that were parsed
+    pub row: Vec<String>,
+    /// What was done with it
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl ReplayEntry {
+    /// Creates an entry for a transaction the engine parsed
+    fn parsed(transaction: &TransactionRecord, outcome: Outcome) -> Self {
+        let row = vec![
+            transaction.transaction_type().to_string(),
+            transaction.client_id.0.to_string(),
+            transaction.transaction_id.0.to_string(),
+            transaction
+                .amount()
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+        ];
+        Self { row, outcome }
+    }
+
+    /// Creates an entry for a transaction the engine rejected
+    fn rejected(transaction: &TransactionRecord, error: &Error) -> Self {
+        let outcome = Outcome::Rejected {
+            code: error.code().to_string(),
+        };
+        Self::parsed(transaction, outcome)
+    }
+
+    /// Creates an entry for a row the engine couldn't parse
+    fn unparsed(record: &ByteRecord, error: &Error) -> Self {
+        Self {
+            row: record
+                .iter()
+                .map(|field| String::from_utf8_lossy(field).into_owned())
+                .collect(),
+            outcome: Outcome::Unparsed {
+                code: error.code().to_string(),
+            },
+        }
+    }
+}
+
+/// Hands an entry for every row an engine handles to a closure
+#[derive(Debug)]
+struct EntryListener<F>(F);
+
+impl<F: FnMut(ReplayEntry)> EventListener for EntryListener<F> {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        (self.0)(ReplayEntry::parsed(transaction, Outcome::Applied));
+    }
+
+    fn on_missing_reference(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        (self.0)(ReplayEntry::parsed(transaction, Outcome::Ignored));
+    }
+
+    fn on_rejected(&mut self, transaction: &TransactionRecord, error: &Error) {
+        (self.0)(ReplayEntry::rejected(transaction, error));
+    }
+
+    fn on_unparsed(&mut self, record: &ByteRecord, error: &Error) {
+        (self.0)(ReplayEntry::unparsed(record, error));
+    }
+}
+
+/// The writer behind a [`ReplayRecorder`] and its clones
+#[derive(Debug)]
+struct RecorderWriter<W> {
+    /// Where entries are written
+    writer: W,
+    /// The first error hit while writing, after which nothing more is written
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> RecorderWriter<W> {
+    /// Writes a value as a line of JSON, unless writing has already failed
+    fn write_line<V: Serialize>(&mut self, value: &V) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_writer(&mut self.writer, value)
+            .map_err(std::io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        self.error = result.err();
+    }
+}
+
+/// Records every row the engine it's registered with handles, to a replay file.
+///
+/// Register it with an engine before loading anything, since replays start from empty storage.
+/// As with an [`AuditLog`](crate::audit::AuditLog), clones share the same output, so keep one to
+/// call [`finish`](Self::finish) on.
+#[derive(Debug)]
+pub struct ReplayRecorder<W> {
+    /// Shared with every clone
+    inner: Arc<Mutex<RecorderWriter<W>>>,
+}
+
+impl<W> Clone for ReplayRecorder<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> ReplayRecorder<W> {
+    /// Creates a recorder writing to the supplied writer, for an engine with the supplied
+    /// settings
+    #[must_use]
+    pub fn new(writer: W, settings: &EngineSettings) -> Self {
+        let mut inner = RecorderWriter {
+            writer,
+            error: None,
+        };
+        inner.write_line(&ReplayHeader {
+            cashflow_replay: FORMAT_VERSION,
+            validate_transactions: settings.validate_transactions,
+        });
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Flushes everything written so far
+    /// # Errors
+    /// If any entry failed to be written (in which case nothing after it was written either), or
+    /// flushing fails
+    pub fn finish(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(err) = inner.error.take() {
+            return Err(err.into());
+        }
+        Ok(inner.writer.flush()?)
+    }
+
+    /// Writes a single entry
+    fn record(&self, entry: &ReplayEntry) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_line(entry);
+    }
+}
+
+impl<W: Write> EventListener for ReplayRecorder<W> {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        self.record(&ReplayEntry::parsed(transaction, Outcome::Applied));
+    }
+
+    fn on_missing_reference(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        self.record(&ReplayEntry::parsed(transaction, Outcome::Ignored));
+    }
+
+    fn on_rejected(&mut self, transaction: &TransactionRecord, error: &Error) {
+        self.record(&ReplayEntry::rejected(transaction, error));
+    }
+
+    fn on_unparsed(&mut self, record: &ByteRecord, error: &Error) {
+        self.record(&ReplayEntry::unparsed(record, error));
+    }
+}
+
+/// Skips every failed row, since a replay needs to see every outcome
+#[derive(Debug)]
+struct SkipAll;
+
+impl ErrorPolicy for SkipAll {
+    fn on_error(&mut self, _row: FailedRow<'_>, _error: &Error, _attempt: u32) -> ErrorAction {
+        ErrorAction::Continue
+    }
+}
+
+/// Where a replay first went differently to its recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Position of the row in the replay file, counting from 1 after the header
+    pub row: u64,
+    /// The row as recorded, or `None` if the replay handled more rows than were recorded
+    pub recorded: Option<ReplayEntry>,
+    /// The row as replayed, or `None` if the replay handled fewer rows than were recorded
+    pub replayed: Option<ReplayEntry>,
+}
+
+/// The result of [`replay`]ing a recording
+#[derive(Debug)]
+pub struct Replay<A, T> {
+    /// The engine the rows were replayed into, for inspecting the resulting accounts
+    pub engine: Engine<A, T>,
+    /// Number of rows replayed
+    pub rows: u64,
+    /// The first row whose outcome differed from the recording, if any did
+    pub divergence: Option<Divergence>,
+}
+
+/// Replays a recording made by a [`ReplayRecorder`] into a fresh engine around the supplied
+/// (empty) storage, with the recorded settings, and compares each outcome with the recording.
+///
+/// Every row is replayed, whatever its outcome, so the comparison carries on past rejections.
+/// # Errors
+/// If the recording can't be read or isn't a replay file, or the engine fails to load the rows
+pub fn replay<R, A, T>(
+    reader: R,
+    account_book: A,
+    transaction_log: T,
+) -> Result<Replay<A, T>, Error>
+where
+    R: BufRead,
+    A: AccountBook,
+    T: TransactionLog,
+{
+    let mut lines = reader.lines();
+    let header_line = lines.next().transpose()?.unwrap_or_default();
+    let header: ReplayHeader = serde_json::from_str(&header_line).map_err(std::io::Error::from)?;
+    if header.cashflow_replay != FORMAT_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unsupported replay format {}", header.cashflow_replay),
+        )
+        .into());
+    }
+    let mut recorded = vec![];
+    for line in lines {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: ReplayEntry = serde_json::from_str(&line).map_err(std::io::Error::from)?;
+        recorded.push(entry);
+    }
+
+    let mut input = csv::WriterBuilder::new().flexible(true).from_writer(vec![]);
+    input.write_record(["type", "client", "tx", "amount"])?;
+    for entry in &recorded {
+        input.write_record(&entry.row)?;
+    }
+    let input = input
+        .into_inner()
+        .map_err(csv::IntoInnerError::into_error)?;
+
+    let settings = EngineSettings {
+        validate_transactions: header.validate_transactions,
+        ..EngineSettings::default()
+    };
+    let mut engine = Engine::with_settings(account_book, transaction_log, settings);
+    engine.set_error_policy(SkipAll);
+    let replayed = Arc::new(Mutex::new(vec![]));
+    let sink = Arc::clone(&replayed);
+    engine.add_listener(EntryListener(move |entry| {
+        sink.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(entry);
+    }));
+    engine.load_named_csv("replay", &mut input.as_slice())?;
+
+    let replayed = std::mem::take(&mut *replayed.lock().unwrap_or_else(PoisonError::into_inner));
+    let rows = replayed.len() as u64;
+    let divergence = (0..recorded.len().max(replayed.len()))
+        .map(|index| (index, recorded.get(index), replayed.get(index)))
+        .find(|(_, recorded, replayed)| recorded != replayed)
+        .map(|(index, recorded, replayed)| Divergence {
+            row: index as u64 + 1,
+            recorded: recorded.cloned(),
+            replayed: replayed.cloned(),
+        });
+    Ok(Replay {
+        engine,
+        rows,
+        divergence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        errors::SkipAndCollect,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    /// Loads some input into an engine with a recorder, returning the recording
+    fn record(input: &str) -> Vec<u8> {
+        let settings = EngineSettings {
+            validate_transactions: true,
+            ..EngineSettings::default()
+        };
+        let mut engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings.clone(),
+        );
+        engine.set_error_policy(SkipAndCollect::new());
+        let recorder = ReplayRecorder::new(vec![], &settings);
+        engine.add_listener(recorder.clone());
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        recorder.finish().unwrap();
+        drop(engine);
+        Arc::try_unwrap(recorder.inner)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .writer
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+teleport,1,2,5.0
+withdrawal,1,3,9.0
+dispute,1,7,
+chargeback,1,1,
+";
+        let recording = record(input);
+        let text = String::from_utf8(recording.clone()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"cashflow_replay":1,"validate_transactions":true}"#,
+                r#"{"row":["deposit","1","1","5.0"],"outcome":"applied"}"#,
+                r#"{"row":["teleport","1","2","5.0"],"outcome":"unparsed","code":"parse"}"#,
+                r#"{"row":["withdrawal","1","3","9.0"],"outcome":"rejected","code":"insufficient_funds"}"#,
+                r#"{"row":["dispute","1","7",""],"outcome":"rejected","code":"unknown_reference"}"#,
+                r#"{"row":["chargeback","1","1",""],"outcome":"applied"}"#,
+            ]
+        );
+
+        let result = replay(
+            Cursor::new(&recording),
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+        )
+        .unwrap();
+        assert_eq!(result.rows, 5);
+        assert_eq!(result.divergence, None);
+        assert_eq!(result.engine.account_book().accounts.len(), 1);
+
+        // Tampering with a recorded outcome shows up as a divergence at that row
+        let tampered = text.replace("insufficient_funds", "locked");
+        let result = replay(
+            Cursor::new(tampered),
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+        )
+        .unwrap();
+        let divergence = result.divergence.unwrap();
+        assert_eq!(divergence.row, 3);
+        assert_eq!(
+            divergence.replayed.unwrap().outcome,
+            Outcome::Rejected {
+                code: "insufficient_funds".to_string()
+            }
+        );
+
+        assert!(replay(
+            Cursor::new("{}\n"),
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,1895 @@
+//! Richer reports on accounts than the flat CSV in [`io`](crate::io), for dashboards and the
+//! like.
+//!
+//! An [`AccountActivity`](crate::report::AccountActivity) registered with an engine keeps track of
+//! what it can't get from the accounts alone: each account's open disputes, and its most recent
+//! transactions. [`write_accounts_json`](crate::report::write_accounts_json) then nests those
+//! inside each account:
+//! ```json
+//! {"accounts":[{"client":1,"available":"0.0000","held":"5.0000","total":"5.0000","locked":false,
+//!   "disputes":[{"tx":1,"held":"5.0000"}],
+//!   "recent":[{"type":"deposit","tx":1,"amount":"5.0"},{"type":"dispute","tx":1,"amount":null}]}]}
+//! ```
+//!
+//! For the figures finance asks for after a run, a [`Totals`](crate::report::Totals) adds up what
+//! was applied, by type, for a [`TotalsReport`](crate::report::TotalsReport) that includes the
+//! balances across all accounts and the largest accounts.
+//!
+//! For risk reviews, [`top_accounts`](crate::report::top_accounts) ranks the accounts with the
+//! most total funds, the most held, and the most overdrawn.
+//!
+//! An [`AccountLocks`](crate::report::AccountLocks) registered with an engine notes which
+//! chargeback locked each account, and when, for
+//! [`locked_accounts`](crate::report::locked_accounts) to list every locked account with the
+//! chargeback's amount from the transaction log and how long it's been locked.
+//!
+//! An `AccountActivity` also notes when each dispute was opened, so
+//! [`AccountActivity::dispute_aging`](crate::report::AccountActivity::dispute_aging) can bucket
+//! the open disputes by age, with the funds held in each bucket.
+//!
+//! For an at-a-glance picture of the portfolio,
+//! [`balance_distribution`](crate::report::balance_distribution) buckets accounts by total funds,
+//! counting and adding up the accounts in each bucket.
+//!
+//! [`segment_accounts`](crate::report::segment_accounts) sorts accounts into segments, like
+//! locked, high value and dormant, using the transaction counts an `AccountActivity` keeps, with
+//! the number of accounts and their funds in each.
+//!
+//! For plotting money flow over time, a [`Flows`](crate::report::Flows) registered with an engine
+//! adds up deposits, withdrawals, disputes opened and chargebacks per hour or day. Transactions are
+//! bucketed by when the engine applied them, going by the tracker's clock, not by their own
+//! timestamps.
+//!
+//! For charting a run as it goes, a [`FundsSeries`](crate::report::FundsSeries) writes the funds
+//! available and held across all accounts, and the number locked, to CSV every so many
+//! transactions or so often.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Display,
+    io::Write,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    clock::{Clock, SharedClock},
+    errors::Error,
+    events::{DomainEvent, DomainEventKind, EventListener},
+    io::{AccountColumn, ReportOptions},
+    locale::{LocalizedDisplay, NumberFormat},
+    metrics::{AccountGauges, TransactionAmounts, TransactionCounts},
+    types::{
+        Account, AccountBook, ClientId, TransactionId, TransactionLog, TransactionRecord,
+        TransactionType, DECIMAL_SCALE,
+    },
+};
+
+/// Funds held for a dispute that hasn't been resolved or charged back
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct OpenDispute {
+    /// The disputed transaction
+    pub tx: TransactionId,
+    /// The funds held for it
+    pub held: Decimal,
+}
+
+/// A transaction applied to an account, as it came in
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct RecentTransaction {
+    /// The transaction's type
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    /// The transaction's ID, or for disputes, resolves and chargebacks, the ID referred to
+    pub tx: TransactionId,
+    /// The transaction's amount, as given
+    pub amount: Option<Decimal>,
+}
+
+/// What's been happening to one account
+#[derive(Debug, Default, Clone)]
+struct Activity {
+    /// Number of transactions applied
+    applied: u64,
+    /// Open disputes, oldest first, with when they were opened. A transaction disputed more than
+    /// once is held more than once.
+    disputes: Vec<(OpenDispute, SystemTime)>,
+    /// The most recent transactions applied, oldest first
+    recent: VecDeque<RecentTransaction>,
+}
+
+/// The activity behind an [`AccountActivity`] and its clones
+#[derive(Debug)]
+struct ActivityState {
+    /// How many recent transactions to keep for each account
+    recent: usize,
+    /// Every account's activity
+    accounts: HashMap<ClientId, Activity>,
+}
+
+/// Keeps track of each account's open disputes and recent transactions as an engine applies
+/// them, for [`write_accounts_json`].
+///
+/// As with an [`AuditLog`](crate::audit::AuditLog), clones share the same state, so keep one to
+/// report with after registering another with the engine.
+#[derive(Debug)]
+pub struct AccountActivity {
+    /// Shared with clones
+    inner: Arc<Mutex<ActivityState>>,
+    /// Where the time disputes were opened comes from
+    clock: SharedClock,
+}
+
+impl Clone for AccountActivity {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl AccountActivity {
+    /// Creates a tracker keeping up to `recent` of each account's most recent transactions
+    #[must_use]
+    pub fn new(recent: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ActivityState {
+                recent,
+                accounts: HashMap::new(),
+            })),
+            clock: SharedClock::default(),
+        }
+    }
+
+    /// Notes when disputes were opened by the supplied clock, rather than the system's
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Returns the client's open disputes, oldest first
+    #[must_use]
+    pub fn disputes(&self, client: ClientId) -> Vec<OpenDispute> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner
+            .accounts
+            .get(&client)
+            .map(open_disputes)
+            .unwrap_or_default()
+    }
+
+    /// Buckets every open dispute by how many whole days before `now` it was opened
+    #[must_use]
+    pub fn dispute_aging(&self, now: SystemTime) -> DisputeAging {
+        let mut buckets = DisputeAging::BUCKETS.map(|(min_days, max_days)| AgingBucket {
+            min_days,
+            max_days,
+            disputes: 0,
+            held: Decimal::ZERO,
+        });
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let disputes = inner
+            .accounts
+            .values()
+            .flat_map(|activity| &activity.disputes);
+        for (dispute, opened_at) in disputes {
+            let days =
+                now.duration_since(*opened_at).unwrap_or_default().as_secs() / SECONDS_PER_DAY;
+            if let Some(bucket) = buckets
+                .iter_mut()
+                .find(|bucket| bucket.max_days.is_none_or(|max_days| days <= max_days))
+            {
+                bucket.disputes += 1;
+                bucket.held += dispute.held;
+            }
+        }
+        DisputeAging {
+            buckets: buckets.to_vec(),
+        }
+    }
+
+    /// Returns the number of the client's transactions applied
+    #[must_use]
+    pub fn applied(&self, client: ClientId) -> u64 {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner
+            .accounts
+            .get(&client)
+            .map_or(0, |activity| activity.applied)
+    }
+
+    /// Returns the client's most recent transactions, oldest first
+    #[must_use]
+    pub fn recent(&self, client: ClientId) -> Vec<RecentTransaction> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner
+            .accounts
+            .get(&client)
+            .map(|activity| activity.recent.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl EventListener for AccountActivity {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let limit = inner.recent;
+        let activity = inner.accounts.entry(transaction.client_id()).or_default();
+        activity.applied += 1;
+        if limit == 0 {
+            return;
+        }
+        let recent = &mut activity.recent;
+        if recent.len() == limit {
+            recent.pop_front();
+        }
+        recent.push_back(RecentTransaction {
+            transaction_type: transaction.transaction_type(),
+            tx: transaction.transaction_id(),
+            amount: transaction.amount(),
+        });
+    }
+
+    fn on_event(&mut self, event: &DomainEvent) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let disputes = &mut inner.accounts.entry(event.client_id).or_default().disputes;
+        match event.kind {
+            DomainEventKind::FundsHeld => disputes.push((
+                OpenDispute {
+                    tx: event.transaction_id,
+                    held: event.amount.unwrap_or_default(),
+                },
+                self.clock.now(),
+            )),
+            // Closes the oldest dispute of the transaction, if there's one open
+            DomainEventKind::FundsReleased | DomainEventKind::FundsChargedBack => {
+                if let Some(index) = disputes
+                    .iter()
+                    .position(|(dispute, _)| dispute.tx == event.transaction_id)
+                {
+                    disputes.remove(index);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Seconds in a day, for [`AccountActivity::dispute_aging`]
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Open disputes of similar age, in a [`DisputeAging`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AgingBucket {
+    /// Fewest whole days since the disputes in the bucket were opened
+    pub min_days: u64,
+    /// Most whole days since the disputes in the bucket were opened, or `None` for no limit
+    pub max_days: Option<u64>,
+    /// Number of disputes
+    pub disputes: u64,
+    /// Funds held for the disputes
+    pub held: Decimal,
+}
+
+/// Open disputes bucketed by age, for compliance monitoring, from
+/// [`AccountActivity::dispute_aging`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DisputeAging {
+    /// Buckets of 0–30, 31–60, 61–90 and over 90 days, youngest first
+    pub buckets: Vec<AgingBucket>,
+}
+
+impl DisputeAging {
+    /// The fewest and most days of each bucket
+    const BUCKETS: [(u64, Option<u64>); 4] =
+        [(0, Some(30)), (31, Some(60)), (61, Some(90)), (91, None)];
+}
+
+impl Display for DisputeAging {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_localized(f, &NumberFormat::CANONICAL)
+    }
+}
+
+impl LocalizedDisplay for DisputeAging {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        writeln!(f, "open disputes by age:")?;
+        for bucket in &self.buckets {
+            match bucket.max_days {
+                Some(max_days) => write!(f, "  {}-{max_days} days", bucket.min_days)?,
+                None => write!(f, "  over {} days", bucket.min_days - 1)?,
+            }
+            let held = format.amount(bucket.held);
+            writeln!(f, ": {} ({held} held)", bucket.disputes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns an account's open disputes, oldest first
+fn open_disputes(activity: &Activity) -> Vec<OpenDispute> {
+    activity
+        .disputes
+        .iter()
+        .map(|(dispute, _)| *dispute)
+        .collect()
+}
+
+/// A single account in [`write_accounts_json`] output. Columns that weren't selected are left
+/// out.
+#[derive(Debug, Serialize)]
+struct AccountReport {
+    /// The client's unique identifier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<ClientId>,
+    /// The amount of available funds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available: Option<Decimal>,
+    /// The amount of held funds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    held: Option<Decimal>,
+    /// The total amount of funds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<Decimal>,
+    /// Whether the account is locked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked: Option<bool>,
+    /// Open disputes, and the funds held for each
+    disputes: Vec<OpenDispute>,
+    /// The most recent transactions applied
+    recent: Vec<RecentTransaction>,
+}
+
+/// The whole of [`write_accounts_json`] output
+#[derive(Debug, Serialize)]
+struct AccountsReport {
+    /// Every account, by client
+    accounts: Vec<AccountReport>,
+}
+
+/// Outputs the state of the supplied accounts as a single JSON document, with each account's open
+/// disputes and recent transactions from `activity` nested inside it. Accounts are ordered by
+/// client.
+///
+/// Only accounts passing the filters in `options` are output. Its columns select which of each
+/// account's balance fields are included; disputes and recent transactions always are.
+///
+/// `activity` only knows about transactions applied while it was registered with the engine.
+/// # Errors
+/// If writing fails
+pub fn write_accounts_json<W, A>(
+    writer: &mut W,
+    account_book: &A,
+    activity: &AccountActivity,
+    options: &ReportOptions,
+) -> Result<(), Error>
+where
+    A: AccountBook + ?Sized,
+    W: Write,
+{
+    let inner = activity
+        .inner
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let columns = options.columns();
+    let mut selected: Vec<_> = account_book
+        .accounts()
+        .filter(|account| options.matches(account))
+        .collect();
+    selected.sort_unstable_by_key(|account| account.client_id());
+    let accounts = selected
+        .into_iter()
+        .map(|account| {
+            let column = |column| columns.contains(&column);
+            let activity = inner.accounts.get(&account.client_id());
+            AccountReport {
+                client: column(AccountColumn::Client).then(|| account.client_id()),
+                available: column(AccountColumn::Available)
+                    .then(|| options.amount(account.funds_available())),
+                held: column(AccountColumn::Held).then(|| options.amount(account.funds_held())),
+                total: column(AccountColumn::Total).then(|| options.amount(account.total())),
+                locked: column(AccountColumn::Locked).then(|| account.is_locked()),
+                disputes: activity.map(open_disputes).unwrap_or_default(),
+                recent: activity
+                    .map(|activity| activity.recent.iter().copied().collect())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect();
+    serde_json::to_writer(&mut *writer, &AccountsReport { accounts })
+        .map_err(std::io::Error::from)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// The counts and amounts behind a [`Totals`] and its clones
+#[derive(Debug, Default)]
+struct TotalsState {
+    /// Transactions applied, by type
+    applied: TransactionCounts,
+    /// Funds moved, by type
+    amounts: TransactionAmounts,
+}
+
+/// Adds up the transactions an engine applies, by type, for a [`TotalsReport`].
+///
+/// Clones share the same totals, so keep one to call [`report`](Self::report) on after
+/// registering another with the engine.
+#[derive(Debug, Default)]
+pub struct Totals {
+    /// Shared with clones
+    inner: Arc<Mutex<TotalsState>>,
+}
+
+impl Clone for Totals {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl Totals {
+    /// Creates a tracker with nothing counted yet
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports the totals so far, along with balances across the supplied accounts, and the
+    /// `largest` accounts by total funds
+    #[must_use]
+    pub fn report<A>(&self, account_book: &A, largest: usize) -> TotalsReport
+    where
+        A: AccountBook + ?Sized,
+    {
+        let gauges = AccountGauges::from_accounts(account_book.accounts());
+        let mut accounts: Vec<_> = account_book
+            .accounts()
+            .map(|account| LargestAccount {
+                client: account.client_id(),
+                total: account.total(),
+            })
+            .collect();
+        accounts.sort_unstable_by(|a, b| b.total.cmp(&a.total).then(a.client.cmp(&b.client)));
+        accounts.truncate(largest);
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        TotalsReport {
+            accounts: gauges.accounts,
+            locked: gauges.locked,
+            available: gauges.available,
+            held: gauges.held,
+            total: gauges.available.saturating_add(gauges.held),
+            applied: inner.applied,
+            amounts: inner.amounts,
+            largest: accounts,
+        }
+    }
+}
+
+impl EventListener for Totals {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.applied.increment(&transaction.transaction_type());
+    }
+
+    fn on_event(&mut self, event: &DomainEvent) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let amount = event.amount.unwrap_or_default();
+        let amounts = &mut inner.amounts;
+        // Saturating, like the gauges, since amounts moved can add up to more than a Decimal
+        // holds without any balance doing so
+        let sum = match event.kind {
+            DomainEventKind::FundsDeposited => &mut amounts.deposits,
+            DomainEventKind::FundsWithdrawn => &mut amounts.withdrawals,
+            DomainEventKind::FundsHeld => &mut amounts.disputes,
+            DomainEventKind::FundsReleased => &mut amounts.resolves,
+            DomainEventKind::FundsChargedBack => &mut amounts.chargebacks,
+            DomainEventKind::AccountLocked => return,
+        };
+        *sum = sum.saturating_add(amount);
+    }
+}
+
+/// One of the largest accounts in a [`TotalsReport`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LargestAccount {
+    /// The client
+    pub client: ClientId,
+    /// The account's total funds
+    pub total: Decimal,
+}
+
+/// Aggregate figures for a run, from [`Totals::report`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TotalsReport {
+    /// Number of accounts
+    pub accounts: u64,
+    /// Number of locked accounts
+    pub locked: u64,
+    /// Available funds across all accounts
+    pub available: Decimal,
+    /// Held funds across all accounts
+    pub held: Decimal,
+    /// Total funds across all accounts
+    pub total: Decimal,
+    /// Transactions applied, by type. Includes those that changed nothing.
+    pub applied: TransactionCounts,
+    /// Funds moved, by type
+    pub amounts: TransactionAmounts,
+    /// The accounts with the most total funds, largest first
+    pub largest: Vec<LargestAccount>,
+}
+
+impl Display for TotalsReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_localized(f, &NumberFormat::CANONICAL)
+    }
+}
+
+impl LocalizedDisplay for TotalsReport {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        writeln!(f, "accounts: {} ({} locked)", self.accounts, self.locked)?;
+        writeln!(f, "available: {}", format.amount(self.available))?;
+        writeln!(f, "held: {}", format.amount(self.held))?;
+        writeln!(f, "total: {}", format.amount(self.total))?;
+        writeln!(f, "applied: {}", self.applied)?;
+        self.amounts.fmt_localized(f, format)?;
+        writeln!(f, "largest accounts:")?;
+        for account in &self.largest {
+            writeln!(
+                f,
+                "  {}: {}",
+                account.client.0,
+                format.amount(account.total)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An account's client and the balance it was ranked by, in [`TopAccounts`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RankedAccount {
+    /// The client
+    pub client: ClientId,
+    /// The balance the account was ranked by
+    pub amount: Decimal,
+}
+
+/// The accounts most worth a look in a risk review, from [`top_accounts`]. Ties are broken by
+/// client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TopAccounts {
+    /// The accounts with the most total funds, largest first
+    pub by_total: Vec<RankedAccount>,
+    /// The accounts with the most funds held, largest first. Only accounts holding funds are
+    /// included.
+    pub by_held: Vec<RankedAccount>,
+    /// The accounts with the most negative balance, whichever of available and total funds is
+    /// lower, most negative first. Only accounts with a negative balance are included.
+    pub by_negative: Vec<RankedAccount>,
+}
+
+/// Ranks the top `n` accounts by total funds, by held funds, and by negative balance
+#[must_use]
+pub fn top_accounts<A>(account_book: &A, n: usize) -> TopAccounts
+where
+    A: AccountBook + ?Sized,
+{
+    let mut by_total = vec![];
+    let mut by_held = vec![];
+    let mut by_negative = vec![];
+    for account in account_book.accounts() {
+        let client = account.client_id();
+        by_total.push(RankedAccount {
+            client,
+            amount: account.total(),
+        });
+        if account.funds_held() > Decimal::ZERO {
+            by_held.push(RankedAccount {
+                client,
+                amount: account.funds_held(),
+            });
+        }
+        let lowest = account.funds_available().min(account.total());
+        if lowest < Decimal::ZERO {
+            by_negative.push(RankedAccount {
+                client,
+                amount: lowest,
+            });
+        }
+    }
+    TopAccounts {
+        by_total: top(by_total, n, |a, b| b.amount.cmp(&a.amount)),
+        by_held: top(by_held, n, |a, b| b.amount.cmp(&a.amount)),
+        by_negative: top(by_negative, n, |a, b| a.amount.cmp(&b.amount)),
+    }
+}
+
+/// Keeps the first `n` accounts in the order given, then by client
+fn top(
+    mut accounts: Vec<RankedAccount>,
+    n: usize,
+    order: impl Fn(&RankedAccount, &RankedAccount) -> Ordering,
+) -> Vec<RankedAccount> {
+    let order = |a: &RankedAccount, b: &RankedAccount| order(a, b).then(a.client.cmp(&b.client));
+    if n < accounts.len() {
+        accounts.select_nth_unstable_by(n, order);
+        accounts.truncate(n);
+    }
+    accounts.sort_unstable_by(order);
+    accounts
+}
+
+impl Display for TopAccounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_localized(f, &NumberFormat::CANONICAL)
+    }
+}
+
+impl LocalizedDisplay for TopAccounts {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        for (heading, accounts) in [
+            ("largest total", &self.by_total),
+            ("most held", &self.by_held),
+            ("most negative", &self.by_negative),
+        ] {
+            writeln!(f, "{heading}:")?;
+            for account in accounts {
+                writeln!(
+                    f,
+                    "  {}: {}",
+                    account.client.0,
+                    format.amount(account.amount)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The chargeback that locked an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountLock {
+    /// The transaction charged back
+    pub tx: TransactionId,
+    /// When the chargeback was applied
+    pub locked_at: SystemTime,
+}
+
+/// Notes the chargeback that locks each account as an engine applies it, for
+/// [`locked_accounts`].
+///
+/// Clones share the same record of locks, so keep one to report with after registering another
+/// with the engine.
+#[derive(Debug, Default)]
+pub struct AccountLocks {
+    /// Shared with clones
+    inner: Arc<Mutex<HashMap<ClientId, AccountLock>>>,
+    /// Where the time accounts were locked comes from
+    clock: SharedClock,
+}
+
+impl Clone for AccountLocks {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl AccountLocks {
+    /// Creates a tracker with no locks noted yet
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notes when accounts were locked by the supplied clock, rather than the system's
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Returns the chargeback that locked the client's account, if it was seen
+    #[must_use]
+    pub fn lock(&self, client: ClientId) -> Option<AccountLock> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.get(&client).copied()
+    }
+}
+
+impl EventListener for AccountLocks {
+    fn on_account_locked(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.insert(
+            transaction.client_id(),
+            AccountLock {
+                tx: transaction.transaction_id(),
+                locked_at: self.clock.now(),
+            },
+        );
+    }
+}
+
+/// A locked account, with the chargeback that locked it, from [`locked_accounts`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LockedAccount {
+    /// The client
+    pub client: ClientId,
+    /// The transaction charged back, if the lock was seen by the [`AccountLocks`]
+    pub tx: Option<TransactionId>,
+    /// The amount charged back, to the account book's [scale](AccountBook::scale), if the
+    /// transaction is in the transaction log
+    pub amount: Option<Decimal>,
+    /// Whole seconds since the account was locked, if the lock was seen
+    pub locked_for_seconds: Option<u64>,
+    /// The account's available funds
+    pub available: Decimal,
+    /// The account's held funds
+    pub held: Decimal,
+    /// The account's total funds
+    pub total: Decimal,
+}
+
+/// Lists every locked account in the book, by client, with the chargeback that locked it and
+/// how long before `now` that was.
+///
+/// Accounts locked before `locks` was registered with the engine, such as those read from a
+/// baseline, are listed without a chargeback.
+/// # Errors
+/// If the transaction log can't be read
+pub fn locked_accounts<A, T>(
+    account_book: &A,
+    transaction_log: &T,
+    locks: &AccountLocks,
+    now: SystemTime,
+) -> Result<Vec<LockedAccount>, Error>
+where
+    A: AccountBook + ?Sized,
+    T: TransactionLog,
+{
+    let mut locked: Vec<_> = account_book
+        .accounts()
+        .filter(|account| account.is_locked())
+        .collect();
+    locked.sort_unstable_by_key(|account| account.client_id());
+    locked
+        .into_iter()
+        .map(|account| {
+            let lock = locks.lock(account.client_id());
+            let amount = match lock {
+                Some(lock) => transaction_log
+                    .transaction(lock.tx)?
+                    .and_then(|charged_back| charged_back.amount),
+                None => None,
+            }
+            .map(|mut amount| {
+                amount.rescale(account_book.scale());
+                amount
+            });
+            Ok(LockedAccount {
+                client: account.client_id(),
+                tx: lock.map(|lock| lock.tx),
+                amount,
+                locked_for_seconds: lock.map(|lock| {
+                    now.duration_since(lock.locked_at)
+                        .unwrap_or_default()
+                        .as_secs()
+                }),
+                available: account.funds_available(),
+                held: account.funds_held(),
+                total: account.total(),
+            })
+        })
+        .collect()
+}
+
+/// Outputs locked accounts to CSV, with the columns
+/// `client,tx,amount,locked_for_seconds,available,held,total`. Anything not known is left empty.
+/// # Errors
+/// If writing fails
+pub fn write_locked_accounts_csv<W: Write>(
+    writer: &mut W,
+    locked: &[LockedAccount],
+) -> Result<(), Error> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for account in locked {
+        csv_writer.serialize(account)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// Bounds between buckets in a [`BalanceDistribution`], for when no others are given
+pub const DEFAULT_BALANCE_BOUNDS: [Decimal; 5] = [
+    Decimal::ZERO,
+    Decimal::ONE_HUNDRED,
+    Decimal::ONE_THOUSAND,
+    Decimal::from_parts(10_000, 0, 0, false, 0),
+    Decimal::from_parts(100_000, 0, 0, false, 0),
+];
+
+/// Accounts with total funds in a range, in a [`BalanceDistribution`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BalanceBucket {
+    /// Least total funds of accounts in the bucket, or `None` for no limit
+    pub min: Option<Decimal>,
+    /// Total funds that accounts in the bucket are under, or `None` for no limit
+    pub max: Option<Decimal>,
+    /// Number of accounts
+    pub accounts: u64,
+    /// Total funds across the accounts
+    pub total: Decimal,
+}
+
+/// Accounts bucketed by total funds, from [`balance_distribution`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BalanceDistribution {
+    /// Buckets in ascending order, from everything under the lowest bound to everything at or over
+    /// the highest
+    pub buckets: Vec<BalanceBucket>,
+}
+
+/// Buckets accounts by total funds. Each bound starts a new bucket, so there's one more bucket than
+/// there are bounds; see [`DEFAULT_BALANCE_BOUNDS`] for a typical set.
+///
+/// Bounds are sorted and deduplicated first.
+#[must_use]
+pub fn balance_distribution<A>(account_book: &A, bounds: &[Decimal]) -> BalanceDistribution
+where
+    A: AccountBook + ?Sized,
+{
+    let mut bounds = bounds.to_vec();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut buckets: Vec<_> = (0..=bounds.len())
+        .map(|index| BalanceBucket {
+            min: index.checked_sub(1).map(|below| bounds[below]),
+            max: bounds.get(index).copied(),
+            accounts: 0,
+            total: Decimal::new(0, account_book.scale()),
+        })
+        .collect();
+    for account in account_book.accounts() {
+        let index = bounds.partition_point(|bound| *bound <= account.total());
+        buckets[index].accounts += 1;
+        // Totals across accounts can outgrow any one account, so they saturate
+        buckets[index].total = buckets[index].total.saturating_add(account.total());
+    }
+    BalanceDistribution { buckets }
+}
+
+/// Outputs a balance distribution to CSV, with the columns `min,max,accounts,total`. Unbounded
+/// ends are left empty.
+/// # Errors
+/// If writing fails
+pub fn write_balance_distribution_csv<W: Write>(
+    writer: &mut W,
+    distribution: &BalanceDistribution,
+) -> Result<(), Error> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for bucket in &distribution.buckets {
+        csv_writer.serialize(bucket)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// Outputs a balance distribution as a single JSON document, like
+/// `{"buckets":[{"min":null,"max":"0","accounts":1,"total":"-5.0000"},...]}`
+/// # Errors
+/// If writing fails
+pub fn write_balance_distribution_json<W: Write>(
+    writer: &mut W,
+    distribution: &BalanceDistribution,
+) -> Result<(), Error> {
+    serde_json::to_writer(&mut *writer, distribution).map_err(std::io::Error::from)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// A kind of client, as classified by [`segment_accounts`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Segment {
+    /// The account is locked
+    Locked,
+    /// The account has negative available or total funds
+    Negative,
+    /// The account's total funds are at least [`SegmentThresholds::high_value`]
+    HighValue,
+    /// The account has had at least [`SegmentThresholds::active_transactions`] applied
+    Active,
+    /// The account has had too few transactions applied to count as active
+    Dormant,
+}
+
+impl Segment {
+    /// Every segment, in the order accounts are checked against them
+    pub const ALL: [Self; 5] = [
+        Self::Locked,
+        Self::Negative,
+        Self::HighValue,
+        Self::Active,
+        Self::Dormant,
+    ];
+
+    /// Returns the segment's name, eg `high_value`
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Locked => "locked",
+            Self::Negative => "negative",
+            Self::HighValue => "high_value",
+            Self::Active => "active",
+            Self::Dormant => "dormant",
+        }
+    }
+}
+
+/// Where the lines between [`Segment`]s fall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentThresholds {
+    /// Total funds from which an account is high value
+    pub high_value: Decimal,
+    /// Number of transactions applied from which an account is active
+    pub active_transactions: u64,
+}
+
+impl Default for SegmentThresholds {
+    fn default() -> Self {
+        Self {
+            high_value: Decimal::new(10_000, 0),
+            active_transactions: 1,
+        }
+    }
+}
+
+impl SegmentThresholds {
+    /// Returns the first segment, in the order of [`Segment::ALL`], that an account with
+    /// `applied` transactions belongs to
+    #[must_use]
+    pub fn classify(&self, account: &Account, applied: u64) -> Segment {
+        if account.is_locked() {
+            Segment::Locked
+        } else if account.funds_available() < Decimal::ZERO || account.total() < Decimal::ZERO {
+            Segment::Negative
+        } else if account.total() >= self.high_value {
+            Segment::HighValue
+        } else if applied >= self.active_transactions {
+            Segment::Active
+        } else {
+            Segment::Dormant
+        }
+    }
+}
+
+/// The accounts in one segment of a [`Segmentation`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SegmentSummary {
+    /// The segment
+    pub segment: Segment,
+    /// Number of accounts
+    pub accounts: u64,
+    /// Available funds across the accounts
+    pub available: Decimal,
+    /// Held funds across the accounts
+    pub held: Decimal,
+    /// Total funds across the accounts
+    pub total: Decimal,
+}
+
+/// Accounts classified into segments, from [`segment_accounts`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Segmentation {
+    /// Every segment, in the order of [`Segment::ALL`], including empty ones
+    pub segments: Vec<SegmentSummary>,
+}
+
+impl Display for Segmentation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_localized(f, &NumberFormat::CANONICAL)
+    }
+}
+
+impl LocalizedDisplay for Segmentation {
+    fn fmt_localized(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        format: &NumberFormat,
+    ) -> std::fmt::Result {
+        writeln!(f, "accounts by segment:")?;
+        for summary in &self.segments {
+            writeln!(
+                f,
+                "  {}: {} (available {}, held {}, total {})",
+                summary.segment.name(),
+                summary.accounts,
+                format.amount(summary.available),
+                format.amount(summary.held),
+                format.amount(summary.total)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Classifies every account into a single [`Segment`], counting and adding up the accounts in
+/// each.
+///
+/// Segments are checked in the order of [`Segment::ALL`], so a locked account with a negative
+/// balance only counts as locked, and the segments add up to the whole book. Transactions are
+/// counted from when `activity` was registered with the engine, so accounts read from a baseline
+/// and untouched since are dormant.
+#[must_use]
+pub fn segment_accounts<A>(
+    account_book: &A,
+    activity: &AccountActivity,
+    thresholds: &SegmentThresholds,
+) -> Segmentation
+where
+    A: AccountBook + ?Sized,
+{
+    let mut segments = Segment::ALL.map(|segment| SegmentSummary {
+        segment,
+        accounts: 0,
+        available: Decimal::new(0, account_book.scale()),
+        held: Decimal::new(0, account_book.scale()),
+        total: Decimal::new(0, account_book.scale()),
+    });
+    let inner = activity
+        .inner
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    for account in account_book.accounts() {
+        let applied = inner
+            .accounts
+            .get(&account.client_id())
+            .map_or(0, |activity| activity.applied);
+        let segment = thresholds.classify(account, applied);
+        let summary = &mut segments[segment as usize];
+        summary.accounts += 1;
+        // Sums across accounts can outgrow any one account, so they saturate rather than overflow
+        summary.available = summary.available.saturating_add(account.funds_available());
+        summary.held = summary.held.saturating_add(account.funds_held());
+        summary.total = summary.total.saturating_add(account.total());
+    }
+    Segmentation {
+        segments: segments.to_vec(),
+    }
+}
+
+/// How long each bucket of a [`Flows`] covers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FlowInterval {
+    /// An hour
+    Hour,
+    /// A day, starting at midnight UTC
+    #[default]
+    Day,
+}
+
+impl FlowInterval {
+    /// Returns the interval's length in seconds
+    #[must_use]
+    pub fn seconds(self) -> u64 {
+        match self {
+            Self::Hour => 60 * 60,
+            Self::Day => SECONDS_PER_DAY,
+        }
+    }
+}
+
+/// Money flow during one interval, in a [`Flows`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FlowBucket {
+    /// When the interval starts, in seconds since the Unix epoch
+    pub start: u64,
+    /// Funds deposited
+    pub deposits: Decimal,
+    /// Funds withdrawn
+    pub withdrawals: Decimal,
+    /// Number of disputes opened
+    pub disputes_opened: u64,
+    /// Funds charged back
+    pub chargebacks: Decimal,
+}
+
+/// The buckets behind a [`Flows`] and its clones
+#[derive(Debug)]
+struct FlowsState {
+    /// How long each bucket covers
+    interval: FlowInterval,
+    /// Buckets with anything in them, by start
+    buckets: BTreeMap<u64, FlowBucket>,
+}
+
+/// Adds up the funds an engine moves per hour or day, by when it applied each transaction.
+///
+/// Clones share the same buckets, so keep one to call [`buckets`](Self::buckets) on after
+/// registering another with the engine.
+#[derive(Debug)]
+pub struct Flows {
+    /// Shared with clones
+    inner: Arc<Mutex<FlowsState>>,
+    /// Where the time each transaction was applied comes from
+    clock: SharedClock,
+}
+
+impl Clone for Flows {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl Flows {
+    /// Creates a tracker with buckets covering `interval` each
+    #[must_use]
+    pub fn new(interval: FlowInterval) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(FlowsState {
+                interval,
+                buckets: BTreeMap::new(),
+            })),
+            clock: SharedClock::default(),
+        }
+    }
+
+    /// Buckets transactions by the supplied clock, rather than the system's
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Returns the buckets with anything in them, oldest first. Intervals with nothing applied are
+    /// left out.
+    #[must_use]
+    pub fn buckets(&self) -> Vec<FlowBucket> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.buckets.values().copied().collect()
+    }
+
+    /// Adds an event to the bucket covering `at`
+    fn record(&self, event: &DomainEvent, at: SystemTime) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let length = inner.interval.seconds();
+        let seconds = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let start = seconds - seconds % length;
+        let amount = event.amount.unwrap_or_default();
+        // Amounts are at the account book's scale, so the first one sets it for the bucket
+        let zero = Decimal::new(0, amount.scale());
+        let bucket = inner.buckets.entry(start).or_insert(FlowBucket {
+            start,
+            deposits: zero,
+            withdrawals: zero,
+            disputes_opened: 0,
+            chargebacks: zero,
+        });
+        // Saturating, since amounts moved can add up to more than a Decimal holds without any
+        // balance doing so
+        let sum = match event.kind {
+            DomainEventKind::FundsDeposited => &mut bucket.deposits,
+            DomainEventKind::FundsWithdrawn => &mut bucket.withdrawals,
+            DomainEventKind::FundsChargedBack => &mut bucket.chargebacks,
+            DomainEventKind::FundsHeld => {
+                bucket.disputes_opened += 1;
+                return;
+            }
+            DomainEventKind::FundsReleased | DomainEventKind::AccountLocked => return,
+        };
+        *sum = sum.saturating_add(amount);
+    }
+}
+
+impl EventListener for Flows {
+    fn on_event(&mut self, event: &DomainEvent) {
+        self.record(event, self.clock.now());
+    }
+}
+
+/// Outputs money flow buckets to CSV, with the columns
+/// `start,deposits,withdrawals,disputes_opened,chargebacks`
+/// # Errors
+/// If writing fails
+pub fn write_flows_csv<W: Write>(writer: &mut W, buckets: &[FlowBucket]) -> Result<(), Error> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for bucket in buckets {
+        csv_writer.serialize(bucket)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// When a [`FundsSeries`] takes a sample. A sample is taken as soon as either limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampling {
+    /// Take a sample after this many transactions have been applied since the last. Zero for no
+    /// limit.
+    pub every_transactions: u64,
+    /// Take a sample after a transaction is applied once this long has passed since the last
+    pub every: Option<Duration>,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            every_transactions: 1000,
+            every: None,
+        }
+    }
+}
+
+/// A row of [`FundsSeries`] output
+#[derive(Debug, Serialize)]
+struct FundsSample {
+    /// When the sample was taken, in milliseconds since the Unix epoch
+    timestamp_ms: u128,
+    /// Transactions applied so far
+    applied: u64,
+    /// Available funds across all accounts
+    available: Decimal,
+    /// Held funds across all accounts
+    held: Decimal,
+    /// Number of locked accounts
+    locked: u64,
+}
+
+/// The running totals behind a [`FundsSeries`] and its clones
+#[derive(Debug)]
+struct SeriesWriter<W: Write> {
+    /// Where samples are written
+    writer: csv::Writer<W>,
+    /// The first error hit while writing, after which nothing more is written
+    error: Option<csv::Error>,
+    /// When to take samples
+    sampling: Sampling,
+    /// Each account's available funds, held funds and lock state, as last seen
+    accounts: HashMap<ClientId, (Decimal, Decimal, bool)>,
+    /// Transactions applied so far
+    applied: u64,
+    /// Transactions applied when the last sample was taken
+    sampled_at: u64,
+    /// When the last sample was taken, or the series was created
+    sampled: Instant,
+    /// Where sample times come from
+    clock: SharedClock,
+    /// Available funds across all accounts
+    available: Decimal,
+    /// Held funds across all accounts
+    held: Decimal,
+    /// Number of locked accounts
+    locked: u64,
+    /// Decimal places of the balances last seen, which are at the account book's scale
+    scale: u32,
+}
+
+impl<W: Write> SeriesWriter<W> {
+    /// Writes a sample of the totals as they are now, unless an earlier one failed
+    fn sample(&mut self) {
+        self.sampled_at = self.applied;
+        self.sampled = self.clock.instant();
+        if self.error.is_some() {
+            return;
+        }
+        let scaled = |mut amount: Decimal| {
+            amount.rescale(self.scale);
+            amount
+        };
+        let sample = FundsSample {
+            timestamp_ms: self
+                .clock
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            applied: self.applied,
+            available: scaled(self.available),
+            held: scaled(self.held),
+            locked: self.locked,
+        };
+        self.error = self.writer.serialize(sample).err();
+    }
+}
+
+/// Writes a time series of the funds across all accounts to CSV as an engine applies
+/// transactions, for charting in Grafana or a spreadsheet:
+/// ```csv
+/// timestamp_ms,applied,available,held,locked
+/// 1792108800123,1000,52311.5000,120.0000,2
+/// ```
+/// Samples are taken as set by [`Sampling`], and once more by [`finish`](Self::finish) if anything
+/// was applied since the last. Only accounts changed while the series was registered with the
+/// engine are counted.
+///
+/// As with an [`AuditLog`](crate::audit::AuditLog), clones share the same output, so keep one to
+/// call `finish` with.
+#[derive(Debug)]
+pub struct FundsSeries<W: Write> {
+    /// Shared with every clone
+    inner: Arc<Mutex<SeriesWriter<W>>>,
+}
+
+impl<W: Write> Clone for FundsSeries<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> FundsSeries<W> {
+    /// Creates a series writing to the supplied writer. Samples aren't flushed individually, so a
+    /// [`BufWriter`](std::io::BufWriter) is a good idea for files.
+    #[must_use]
+    pub fn new(writer: W, sampling: Sampling) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SeriesWriter {
+                writer: csv::Writer::from_writer(writer),
+                error: None,
+                sampling,
+                accounts: HashMap::new(),
+                applied: 0,
+                sampled_at: 0,
+                sampled: Instant::now(),
+                clock: SharedClock::default(),
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                locked: 0,
+                scale: DECIMAL_SCALE,
+            })),
+        }
+    }
+
+    /// Times samples by the supplied clock, rather than the system's
+    #[must_use]
+    pub fn with_clock<C: Clock + 'static>(self, clock: C) -> Self {
+        {
+            let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            inner.clock = SharedClock::new(clock);
+            inner.sampled = inner.clock.instant();
+        }
+        self
+    }
+
+    /// Takes a final sample if anything's been applied since the last, and flushes everything
+    /// written so far.
+    /// # Errors
+    /// If any sample failed to be written (in which case nothing after it was written either), or
+    /// flushing fails
+    pub fn finish(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.applied > inner.sampled_at {
+            inner.sample();
+        }
+        if let Some(err) = inner.error.take() {
+            return Err(err.into());
+        }
+        Ok(inner.writer.flush()?)
+    }
+}
+
+impl<W: Write> EventListener for FundsSeries<W> {
+    fn on_applied(&mut self, _transaction: &TransactionRecord, account: &Account) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = (
+            account.funds_available(),
+            account.funds_held(),
+            account.is_locked(),
+        );
+        let (available, held, locked) = inner
+            .accounts
+            .insert(account.client_id(), state)
+            .unwrap_or_default();
+        // Sums across accounts can outgrow any one account, so they saturate rather than overflow
+        inner.available = inner
+            .available
+            .saturating_add(state.0.saturating_sub(available));
+        inner.held = inner.held.saturating_add(state.1.saturating_sub(held));
+        inner.locked = inner.locked + u64::from(state.2) - u64::from(locked);
+        inner.scale = state.0.scale();
+        inner.applied += 1;
+        let sampling = inner.sampling;
+        let by_count = sampling.every_transactions > 0
+            && inner.applied - inner.sampled_at >= sampling.every_transactions;
+        let by_time = sampling.every.is_some_and(|every| {
+            inner
+                .clock
+                .instant()
+                .saturating_duration_since(inner.sampled)
+                >= every
+        });
+        if by_count || by_time {
+            inner.sample();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        clock::MockClock,
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog, RawClientId},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_accounts_json() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let activity = AccountActivity::new(2);
+        engine.add_listener(activity.clone());
+        let input = "type,client,tx,amount
+deposit,2,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+dispute,1,2,
+dispute,1,3,
+resolve,1,2,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(
+            activity.disputes(ClientId(1)),
+            vec![OpenDispute {
+                tx: TransactionId(3),
+                held: dec!(4),
+            }]
+        );
+        assert_eq!(activity.recent(ClientId(1)).len(), 2);
+
+        let mut output = vec![];
+        write_accounts_json(
+            &mut output,
+            engine.account_book(),
+            &activity,
+            &ReportOptions::default(),
+        )
+        .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let accounts = report["accounts"].as_array().unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0]["client"], 1);
+        assert_eq!(accounts[0]["held"], "4.0000");
+        assert_eq!(
+            accounts[0]["disputes"],
+            serde_json::json!([{"tx": 3, "held": "4.0000"}])
+        );
+        assert_eq!(
+            accounts[0]["recent"],
+            serde_json::json!([
+                {"type": "dispute", "tx": 3, "amount": null},
+                {"type": "resolve", "tx": 2, "amount": null},
+            ])
+        );
+        assert_eq!(accounts[1]["disputes"], serde_json::json!([]));
+        assert_eq!(accounts[1]["recent"][0]["amount"], "5.0");
+
+        let options = ReportOptions {
+            clients: Some([ClientId(2)].into()),
+            columns: vec![AccountColumn::Client],
+            ..ReportOptions::default()
+        };
+        let mut output = vec![];
+        write_accounts_json(&mut output, engine.account_book(), &activity, &options).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(
+            report["accounts"],
+            serde_json::json!([{"client": 2, "disputes": [], "recent": [
+                {"type": "deposit", "tx": 1, "amount": "5.0"},
+            ]}])
+        );
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let totals = Totals::new();
+        engine.add_listener(totals.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+deposit,3,3,8.0
+withdrawal,1,4,1.0
+dispute,2,2,
+dispute,3,3,
+resolve,3,3,
+dispute,1,1,
+chargeback,1,1,
+dispute,1,99,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let report = totals.report(engine.account_book(), 2);
+        assert_eq!(report.accounts, 3);
+        assert_eq!(report.locked, 1);
+        assert_eq!(report.available, dec!(7));
+        assert_eq!(report.held, dec!(3));
+        assert_eq!(report.total, dec!(10));
+        assert_eq!(report.applied.disputes, 4);
+        assert_eq!(
+            report.amounts,
+            TransactionAmounts {
+                deposits: dec!(16),
+                withdrawals: dec!(1),
+                disputes: dec!(16),
+                resolves: dec!(8),
+                chargebacks: dec!(5),
+            }
+        );
+        assert_eq!(
+            report.largest,
+            vec![
+                LargestAccount {
+                    client: ClientId(3),
+                    total: dec!(8),
+                },
+                LargestAccount {
+                    client: ClientId(2),
+                    total: dec!(3),
+                },
+            ]
+        );
+        assert!(report
+            .to_string()
+            .contains("largest accounts:\n  3: 8.0000\n"));
+        let german = NumberFormat::for_locale("de-DE").unwrap();
+        let localized = german.display(&report).to_string();
+        assert!(localized.contains("available: 7,0000\n"));
+        assert!(localized.contains("amounts: deposit 16,0000, withdrawal 1,0000,"));
+    }
+
+    #[test]
+    fn test_top_accounts() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+deposit,3,3,8.0
+deposit,4,4,3.0
+withdrawal,1,5,4.0
+dispute,1,1,
+dispute,3,3,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let top = top_accounts(engine.account_book(), 2);
+        let ranked = |client: RawClientId, amount: Decimal| RankedAccount {
+            client: ClientId(client),
+            amount,
+        };
+        assert_eq!(top.by_total, [ranked(3, dec!(8)), ranked(2, dec!(3))]);
+        assert_eq!(top.by_held, [ranked(3, dec!(8)), ranked(1, dec!(5))]);
+        assert_eq!(top.by_negative, [ranked(1, dec!(-4))]);
+        assert!(top.to_string().ends_with("most negative:\n  1: -4.0000\n"));
+    }
+
+    #[test]
+    fn test_locked_accounts() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let clock = MockClock::new(UNIX_EPOCH + std::time::Duration::from_secs(1_000));
+        let locks = AccountLocks::new().with_clock(clock.clone());
+        engine.add_listener(locks.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,2.0
+deposit,2,3,3.0
+dispute,1,1,
+chargeback,1,1,
+dispute,2,3,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(locks.lock(ClientId(1)).unwrap().locked_at, clock.now());
+        clock.advance(std::time::Duration::from_secs(90));
+        let locked = locked_accounts(
+            engine.account_book(),
+            engine.transaction_log(),
+            &locks,
+            clock.now(),
+        )
+        .unwrap();
+        assert_eq!(
+            locked,
+            [LockedAccount {
+                client: ClientId(1),
+                tx: Some(TransactionId(1)),
+                amount: Some(dec!(5.0)),
+                locked_for_seconds: Some(90),
+                available: dec!(2),
+                held: dec!(0),
+                total: dec!(2),
+            }]
+        );
+        let mut output = vec![];
+        write_locked_accounts_csv(&mut output, &locked).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,amount,locked_for_seconds,available,held,total\n1,1,5.0000,90,2.0000,0.0000,2.0000\n"
+        );
+    }
+
+    #[test]
+    fn test_dispute_aging() {
+        let activity = AccountActivity::new(0);
+        let mut listener = activity.clone();
+        let day = std::time::Duration::from_secs(SECONDS_PER_DAY);
+        let now = SystemTime::now();
+        {
+            let mut inner = activity.inner.lock().unwrap();
+            let disputes = &mut inner.accounts.entry(ClientId(1)).or_default().disputes;
+            for (tx, age) in [(1, 0), (2, 30), (3, 31), (4, 90), (5, 91), (6, 400)] {
+                let dispute = OpenDispute {
+                    tx: TransactionId(tx),
+                    held: dec!(1.5),
+                };
+                disputes.push((dispute, now - day * age));
+            }
+        }
+        // Charged back, so no longer open
+        listener.on_event(&DomainEvent {
+            sequence: 1,
+            kind: DomainEventKind::FundsChargedBack,
+            client_id: ClientId(1),
+            transaction_id: TransactionId(6),
+            amount: Some(dec!(1.5)),
+        });
+        let aging = activity.dispute_aging(now);
+        let counts: Vec<_> = aging
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.disputes, bucket.held))
+            .collect();
+        assert_eq!(
+            counts,
+            [(2, dec!(3)), (1, dec!(1.5)), (1, dec!(1.5)), (1, dec!(1.5))]
+        );
+        assert!(aging
+            .to_string()
+            .ends_with("  61-90 days: 1 (1.5000 held)\n  over 90 days: 1 (1.5000 held)\n"));
+    }
+
+    #[test]
+    fn test_balance_distribution() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,100.0
+deposit,3,3,250.5
+deposit,4,4,2.0
+dispute,4,4,
+chargeback,4,4,
+deposit,5,5,1000.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let distribution =
+            balance_distribution(engine.account_book(), &[dec!(1000), dec!(100), dec!(1000)]);
+        let buckets: Vec<_> = distribution
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.min, bucket.max, bucket.accounts, bucket.total))
+            .collect();
+        assert_eq!(
+            buckets,
+            [
+                (None, Some(dec!(100)), 2, dec!(5)),
+                (Some(dec!(100)), Some(dec!(1000)), 2, dec!(350.5)),
+                (Some(dec!(1000)), None, 1, dec!(1000)),
+            ]
+        );
+        let mut output = vec![];
+        write_balance_distribution_csv(&mut output, &distribution).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("min,max,accounts,total\n,100,2,5.0000\n"));
+        let mut output = vec![];
+        write_balance_distribution_json(&mut output, &distribution).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(
+            json["buckets"][0],
+            serde_json::json!({"min": null, "max": "100", "accounts": 2, "total": "5.0000"})
+        );
+    }
+
+    #[test]
+    fn test_segment_accounts() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        // Accounts from before the activity was registered look dormant
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndeposit,5,1,2.0\n"))
+            .unwrap();
+        let activity = AccountActivity::new(0);
+        engine.add_listener(activity.clone());
+        let input = "type,client,tx,amount
+deposit,1,2,20000.0
+deposit,2,3,3.0
+withdrawal,2,4,5.0
+deposit,3,5,8.0
+dispute,3,5,
+chargeback,3,5,
+deposit,4,6,1.5
+deposit,6,7,20000.0
+withdrawal,6,8,20000.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(activity.applied(ClientId(3)), 3);
+        let thresholds = SegmentThresholds {
+            active_transactions: 2,
+            ..SegmentThresholds::default()
+        };
+        let segmentation = segment_accounts(engine.account_book(), &activity, &thresholds);
+        let counts: Vec<_> = segmentation
+            .segments
+            .iter()
+            .map(|summary| (summary.segment, summary.accounts, summary.total))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (Segment::Locked, 1, dec!(0)),
+                (Segment::Negative, 1, dec!(-2)),
+                (Segment::HighValue, 1, dec!(20000)),
+                (Segment::Active, 1, dec!(0)),
+                (Segment::Dormant, 2, dec!(3.5)),
+            ]
+        );
+        assert!(segmentation
+            .to_string()
+            .contains("  negative: 1 (available -2.0000, held 0.0000, total -2.0000)\n"));
+    }
+
+    #[test]
+    fn test_flows() {
+        let flows = Flows::new(FlowInterval::Hour);
+        let event = |kind, amount| DomainEvent {
+            sequence: 1,
+            kind,
+            client_id: ClientId(1),
+            transaction_id: TransactionId(1),
+            amount,
+        };
+        let at = |seconds| UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+        flows.record(
+            &event(DomainEventKind::FundsDeposited, Some(dec!(5.0000))),
+            at(7200),
+        );
+        flows.record(
+            &event(DomainEventKind::FundsHeld, Some(dec!(5.0000))),
+            at(7300),
+        );
+        flows.record(
+            &event(DomainEventKind::FundsChargedBack, Some(dec!(5.0000))),
+            at(10799),
+        );
+        flows.record(&event(DomainEventKind::AccountLocked, None), at(10799));
+        flows.record(
+            &event(DomainEventKind::FundsDeposited, Some(dec!(2.0000))),
+            at(20000),
+        );
+        flows.record(
+            &event(DomainEventKind::FundsWithdrawn, Some(dec!(1.5000))),
+            at(20001),
+        );
+        let mut output = vec![];
+        write_flows_csv(&mut output, &flows.buckets()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "start,deposits,withdrawals,disputes_opened,chargebacks
+7200,5.0000,0.0000,1,5.0000
+18000,2.0000,1.5000,0,0.0000
+"
+        );
+    }
+
+    #[test]
+    fn test_funds_series() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let series = FundsSeries::new(
+            vec![],
+            Sampling {
+                every_transactions: 2,
+                every: None,
+            },
+        );
+        engine.add_listener(series.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+dispute,1,1,
+chargeback,1,1,
+withdrawal,2,3,1.0
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        series.finish().unwrap();
+        let inner = series.inner.lock().unwrap();
+        let output = String::from_utf8(inner.writer.get_ref().clone()).unwrap();
+        let rows: Vec<_> = output
+            .lines()
+            .map(|line| line.split_once(',').unwrap().1)
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                "applied,available,held,locked",
+                "2,8.0000,0.0000,0",
+                "4,3.0000,0.0000,1",
+                "5,2.0000,0.0000,1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_listeners_near_max() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let totals = Totals::new();
+        engine.add_listener(totals.clone());
+        let activity = AccountActivity::new(0);
+        engine.add_listener(activity.clone());
+        let flows = Flows::new(FlowInterval::Day);
+        engine.add_listener(flows.clone());
+        let series = FundsSeries::new(
+            vec![],
+            Sampling {
+                every_transactions: 1,
+                every: None,
+            },
+        );
+        engine.add_listener(series.clone());
+        // Every account stays in range, but their sums add up to more than a Decimal holds
+        let input = "type,client,tx,amount
+deposit,1,1,40000000000000000000000000000
+withdrawal,1,2,40000000000000000000000000000
+deposit,1,3,40000000000000000000000000000
+deposit,2,4,40000000000000000000000000000
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+
+        let report = totals.report(engine.account_book(), 1);
+        assert_eq!(report.amounts.deposits, Decimal::MAX);
+        assert_eq!(report.available, Decimal::MAX);
+        assert_eq!(report.total, Decimal::MAX);
+
+        let distribution = balance_distribution(engine.account_book(), &[]);
+        assert_eq!(distribution.buckets[0].total, Decimal::MAX);
+
+        let segmentation = segment_accounts(
+            engine.account_book(),
+            &activity,
+            &SegmentThresholds::default(),
+        );
+        let high_value = &segmentation.segments[Segment::HighValue as usize];
+        assert_eq!(high_value.accounts, 2);
+        assert_eq!(high_value.total, Decimal::MAX);
+
+        assert_eq!(flows.buckets()[0].deposits, Decimal::MAX);
+
+        series.finish().unwrap();
+        assert_eq!(series.inner.lock().unwrap().available, Decimal::MAX);
+    }
+}
@@ -0,0 +1,476 @@
+//! Keeping live storage bounded by moving old history out to an archive.
+//!
+//! A [`RetentionPolicy`](crate::retention::RetentionPolicy) says how long transactions, and
+//! rotated segments of an [`AuditLog`](crate::audit::AuditLog) written to a
+//! [`RotatingFile`](crate::audit::RotatingFile), are kept live. Once it's
+//! [set](crate::engine::Engine::set_retention),
+//! [`Engine::enforce_retention`](crate::engine::Engine::enforce_retention) hands everything older
+//! to the policy's [`Archive`](crate::retention::Archive), and only once the archive has stored it,
+//! purges it. Nothing runs on its own, so call it on whatever schedule suits, eg nightly.
+//!
+//! Transactions are archived as CSV in the same format they're loaded from, so they can be read
+//! back. Only transactions with a timestamp can be aged, so those without are kept, as are those
+//! under dispute, until the dispute is resolved or charged back. Once a transaction is purged,
+//! disputes of it are ignored like those of any unknown transaction, so keep transactions at
+//! least as long as they can be disputed. Not every
+//! [`TransactionLog`](crate::types::TransactionLog) can purge transactions; those that can't keep
+//! them, after they've been archived.
+//!
+//! A [`DirectoryArchive`](crate::retention::DirectoryArchive) keeps archives as files in a
+//! directory, compressed with the `zstd` feature. Implement [`Archive`](crate::retention::Archive)
+//! to send them elsewhere, eg to object storage.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    audit::segment_path,
+    errors::Error,
+    types::{ClientId, TransactionId, TransactionLog, TransactionRecord, TransactionType},
+};
+
+/// Somewhere archived history is stored, such as a directory or an object storage bucket
+pub trait Archive: Debug + Send {
+    /// Stores `contents` under `name`, which is unique to what's archived. Nothing is purged
+    /// until this returns, so it shouldn't return until the contents are safely stored.
+    ///
+    /// Whatever's already stored under `name` must never be replaced, as it may have been purged
+    /// already: the same contents can be taken as stored, but different contents are an error.
+    /// # Errors
+    /// If the contents couldn't be stored, or something else is stored under `name`, in which
+    /// case nothing is purged
+    fn store(&mut self, name: &str, contents: &[u8]) -> Result<(), Error>;
+}
+
+/// Stores archives as files in a directory
+#[derive(Debug, Clone)]
+pub struct DirectoryArchive {
+    /// Where archives are kept
+    dir: PathBuf,
+    /// Whether to compress archives with zstd
+    #[cfg(feature = "zstd")]
+    compress: bool,
+}
+
+impl DirectoryArchive {
+    /// Creates an archive storing files in `dir`, creating the directory if need be
+    /// # Errors
+    /// If the directory can't be created
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            #[cfg(feature = "zstd")]
+            compress: false,
+        })
+    }
+
+    /// Compresses archives with zstd, adding `.zst` to their names. Audit segments that were
+    /// already compressed when they were rotated are stored as they are.
+    #[cfg(feature = "zstd")]
+    #[must_use]
+    pub fn with_compression(mut self) -> Self {
+        self.compress = true;
+        self
+    }
+
+    /// Returns the directory archives are kept in
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Archive for DirectoryArchive {
+    fn store(&mut self, name: &str, contents: &[u8]) -> Result<(), Error> {
+        #[cfg(feature = "zstd")]
+        let compressed;
+        #[cfg(feature = "zstd")]
+        let (name, contents) = if self.compress && !name.ends_with(".zst") {
+            compressed = zstd::encode_all(contents, 0)?;
+            (format!("{name}.zst"), compressed.as_slice())
+        } else {
+            (name.to_string(), contents)
+        };
+        // Written aside and linked into place, so a partly written archive is never mistaken for
+        // a whole one. Unlike a rename, linking fails rather than replace an existing archive.
+        let path = self.dir.join(name);
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        let mut file = File::create(&partial)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        let linked = fs::hard_link(&partial, &path);
+        fs::remove_file(&partial)?;
+        match linked {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                // Archived by an earlier run that didn't get as far as purging
+                if fs::read(&path)? == contents {
+                    return Ok(());
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("archive {} already exists", path.display()),
+                )
+                .into())
+            }
+            linked => Ok(linked?),
+        }
+    }
+}
+
+/// How long history is kept live before it's archived and purged
+#[derive(Debug)]
+pub struct RetentionPolicy {
+    /// Where history goes before it's purged
+    archive: Box<dyn Archive>,
+    /// How long transactions are kept, if they're ever purged
+    transactions: Option<Duration>,
+    /// The audit file whose rotated segments are purged, and how long they're kept
+    audit: Option<(PathBuf, Duration)>,
+}
+
+impl RetentionPolicy {
+    /// Creates a policy archiving to `archive`, which keeps everything until told otherwise
+    #[must_use]
+    pub fn new<A: Archive + 'static>(archive: A) -> Self {
+        Self {
+            archive: Box::new(archive),
+            transactions: None,
+            audit: None,
+        }
+    }
+
+    /// Archives and purges transactions whose timestamp is more than `age` ago
+    #[must_use]
+    pub fn transactions_older_than(mut self, age: Duration) -> Self {
+        self.transactions = Some(age);
+        self
+    }
+
+    /// Archives and purges rotated segments of the audit file at `path`, as named by
+    /// [`RotatingFile::segment_path`](crate::audit::RotatingFile::segment_path), last written to
+    /// more than `age` ago. The file being written is left alone.
+    #[must_use]
+    pub fn audit_older_than(mut self, path: impl Into<PathBuf>, age: Duration) -> Self {
+        self.audit = Some((path.into(), age));
+        self
+    }
+
+    /// Archives then purges everything older than the policy allows, as of `now`, except the
+    /// `disputed` transactions, returning the transactions that were purged along with the report
+    pub(crate) fn enforce<T: TransactionLog>(
+        &mut self,
+        transaction_log: &mut T,
+        disputed: &BTreeMap<TransactionId, u32>,
+        now: SystemTime,
+    ) -> Result<(RetentionReport, Vec<TransactionRecord>), Error> {
+        let mut report = RetentionReport::default();
+        let mut purged = vec![];
+        if let Some(age) = self.transactions {
+            purged = self.enforce_transactions(transaction_log, disputed, cutoff(now, age))?;
+            report.transactions = purged.len();
+        }
+        if let Some((path, age)) = &self.audit {
+            let cutoff = now.checked_sub(*age).unwrap_or(UNIX_EPOCH);
+            report.audit_segments = enforce_audit(&mut *self.archive, path, cutoff)?;
+        }
+        Ok((report, purged))
+    }
+
+    /// Archives then purges transactions from before `cutoff`, in seconds since the Unix epoch,
+    /// other than the `disputed` ones, returning those that were purged
+    fn enforce_transactions<T: TransactionLog>(
+        &mut self,
+        transaction_log: &mut T,
+        disputed: &BTreeMap<TransactionId, u32>,
+        cutoff: u64,
+    ) -> Result<Vec<TransactionRecord>, Error> {
+        let mut expired: Vec<TransactionRecord> = transaction_log
+            .transactions()
+            .filter(|record| record.timestamp().is_some_and(|time| time < cutoff))
+            // Purging these would leave their held funds with nothing to release them
+            .filter(|record| !disputed.contains_key(&record.transaction_id()))
+            .collect();
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        expired.sort_unstable_by_key(TransactionRecord::transaction_id);
+        let contents = transactions_to_csv(&expired)?;
+        // Named by what's in it, as well as when, so runs in the same second don't collide
+        let (first, last) = (
+            expired[0].transaction_id(),
+            expired[expired.len() - 1].transaction_id(),
+        );
+        self.archive.store(
+            &format!("transactions-{cutoff}-{}-{}.csv", first.0, last.0),
+            &contents,
+        )?;
+        let ids: Vec<TransactionId> = expired
+            .iter()
+            .map(|record| record.transaction_id())
+            .collect();
+        if transaction_log.purge(&ids)? < ids.len() {
+            // Not every log can purge everything, and what's still there isn't purged
+            let mut purged = Vec::with_capacity(expired.len());
+            for record in expired {
+                if transaction_log
+                    .transaction(record.transaction_id())?
+                    .is_none()
+                {
+                    purged.push(record);
+                }
+            }
+            return Ok(purged);
+        }
+        Ok(expired)
+    }
+}
+
+/// What a call to [`Engine::enforce_retention`](crate::engine::Engine::enforce_retention)
+/// archived and purged
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// How many transactions were purged from the transaction log
+    pub transactions: usize,
+    /// How many rotated audit segments were purged
+    pub audit_segments: usize,
+}
+
+/// A transaction in an archive, with the same columns as transaction input
+#[derive(Debug, Serialize)]
+struct ArchivedTransaction {
+    /// The transaction's type
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    /// The transaction's client
+    client: ClientId,
+    /// The transaction's ID
+    tx: TransactionId,
+    /// The transaction's amount, if it has one
+    amount: Option<Decimal>,
+    /// When the transaction happened, in seconds since the Unix epoch
+    timestamp: Option<u64>,
+}
+
+impl From<&TransactionRecord> for ArchivedTransaction {
+    fn from(record: &TransactionRecord) -> Self {
+        Self {
+            transaction_type: record.transaction_type(),
+            client: record.client_id(),
+            tx: record.transaction_id(),
+            amount: record.amount(),
+            timestamp: record.timestamp(),
+        }
+    }
+}
+
+/// Writes transactions as CSV, with the same columns as transaction input
+pub(crate) fn transactions_to_csv(records: &[TransactionRecord]) -> Result<Vec<u8>, Error> {
+    let mut csv_writer = csv::Writer::from_writer(vec![]);
+    for record in records {
+        csv_writer.serialize(ArchivedTransaction::from(record))?;
+    }
+    Ok(csv_writer.into_inner().map_err(|err| err.into_error())?)
+}
+
+/// Returns the time `age` before `now`, in seconds since the Unix epoch
+fn cutoff(now: SystemTime, age: Duration) -> u64 {
+    now.checked_sub(age)
+        .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |cutoff| cutoff.as_secs())
+}
+
+/// Archives then deletes the rotated segments of the audit file at `path` last written to before
+/// `cutoff`, returning how many were deleted
+fn enforce_audit(
+    archive: &mut dyn Archive,
+    path: &Path,
+    cutoff: SystemTime,
+) -> Result<usize, Error> {
+    let mut purged = 0;
+    // Segments are numbered from 1 for the newest, with no gaps, so stop at the first missing
+    for index in 1.. {
+        let segments: Vec<PathBuf> = [false, true]
+            .into_iter()
+            .map(|compressed| segment_path(path, index, compressed))
+            .filter(|segment| segment.exists())
+            .collect();
+        if segments.is_empty() {
+            break;
+        }
+        for segment in segments {
+            let modified = fs::metadata(&segment)?.modified()?;
+            if modified >= cutoff {
+                continue;
+            }
+            let written = modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let name = segment
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            archive.store(&format!("{written}-{name}"), &fs::read(&segment)?)?;
+            fs::remove_file(&segment)?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_enforce_retention() {
+        let directory =
+            std::env::temp_dir().join(format!("cashflow-retention-{}", std::process::id()));
+        let archive = DirectoryArchive::new(directory.join("archive")).unwrap();
+        let audit = directory.join("audit.ndjson");
+        // Last written at 990 and 500
+        for (index, written) in [(1, 990), (2, 500)] {
+            let segment = segment_path(&audit, index, false);
+            fs::write(&segment, format!("segment {index}\n")).unwrap();
+            let modified = UNIX_EPOCH + Duration::from_secs(written);
+            File::options()
+                .write(true)
+                .open(&segment)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        // Nothing happens until a policy is set
+        assert_eq!(
+            engine.enforce_retention(now).unwrap(),
+            RetentionReport::default()
+        );
+        engine.set_retention(
+            RetentionPolicy::new(archive)
+                .transactions_older_than(Duration::from_secs(100))
+                .audit_older_than(&audit, Duration::from_secs(100)),
+        );
+        // Transactions from before 900 are archived, except those that can't be aged
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,5.0,100
+deposit,1,2,2.0,
+withdrawal,1,3,1.0,899
+deposit,1,4,3.0,900
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        assert_eq!(
+            engine.enforce_retention(now).unwrap(),
+            RetentionReport {
+                transactions: 2,
+                audit_segments: 1,
+            }
+        );
+        let log = engine.transaction_log();
+        let mut kept: Vec<_> = log
+            .transactions()
+            .map(|record| record.transaction_id())
+            .collect();
+        kept.sort_unstable();
+        assert_eq!(kept, [TransactionId(2), TransactionId(4)]);
+        // What the purged ones came to is carried forward, so the account still checks out
+        assert!(engine.check_consistency().is_consistent());
+        assert_eq!(
+            fs::read_to_string(directory.join("archive/transactions-900-1-3.csv")).unwrap(),
+            "type,client,tx,amount,timestamp\ndeposit,1,1,5.0,100\nwithdrawal,1,3,1.0,899\n"
+        );
+        // Disputes of purged transactions are ignored
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndispute,1,1,\n"))
+            .unwrap();
+        assert_eq!(
+            engine.account_book().accounts[&ClientId(1)].funds_held(),
+            Decimal::ZERO
+        );
+
+        // Only the older audit segment went
+        assert!(segment_path(&audit, 1, false).exists());
+        assert!(!segment_path(&audit, 2, false).exists());
+        let archived: Vec<String> = fs::read_dir(directory.join("archive"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with("-audit.ndjson.2"))
+            .collect();
+        assert_eq!(archived.len(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_disputed_kept() {
+        let directory =
+            std::env::temp_dir().join(format!("cashflow-disputed-{}", std::process::id()));
+        let archive = DirectoryArchive::new(&directory).unwrap();
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.set_retention(
+            RetentionPolicy::new(archive).transactions_older_than(Duration::from_secs(100)),
+        );
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,5.0,100
+deposit,1,2,1.0,200
+dispute,1,1,,300
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        // Only the undisputed deposit goes, though both are past the cutoff
+        assert_eq!(engine.enforce_retention(now).unwrap().transactions, 1);
+        let account = |engine: &Engine<MemoryAccountBook, MemoryTransactionLog>| {
+            engine.account_book().accounts[&ClientId(1)].clone()
+        };
+        assert_eq!(account(&engine).funds_held(), dec!(5));
+        // So the dispute can still be resolved
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\nresolve,1,1,\n"))
+            .unwrap();
+        assert_eq!(account(&engine).funds_held(), dec!(0));
+        assert_eq!(account(&engine).funds_available(), dec!(6));
+        // And once it is, the deposit goes too
+        assert_eq!(engine.enforce_retention(now).unwrap().transactions, 1);
+        assert_eq!(engine.transaction_log().transactions().count(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_archive_not_replaced() {
+        let directory =
+            std::env::temp_dir().join(format!("cashflow-archive-{}", std::process::id()));
+        let mut archive = DirectoryArchive::new(&directory).unwrap();
+        archive.store("transactions.csv", b"first").unwrap();
+        // Storing the same again is fine, but something else under the same name isn't
+        archive.store("transactions.csv", b"first").unwrap();
+        assert!(archive.store("transactions.csv", b"second").is_err());
+        assert_eq!(
+            fs::read(directory.join("transactions.csv")).unwrap(),
+            b"first"
+        );
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
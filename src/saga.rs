@@ -0,0 +1,495 @@
+//! Movements of money spanning several accounts, like transfers between clients or conversions
+//! between currencies, applied step by step and undone if a step fails.
+//!
+//! A [`Saga`](crate::saga::Saga) is a sequence of deposits and withdrawals, each with a
+//! compensating transaction that undoes it: a withdrawal of the same amount for a deposit, and a
+//! deposit for a withdrawal. [`Engine::run_saga`](crate::engine::Engine::run_saga) applies the
+//! steps in order, and if one is rejected, applies the compensations of those already applied,
+//! latest first, so no money is left half moved. Every step, and its compensation, is applied like
+//! any other transaction, so the engine's rules, listeners and metrics all see them.
+//!
+//! The [`SagaOutcome`](crate::saga::SagaOutcome) records what became of each step:
+//! ```
+//! # use cashflow::{engine::*, saga::*, types::*};
+//! # use rust_decimal_macros::dec;
+//! let settings = EngineSettings {
+//!     validate_transactions: true,
+//!     ..EngineSettings::default()
+//! };
+//! let mut engine =
+//!     Engine::with_settings(MemoryAccountBook::new(), MemoryTransactionLog::new(), settings);
+//! engine.apply(Transaction::deposit(ClientId::from(1), TransactionId::from(1), dec!(10))?)?;
+//!
+//! let amount = Money::new(dec!(4), Currency::NONE);
+//! let ids = [2, 3, 4].map(TransactionId::from);
+//! let transfer = Saga::transfer(ClientId::from(1), ClientId::from(2), amount, ids)?;
+//! assert!(engine.run_saga(transfer)?.is_complete());
+//!
+//! // Client 1 only has 6 left, so the withdrawal fails, and nothing is moved
+//! let amount = Money::new(dec!(7), Currency::NONE);
+//! let ids = [5, 6, 7].map(TransactionId::from);
+//! let transfer = Saga::transfer(ClientId::from(1), ClientId::from(2), amount, ids)?;
+//! let outcome = engine.run_saga(transfer)?;
+//! assert_eq!(outcome.steps[0].status, StepStatus::Failed);
+//! assert_eq!(outcome.steps[1].status, StepStatus::NotRun);
+//! assert!(outcome.is_consistent());
+//! # Ok::<(), cashflow::errors::Error>(())
+//! ```
+//!
+//! A saga stopped partway through, eg by a crash, would leave money half moved, so listeners are
+//! told when each saga starts and finishes. A [`Wal`](crate::state::Wal) records the saga when it
+//! starts, and from then until it finishes, flushes each step as it's applied, before the next
+//! runs. On restart, after [replaying](crate::engine::Engine::replay) the WAL,
+//! [`Engine::recover_sagas`](crate::engine::Engine::recover_sagas) undoes the steps applied by
+//! any saga it recorded starting but not finishing:
+//! ```
+//! # use std::io::Read;
+//! # use cashflow::{engine::*, errors::Error, state::*, types::*};
+//! fn restart<R: Read>(
+//!     engine: &mut Engine<MemoryAccountBook, MemoryTransactionLog>,
+//!     wal: &mut R,
+//! ) -> Result<(), Error> {
+//!     engine.replay(read_wal_entries(wal)?)?;
+//!     for outcome in engine.recover_sagas()? {
+//!         if !outcome.is_consistent() {
+//!             eprintln!("Saga left inconsistent: {outcome:?}");
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{
+    errors::{DomainError, Error},
+    types::{ClientId, Money, Transaction, TransactionId, TransactionType},
+};
+
+/// A step of a saga
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Step {
+    /// The deposit or withdrawal to apply
+    pub(crate) transaction: Transaction,
+    /// The ID of the transaction undoing it, if it has one
+    pub(crate) compensation_id: Option<TransactionId>,
+}
+
+impl Step {
+    /// Returns the transaction undoing the step, if it has one
+    fn compensation(&self) -> Option<Result<Transaction, Error>> {
+        let compensation_id = self.compensation_id?;
+        let transaction = &self.transaction;
+        let (client, amount) = (transaction.client_id(), transaction.amount()?);
+        let compensation = match transaction.transaction_type() {
+            TransactionType::Deposit => Transaction::withdrawal(client, compensation_id, amount),
+            _ => Transaction::deposit(client, compensation_id, amount),
+        };
+        Some(compensation.map(|compensation| compensation.with_currency(transaction.currency())))
+    }
+}
+
+/// A sequence of deposits and withdrawals to apply together, undoing those applied if a later
+/// one fails
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Saga {
+    /// The steps, in the order they're applied
+    pub(crate) steps: Vec<Step>,
+}
+
+impl Saga {
+    /// Creates a saga with no steps
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a step, undone by a transaction with `compensation_id` if a later step fails. Only
+    /// the last step can do without a compensation, since there's nothing after it to fail.
+    /// # Errors
+    /// [`DomainError::Uncompensable`] if the transaction isn't a deposit or withdrawal, or the
+    /// step before has no compensation
+    pub fn step(
+        mut self,
+        transaction: Transaction,
+        compensation_id: Option<TransactionId>,
+    ) -> Result<Self, Error> {
+        if let Some(last) = self.steps.last() {
+            if last.compensation_id.is_none() {
+                return Err(DomainError::Uncompensable(last.transaction.transaction_id()).into());
+            }
+        }
+        if !matches!(
+            transaction.transaction_type(),
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return Err(DomainError::Uncompensable(transaction.transaction_id()).into());
+        }
+        self.steps.push(Step {
+            transaction,
+            compensation_id,
+        });
+        Ok(self)
+    }
+
+    /// Creates a saga moving an amount from one client's account to another's. `ids` are for the
+    /// withdrawal, the deposit, and the refund of the withdrawal if the deposit fails.
+    /// # Errors
+    /// [`DomainError::InvalidAmount`] if the amount is zero or negative
+    pub fn transfer(
+        from: ClientId,
+        to: ClientId,
+        amount: Money,
+        ids: [TransactionId; 3],
+    ) -> Result<Self, Error> {
+        Self::exchange(from, amount, to, amount, ids)
+    }
+
+    /// Creates a saga taking `debit` from one account and giving `credit` to another, eg in
+    /// another currency for a conversion. `ids` are for the withdrawal, the deposit, and the
+    /// refund of the withdrawal if the deposit fails.
+    /// # Errors
+    /// [`DomainError::InvalidAmount`] if either amount is zero or negative
+    pub fn exchange(
+        from: ClientId,
+        debit: Money,
+        to: ClientId,
+        credit: Money,
+        ids: [TransactionId; 3],
+    ) -> Result<Self, Error> {
+        let [withdrawal_id, deposit_id, refund_id] = ids;
+        let withdrawal = Transaction::withdrawal(from, withdrawal_id, debit.amount())?
+            .with_currency(debit.currency());
+        let deposit =
+            Transaction::deposit(to, deposit_id, credit.amount())?.with_currency(credit.currency());
+        Self::new()
+            .step(withdrawal, Some(refund_id))?
+            .step(deposit, None)
+    }
+
+    /// Returns the number of steps
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Returns whether the saga has no steps
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns the ID of the first step, which identifies the saga, or `None` if it has no steps
+    pub(crate) fn id(&self) -> Option<TransactionId> {
+        Some(self.steps.first()?.transaction.transaction_id())
+    }
+
+    /// Applies each step in turn with `apply`, and if one fails, applies the compensations of
+    /// those before it, latest first
+    pub(crate) fn run(
+        self,
+        mut apply: impl FnMut(Transaction) -> Result<(), Error>,
+    ) -> SagaOutcome {
+        let mut outcome = self.outcome();
+        let mut applied = vec![];
+        for (index, step) in self.steps.into_iter().enumerate() {
+            let compensation = step.compensation();
+            match apply(step.transaction) {
+                Ok(()) => {
+                    outcome.steps[index].status = StepStatus::Applied;
+                    applied.push((index, compensation));
+                }
+                Err(err) => {
+                    outcome.steps[index].status = StepStatus::Failed;
+                    outcome.error = Some(err);
+                    break;
+                }
+            }
+        }
+        if outcome.error.is_some() {
+            outcome.compensate(applied, apply);
+        }
+        outcome
+    }
+
+    /// Finishes a saga that was stopped partway through, given whether each of its steps and
+    /// compensations was applied before it stopped. Unless every step was applied, the steps
+    /// applied and not yet undone are undone with `apply`, latest first.
+    pub(crate) fn recover(
+        self,
+        mut was_applied: impl FnMut(TransactionId) -> bool,
+        apply: impl FnMut(Transaction) -> Result<(), Error>,
+    ) -> SagaOutcome {
+        let mut outcome = self.outcome();
+        let Some(id) = self.id() else {
+            return outcome;
+        };
+        let mut applied = vec![];
+        // Steps are applied in order, so those applied come first
+        for (index, step) in self.steps.into_iter().enumerate() {
+            if !was_applied(step.transaction.transaction_id()) {
+                outcome.error = Some(DomainError::SagaInterrupted(id).into());
+                break;
+            }
+            if step.compensation_id.is_some_and(&mut was_applied) {
+                outcome.steps[index].status = StepStatus::Compensated;
+                outcome.error = Some(DomainError::SagaInterrupted(id).into());
+            } else {
+                outcome.steps[index].status = StepStatus::Applied;
+                applied.push((index, step.compensation()));
+            }
+        }
+        if outcome.error.is_some() {
+            outcome.compensate(applied, apply);
+        }
+        outcome
+    }
+
+    /// Returns an outcome with every step not yet run
+    fn outcome(&self) -> SagaOutcome {
+        SagaOutcome {
+            steps: self
+                .steps
+                .iter()
+                .map(|step| StepOutcome {
+                    transaction: step.transaction.transaction_id(),
+                    compensation: step.compensation_id,
+                    status: StepStatus::NotRun,
+                })
+                .collect(),
+            error: None,
+            compensation_error: None,
+        }
+    }
+}
+
+/// What became of a step of a saga
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepStatus {
+    /// The step wasn't run, because an earlier one failed, or the saga was interrupted
+    NotRun,
+    /// The step was applied, and still stands
+    Applied,
+    /// The step was rejected
+    Failed,
+    /// The step was applied, then undone by its compensation after a later step failed
+    Compensated,
+    /// The step was applied, but its compensation was rejected after a later step failed, so it
+    /// still stands and needs putting right by hand
+    CompensationFailed,
+}
+
+/// A step of a saga, and what became of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepOutcome {
+    /// The step's transaction
+    pub transaction: TransactionId,
+    /// The transaction that would undo it, if it has one
+    pub compensation: Option<TransactionId>,
+    /// What became of it
+    pub status: StepStatus,
+}
+
+/// What came of running a [`Saga`]
+#[derive(Debug)]
+pub struct SagaOutcome {
+    /// Every step, in order
+    pub steps: Vec<StepOutcome>,
+    /// Why a step failed, if one did
+    pub error: Option<Error>,
+    /// Why the first compensation to fail did, if any did
+    pub compensation_error: Option<Error>,
+}
+
+impl SagaOutcome {
+    /// Returns whether every step was applied
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Returns whether the book is consistent: either every step was applied, or every step
+    /// applied before one failed was undone
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        self.compensation_error.is_none()
+    }
+
+    /// Applies the compensations of the applied steps, latest first, after a step failed
+    fn compensate(
+        &mut self,
+        applied: Vec<(usize, Option<Result<Transaction, Error>>)>,
+        mut apply: impl FnMut(Transaction) -> Result<(), Error>,
+    ) {
+        for (index, compensation) in applied.into_iter().rev() {
+            // Every step but the last has a compensation, and the last can't be followed by a
+            // failure
+            let Some(compensation) = compensation else {
+                continue;
+            };
+            match compensation.and_then(&mut apply) {
+                Ok(()) => self.steps[index].status = StepStatus::Compensated,
+                Err(err) => {
+                    self.steps[index].status = StepStatus::CompensationFailed;
+                    self.compensation_error.get_or_insert(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::types::{Currency, RawTransactionId};
+
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let usd: Currency = "USD".parse().unwrap();
+        let eur: Currency = "EUR".parse().unwrap();
+        let ids = [1, 2, 3].map(TransactionId::from);
+        let saga = Saga::exchange(
+            ClientId::from(1),
+            Money::new(dec!(10), usd),
+            ClientId::from(2),
+            Money::new(dec!(9), eur),
+            ids,
+        )
+        .unwrap();
+        assert_eq!(saga.len(), 2);
+
+        // The deposit fails, so the withdrawal is refunded
+        let mut applied = vec![];
+        let outcome = saga.run(|transaction| {
+            if transaction.transaction_type() == TransactionType::Deposit
+                && transaction.client_id() == ClientId::from(2)
+            {
+                return Err(DomainError::Locked(transaction.client_id()).into());
+            }
+            applied.push(transaction);
+            Ok(())
+        });
+        let statuses: Vec<_> = outcome.steps.iter().map(|step| step.status).collect();
+        assert_eq!(statuses, [StepStatus::Compensated, StepStatus::Failed]);
+        assert!(!outcome.is_complete());
+        assert!(outcome.is_consistent());
+        let refund = &applied[1];
+        assert_eq!(refund.transaction_type(), TransactionType::Deposit);
+        assert_eq!(refund.transaction_id(), TransactionId::from(3));
+        assert_eq!(refund.money(), Some(Money::new(dec!(10), usd)));
+
+        // A compensation failing is reported, and leaves the book inconsistent
+        let saga = Saga::new()
+            .step(
+                Transaction::deposit(ClientId::from(1), TransactionId::from(1), dec!(1)).unwrap(),
+                Some(TransactionId::from(2)),
+            )
+            .unwrap()
+            .step(
+                Transaction::deposit(ClientId::from(2), TransactionId::from(3), dec!(1)).unwrap(),
+                Some(TransactionId::from(4)),
+            )
+            .unwrap()
+            .step(
+                Transaction::deposit(ClientId::from(3), TransactionId::from(5), dec!(1)).unwrap(),
+                None,
+            )
+            .unwrap();
+        let outcome = saga.run(|transaction| match transaction.transaction_id().0 {
+            1 | 3 => Ok(()),
+            _ => Err(DomainError::Locked(transaction.client_id()).into()),
+        });
+        let statuses: Vec<_> = outcome.steps.iter().map(|step| step.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                StepStatus::CompensationFailed,
+                StepStatus::CompensationFailed,
+                StepStatus::Failed
+            ]
+        );
+        assert!(!outcome.is_consistent());
+    }
+
+    #[test]
+    fn test_recover() {
+        let deposit =
+            |client, id| Transaction::deposit(ClientId(client), TransactionId(id), dec!(1));
+        let saga = || {
+            Saga::new()
+                .step(deposit(1, 1).unwrap(), Some(TransactionId(2)))
+                .unwrap()
+                .step(deposit(2, 3).unwrap(), Some(TransactionId(4)))
+                .unwrap()
+                .step(deposit(3, 5).unwrap(), None)
+                .unwrap()
+        };
+        let recover = |was_applied: &[RawTransactionId]| {
+            let mut applied = vec![];
+            let outcome = saga().recover(
+                |id| was_applied.contains(&id.0),
+                |transaction| {
+                    applied.push(transaction.transaction_id().0);
+                    Ok(())
+                },
+            );
+            let statuses: Vec<_> = outcome.steps.iter().map(|step| step.status).collect();
+            (outcome, statuses, applied)
+        };
+
+        // Stopped after the first step, which is undone
+        let (outcome, statuses, applied) = recover(&[1]);
+        assert_eq!(
+            statuses,
+            [
+                StepStatus::Compensated,
+                StepStatus::NotRun,
+                StepStatus::NotRun
+            ]
+        );
+        assert_eq!(applied, [2]);
+        assert!(matches!(
+            outcome.error,
+            Some(Error::Domain(DomainError::SagaInterrupted(TransactionId(
+                1
+            ))))
+        ));
+        assert!(outcome.is_consistent());
+
+        // Stopped partway through undoing the steps, so only what's left is undone
+        let (_, statuses, applied) = recover(&[1, 3, 4]);
+        assert_eq!(
+            statuses,
+            [
+                StepStatus::Compensated,
+                StepStatus::Compensated,
+                StepStatus::NotRun
+            ]
+        );
+        assert_eq!(applied, [2]);
+
+        // Stopped after the last step, so there's nothing to undo
+        let (outcome, statuses, applied) = recover(&[1, 3, 5]);
+        assert_eq!(statuses, [StepStatus::Applied; 3]);
+        assert!(applied.is_empty());
+        assert!(outcome.is_complete());
+    }
+
+    #[test]
+    fn test_uncompensable() {
+        let deposit =
+            || Transaction::deposit(ClientId::from(1), TransactionId::from(1), dec!(1)).unwrap();
+        let dispute = Transaction::dispute(ClientId::from(1), TransactionId::from(1));
+        let is_uncompensable = |result: Result<Saga, Error>| {
+            matches!(
+                result,
+                Err(Error::Domain(DomainError::Uncompensable(transaction)))
+                    if transaction == TransactionId::from(1)
+            )
+        };
+        assert!(is_uncompensable(Saga::new().step(dispute, None)));
+        let saga = Saga::new().step(deposit(), None).unwrap();
+        assert!(is_uncompensable(saga.step(deposit(), None)));
+    }
+}
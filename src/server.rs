@@ -0,0 +1,1061 @@
+//! A small, single-threaded HTTP/1.1 server exposing an [`Engine`](crate::engine::Engine) over the
+//! network.
+//!
+//! Endpoints:
+//! - `POST /transactions`: applies the CSV-formatted transactions in the request body, which
+//!   must include a header row (see [`io::load_transactions_from_csv`]). Returns JSON such as
+//!   `{"applied":2}`, plus an `error` [report](crate::errors::ErrorReport) if loading stopped
+//!   part way through. The status is `429` if that was because a client went over its
+//!   [rate limit](crate::ratelimit). With an `Idempotency-Key` header, a retry with the same key
+//!   gets the first response again, with an `Idempotent-Replayed: true` header, rather than
+//!   applying the transactions twice; see [`crate::idempotency`]
+//! - `GET /accounts`: returns all accounts, formatted as by [`io::write_accounts_to_csv`]. With
+//!   `?after=<client>&limit=<n>` (either can be left out), returns one page of accounts in client
+//!   order instead, as from
+//!   [`AccountBook::accounts_after`](crate::types::AccountBook::accounts_after). `limit` defaults
+//!   to, and is capped at, 10000. A full page comes with a `Link: <...>; rel="next"` header for
+//!   the next one
+//! - `POST /accounts/<client>/unlock`: unlocks the client's account, returning JSON such as
+//!   `{"was_locked":true}`, or `404` if the client has no account
+//! - `GET /metrics`: returns metrics in the Prometheus text format
+//! - `GET /healthz`: liveness; always `200` while the server is handling requests
+//! - `GET /readyz`: readiness; `503` if the engine's storage can't be reached
+//!
+//! Both probes return JSON like
+//! `{"status":"ok","backlog":0,"last_applied":7,"storage":"ok","snapshot_age_seconds":12.5}`, where
+//! `backlog` is the number of connections waiting behind the current one, and
+//! `snapshot_age_seconds` is the time since accounts were last exported from `GET /accounts`, or
+//! the last page of them was (`null` if they never have been). A storage failure is described in a
+//! `storage_error` object.
+//!
+//! Who may submit transactions, read accounts or unlock them is up to the server's
+//! [`Authorizer`](crate::auth::Authorizer); by default, anyone may. The metrics and probes are
+//! always open, for monitoring.
+//!
+//! Each connection handles one request and is then closed. Requests are handled one at a time,
+//! so there's no locking around the engine. A connection that goes quiet for longer than the
+//! server's [timeout](crate::server::Server::set_timeout), or takes longer than its
+//! [request timeout](crate::server::Server::set_request_timeout) to send the whole request,
+//! however steadily, is sent a `408` and closed, so it can't hold up the probes and everyone
+//! else. A request or header line over 8 KiB is refused with a `400` or `431`.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{
+    auth::{AllowAll, Authorizer, Denial, Operation},
+    clock::Clock,
+    engine::Engine,
+    errors::{Error, ErrorReport},
+    io as cashflow_io,
+    io::ReportOptions,
+    metrics::{self, AccountGauges},
+    types::{AccountBook, ClientId, RawClientId, TransactionId, TransactionLog},
+};
+
+/// Longest request line or header line accepted, in bytes
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+/// Most header lines accepted in one request
+const MAX_HEADERS: usize = 64;
+/// Largest request body accepted, in bytes
+const MAX_BODY_LENGTH: u64 = 64 * 1024 * 1024;
+/// Most waiting connections taken off the listener and queued at once. Any more are left for
+/// the operating system to queue.
+const MAX_PENDING: usize = 1024;
+/// Most accounts returned in one page of `GET /accounts`
+const MAX_PAGE_SIZE: usize = 10_000;
+/// How long a connection may go without sending or accepting data, by default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection may take to send a whole request, by default
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An HTTP request, as much as the server needs of it
+#[derive(Debug, Default)]
+pub(crate) struct Request {
+    /// eg `GET`
+    pub(crate) method: String,
+    /// The path, without any query string
+    pub(crate) path: String,
+    /// The query string, without the `?`
+    pub(crate) query: String,
+    /// Header names (lowercased) and values
+    pub(crate) headers: Vec<(String, String)>,
+    /// The request body
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads a request, returning `None` if the connection was closed before one arrived.
+    ///
+    /// Malformed or oversized requests are returned as [`Err`]`(`[`Response`]`)`, ready to send.
+    fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Result<Option<Self>, Response>> {
+        let request_line = match read_line(reader)? {
+            Line::Complete(line) => line,
+            Line::TooLong => return Ok(Err(Response::text(400, "Request line too long\n"))),
+            Line::End => return Ok(Ok(None)),
+        };
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Ok(Err(Response::text(400, "Malformed request line\n")));
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut request = Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            ..Self::default()
+        };
+        loop {
+            let line = match read_line(reader)? {
+                Line::Complete(line) => line,
+                Line::TooLong => return Ok(Err(Response::text(431, "Header line too long\n"))),
+                Line::End => return Ok(Err(Response::text(400, "Incomplete request\n"))),
+            };
+            if line.is_empty() {
+                break;
+            }
+            if request.headers.len() == MAX_HEADERS {
+                return Ok(Err(Response::text(431, "Too many headers\n")));
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Ok(Err(Response::text(400, "Malformed header\n")));
+            };
+            request
+                .headers
+                .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let length = match request.header("content-length").map(str::parse::<u64>) {
+            None => 0,
+            Some(Ok(length)) if length <= MAX_BODY_LENGTH => length,
+            Some(Ok(_)) => return Ok(Err(Response::text(413, "Request body too large\n"))),
+            Some(Err(_)) => return Ok(Err(Response::text(400, "Malformed Content-Length\n"))),
+        };
+        reader.take(length).read_to_end(&mut request.body)?;
+        Ok(Ok(Some(request)))
+    }
+
+    /// Returns the value of the first header with the given (lowercase) name
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the first query parameter with the given name. Values aren't
+    /// percent-decoded, as none of the parameters accepted need it.
+    pub(crate) fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// What [`read_line`] found
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    /// A line, without its terminator
+    Complete(String),
+    /// A line longer than [`MAX_LINE_LENGTH`], which is left partly read
+    TooLong,
+    /// The end of the stream
+    End,
+}
+
+/// Reads one CRLF- or LF-terminated line of up to [`MAX_LINE_LENGTH`] bytes, terminator included
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Line> {
+    let mut line = String::new();
+    let length = reader.take(MAX_LINE_LENGTH).read_line(&mut line)?;
+    if length == 0 {
+        return Ok(Line::End);
+    }
+    if length as u64 == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Ok(Line::TooLong);
+    }
+    let trimmed_length = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(trimmed_length);
+    Ok(Line::Complete(line))
+}
+
+/// Reads from a connection, failing as if it had gone quiet once a deadline has passed, however
+/// steadily data is arriving
+#[derive(Debug)]
+struct DeadlineReader {
+    /// The connection
+    stream: TcpStream,
+    /// When reading stops
+    deadline: Instant,
+    /// Longest to wait for each read
+    timeout: Duration,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream
+            .set_read_timeout(Some(remaining.min(self.timeout)))?;
+        self.stream.read(buf)
+    }
+}
+
+/// An HTTP response, ready to be sent
+#[derive(Debug)]
+pub(crate) struct Response {
+    /// The status code, eg 200
+    pub(crate) status: u16,
+    /// The `Content-Type` of the body
+    pub(crate) content_type: &'static str,
+    /// Any headers besides `Content-Type`, `Content-Length` and `Connection`
+    pub(crate) headers: Vec<(&'static str, String)>,
+    /// The response body
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    /// Creates a plain text response
+    pub(crate) fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            headers: vec![],
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// Creates a JSON response
+    pub(crate) fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        match serde_json::to_vec(body) {
+            Ok(body) => Self {
+                status,
+                content_type: "application/json",
+                headers: vec![],
+                body,
+            },
+            Err(err) => Self::text(500, format!("{err}\n")),
+        }
+    }
+
+    /// Sends the response
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        for (name, value) in &self.headers {
+            write!(writer, "{name}: {value}\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+/// The body of a `POST /transactions` response
+#[derive(Debug, Serialize)]
+struct TransactionsResponse {
+    /// How many transactions were applied before any error
+    applied: u64,
+    /// Why the rest of the transactions weren't applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorReport>,
+}
+
+/// The body of a `/healthz` or `/readyz` response
+#[derive(Debug, Serialize)]
+struct Health {
+    /// `ok`, or `unavailable` if the engine isn't ready
+    status: &'static str,
+    /// Connections waiting to be handled
+    backlog: usize,
+    /// The most recent transaction applied
+    last_applied: Option<TransactionId>,
+    /// `ok` or `unreachable`
+    storage: &'static str,
+    /// Why the storage is unreachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_error: Option<ErrorReport>,
+    /// Seconds since accounts were last exported
+    snapshot_age_seconds: Option<f64>,
+}
+
+/// Returns the standard reason phrase for the status codes the server uses
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// The body of a `POST /accounts/<client>/unlock` response
+#[derive(Debug, Serialize)]
+struct UnlockResponse {
+    /// Whether the account was locked beforehand
+    was_locked: bool,
+}
+
+/// Serves an [`Engine`] over HTTP. See the [module documentation](self) for the endpoints.
+#[derive(Debug)]
+pub struct Server<A, T> {
+    /// Socket accepting connections
+    listener: TcpListener,
+    /// The engine requests are applied to
+    engine: Engine<A, T>,
+    /// Connections accepted but not handled yet
+    pending: VecDeque<TcpStream>,
+    /// When accounts were last exported
+    last_snapshot: Option<SystemTime>,
+    /// Decides what each request may do
+    authorizer: Box<dyn Authorizer>,
+    /// How long a connection may go without sending or accepting data
+    timeout: Duration,
+    /// How long a connection may take to send a whole request
+    request_timeout: Duration,
+}
+
+impl<A, T> Server<A, T>
+where
+    A: AccountBook,
+    T: TransactionLog,
+{
+    /// Creates a server listening on the supplied address, serving the supplied engine
+    /// # Errors
+    /// If the address can't be bound
+    pub fn bind<S: ToSocketAddrs>(address: S, engine: Engine<A, T>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+            engine,
+            pending: VecDeque::new(),
+            last_snapshot: None,
+            authorizer: Box::new(AllowAll),
+            timeout: DEFAULT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        })
+    }
+
+    /// Sets what requests may do, by the API key they come with. Until this is called, the server
+    /// allows everything.
+    pub fn set_authorizer<Z: Authorizer + 'static>(&mut self, authorizer: Z) {
+        self.authorizer = Box::new(authorizer);
+    }
+
+    /// Sets how long a connection may go without sending or accepting data, 10 seconds by
+    /// default. A client that's silent for longer is sent a `408` and disconnected, so it can't
+    /// hold up the connections behind it.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how long a connection may take to send a whole request, body included, 60 seconds
+    /// by default. A client that's slower is sent a `408` and disconnected, even if it never goes
+    /// quiet for long enough to hit the [timeout](Self::set_timeout).
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Returns the address the server is listening on, eg to find the port after binding port 0
+    /// # Errors
+    /// If the socket's address can't be determined
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handles connections until accepting a connection fails.
+    ///
+    /// Errors on individual connections (eg clients disconnecting early) are ignored.
+    /// # Errors
+    /// If accepting a connection fails
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let stream = self.next_connection()?;
+            let _ = self.handle_connection(stream);
+        }
+    }
+
+    /// Waits for a single connection and handles its request
+    /// # Errors
+    /// If accepting the connection fails, or on any IO error during the request
+    pub fn handle_next(&mut self) -> io::Result<()> {
+        let stream = self.next_connection()?;
+        self.handle_connection(stream)
+    }
+
+    /// Takes the next connection, waiting for one if none are queued, then queues any others
+    /// already waiting so the backlog can be reported
+    fn next_connection(&mut self) -> io::Result<TcpStream> {
+        let stream = match self.pending.pop_front() {
+            Some(stream) => stream,
+            None => self.listener.accept()?.0,
+        };
+        self.listener.set_nonblocking(true)?;
+        let result = loop {
+            if self.pending.len() >= MAX_PENDING {
+                break Ok(());
+            }
+            match self.listener.accept() {
+                // Some platforms pass non-blocking mode on to accepted sockets
+                Ok((waiting, _)) => match waiting.set_nonblocking(false) {
+                    Ok(()) => self.pending.push_back(waiting),
+                    Err(_) => continue,
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.listener.set_nonblocking(false)?;
+        result.map(|()| stream)
+    }
+
+    /// Reads one request from the stream, handles it, and writes the response
+    fn handle_connection(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(DeadlineReader {
+            stream: stream.try_clone()?,
+            deadline: Instant::now() + self.request_timeout,
+            timeout: self.timeout,
+        });
+        let response = match Request::read_from(&mut reader) {
+            Ok(Ok(Some(request))) => self.handle(&request),
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(response)) => response,
+            // Timeouts show up as either, depending on the platform
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Response::text(408, "Request timed out\n")
+            }
+            Err(err) => return Err(err),
+        };
+        response.write_to(&mut &stream)
+    }
+
+    /// Routes a request to its handler, if it's allowed
+    pub(crate) fn handle(&mut self, request: &Request) -> Response {
+        if let Some(operation) = operation(request) {
+            let api_key = request
+                .header("authorization")
+                .and_then(|value| value.strip_prefix("Bearer "));
+            match self.authorizer.authorize(api_key, operation) {
+                Ok(()) => {}
+                Err(denial @ Denial::Unauthenticated) => {
+                    let mut response = Response::text(401, format!("{denial}\n"));
+                    response
+                        .headers
+                        .push(("WWW-Authenticate", "Bearer".to_string()));
+                    return response;
+                }
+                Err(denial @ Denial::Forbidden(_)) => {
+                    return Response::text(403, format!("{denial}\n"));
+                }
+            }
+        }
+        if let Some(client) = unlock_client(request) {
+            return match request.method.as_str() {
+                "POST" => self.post_unlock(client),
+                _ => Response::text(405, "Method not allowed\n"),
+            };
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/transactions") => self.post_transactions(request),
+            ("GET", "/accounts") if request.query.is_empty() => self.get_accounts(),
+            ("GET", "/accounts") => self.get_accounts_page(request),
+            ("GET", "/metrics") => self.get_metrics(),
+            ("GET", "/healthz") => self.get_health(false),
+            ("GET", "/readyz") => self.get_health(true),
+            (_, "/transactions" | "/accounts" | "/metrics" | "/healthz" | "/readyz") => {
+                Response::text(405, "Method not allowed\n")
+            }
+            _ => Response::text(404, "Not found\n"),
+        }
+    }
+
+    /// Applies the transactions in the request body, unless they've already been submitted with
+    /// the same idempotency key
+    fn post_transactions(&mut self, request: &Request) -> Response {
+        let idempotency_key = request.header("idempotency-key");
+        let submission = match self.engine.submit_csv(idempotency_key, &request.body) {
+            Ok(submission) => submission,
+            Err(err) => {
+                let body = TransactionsResponse {
+                    applied: 0,
+                    error: Some(ErrorReport::from(&err)),
+                };
+                return Response::json(422, &body);
+            }
+        };
+        let status = match submission.error.as_deref() {
+            None => 200,
+            Some(Error::Parse(_)) => 400,
+            Some(Error::RateLimited { .. }) => 429,
+            Some(_) => 422,
+        };
+        let body = TransactionsResponse {
+            applied: submission.applied,
+            error: submission.error.as_deref().map(ErrorReport::from),
+        };
+        let mut response = Response::json(status, &body);
+        if submission.replayed {
+            response
+                .headers
+                .push(("Idempotent-Replayed", "true".to_string()));
+        }
+        response
+    }
+
+    /// Unlocks a client's account
+    fn post_unlock(&mut self, client: Result<ClientId, ()>) -> Response {
+        let Ok(client) = client else {
+            return Response::text(400, "Malformed client\n");
+        };
+        match self.engine.unlock(client) {
+            Ok(Some(was_locked)) => Response::json(200, &UnlockResponse { was_locked }),
+            Ok(None) => Response::text(404, "No such account\n"),
+            Err(err) => Response::text(500, format!("{err}\n")),
+        }
+    }
+
+    /// Returns all accounts as CSV
+    fn get_accounts(&mut self) -> Response {
+        let mut body = vec![];
+        match cashflow_io::write_accounts_to_csv(&mut body, self.engine.account_book()) {
+            Ok(()) => {
+                self.last_snapshot = Some(self.engine.clock().now());
+                Response {
+                    status: 200,
+                    content_type: "text/csv; charset=utf-8",
+                    headers: vec![],
+                    body,
+                }
+            }
+            Err(err) => Response::text(500, format!("{err}\n")),
+        }
+    }
+
+    /// Returns one page of accounts as CSV, linking to the next page if there might be one
+    fn get_accounts_page(&mut self, request: &Request) -> Response {
+        let after = match request.query_param("after").map(str::parse::<RawClientId>) {
+            None => None,
+            Some(Ok(client)) => Some(ClientId::from(client)),
+            Some(Err(_)) => return Response::text(400, "Malformed after\n"),
+        };
+        let limit = match request.query_param("limit").map(str::parse::<usize>) {
+            None => MAX_PAGE_SIZE,
+            Some(Ok(limit)) => limit.min(MAX_PAGE_SIZE),
+            Some(Err(_)) => return Response::text(400, "Malformed limit\n"),
+        };
+        let page = match self.engine.account_book().accounts_after(after, limit) {
+            Ok(page) => page,
+            Err(err) => return Response::text(500, format!("{err}\n")),
+        };
+        let next = page.last().filter(|_| page.len() == limit).map(|last| {
+            format!(
+                "</accounts?after={}&limit={limit}>; rel=\"next\"",
+                last.client_id.0
+            )
+        });
+        let mut body = vec![];
+        if let Err(err) =
+            cashflow_io::write_accounts_to_csv_with(&mut body, page, &ReportOptions::default())
+        {
+            return Response::text(500, format!("{err}\n"));
+        }
+        if next.is_none() {
+            self.last_snapshot = Some(self.engine.clock().now());
+        }
+        Response {
+            status: 200,
+            content_type: "text/csv; charset=utf-8",
+            headers: next.into_iter().map(|link| ("Link", link)).collect(),
+            body,
+        }
+    }
+
+    /// Returns metrics in Prometheus text format
+    fn get_metrics(&self) -> Response {
+        let gauges = AccountGauges::from_accounts(self.engine.account_book().accounts());
+        let mut body = vec![];
+        match metrics::write_prometheus(
+            &mut body,
+            &self.engine.metrics(),
+            &gauges,
+            self.engine.clock().now(),
+        ) {
+            Ok(()) => Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4; charset=utf-8",
+                headers: vec![],
+                body,
+            },
+            Err(err) => Response::text(500, format!("{err}\n")),
+        }
+    }
+
+    /// Reports on the engine's health. For readiness, unreachable storage is an error.
+    fn get_health(&self, readiness: bool) -> Response {
+        let storage = self.engine.check_storage();
+        let ready = storage.is_ok();
+        let body = Health {
+            status: if ready { "ok" } else { "unavailable" },
+            backlog: self.pending.len(),
+            last_applied: self.engine.metrics().last_applied,
+            storage: if ready { "ok" } else { "unreachable" },
+            storage_error: storage.as_ref().err().map(ErrorReport::from),
+            snapshot_age_seconds: self.last_snapshot.map(|taken| {
+                self.engine
+                    .clock()
+                    .now()
+                    .duration_since(taken)
+                    .unwrap_or_default()
+                    .as_secs_f64()
+            }),
+        };
+        let status = if readiness && !ready { 503 } else { 200 };
+        Response::json(status, &body)
+    }
+
+    /// Returns the engine being served
+    #[must_use]
+    pub fn engine(&self) -> &Engine<A, T> {
+        &self.engine
+    }
+
+    /// Stops serving, returning the engine
+    #[must_use]
+    pub fn into_engine(self) -> Engine<A, T> {
+        self.engine
+    }
+}
+
+/// Returns the operation a request asks for, if it needs authorizing. Requests for paths that
+/// don't exist, or with the wrong method, aren't, so they get the usual `404` or `405`.
+fn operation(request: &Request) -> Option<Operation> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/transactions") => Some(Operation::SubmitTransactions),
+        ("GET", "/accounts") => Some(Operation::ReadAccounts),
+        ("POST", _) if unlock_client(request).is_some() => Some(Operation::UnlockAccount),
+        _ => None,
+    }
+}
+
+/// Returns the client a request's path is for, if it's an unlock path, or `Err` if the client
+/// isn't a number
+fn unlock_client(request: &Request) -> Option<Result<ClientId, ()>> {
+    let client = request
+        .path
+        .strip_prefix("/accounts/")?
+        .strip_suffix("/unlock")?;
+    Some(
+        client
+            .parse::<RawClientId>()
+            .map(ClientId::from)
+            .map_err(|_| ()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, thread, time::Duration};
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        auth::{ApiKeys, Role},
+        engine::EngineSettings,
+        ratelimit::RateLimit,
+        types::{MemoryAccountBook, MemoryTransactionLog, Transaction, TransactionRecord},
+    };
+
+    use super::*;
+
+    /// A transaction log whose storage can never be reached
+    struct UnreachableLog;
+
+    impl TransactionLog for UnreachableLog {
+        fn transaction(&self, _: TransactionId) -> Result<Option<TransactionRecord>, Error> {
+            Ok(None)
+        }
+
+        fn register(&mut self, _: Transaction) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn check_connection(&self) -> Result<(), Error> {
+            Err(Error::Transient("connection refused".into()))
+        }
+    }
+
+    /// Creates a server on a free local port
+    fn test_server() -> Server<MemoryAccountBook, MemoryTransactionLog> {
+        let engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        Server::bind("127.0.0.1:0", engine).unwrap()
+    }
+
+    /// Builds a request as though it had been read from a connection
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            body: body.as_bytes().to_vec(),
+            ..Request::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let raw = "POST /transactions?dry=1 HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello";
+        let request = Request::read_from(&mut Cursor::new(raw))
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/transactions");
+        assert_eq!(request.query_param("dry"), Some("1"));
+        assert_eq!(request.header("host"), Some("x"));
+        assert_eq!(request.body, b"hello");
+
+        let raw = "GET / HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n";
+        let response = Request::read_from(&mut Cursor::new(raw))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(response.status, 413);
+
+        // Over-long lines are refused, rather than split into two
+        let long = "a".repeat(MAX_LINE_LENGTH as usize);
+        let raw = format!("GET /{long} HTTP/1.1\r\n\r\n");
+        let response = Request::read_from(&mut Cursor::new(raw))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(response.status, 400);
+        let raw = format!("GET / HTTP/1.1\r\nX-Padding: {long}\r\n\r\n");
+        let response = Request::read_from(&mut Cursor::new(raw))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(response.status, 431);
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut server = test_server();
+        let body = "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,2,1.0\n";
+        let response = server.handle(&request("POST", "/transactions", body));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "application/json");
+        assert_eq!(response.body, br#"{"applied":2}"#);
+
+        let response = server.handle(&request("POST", "/transactions", "type,client\nbad,1\n"));
+        assert_eq!(response.status, 400);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["applied"], 0);
+        assert_eq!(body["error"]["code"], "parse");
+        assert_eq!(body["error"]["line"], 2);
+
+        let response = server.handle(&request("GET", "/accounts", ""));
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("1,2.5000,0.0000,2.5000,false"));
+
+        let response = server.handle(&request("GET", "/metrics", ""));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("cashflow_accounts 2\n"));
+
+        assert_eq!(server.handle(&request("GET", "/nowhere", "")).status, 404);
+        assert_eq!(server.handle(&request("PUT", "/accounts", "")).status, 405);
+    }
+
+    #[test]
+    fn test_authorization() {
+        let mut server = test_server();
+        server.set_authorizer(
+            ApiKeys::new()
+                .with_key("feed", [Role::Producer])
+                .with_key("ops", [Role::Reader, Role::Admin]),
+        );
+        let with_key = |method, target, body, key: Option<&str>| {
+            let mut request = request(method, target, body);
+            if let Some(key) = key {
+                request
+                    .headers
+                    .push(("authorization".to_string(), format!("Bearer {key}")));
+            }
+            request
+        };
+        let body = "type,client,tx,amount\ndeposit,1,1,2.5\ndispute,1,1,\nchargeback,1,1,\n";
+        let mut status =
+            |method, target, key| server.handle(&with_key(method, target, body, key)).status;
+
+        assert_eq!(status("POST", "/transactions", None), 401);
+        assert_eq!(status("POST", "/transactions", Some("wrong")), 401);
+        assert_eq!(status("POST", "/transactions", Some("ops")), 403);
+        assert_eq!(status("POST", "/transactions", Some("feed")), 200);
+        assert_eq!(status("GET", "/accounts", Some("feed")), 403);
+        assert_eq!(status("GET", "/accounts?limit=1", Some("ops")), 200);
+        assert_eq!(status("POST", "/accounts/1/unlock", Some("feed")), 403);
+        // Monitoring stays open, and unknown routes aren't given away as needing a key
+        assert_eq!(status("GET", "/metrics", None), 200);
+        assert_eq!(status("GET", "/healthz", None), 200);
+        assert_eq!(status("GET", "/nowhere", None), 404);
+        assert_eq!(status("GET", "/accounts/1/unlock", Some("ops")), 405);
+        assert_eq!(status("POST", "/accounts/x/unlock", Some("ops")), 400);
+
+        assert!(server.engine().account_book().accounts[&ClientId(1)].is_locked());
+        let response = server.handle(&with_key("POST", "/accounts/1/unlock", "", Some("ops")));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, br#"{"was_locked":true}"#);
+        assert!(!server.engine().account_book().accounts[&ClientId(1)].is_locked());
+        let response = server.handle(&with_key("POST", "/accounts/1/unlock", "", Some("ops")));
+        assert_eq!(response.body, br#"{"was_locked":false}"#);
+        // Unlocking a client without an account doesn't create one
+        let response = server.handle(&with_key("POST", "/accounts/9/unlock", "", Some("ops")));
+        assert_eq!(response.status, 404);
+        assert_eq!(server.engine().account_book().accounts.len(), 1);
+    }
+
+    #[test]
+    fn test_accounts_pages() {
+        let mut server = test_server();
+        let body = "type,client,tx,amount\ndeposit,3,1,1\ndeposit,1,2,1\ndeposit,2,3,1\n";
+        assert_eq!(
+            server
+                .handle(&request("POST", "/transactions", body))
+                .status,
+            200
+        );
+
+        let response = server.handle(&request("GET", "/accounts?limit=2", ""));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers,
+            [(
+                "Link",
+                "</accounts?after=2&limit=2>; rel=\"next\"".to_string()
+            )]
+        );
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.starts_with("client,available,held,total,locked\n1,"));
+        assert!(body.contains("\n2,"));
+        assert!(server.last_snapshot.is_none());
+
+        let response = server.handle(&request("GET", "/accounts?after=2&limit=2", ""));
+        assert!(response.headers.is_empty());
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(body.lines().skip(1).collect::<Vec<_>>().len(), 1);
+        assert!(body.contains("\n3,"));
+        assert!(server.last_snapshot.is_some());
+
+        let response = server.handle(&request("GET", "/accounts?after=x", ""));
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let settings = EngineSettings {
+            rate_limit: Some(RateLimit {
+                burst: 2,
+                ..RateLimit::per_second(1)
+            }),
+            ..EngineSettings::default()
+        };
+        let engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        let mut server = Server::bind("127.0.0.1:0", engine).unwrap();
+        let body = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+deposit,1,3,1.0
+deposit,1,4,1.0
+";
+        let response = server.handle(&request("POST", "/transactions", body));
+        assert_eq!(response.status, 429);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["applied"], 3);
+        assert_eq!(body["error"]["code"], "rate_limited");
+        assert_eq!(body["error"]["client"], 1);
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let settings = EngineSettings {
+            idempotency_window: Some(Duration::from_secs(60)),
+            ..EngineSettings::default()
+        };
+        let engine = Engine::with_settings(
+            MemoryAccountBook::new(),
+            MemoryTransactionLog::new(),
+            settings,
+        );
+        let mut server = Server::bind("127.0.0.1:0", engine).unwrap();
+        let with_key = |body, key: &str| {
+            let mut request = request("POST", "/transactions", body);
+            request
+                .headers
+                .push(("idempotency-key".to_string(), key.to_string()));
+            request
+        };
+        let body = "type,client,tx,amount\ndeposit,1,1,2.5\n";
+        let first = server.handle(&with_key(body, "k"));
+        assert_eq!((first.status, first.headers.is_empty()), (200, true));
+        let retry = server.handle(&with_key(body, "k"));
+        assert_eq!(retry.body, first.body);
+        assert_eq!(retry.headers, [("Idempotent-Replayed", "true".to_string())]);
+        assert_eq!(
+            server.engine().account_book().accounts[&ClientId(1)].total(),
+            dec!(2.5)
+        );
+
+        let response = server.handle(&with_key("type,client,tx,amount\n", "k"));
+        assert_eq!(response.status, 422);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["error"]["code"], "idempotency_key_reused");
+    }
+
+    #[test]
+    fn test_health() {
+        let mut server = test_server();
+        let response = server.handle(&request("GET", "/readyz", ""));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.body,
+            br#"{"status":"ok","backlog":0,"last_applied":null,"storage":"ok","snapshot_age_seconds":null}"#
+        );
+
+        let body = "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,7,1.0\n";
+        server.handle(&request("POST", "/transactions", body));
+        server.handle(&request("GET", "/accounts", ""));
+        let response = server.handle(&request("GET", "/healthz", ""));
+        let health: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(health["last_applied"], 7);
+        assert!(health["snapshot_age_seconds"].as_f64().unwrap() < 60.0);
+
+        let engine = Engine::new(MemoryAccountBook::new(), UnreachableLog);
+        let mut server = Server::bind("127.0.0.1:0", engine).unwrap();
+        assert_eq!(server.handle(&request("GET", "/healthz", "")).status, 200);
+        let response = server.handle(&request("GET", "/readyz", ""));
+        assert_eq!(response.status, 503);
+        let health: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(health["storage"], "unreachable");
+        assert_eq!(health["storage_error"]["code"], "transient");
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut server = test_server();
+        let address = server.local_addr().unwrap();
+        let mut clients: Vec<_> = (0..3)
+            .map(|_| {
+                let mut stream = TcpStream::connect(address).unwrap();
+                write!(stream, "GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+                stream
+            })
+            .collect();
+        for expected_backlog in [2, 1, 0] {
+            server.handle_next().unwrap();
+            let mut response = String::new();
+            clients.remove(0).read_to_string(&mut response).unwrap();
+            assert!(response.contains(&format!(r#""backlog":{expected_backlog}"#)));
+        }
+    }
+
+    #[test]
+    fn test_serve_over_tcp() {
+        let mut server = test_server();
+        let address = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let body = "type,client,tx,amount\ndeposit,7,1,3.0\n";
+            write!(
+                stream,
+                "POST /transactions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        server.handle_next().unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"applied":1}"#));
+        assert_eq!(server.engine().metrics().applied.deposits, 1);
+    }
+
+    #[test]
+    fn test_silent_client_times_out() {
+        let mut server = test_server();
+        server.set_timeout(Duration::from_millis(50));
+        let address = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            // Connects, but never sends a request
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        server.handle_next().unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn test_slow_client_times_out() {
+        let mut server = test_server();
+        server.set_timeout(Duration::from_secs(5));
+        server.set_request_timeout(Duration::from_millis(100));
+        let address = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            // Never goes quiet for long, but takes half a second to send the headers
+            thread::spawn(move || {
+                let _ = writer.write_all(b"GET /healthz HTTP/1.1\r\n");
+                for _ in 0..50 {
+                    thread::sleep(Duration::from_millis(10));
+                    let _ = writer.write_all(b"X-Slow: 1\r\n");
+                }
+            });
+            // The connection may be reset after the response, since the rest goes unread
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response
+        });
+        server.handle_next().unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+}
@@ -0,0 +1,298 @@
+//! An engine handle that can be shared between threads, eg cloned into every request handler of
+//! a web server, rather than putting a whole [`Engine`](crate::engine::Engine) behind one
+//! [`Mutex`](std::sync::Mutex).
+//!
+//! A [`SharedEngine`](crate::shared::SharedEngine) splits clients between shards, each with its
+//! own [`AccountBook`](crate::types::AccountBook) and
+//! [`TransactionLog`](crate::types::TransactionLog) behind a [`RwLock`](std::sync::RwLock).
+//! Applying a transaction only locks its client's shard, so transactions for clients in different
+//! shards are applied in parallel, and [reports](crate::shared::SharedEngine::report) only need
+//! read locks.
+//!
+//! Since each shard keeps its own transaction log, a dispute, resolve or chargeback only finds
+//! transactions of clients in the same shard. Transaction IDs need to be unique across clients,
+//! as for an [`Engine`](crate::engine::Engine): a deposit or withdrawal reusing an ID already in
+//! its shard's log is rejected as a [duplicate](crate::errors::DomainError::Duplicate), and a
+//! dispute, resolve or chargeback of another client's transaction as a
+//! [mismatch](crate::errors::DomainError::ClientMismatch). An ID reused by clients in different
+//! shards can't be caught.
+
+use std::{
+    io::Write,
+    num::NonZeroUsize,
+    sync::{PoisonError, RwLock},
+};
+
+use crate::{
+    engine::{self, EngineSettings},
+    errors::{DomainError, Error},
+    io::{self, ReportOptions},
+    types::{
+        Account, AccountBook, ClientId, MemoryAccountBook, MemoryTransactionLog, RawClientId,
+        Transaction, TransactionLog, TransactionRecord, TransactionType,
+    },
+};
+
+/// One shard's share of the accounts and transactions
+#[derive(Debug)]
+struct Shard<A, T> {
+    /// Accounts of the shard's clients
+    account_book: A,
+    /// Transactions of the shard's clients
+    transaction_log: T,
+}
+
+/// An engine that applies transactions through a shared reference, so it can be put in an
+/// [`Arc`](std::sync::Arc) and used from any number of threads at once.
+///
+/// Only [`EngineSettings::validate_transactions`] applies; the other settings are for loading
+/// input, which is left to the caller.
+#[derive(Debug)]
+pub struct SharedEngine<A, T> {
+    /// Accounts and transactions, with client `n` in shard `n % shards.len()`
+    shards: Box<[RwLock<Shard<A, T>>]>,
+    /// How transactions are applied
+    settings: EngineSettings,
+}
+
+impl<A, T> SharedEngine<A, T>
+where
+    A: AccountBook,
+    T: TransactionLog,
+{
+    /// Creates an engine with a shard for each account book and transaction log supplied, using
+    /// the default settings
+    /// # Panics
+    /// If no shards are supplied
+    pub fn new<I>(shards: I) -> Self
+    where
+        I: IntoIterator<Item = (A, T)>,
+    {
+        Self::with_settings(shards, EngineSettings::default())
+    }
+
+    /// Creates an engine with a shard for each account book and transaction log supplied
+    /// # Panics
+    /// If no shards are supplied
+    pub fn with_settings<I>(shards: I, settings: EngineSettings) -> Self
+    where
+        I: IntoIterator<Item = (A, T)>,
+    {
+        let shards: Box<[_]> = shards
+            .into_iter()
+            .map(|(account_book, transaction_log)| {
+                RwLock::new(Shard {
+                    account_book,
+                    transaction_log,
+                })
+            })
+            .collect();
+        assert!(
+            !shards.is_empty(),
+            "a shared engine needs at least one shard"
+        );
+        Self { shards, settings }
+    }
+
+    /// Returns the number of shards clients are split between
+    #[must_use]
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Applies a single transaction, locking only the shard of its client
+    /// # Errors
+    /// The error the transaction was rejected with, including
+    /// [`DomainError::Duplicate`] for a deposit or withdrawal whose ID is already in the shard's
+    /// log, and [`DomainError::ClientMismatch`] for a reference to another client's transaction
+    pub fn apply(&self, transaction: Transaction) -> Result<(), Error> {
+        let mut shard = self
+            .shard(transaction.client_id)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Shard {
+            account_book,
+            transaction_log,
+        } = &mut *shard;
+        let record = TransactionRecord::from(&transaction);
+        check_ids(transaction_log, &record)?;
+        if self.settings.validate_transactions {
+            engine::validate(account_book, transaction_log, &record)?;
+        }
+        account_book.apply(transaction_log, &mut transaction.into())
+    }
+
+    /// Returns a copy of a client's account. As with [`AccountBook::account`], it's created if it
+    /// doesn't exist yet, so this takes a write lock on the client's shard.
+    /// # Errors
+    /// Any error from the shard's account book
+    pub fn account(&self, client_id: ClientId) -> Result<Account, Error> {
+        let mut shard = self
+            .shard(client_id)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        shard.account_book.account(client_id).cloned()
+    }
+
+    /// Writes every account as CSV, in client order, in the same format as
+    /// [`io::write_accounts_to_csv`].
+    ///
+    /// Every shard is read locked while the report is written, so it's consistent: transactions
+    /// applied meanwhile wait until it's done.
+    /// # Errors
+    /// If writing fails
+    pub fn report<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+        let mut accounts: Vec<&Account> = shards
+            .iter()
+            .flat_map(|shard| shard.account_book.accounts())
+            .collect();
+        accounts.sort_unstable_by_key(|account| account.client_id());
+        io::write_accounts_to_csv_with(writer, accounts, &ReportOptions::default())
+    }
+
+    /// Returns the account book and transaction log of each shard, in order
+    #[must_use]
+    pub fn into_shards(self) -> Vec<(A, T)> {
+        self.shards
+            .into_vec()
+            .into_iter()
+            .map(|shard| {
+                let shard = shard.into_inner().unwrap_or_else(PoisonError::into_inner);
+                (shard.account_book, shard.transaction_log)
+            })
+            .collect()
+    }
+
+    /// Returns the lock on a client's shard
+    #[cfg_attr(feature = "wide-ids", allow(clippy::useless_conversion))]
+    fn shard(&self, client_id: ClientId) -> &RwLock<Shard<A, T>> {
+        let client = u64::from(RawClientId::from(client_id));
+        // The remainder is less than the number of shards, so fits in a usize
+        let index = usize::try_from(client % self.shards.len() as u64).unwrap_or_default();
+        &self.shards[index]
+    }
+}
+
+impl SharedEngine<MemoryAccountBook, MemoryTransactionLog> {
+    /// Creates an engine keeping accounts and transactions in memory, split between `shards`
+    /// shards, using the default settings
+    #[must_use]
+    pub fn in_memory(shards: NonZeroUsize) -> Self {
+        Self::new(
+            (0..shards.get()).map(|_| (MemoryAccountBook::new(), MemoryTransactionLog::new())),
+        )
+    }
+}
+
+/// Checks a transaction's ID against its shard's log, which clients in the shard share, so one
+/// client's transaction can't replace or be disputed by another's
+fn check_ids<T>(transaction_log: &T, transaction: &TransactionRecord) -> Result<(), Error>
+where
+    T: TransactionLog + ?Sized,
+{
+    let transaction_id = transaction.transaction_id;
+    let Some(existing) = transaction_log.transaction(transaction_id)? else {
+        return Ok(());
+    };
+    match transaction.transaction_type {
+        TransactionType::Deposit | TransactionType::Withdrawal => {
+            Err(DomainError::Duplicate(transaction_id).into())
+        }
+        _ if existing.client_id != transaction.client_id => Err(DomainError::ClientMismatch {
+            transaction: transaction_id,
+            owner: existing.client_id,
+            client: transaction.client_id,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        errors::DomainError,
+        types::{RawTransactionId, TransactionId},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_shared_engine() {
+        let engine = Arc::new(SharedEngine::in_memory(NonZeroUsize::new(4).unwrap()));
+        let handles: Vec<_> = (1..=8)
+            .map(|client: RawClientId| {
+                let engine = Arc::clone(&engine);
+                thread::spawn(move || {
+                    let client_id = ClientId::from(client);
+                    // Each client deposits a different amount, under IDs of its own
+                    let amount = Decimal::from(client);
+                    for tx in 1..=10 {
+                        let transaction_id =
+                            TransactionId::from(RawTransactionId::from(client) * 100 + tx);
+                        let deposit =
+                            Transaction::deposit(client_id, transaction_id, amount).unwrap();
+                        engine.apply(deposit).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(engine.account(ClientId::from(3)).unwrap().total(), dec!(30));
+
+        let dispute = Transaction::dispute(ClientId::from(2), TransactionId::from(204));
+        engine.apply(dispute).unwrap();
+        // Clients 2 and 6 share a shard, but can't dispute or reuse each other's transactions
+        let dispute = Transaction::dispute(ClientId::from(6), TransactionId::from(204));
+        assert!(matches!(
+            engine.apply(dispute),
+            Err(Error::Domain(DomainError::ClientMismatch { .. }))
+        ));
+        let deposit =
+            Transaction::deposit(ClientId::from(6), TransactionId::from(204), dec!(1)).unwrap();
+        assert!(matches!(
+            engine.apply(deposit),
+            Err(Error::Domain(DomainError::Duplicate(_)))
+        ));
+        let mut report = vec![];
+        engine.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[2], "2,18.0000,2.0000,20.0000,false");
+        assert_eq!(lines[6], "6,60.0000,0.0000,60.0000,false");
+
+        let shards = Arc::into_inner(engine).unwrap().into_shards();
+        assert_eq!(shards.len(), 4);
+    }
+
+    #[test]
+    fn test_shared_engine_validation() {
+        let settings = EngineSettings {
+            validate_transactions: true,
+            ..EngineSettings::default()
+        };
+        let engine = SharedEngine::with_settings(
+            [(MemoryAccountBook::new(), MemoryTransactionLog::new())],
+            settings,
+        );
+        let withdrawal =
+            Transaction::withdrawal(ClientId::from(1), TransactionId::from(1), dec!(5)).unwrap();
+        assert!(matches!(
+            engine.apply(withdrawal),
+            Err(Error::Domain(DomainError::InsufficientFunds { .. }))
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+//! Checking transactions were signed by their client, for feeds that cross a trust boundary.
+//!
+//! Each client signs the [message](crate::types::Transaction::signed_message) of each of its
+//! transactions with its Ed25519 key, and the signature goes in the input's `signature` column, as
+//! 128 hex digits. A [`SignatureVerifier`](crate::signature::SignatureVerifier) holds every
+//! client's public key; set one with [`Engine::set_verifier`](crate::engine::Engine::set_verifier),
+//! and the engine rejects any transaction without a valid signature from its client with
+//! [`DomainError::BadSignature`](crate::errors::DomainError::BadSignature), before applying it.
+//!
+//! Keys can be read from CSV with a `client` and a `public_key` column, the key as 64 hex digits:
+//! ```csv
+//! client,public_key
+//! 1,d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a
+//! ```
+
+use std::{collections::HashMap, io::Read};
+
+pub use ed25519_dalek::VerifyingKey;
+use serde::Deserialize;
+
+use crate::{
+    errors::{DomainError, Error, ParseError},
+    types::{self, ClientId, Signature, Transaction},
+};
+
+/// Every client's public key, for checking the signatures of their transactions
+#[derive(Debug, Default, Clone)]
+pub struct SignatureVerifier {
+    /// Public keys by client
+    keys: HashMap<ClientId, VerifyingKey>,
+}
+
+/// A row of a keys file
+#[derive(Deserialize)]
+struct KeyRow {
+    /// The client the key belongs to
+    client: ClientId,
+    /// The key, as hex digits
+    public_key: String,
+}
+
+impl SignatureVerifier {
+    /// Creates a verifier with no keys, which rejects every transaction until keys are added
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a client's public key, replacing any it had
+    pub fn add_key(&mut self, client: ClientId, key: VerifyingKey) {
+        self.keys.insert(client, key);
+    }
+
+    /// Reads clients' public keys from CSV with `client` and `public_key` columns
+    /// # Errors
+    /// If the CSV can't be read, or a key isn't 64 hex digits of a valid Ed25519 key
+    pub fn read_keys<R: Read>(reader: R) -> Result<Self, Error> {
+        let mut verifier = Self::new();
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in csv_reader.deserialize() {
+            let row: KeyRow = row?;
+            let key = types::decode_hex(&row.public_key)
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+                .ok_or(ParseError::InvalidKey(row.public_key))?;
+            verifier.add_key(row.client, key);
+        }
+        Ok(verifier)
+    }
+
+    /// Returns the number of clients with a key
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns whether no client has a key
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Checks a transaction was signed with its client's key
+    /// # Errors
+    /// [`DomainError::BadSignature`] if it isn't signed, the signature doesn't match, or the
+    /// client has no key
+    pub fn verify(&self, transaction: &Transaction) -> Result<(), Error> {
+        let client = transaction.client_id();
+        let verified = match (self.keys.get(&client), transaction.signature()) {
+            (Some(key), Some(signature)) => key
+                .verify_strict(&transaction.signed_message(), &to_dalek(signature))
+                .is_ok(),
+            _ => false,
+        };
+        if verified {
+            Ok(())
+        } else {
+            Err(DomainError::BadSignature {
+                transaction: transaction.transaction_id(),
+                client,
+            }
+            .into())
+        }
+    }
+}
+
+/// Converts a parsed signature for checking
+fn to_dalek(signature: Signature) -> ed25519_dalek::Signature {
+    ed25519_dalek::Signature::from_bytes(&signature.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use rust_decimal_macros::dec;
+
+    use crate::types::TransactionId;
+
+    use super::*;
+
+    /// Signs a transaction with the key
+    fn sign(key: &SigningKey, transaction: Transaction) -> Transaction {
+        let signature = key.sign(&transaction.signed_message());
+        transaction.with_signature(Signature::from_bytes(signature.to_bytes()))
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+        let public_key: String = key
+            .verifying_key()
+            .to_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let keys = format!("client,public_key\n1,{public_key}\n");
+        let verifier = SignatureVerifier::read_keys(keys.as_bytes()).unwrap();
+        assert_eq!(verifier.len(), 1);
+
+        let deposit = || Transaction::deposit(ClientId(1), TransactionId(1), dec!(1.5)).unwrap();
+        assert_eq!(deposit().signed_message(), b"deposit,1,1,1.5,,");
+        verifier.verify(&sign(&key, deposit())).unwrap();
+        let is_bad = |transaction: Transaction| {
+            matches!(
+                verifier.verify(&transaction),
+                Err(Error::Domain(DomainError::BadSignature { .. }))
+            )
+        };
+        assert!(is_bad(deposit()));
+        assert!(is_bad(sign(&other_key, deposit())));
+        // Signed, then tampered with
+        assert!(is_bad(sign(&key, deposit()).with_timestamp(1)));
+        let other_client = Transaction::dispute(ClientId(2), TransactionId(1));
+        assert!(is_bad(sign(&key, other_client)));
+
+        let bad_key = "client,public_key\n1,abc\n";
+        assert!(matches!(
+            SignatureVerifier::read_keys(bad_key.as_bytes()),
+            Err(Error::Parse(ParseError::InvalidKey(_)))
+        ));
+    }
+}
@@ -0,0 +1,378 @@
+//! Deterministic simulation testing, for validating new storage backends against the built-in
+//! ones.
+//!
+//! A [`Simulation`](crate::simulation::Simulation) generates a stream of transactions from a seed,
+//! so the same seed always gives the same stream, on any platform.
+//! [`run`](crate::simulation::Simulation::run) applies it to a
+//! [`MemoryAccountBook`](crate::types::MemoryAccountBook) and
+//! [`MemoryTransactionLog`](crate::types::MemoryTransactionLog) as a reference, and to the account
+//! book and transaction log under test, one transaction at a time. It panics as soon as they
+//! disagree about whether a transaction is accepted, or about the accounts it leaves, or if either
+//! breaks one of the invariants checked in
+//! [paranoid](crate::engine::EngineSettings::paranoid) mode. Any failure can be reproduced by
+//! running the same seed again:
+//! ```
+//! # use cashflow::{simulation::Simulation, types::*};
+//! for seed in 0..10 {
+//!     let simulation = Simulation::new(seed);
+//!     let mut account_book = MemoryAccountBook::new();
+//!     let outcome = simulation.run(&mut account_book, &mut MinorUnitsTransactionLog::new());
+//!     assert_eq!(outcome.applied + outcome.rejected, simulation.transactions);
+//! }
+//! ```
+//!
+//! Streams are mostly well-formed, but not entirely: withdrawals can be for more than is
+//! available, and some disputes, resolves and chargebacks refer to transactions that don't exist,
+//! belong to another client, or aren't under dispute, since how those are handled must match too.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    errors::Error,
+    invariants::Invariants,
+    types::{
+        Account, AccountBook, ClientId, Currency, MemoryAccountBook, MemoryTransactionLog,
+        RawClientId, RawTransactionId, Transaction, TransactionId, TransactionLog,
+        TransactionRecord, TransactionType, DECIMAL_SCALE,
+    },
+};
+
+/// The largest amount generated, in ten-thousandths
+const MAX_MINOR_UNITS: u64 = 100_000_000;
+
+/// Roughly one in this many disputes, resolves and chargebacks refers to a transaction picked at
+/// random, rather than one that makes sense
+const NOISE: u64 = 10;
+
+/// Settings for a seeded stream of transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Simulation {
+    /// Seeds the stream. The same seed (with the same settings) always gives the same stream.
+    pub seed: u64,
+    /// Transactions are for clients below this. Must be at least one.
+    pub clients: RawClientId,
+    /// Number of transactions in the stream
+    pub transactions: usize,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clients: 16,
+            transactions: 1_000,
+        }
+    }
+}
+
+/// What happened to a simulated stream, once both backends agreed on all of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimulationOutcome {
+    /// Number of transactions both backends applied
+    pub applied: usize,
+    /// Number of transactions both backends rejected
+    pub rejected: usize,
+}
+
+impl Simulation {
+    /// Creates a simulation with the supplied seed, and the default number of clients and
+    /// transactions
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
+
+    /// Generates the stream of transactions for this simulation's seed
+    ///
+    /// # Panics
+    /// If [`Simulation::clients`] is zero
+    #[must_use]
+    // IDs are already u64 with wide IDs
+    #[cfg_attr(
+        feature = "wide-ids",
+        allow(clippy::useless_conversion, clippy::unnecessary_fallible_conversions)
+    )]
+    pub fn generate(&self) -> Vec<TransactionRecord> {
+        assert!(self.clients > 0, "simulations need at least one client");
+        let mut rng = SplitMix64(self.seed);
+        let mut records = Vec::with_capacity(self.transactions);
+        let mut next_id: RawTransactionId = 1;
+        // Deposits that can be disputed, and those under dispute, by client and ID
+        let mut undisputed: Vec<(ClientId, TransactionId)> = vec![];
+        let mut disputed: Vec<(ClientId, TransactionId)> = vec![];
+        while records.len() < self.transactions {
+            let client_id =
+                ClientId(RawClientId::try_from(rng.below(self.clients.into())).unwrap_or(0));
+            let roll = rng.below(9);
+            let (transaction_type, client_id, transaction_id, amount) = match roll {
+                0..=4 => {
+                    let transaction_id = TransactionId(next_id);
+                    next_id += 1;
+                    let amount = Decimal::new(
+                        i64::try_from(rng.below(MAX_MINOR_UNITS) + 1).unwrap_or(1),
+                        DECIMAL_SCALE,
+                    );
+                    let transaction_type = if roll < 3 {
+                        undisputed.push((client_id, transaction_id));
+                        TransactionType::Deposit
+                    } else {
+                        TransactionType::Withdrawal
+                    };
+                    (transaction_type, client_id, transaction_id, Some(amount))
+                }
+                _ if rng.below(NOISE) == 0 => {
+                    let transaction_type = match roll {
+                        5 | 6 => TransactionType::Dispute,
+                        7 => TransactionType::Resolve,
+                        _ => TransactionType::Chargeback,
+                    };
+                    // Anything up to a little past the last ID, so some don't exist
+                    let referred = RawTransactionId::try_from(rng.below(u64::from(next_id) + 2))
+                        .unwrap_or(RawTransactionId::MAX);
+                    (transaction_type, client_id, TransactionId(referred), None)
+                }
+                5 | 6 if !undisputed.is_empty() => {
+                    let index = rng.index(undisputed.len());
+                    let (client_id, transaction_id) = undisputed.swap_remove(index);
+                    disputed.push((client_id, transaction_id));
+                    (TransactionType::Dispute, client_id, transaction_id, None)
+                }
+                7 if !disputed.is_empty() => {
+                    let index = rng.index(disputed.len());
+                    let (client_id, transaction_id) = disputed.swap_remove(index);
+                    undisputed.push((client_id, transaction_id));
+                    (TransactionType::Resolve, client_id, transaction_id, None)
+                }
+                8 if !disputed.is_empty() => {
+                    let index = rng.index(disputed.len());
+                    let (client_id, transaction_id) = disputed.swap_remove(index);
+                    (TransactionType::Chargeback, client_id, transaction_id, None)
+                }
+                // Nothing to refer to yet
+                _ => continue,
+            };
+            records.push(TransactionRecord {
+                transaction_type,
+                client_id,
+                transaction_id,
+                amount,
+                timestamp: None,
+            });
+        }
+        records
+    }
+
+    /// Applies this simulation's stream to the reference backend and to the supplied one, which
+    /// should start out empty, checking they agree after every transaction.
+    ///
+    /// # Panics
+    /// If the backends disagree about whether a transaction is accepted, or about the account it
+    /// leaves, or the accounts they end up with, or if either breaks an invariant. The message
+    /// names the seed, to reproduce it with.
+    pub fn run<A, T>(&self, account_book: &mut A, transaction_log: &mut T) -> SimulationOutcome
+    where
+        A: AccountBook,
+        T: TransactionLog,
+    {
+        let (mut reference_book, mut reference_log) =
+            (MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let mut reference = Backend::new(&mut reference_book, &mut reference_log);
+        let mut tested = Backend::new(account_book, transaction_log);
+        let mut outcome = SimulationOutcome::default();
+        let mut clients = BTreeSet::new();
+        for (index, record) in self.generate().into_iter().enumerate() {
+            clients.insert(record.client_id);
+            let expected = reference.apply(&record);
+            let actual = tested.apply(&record);
+            let diverged = |what: &str| -> ! {
+                panic!(
+                    "simulation with seed {} diverged at transaction {index} ({} of {} by client \
+                     {}): {what}",
+                    self.seed, record.transaction_type, record.transaction_id, record.client_id
+                )
+            };
+            match (&expected, &actual) {
+                (Ok(()), Ok(())) => outcome.applied += 1,
+                (Err(expected), Err(actual)) if expected.code() == actual.code() => {
+                    outcome.rejected += 1;
+                }
+                _ => diverged(&format!("expected {expected:?}, got {actual:?}")),
+            }
+            let expected = reference
+                .account_book
+                .account(record.client_id)
+                .ok()
+                .cloned();
+            let actual = tested.account_book.account(record.client_id).ok().cloned();
+            if expected != actual {
+                diverged(&format!("expected {expected:?}, got {actual:?}"));
+            }
+        }
+
+        let expected = accounts(reference.account_book);
+        let actual = accounts(tested.account_book);
+        assert_eq!(
+            expected, actual,
+            "simulation with seed {} ended with different accounts",
+            self.seed
+        );
+        // Accounts are only ever opened by a client's own transactions
+        assert!(
+            actual.keys().eq(clients.iter()),
+            "simulation with seed {} ended with accounts for clients not in the stream",
+            self.seed
+        );
+        outcome
+    }
+}
+
+/// An account book and transaction log being simulated, with the invariants they must keep
+struct Backend<'b, A, T> {
+    /// Where accounts are kept
+    account_book: &'b mut A,
+    /// Where transactions are kept
+    transaction_log: &'b mut T,
+    /// Shadow copies of every account, worked out from the transactions applied
+    invariants: Invariants,
+}
+
+impl<'b, A, T> Backend<'b, A, T>
+where
+    A: AccountBook,
+    T: TransactionLog,
+{
+    /// Starts simulating an account book and transaction log
+    fn new(account_book: &'b mut A, transaction_log: &'b mut T) -> Self {
+        Self {
+            account_book,
+            transaction_log,
+            invariants: Invariants::default(),
+        }
+    }
+
+    /// Applies a transaction, checking the invariants of its account if it was applied
+    fn apply(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        let referred_amount = match record.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => None,
+            _ => self
+                .transaction_log
+                .transaction(record.transaction_id)?
+                .and_then(|referred| referred.amount),
+        };
+        let transaction = Transaction {
+            transaction_type: record.transaction_type,
+            client_id: record.client_id,
+            transaction_id: record.transaction_id,
+            amount: record.amount,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        };
+        self.account_book
+            .apply(self.transaction_log, &mut transaction.into())?;
+        let account = self.account_book.account(record.client_id)?;
+        self.invariants.check(record, referred_amount, account);
+        Ok(())
+    }
+}
+
+/// Returns every account in an account book, by client
+fn accounts<A>(account_book: &A) -> BTreeMap<ClientId, Account>
+where
+    A: AccountBook + ?Sized,
+{
+    account_book
+        .accounts()
+        .map(|account| (account.client_id, account.clone()))
+        .collect()
+}
+
+/// A small, fast generator of pseudo-random numbers, so streams don't depend on a `rand` crate's
+/// algorithm staying the same between versions
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    /// Returns the next number in the sequence
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number below `bound`, which must not be zero. Slightly biased towards small
+    /// numbers, which doesn't matter here.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// Returns an index into a collection of `len` items, which must not be zero
+    fn index(&mut self, len: usize) -> usize {
+        // A usize always fits in a u64, and the result is below `len`
+        usize::try_from(self.below(len as u64)).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::MinorUnitsTransactionLog;
+
+    use super::*;
+
+    #[test]
+    fn test_simulation() {
+        let simulation = Simulation {
+            seed: 42,
+            clients: 8,
+            transactions: 5_000,
+        };
+        let records = simulation.generate();
+        assert_eq!(records.len(), 5_000);
+        assert_eq!(records, simulation.generate());
+        assert_ne!(records, Simulation::new(43).generate());
+        for transaction_type in TransactionType::ALL {
+            assert!(records
+                .iter()
+                .any(|record| record.transaction_type == transaction_type));
+        }
+
+        let outcome = simulation.run(
+            &mut MemoryAccountBook::new(),
+            &mut MinorUnitsTransactionLog::new(),
+        );
+        assert_eq!(outcome.applied + outcome.rejected, 5_000);
+        // Chargebacks lock accounts, so some later transactions are rejected
+        assert!(outcome.rejected > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "simulation with seed 7 diverged")]
+    fn test_simulation_divergence() {
+        /// Loses every other deposit
+        #[derive(Default)]
+        struct LossyLog(MemoryTransactionLog, bool);
+        impl TransactionLog for LossyLog {
+            fn transaction(
+                &self,
+                transaction_id: TransactionId,
+            ) -> Result<Option<TransactionRecord>, Error> {
+                self.0.transaction(transaction_id)
+            }
+
+            fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+                self.1 = !self.1;
+                if self.1 {
+                    self.0.register(transaction)?;
+                }
+                Ok(())
+            }
+        }
+        Simulation::new(7).run(&mut MemoryAccountBook::new(), &mut LossyLog::default());
+    }
+}
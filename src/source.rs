@@ -0,0 +1,266 @@
+//! Sources of transactions, decoupling where transactions come from, and how they're parsed, from
+//! applying them.
+//!
+//! A [`TransactionSource`](crate::source::TransactionSource) hands out one transaction at a time.
+//! [`CsvSource`](crate::io::CsvSource) reads CSV,
+//! [`JsonLinesSource`](crate::source::JsonLinesSource) reads one JSON object per line, and
+//! [`MemorySource`](crate::source::MemorySource) hands out transactions already in memory. Any of
+//! them can be applied with [`apply_all`](crate::source::apply_all), or by an
+//! [`Engine`](crate::engine::Engine) with [`load_source`](crate::engine::Engine::load_source) or
+//! [`attach_source`](crate::engine::Engine::attach_source), so supporting a new input format only
+//! takes a new source.
+
+use std::collections::VecDeque;
+#[cfg(feature = "csv")]
+use std::io::BufRead;
+
+#[cfg(feature = "csv")]
+use crate::errors::ParseError;
+use crate::{
+    errors::Error,
+    types::{AccountBook, Transaction, TransactionLog},
+};
+
+/// Hands out transactions one at a time, eg parsed from a file or received over a network.
+///
+/// Sources that parse their input should return an [`Error::Parse`] for an item that can't be
+/// parsed, and move on to the next one, so an [`ErrorPolicy`](crate::errors::ErrorPolicy) can
+/// skip it. Any other error is taken to mean the source can't carry on.
+pub trait TransactionSource {
+    /// Returns the next transaction, or `None` once there are no more
+    /// # Errors
+    /// [`Error::Parse`] if the next item can't be parsed, or any error reading the input
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, Error>;
+
+    /// Returns the line of the input the last item was read from, for its
+    /// [`Provenance`](crate::types::Provenance), or 0 if the source has no lines.
+    ///
+    /// The default implementation returns 0.
+    fn line(&self) -> u64 {
+        0
+    }
+
+    /// Returns the fields of the last item read, as they were in the input, so one that couldn't
+    /// be parsed can be reported or set aside, eg by a
+    /// [`DeadLetterLog`](crate::audit::DeadLetterLog).
+    ///
+    /// The default implementation returns none.
+    fn raw_fields(&self) -> Vec<&[u8]> {
+        vec![]
+    }
+}
+
+impl<S: TransactionSource + ?Sized> TransactionSource for Box<S> {
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, Error> {
+        (**self).next_transaction()
+    }
+
+    fn line(&self) -> u64 {
+        (**self).line()
+    }
+
+    fn raw_fields(&self) -> Vec<&[u8]> {
+        (**self).raw_fields()
+    }
+}
+
+/// Applies every transaction from a source to an account book, stopping at the first error
+/// # Errors
+/// The first error reading from the source or applying a transaction
+pub fn apply_all<S, A, T>(
+    source: &mut S,
+    account_book: &mut A,
+    transaction_log: &mut T,
+) -> Result<(), Error>
+where
+    S: TransactionSource + ?Sized,
+    A: AccountBook,
+    T: TransactionLog,
+{
+    while let Some(transaction) = source.next_transaction()? {
+        account_book.apply(transaction_log, &mut transaction.into())?;
+    }
+    Ok(())
+}
+
+/// Hands out transactions already in memory, in order, eg ones created in code or received in a
+/// batch
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    /// The transactions still to be handed out
+    transactions: VecDeque<Transaction>,
+}
+
+impl MemorySource {
+    /// Creates a source with no transactions
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a transaction to the end of the source
+    pub fn push(&mut self, transaction: Transaction) {
+        self.transactions.push_back(transaction);
+    }
+
+    /// Returns the number of transactions still to be handed out
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Returns whether every transaction has been handed out
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+impl From<Vec<Transaction>> for MemorySource {
+    fn from(transactions: Vec<Transaction>) -> Self {
+        Self {
+            transactions: transactions.into(),
+        }
+    }
+}
+
+impl FromIterator<Transaction> for MemorySource {
+    fn from_iter<I: IntoIterator<Item = Transaction>>(iter: I) -> Self {
+        Self {
+            transactions: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl TransactionSource for MemorySource {
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, Error> {
+        Ok(self.transactions.pop_front())
+    }
+}
+
+/// Reads transactions as JSON Lines: one object per line, with the same fields as CSV input, eg
+/// `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`. Amounts are strings, so they're read
+/// exactly; `amount` can be left out, or `null`, for disputes, resolves and chargebacks. Blank
+/// lines are skipped.
+#[cfg(feature = "csv")]
+#[derive(Debug)]
+pub struct JsonLinesSource<R> {
+    /// Where lines are read from
+    reader: R,
+    /// The line last read, without its line ending
+    buffer: String,
+    /// Number of the line last read, from 1
+    line: u64,
+    /// Offset in bytes from the start of the input to the line last read
+    byte: u64,
+    /// Offset in bytes from the start of the input to the next line
+    next_byte: u64,
+}
+
+#[cfg(feature = "csv")]
+impl<R: BufRead> JsonLinesSource<R> {
+    /// Creates a source reading from the supplied stream
+    #[must_use]
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: String::new(),
+            line: 0,
+            byte: 0,
+            next_byte: 0,
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+impl<R: BufRead> TransactionSource for JsonLinesSource<R> {
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, Error> {
+        loop {
+            self.buffer.clear();
+            let read = self.reader.read_line(&mut self.buffer)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            self.byte = self.next_byte;
+            self.next_byte += read as u64;
+            let trimmed = self.buffer.trim_end_matches(['\r', '\n']).len();
+            self.buffer.truncate(trimmed);
+            if self.buffer.trim().is_empty() {
+                continue;
+            }
+            return serde_json::from_str(&self.buffer).map(Some).map_err(|err| {
+                ParseError::Row {
+                    line: self.line,
+                    byte: self.byte,
+                    record: self.buffer.clone(),
+                    reason: err.to_string(),
+                }
+                .into()
+            });
+        }
+    }
+
+    fn line(&self) -> u64 {
+        self.line
+    }
+
+    fn raw_fields(&self) -> Vec<&[u8]> {
+        vec![self.buffer.as_bytes()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::types::{ClientId, MemoryAccountBook, MemoryTransactionLog, TransactionId};
+
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        let client = ClientId::from(1);
+        let mut source: MemorySource = [
+            Transaction::deposit(client, TransactionId::from(1), Decimal::TEN).unwrap(),
+            Transaction::dispute(client, TransactionId::from(1)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(source.len(), 2);
+        let mut account_book = MemoryAccountBook::new();
+        let mut transaction_log = MemoryTransactionLog::new();
+        apply_all(&mut source, &mut account_book, &mut transaction_log).unwrap();
+        assert!(source.is_empty());
+        let account = account_book.account(client).unwrap();
+        assert_eq!(account.funds_held(), Decimal::TEN);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_json_lines_source() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+
+{"type":"dispute","client":1,"tx":1}
+{"type":"refund","client":1,"tx":2}
+{"type":"resolve","client":1,"tx":1,"amount":null}
+"#;
+        let mut source = JsonLinesSource::new(input.as_bytes());
+        let deposit = source.next_transaction().unwrap().unwrap();
+        assert_eq!(deposit.amount(), Some(Decimal::new(15, 1)));
+        let dispute = source.next_transaction().unwrap().unwrap();
+        assert_eq!(dispute.amount(), None);
+        assert_eq!(source.line(), 3);
+        let Err(Error::Parse(ParseError::Row { line, byte, .. })) = source.next_transaction()
+        else {
+            panic!("refunds aren't a transaction type");
+        };
+        assert_eq!((line, byte), (4, 90));
+        assert_eq!(
+            source.raw_fields(),
+            [br#"{"type":"refund","client":1,"tx":2}"#]
+        );
+        assert!(source.next_transaction().unwrap().is_some());
+        assert!(source.next_transaction().unwrap().is_none());
+    }
+}
@@ -0,0 +1,1527 @@
+//! Files for persisting engine state between runs, in a format later versions of this crate can
+//! still read.
+//!
+//! A [`Snapshot`](crate::state::Snapshot) holds every account and transaction at a point in time,
+//! and a [`Wal`](crate::state::Wal) records each transaction applied after it, so state can be
+//! restored by [restoring](crate::state::Snapshot::restore) the latest snapshot and applying what
+//! [`read_wal`](crate::state::read_wal) returns on top. A WAL also records the
+//! [custom transactions](crate::handler) applied, which only an engine with their handlers can
+//! apply again, so restore one with [`Engine::restore`](crate::engine::Engine::restore) and
+//! [`Engine::replay`](crate::engine::Engine::replay) what
+//! [`read_wal_entries`](crate::state::read_wal_entries) returns.
+//!
+//! A consumer of a stream, like a message queue, can keep its position in the stream in the same
+//! files as the state it led to, rather than committing it somewhere else afterwards, where a
+//! crash in between would have it apply messages twice. A snapshot holds the position when it was
+//! taken, in [`Snapshot::offsets`](crate::state::Snapshot::offsets). Before applying each message,
+//! pass the offset to resume from after it to [`Wal::advance`](crate::state::Wal::advance), which
+//! writes it in the same frame as the transaction the message applies, so the two are recorded
+//! together or not at all. On restart, restore the snapshot, replay its WAL, and resume from the
+//! snapshot's offsets updated with [`read_wal_offsets`](crate::state::read_wal_offsets). A message
+//! that was rejected, or skipped, may be read again after a crash, but has nothing to apply. Write
+//! snapshots to a temporary file and rename it over the last one, so it's replaced whole or not
+//! at all, and start a new WAL alongside each one.
+//!
+//! Both are written in the same envelope: a header, then one or more frames. All integers are
+//! little-endian.
+//!
+//! | Bytes | Header field                                                      |
+//! |-------|-------------------------------------------------------------------|
+//! | 4     | Magic bytes, `CFST`                                               |
+//! | 2     | Schema version the file was written with                          |
+//! | 2     | Oldest schema version that can read the file                      |
+//! | 1     | Kind of file: 1 for a snapshot, 2 for a WAL, plus 128 if encrypted |
+//!
+//! Each frame is a 4 byte length, the CRC-32 of the payload, and the payload, a JSON object.
+//! A snapshot has a single frame; a WAL has one per transaction, with custom transactions under a
+//! `custom` key, and the offsets advanced since the frame before, if any, under an `offsets` key.
+//! Offsets advanced past messages that applied nothing, and not yet written, get a frame of their
+//! own when the WAL is finished. A [saga](crate::saga) gets a frame with its steps under a `saga`
+//! key before its first step, and one with the ID of its first step under a `saga_finished` key
+//! once it's done, so one interrupted by a crash can be finished on restart.
+//!
+//! Each of a WAL's payloads also has a `prev` key, holding the hex SHA-256 digest of the payload
+//! before it, as written, or 64 zeros for the first, so a frame dropped from the middle of a WAL,
+//! or frames swapped around, are caught when it's read, encrypted or not. Frames cut off the end
+//! leave a valid chain, so keep the [`head`](crate::state::Wal::head) of a WAL once it's
+//! finished to compare with what [`verify_wal`](crate::state::verify_wal) returns.
+//!
+//! Account balances are sensitive, so with the `encryption` feature, state files can be encrypted
+//! with AES-256-GCM under an `EncryptionKey`, using `Snapshot::write_encrypted` and
+//! `Wal::encrypted`. Each encrypted payload is a random 12 byte
+//! nonce followed by the ciphertext of the JSON and its tag, authenticated with the frame's index
+//! in the file, so frames can't be swapped or dropped from the middle of a WAL unnoticed. The
+//! header stays in the clear, so a file can be told to be encrypted without the key.
+//!
+//! Files written by a newer version of the crate are read as long as they say this version can
+//! read them, ignoring any fields it doesn't know about. Files written by an older version are
+//! migrated to the current schema as they're read, by the steps in
+//! [`Migrations`](crate::migration::Migrations), and can be rewritten in it for good with
+//! [`upgrade`](crate::migration::Migrations::upgrade).
+
+#[cfg(feature = "encryption")]
+use std::str::FromStr;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+#[cfg(feature = "encryption")]
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm,
+};
+
+use rust_decimal::Decimal;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    audit::{digest, GENESIS},
+    errors::{Error, ParseError},
+    events::EventListener,
+    handler::CustomTransaction,
+    migration::Migrations,
+    saga::{Saga, SagaOutcome, Step},
+    types::{
+        Account, AccountBook, ClientId, Currency, Transaction, TransactionId, TransactionLog,
+        TransactionRecord, TransactionType,
+    },
+};
+
+/// Version of the schema written by this crate. Bump it whenever the payloads change, and add a
+/// step to the built-in [`Migrations`] if older versions can't be read as they are.
+pub const SCHEMA_VERSION: u16 = 6;
+
+/// First schema version whose WAL frames are chained together
+pub(crate) const CHAINED_SINCE: u16 = 4;
+
+/// Oldest schema version that can read the snapshots this crate writes
+const MIN_SNAPSHOT_READER_VERSION: u16 = 1;
+
+/// Oldest schema version that can read the WALs this crate writes, since older versions don't
+/// know frames with nothing but offsets, or the start and end of sagas
+const MIN_WAL_READER_VERSION: u16 = 6;
+
+/// The first bytes of every state file
+const MAGIC: [u8; 4] = *b"CFST";
+
+/// Length of the header, in bytes
+const HEADER_LEN: usize = 9;
+
+/// Added to the kind of file in the header if its frames are encrypted
+const ENCRYPTED: u8 = 0x80;
+
+/// Length of the nonce starting each encrypted payload, in bytes
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// What frames are encrypted with, if anything. There's nothing to encrypt with without the
+/// `encryption` feature.
+#[cfg(feature = "encryption")]
+pub(crate) type Key = EncryptionKey;
+#[cfg(not(feature = "encryption"))]
+pub(crate) type Key = std::convert::Infallible;
+
+/// What a state file holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// A single [`Snapshot`]
+    Snapshot = 1,
+    /// Transactions written by a [`Wal`]
+    Wal = 2,
+}
+
+impl Kind {
+    /// Returns the oldest schema version that can read files of this kind written by this crate
+    fn min_reader_version(self) -> u16 {
+        match self {
+            Self::Snapshot => MIN_SNAPSHOT_READER_VERSION,
+            Self::Wal => MIN_WAL_READER_VERSION,
+        }
+    }
+
+    /// Describes the kind of file, for errors
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Snapshot => "a snapshot",
+            Self::Wal => "a WAL",
+        }
+    }
+}
+
+/// What a state file's header says about it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Header {
+    /// The schema version the file was written with
+    pub(crate) version: u16,
+    /// What the file holds
+    pub(crate) kind: Kind,
+    /// Whether its frames are encrypted
+    pub(crate) encrypted: bool,
+}
+
+/// A transaction, as kept in a state file
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredTransaction {
+    /// See [`Transaction::transaction_type`]
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    /// See [`Transaction::client_id`]
+    client: ClientId,
+    /// See [`Transaction::transaction_id`]
+    tx: TransactionId,
+    /// See [`Transaction::amount`]
+    amount: Option<Decimal>,
+    /// See [`Transaction::timestamp`]. Left out if there isn't one, so states without
+    /// timestamps read and write as before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+}
+
+impl From<TransactionRecord> for StoredTransaction {
+    fn from(record: TransactionRecord) -> Self {
+        Self {
+            transaction_type: record.transaction_type,
+            client: record.client_id,
+            tx: record.transaction_id,
+            amount: record.amount,
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+impl From<StoredTransaction> for Transaction {
+    fn from(stored: StoredTransaction) -> Self {
+        Self {
+            transaction_type: stored.transaction_type,
+            client_id: stored.client,
+            transaction_id: stored.tx,
+            amount: stored.amount,
+            currency: Currency::NONE,
+            timestamp: stored.timestamp,
+            signature: None,
+        }
+    }
+}
+
+/// A custom transaction, as kept in a WAL
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredCustom {
+    /// See [`CustomTransaction::kind`]
+    #[serde(rename = "type")]
+    kind: String,
+    /// See [`CustomTransaction::client_id`]
+    client: ClientId,
+    /// See [`CustomTransaction::transaction_id`]
+    tx: TransactionId,
+    /// See [`CustomTransaction::amount`]
+    amount: Option<Decimal>,
+    /// See [`CustomTransaction::timestamp`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+}
+
+impl From<&CustomTransaction> for StoredCustom {
+    fn from(transaction: &CustomTransaction) -> Self {
+        Self {
+            kind: transaction.kind.clone(),
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            amount: transaction.amount,
+            timestamp: transaction.timestamp,
+        }
+    }
+}
+
+impl From<StoredCustom> for CustomTransaction {
+    fn from(stored: StoredCustom) -> Self {
+        Self {
+            kind: stored.kind,
+            client_id: stored.client,
+            transaction_id: stored.tx,
+            amount: stored.amount,
+            timestamp: stored.timestamp,
+        }
+    }
+}
+
+/// A step of a saga, as kept in a WAL
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredStep {
+    /// The step's transaction, kept as it is in a snapshot
+    transaction: StoredTransaction,
+    /// The ID of the transaction undoing the step, if it has one
+    compensation: Option<TransactionId>,
+}
+
+impl From<&Step> for StoredStep {
+    fn from(step: &Step) -> Self {
+        Self {
+            transaction: TransactionRecord::from(&step.transaction).into(),
+            compensation: step.compensation_id,
+        }
+    }
+}
+
+impl From<StoredStep> for Step {
+    fn from(stored: StoredStep) -> Self {
+        Self {
+            transaction: stored.transaction.into(),
+            compensation_id: stored.compensation,
+        }
+    }
+}
+
+/// The payload of a WAL's frame
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredEntry {
+    /// A built-in transaction, kept as it is in a snapshot
+    Transaction(StoredTransaction),
+    /// A custom transaction, under a `custom` key so it can't be taken for a built-in one
+    Custom {
+        /// The transaction
+        custom: StoredCustom,
+    },
+    /// The start of a saga, written before its first step, with its steps under a `saga` key
+    SagaStarted {
+        /// Every step, in order
+        saga: Vec<StoredStep>,
+    },
+    /// The end of a saga, written once its steps and compensations have all been applied or
+    /// rejected, with the ID of its first step under a `saga_finished` key
+    SagaFinished {
+        /// The ID of the saga's first step
+        saga_finished: TransactionId,
+    },
+    /// Nothing but offsets, written when a WAL is finished with offsets still to write
+    Offsets {
+        /// See [`Wal::advance`]
+        offsets: BTreeMap<String, u64>,
+    },
+}
+
+impl StoredEntry {
+    /// Returns what there is to replay, if anything
+    fn into_entry(self) -> Option<WalEntry> {
+        match self {
+            Self::Transaction(stored) => Some(WalEntry::Transaction(stored.into())),
+            Self::Custom { custom } => Some(WalEntry::Custom(custom.into())),
+            Self::SagaStarted { saga } => Some(WalEntry::SagaStarted(Saga {
+                steps: saga.into_iter().map(Step::from).collect(),
+            })),
+            Self::SagaFinished { saga_finished } => Some(WalEntry::SagaFinished(saga_finished)),
+            Self::Offsets { .. } => None,
+        }
+    }
+}
+
+/// Something a [`Wal`] recorded, to be applied again with
+/// [`Engine::replay`](crate::engine::Engine::replay)
+#[derive(Debug, PartialEq, Eq)]
+pub enum WalEntry {
+    /// A built-in transaction
+    Transaction(Transaction),
+    /// A [custom transaction](crate::handler), for its handler
+    Custom(CustomTransaction),
+    /// A [`Saga`] about to apply its first step, which the engine
+    /// [recovers](crate::engine::Engine::recover_sagas) unless it's followed by its
+    /// [`SagaFinished`](Self::SagaFinished)
+    SagaStarted(Saga),
+    /// The end of the saga whose first step has this ID
+    SagaFinished(TransactionId),
+}
+
+/// The payload of a snapshot's frame
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotPayload {
+    /// Every account, in client order
+    accounts: Vec<Account>,
+    /// Every transaction, in ID order
+    transactions: Vec<StoredTransaction>,
+    /// See [`Snapshot::offsets`]. Left out if there aren't any, so snapshots without offsets read
+    /// and write as before.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    offsets: BTreeMap<String, u64>,
+    /// See [`Snapshot::custom`]. Left out if there aren't any.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    custom: BTreeSet<TransactionId>,
+    /// See [`Snapshot::charged_back`]. Left out if there aren't any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    charged_back: BTreeMap<ClientId, Decimal>,
+    /// See [`Snapshot::disputed`]. Left out if there aren't any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    disputed: BTreeMap<TransactionId, u32>,
+    /// See [`Snapshot::opening`]. Left out if there aren't any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    opening: BTreeMap<ClientId, Decimal>,
+}
+
+/// Every account and transaction at a point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Every account, in client order
+    pub accounts: Vec<Account>,
+    /// Every transaction, in ID order
+    pub transactions: Vec<TransactionRecord>,
+    /// How far each input had been consumed when the snapshot was taken, by a name of the
+    /// consumer's choosing, eg the next offset to read from each partition of a topic. Empty
+    /// unless set. The WAL written after it records how far they got since, as given by
+    /// [`read_wal_offsets`].
+    pub offsets: BTreeMap<String, u64>,
+    /// The IDs of the [custom transactions](crate::handler) applied, so none is applied again
+    /// once [restored](crate::engine::Engine::restore). Only filled in by
+    /// [`Engine::snapshot`](crate::engine::Engine::snapshot).
+    pub custom: BTreeSet<TransactionId>,
+    /// The funds charged back from each client, which the transaction log doesn't keep, as
+    /// given by [`Engine::charged_back`](crate::engine::Engine::charged_back). Only filled in by
+    /// [`Engine::snapshot`](crate::engine::Engine::snapshot).
+    pub charged_back: BTreeMap<ClientId, Decimal>,
+    /// How many disputes of each transaction are open, as given by
+    /// [`Engine::disputed`](crate::engine::Engine::disputed). Only filled in by
+    /// [`Engine::snapshot`](crate::engine::Engine::snapshot).
+    pub disputed: BTreeMap<TransactionId, u32>,
+    /// What each client's total came to from transactions purged from the log by retention or
+    /// erasure, which [consistency checks](crate::engine::Engine::check_consistency) start from.
+    /// Only filled in by [`Engine::snapshot`](crate::engine::Engine::snapshot).
+    pub opening: BTreeMap<ClientId, Decimal>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of an account book and transaction log. Transactions are read with
+    /// [`TransactionLog::transactions`], so a log that can't be read back in full gives a snapshot
+    /// without them.
+    #[must_use]
+    pub fn capture<A, T>(account_book: &A, transaction_log: &T) -> Self
+    where
+        A: AccountBook + ?Sized,
+        T: TransactionLog,
+    {
+        let mut accounts: Vec<Account> = account_book.accounts().cloned().collect();
+        accounts.sort_unstable_by_key(Account::client_id);
+        let mut transactions: Vec<TransactionRecord> = transaction_log.transactions().collect();
+        transactions.sort_unstable_by_key(TransactionRecord::transaction_id);
+        Self {
+            accounts,
+            transactions,
+            offsets: BTreeMap::new(),
+            custom: BTreeSet::new(),
+            charged_back: BTreeMap::new(),
+            disputed: BTreeMap::new(),
+            opening: BTreeMap::new(),
+        }
+    }
+
+    /// Writes the snapshot as a state file
+    /// # Errors
+    /// If writing fails
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        self.write_with(writer, None)
+    }
+
+    /// Writes the snapshot as a state file, encrypted with the supplied key
+    /// # Errors
+    /// If writing fails
+    #[cfg(feature = "encryption")]
+    pub fn write_encrypted<W: Write>(
+        &self,
+        writer: &mut W,
+        key: &EncryptionKey,
+    ) -> Result<(), Error> {
+        self.write_with(writer, Some(key))
+    }
+
+    /// Writes the snapshot as a state file, encrypted if there's a key
+    fn write_with<W: Write>(&self, writer: &mut W, key: Option<&Key>) -> Result<(), Error> {
+        let payload = SnapshotPayload {
+            accounts: self.accounts.clone(),
+            transactions: self
+                .transactions
+                .iter()
+                .map(|&record| record.into())
+                .collect(),
+            offsets: self.offsets.clone(),
+            custom: self.custom.clone(),
+            charged_back: self.charged_back.clone(),
+            disputed: self.disputed.clone(),
+            opening: self.opening.clone(),
+        };
+        write_header(writer, Kind::Snapshot, SCHEMA_VERSION, key.is_some())?;
+        write_frame(writer, &payload, key, 0)?;
+        Ok(writer.flush()?)
+    }
+
+    /// Reads a snapshot from a state file
+    /// # Errors
+    /// [`ParseError::InvalidState`] if the file isn't a snapshot, is corrupt, is encrypted, or can
+    /// only be read by a newer version of this crate, or any error reading it
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        Self::read_with(reader, None)
+    }
+
+    /// Reads a snapshot from a state file encrypted with the supplied key
+    /// # Errors
+    /// [`ParseError::InvalidState`] if the file isn't an encrypted snapshot, is corrupt or
+    /// encrypted with another key, or can only be read by a newer version of this crate, or any
+    /// error reading it
+    #[cfg(feature = "encryption")]
+    pub fn read_encrypted<R: Read>(reader: &mut R, key: &EncryptionKey) -> Result<Self, Error> {
+        Self::read_with(reader, Some(key))
+    }
+
+    /// Reads a snapshot from a state file, decrypting it if there's a key
+    fn read_with<R: Read>(reader: &mut R, key: Option<&Key>) -> Result<Self, Error> {
+        let version = read_header(reader, Kind::Snapshot, key.is_some())?;
+        let payload: SnapshotPayload = match read_frame(reader, version, Kind::Snapshot, key, 0)? {
+            Some(payload) => payload,
+            None => return Err(invalid("snapshot is truncated")),
+        };
+        Ok(Self {
+            accounts: payload.accounts,
+            transactions: payload
+                .transactions
+                .into_iter()
+                .map(|stored| (&Transaction::from(stored)).into())
+                .collect(),
+            offsets: payload.offsets,
+            custom: payload.custom,
+            charged_back: payload.charged_back,
+            disputed: payload.disputed,
+            opening: payload.opening,
+        })
+    }
+
+    /// Puts every account and transaction in the snapshot into an account book and transaction
+    /// log, replacing any accounts with the same clients. What only an engine keeps, like the IDs
+    /// of custom transactions and the funds charged back, is left out; see
+    /// [`Engine::restore`](crate::engine::Engine::restore).
+    /// # Errors
+    /// If the account book or transaction log fails
+    pub fn restore<A, T>(self, account_book: &mut A, transaction_log: &mut T) -> Result<(), Error>
+    where
+        A: AccountBook,
+        T: TransactionLog,
+    {
+        for account in self.accounts {
+            let client_id = account.client_id;
+            *account_book.account_mut(client_id)? = account;
+        }
+        for record in self.transactions {
+            transaction_log.register(StoredTransaction::from(record).into())?;
+        }
+        Ok(())
+    }
+}
+
+/// The writer behind a [`Wal`] and its clones
+#[derive(Debug)]
+struct WalWriter<W> {
+    /// Where frames are written
+    writer: W,
+    /// What frames are encrypted with, if anything
+    key: Option<Key>,
+    /// Number of frames written so far
+    frames: u64,
+    /// Digest of the last frame written
+    head: String,
+    /// Offsets advanced since the last frame was written, to write with the next
+    offsets: BTreeMap<String, u64>,
+    /// Whether a saga is running, so each frame is flushed as it's written
+    in_saga: bool,
+    /// The first error hit while writing, after which nothing more is written
+    error: Option<io::Error>,
+}
+
+impl<W: Write> WalWriter<W> {
+    /// Writes a frame, with any offsets advanced since the last, unless writing has already
+    /// failed
+    fn append(&mut self, mut payload: Value) {
+        if self.error.is_some() {
+            return;
+        }
+        if !self.offsets.is_empty() {
+            let offsets = std::mem::take(&mut self.offsets);
+            if let Some(fields) = payload.as_object_mut() {
+                fields.insert("offsets".to_string(), Value::from_iter(offsets));
+            }
+        }
+        let result = link(payload, &mut self.head).and_then(|payload| {
+            write_frame(&mut self.writer, &payload, self.key.as_ref(), self.frames)
+        });
+        self.frames += 1;
+        self.error = result.and_then(|()| self.flush_in_saga()).err();
+    }
+
+    /// Flushes what's been written if a saga is running, so each step is kept before the next
+    /// runs
+    fn flush_in_saga(&mut self) -> io::Result<()> {
+        if self.in_saga {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Records every transaction applied by the engine it's registered with, custom transactions
+/// included, as a state file to be read back with [`read_wal`] or [`read_wal_entries`].
+///
+/// Clones share the same output, so keep one to call [`finish`](Self::finish) with after
+/// registering another with [`Engine::add_listener`](crate::engine::Engine::add_listener). Frames
+/// aren't flushed individually, so a [`BufWriter`](std::io::BufWriter) is a good idea for files,
+/// except while a [saga](crate::saga) runs, when each step is flushed before the next runs.
+#[derive(Debug)]
+pub struct Wal<W> {
+    /// Shared with every clone
+    inner: Arc<Mutex<WalWriter<W>>>,
+}
+
+impl<W> Clone for Wal<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> Wal<W> {
+    /// Creates a WAL writing to the supplied writer, starting with the header
+    #[must_use]
+    pub fn new(writer: W) -> Self {
+        Self::with_key(writer, None)
+    }
+
+    /// Creates a WAL writing to the supplied writer, starting with the header, with every
+    /// transaction encrypted with the supplied key
+    #[cfg(feature = "encryption")]
+    #[must_use]
+    pub fn encrypted(writer: W, key: EncryptionKey) -> Self {
+        Self::with_key(writer, Some(key))
+    }
+
+    /// Creates a WAL, encrypted if there's a key
+    fn with_key(mut writer: W, key: Option<Key>) -> Self {
+        let error = write_header(&mut writer, Kind::Wal, SCHEMA_VERSION, key.is_some()).err();
+        Self {
+            inner: Arc::new(Mutex::new(WalWriter {
+                writer,
+                key,
+                frames: 0,
+                head: GENESIS.to_string(),
+                offsets: BTreeMap::new(),
+                in_saga: false,
+                error,
+            })),
+        }
+    }
+
+    /// Records that a consumer resumes the named input from `offset`, eg the offset after the
+    /// message it's about to apply. It's written in the next frame, along with the transaction
+    /// the message applies, or on its own by [`finish`](Self::finish) if there isn't one.
+    pub fn advance(&self, input: impl Into<String>, offset: u64) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.offsets.insert(input.into(), offset);
+    }
+
+    /// Writes any offsets [advanced](Self::advance) since the last frame, and flushes everything
+    /// written so far.
+    /// # Errors
+    /// If any transaction failed to be written (in which case nothing after it was written
+    /// either), or flushing fails
+    pub fn finish(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if !inner.offsets.is_empty() {
+            inner.append(Value::Object(serde_json::Map::new()));
+        }
+        if let Some(err) = inner.error.take() {
+            return Err(err.into());
+        }
+        Ok(inner.writer.flush()?)
+    }
+
+    /// Returns the hex digest of the last frame written, which [`verify_wal`] returns for the
+    /// WAL as long as nothing's been cut off the end, or 64 zeros if nothing's been written
+    #[must_use]
+    pub fn head(&self) -> String {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.head.clone()
+    }
+}
+
+impl<W: Write> Wal<W> {
+    /// Writes a frame, unless writing has already failed
+    fn append(&self, entry: &StoredEntry) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match serde_json::to_value(entry) {
+            Ok(payload) => inner.append(payload),
+            Err(err) => {
+                inner.error.get_or_insert(err.into());
+            }
+        }
+    }
+}
+
+impl<W: Write> EventListener for Wal<W> {
+    fn on_applied(&mut self, transaction: &TransactionRecord, _account: &Account) {
+        self.append(&StoredEntry::Transaction((*transaction).into()));
+    }
+
+    fn on_custom_applied(&mut self, transaction: &CustomTransaction, _account: &Account) {
+        self.append(&StoredEntry::Custom {
+            custom: transaction.into(),
+        });
+    }
+
+    fn on_saga_started(&mut self, saga: &Saga) {
+        if saga.is_empty() {
+            return;
+        }
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .in_saga = true;
+        self.append(&StoredEntry::SagaStarted {
+            saga: saga.steps.iter().map(StoredStep::from).collect(),
+        });
+    }
+
+    fn on_saga_finished(&mut self, outcome: &SagaOutcome) {
+        let Some(first) = outcome.steps.first() else {
+            return;
+        };
+        self.append(&StoredEntry::SagaFinished {
+            saga_finished: first.transaction,
+        });
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .in_saga = false;
+    }
+}
+
+/// Reads back the transactions written by a [`Wal`], in the order they were applied, ready to be
+/// applied again. A frame cut short at the end of the file, as left by a crash partway through
+/// writing it, is dropped. The start and end of sagas are left out, so a saga interrupted by a
+/// crash can only be [recovered](crate::engine::Engine::recover_sagas) from
+/// [`read_wal_entries`].
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't a WAL, is corrupt, is encrypted, can only be
+/// read by a newer version of this crate, or records custom transactions, which need reading with
+/// [`read_wal_entries`], or any error reading it
+pub fn read_wal<R: Read>(reader: &mut R) -> Result<Vec<Transaction>, Error> {
+    transactions_only(read_wal_with(reader, None)?.entries)
+}
+
+/// Reads back the transactions written by a [`Wal`] encrypted with the supplied key, as for
+/// [`read_wal`]
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't an encrypted WAL, is corrupt or encrypted with
+/// another key, can only be read by a newer version of this crate, or records custom
+/// transactions, or any error reading it
+#[cfg(feature = "encryption")]
+pub fn read_wal_encrypted<R: Read>(
+    reader: &mut R,
+    key: &EncryptionKey,
+) -> Result<Vec<Transaction>, Error> {
+    transactions_only(read_wal_with(reader, Some(key))?.entries)
+}
+
+/// Reads back everything written by a [`Wal`], custom transactions and sagas included, in the order
+/// it was applied, ready to be [replayed](crate::engine::Engine::replay). A frame cut short at the
+/// end of the file is dropped, as for [`read_wal`].
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't a WAL, is corrupt, is encrypted, or can only be
+/// read by a newer version of this crate, or any error reading it
+pub fn read_wal_entries<R: Read>(reader: &mut R) -> Result<Vec<WalEntry>, Error> {
+    Ok(read_wal_with(reader, None)?.entries)
+}
+
+/// Reads back everything written by a [`Wal`] encrypted with the supplied key, as for
+/// [`read_wal_entries`]
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't an encrypted WAL, is corrupt or encrypted with
+/// another key, or can only be read by a newer version of this crate, or any error reading it
+#[cfg(feature = "encryption")]
+pub fn read_wal_entries_encrypted<R: Read>(
+    reader: &mut R,
+    key: &EncryptionKey,
+) -> Result<Vec<WalEntry>, Error> {
+    Ok(read_wal_with(reader, Some(key))?.entries)
+}
+
+/// Checks every frame of a WAL follows on from the one before, and returns the digest of the
+/// last, to compare with the [`head`](Wal::head) kept when it was written. A frame cut short at
+/// the end of the file is dropped, as for [`read_wal`].
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't a WAL, is corrupt, has had frames dropped or
+/// moved around, is encrypted, or can only be read by a newer version of this crate, or any error
+/// reading it
+pub fn verify_wal<R: Read>(reader: &mut R) -> Result<String, Error> {
+    Ok(read_wal_with(reader, None)?.head)
+}
+
+/// Checks every frame of a WAL encrypted with the supplied key follows on from the one before, as
+/// for [`verify_wal`]
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't an encrypted WAL, is corrupt or encrypted with
+/// another key, or can only be read by a newer version of this crate, or any error reading it
+#[cfg(feature = "encryption")]
+pub fn verify_wal_encrypted<R: Read>(reader: &mut R, key: &EncryptionKey) -> Result<String, Error> {
+    Ok(read_wal_with(reader, Some(key))?.head)
+}
+
+/// Reads back the latest offset a WAL recorded for each input, as [advanced](Wal::advance), to
+/// resume from on top of those in the [`Snapshot`] it was started alongside. A frame cut short at
+/// the end of the file is dropped, along with its offsets, as for [`read_wal`].
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't a WAL, is corrupt, is encrypted, or can only be
+/// read by a newer version of this crate, or any error reading it
+pub fn read_wal_offsets<R: Read>(reader: &mut R) -> Result<BTreeMap<String, u64>, Error> {
+    Ok(read_wal_with(reader, None)?.offsets)
+}
+
+/// Reads back the latest offset a WAL encrypted with the supplied key recorded for each input, as
+/// for [`read_wal_offsets`]
+/// # Errors
+/// [`ParseError::InvalidState`] if the file isn't an encrypted WAL, is corrupt or encrypted with
+/// another key, or can only be read by a newer version of this crate, or any error reading it
+#[cfg(feature = "encryption")]
+pub fn read_wal_offsets_encrypted<R: Read>(
+    reader: &mut R,
+    key: &EncryptionKey,
+) -> Result<BTreeMap<String, u64>, Error> {
+    Ok(read_wal_with(reader, Some(key))?.offsets)
+}
+
+/// Everything read back from a WAL
+#[derive(Debug)]
+struct WalContents {
+    /// What it recorded applying, in order
+    entries: Vec<WalEntry>,
+    /// The latest offset it recorded for each input
+    offsets: BTreeMap<String, u64>,
+    /// The digest of its last frame
+    head: String,
+}
+
+/// Reads back everything written by a [`Wal`], decrypting it if there's a key
+fn read_wal_with<R: Read>(reader: &mut R, key: Option<&Key>) -> Result<WalContents, Error> {
+    let version = read_header(reader, Kind::Wal, key.is_some())?;
+    let mut chain = Chain::new(version);
+    let mut entries = vec![];
+    let mut offsets = BTreeMap::new();
+    while let Some(payload) = chain.next(reader, key)? {
+        let payload = Migrations::builtin().migrate(version, Kind::Wal, payload)?;
+        if let Some(advanced) = payload.get("offsets") {
+            offsets.extend(
+                BTreeMap::<String, u64>::deserialize(advanced)
+                    .map_err(|err| invalid(format!("malformed frame: {err}")))?,
+            );
+        }
+        let stored: StoredEntry = serde_json::from_value(payload)
+            .map_err(|err| invalid(format!("malformed frame: {err}")))?;
+        entries.extend(stored.into_entry());
+    }
+    Ok(WalContents {
+        entries,
+        offsets,
+        head: chain.head,
+    })
+}
+
+/// Returns the transactions a WAL recorded, failing if it recorded custom transactions too,
+/// rather than leaving them out
+fn transactions_only(entries: Vec<WalEntry>) -> Result<Vec<Transaction>, Error> {
+    entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            WalEntry::Transaction(transaction) => Some(Ok(transaction)),
+            WalEntry::Custom(_) => Some(Err(invalid(
+                "WAL records custom transactions, so needs reading with read_wal_entries",
+            ))),
+            WalEntry::SagaStarted(_) | WalEntry::SagaFinished(_) => None,
+        })
+        .collect()
+}
+
+/// A 256-bit key for encrypting state files with AES-256-GCM, behind the `encryption` feature.
+///
+/// Keys are written as 64 hex digits, so they can be kept in configuration or the environment
+/// rather than code. The key itself is never shown, including by `Debug`.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct EncryptionKey(Aes256Gcm);
+
+#[cfg(feature = "encryption")]
+impl EncryptionKey {
+    /// Creates a key from its bytes
+    #[must_use]
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(Aes256Gcm::new(&bytes.into()))
+    }
+
+    /// Reads a key from the named environment variable, as 64 hex digits
+    /// # Errors
+    /// [`ParseError::InvalidKey`] if the variable isn't set, or isn't 64 hex digits
+    pub fn from_env(name: &str) -> Result<Self, Error> {
+        let key =
+            std::env::var(name).map_err(|_| ParseError::InvalidKey(format!("{name} isn't set")))?;
+        Ok(key.parse()?)
+    }
+
+    /// Encrypts a frame's payload, authenticating it with the frame's index
+    fn seal(&self, payload: &[u8], index: u64) -> io::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: &index.to_le_bytes(),
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "frame too large to encrypt")
+            })?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a frame's payload, checking it's the frame at the index
+    fn open(&self, payload: &[u8], index: u64) -> Result<Vec<u8>, Error> {
+        if payload.len() < NONCE_LEN {
+            return Err(invalid("encrypted frame is too short"));
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        self.0
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &index.to_le_bytes(),
+                },
+            )
+            .map_err(|_| invalid("can't decrypt frame; wrong key, or it's been tampered with"))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl FromStr for EncryptionKey {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The key's left out of the error, so it doesn't end up in logs
+        crate::types::decode_hex(s)
+            .map(Self::from_bytes)
+            .ok_or_else(|| ParseError::InvalidKey("encryption key isn't 64 hex digits".to_string()))
+    }
+}
+
+/// Creates a [`ParseError::InvalidState`]
+pub(crate) fn invalid(reason: impl Into<String>) -> Error {
+    ParseError::InvalidState(reason.into()).into()
+}
+
+/// Writes the header for a file of the supplied kind, written with the supplied schema version
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    kind: Kind,
+    version: u16,
+    encrypted: bool,
+) -> io::Result<()> {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&version.to_le_bytes());
+    header[6..8].copy_from_slice(&kind.min_reader_version().to_le_bytes());
+    header[8] = kind as u8 | if encrypted { ENCRYPTED } else { 0 };
+    writer.write_all(&header)
+}
+
+/// Reads a header, checking it's for a file of the supplied kind that this crate can read, and is
+/// encrypted or not as expected, and returns the schema version the file was written with
+fn read_header<R: Read>(reader: &mut R, kind: Kind, encrypted: bool) -> Result<u16, Error> {
+    let header = read_any_header(reader)?;
+    match (header.encrypted, encrypted) {
+        (true, false) => return Err(invalid("file is encrypted, so needs a key to read")),
+        (false, true) => return Err(invalid("file isn't encrypted")),
+        _ => {}
+    }
+    if header.kind != kind {
+        return Err(invalid(format!(
+            "expected {}, found {}",
+            kind.describe(),
+            header.kind.describe()
+        )));
+    }
+    Ok(header.version)
+}
+
+/// Reads a header, checking it's for a state file this crate can read, of either kind
+pub(crate) fn read_any_header<R: Read>(reader: &mut R) -> Result<Header, Error> {
+    let mut header = [0; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(invalid("not a cashflow state file"));
+        }
+        result => result?,
+    }
+    if header[..4] != MAGIC {
+        return Err(invalid("not a cashflow state file"));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    let min_reader_version = u16::from_le_bytes([header[6], header[7]]);
+    if min_reader_version > SCHEMA_VERSION {
+        return Err(invalid(format!(
+            "written with schema version {version}, which needs at least version \
+             {min_reader_version} to read; this version reads up to {SCHEMA_VERSION}"
+        )));
+    }
+    let kind = match header[8] & !ENCRYPTED {
+        1 => Kind::Snapshot,
+        2 => Kind::Wal,
+        kind => return Err(invalid(format!("unknown kind of file {kind}"))),
+    };
+    Ok(Header {
+        version,
+        kind,
+        encrypted: header[8] & ENCRYPTED != 0,
+    })
+}
+
+/// Writes a frame holding the supplied payload, encrypted if there's a key. `index` counts the
+/// frames in the file from 0.
+pub(crate) fn write_frame<W: Write, P: Serialize>(
+    writer: &mut W,
+    payload: &P,
+    key: Option<&Key>,
+    index: u64,
+) -> io::Result<()> {
+    let payload = serde_json::to_vec(payload)?;
+    let payload = match key {
+        #[cfg(feature = "encryption")]
+        Some(key) => key.seal(&payload, index)?,
+        #[cfg(not(feature = "encryption"))]
+        Some(&never) => match never {},
+        None => payload,
+    };
+    #[cfg(not(feature = "encryption"))]
+    let _ = index;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&crc32(&payload).to_le_bytes())?;
+    writer.write_all(&payload)
+}
+
+/// Reads the next frame, decrypting it if there's a key, and migrating its payload from the
+/// schema version it was written with. Returns `None` at the end of the file, or if the last
+/// frame was cut short.
+fn read_frame<P: DeserializeOwned, R: Read>(
+    reader: &mut R,
+    version: u16,
+    kind: Kind,
+    key: Option<&Key>,
+    index: u64,
+) -> Result<Option<P>, Error> {
+    read_raw_frame(reader, key, index)?
+        .map(|payload| decode(version, kind, payload))
+        .transpose()
+}
+
+/// Migrates a frame's payload from the schema version it was written with, and deserializes it
+fn decode<P: DeserializeOwned>(version: u16, kind: Kind, payload: Value) -> Result<P, Error> {
+    let payload = Migrations::builtin().migrate(version, kind, payload)?;
+    serde_json::from_value(payload).map_err(|err| invalid(format!("malformed frame: {err}")))
+}
+
+/// Reads the next frame, decrypting it if there's a key, and returns its payload as written.
+/// Returns `None` at the end of the file, or if the last frame was cut short.
+pub(crate) fn read_raw_frame<R: Read>(
+    reader: &mut R,
+    key: Option<&Key>,
+    index: u64,
+) -> Result<Option<Value>, Error> {
+    read_plain_frame(reader, key, index)?
+        .map(|payload| parse(&payload))
+        .transpose()
+}
+
+/// Parses a frame's payload
+fn parse(payload: &[u8]) -> Result<Value, Error> {
+    serde_json::from_slice(payload).map_err(|err| invalid(format!("malformed frame: {err}")))
+}
+
+/// Reads the next frame, decrypting it if there's a key, and returns its payload's bytes as
+/// written. Returns `None` at the end of the file, or if the last frame was cut short.
+fn read_plain_frame<R: Read>(
+    reader: &mut R,
+    key: Option<&Key>,
+    index: u64,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut prefix = [0; 8];
+    match reader.read_exact(&mut prefix) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+    // Read through `take` rather than into a buffer of `len` bytes, so a corrupt length can't
+    // allocate gigabytes
+    let mut payload = vec![];
+    reader
+        .by_ref()
+        .take(u64::from(len))
+        .read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Ok(None);
+    }
+    let checksum = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+    if crc32(&payload) != checksum {
+        return Err(invalid("checksum mismatch"));
+    }
+    let payload = match key {
+        #[cfg(feature = "encryption")]
+        Some(key) => key.open(&payload, index)?,
+        #[cfg(not(feature = "encryption"))]
+        Some(&never) => match never {},
+        None => payload,
+    };
+    #[cfg(not(feature = "encryption"))]
+    let _ = index;
+    Ok(Some(payload))
+}
+
+/// Adds the digest of the last frame to a WAL frame's payload, replacing any it had, and moves
+/// `head` on to it
+pub(crate) fn link(mut payload: Value, head: &mut String) -> io::Result<Value> {
+    let Some(fields) = payload.as_object_mut() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame isn't an object",
+        ));
+    };
+    fields.insert("prev".to_string(), Value::from(head.as_str()));
+    *head = digest(None, &serde_json::to_vec(&payload)?);
+    Ok(payload)
+}
+
+/// Reads a WAL's frames in turn, checking each follows on from the one before
+#[derive(Debug)]
+pub(crate) struct Chain {
+    /// Schema version the WAL was written with, since those written before frames were chained
+    /// aren't
+    version: u16,
+    /// Digest of the last frame read
+    head: String,
+    /// Number of frames read so far
+    frames: u64,
+}
+
+impl Chain {
+    /// Starts reading the frames of a WAL written with the supplied schema version
+    pub(crate) fn new(version: u16) -> Self {
+        Self {
+            version,
+            head: GENESIS.to_string(),
+            frames: 0,
+        }
+    }
+
+    /// Reads the next frame, decrypting it if there's a key, and returns its payload as written.
+    /// Returns `None` at the end of the file, or if the last frame was cut short.
+    pub(crate) fn next<R: Read>(
+        &mut self,
+        reader: &mut R,
+        key: Option<&Key>,
+    ) -> Result<Option<Value>, Error> {
+        let Some(bytes) = read_plain_frame(reader, key, self.frames)? else {
+            return Ok(None);
+        };
+        let payload = parse(&bytes)?;
+        match payload.get("prev") {
+            None if self.version < CHAINED_SINCE => {}
+            Some(prev) if prev.as_str() == Some(&self.head) => {}
+            _ => {
+                return Err(invalid(format!(
+                    "frame {} doesn't follow on from the one before",
+                    self.frames
+                )))
+            }
+        }
+        self.head = digest(None, &bytes);
+        self.frames += 1;
+        Ok(Some(payload))
+    }
+}
+
+/// Computes the CRC-32 (as used by zip and PNG) of some bytes
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        saga::StepStatus,
+        types::{MemoryAccountBook, MemoryTransactionLog, Money, RawTransactionId},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_and_wal() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+dispute,2,2,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let snapshot = Snapshot::capture(engine.account_book(), engine.transaction_log());
+        let mut file = vec![];
+        snapshot.write(&mut file).unwrap();
+        assert_eq!(&file[..4], b"CFST");
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let wal = Wal::new(vec![]);
+        engine.add_listener(wal.clone());
+        let input = "type,client,tx,amount
+withdrawal,1,3,1.5
+chargeback,2,2,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        wal.finish().unwrap();
+        let mut wal = wal.inner.lock().unwrap().writer.clone();
+
+        let mut account_book = MemoryAccountBook::new();
+        let mut transaction_log = MemoryTransactionLog::new();
+        let restored = Snapshot::read(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(restored, snapshot);
+        restored
+            .restore(&mut account_book, &mut transaction_log)
+            .unwrap();
+        // A crash partway through writing the last frame leaves it cut short
+        wal.truncate(wal.len() - 3);
+        let transactions = read_wal(&mut Cursor::new(&wal)).unwrap();
+        assert_eq!(transactions.len(), 1);
+        for transaction in transactions {
+            account_book
+                .apply(&mut transaction_log, &mut transaction.into())
+                .unwrap();
+        }
+        assert_eq!(
+            account_book.account(ClientId(1)).unwrap().funds_available(),
+            dec!(3.5)
+        );
+        assert_eq!(
+            account_book.account(ClientId(2)).unwrap().funds_held(),
+            dec!(3)
+        );
+
+        assert!(matches!(
+            read_wal(&mut Cursor::new(&file)),
+            Err(Error::Parse(ParseError::InvalidState(_)))
+        ));
+        file[4..8].copy_from_slice(&[99, 0, 98, 0]);
+        assert!(Snapshot::read(&mut Cursor::new(&file)).is_err());
+        file[6] = 1;
+        assert_eq!(Snapshot::read(&mut Cursor::new(&file)).unwrap(), snapshot);
+        let payload = file.len() - 2;
+        file[payload] ^= 1;
+        let err = Snapshot::read(&mut Cursor::new(&file)).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid state file: checksum mismatch");
+    }
+
+    #[test]
+    fn test_wal_chain() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let wal = Wal::new(vec![]);
+        assert_eq!(wal.head(), GENESIS);
+        engine.add_listener(wal.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+withdrawal,1,3,1.5
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        wal.finish().unwrap();
+        let head = wal.head();
+        let file = wal.inner.lock().unwrap().writer.clone();
+        assert_eq!(verify_wal(&mut Cursor::new(&file)).unwrap(), head);
+
+        let frame_len = |at: usize| {
+            8 + u32::from_le_bytes([file[at], file[at + 1], file[at + 2], file[at + 3]]) as usize
+        };
+        let second = HEADER_LEN + frame_len(HEADER_LEN);
+        let third = second + frame_len(second);
+        let reason = |file: &[u8]| read_wal(&mut Cursor::new(file)).unwrap_err().to_string();
+        // Each frame has a valid checksum on its own, but doesn't follow on from the one before
+        let dropped = [&file[..second], &file[third..]].concat();
+        assert_eq!(
+            reason(&dropped),
+            "Invalid state file: frame 1 doesn't follow on from the one before"
+        );
+        let swapped = [
+            &file[..HEADER_LEN],
+            &file[second..third],
+            &file[HEADER_LEN..second],
+            &file[third..],
+        ]
+        .concat();
+        assert_eq!(
+            reason(&swapped),
+            "Invalid state file: frame 0 doesn't follow on from the one before"
+        );
+        // Cutting frames off the end leaves a valid chain, but not the one written
+        let truncated = &file[..third];
+        assert_eq!(read_wal(&mut Cursor::new(truncated)).unwrap().len(), 2);
+        assert_ne!(verify_wal(&mut Cursor::new(truncated)).unwrap(), head);
+    }
+
+    #[test]
+    fn test_offsets() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine
+            .load_csv(&mut Cursor::new("type,client,tx,amount\ndeposit,1,1,5.0\n"))
+            .unwrap();
+        let mut snapshot = Snapshot::capture(engine.account_book(), engine.transaction_log());
+        let mut file = vec![];
+        snapshot.write(&mut file).unwrap();
+        // Snapshots without offsets are written as before
+        let contains = |file: &[u8]| file.windows(9).any(|window| window == b"\"offsets\"");
+        assert!(!contains(&file));
+
+        snapshot.offsets.insert("transactions/0".to_string(), 42);
+        snapshot.offsets.insert("transactions/1".to_string(), 7);
+        let mut file = vec![];
+        snapshot.write(&mut file).unwrap();
+        assert!(contains(&file));
+        let restored = Snapshot::read(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(restored.offsets["transactions/0"], 42);
+        assert_eq!(restored, snapshot);
+    }
+
+    #[test]
+    fn test_wal_offsets() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let wal = Wal::new(vec![]);
+        engine.add_listener(wal.clone());
+        // Each message is applied after advancing past it, as a consumer would
+        let messages = ["deposit,1,1,5.0", "withdrawal,1,2,1.5", "deposit,1,3,2.0"];
+        for (offset, message) in (1..).zip(messages) {
+            wal.advance("transactions/0", offset);
+            let input = format!("type,client,tx,amount\n{message}\n");
+            engine.load_csv(&mut Cursor::new(input)).unwrap();
+        }
+        let file = wal.inner.lock().unwrap().writer.clone();
+        assert_eq!(
+            read_wal_offsets(&mut Cursor::new(&file)).unwrap(),
+            [("transactions/0".to_string(), 3)].into()
+        );
+        // A crash partway through the last frame loses its offset along with its transaction
+        let crashed = &file[..file.len() - 3];
+        assert_eq!(read_wal(&mut Cursor::new(crashed)).unwrap().len(), 2);
+        assert_eq!(
+            read_wal_offsets(&mut Cursor::new(crashed)).unwrap(),
+            [("transactions/0".to_string(), 2)].into()
+        );
+
+        // Offsets past messages that applied nothing are written when the WAL is finished
+        wal.advance("transactions/0", 4);
+        wal.advance("transactions/1", 9);
+        wal.finish().unwrap();
+        let file = wal.inner.lock().unwrap().writer.clone();
+        assert_eq!(read_wal_entries(&mut Cursor::new(&file)).unwrap().len(), 3);
+        assert_eq!(
+            read_wal_offsets(&mut Cursor::new(&file)).unwrap(),
+            [
+                ("transactions/0".to_string(), 4),
+                ("transactions/1".to_string(), 9)
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_wal_sagas() {
+        let transfer = |amount, ids: [RawTransactionId; 3]| {
+            let amount = Money::new(amount, Currency::NONE);
+            Saga::transfer(ClientId(1), ClientId(2), amount, ids.map(TransactionId)).unwrap()
+        };
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let mut wal = Wal::new(vec![]);
+        engine.add_listener(wal.clone());
+        let deposit = Transaction::deposit(ClientId(1), TransactionId(1), dec!(10)).unwrap();
+        engine.apply(deposit).unwrap();
+        assert!(engine
+            .run_saga(transfer(dec!(4), [2, 3, 4]))
+            .unwrap()
+            .is_complete());
+        // A crash after the first step of a saga, before the second
+        let interrupted = transfer(dec!(3), [5, 6, 7]);
+        wal.on_saga_started(&interrupted);
+        let withdrawal = Transaction::withdrawal(ClientId(1), TransactionId(5), dec!(3)).unwrap();
+        engine.apply(withdrawal).unwrap();
+        let file = wal.inner.lock().unwrap().writer.clone();
+        assert_eq!(read_wal(&mut Cursor::new(&file)).unwrap().len(), 4);
+
+        // Replayed, the withdrawal is refunded, and the refund recorded
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine
+            .replay(read_wal_entries(&mut Cursor::new(&file)).unwrap())
+            .unwrap();
+        let balance = |engine: &Engine<MemoryAccountBook, MemoryTransactionLog>| {
+            let mut accounts = engine.account_book().accounts();
+            accounts
+                .find(|account| account.client_id() == ClientId(1))
+                .unwrap()
+                .total()
+        };
+        assert_eq!(balance(&engine), dec!(3));
+        let wal = Wal::new(vec![]);
+        engine.add_listener(wal.clone());
+        let outcomes = engine.recover_sagas().unwrap();
+        assert_eq!(outcomes.len(), 1);
+        let statuses: Vec<_> = outcomes[0].steps.iter().map(|step| step.status).collect();
+        assert_eq!(statuses, [StepStatus::Compensated, StepStatus::NotRun]);
+        assert_eq!(
+            outcomes[0].error.as_ref().unwrap().code(),
+            "saga_interrupted"
+        );
+        assert_eq!(balance(&engine), dec!(6));
+        assert!(engine.recover_sagas().unwrap().is_empty());
+        let file = wal.inner.lock().unwrap().writer.clone();
+        let entries = read_wal_entries(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(entries[0], WalEntry::SagaStarted(interrupted));
+        assert!(
+            matches!(&entries[1], WalEntry::Transaction(refund) if refund.transaction_id() == TransactionId(7))
+        );
+        assert_eq!(entries[2], WalEntry::SagaFinished(TransactionId(5)));
+    }
+
+    #[test]
+    fn test_engine_state() {
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+dispute,1,2,
+chargeback,1,2,
+deposit,2,3,4.0
+withdrawal,2,4,1.0
+deposit,2,5,2.0
+dispute,2,5,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        engine.erase_client(ClientId(1), b"secret").unwrap();
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.opening[&ClientId(1)], dec!(5));
+        assert_eq!(snapshot.disputed[&TransactionId(5)], 1);
+        let mut file = vec![];
+        snapshot.write(&mut file).unwrap();
+        let restored = Snapshot::read(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(restored, snapshot);
+
+        // Restored, the engine still checks out, and still knows what's under dispute
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.restore(restored).unwrap();
+        assert!(engine.check_consistency().is_consistent());
+        let err = engine.erase_client(ClientId(2), b"secret").unwrap_err();
+        assert_eq!(err.code(), "disputed");
+        engine
+            .load_csv(&mut Cursor::new(
+                "type,client,tx,amount
+chargeback,2,5,
+",
+            ))
+            .unwrap();
+        assert_eq!(
+            engine.charged_back().collect::<Vec<_>>(),
+            [(ClientId(2), dec!(2.0000))]
+        );
+        assert!(engine.check_consistency().is_consistent());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted() {
+        let key: EncryptionKey = "2a".repeat(32).parse().unwrap();
+        let other_key = EncryptionKey::from_bytes([1; 32]);
+        assert_eq!(format!("{key:?}"), "EncryptionKey(..)");
+        assert!("2a".parse::<EncryptionKey>().is_err());
+
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let wal = Wal::encrypted(vec![], key.clone());
+        engine.add_listener(wal.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+withdrawal,1,3,1.5
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let snapshot = engine.snapshot();
+        let mut file = vec![];
+        snapshot.write_encrypted(&mut file, &key).unwrap();
+        // Nothing legible makes it to disk
+        assert!(!String::from_utf8_lossy(&file).contains("\"client\""));
+        assert_eq!(
+            Snapshot::read_encrypted(&mut Cursor::new(&file), &key).unwrap(),
+            snapshot
+        );
+        let reason = |result: Result<Snapshot, Error>| result.unwrap_err().to_string();
+        assert_eq!(
+            reason(Snapshot::read(&mut Cursor::new(&file))),
+            "Invalid state file: file is encrypted, so needs a key to read"
+        );
+        assert_eq!(
+            reason(Snapshot::read_encrypted(
+                &mut Cursor::new(&file),
+                &other_key
+            )),
+            "Invalid state file: can't decrypt frame; wrong key, or it's been tampered with"
+        );
+        let mut plain = vec![];
+        snapshot.write(&mut plain).unwrap();
+        assert_eq!(
+            reason(Snapshot::read_encrypted(&mut Cursor::new(&plain), &key)),
+            "Invalid state file: file isn't encrypted"
+        );
+
+        wal.finish().unwrap();
+        let wal = wal.inner.lock().unwrap().writer.clone();
+        let transactions = read_wal_encrypted(&mut Cursor::new(&wal), &key).unwrap();
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(transactions[2].amount(), Some(dec!(1.5)));
+        // Dropping a frame from the middle breaks the authentication of those after it
+        let frame_len = |at: usize| {
+            8 + u32::from_le_bytes([wal[at], wal[at + 1], wal[at + 2], wal[at + 3]]) as usize
+        };
+        let second = HEADER_LEN + frame_len(HEADER_LEN);
+        let third = second + frame_len(second);
+        let dropped = [&wal[..second], &wal[third..]].concat();
+        assert!(read_wal_encrypted(&mut Cursor::new(&dropped), &key).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! [`proptest`] strategies for generating transactions, behind the `proptest` feature, so other
+//! [`AccountBook`](crate::types::AccountBook) and [`TransactionLog`](crate::types::TransactionLog)
+//! implementations can be property tested against the built-in ones.
+//!
+//! [`transaction`](crate::strategy::transaction) generates any single transaction, including
+//! nonsense like a chargeback of a transaction that never happened.
+//! [`transaction_sequence`](crate::strategy::transaction_sequence) generates well-formed inputs,
+//! with disputes, resolves and chargebacks of earlier deposits interleaved at random. Sequences
+//! are made of [`TransactionRecord`](crate::types::TransactionRecord)s, so the same sequence can
+//! be fed to several backends with [`apply_records`](crate::strategy::apply_records):
+//! ```
+//! # use cashflow::{strategy, types::*};
+//! # use proptest::prelude::*;
+//! proptest!(|(records in strategy::transaction_sequence(4, 50))| {
+//!     let mut reference = MemoryAccountBook::new();
+//!     let mut tested = MemoryAccountBook::new();
+//!     strategy::apply_records(&records, &mut reference, &mut MemoryTransactionLog::new());
+//!     strategy::apply_records(&records, &mut tested, &mut MinorUnitsTransactionLog::new());
+//!     for account in &reference {
+//!         let client = account.client_id();
+//!         prop_assert_eq!(account, tested.account(client).unwrap());
+//!     }
+//! });
+//! ```
+
+use proptest::{prelude::*, sample::Index};
+use rust_decimal::Decimal;
+
+use crate::{
+    errors::Error,
+    types::{
+        AccountBook, ClientId, Currency, RawClientId, RawTransactionId, Transaction, TransactionId,
+        TransactionLog, TransactionRecord, TransactionType, DECIMAL_SCALE,
+    },
+};
+
+/// The largest amount generated, in ten-thousandths
+const MAX_MINOR_UNITS: i64 = 1_000_000_000_000;
+
+/// Generates any client ID
+pub fn client_id() -> impl Strategy<Value = ClientId> {
+    any::<RawClientId>().prop_map(ClientId)
+}
+
+/// Generates any transaction ID
+pub fn transaction_id() -> impl Strategy<Value = TransactionId> {
+    any::<RawTransactionId>().prop_map(TransactionId)
+}
+
+/// Generates a positive amount with up to four decimal places, of at most 100 million
+pub fn amount() -> impl Strategy<Value = Decimal> {
+    (1..=MAX_MINOR_UNITS).prop_map(|minor_units| Decimal::new(minor_units, DECIMAL_SCALE))
+}
+
+/// Generates any transaction type
+pub fn transaction_type() -> impl Strategy<Value = TransactionType> {
+    prop::sample::select(TransactionType::ALL.to_vec())
+}
+
+/// Generates any transaction. Deposits and withdrawals have a positive amount, and the rest have
+/// none, but nothing else is guaranteed: disputes may refer to transactions that don't exist, or
+/// belong to another client.
+pub fn transaction() -> impl Strategy<Value = Transaction> {
+    (transaction_type(), client_id(), transaction_id(), amount()).prop_map(
+        |(transaction_type, client_id, transaction_id, amount)| Transaction {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: matches!(
+                transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+            .then_some(amount),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        },
+    )
+}
+
+/// One step of a generated sequence, before it's turned into a transaction
+#[derive(Debug, Clone)]
+enum Step {
+    /// A deposit into the client's account
+    Deposit(RawClientId, Decimal),
+    /// A withdrawal from the client's account
+    Withdrawal(RawClientId, Decimal),
+    /// A dispute of one of the deposits not under dispute, if there are any
+    Dispute(Index),
+    /// A resolve of one of the deposits under dispute, if there are any
+    Resolve(Index),
+    /// A chargeback of one of the deposits under dispute, if there are any
+    Chargeback(Index),
+}
+
+/// Generates well-formed sequences of up to `max_len` transactions, for clients below `clients`.
+///
+/// Transaction IDs are unique and increasing, and every dispute, resolve and chargeback refers to
+/// an earlier deposit by the same client: disputes to one not under dispute, and resolves and
+/// chargebacks to one that is. A resolved deposit may be disputed again, but a charged back one
+/// can't. Otherwise, anything goes, so withdrawals may be for more than is available, and
+/// transactions may follow a chargeback that locked their account; how those are handled is part
+/// of what's being tested.
+///
+/// # Panics
+/// If `clients` is zero
+pub fn transaction_sequence(
+    clients: RawClientId,
+    max_len: usize,
+) -> impl Strategy<Value = Vec<TransactionRecord>> {
+    assert!(clients > 0, "sequences need at least one client");
+    let step = prop_oneof![
+        3 => (0..clients, amount()).prop_map(|(client, amount)| Step::Deposit(client, amount)),
+        2 => (0..clients, amount()).prop_map(|(client, amount)| Step::Withdrawal(client, amount)),
+        2 => any::<Index>().prop_map(Step::Dispute),
+        1 => any::<Index>().prop_map(Step::Resolve),
+        1 => any::<Index>().prop_map(Step::Chargeback),
+    ];
+    prop::collection::vec(step, 0..=max_len).prop_map(build_sequence)
+}
+
+/// Turns steps into transactions, dropping disputes, resolves and chargebacks with nothing to
+/// refer to
+fn build_sequence(steps: Vec<Step>) -> Vec<TransactionRecord> {
+    let mut records = Vec::with_capacity(steps.len());
+    let mut next_id: RawTransactionId = 1;
+    // Deposits that can be disputed, and those under dispute, by client and ID
+    let mut undisputed: Vec<(ClientId, TransactionId)> = vec![];
+    let mut disputed: Vec<(ClientId, TransactionId)> = vec![];
+    for step in steps {
+        let (transaction_type, client_id, transaction_id, amount) = match step {
+            Step::Deposit(client, amount) | Step::Withdrawal(client, amount) => {
+                let transaction_id = TransactionId(next_id);
+                next_id += 1;
+                let transaction_type = if let Step::Deposit(..) = step {
+                    undisputed.push((ClientId(client), transaction_id));
+                    TransactionType::Deposit
+                } else {
+                    TransactionType::Withdrawal
+                };
+                (
+                    transaction_type,
+                    ClientId(client),
+                    transaction_id,
+                    Some(amount),
+                )
+            }
+            Step::Dispute(index) if !undisputed.is_empty() => {
+                let (client_id, transaction_id) =
+                    undisputed.swap_remove(index.index(undisputed.len()));
+                disputed.push((client_id, transaction_id));
+                (TransactionType::Dispute, client_id, transaction_id, None)
+            }
+            Step::Resolve(index) if !disputed.is_empty() => {
+                let (client_id, transaction_id) = disputed.swap_remove(index.index(disputed.len()));
+                undisputed.push((client_id, transaction_id));
+                (TransactionType::Resolve, client_id, transaction_id, None)
+            }
+            Step::Chargeback(index) if !disputed.is_empty() => {
+                let (client_id, transaction_id) = disputed.swap_remove(index.index(disputed.len()));
+                (TransactionType::Chargeback, client_id, transaction_id, None)
+            }
+            Step::Dispute(_) | Step::Resolve(_) | Step::Chargeback(_) => continue,
+        };
+        records.push(TransactionRecord {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            timestamp: None,
+        });
+    }
+    records
+}
+
+/// Applies each transaction in turn, as a [`Transaction`] rebuilt from its record, returning the
+/// result of each. Rejected transactions don't stop the rest from being applied.
+pub fn apply_records<A, T>(
+    records: &[TransactionRecord],
+    account_book: &mut A,
+    transaction_log: &mut T,
+) -> Vec<Result<(), Error>>
+where
+    A: AccountBook,
+    T: TransactionLog,
+{
+    records
+        .iter()
+        .map(|record| {
+            let transaction = Transaction {
+                transaction_type: record.transaction_type,
+                client_id: record.client_id,
+                transaction_id: record.transaction_id,
+                amount: record.amount,
+                currency: Currency::NONE,
+                timestamp: None,
+                signature: None,
+            };
+            account_book.apply(transaction_log, &mut transaction.into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{MemoryAccountBook, MemoryTransactionLog, MinorUnitsTransactionLog};
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_backends_agree(records in transaction_sequence(5, 100)) {
+            let mut reference = MemoryAccountBook::new();
+            let mut minor_units = MemoryAccountBook::new();
+            let outcomes = apply_records(&records, &mut reference, &mut MemoryTransactionLog::new());
+            let minor_outcomes =
+                apply_records(&records, &mut minor_units, &mut MinorUnitsTransactionLog::new());
+            let codes = |outcomes: Vec<Result<(), Error>>| -> Vec<_> {
+                outcomes.iter().map(|outcome| outcome.as_ref().map_err(Error::code).err()).collect()
+            };
+            prop_assert_eq!(codes(outcomes), codes(minor_outcomes));
+            for account in &reference {
+                // Only deposits are disputed, and each is resolved or charged back at most once
+                // per dispute, so nothing more can be released than was held
+                prop_assert!(account.funds_held() >= Decimal::ZERO);
+                prop_assert_eq!(Some(account), minor_units.accounts.get(&account.client_id));
+            }
+        }
+    }
+}
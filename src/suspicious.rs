@@ -0,0 +1,333 @@
+//! A consolidated report of suspicious activity, for compliance review at the end of a run.
+//!
+//! A [`SuspiciousActivity`](crate::suspicious::SuspiciousActivity) listener runs the
+//! [alerting](crate::notify::Alerting) rules and the [anomaly](crate::anomaly::AnomalyDetector)
+//! checks side by side, and gathers everything they flag into one
+//! [`Finding`](crate::suspicious::Finding) per client and [`Rule`](crate::suspicious::Rule), with
+//! the transactions involved and their amounts. Nothing is rejected or sent anywhere: findings
+//! are only written out, as CSV or JSON, when asked for.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    anomaly::{AnomalyDetector, AnomalyThresholds},
+    errors::Error,
+    events::{DomainEvent, EventListener},
+    notify::{Alert, Alerting, Notifier},
+    types::{Account, ClientId, TransactionId, TransactionRecord, DECIMAL_SCALE},
+};
+
+/// What made a transaction suspicious
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// A deposit or withdrawal far larger than the account's average, as judged by
+    /// [`AnomalyThresholds`]. The amount is the deposit or withdrawal.
+    UnusualAmount,
+    /// A chargeback of at least the threshold set with
+    /// [`with_large_chargeback`](SuspiciousActivity::with_large_chargeback). The amount is what
+    /// was charged back.
+    LargeChargeback,
+    /// A chargeback locked the account. The amount is what was charged back.
+    AccountLocked,
+    /// The account's total balance went below zero. The amount is the total afterwards.
+    NegativeBalance,
+}
+
+impl Rule {
+    /// Returns the rule's name, as written in reports
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UnusualAmount => "unusual_amount",
+            Self::LargeChargeback => "large_chargeback",
+            Self::AccountLocked => "account_locked",
+            Self::NegativeBalance => "negative_balance",
+        }
+    }
+}
+
+/// Everything one rule flagged for one client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    /// The client flagged
+    pub client: ClientId,
+    /// The rule triggered
+    pub rule: Rule,
+    /// The transactions that triggered it, in the order they were applied
+    pub transactions: Vec<TransactionId>,
+    /// The amount for each transaction, as described for the [`Rule`]
+    pub amounts: Vec<Decimal>,
+}
+
+/// The findings in a JSON report
+#[derive(Debug, Serialize)]
+struct Findings<'a> {
+    /// Every finding, by client and then rule
+    findings: &'a [Finding],
+}
+
+/// Keeps every alert it's sent, for a [`SuspiciousActivity`] to report on
+#[derive(Debug, Default)]
+struct AlertLog {
+    /// Shared with clones
+    alerts: Arc<Mutex<Vec<Alert>>>,
+}
+
+impl Clone for AlertLog {
+    fn clone(&self) -> Self {
+        Self {
+            alerts: Arc::clone(&self.alerts),
+        }
+    }
+}
+
+impl Notifier for AlertLog {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Error> {
+        self.alerts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(*alert);
+        Ok(())
+    }
+}
+
+/// Watches what an engine applies with both the alerting rules and the anomaly checks, to report
+/// everything suspicious at the end of a run.
+///
+/// As with an [`AnomalyDetector`], clones share the same findings, so keep one to call
+/// [`report`](Self::report) on after registering another with the engine.
+#[derive(Debug, Clone)]
+pub struct SuspiciousActivity {
+    /// Checks for locks, large chargebacks and negative balances
+    alerting: Alerting<AlertLog>,
+    /// Everything `alerting` has raised
+    alerts: AlertLog,
+    /// Checks for unusually large deposits and withdrawals
+    anomalies: AnomalyDetector,
+    /// Decimal places of the balances last seen, which are at the account book's scale
+    scale: Arc<AtomicU32>,
+}
+
+impl SuspiciousActivity {
+    /// Creates a listener judging amounts according to the supplied thresholds. Chargebacks are
+    /// only flagged if they lock the account, until a threshold is set with
+    /// [`with_large_chargeback`](Self::with_large_chargeback).
+    #[must_use]
+    pub fn new(thresholds: AnomalyThresholds) -> Self {
+        let alerts = AlertLog::default();
+        Self {
+            alerting: Alerting::new(alerts.clone()),
+            alerts,
+            anomalies: AnomalyDetector::new(thresholds),
+            scale: Arc::new(AtomicU32::new(DECIMAL_SCALE)),
+        }
+    }
+
+    /// Flags chargebacks of at least `amount`
+    #[must_use]
+    pub fn with_large_chargeback(self, amount: Decimal) -> Self {
+        Self {
+            alerting: self.alerting.with_large_chargeback(amount),
+            ..self
+        }
+    }
+
+    /// Returns everything flagged so far, with one finding per client and rule, in client order
+    #[must_use]
+    pub fn report(&self) -> Vec<Finding> {
+        let mut findings: BTreeMap<(ClientId, Rule), Finding> = BTreeMap::new();
+        let scale = self.scale.load(Ordering::Relaxed);
+        let mut flag = |client, rule, transaction, mut amount: Decimal| {
+            // Anomalies keep the scale of the input, but alerts are at the account book's scale
+            amount.rescale(scale);
+            let finding = findings.entry((client, rule)).or_insert_with(|| Finding {
+                client,
+                rule,
+                transactions: vec![],
+                amounts: vec![],
+            });
+            finding.transactions.push(transaction);
+            finding.amounts.push(amount);
+        };
+        for anomaly in self.anomalies.report() {
+            flag(
+                anomaly.client,
+                Rule::UnusualAmount,
+                anomaly.transaction,
+                anomaly.amount,
+            );
+        }
+        let alerts = self
+            .alerts
+            .alerts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for alert in alerts.iter() {
+            match *alert {
+                Alert::LargeChargeback {
+                    client,
+                    transaction,
+                    amount,
+                } => flag(client, Rule::LargeChargeback, transaction, amount),
+                // Only a chargeback of a disputed amount can lock an account
+                Alert::AccountLocked {
+                    client,
+                    transaction,
+                    amount,
+                    ..
+                } => flag(
+                    client,
+                    Rule::AccountLocked,
+                    transaction,
+                    amount.unwrap_or_default(),
+                ),
+                Alert::NegativeBalance {
+                    client,
+                    transaction,
+                    total,
+                } => flag(client, Rule::NegativeBalance, transaction, total),
+            }
+        }
+        findings.into_values().collect()
+    }
+
+    /// Writes everything flagged so far as CSV, with a header of
+    /// `client,rule,transactions,amounts`. The transactions and amounts of each finding are
+    /// separated by spaces.
+    /// # Errors
+    /// If writing fails
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        csv_writer.write_record(["client", "rule", "transactions", "amounts"])?;
+        for finding in self.report() {
+            let transactions: Vec<String> = finding
+                .transactions
+                .iter()
+                .map(|transaction| transaction.0.to_string())
+                .collect();
+            let amounts: Vec<String> = finding.amounts.iter().map(ToString::to_string).collect();
+            csv_writer.write_record([
+                finding.client.0.to_string(),
+                finding.rule.name().to_string(),
+                transactions.join(" "),
+                amounts.join(" "),
+            ])?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes everything flagged so far as a single JSON document, like
+    /// `{"findings":[{"client":1,"rule":"unusual_amount","transactions":[3],"amounts":["500.0000"]}]}`
+    /// # Errors
+    /// If writing fails
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let findings = self.report();
+        serde_json::to_writer(
+            &mut *writer,
+            &Findings {
+                findings: &findings,
+            },
+        )
+        .map_err(std::io::Error::from)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl EventListener for SuspiciousActivity {
+    fn on_applied(&mut self, transaction: &TransactionRecord, account: &Account) {
+        self.alerting.on_applied(transaction, account);
+        self.anomalies.on_applied(transaction, account);
+        self.scale
+            .store(account.funds_available().scale(), Ordering::Relaxed);
+    }
+
+    fn on_account_locked(&mut self, transaction: &TransactionRecord, account: &Account) {
+        self.alerting.on_account_locked(transaction, account);
+    }
+
+    fn on_event(&mut self, event: &DomainEvent) {
+        self.alerting.on_event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_suspicious_activity() {
+        let suspicious = SuspiciousActivity::new(AnomalyThresholds {
+            min_history: 2,
+            ..AnomalyThresholds::default()
+        })
+        .with_large_chargeback(dec!(100));
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        engine.add_listener(suspicious.clone());
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,12.0
+deposit,1,3,500.0
+deposit,2,4,150.0
+withdrawal,2,5,100.0
+dispute,2,4,
+chargeback,2,4,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let report = suspicious.report();
+        let flagged: Vec<_> = report
+            .iter()
+            .map(|finding| (finding.client.0, finding.rule))
+            .collect();
+        assert_eq!(
+            flagged,
+            [
+                (1, Rule::UnusualAmount),
+                (2, Rule::LargeChargeback),
+                (2, Rule::AccountLocked),
+                (2, Rule::NegativeBalance),
+            ]
+        );
+        assert_eq!(report[3].amounts, [dec!(-100)]);
+
+        let mut output = vec![];
+        suspicious.write_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,rule,transactions,amounts
+1,unusual_amount,3,500.0000
+2,large_chargeback,4,150.0000
+2,account_locked,4,150.0000
+2,negative_balance,4,-100.0000
+"
+        );
+
+        let mut output = vec![];
+        suspicious.write_json(&mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json["findings"][0]["rule"], "unusual_amount");
+        assert_eq!(json["findings"][0]["transactions"][0], 3);
+        assert_eq!(json["findings"][0]["amounts"][0], "500.0000");
+    }
+}
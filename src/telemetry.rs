@@ -0,0 +1,293 @@
+//! OpenTelemetry traces and metrics, so an [`Engine`](crate::engine::Engine) shows up in the same
+//! observability stack as the services around it.
+//!
+//! Engines report through the global OpenTelemetry providers:
+//! - a `cashflow.batch` span for each call to
+//!   [`load_csv`](crate::engine::Engine::load_csv),
+//!   [`load_csv_files`](crate::engine::Engine::load_csv_files) or
+//!   [`apply_chunk`](crate::engine::Engine::apply_chunk), with the number of transactions
+//!   applied and rejected
+//! - a `cashflow.apply` span for each transaction within it, with its type, client, ID and
+//!   `cashflow.outcome` (`applied` or `rejected`, plus the error's
+//!   [code](crate::errors::Error::code) as `error.type`)
+//! - a `cashflow.transactions` counter and a `cashflow.apply.duration` histogram, with the same
+//!   type and outcome attributes
+//!
+//! [`OtlpExporter::install`](crate::telemetry::OtlpExporter::install) sets up the global
+//! providers to export over OTLP. Install it before creating any engines, since they look up the
+//! providers when they're created.
+
+use std::{borrow::Cow, fmt::Debug, time::SystemTime};
+
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    metrics::{Counter, Histogram},
+    trace::{Span, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::{metrics::SdkMeterProvider, trace::SdkTracerProvider, Resource};
+
+use crate::{errors::Error, types::TransactionRecord};
+
+/// The instrumentation scope engines report under
+const SCOPE: &str = "cashflow";
+
+/// Exports traces and metrics over OTLP/HTTP.
+///
+/// Where to is configured by the standard `OTEL_EXPORTER_OTLP_*` environment variables,
+/// defaulting to a collector on `localhost:4318`.
+#[derive(Debug)]
+pub struct OtlpExporter {
+    /// Batches and exports spans
+    tracer_provider: SdkTracerProvider,
+    /// Periodically exports metrics
+    meter_provider: SdkMeterProvider,
+}
+
+impl OtlpExporter {
+    /// Creates the exporters, and installs them as the global tracer and meter providers
+    /// # Errors
+    /// If either exporter can't be created
+    pub fn install() -> Result<Self, Error> {
+        let resource = Resource::builder().with_service_name(SCOPE).build();
+        let span_exporter = SpanExporter::builder()
+            .with_http()
+            .build()
+            .map_err(std::io::Error::other)?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(span_exporter)
+            .with_resource(resource.clone())
+            .build();
+        let metric_exporter = MetricExporter::builder()
+            .with_http()
+            .build()
+            .map_err(std::io::Error::other)?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(metric_exporter)
+            .with_resource(resource)
+            .build();
+        global::set_tracer_provider(tracer_provider.clone());
+        global::set_meter_provider(meter_provider.clone());
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// Exports anything still buffered, then stops exporting
+    /// # Errors
+    /// If either exporter fails to flush or shut down
+    pub fn shutdown(self) -> Result<(), Error> {
+        let traces = self.tracer_provider.shutdown();
+        let metrics = self.meter_provider.shutdown();
+        traces.and(metrics).map_err(std::io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// An engine's instruments, and the batch it's working through
+pub(crate) struct Telemetry {
+    /// Starts spans
+    tracer: BoxedTracer,
+    /// Counts transactions by type and outcome
+    transactions: Counter<u64>,
+    /// Time taken to apply each transaction, in seconds
+    apply_duration: Histogram<f64>,
+    /// The current batch's span, and its counts of applied and rejected transactions
+    batch: Option<(Context, u64, u64)>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        let meter = global::meter(SCOPE);
+        Self {
+            tracer: global::tracer(SCOPE),
+            transactions: meter
+                .u64_counter("cashflow.transactions")
+                .with_description("Transactions processed, by type and outcome")
+                .build(),
+            apply_duration: meter
+                .f64_histogram("cashflow.apply.duration")
+                .with_description("Time taken to apply each transaction")
+                .with_unit("s")
+                .build(),
+            batch: None,
+        }
+    }
+}
+
+impl Debug for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Telemetry")
+            .field("in_batch", &self.batch.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Telemetry {
+    /// Starts a span for a batch of transactions, which each transaction's span will be a child of
+    pub(crate) fn start_batch(&mut self, name: &'static str) {
+        let span = self
+            .tracer
+            .span_builder("cashflow.batch")
+            .with_attributes([KeyValue::new("cashflow.batch.method", name)])
+            .start(&self.tracer);
+        self.batch = Some((Context::new().with_span(span), 0, 0));
+    }
+
+    /// Ends the current batch's span, marking it as an error if the batch failed
+    pub(crate) fn end_batch<R>(&mut self, result: &Result<R, Error>) {
+        let Some((context, applied, rejected)) = self.batch.take() else {
+            return;
+        };
+        let span = context.span();
+        span.set_attribute(KeyValue::new("cashflow.batch.applied", to_i64(applied)));
+        span.set_attribute(KeyValue::new("cashflow.batch.rejected", to_i64(rejected)));
+        if let Err(err) = result {
+            span.set_attribute(KeyValue::new("error.type", err.code()));
+            span.set_status(Status::error(err.to_string()));
+        }
+        span.end();
+    }
+
+    /// Records a span and metrics for a transaction that was applied or rejected
+    pub(crate) fn record(
+        &mut self,
+        transaction: &TransactionRecord,
+        started: SystemTime,
+        result: &Result<(), Error>,
+    ) {
+        let outcome = if result.is_ok() {
+            "applied"
+        } else {
+            "rejected"
+        };
+        let transaction_type = transaction.transaction_type.name();
+        let mut metric_attributes = vec![
+            KeyValue::new("cashflow.transaction.type", transaction_type),
+            KeyValue::new("cashflow.outcome", outcome),
+        ];
+        if let Err(err) = result {
+            metric_attributes.push(KeyValue::new("error.type", err.code()));
+        }
+        self.transactions.add(1, &metric_attributes);
+        self.apply_duration.record(
+            started.elapsed().unwrap_or_default().as_secs_f64(),
+            &metric_attributes,
+        );
+
+        let mut span_attributes = metric_attributes;
+        span_attributes.push(KeyValue::new(
+            "cashflow.client",
+            attribute_id(transaction.client_id.0),
+        ));
+        span_attributes.push(KeyValue::new(
+            "cashflow.transaction.id",
+            attribute_id(transaction.transaction_id.0),
+        ));
+        let parent = match &mut self.batch {
+            Some((context, applied, rejected)) => {
+                *(if result.is_ok() { applied } else { rejected }) += 1;
+                Cow::Borrowed(&*context)
+            }
+            None => Cow::Owned(Context::new()),
+        };
+        let mut span = self
+            .tracer
+            .span_builder("cashflow.apply")
+            .with_start_time(started)
+            .with_attributes(span_attributes)
+            .start_with_context(&self.tracer, &parent);
+        if let Err(err) = result {
+            span.set_status(Status::error(err.to_string()));
+        }
+        span.end();
+    }
+}
+
+/// Converts a count to the signed integers OpenTelemetry attributes hold
+fn to_i64(count: u64) -> i64 {
+    i64::try_from(count).unwrap_or(i64::MAX)
+}
+
+/// Converts an ID to an attribute value, which OpenTelemetry only has signed integers for. Wide IDs
+/// too large to fit are capped.
+fn attribute_id(id: impl Into<u64>) -> i64 {
+    i64::try_from(id.into()).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use opentelemetry::trace::SpanId;
+    use opentelemetry_sdk::{
+        error::OTelSdkResult,
+        trace::{SpanData, SpanExporter},
+    };
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    /// Keeps every span exported, for inspection
+    #[derive(Debug, Clone, Default)]
+    struct CollectingExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for CollectingExporter {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    /// Returns a span's attribute as a string, if it has it
+    fn attribute(span: &SpanData, key: &str) -> Option<String> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.to_string())
+    }
+
+    #[test]
+    fn test_spans() {
+        let exporter = CollectingExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        global::set_tracer_provider(provider.clone());
+
+        let mut engine = Engine::new(MemoryAccountBook::new(), MemoryTransactionLog::new());
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,\n";
+        assert!(engine.load_csv(&mut Cursor::new(input)).is_err());
+        provider.force_flush().unwrap();
+
+        let spans = exporter.0.lock().unwrap();
+        let [applied, rejected, batch] = spans.as_slice() else {
+            panic!("Expected 3 spans, got {}", spans.len());
+        };
+        assert_eq!(batch.name, "cashflow.batch");
+        assert_eq!(batch.parent_span_id, SpanId::INVALID);
+        assert_eq!(attribute(batch, "cashflow.batch.applied").unwrap(), "1");
+        assert_eq!(attribute(batch, "cashflow.batch.rejected").unwrap(), "1");
+        assert_eq!(attribute(batch, "error.type").unwrap(), "missing_amount");
+        for span in [applied, rejected] {
+            assert_eq!(span.name, "cashflow.apply");
+            assert_eq!(span.parent_span_id, batch.span_context.span_id());
+            assert_eq!(
+                attribute(span, "cashflow.transaction.type").unwrap(),
+                "deposit"
+            );
+        }
+        assert_eq!(attribute(applied, "cashflow.outcome").unwrap(), "applied");
+        assert_eq!(attribute(rejected, "cashflow.outcome").unwrap(), "rejected");
+        assert!(matches!(rejected.status, Status::Error { .. }));
+    }
+}
@@ -0,0 +1,260 @@
+//! Serving several business units from one deployment, each with accounts and transactions of its
+//! own.
+//!
+//! A [`TenantManager`](crate::tenant::TenantManager) keeps a separate
+//! [`Engine`](crate::engine::Engine) for each [`TenantId`](crate::tenant::TenantId), made by a
+//! factory the first time the tenant is [added](crate::tenant::TenantManager::add_tenant). Every
+//! operation names its tenant, and only ever touches that tenant's engine, so tenants are isolated
+//! by construction: client 1 of one tenant is a different account from client 1 of another,
+//! transaction IDs only need to be unique within a tenant, a dispute can't reach another tenant's
+//! transactions, and reports only include the tenant's own accounts. Naming a tenant that hasn't
+//! been added is an error, [`Error::UnknownTenant`](crate::errors::Error::UnknownTenant), rather
+//! than a new, empty book.
+//! ```
+//! # use cashflow::{engine::Engine, tenant::*, types::*};
+//! # use rust_decimal_macros::dec;
+//! let mut tenants = TenantManager::new(|_: &TenantId| Engine::in_memory());
+//! let retail: TenantId = "retail".parse()?;
+//! let business: TenantId = "business".parse()?;
+//! tenants.add_tenant(retail.clone());
+//! tenants.add_tenant(business.clone());
+//!
+//! let deposit = Transaction::deposit(ClientId::from(1), TransactionId::from(1), dec!(5))?;
+//! tenants.apply(&retail, deposit)?;
+//! let mut report = vec![];
+//! tenants.report(&business, &mut report)?;
+//! // Retail's client 1 is no client of business
+//! assert!(report.is_empty());
+//! # Ok::<(), cashflow::errors::Error>(())
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use crate::{
+    engine::Engine,
+    errors::{Error, ParseError},
+    types::{AccountBook, Transaction, TransactionLog},
+};
+
+/// The longest a tenant ID can be, in bytes
+const MAX_TENANT_LEN: usize = 64;
+
+/// Identifies a tenant: 1 to 64 ASCII letters, digits, `-` or `_`, so it's safe to use in file
+/// names, URLs and metric labels
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TenantId(String);
+
+impl TenantId {
+    /// Returns the ID as a string
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for TenantId {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = (1..=MAX_TENANT_LEN).contains(&s.len())
+            && s.bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+        if valid {
+            Ok(Self(s.to_string()))
+        } else {
+            Err(ParseError::InvalidTenant(s.to_string()))
+        }
+    }
+}
+
+impl Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Makes the engine for a newly added tenant
+type EngineFactory<A, T> = Box<dyn FnMut(&TenantId) -> Engine<A, T> + Send>;
+
+/// A separate engine for each tenant, with every operation scoped to one of them
+pub struct TenantManager<A, T> {
+    /// Each tenant's engine, by tenant
+    engines: BTreeMap<TenantId, Engine<A, T>>,
+    /// Makes the engine for each tenant as it's added
+    factory: EngineFactory<A, T>,
+}
+
+impl<A, T> TenantManager<A, T>
+where
+    A: AccountBook,
+    T: TransactionLog,
+{
+    /// Creates a manager with no tenants, which makes each tenant's engine with `factory` as it's
+    /// added, eg with storage and settings of its own
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: FnMut(&TenantId) -> Engine<A, T> + Send + 'static,
+    {
+        Self {
+            engines: BTreeMap::new(),
+            factory: Box::new(factory),
+        }
+    }
+
+    /// Adds a tenant, making its engine, and returns the engine. If the tenant was already added,
+    /// returns its existing engine.
+    pub fn add_tenant(&mut self, tenant: TenantId) -> &mut Engine<A, T> {
+        let factory = &mut self.factory;
+        self.engines
+            .entry(tenant)
+            .or_insert_with_key(|tenant| factory(tenant))
+    }
+
+    /// Removes a tenant, returning its engine, if it was added
+    pub fn remove_tenant(&mut self, tenant: &TenantId) -> Option<Engine<A, T>> {
+        self.engines.remove(tenant)
+    }
+
+    /// Iterates over the tenants, in order
+    pub fn tenants(&self) -> impl Iterator<Item = &TenantId> {
+        self.engines.keys()
+    }
+
+    /// Returns a tenant's engine
+    /// # Errors
+    /// [`Error::UnknownTenant`] if the tenant hasn't been added
+    pub fn engine(&self, tenant: &TenantId) -> Result<&Engine<A, T>, Error> {
+        self.engines
+            .get(tenant)
+            .ok_or_else(|| Error::UnknownTenant(tenant.clone()))
+    }
+
+    /// Returns a tenant's engine, to apply transactions to or change its settings
+    /// # Errors
+    /// [`Error::UnknownTenant`] if the tenant hasn't been added
+    pub fn engine_mut(&mut self, tenant: &TenantId) -> Result<&mut Engine<A, T>, Error> {
+        self.engines
+            .get_mut(tenant)
+            .ok_or_else(|| Error::UnknownTenant(tenant.clone()))
+    }
+
+    /// Applies a single transaction to a tenant's accounts, as with [`Engine::apply`]
+    /// # Errors
+    /// [`Error::UnknownTenant`] if the tenant hasn't been added, or as for [`Engine::apply`]
+    pub fn apply(&mut self, tenant: &TenantId, transaction: Transaction) -> Result<(), Error> {
+        self.engine_mut(tenant)?.apply(transaction)
+    }
+
+    /// Loads and applies CSV transactions to a tenant's accounts, as with [`Engine::load_csv`]
+    /// # Errors
+    /// [`Error::UnknownTenant`] if the tenant hasn't been added, or as for [`Engine::load_csv`]
+    pub fn load_csv<R: Read>(&mut self, tenant: &TenantId, reader: &mut R) -> Result<(), Error> {
+        self.engine_mut(tenant)?.load_csv(reader)
+    }
+
+    /// Writes a tenant's accounts as CSV, as with [`Engine::report`]
+    /// # Errors
+    /// [`Error::UnknownTenant`] if the tenant hasn't been added, or if writing fails
+    pub fn report<W: Write>(&self, tenant: &TenantId, writer: &mut W) -> Result<(), Error> {
+        self.engine(tenant)?.report(writer)
+    }
+}
+
+impl<A, T> fmt::Debug for TenantManager<A, T> {
+    // The factory can't be shown, so only the tenants are
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TenantManager")
+            .field("tenants", &self.engines.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::EngineSettings,
+        errors::DomainError,
+        types::{ClientId, MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_isolation() {
+        let mut tenants = TenantManager::new(|tenant: &TenantId| {
+            // Each tenant can have settings of its own
+            let settings = EngineSettings {
+                validate_transactions: tenant.as_str() == "strict",
+                ..EngineSettings::default()
+            };
+            Engine::with_settings(
+                MemoryAccountBook::new(),
+                MemoryTransactionLog::new(),
+                settings,
+            )
+        });
+        let lax: TenantId = "lax".parse().unwrap();
+        let strict: TenantId = "strict".parse().unwrap();
+        tenants.add_tenant(lax.clone());
+        tenants.add_tenant(strict.clone());
+        assert_eq!(
+            format!("{tenants:?}"),
+            r#"TenantManager { tenants: [TenantId("lax"), TenantId("strict")], .. }"#
+        );
+
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        tenants.load_csv(&lax, &mut Cursor::new(input)).unwrap();
+        // The same client and transaction ID in another tenant are unrelated
+        let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndispute,1,1,\n";
+        tenants.load_csv(&strict, &mut Cursor::new(input)).unwrap();
+        let account = |tenant| {
+            tenants.engine(tenant).unwrap().account_book().accounts[&ClientId::from(1)].clone()
+        };
+        assert_eq!(account(&lax).funds_available(), dec!(5));
+        assert_eq!(account(&strict).funds_held(), dec!(2));
+
+        // A dispute can't reach another tenant's transactions
+        let input = "type,client,tx,amount\ndispute,1,2,\n";
+        tenants
+            .load_csv(
+                &lax,
+                &mut Cursor::new("type,client,tx,amount\ndeposit,1,2,1.0\n"),
+            )
+            .unwrap();
+        assert!(matches!(
+            tenants.load_csv(&strict, &mut Cursor::new(input)),
+            Err(Error::Domain(DomainError::UnknownReference(_)))
+        ));
+
+        let unknown: TenantId = "unknown".parse().unwrap();
+        assert!(matches!(
+            tenants.report(&unknown, &mut vec![]),
+            Err(Error::UnknownTenant(tenant)) if tenant == unknown
+        ));
+        assert!(tenants.remove_tenant(&lax).is_some());
+        assert_eq!(tenants.tenants().collect::<Vec<_>>(), [&strict]);
+    }
+
+    #[test]
+    fn test_tenant_id() {
+        assert_eq!(
+            "Retail_EU-1".parse::<TenantId>().unwrap().to_string(),
+            "Retail_EU-1"
+        );
+        for invalid in ["", "retail eu", "../etc", &"a".repeat(65)] {
+            assert!(matches!(
+                invalid.parse::<TenantId>(),
+                Err(ParseError::InvalidTenant(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,487 @@
+//! Keeping recent transactions in a fast store and older ones compressed, so memory use stays
+//! bounded without giving up late disputes.
+//!
+//! A [`TieredLog`](crate::tiering::TieredLog) wraps a hot
+//! [`TransactionLog`](crate::types::TransactionLog), like a
+//! [`MemoryTransactionLog`](crate::types::MemoryTransactionLog), and moves each transaction to a
+//! [`ColdLog`](crate::tiering::ColdLog) once it's older than the log's age. Age is measured by
+//! transaction timestamps, against the latest registered, so transactions without one stay hot.
+//! Lookups check the hot log, then the cold one, so a dispute of a cold transaction works as
+//! usual, only slower.
+//!
+//! A [`ColdLog`](crate::tiering::ColdLog) packs transactions into blocks of a few thousand, each
+//! compressed with zstd with the `zstd` feature, taking a fraction of the memory of a hot log.
+//! Without the feature, blocks aren't compressed at all: each transaction is packed into a fixed 42
+//! bytes, which still saves the hot log's per-entry overhead, but far less. A lookup unpacks a
+//! block, so it's much slower than a hot lookup; pick an age that keeps most disputes within it.
+//! Lookups skip blocks whose IDs can't include the one looked up, so they're quickest when IDs
+//! mostly increase.
+//! ```
+//! # use std::time::Duration;
+//! # use cashflow::{engine::Engine, tiering::TieredLog, types::{
+//! #     AccountBook, MemoryAccountBook, MemoryTransactionLog}};
+//! let day = Duration::from_secs(24 * 60 * 60);
+//! let log = TieredLog::new(MemoryTransactionLog::new(), 30 * day);
+//! let mut engine = Engine::new(MemoryAccountBook::new(), log);
+//! let input = "type,client,tx,amount,timestamp
+//! deposit,1,1,5.0,0
+//! deposit,1,2,3.0,3000000
+//! dispute,1,1,,
+//! ";
+//! engine.load_csv(&mut input.as_bytes())?;
+//! // Deposit 1 went cold, but could still be disputed
+//! assert_eq!(engine.transaction_log().cold().len(), 1);
+//! let account = engine.account_book().accounts().next().unwrap();
+//! assert_eq!(account.funds_held(), rust_decimal_macros::dec!(5));
+//! # Ok::<(), cashflow::errors::Error>(())
+//! ```
+
+use std::{cell::RefCell, collections::VecDeque, sync::Arc, time::Duration};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    errors::Error,
+    types::{
+        ClientId, Provenance, RawClientId, RawTransactionId, Transaction, TransactionId,
+        TransactionLog, TransactionRecord, TransactionType,
+    },
+};
+
+/// Number of transactions packed into each cold block
+const BLOCK_LEN: usize = 4096;
+
+/// Bytes each transaction takes in a block, before compression: type, flags, client, ID, amount
+/// and timestamp
+const ENTRY_LEN: usize = 1 + 1 + 8 + 8 + 16 + 8;
+
+/// Flag set on an entry with an amount
+const HAS_AMOUNT: u8 = 1;
+
+/// Flag set on an entry with a timestamp
+const HAS_TIMESTAMP: u8 = 2;
+
+/// A block of cold transactions
+#[derive(Debug)]
+struct Block {
+    /// Lowest transaction ID in the block
+    min: TransactionId,
+    /// Highest transaction ID in the block
+    max: TransactionId,
+    /// Number of transactions in the block
+    len: usize,
+    /// The packed transactions, sorted by ID, compressed with the `zstd` feature and as they are
+    /// without it
+    bytes: Vec<u8>,
+}
+
+/// Older transactions, packed into blocks that are only unpacked to be read, and compressed with
+/// the `zstd` feature.
+///
+/// Only what a [`TransactionRecord`] holds is kept; currencies and signatures are dropped.
+#[derive(Debug, Default)]
+pub struct ColdLog {
+    /// Full blocks
+    blocks: Vec<Block>,
+    /// Transactions waiting for a block to fill, in the order they were added
+    pending: Vec<TransactionRecord>,
+    /// The block last unpacked, by index, since lookups of nearby IDs tend to follow each other
+    unpacked: RefCell<Option<(usize, Arc<Vec<TransactionRecord>>)>>,
+}
+
+impl ColdLog {
+    /// Creates an empty cold log
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of transactions in the log
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|block| block.len).sum::<usize>() + self.pending.len()
+    }
+
+    /// Returns whether the log has no transactions
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the bytes taken by the packed transactions, to compare with the hot log
+    #[must_use]
+    pub fn packed_bytes(&self) -> usize {
+        self.blocks.iter().map(|block| block.bytes.len()).sum()
+    }
+
+    /// Adds a transaction, packing a block once enough are waiting
+    /// # Errors
+    /// If a block can't be compressed
+    pub fn push(&mut self, record: TransactionRecord) -> Result<(), Error> {
+        self.pending.push(record);
+        if self.pending.len() >= BLOCK_LEN {
+            let records = std::mem::take(&mut self.pending);
+            self.blocks.push(Block::pack(records)?);
+        }
+        Ok(())
+    }
+
+    /// Unpacks a block, reusing the last one unpacked if it's the same
+    fn unpack(&self, index: usize) -> Result<Arc<Vec<TransactionRecord>>, Error> {
+        let mut unpacked = self.unpacked.borrow_mut();
+        if let Some((last, records)) = &*unpacked {
+            if *last == index {
+                return Ok(Arc::clone(records));
+            }
+        }
+        let records = Arc::new(self.blocks[index].unpack()?);
+        *unpacked = Some((index, Arc::clone(&records)));
+        Ok(records)
+    }
+}
+
+impl TransactionLog for ColdLog {
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        // Later additions win, as they would in a hot log
+        if let Some(record) = self
+            .pending
+            .iter()
+            .rev()
+            .find(|record| record.transaction_id == transaction_id)
+        {
+            return Ok(Some(*record));
+        }
+        for (index, block) in self.blocks.iter().enumerate().rev() {
+            if !(block.min..=block.max).contains(&transaction_id) {
+                continue;
+            }
+            let records = self.unpack(index)?;
+            if let Ok(found) =
+                records.binary_search_by_key(&transaction_id, |record| record.transaction_id)
+            {
+                return Ok(Some(records[found]));
+            }
+        }
+        Ok(None)
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.push(TransactionRecord::from(&transaction))
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        // Blocks that can't be unpacked can't be read back, so are left out
+        let blocks = self.blocks.iter().filter_map(|block| block.unpack().ok());
+        Box::new(blocks.flatten().chain(self.pending.iter().copied()))
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        let before = self.len();
+        self.pending
+            .retain(|record| !transaction_ids.contains(&record.transaction_id));
+        for block in &mut self.blocks {
+            if !transaction_ids
+                .iter()
+                .any(|transaction_id| (block.min..=block.max).contains(transaction_id))
+            {
+                continue;
+            }
+            let mut records = block.unpack()?;
+            records.retain(|record| !transaction_ids.contains(&record.transaction_id));
+            if records.len() != block.len {
+                *block = Block::pack(records)?;
+            }
+        }
+        self.blocks.retain(|block| block.len > 0);
+        self.unpacked.take();
+        Ok(before - self.len())
+    }
+}
+
+impl Block {
+    /// Packs transactions into a block
+    fn pack(mut records: Vec<TransactionRecord>) -> Result<Self, Error> {
+        // Stable, so the last of any with the same ID is found last, and dropped as a duplicate
+        records.sort_by_key(|record| record.transaction_id);
+        records.reverse();
+        records.dedup_by_key(|record| record.transaction_id);
+        records.reverse();
+        let mut bytes = Vec::with_capacity(records.len() * ENTRY_LEN);
+        for record in &records {
+            encode(record, &mut bytes);
+        }
+        #[cfg(feature = "zstd")]
+        let bytes = zstd::encode_all(&*bytes, 0)?;
+        Ok(Self {
+            min: records
+                .first()
+                .map_or(TransactionId(0), |record| record.transaction_id),
+            max: records
+                .last()
+                .map_or(TransactionId(0), |record| record.transaction_id),
+            len: records.len(),
+            bytes,
+        })
+    }
+
+    /// Unpacks the block's transactions, sorted by ID
+    fn unpack(&self) -> Result<Vec<TransactionRecord>, Error> {
+        #[cfg(feature = "zstd")]
+        let bytes = zstd::decode_all(&*self.bytes)?;
+        #[cfg(not(feature = "zstd"))]
+        let bytes = &self.bytes;
+        Ok(bytes.chunks_exact(ENTRY_LEN).filter_map(decode).collect())
+    }
+}
+
+/// Packs a transaction onto the end of a block
+// IDs are already u64 with wide IDs
+#[cfg_attr(feature = "wide-ids", allow(clippy::useless_conversion))]
+fn encode(record: &TransactionRecord, bytes: &mut Vec<u8>) {
+    let kind = TransactionType::ALL
+        .iter()
+        .position(|&kind| kind == record.transaction_type)
+        .unwrap_or_default();
+    let flags = if record.amount.is_some() {
+        HAS_AMOUNT
+    } else {
+        0
+    } | if record.timestamp.is_some() {
+        HAS_TIMESTAMP
+    } else {
+        0
+    };
+    bytes.push(kind as u8);
+    bytes.push(flags);
+    bytes.extend_from_slice(&u64::from(record.client_id.0).to_le_bytes());
+    bytes.extend_from_slice(&u64::from(record.transaction_id.0).to_le_bytes());
+    bytes.extend_from_slice(&record.amount.unwrap_or_default().serialize());
+    bytes.extend_from_slice(&record.timestamp.unwrap_or_default().to_le_bytes());
+}
+
+/// Unpacks a transaction from a block, if it's well formed
+// IDs are already u64 with wide IDs
+#[cfg_attr(
+    feature = "wide-ids",
+    allow(clippy::useless_conversion, clippy::unnecessary_fallible_conversions)
+)]
+fn decode(entry: &[u8]) -> Option<TransactionRecord> {
+    let u64_at = |at: usize| Some(u64::from_le_bytes(entry.get(at..at + 8)?.try_into().ok()?));
+    let transaction_type = *TransactionType::ALL.get(usize::from(entry[0]))?;
+    let flags = entry[1];
+    let client_id = ClientId(RawClientId::try_from(u64_at(2)?).ok()?);
+    let transaction_id = TransactionId(RawTransactionId::try_from(u64_at(10)?).ok()?);
+    let amount = Decimal::deserialize(entry.get(18..34)?.try_into().ok()?);
+    Some(TransactionRecord {
+        transaction_type,
+        client_id,
+        transaction_id,
+        amount: (flags & HAS_AMOUNT != 0).then_some(amount),
+        timestamp: (flags & HAS_TIMESTAMP != 0).then(|| u64_at(34)).flatten(),
+    })
+}
+
+/// A hot [`TransactionLog`] whose older transactions are moved to a [`ColdLog`], with lookups
+/// falling back from one to the other
+#[derive(Debug)]
+pub struct TieredLog<H> {
+    /// Recent transactions
+    hot: H,
+    /// Transactions moved out of the hot log
+    cold: ColdLog,
+    /// How old a transaction gets before it's moved, in seconds
+    age: u64,
+    /// Hot transactions with a timestamp, in the order they were registered, to be moved in turn
+    queue: VecDeque<(u64, TransactionId)>,
+    /// The latest timestamp registered
+    latest: Option<u64>,
+}
+
+impl<H: TransactionLog> TieredLog<H> {
+    /// Wraps a hot log, moving transactions out of it once they're more than `age` older than
+    /// the latest registered
+    #[must_use]
+    pub fn new(hot: H, age: Duration) -> Self {
+        Self {
+            hot,
+            cold: ColdLog::new(),
+            age: age.as_secs(),
+            queue: VecDeque::new(),
+            latest: None,
+        }
+    }
+
+    /// Returns the hot log
+    #[must_use]
+    pub fn hot(&self) -> &H {
+        &self.hot
+    }
+
+    /// Returns the cold log
+    #[must_use]
+    pub fn cold(&self) -> &ColdLog {
+        &self.cold
+    }
+
+    /// Moves every hot transaction older than the age, as of the latest timestamp registered
+    fn tier_down(&mut self) -> Result<(), Error> {
+        let Some(cutoff) = self.latest.map(|latest| latest.saturating_sub(self.age)) else {
+            return Ok(());
+        };
+        while let Some(&(timestamp, transaction_id)) = self.queue.front() {
+            if timestamp >= cutoff {
+                break;
+            }
+            // Skipped if it's since been purged, or replaced by a later registration with the
+            // same ID, which is queued in its own right
+            match self.hot.transaction(transaction_id)? {
+                Some(record) if record.timestamp == Some(timestamp) => {
+                    self.cold.push(record)?;
+                    self.hot.purge(&[transaction_id])?;
+                }
+                _ => {}
+            }
+            self.queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+impl<H: TransactionLog> TransactionLog for TieredLog<H> {
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error> {
+        match self.hot.transaction(transaction_id)? {
+            Some(record) => Ok(Some(record)),
+            None => self.cold.transaction(transaction_id),
+        }
+    }
+
+    fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+        let timestamp = transaction.timestamp;
+        let transaction_id = transaction.transaction_id;
+        self.hot.register(transaction)?;
+        if let Some(timestamp) = timestamp {
+            self.queue.push_back((timestamp, transaction_id));
+            self.latest = self.latest.max(Some(timestamp));
+            self.tier_down()?;
+        }
+        Ok(())
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.hot.reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        self.hot.check_connection()
+    }
+
+    fn record_provenance(&mut self, transaction_id: TransactionId, provenance: Provenance) {
+        self.hot.record_provenance(transaction_id, provenance);
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        Box::new(self.hot.transactions().chain(self.cold.transactions()))
+    }
+
+    fn provenance(&self, transaction_id: TransactionId) -> Result<Option<Provenance>, Error> {
+        self.hot.provenance(transaction_id)
+    }
+
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        Ok(self.hot.purge(transaction_ids)? + self.cold.purge(transaction_ids)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        engine::Engine,
+        types::{MemoryAccountBook, MemoryTransactionLog},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_tiered_log() {
+        let log = TieredLog::new(MemoryTransactionLog::new(), Duration::from_secs(100));
+        let mut engine = Engine::new(MemoryAccountBook::new(), log);
+        // Enough transactions to fill a cold block, then one to push them all out of the hot log
+        let mut input = String::from("type,client,tx,amount,timestamp\n");
+        for id in 1..=BLOCK_LEN + 10 {
+            input.push_str(&format!("deposit,{},{id},1.5,{id}\n", id % 3));
+        }
+        input.push_str("deposit,1,9000,1.0,10000\ndeposit,2,9001,2.0,\n");
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let log = engine.transaction_log();
+        assert_eq!(log.cold().len(), BLOCK_LEN + 10);
+        assert!(log.cold().packed_bytes() > 0);
+        assert_eq!(log.hot().transactions().count(), 2);
+        assert_eq!(log.transactions().count(), BLOCK_LEN + 12);
+
+        // Late disputes find cold transactions, both packed and waiting for a block
+        let input = "type,client,tx,amount
+dispute,2,5,
+dispute,2,4100,
+resolve,2,5,
+";
+        engine.load_csv(&mut Cursor::new(input)).unwrap();
+        let account = &engine.account_book().accounts[&ClientId(2)];
+        assert_eq!(account.funds_held(), dec!(1.5));
+        let record = engine
+            .transaction_log()
+            .transaction(TransactionId(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.client_id, ClientId(2));
+        assert_eq!(record.amount, Some(dec!(1.5)));
+        assert_eq!(record.timestamp, Some(5));
+
+        // The transaction without a timestamp stays hot
+        let record = engine
+            .transaction_log()
+            .transaction(TransactionId(9001))
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.timestamp, None);
+        assert!(engine
+            .transaction_log()
+            .hot()
+            .transaction(TransactionId(9001))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_cold_log_purge() {
+        let mut cold = ColdLog::new();
+        for id in 0..BLOCK_LEN + 1 {
+            let id = RawTransactionId::try_from(id).unwrap();
+            cold.push(TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client_id: ClientId(1),
+                transaction_id: TransactionId(id),
+                amount: Some(dec!(1)),
+                timestamp: Some(0),
+            })
+            .unwrap();
+        }
+        assert!(cold.transaction(TransactionId(7)).unwrap().is_some());
+        let purged = cold
+            .purge(&[TransactionId(7), TransactionId(4096), TransactionId(9999)])
+            .unwrap();
+        assert_eq!(purged, 2);
+        assert_eq!(cold.len(), BLOCK_LEN - 1);
+        assert!(cold.transaction(TransactionId(7)).unwrap().is_none());
+        assert!(cold.transaction(TransactionId(8)).unwrap().is_some());
+    }
+}
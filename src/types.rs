@@ -1,25 +1,57 @@
 //! Common datatypes supporting functions throughout the Cashflow Engine
 
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    hash::BuildHasher,
+    str::FromStr,
+    sync::Arc,
+};
 
 use rust_decimal::Decimal;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::{errors::Error, ops};
+use crate::{
+    errors::{DomainError, Error, ParseError, RetryWithBackoff},
+    ops,
+};
 
-/// The number of decimals to track for all amounts
+/// The number of decimals amounts are kept to, unless an account book is given another
+/// [scale](AccountBook::scale)
 pub const DECIMAL_SCALE: u32 = 4;
 
+/// The integer behind a [`ClientId`]: `u16`, or `u64` with the `wide-ids` feature
+#[cfg(not(feature = "wide-ids"))]
+pub type RawClientId = u16;
+/// The integer behind a [`ClientId`]: `u16`, or `u64` with the `wide-ids` feature
+#[cfg(feature = "wide-ids")]
+pub type RawClientId = u64;
+
+/// The integer behind a [`TransactionId`]: `u32`, or `u64` with the `wide-ids` feature
+#[cfg(not(feature = "wide-ids"))]
+pub type RawTransactionId = u32;
+/// The integer behind a [`TransactionId`]: `u32`, or `u64` with the `wide-ids` feature
+#[cfg(feature = "wide-ids")]
+pub type RawTransactionId = u64;
+
 /// Unique identifier for a client
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct ClientId(u16);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClientId(pub(crate) RawClientId);
 
-impl From<u16> for ClientId {
-    fn from(client_id: u16) -> Self {
+impl From<RawClientId> for ClientId {
+    fn from(client_id: RawClientId) -> Self {
         Self(client_id)
     }
 }
 
+impl From<ClientId> for RawClientId {
+    fn from(client_id: ClientId) -> Self {
+        client_id.0
+    }
+}
+
 impl Display for ClientId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "id[{}]", self.0)
@@ -27,24 +59,173 @@ impl Display for ClientId {
 }
 
 /// Unique identifier for a transaction
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct TransactionId(u32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TransactionId(pub(crate) RawTransactionId);
 
-impl From<u32> for TransactionId {
-    fn from(transaction_id: u32) -> Self {
+impl From<RawTransactionId> for TransactionId {
+    fn from(transaction_id: RawTransactionId) -> Self {
         Self(transaction_id)
     }
 }
 
+impl From<TransactionId> for RawTransactionId {
+    fn from(transaction_id: TransactionId) -> Self {
+        transaction_id.0
+    }
+}
+
 impl Display for TransactionId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "id[{}]", self.0)
     }
 }
 
-/// Represents the different types of operations that can be performed on a client's account
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
+/// A three-letter currency code, like `USD`.
+///
+/// Amounts in the existing CSV format have no currency, so they're in [`Currency::NONE`], the
+/// ISO 4217 code for no currency. A system using only that runs just as before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// No currency in particular, `XXX`: the currency of every amount in single-currency input
+    pub const NONE: Self = Self(*b"XXX");
+
+    /// Returns the three-letter code
+    #[must_use]
+    pub fn code(&self) -> &str {
+        // Only ever made from ASCII letters
+        std::str::from_utf8(&self.0).unwrap_or("XXX")
+    }
+
+    /// Returns whether this is [`Currency::NONE`]
+    #[must_use]
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        *self == Self::NONE
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = Error;
+
+    /// Parses a code of three ASCII letters, in either case, eg `usd`
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidCurrency(code.to_string());
+        let code: [u8; 3] = code.as_bytes().try_into().map_err(|_| invalid())?;
+        if !code.iter().all(u8::is_ascii_alphabetic) {
+            return Err(invalid().into());
+        }
+        Ok(Self(code.map(|letter| letter.to_ascii_uppercase())))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+/// Deserializes from a code, or an empty string for [`Currency::NONE`], as in a CSV row with a
+/// blank `currency` field
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        if code.is_empty() {
+            return Ok(Self::NONE);
+        }
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An amount in a particular currency.
+///
+/// Amounts in different currencies can't be added or subtracted, so a sum of them can't be made
+/// by mistake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Money {
+    /// How much
+    #[cfg_attr(feature = "serde", serde(with = "rust_decimal::serde::str"))]
+    amount: Decimal,
+    /// What in
+    currency: Currency,
+}
+
+impl Money {
+    /// Creates an amount in the given currency
+    #[must_use]
+    pub fn new(amount: Decimal, currency: Currency) -> Self {
+        Self { amount, currency }
+    }
+
+    /// Returns how much
+    #[must_use]
+    #[inline]
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// Returns the currency
+    #[must_use]
+    #[inline]
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// Adds two amounts, returning `None` if they're in different currencies, or the sum is out
+    /// of range
+    #[must_use]
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        (self.currency == other.currency)
+            .then(|| self.amount.checked_add(other.amount))
+            .flatten()
+            .map(|amount| Self::new(amount, self.currency))
+    }
+
+    /// Subtracts `other`, returning `None` if they're in different currencies, or the difference
+    /// is out of range
+    #[must_use]
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        (self.currency == other.currency)
+            .then(|| self.amount.checked_sub(other.amount))
+            .flatten()
+            .map(|amount| Self::new(amount, self.currency))
+    }
+}
+
+/// Displays as eg `1.5000 USD`, or without a code in [`Currency::NONE`]
+impl Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.currency.is_none() {
+            write!(f, "{}", self.amount)
+        } else {
+            write!(f, "{} {}", self.amount, self.currency)
+        }
+    }
+}
+
+/// Represents the different types of operations that can be performed on a client's account.
+///
+/// Displays and parses as its name in CSV input, eg `deposit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum TransactionType {
     /// Credit to the client's asset account
     Deposit,
@@ -58,13 +239,56 @@ pub enum TransactionType {
     Chargeback,
 }
 
+impl TransactionType {
+    /// Every transaction type, in the order declared
+    pub const ALL: [Self; 5] = [
+        Self::Deposit,
+        Self::Withdrawal,
+        Self::Dispute,
+        Self::Resolve,
+        Self::Chargeback,
+    ];
+
+    /// Returns the type's name, as in CSV input
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+        }
+    }
+}
+
+impl Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = Error;
+
+    /// Parses a type from its name, as in CSV input
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|transaction_type| transaction_type.name() == name)
+            .ok_or_else(|| ParseError::UnknownTransactionType(name.to_string()).into())
+    }
+}
+
 /// A holder for an incoming [`Transaction`] that ensures it can only be applied once.
 ///
-/// This mainly exists because we aren't allowing [`Clone`] for [`Transaction`]s, since
-/// inadvertent duplication would be so disastrous. Since the [`TransactionLog`] wants ownership
-/// of the [`Transaction`], this is a way to take ownership if the transaction applies successfully,
-/// but to allow the caller to retain it if it doesn't. (Then, the caller can retry or whatever they
-/// wish to do.)
+/// We aren't allowing [`Clone`] for [`Transaction`]s outside this crate, since inadvertent
+/// duplication would be so disastrous. Inside it, a copy is only ever made to hand to a
+/// [`TransactionLog`], which wants ownership, or to preview a transaction against a throwaway
+/// account book; the original stays here either way. Applying it swaps it for
+/// [`Applied`](Self::Applied), so it's this state, rather than the lack of copies, that stops a
+/// transaction applying twice. If it doesn't apply, the caller still has it to retry or whatever
+/// they wish to do.
 #[derive(Debug)]
 pub enum TransactionState {
     /// Contains a [`Transaction`] that has not yet been applied successfully.
@@ -80,64 +304,497 @@ impl From<Transaction> for TransactionState {
 }
 
 /// Represents an actual operation on a customer's account
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct Transaction {
     /// The type of this transaction (see [`TransactionType`])
-    #[serde(rename = "type")]
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
     pub(crate) transaction_type: TransactionType,
     /// Account ID for this transaction
-    #[serde(rename = "client")]
+    #[cfg_attr(feature = "serde", serde(rename = "client"))]
     pub(crate) client_id: ClientId,
     /// Unique identifier for this transaction
-    #[serde(rename = "tx")]
+    #[cfg_attr(feature = "serde", serde(rename = "tx"))]
     pub(crate) transaction_id: TransactionId,
     /// The amount of money in this transaction, if applicable.
     /// [`TransactionType::Deposit`] and [`TransactionType::Withdrawal`]
     /// should have amounts.
-    #[serde(deserialize_with = "deserialize_option_decimal")]
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "deserialize_option_decimal")
+    )]
     pub(crate) amount: Option<Decimal>,
+    /// The currency of the amount. Input without a `currency` column is in [`Currency::NONE`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) currency: Currency,
+    /// When the transaction happened, in seconds since the Unix epoch, if the input has a
+    /// `timestamp` column
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) timestamp: Option<u64>,
+    /// The client's signature of the transaction, if the input has a `signature` column
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) signature: Option<Signature>,
 }
 
-/// Function to help [`serde`] deserialize from a string into a [`Decimal`] with [`DECIMAL_SCALE`] scale
-fn deserialize_option_decimal<'de, D>(value: D) -> Result<Option<Decimal>, D::Error>
+impl Transaction {
+    /// Creates a deposit of `amount` into a client's account, eg to apply without going through
+    /// CSV. As when parsed, the amount is kept exactly, and rounded to the account book's
+    /// [scale](AccountBook::scale) when applied.
+    /// # Errors
+    /// [`DomainError::InvalidAmount`] if the amount is zero or negative
+    pub fn deposit(
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<Self, Error> {
+        Self::with_amount(TransactionType::Deposit, client_id, transaction_id, amount)
+    }
+
+    /// Creates a withdrawal of `amount` from a client's account. As when parsed, the amount is
+    /// kept exactly, and rounded to the account book's [scale](AccountBook::scale) when applied.
+    /// # Errors
+    /// [`DomainError::InvalidAmount`] if the amount is zero or negative
+    pub fn withdrawal(
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<Self, Error> {
+        Self::with_amount(
+            TransactionType::Withdrawal,
+            client_id,
+            transaction_id,
+            amount,
+        )
+    }
+
+    /// Creates a dispute of the client's transaction with the given ID
+    #[must_use]
+    pub fn dispute(client_id: ClientId, transaction_id: TransactionId) -> Self {
+        Self::referring(TransactionType::Dispute, client_id, transaction_id)
+    }
+
+    /// Creates a resolution of the dispute of the client's transaction with the given ID
+    #[must_use]
+    pub fn resolve(client_id: ClientId, transaction_id: TransactionId) -> Self {
+        Self::referring(TransactionType::Resolve, client_id, transaction_id)
+    }
+
+    /// Creates a chargeback of the disputed transaction with the given ID
+    #[must_use]
+    pub fn chargeback(client_id: ClientId, transaction_id: TransactionId) -> Self {
+        Self::referring(TransactionType::Chargeback, client_id, transaction_id)
+    }
+
+    /// Creates a deposit or withdrawal, checking its amount is positive
+    fn with_amount(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> Result<Self, Error> {
+        if amount <= Decimal::ZERO {
+            return Err(DomainError::InvalidAmount {
+                transaction: transaction_id,
+                amount,
+            }
+            .into());
+        }
+        Ok(Self {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        })
+    }
+
+    /// Creates a dispute, resolve or chargeback, which refer to another transaction by its ID
+    fn referring(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: None,
+            currency: Currency::NONE,
+            timestamp: None,
+            signature: None,
+        }
+    }
+
+    /// Returns the type of the transaction
+    #[must_use]
+    #[inline]
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    /// Returns the ID of the client the transaction applies to
+    #[must_use]
+    #[inline]
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Returns the transaction's unique identifier, or for a dispute, resolve or chargeback, the
+    /// identifier of the transaction it refers to
+    #[must_use]
+    #[inline]
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    /// Returns the amount of a deposit or withdrawal
+    #[must_use]
+    #[inline]
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    /// Returns the currency of the transaction's amount
+    #[must_use]
+    #[inline]
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// Returns the amount of a deposit or withdrawal, in its currency
+    #[must_use]
+    pub fn money(&self) -> Option<Money> {
+        self.amount.map(|amount| Money::new(amount, self.currency))
+    }
+
+    /// Puts the transaction in a currency. Transactions are created in [`Currency::NONE`].
+    #[must_use]
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Returns when the transaction happened, in seconds since the Unix epoch, if known
+    #[must_use]
+    #[inline]
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Sets when the transaction happened, in seconds since the Unix epoch. Transactions are
+    /// created without a timestamp.
+    #[must_use]
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Returns the client's signature of the transaction, if it was signed
+    #[must_use]
+    #[inline]
+    pub fn signature(&self) -> Option<Signature> {
+        self.signature
+    }
+
+    /// Sets the client's signature of the [signed message](Self::signed_message). Transactions
+    /// are created unsigned.
+    #[must_use]
+    pub fn with_signature(mut self, signature: Signature) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// Returns the message a client signs to vouch for the transaction: its type, client, ID,
+    /// amount, currency and timestamp, separated by commas, with a blank for any it doesn't have,
+    /// eg `deposit,1,7,1.5,,` or `dispute,1,7,,USD,1700000000`. The currency is blank if it's
+    /// [`Currency::NONE`], and the amount is as parsed, so `1.50` stays `1.50`.
+    #[must_use]
+    pub fn signed_message(&self) -> Vec<u8> {
+        let amount = self.amount.map(|amount| amount.to_string());
+        let currency = (!self.currency.is_none()).then(|| self.currency.code());
+        let timestamp = self.timestamp.map(|timestamp| timestamp.to_string());
+        format!(
+            "{},{},{},{},{},{}",
+            self.transaction_type.name(),
+            self.client_id.0,
+            self.transaction_id.0,
+            amount.as_deref().unwrap_or_default(),
+            currency.unwrap_or_default(),
+            timestamp.as_deref().unwrap_or_default(),
+        )
+        .into_bytes()
+    }
+}
+
+/// An Ed25519 signature of a transaction's [signed message](Transaction::signed_message), written
+/// as 128 hex digits in input.
+///
+/// Signatures are only checked by a `SignatureVerifier`, with the `signatures` feature;
+/// otherwise they're parsed and ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub(crate) [u8; 64]);
+
+impl Signature {
+    /// Creates a signature from its bytes
+    #[must_use]
+    pub fn from_bytes(bytes: [u8; 64]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the signature's bytes
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl FromStr for Signature {
+    type Err = Error;
+
+    /// Parses 128 hex digits, in either case
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        decode_hex(hex)
+            .map(Self)
+            .ok_or_else(|| ParseError::InvalidSignature(hex.to_string()).into())
+    }
+}
+
+/// Deserializes from hex digits, as in a CSV row's `signature` field
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        hex.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Decodes exactly `N` bytes from twice as many hex digits, in either case
+pub(crate) fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        // Only ASCII, so each pair of bytes is a str
+        let digits = std::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// A read-only copy of the details of a [`Transaction`] that's been registered in a
+/// [`TransactionLog`].
+///
+/// Unlike a [`Transaction`], this can be freely copied, since it can't be applied to an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionRecord {
+    /// See [`Transaction::transaction_type`]
+    pub(crate) transaction_type: TransactionType,
+    /// See [`Transaction::client_id`]
+    pub(crate) client_id: ClientId,
+    /// See [`Transaction::transaction_id`]
+    pub(crate) transaction_id: TransactionId,
+    /// See [`Transaction::amount`]
+    pub(crate) amount: Option<Decimal>,
+    /// See [`Transaction::timestamp`]
+    pub(crate) timestamp: Option<u64>,
+}
+
+impl TransactionRecord {
+    /// Creates a record from its parts, eg for a [`TransactionLog`] reading back a transaction it
+    /// stored with the accessors of [`Transaction`]
+    #[must_use]
+    pub fn new(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            timestamp: None,
+        }
+    }
+
+    /// Sets when the transaction happened, for a [`TransactionLog`] that stores timestamps
+    #[must_use]
+    pub fn with_timestamp(mut self, timestamp: Option<u64>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Returns the type of the registered transaction
+    #[must_use]
+    #[inline]
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    /// Returns the ID of the client the transaction applied to
+    #[must_use]
+    #[inline]
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Returns the transaction's unique identifier
+    #[must_use]
+    #[inline]
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    /// Returns the amount of the transaction, if it had one
+    #[must_use]
+    #[inline]
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    /// Returns when the transaction happened, in seconds since the Unix epoch, if it had a
+    /// timestamp and the log stored it
+    #[must_use]
+    #[inline]
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+}
+
+impl From<&Transaction> for TransactionRecord {
+    fn from(transaction: &Transaction) -> Self {
+        Self {
+            transaction_type: transaction.transaction_type,
+            client_id: transaction.client_id,
+            transaction_id: transaction.transaction_id,
+            amount: transaction.amount,
+            timestamp: transaction.timestamp,
+        }
+    }
+}
+
+/// Where a transaction was read from, so a balance can be traced back to the input that caused it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Provenance {
+    /// The input, eg a filename or a topic
+    pub source: Arc<str>,
+    /// The transaction's line within the input, or its offset in a stream without lines
+    pub line: u64,
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.source, self.line)
+    }
+}
+
+#[cfg(feature = "serde")]
+/// Function to help [`serde`] deserialize from a string into a [`Decimal`], exactly: it's only
+/// rounded to the account book's [scale](AccountBook::scale) once it's applied
+pub(crate) fn deserialize_option_decimal<'de, D>(value: D) -> Result<Option<Decimal>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let amount = rust_decimal::serde::str_option::deserialize(value)?;
-    if let Some(mut amount) = amount {
-        amount.rescale(DECIMAL_SCALE);
-    }
-    Ok(amount)
+    rust_decimal::serde::str_option::deserialize(value)
 }
 
-/// Overall state of a single account held by a client
-#[derive(Debug)]
+/// Overall state of a single account held by a client.
+///
+/// Serializes with the same field names as account reports, eg
+/// `{"client":1,"available":"1.5000","held":"0.0000","locked":false}`, so states can be persisted
+/// and restored. Use an [`AccountBuilder`] to create one with balances.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "AccountFields")
+)]
 pub struct Account {
     /// The unique identifier for the account
+    #[cfg_attr(feature = "serde", serde(rename = "client"))]
     pub(crate) client_id: ClientId,
     /// The total funds that are available for trading, staking, withdrawal, etc.
     ///
     /// Note that funds may go negative if total withdrawals or disputes are larger than total
     /// deposits.
+    #[cfg_attr(feature = "serde", serde(rename = "available"))]
     pub(crate) funds_available: Decimal,
     /// The total funds that are held for dispute.
     ///
     /// Note that funds may go negative if total resolutions or chargebacks are larger than total
     /// deposits.
+    #[cfg_attr(feature = "serde", serde(rename = "held"))]
     pub(crate) funds_held: Decimal,
     /// Whether the account is locked. An account is locked if a charge back occurs
     pub(crate) locked: bool,
+    /// The currency of the account's funds: [`Currency::NONE`] until the account is first
+    /// deposited into or withdrawn from in another currency. Left out when serialized if it's
+    /// [`Currency::NONE`], so single-currency states read and write as before.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Currency::is_none")
+    )]
+    pub(crate) currency: Currency,
+}
+
+/// An [`Account`] as deserialized, before its balances are checked by an [`AccountBuilder`]
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct AccountFields {
+    /// See [`Account::client_id`]
+    client: ClientId,
+    /// See [`Account::funds_available`]
+    available: Decimal,
+    /// See [`Account::funds_held`]
+    held: Decimal,
+    /// See [`Account::is_locked`]
+    locked: bool,
+    /// See [`Account::currency`]
+    #[serde(default)]
+    currency: Currency,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<AccountFields> for Account {
+    type Error = Error;
+
+    fn try_from(fields: AccountFields) -> Result<Self, Error> {
+        Account::builder(fields.client)
+            .available(fields.available)
+            .held(fields.held)
+            .locked(fields.locked)
+            .currency(fields.currency)
+            .build()
+    }
 }
 
 impl Account {
     /// Creates a new, empty account, with zero balances
     #[must_use]
     pub fn new(client_id: ClientId) -> Self {
+        Self::with_scale(client_id, DECIMAL_SCALE)
+    }
+
+    /// Creates a new, empty account, with zero balances to `scale` decimal places, for an account
+    /// book with that [scale](AccountBook::scale)
+    #[must_use]
+    pub fn with_scale(client_id: ClientId, scale: u32) -> Self {
         Self {
             client_id,
-            funds_available: Decimal::new(0, DECIMAL_SCALE),
-            funds_held: Decimal::new(0, DECIMAL_SCALE),
+            funds_available: Decimal::new(0, scale),
+            funds_held: Decimal::new(0, scale),
             locked: false,
+            currency: Currency::NONE,
         }
     }
 
@@ -165,7 +822,9 @@ impl Account {
     #[must_use]
     #[inline]
     pub fn total(&self) -> Decimal {
-        self.funds_available + self.funds_held
+        // Transactions, the builder and deserializing all keep the total in range, so this never
+        // saturates
+        self.funds_available.saturating_add(self.funds_held)
     }
     /// Returns whether the account is locked
     #[must_use]
@@ -173,23 +832,148 @@ impl Account {
     pub fn is_locked(&self) -> bool {
         self.locked
     }
+
+    /// Returns the currency of the account's funds
+    #[must_use]
+    #[inline]
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// Returns total funds in the account, available or held, in its currency
+    #[must_use]
+    pub fn balance(&self) -> Money {
+        Money::new(self.total(), self.currency)
+    }
+
+    /// Starts building an account with the given balances, eg to restore one from storage
+    pub fn builder(client_id: ClientId) -> AccountBuilder {
+        AccountBuilder::new(client_id)
+    }
 }
 
-/// An interface to all accounts
-pub trait AccountBook: IntoIterator<Item = Account>
-where
-    for<'a> &'a Self: IntoIterator<Item = &'a Account>,
-    Self: Sized,
-{
-    /// Takes a [`TransactionState`] reference and applies it to an account in the account book
-    fn apply<T>(
+/// Builds an [`Account`] in any state, from [`Account::builder`]. Balances not set are zero, and
+/// the account is unlocked unless set otherwise.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct AccountBuilder {
+    /// The account being built
+    account: Account,
+}
+
+impl AccountBuilder {
+    /// Starts building an empty, unlocked account
+    pub fn new(client_id: ClientId) -> Self {
+        Self {
+            account: Account::new(client_id),
+        }
+    }
+
+    /// Sets the funds available. The amount is kept as given, at whatever scale it has.
+    pub fn available(mut self, amount: Decimal) -> Self {
+        self.account.funds_available = amount;
+        self
+    }
+
+    /// Sets the funds held for disputes. The amount is kept as given, at whatever scale it has.
+    pub fn held(mut self, amount: Decimal) -> Self {
+        self.account.funds_held = amount;
+        self
+    }
+
+    /// Sets whether the account is locked
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.account.locked = locked;
+        self
+    }
+
+    /// Sets the currency of the account's funds
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.account.currency = currency;
+        self
+    }
+
+    /// Returns the account
+    /// # Errors
+    /// [`DomainError::BalanceOutOfRange`] if the funds available and held add up to more than a
+    /// [`Decimal`] can hold
+    pub fn build(self) -> Result<Account, Error> {
+        let account = self.account;
+        if account
+            .funds_available
+            .checked_add(account.funds_held)
+            .is_none()
+        {
+            return Err(DomainError::BalanceOutOfRange(account.client_id).into());
+        }
+        Ok(account)
+    }
+}
+
+/// An estimate of how much data is about to be loaded, used to pre-size storage.
+///
+/// Sizing storage up front avoids repeatedly growing (and rehashing) it during very large loads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityHint {
+    /// The estimated number of incoming rows
+    rows: usize,
+}
+
+impl CapacityHint {
+    /// A rough average length of one row of CSV input, including the line ending.
+    ///
+    /// Something like `deposit,1234,567890,12.3456` is about this long; disputes and resolutions
+    /// are shorter, which pushes the estimate up slightly rather than down.
+    pub const ESTIMATED_BYTES_PER_ROW: u64 = 24;
+
+    /// Creates a hint from a known (or estimated) number of rows
+    #[must_use]
+    pub fn from_rows(rows: usize) -> Self {
+        Self { rows }
+    }
+
+    /// Creates a hint from the size of the input in bytes, eg from file metadata
+    #[must_use]
+    pub fn from_input_size(bytes: u64) -> Self {
+        let rows = bytes / Self::ESTIMATED_BYTES_PER_ROW;
+        Self::from_rows(usize::try_from(rows).unwrap_or(usize::MAX))
+    }
+
+    /// Returns the estimated number of transactions that will be registered
+    #[must_use]
+    pub fn transactions(&self) -> usize {
+        self.rows
+    }
+
+    /// Returns the estimated number of accounts that will be created.
+    ///
+    /// This can't be more than the number of rows, or the number of possible [`ClientId`]s.
+    #[must_use]
+    // Only fallible with wide IDs
+    #[cfg_attr(
+        not(feature = "wide-ids"),
+        allow(clippy::unnecessary_fallible_conversions)
+    )]
+    pub fn accounts(&self) -> usize {
+        usize::try_from(RawClientId::MAX)
+            .map_or(self.rows, |max| self.rows.min(max.saturating_add(1)))
+    }
+}
+
+/// An interface to all accounts.
+///
+/// The trait is object safe, so account books can be chosen at runtime as a
+/// `Box<dyn AccountBook>`.
+pub trait AccountBook {
+    /// Takes a [`TransactionState`] reference and applies it to an account in the account book.
+    ///
+    /// The account is only changed once the log has registered the transaction, so if either
+    /// fails, neither is changed and the transaction can be applied again.
+    fn apply(
         &mut self,
-        transaction_log: &mut T,
+        transaction_log: &mut dyn TransactionLog,
         transaction: &mut TransactionState,
-    ) -> Result<(), Error>
-    where
-        T: TransactionLog,
-    {
+    ) -> Result<(), Error> {
         ops::apply_transaction(self, transaction_log, transaction)
     }
 
@@ -199,28 +983,296 @@ where
     /// Fetches a client's account, returning a mutable reference. If an account does not exist yet,
     /// it will be created.
     fn account_mut(&mut self, client_id: ClientId) -> Result<&mut Account, Error>;
+
+    /// Iterates over every account, in no particular order
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_>;
+
+    /// Fetches a client's account without creating it, or `None` if the client has none.
+    ///
+    /// The default implementation scans every account. Account books that can look an account
+    /// up directly should override it.
+    /// # Errors
+    /// If the storage can't be reached
+    fn get(&self, client_id: ClientId) -> Result<Option<&Account>, Error> {
+        Ok(self
+            .accounts()
+            .find(|account| account.client_id == client_id))
+    }
+
+    /// Returns the number of decimal places amounts are kept to. Amounts are rounded to it as
+    /// they're applied, so balances, and the amounts registered in the transaction log, have
+    /// exactly this many.
+    ///
+    /// The default implementation returns [`DECIMAL_SCALE`].
+    fn scale(&self) -> u32 {
+        DECIMAL_SCALE
+    }
+
+    /// Prepares room for at least `additional` more accounts.
+    ///
+    /// This is only a hint; the default implementation does nothing.
+    fn reserve(&mut self, additional: usize) {
+        let _ = additional;
+    }
+
+    /// Checks the account book's storage can be reached, eg for a readiness probe.
+    ///
+    /// In-memory account books are always reachable; the default implementation returns `Ok`.
+    /// # Errors
+    /// If the storage can't be reached
+    fn check_connection(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Fetches up to `limit` accounts in client order, starting after the client `after`, or from
+    /// the first client if `after` is `None`.
+    ///
+    /// To page through the whole book, pass the last client of each page as `after` for the next
+    /// one; a page shorter than `limit` is the last. Accounts created between pages are included
+    /// if they come after the cursor.
+    ///
+    /// The default implementation scans every account for each page, keeping only the page
+    /// itself in memory. Account books with ordered storage should override it.
+    /// # Errors
+    /// If the storage can't be reached
+    fn accounts_after(
+        &self,
+        after: Option<ClientId>,
+        limit: usize,
+    ) -> Result<Vec<&Account>, Error> {
+        let mut page = BTreeMap::new();
+        for account in self.accounts() {
+            if after.is_some_and(|after| account.client_id <= after) {
+                continue;
+            }
+            page.insert(account.client_id, account);
+            if page.len() > limit {
+                page.pop_last();
+            }
+        }
+        Ok(page.into_values().collect())
+    }
+}
+
+impl<A: AccountBook + ?Sized> AccountBook for Box<A> {
+    fn apply(
+        &mut self,
+        transaction_log: &mut dyn TransactionLog,
+        transaction: &mut TransactionState,
+    ) -> Result<(), Error> {
+        (**self).apply(transaction_log, transaction)
+    }
+
+    fn account(&mut self, client_id: ClientId) -> Result<&Account, Error> {
+        (**self).account(client_id)
+    }
+
+    fn account_mut(&mut self, client_id: ClientId) -> Result<&mut Account, Error> {
+        (**self).account_mut(client_id)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+        (**self).accounts()
+    }
+
+    fn get(&self, client_id: ClientId) -> Result<Option<&Account>, Error> {
+        (**self).get(client_id)
+    }
+
+    fn scale(&self) -> u32 {
+        (**self).scale()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        (**self).reserve(additional);
+    }
+
+    fn check_connection(&self) -> Result<(), Error> {
+        (**self).check_connection()
+    }
+
+    fn accounts_after(
+        &self,
+        after: Option<ClientId>,
+        limit: usize,
+    ) -> Result<Vec<&Account>, Error> {
+        (**self).accounts_after(after, limit)
+    }
 }
 
-/// An interface to all transactions
+/// An interface to all transactions.
+///
+/// Implementations can inspect what they're given with the accessors on [`Transaction`], and
+/// build what they return with [`TransactionRecord::new`].
 pub trait TransactionLog {
-    /// Fetches a transaction by ID, if one exists
-    fn transaction(&self, transaction_id: TransactionId) -> Result<Option<&Transaction>, Error>;
+    /// Fetches the details of a transaction by ID, if one exists
+    fn transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, Error>;
 
-    /// Registers a transaction in the log
+    /// Registers a transaction in the log.
+    ///
+    /// # Errors
+    /// Returns [`Error::Transient`] for failures worth retrying, in which case the transaction
+    /// must not have been kept.
     fn register(&mut self, transaction: Transaction) -> Result<(), Error>;
+
+    /// Prepares room for at least `additional` more transactions.
+    ///
+    /// This is only a hint; the default implementation does nothing.
+    fn reserve(&mut self, additional: usize) {
+        let _ = additional;
+    }
+
+    /// Checks the transaction log's storage can be reached, eg for a readiness probe.
+    ///
+    /// In-memory logs are always reachable; the default implementation returns `Ok`.
+    /// # Errors
+    /// If the storage can't be reached
+    fn check_connection(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Notes where a transaction that's just been registered was read from.
+    ///
+    /// By now, the transaction has been applied, so a log that can't keep this should drop it
+    /// rather than fail. The default implementation drops it; see [`ProvenanceLog`] for one that
+    /// doesn't.
+    fn record_provenance(&mut self, transaction_id: TransactionId, provenance: Provenance) {
+        let _ = (transaction_id, provenance);
+    }
+
+    /// Iterates over every registered transaction, in no particular order, eg for a
+    /// [`ReportPlugin`](crate::plugin::ReportPlugin).
+    ///
+    /// Not every log can be read back in full; the default implementation yields nothing.
+    fn transactions(&self) -> Box<dyn Iterator<Item = TransactionRecord> + '_> {
+        Box::new(std::iter::empty())
+    }
+
+    /// Fetches where a transaction was read from, if the log kept it
+    /// # Errors
+    /// If the log can't be read
+    fn provenance(&self, transaction_id: TransactionId) -> Result<Option<Provenance>, Error> {
+        let _ = transaction_id;
+        Ok(None)
+    }
+
+    /// Removes transactions from the log, eg once they've been archived under a retention
+    /// policy, returning how many were removed. IDs not in the log are skipped.
+    ///
+    /// Not every log can remove transactions; the default implementation removes none.
+    /// # Errors
+    /// If the log's storage can't be written
+    fn purge(&mut self, transaction_ids: &[TransactionId]) -> Result<usize, Error> {
+        let _ = transaction_ids;
+        Ok(0)
+    }
+}
+
+/// Wraps a [`TransactionLog`], keeping the [`Provenance`] of every registered transaction in
+/// memory alongside it.
+///
+/// The in-memory logs don't keep provenance themselves, since it more than doubles the memory
+/// each transaction takes.
+#[derive(Debug, Default)]
+pub struct ProvenanceLog<T> {
+    /// The log being wrapped
+    pub(crate) inner: T,
+    /// Where each registered transaction was read from
+    pub(crate) provenance: HashMap<TransactionId, Provenance, DefaultHashBuilder>,
+}
+
+impl<T> ProvenanceLog<T> {
+    /// Wraps `inner`, keeping provenance alongside it
+    #[must_use]
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            provenance: HashMap::default(),
+        }
+    }
+
+    /// Returns the wrapped log
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+/// Wraps an [`AccountBook`] or [`TransactionLog`], retrying calls that fail with
+/// [`Error::Transient`], so a blip in a remote backend doesn't abort a long run.
+///
+/// Waits between retries as set by the [`RetryWithBackoff`], then gives up and returns the last
+/// error. Other errors are returned straight away.
+///
+/// Retrying [`TransactionLog::register`] means a backend must not have kept a transaction it
+/// returns [`Error::Transient`] for. Since an account can't be returned from a failed attempt,
+/// it's fetched once more after the first attempt that succeeds.
+#[derive(Debug)]
+pub struct Retrying<S> {
+    /// The storage being wrapped
+    pub(crate) inner: S,
+    /// How many times to retry, and how long to wait in between
+    pub(crate) backoff: RetryWithBackoff,
+}
+
+impl<S> Retrying<S> {
+    /// Wraps `inner`, retrying transient failures as set by `backoff`
+    #[must_use]
+    pub fn new(inner: S, backoff: RetryWithBackoff) -> Self {
+        Self { inner, backoff }
+    }
+
+    /// Returns the wrapped storage
+    #[must_use]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
 }
 
+/// The hasher used by [`MemoryAccountBook`] and [`MemoryTransactionLog`] unless another is chosen.
+///
+/// This is the standard library's SipHash-based
+/// [`RandomState`](std::collections::hash_map::RandomState), or `ahash::RandomState` when the
+/// `ahash` feature is enabled. SipHash is robust but slow, and tends to dominate profiles on large
+/// runs.
+#[cfg(not(feature = "ahash"))]
+pub type DefaultHashBuilder = std::collections::hash_map::RandomState;
+
+/// The hasher used by [`MemoryAccountBook`] and [`MemoryTransactionLog`] unless another is chosen.
+///
+/// This is [`ahash::RandomState`], since the `ahash` feature is enabled. Without the feature,
+/// the standard library's SipHash-based [`RandomState`](std::collections::hash_map::RandomState)
+/// is used.
+#[cfg(feature = "ahash")]
+pub type DefaultHashBuilder = ahash::RandomState;
+
 /// Holds all accounts in an in-memory structure.
 ///
+/// The hasher used for looking up accounts can be swapped out via `S`.
+///
 /// # Limitations
 /// No persistence.
 ///
 /// Only a single operation is allowed on the entire
 /// account book at any given time.
-#[derive(Default, Debug)]
-pub struct MemoryAccountBook {
+#[derive(Debug)]
+pub struct MemoryAccountBook<S = DefaultHashBuilder> {
     /// Storage for the map of account ID to account
-    pub(crate) accounts: HashMap<ClientId, Account>,
+    pub(crate) accounts: HashMap<ClientId, Account, S>,
+    /// See [`AccountBook::scale`]
+    pub(crate) scale: u32,
+}
+
+impl<S: Default> Default for MemoryAccountBook<S> {
+    fn default() -> Self {
+        Self {
+            accounts: HashMap::default(),
+            scale: DECIMAL_SCALE,
+        }
+    }
 }
 
 impl MemoryAccountBook {
@@ -229,10 +1281,55 @@ impl MemoryAccountBook {
     pub fn new() -> Self {
         MemoryAccountBook::default()
     }
+
+    /// Creates a new, empty [`MemoryAccountBook`] with room for at least `capacity` accounts.
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, DefaultHashBuilder::default())
+    }
+}
+
+impl<S> MemoryAccountBook<S>
+where
+    S: BuildHasher,
+{
+    /// Creates a new, empty [`MemoryAccountBook`] which will use the given hasher.
+    #[must_use]
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    /// Creates a new, empty [`MemoryAccountBook`] with room for at least `capacity` accounts,
+    /// which will use the given hasher.
+    #[must_use]
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self {
+            accounts: HashMap::with_capacity_and_hasher(capacity, hasher),
+            scale: DECIMAL_SCALE,
+        }
+    }
+
+    /// Keeps amounts to `scale` decimal places, rather than [`DECIMAL_SCALE`], eg 2 for fiat
+    /// currencies with cents, or 8 for cryptocurrencies. Accounts already in the book keep their
+    /// balances until they're next changed.
+    /// # Panics
+    /// If `scale` is more than [`Decimal::MAX_SCALE`]
+    #[must_use]
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        assert!(
+            scale <= Decimal::MAX_SCALE,
+            "scale {scale} is more than the {} places a Decimal can hold",
+            Decimal::MAX_SCALE
+        );
+        self.scale = scale;
+        self
+    }
 }
 
 /// Holds all transactions in an in-memory structure.
 ///
+/// The hasher used for looking up transactions can be swapped out via `S`.
+///
 /// # Limitations
 /// Only a single transaction per ID is supported,
 /// so operations such as [`TransactionType::Dispute`] or
@@ -243,9 +1340,9 @@ impl MemoryAccountBook {
 /// Only a single operation is allowed on the entire log
 /// at any given time.
 #[derive(Default, Debug)]
-pub struct MemoryTransactionLog {
+pub struct MemoryTransactionLog<S = DefaultHashBuilder> {
     /// Storage for transactions that have been registered
-    pub(crate) transactions: HashMap<TransactionId, Transaction>,
+    pub(crate) transactions: HashMap<TransactionId, Transaction, S>,
 }
 
 impl MemoryTransactionLog {
@@ -254,4 +1351,308 @@ impl MemoryTransactionLog {
     pub fn new() -> Self {
         MemoryTransactionLog::default()
     }
+
+    /// Creates a new, empty [`MemoryTransactionLog`] with room for at least `capacity` transactions
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, DefaultHashBuilder::default())
+    }
+}
+
+impl<S> MemoryTransactionLog<S>
+where
+    S: BuildHasher,
+{
+    /// Creates a new, empty [`MemoryTransactionLog`] which will use the given hasher
+    #[must_use]
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    /// Creates a new, empty [`MemoryTransactionLog`] with room for at least `capacity`
+    /// transactions, which will use the given hasher
+    #[must_use]
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self {
+            transactions: HashMap::with_capacity_and_hasher(capacity, hasher),
+        }
+    }
+}
+
+/// Holds all transactions in an in-memory structure, storing amounts as integer minor units
+/// (ten-thousandths, per [`DECIMAL_SCALE`], unless [another scale](Self::with_scale) is set)
+/// rather than as [`Decimal`]s.
+///
+/// Each entry takes 16 bytes of table space, half of what [`MemoryTransactionLog`] uses, which
+/// matters for deposit-heavy logs with hundreds of millions of entries.
+///
+/// # Limitations
+/// Amounts must fit in an [`i64`] of minor units, ie be less than about 922 trillion in
+/// magnitude. Registering a larger amount fails with [`DomainError::AmountOutOfRange`]. Amounts
+/// with more decimal places than the log's scale are rounded, so it should match the
+/// [account book's](AccountBook::scale). Timestamps aren't stored, since they'd double the size
+/// of each entry.
+///
+/// Otherwise, the same as [`MemoryTransactionLog`].
+#[derive(Debug)]
+pub struct MinorUnitsTransactionLog<S = DefaultHashBuilder> {
+    /// Storage for transactions that have been registered
+    pub(crate) transactions: HashMap<TransactionId, MinorUnitsEntry, S>,
+    /// Decimal places of a minor unit
+    pub(crate) scale: u32,
+}
+
+impl<S: Default> Default for MinorUnitsTransactionLog<S> {
+    fn default() -> Self {
+        Self {
+            transactions: HashMap::default(),
+            scale: DECIMAL_SCALE,
+        }
+    }
+}
+
+/// The compact form in which [`MinorUnitsTransactionLog`] stores a transaction.
+///
+/// Packed to 4-byte alignment so the 8-byte amount doesn't force padding out to 24 bytes.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed(4))]
+pub(crate) struct MinorUnitsEntry {
+    /// The amount in minor units, or [`MinorUnitsEntry::NO_AMOUNT`] if there wasn't one
+    pub(crate) amount: i64,
+    /// See [`Transaction::client_id`]
+    pub(crate) client_id: ClientId,
+    /// See [`Transaction::transaction_type`]
+    pub(crate) transaction_type: TransactionType,
+}
+
+impl MinorUnitsEntry {
+    /// Marks an entry with no amount. Never a valid amount, since it has no positive counterpart.
+    pub(crate) const NO_AMOUNT: i64 = i64::MIN;
+
+    /// Unpacks the entry for the given transaction, with amounts in minor units of `scale`
+    /// decimal places
+    pub(crate) fn record(&self, transaction_id: TransactionId, scale: u32) -> TransactionRecord {
+        let amount = self.amount;
+        TransactionRecord {
+            transaction_type: self.transaction_type,
+            client_id: self.client_id,
+            transaction_id,
+            amount: (amount != Self::NO_AMOUNT).then(|| Decimal::new(amount, scale)),
+            timestamp: None,
+        }
+    }
+}
+
+impl MinorUnitsTransactionLog {
+    /// Creates a new, empty [`MinorUnitsTransactionLog`]
+    #[must_use]
+    pub fn new() -> Self {
+        MinorUnitsTransactionLog::default()
+    }
+
+    /// Creates a new, empty [`MinorUnitsTransactionLog`] with room for at least `capacity`
+    /// transactions
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, DefaultHashBuilder::default())
+    }
+}
+
+impl<S> MinorUnitsTransactionLog<S>
+where
+    S: BuildHasher,
+{
+    /// Creates a new, empty [`MinorUnitsTransactionLog`] which will use the given hasher
+    #[must_use]
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    /// Creates a new, empty [`MinorUnitsTransactionLog`] with room for at least `capacity`
+    /// transactions, which will use the given hasher
+    #[must_use]
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self {
+            transactions: HashMap::with_capacity_and_hasher(capacity, hasher),
+            scale: DECIMAL_SCALE,
+        }
+    }
+
+    /// Stores amounts in minor units of `scale` decimal places, rather than [`DECIMAL_SCALE`],
+    /// to match an account book with that [scale](AccountBook::scale). Set it while the log is
+    /// empty, since amounts already registered would be read back at the new scale.
+    /// # Panics
+    /// If `scale` is more than [`Decimal::MAX_SCALE`]
+    #[must_use]
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        assert!(
+            scale <= Decimal::MAX_SCALE,
+            "scale {scale} is more than the {} places a Decimal can hold",
+            Decimal::MAX_SCALE
+        );
+        self.scale = scale;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_construct_transactions() {
+        let client = ClientId::from(1);
+        let mut account_book = MemoryAccountBook::new();
+        let mut transaction_log = MemoryTransactionLog::new();
+        let transactions = [
+            Transaction::deposit(client, TransactionId::from(1), dec!(5.123456)).unwrap(),
+            Transaction::withdrawal(client, TransactionId::from(2), dec!(1)).unwrap(),
+            Transaction::dispute(client, TransactionId::from(1)),
+        ];
+        // Kept exactly until it's applied
+        assert_eq!(transactions[0].amount(), Some(dec!(5.123456)));
+        assert_eq!(transactions[2].transaction_type(), TransactionType::Dispute);
+        assert_eq!(transactions[2].amount(), None);
+        for transaction in transactions {
+            account_book
+                .apply(&mut transaction_log, &mut transaction.into())
+                .unwrap();
+        }
+        let account = &account_book.accounts[&client];
+        assert_eq!(account.funds_available(), dec!(-1.0000));
+        assert_eq!(account.funds_held(), dec!(5.1235));
+
+        let err = Transaction::withdrawal(client, TransactionId::from(3), dec!(-1)).unwrap_err();
+        assert_eq!(err.code(), "invalid_amount");
+        assert!(Transaction::deposit(client, TransactionId::from(3), dec!(0)).is_err());
+    }
+
+    #[test]
+    fn test_transaction_type_names() {
+        for transaction_type in TransactionType::ALL {
+            let name = transaction_type.to_string();
+            assert_eq!(name.parse::<TransactionType>().unwrap(), transaction_type);
+        }
+        assert_eq!(TransactionType::Withdrawal.to_string(), "withdrawal");
+        let err = "refund".parse::<TransactionType>().unwrap_err();
+        assert_eq!(err.code(), "unknown_transaction_type");
+    }
+
+    /// A log keeping only deposits, as plain numbers, the way one backed by a database might
+    #[derive(Default)]
+    struct DepositLog {
+        /// Client and amount in ten-thousandths, by transaction ID
+        deposits: HashMap<RawTransactionId, (RawClientId, i64)>,
+    }
+
+    impl TransactionLog for DepositLog {
+        fn transaction(
+            &self,
+            transaction_id: TransactionId,
+        ) -> Result<Option<TransactionRecord>, Error> {
+            Ok(self
+                .deposits
+                .get(&RawTransactionId::from(transaction_id))
+                .map(|&(client, amount)| {
+                    TransactionRecord::new(
+                        TransactionType::Deposit,
+                        client.into(),
+                        transaction_id,
+                        Some(Decimal::new(amount, DECIMAL_SCALE)),
+                    )
+                }))
+        }
+
+        fn register(&mut self, transaction: Transaction) -> Result<(), Error> {
+            if transaction.transaction_type() == TransactionType::Deposit {
+                let mut amount = transaction.amount().unwrap_or_default();
+                amount.rescale(DECIMAL_SCALE);
+                let amount = amount.mantissa();
+                self.deposits.insert(
+                    transaction.transaction_id().into(),
+                    (
+                        transaction.client_id().into(),
+                        i64::try_from(amount).unwrap(),
+                    ),
+                );
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_transaction_log() {
+        let client = ClientId::from(2);
+        let mut account_book = MemoryAccountBook::new();
+        let mut transaction_log = DepositLog::default();
+        let deposit = Transaction::deposit(client, TransactionId::from(7), dec!(2.5)).unwrap();
+        let record = TransactionRecord::from(&deposit);
+        account_book
+            .apply(&mut transaction_log, &mut deposit.into())
+            .unwrap();
+        assert_eq!(
+            transaction_log.transaction(TransactionId::from(7)).unwrap(),
+            Some(record)
+        );
+        account_book
+            .apply(
+                &mut transaction_log,
+                &mut Transaction::dispute(client, TransactionId::from(7)).into(),
+            )
+            .unwrap();
+        assert_eq!(account_book.accounts[&client].funds_held(), dec!(2.5));
+    }
+
+    #[test]
+    fn test_dyn_account_book() {
+        let mut account_book: Box<dyn AccountBook> = Box::new(MemoryAccountBook::new());
+        let mut transaction_log: Box<dyn TransactionLog> = Box::new(MemoryTransactionLog::new());
+        for (client, tx) in [(3, 1), (1, 2), (2, 3)] {
+            let deposit =
+                Transaction::deposit(ClientId::from(client), TransactionId::from(tx), dec!(1))
+                    .unwrap();
+            account_book
+                .apply(transaction_log.as_mut(), &mut deposit.into())
+                .unwrap();
+        }
+        assert_eq!(account_book.accounts().count(), 3);
+        let page = account_book.accounts_after(Some(ClientId::from(1)), 10);
+        let clients: Vec<_> = page.unwrap().iter().map(|a| a.client_id()).collect();
+        assert_eq!(clients, [ClientId::from(2), ClientId::from(3)]);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_account_serde() {
+        let account = Account::builder(ClientId::from(7))
+            .available(dec!(1.5000))
+            .held(dec!(2.0000))
+            .locked(true)
+            .build()
+            .unwrap();
+        assert_eq!(account.total(), dec!(3.5));
+        assert_eq!(RawClientId::from(account.client_id()), 7);
+        let json = serde_json::to_string(&account).unwrap();
+        assert_eq!(
+            json,
+            r#"{"client":7,"available":"1.5000","held":"2.0000","locked":true}"#
+        );
+        let restored: Account = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, account.clone());
+        assert_eq!(RawTransactionId::from(TransactionId::from(9)), 9);
+
+        // Balances whose total is out of range are refused, rather than overflowing later
+        let builder = Account::builder(ClientId::from(7))
+            .available(Decimal::MAX)
+            .held(Decimal::ONE);
+        let err = builder.clone().build().unwrap_err();
+        assert_eq!(err.code(), "balance_out_of_range");
+        let json =
+            r#"{"client":7,"available":"79228162514264337593543950335","held":"1","locked":false}"#;
+        let err = serde_json::from_str::<Account>(json).unwrap_err();
+        assert!(err.to_string().contains("out of range"));
+        assert!(builder.held(Decimal::NEGATIVE_ONE).build().is_ok());
+    }
 }
@@ -0,0 +1,74 @@
+//! JavaScript bindings for the string entry points in [`embed`](crate::embed), behind the `wasm`
+//! feature, for a browser tool built as a `cdylib` for `wasm32-unknown-unknown` and run through
+//! the `wasm-bindgen` command line tool.
+//!
+//! `processCsv(input)` applies a whole input and returns the accounts, and a `CsvSession` applies
+//! input as it arrives with `applyCsv(input)`, returning the accounts from `accountsCsv()`. Rows
+//! that can't be parsed or applied are thrown as an `Error` with the reason as its message.
+// wasm-bindgen's generated glue is unsafe
+#![allow(unsafe_code)]
+
+use wasm_bindgen::prelude::*;
+
+use crate::{embed, errors::Error};
+
+/// Applies transactions in CSV, returning the accounts as CSV, as [`embed::process_csv`] does
+/// # Errors
+/// If a row can't be parsed or applied
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv(input: &str) -> Result<String, JsError> {
+    embed::process_csv(input).map_err(js_error)
+}
+
+/// An engine kept between calls, applying transactions in CSV as they arrive, as an
+/// [`embed::CsvSession`] does
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct CsvSession(embed::CsvSession);
+
+#[wasm_bindgen]
+impl CsvSession {
+    /// Starts a session with no accounts
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies transactions in CSV, with a header row, on top of everything applied before
+    /// # Errors
+    /// If a row can't be parsed or applied, in which case the rows before it are still applied
+    #[wasm_bindgen(js_name = applyCsv)]
+    pub fn apply_csv(&mut self, input: &str) -> Result<(), JsError> {
+        self.0.apply_csv(input).map_err(js_error)
+    }
+
+    /// Writes every account as CSV, in client order
+    /// # Errors
+    /// If writing fails
+    #[wasm_bindgen(js_name = accountsCsv)]
+    pub fn accounts_csv(&self) -> Result<String, JsError> {
+        self.0.accounts_csv().map_err(js_error)
+    }
+}
+
+/// Creates a JavaScript `Error` with the error's message
+fn js_error(error: Error) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Errors can only be created in JavaScript, so only what succeeds is tested natively
+    #[test]
+    fn test_bindings() {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        let accounts = "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n";
+        assert_eq!(process_csv(input).unwrap(), accounts);
+        let mut session = CsvSession::new();
+        session.apply_csv(input).unwrap();
+        assert_eq!(session.accounts_csv().unwrap(), accounts);
+    }
+}